@@ -0,0 +1,124 @@
+//! Rendimiento del cálculo del balance con datos horarios
+//!
+//! Ejecución: `cargo bench --bench balance` (o `cargo bench --features parallel --bench balance`
+//! para el cálculo en paralelo de los vectores energéticos)
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+use cteepbd::{cte::*, *};
+
+/// Factores de paso de la península
+fn get_ctefp_peninsula() -> Factors {
+    let user_wf = UserWF {
+        red1: None,
+        red2: None,
+        residuos: None,
+        cogen_to_grid: None,
+        cogen_to_nepb: None,
+    };
+    wfactors_from_loc("PENINSULA", &CTE_LOCWF_RITE2014, user_wf, CTE_USERWF).unwrap()
+}
+
+/// Lista de valores horarios con un perfil diario
+fn hourly_values(day: &[f32]) -> String {
+    (0..8760)
+        .map(|h| format!("{:.2}", day[h % 24]))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Componentes horarios de un edificio con producción fotovoltaica y varios vectores
+fn get_hourly_components() -> Components {
+    let flat = [1.0_f32; 24];
+    let daytime: Vec<f32> = (0..24)
+        .map(|h| if (8..20).contains(&h) { 2.5 } else { 0.0 })
+        .collect();
+    let evening: Vec<f32> = (0..24)
+        .map(|h| if (18..23).contains(&h) { 3.0 } else { 0.5 })
+        .collect();
+    format!(
+        "#META CTE_PASO: HORARIO
+ELECTRICIDAD, CONSUMO, EPB, CAL, {evening}
+ELECTRICIDAD, CONSUMO, EPB, REF, {flat}
+ELECTRICIDAD, CONSUMO, EPB, ACS, {evening}
+ELECTRICIDAD, CONSUMO, NEPB, NDEF, {flat}
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, {daytime}
+GASNATURAL, CONSUMO, EPB, CAL, {evening}
+BIOMASA, CONSUMO, EPB, ACS, {flat}",
+        evening = hourly_values(&evening),
+        flat = hourly_values(&flat),
+        daytime = hourly_values(&daytime),
+    )
+    .parse::<Components>()
+    .unwrap()
+    .normalize()
+}
+
+/// Componentes horarios de un edificio terciario con varios servicios, vectores y orígenes
+fn get_tertiary_components() -> Components {
+    // Perfiles de uso de oficina (laborables de 8 a 19h) y continuos
+    let office = |peak: f32| -> Vec<f32> {
+        (0..24)
+            .map(|h| {
+                if (8..19).contains(&h) {
+                    peak
+                } else {
+                    0.1 * peak
+                }
+            })
+            .collect()
+    };
+    let flat = [2.0_f32; 24];
+    let solar: Vec<f32> = (0..24)
+        .map(|h| if (8..19).contains(&h) { 4.0 } else { 0.0 })
+        .collect();
+    format!(
+        "#META CTE_PASO: HORARIO
+ELECTRICIDAD, CONSUMO, EPB, CAL, {heating}
+ELECTRICIDAD, CONSUMO, EPB, REF, {cooling}
+ELECTRICIDAD, CONSUMO, EPB, ILU, {lighting}
+ELECTRICIDAD, CONSUMO, EPB, VEN, {ventilation}
+ELECTRICIDAD, CONSUMO, EPB, ACS, {flat}
+ELECTRICIDAD, CONSUMO, NEPB, NDEF, {appliances}
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, {solar}
+ELECTRICIDAD, PRODUCCION, COGENERACION, NDEF, {flat}
+GASNATURAL, CONSUMO, EPB, CAL, {heating}
+GASNATURAL, CONSUMO, EPB, ACS, {flat}
+GASNATURAL, CONSUMO, EPB, NDEF, {flat}
+RED1, CONSUMO, EPB, CAL, {heating}
+RED1, CONSUMO, EPB, REF, {cooling}
+RED2, CONSUMO, EPB, ACS, {flat}
+BIOMASA, CONSUMO, EPB, CAL, {heating}
+BIOMASADENSIFICADA, CONSUMO, EPB, ACS, {flat}
+GASOLEO, CONSUMO, EPB, CAL, {heating}
+MEDIOAMBIENTE, CONSUMO, EPB, CAL, {heating}
+MEDIOAMBIENTE, CONSUMO, EPB, ACS, {flat}",
+        heating = hourly_values(&office(12.0)),
+        cooling = hourly_values(&office(9.0)),
+        lighting = hourly_values(&office(5.0)),
+        ventilation = hourly_values(&office(3.0)),
+        appliances = hourly_values(&office(6.0)),
+        flat = hourly_values(&flat),
+        solar = hourly_values(&solar),
+    )
+    .parse::<Components>()
+    .unwrap()
+    .normalize()
+}
+
+fn balance_benchmark(c: &mut Criterion) {
+    let components = get_hourly_components();
+    let wfactors = get_ctefp_peninsula();
+    c.bench_function("energy_performance horario", |b| {
+        b.iter(|| energy_performance(black_box(&components), &wfactors, 0.0, 100.0).unwrap())
+    });
+
+    let components = get_tertiary_components();
+    let wfactors = get_ctefp_peninsula();
+    c.bench_function("energy_performance horario terciario", |b| {
+        b.iter(|| energy_performance(black_box(&components), &wfactors, 0.0, 2500.0).unwrap())
+    });
+}
+
+criterion_group!(benches, balance_benchmark);
+criterion_main!(benches);
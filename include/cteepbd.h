@@ -0,0 +1,53 @@
+/*
+ * Interfaz C de la librería cteepbd (característica `ffi`)
+ *
+ * Copyright (c) 2018-2019  Ministerio de Fomento
+ *                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+ *
+ * Licencia MIT. Ver el archivo LICENSE del proyecto.
+ *
+ * Las cadenas se intercambian en UTF-8 terminadas en nulo. Las cadenas devueltas por la
+ * librería (salvo cteepbd_version) deben liberarse con cteepbd_string_free y los objetos
+ * opacos con su función *_free. Las funciones que fallan devuelven NULL y el error se
+ * consulta, en el mismo hilo, con cteepbd_last_error_code y cteepbd_last_error_message.
+ */
+
+#ifndef CTEEPBD_H
+#define CTEEPBD_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Objetos opacos */
+typedef struct Components Components;
+typedef struct Factors Factors;
+typedef struct Balance Balance;
+
+/* Interpretación de datos */
+Components *cteepbd_parse_components(const char *s);
+void cteepbd_components_free(Components *components);
+Factors *cteepbd_parse_wfactors(const char *s);
+void cteepbd_wfactors_free(Factors *wfactors);
+
+/* Cálculo del balance */
+Balance *cteepbd_energy_performance(const Components *components, const Factors *wfactors,
+                                    double k_exp, double arearef);
+void cteepbd_balance_free(Balance *balance);
+
+/* Serialización JSON */
+char *cteepbd_components_to_json(const Components *components);
+char *cteepbd_wfactors_to_json(const Factors *wfactors);
+char *cteepbd_balance_to_json(const Balance *balance);
+void cteepbd_string_free(char *s);
+
+/* Errores y versión */
+char *cteepbd_last_error_code(void);
+char *cteepbd_last_error_message(void);
+const char *cteepbd_version(void);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CTEEPBD_H */
@@ -35,16 +35,24 @@ según la EN ISO 52000-1.
 
 use std::collections::{HashMap, HashSet};
 use std::convert::TryInto;
+use std::fmt;
 
+use indexmap::IndexMap;
+use log::{debug, info};
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
 
 use crate::{
     error::{EpbdError, Result},
     types::{
-        CSubtype, CType, Carrier, Component, Dest, Factor, RenNrenCo2, Service, Source, Step,
-        SERVICES,
+        CSubtype, CType, Carrier, Component, Dest, FactorKey, FactorSeries, Perimeter, Real,
+        RenNrenCo2, Service, Source, Step, Storage, Timestep, WeightedQuantity, SERVICES,
+    },
+    vecops::{
+        vecitersum, veckmul, vecsum, vecvecdif, vecvecdif_assign, vecvecmin, vecvecmin_assign,
+        vecvecmul, vecvecmul_assign, vecvecsum_assign,
     },
-    vecops::{veckmul, vecsum, vecvecdif, vecvecmin, vecvecmul, vecvecsum},
     Components, Factors,
 };
 
@@ -59,18 +67,118 @@ pub struct Balance {
     pub components: Components,
     /// Weighting factors (weighting factors + metadata)
     pub wfactors: Factors,
-    /// Exported energy factor [0, 1]
-    pub k_exp: f32,
+    /// Exported energy factor [0, 1] for energy exported to the grid and, by default, to nEPB uses
+    pub k_exp: Real,
+    /// Exported energy factor for energy exported to nEPB uses [0, 1]
+    #[serde(default)]
+    pub k_exp_nepb: Real,
     /// Reference area used for energy performance ratios (>1e-3)
-    pub arearef: f32,
+    pub arearef: Real,
     /// Energy balance results by carrier
     pub balance_cr: HashMap<Carrier, BalanceForCarrier>,
     /// Global energy balance results
     pub balance: BalanceTotal,
     /// Global energy balance results expressed as area ratios
     pub balance_m2: BalanceTotal,
-    /// Generic miscelaneous user provided data
-    pub misc: Option<HashMap<String, String>>,
+    /// Generic miscelaneous indicators, in insertion order
+    #[serde(default)]
+    pub misc: MiscMap,
+    /// Load matching factor calculation method
+    #[serde(default, skip_serializing_if = "LoadMatching::is_unit")]
+    pub load_matching: LoadMatching,
+    /// Self-consumption indicators of produced electricity (`None` without electricity production)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub self_consumption: Option<SelfConsumption>,
+}
+
+/// Exponente k de la fórmula B.32 del factor de coincidencia de cargas (EN ISO 52000-1, apéndice B)
+pub const K_MATCH: Real = 2.0;
+
+/// Método de cálculo del factor de coincidencia de cargas f_match_t de la electricidad producida
+///
+/// Load matching factor calculation method for on-site produced electricity
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LoadMatching {
+    /// Factor constante igual a la unidad (toda la producción coincidente con el consumo EPB se aprovecha)
+    #[default]
+    Unit,
+    /// Factor calculado en cada paso según la fórmula B.32 del apéndice B de la EN ISO 52000-1,
+    /// para pasos de cálculo mensuales (el cálculo del balance da error con otros pasos):
+    /// f_match_t = (x + 1 - (x^k + 1)^(1/k)) / x, siendo x = E_pr_t / E_EPus_t y k = `K_MATCH`
+    B32,
+}
+
+impl LoadMatching {
+    /// Indica si se usa el factor constante igual a la unidad
+    pub fn is_unit(&self) -> bool {
+        *self == LoadMatching::Unit
+    }
+
+    /// Factor de coincidencia de cargas para la producción y consumo EPB de un paso de cálculo
+    pub fn factor(&self, produced: Real, used_epb: Real) -> Real {
+        match self {
+            LoadMatching::Unit => 1.0,
+            LoadMatching::B32 => {
+                if produced <= 0.0 || used_epb <= 0.0 {
+                    return 1.0;
+                }
+                let x = produced / used_epb;
+                (x + 1.0 - (x.powf(K_MATCH) + 1.0).powf(1.0 / K_MATCH)) / x
+            }
+        }
+    }
+}
+
+/// Indicadores adicionales del balance, en orden de inserción
+///
+/// Extra indicators computed by the CTE layer or user code (RER variants, matching factors, ...)
+pub type MiscMap = IndexMap<String, MiscValue>;
+
+/// Valor de un indicador adicional, numérico o de texto
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum MiscValue {
+    /// Valor numérico
+    Number(Real),
+    /// Valor de texto
+    Text(String),
+}
+
+impl MiscValue {
+    /// Valor numérico, si lo es
+    pub fn as_real(&self) -> Option<Real> {
+        match self {
+            MiscValue::Number(v) => Some(*v),
+            MiscValue::Text(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for MiscValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MiscValue::Number(v) => write!(f, "{}", v),
+            MiscValue::Text(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl From<Real> for MiscValue {
+    fn from(v: Real) -> Self {
+        MiscValue::Number(v)
+    }
+}
+
+impl From<String> for MiscValue {
+    fn from(v: String) -> Self {
+        MiscValue::Text(v)
+    }
+}
+
+impl From<&str> for MiscValue {
+    fn from(v: &str) -> Self {
+        MiscValue::Text(v.to_string())
+    }
 }
 
 /// Resultados del balance global (todos los vectores), en valor absoluto o por m2.
@@ -78,7 +186,10 @@ pub struct Balance {
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BalanceTotal {
     /// Global energy use for EPB uses, by use
-    pub used_EPB_byuse: HashMap<Service, f32>,
+    pub used_EPB_byuse: HashMap<Service, Real>,
+    /// Global auxiliary energy use for EPB uses (included in `used_EPB_byuse`), by use
+    #[serde(default)]
+    pub used_EPB_aux_byuse: HashMap<Service, Real>,
     /// Balance result for calculation step A
     pub A: RenNrenCo2,
     /// Weighted energy for calculation step A, by use (for EPB services)
@@ -93,6 +204,8 @@ pub struct BalanceTotal {
     pub we_exp_A: RenNrenCo2,
     /// Weighted exported energy for calculation step A+B
     pub we_exp: RenNrenCo2,
+    /// Weighted grid resources avoided by exported energy (step B term, k_exp * E_we_exp_AB)
+    pub we_exp_avoided: RenNrenCo2,
 }
 
 /// Calcula enficiencia energética agregando resultados por vector energético
@@ -109,12 +222,76 @@ pub struct BalanceTotal {
 /// * Use of an `arearef` less than 1e-3 raises an error
 /// * Missing weighting factors needed for balance computation
 ///
-#[allow(non_snake_case)]
 pub fn energy_performance(
     components: &Components,
     wfactors: &Factors,
-    k_exp: f32,
-    arearef: f32,
+    k_exp: Real,
+    arearef: Real,
+) -> Result<Balance> {
+    energy_performance_by_dest(components, wfactors, k_exp, k_exp, arearef)
+}
+
+/// Calcula enficiencia energética con factores de exportación distintos según el destino
+///
+/// Compute overall energy performance using different exported energy factors for energy
+/// exported to the grid and to nEPB uses, since their avoided resources on the grid differ.
+///
+/// * `components` - energy components
+/// * `wfactors` - weighting factors
+/// * `k_exp` - exported energy factor for energy exported to the grid [0, 1]
+/// * `k_exp_nepb` - exported energy factor for energy exported to nEPB uses [0, 1]
+/// * `arearef` - reference area used for computing energy performance ratios
+///
+/// # Errors
+///
+/// * Use of an `arearef` less than 1e-3 raises an error
+/// * Missing weighting factors needed for balance computation
+///
+#[allow(non_snake_case)]
+pub fn energy_performance_by_dest(
+    components: &Components,
+    wfactors: &Factors,
+    k_exp: Real,
+    k_exp_nepb: Real,
+    arearef: Real,
+) -> Result<Balance> {
+    energy_performance_with_load_matching(
+        components,
+        wfactors,
+        k_exp,
+        k_exp_nepb,
+        arearef,
+        LoadMatching::Unit,
+    )
+}
+
+/// Calcula enficiencia energética con el método indicado para el factor de coincidencia de cargas
+///
+/// Compute overall energy performance using different exported energy factors by destination
+/// and the given load matching factor calculation method for on-site produced electricity.
+///
+/// * `components` - energy components
+/// * `wfactors` - weighting factors
+/// * `k_exp` - exported energy factor for energy exported to the grid [0, 1]
+/// * `k_exp_nepb` - exported energy factor for energy exported to nEPB uses [0, 1]
+/// * `arearef` - reference area used for computing energy performance ratios
+/// * `load_matching` - load matching factor calculation method (`LoadMatching::Unit` for f_match_t = 1)
+///
+/// # Errors
+///
+/// * Use of an `arearef` less than 1e-3 raises an error
+/// * Components with different timestep counts (see `Components::check_timesteps`)
+/// * Use of `LoadMatching::B32` with a calculation timestep other than monthly (`Timestep::MENSUAL`)
+/// * Missing weighting factors needed for balance computation
+///
+#[allow(non_snake_case)]
+pub fn energy_performance_with_load_matching(
+    components: &Components,
+    wfactors: &Factors,
+    k_exp: Real,
+    k_exp_nepb: Real,
+    arearef: Real,
+    load_matching: LoadMatching,
 ) -> Result<Balance> {
     if arearef < 1e-3 {
         return Err(EpbdError::WrongInput(format!(
@@ -122,27 +299,110 @@ pub fn energy_performance(
             arearef
         )));
     };
+    // Components with different timestep counts would be silently padded with zeros
+    components.check_timesteps()?;
+
+    // Compensación de la energía producida en periodos más largos que el paso de cálculo
+    // Los componentes agregados solo se usan en el cálculo y el balance guarda los originales
+    let aggregated;
+    let calc_components = match (components.compensation_period()?, components.timestep()?) {
+        (Some(period), Some(timestep)) if timestep < period => {
+            if !wfactors.wseries.is_empty() {
+                return Err(EpbdError::WrongInput(
+                    "no se pueden usar factores de paso variables en el tiempo con un periodo de compensación distinto al paso de cálculo".to_string(),
+                ));
+            }
+            debug!(
+                "Compensación de la energía producida con periodo {} y paso de cálculo {}",
+                period, timestep
+            );
+            aggregated = components.aggregate(period)?;
+            &aggregated
+        }
+        (Some(_), None) => {
+            return Err(EpbdError::WrongInput(
+                "no se puede determinar el paso de cálculo de los componentes para aplicar el periodo de compensación".to_string(),
+            ))
+        }
+        _ => components,
+    };
 
-    let carriers: HashSet<_> = components.cdata.iter().map(|e| e.carrier).collect();
+    // La fórmula B.32 del factor de coincidencia de cargas solo es aplicable a pasos mensuales
+    if load_matching == LoadMatching::B32 && calc_components.timestep()? != Some(Timestep::MENSUAL)
+    {
+        return Err(EpbdError::WrongInput(
+            "el factor de coincidencia de cargas según la fórmula B.32 requiere un paso de cálculo mensual".to_string(),
+        ));
+    }
 
-    // Compute balance for each carrier
-    let mut balance_cr: HashMap<Carrier, BalanceForCarrier> = HashMap::new();
-    for &carrier in &carriers {
-        let components_cr: Vec<Component> = components
-            .cdata
-            .iter()
-            .filter(|e| e.carrier == carrier)
-            .cloned()
+    // Reference components (negative system ids) are not part of the building balance
+    let carriers: HashSet<_> = calc_components
+        .building_cdata()
+        .map(|e| e.carrier.clone())
+        .collect();
+    let priority = calc_components.production_priority()?;
+    let storages = calc_components.storages()?;
+    let service_priority = calc_components.service_priority()?;
+    let settings = CarrierBalanceSettings {
+        k_exp_grid: k_exp,
+        k_exp_nepb,
+        load_matching,
+        priority: priority.as_deref(),
+        storages: &storages,
+        by_service: calc_components.compensation_by_service()?,
+        service_priority: service_priority.as_deref(),
+    };
+    info!(
+        "Cálculo del balance: {} vectores, k_exp={:.2}, k_exp_nepb={:.2}, área de referencia={:.2}",
+        carriers.len(),
+        k_exp,
+        k_exp_nepb,
+        arearef
+    );
+    debug!(
+        "Opciones del balance: {:?}, prioridad de producción={:?}, almacenamientos={}, compensación por servicio={}, prioridad de servicios={:?}",
+        load_matching,
+        priority,
+        storages.len(),
+        settings.by_service,
+        service_priority
+    );
+
+    // Compute balance for each carrier (in parallel with the "parallel" feature)
+    let wf_index = wfactors.index();
+    let carrier_balance = |carrier: &Carrier| -> Result<(Carrier, BalanceForCarrier)> {
+        let components_cr: Vec<&Component> = calc_components
+            .building_cdata()
+            .filter(|e| e.carrier == *carrier)
             .collect();
-        let fp_cr: Vec<Factor> = wfactors
-            .wdata
+        let fs_cr: Vec<FactorSeries> = wfactors
+            .wseries
             .iter()
-            .filter(|e| e.carrier == carrier)
+            .filter(|e| e.carrier == *carrier)
             .cloned()
             .collect();
-        let bal = balance_for_carrier(carrier, &components_cr, &fp_cr, k_exp)?;
-        balance_cr.insert(carrier, bal);
-    }
+        let bal =
+            balance_for_carrier(carrier.clone(), &components_cr, &wf_index, &fs_cr, settings)?;
+        debug!(
+            "Balance de {}: {} componentes, suministrada de red={:.2}, producida={:.2}, exportada={:.2} kWh/an",
+            carrier,
+            components_cr.len(),
+            bal.delivered_grid_an,
+            bal.produced_an,
+            bal.exported_an
+        );
+        Ok((carrier.clone(), bal))
+    };
+    #[cfg(feature = "parallel")]
+    let balance_cr: HashMap<Carrier, BalanceForCarrier> = carriers
+        .par_iter()
+        .map(carrier_balance)
+        .collect::<Result<_>>()?;
+    #[cfg(not(feature = "parallel"))]
+    let balance_cr: HashMap<Carrier, BalanceForCarrier> = carriers
+        .iter()
+        .map(carrier_balance)
+        .collect::<Result<_>>()?;
 
     // Accumulate partial balance values for total balance
     let balance: BalanceTotal = carriers
@@ -156,6 +416,11 @@ pub fn energy_performance(
             acc.we_del += balance_cr[cr].we_delivered_an;
             acc.we_exp_A += balance_cr[cr].we_exported_an_A;
             acc.we_exp += balance_cr[cr].we_exported_an;
+            acc.we_exp_avoided += balance_cr[cr].we_exported_an_avoided;
+            // Auxiliary energy use
+            for (service, value) in &balance_cr[cr].used_EPB_aux_an_byuse {
+                *acc.used_EPB_aux_byuse.entry(*service).or_default() += *value
+            }
             // Weighted energy for each use item (EPB services)
             for &service in &SERVICES {
                 // Energy use
@@ -179,6 +444,9 @@ pub fn energy_performance(
     let mut used_EPB_byuse = balance.used_EPB_byuse.clone();
     used_EPB_byuse.values_mut().for_each(|v| *v *= k_area);
 
+    let mut used_EPB_aux_byuse = balance.used_EPB_aux_byuse.clone();
+    used_EPB_aux_byuse.values_mut().for_each(|v| *v *= k_area);
+
     let mut A_byuse = balance.A_byuse.clone();
     A_byuse.values_mut().for_each(|v| *v *= k_area);
 
@@ -187,6 +455,7 @@ pub fn energy_performance(
 
     let balance_m2 = BalanceTotal {
         used_EPB_byuse,
+        used_EPB_aux_byuse,
         A: k_area * balance.A,
         A_byuse,
         B: k_area * balance.B,
@@ -194,18 +463,33 @@ pub fn energy_performance(
         we_del: k_area * balance.we_del,
         we_exp_A: k_area * balance.we_exp_A,
         we_exp: k_area * balance.we_exp,
+        we_exp_avoided: k_area * balance.we_exp_avoided,
     };
 
+    let self_consumption = balance_cr
+        .get(&Carrier::ELECTRICIDAD)
+        .and_then(BalanceForCarrier::self_consumption);
+
+    info!(
+        "Resultado del balance (paso B): C_ep,nren={:.2}, C_ep,tot={:.2}, emisiones CO2={:.2} por m2",
+        balance_m2.B.nren,
+        balance_m2.B.tot(),
+        balance_m2.B.co2
+    );
+
     // Global data and results
     Ok(Balance {
         components: components.clone(),
         wfactors: wfactors.clone(),
         k_exp,
+        k_exp_nepb,
         arearef,
         balance_cr,
         balance,
         balance_m2,
-        misc: None,
+        misc: MiscMap::new(),
+        load_matching,
+        self_consumption,
     })
 }
 
@@ -221,45 +505,58 @@ pub struct BalanceForCarrier {
     /// Energy carrier
     pub carrier: Carrier,
     /// Energy used for EPB uses in each timestep
-    pub used_EPB: Vec<f32>,
+    pub used_EPB: Vec<Real>,
     /// Energy used for EPB uses, by use
-    pub used_EPB_an_byuse: HashMap<Service, f32>,
+    pub used_EPB_an_byuse: HashMap<Service, Real>,
+    /// Auxiliary energy used for EPB uses (included in `used_EPB_an_byuse`), by use
+    #[serde(default)]
+    pub used_EPB_aux_an_byuse: HashMap<Service, Real>,
     /// Used energy for non EPB uses in each timestep
-    pub used_nEPB: Vec<f32>,
+    pub used_nEPB: Vec<Real>,
     /// Produced energy in each timestep
-    pub produced: Vec<f32>,
+    pub produced: Vec<Real>,
     /// Produced energy (from all sources)
-    pub produced_an: f32,
+    pub produced_an: Real,
     /// Produced energy in each timestep by non grid source (COGENERACION / INSITU)
-    pub produced_bygen: HashMap<CSubtype, Vec<f32>>,
+    pub produced_bygen: HashMap<CSubtype, Vec<Real>>,
     /// Produced energy by non grid source (COGENERACION / INSITU)
-    pub produced_bygen_an: HashMap<CSubtype, f32>,
+    pub produced_bygen_an: HashMap<CSubtype, Real>,
     /// Produced energy from all origins and used for EPB services
-    pub produced_used_EPus: Vec<f32>,
-    /// Produced energy with origin in generator i and used for EPB services
-    pub produced_used_EPus_bygen: HashMap<CSubtype, Vec<f32>>,
+    pub produced_used_EPus: Vec<Real>,
+    /// Produced energy with origin in generator i and used for EPB services (directly or stored).
+    /// Adds up to `produced_used_EPus` in each timestep
+    pub produced_used_EPus_bygen: HashMap<CSubtype, Vec<Real>>,
+    /// Produced energy directly used for EPB services, by use (only with service by service compensation)
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub produced_used_EPus_an_byuse: HashMap<Service, Real>,
     /// Load matching factor
-    pub f_match: Vec<f32>,
+    pub f_match: Vec<Real>,
+    /// Produced energy charged into storage in each timestep (empty without storage)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stored: Vec<Real>,
+    /// Stored energy discharged for EPB services in each timestep (empty without storage)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub stored_used: Vec<Real>,
     /// Exported energy to the grid and non EPB uses in each timestep
-    pub exported: Vec<f32>, // exp_used_nEPus + exp_grid
+    pub exported: Vec<Real>, // exp_used_nEPus + exp_grid
     /// Exported energy to the grid and non EPB uses
-    pub exported_an: f32,
+    pub exported_an: Real,
     /// Exported energy to the grid and non EPB uses in each timestep, by generation source
-    pub exported_bygen: HashMap<CSubtype, Vec<f32>>, // cambiado origin -> gen
+    pub exported_bygen: HashMap<CSubtype, Vec<Real>>, // cambiado origin -> gen
     /// Exported energy to the grid and non EPB uses, by generation source
-    pub exported_bygen_an: HashMap<CSubtype, f32>, // cambiado origin -> gen
+    pub exported_bygen_an: HashMap<CSubtype, Real>, // cambiado origin -> gen
     /// Exported energy to the grid in each timestep
-    pub exported_grid: Vec<f32>,
+    pub exported_grid: Vec<Real>,
     /// Exported energy to the grid
-    pub exported_grid_an: f32,
+    pub exported_grid_an: Real,
     /// Exported energy to non EPB uses in each timestep
-    pub exported_nEPB: Vec<f32>,
+    pub exported_nEPB: Vec<Real>,
     /// Exported energy to non EPB uses
-    pub exported_nEPB_an: f32,
+    pub exported_nEPB_an: Real,
     /// Delivered energy by the grid in each timestep
-    pub delivered_grid: Vec<f32>,
+    pub delivered_grid: Vec<Real>,
     /// Delivered energy by the grid
-    pub delivered_grid_an: f32,
+    pub delivered_grid_an: Real,
     /// Weighted delivered energy by the grid
     pub we_delivered_grid_an: RenNrenCo2,
     /// Weighted delivered energy by any energy production sources
@@ -276,6 +573,9 @@ pub struct BalanceForCarrier {
     pub we_exported_an_AB: RenNrenCo2,
     /// Weighted exported energy for calculation step A+B
     pub we_exported_an: RenNrenCo2,
+    /// Weighted grid resources avoided by exported energy (step B term, k_exp * we_exported_an_AB,
+    /// with k_exp for each destination)
+    pub we_exported_an_avoided: RenNrenCo2,
     /// Weighted energy for calculation step A
     pub we_an_A: RenNrenCo2,
     /// Weighted energy for calculation step A, by use (for EPB services)
@@ -284,6 +584,396 @@ pub struct BalanceForCarrier {
     pub we_an: RenNrenCo2,
     /// Weighted energy, by use (for EPB services)
     pub we_an_byuse: HashMap<Service, RenNrenCo2>,
+    /// Weighted delivered energy by the grid and any energy production sources in each timestep
+    #[serde(default)]
+    pub we_delivered_t: Vec<RenNrenCo2>,
+    /// Weighted exported energy for calculation step A in each timestep
+    #[serde(default)]
+    pub we_exported_A_t: Vec<RenNrenCo2>,
+    /// Weighted exported energy for calculation step A+B in each timestep
+    #[serde(default)]
+    pub we_exported_t: Vec<RenNrenCo2>,
+}
+
+/// Energía ponderada de un vector energético, para cualquier magnitud ponderada
+///
+/// Weighted energy results for a carrier, for any weighted quantity (see `WeightedQuantity`),
+/// such as `RenNrenCo2`, a scalar indicator (`Real`, e.g. costs) or several channels (`Weighted<N>`)
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WeightedBalanceForCarrier<W> {
+    /// Weighted delivered energy by the grid
+    pub delivered_grid_an: W,
+    /// Weighted delivered energy by any energy production sources
+    pub delivered_prod_an: W,
+    /// Weighted delivered energy by the grid and any energy production sources
+    pub delivered_an: W,
+    /// Weighted exported energy for calculation step A
+    pub exported_an_A: W,
+    /// Weighted exported energy for non EPB uses and calculation step AB
+    pub exported_nEPB_an_AB: W,
+    /// Weighted exported energy to the grid and calculation step AB
+    pub exported_grid_an_AB: W,
+    /// Weighted exported energy and calculation step AB
+    pub exported_an_AB: W,
+    /// Weighted exported energy for calculation step A+B
+    pub exported_an: W,
+    /// Weighted grid resources avoided by exported energy (step B term)
+    pub exported_an_avoided: W,
+    /// Weighted energy for calculation step A
+    pub an_A: W,
+    /// Weighted energy for calculation step A, by use (for EPB services)
+    pub an_A_byuse: HashMap<Service, W>,
+    /// Weighted energy
+    pub an: W,
+    /// Weighted energy, by use (for EPB services)
+    pub an_byuse: HashMap<Service, W>,
+}
+
+/// Energía suministrada a un vector, por fuente (red o producción)
+///
+/// Delivered energy for a carrier, by source (grid or produced energy used for EPB services)
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DeliveredBySource<'a> {
+    /// Delivered energy by the grid in each timestep
+    pub grid: &'a [Real],
+    /// Delivered energy by the grid
+    pub grid_an: Real,
+    /// Delivered energy by production sources (produced and used for EPB services) in each timestep
+    pub produced: &'a [Real],
+    /// Weighted delivered energy by the grid
+    pub we_grid_an: RenNrenCo2,
+    /// Weighted delivered energy by production sources
+    pub we_produced_an: RenNrenCo2,
+    /// Weighted delivered energy by the grid and production sources
+    pub we_an: RenNrenCo2,
+}
+
+/// Energía producida de un vector, por origen (INSITU, COGENERACION)
+///
+/// Produced energy for a carrier, by origin
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ProducedByOrigin<'a> {
+    /// Produced energy (all origins) in each timestep
+    pub total: &'a [Real],
+    /// Produced energy (all origins)
+    pub total_an: Real,
+    /// Produced energy by origin in each timestep
+    pub by_origin: &'a HashMap<CSubtype, Vec<Real>>,
+    /// Produced energy by origin
+    pub by_origin_an: &'a HashMap<CSubtype, Real>,
+    /// Produced energy (all origins) used for EPB services in each timestep
+    pub used_EPus: &'a [Real],
+    /// Produced energy used for EPB services by origin in each timestep
+    pub used_EPus_by_origin: &'a HashMap<CSubtype, Vec<Real>>,
+    /// Load matching factor in each timestep
+    pub f_match: &'a [Real],
+}
+
+/// Energía exportada de un vector, por destino (red o usos no EPB)
+///
+/// Exported energy for a carrier, by destination (grid or non EPB uses)
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ExportedByDest<'a> {
+    /// Exported energy (all destinations) in each timestep
+    pub total: &'a [Real],
+    /// Exported energy (all destinations)
+    pub total_an: Real,
+    /// Exported energy to the grid in each timestep
+    pub grid: &'a [Real],
+    /// Exported energy to the grid
+    pub grid_an: Real,
+    /// Exported energy to non EPB uses in each timestep
+    pub nEPB: &'a [Real],
+    /// Exported energy to non EPB uses
+    pub nEPB_an: Real,
+    /// Exported energy by generation origin in each timestep
+    pub by_origin: &'a HashMap<CSubtype, Vec<Real>>,
+    /// Exported energy by generation origin
+    pub by_origin_an: &'a HashMap<CSubtype, Real>,
+    /// Weighted exported energy for calculation step A
+    pub we_A: RenNrenCo2,
+    /// Weighted exported energy to the grid for calculation step AB
+    pub we_grid_AB: RenNrenCo2,
+    /// Weighted exported energy to non EPB uses for calculation step AB
+    pub we_nEPB_AB: RenNrenCo2,
+    /// Weighted exported energy for calculation step AB
+    pub we_AB: RenNrenCo2,
+    /// Weighted grid resources avoided by exported energy (step B term)
+    pub we_avoided: RenNrenCo2,
+    /// Weighted exported energy for calculation step A+B
+    pub we_B: RenNrenCo2,
+}
+
+/// Energía ponderada de un vector en los pasos A y B, total y por servicio EPB
+///
+/// Weighted energy for a carrier in calculation steps A and B, total and by EPB service
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct WeightedBySteps<'a> {
+    /// Weighted delivered energy
+    pub delivered: RenNrenCo2,
+    /// Weighted exported energy for calculation step A
+    pub exported_A: RenNrenCo2,
+    /// Weighted exported energy for calculation step A+B
+    pub exported_B: RenNrenCo2,
+    /// Weighted energy for calculation step A (delivered - exported_A)
+    pub A: RenNrenCo2,
+    /// Weighted energy for calculation step A, by EPB service
+    pub A_byuse: &'a HashMap<Service, RenNrenCo2>,
+    /// Weighted energy for calculation step A+B (delivered - exported_B)
+    pub B: RenNrenCo2,
+    /// Weighted energy for calculation step A+B, by EPB service
+    pub B_byuse: &'a HashMap<Service, RenNrenCo2>,
+}
+
+/// Resumen anual de los flujos de energía de un vector y de su energía ponderada
+///
+/// Annual summary of delivered and exported energy for a carrier and their weighted contributions
+#[allow(non_snake_case)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CarrierSummary {
+    /// Energy carrier
+    pub carrier: Carrier,
+    /// Delivered energy by the grid
+    pub delivered_grid: Real,
+    /// Delivered energy by production sources (produced and used for EPB services)
+    pub delivered_onsite: Real,
+    /// Exported energy to the grid
+    pub exported_grid: Real,
+    /// Exported energy to non EPB uses
+    pub exported_nEPB: Real,
+    /// Weighted delivered energy by the grid
+    pub we_delivered_grid: RenNrenCo2,
+    /// Weighted delivered energy by production sources
+    pub we_delivered_onsite: RenNrenCo2,
+    /// Weighted exported energy for calculation step A
+    pub we_exported_A: RenNrenCo2,
+    /// Weighted grid resources avoided by exported energy (step B term)
+    pub we_exported_avoided: RenNrenCo2,
+    /// Weighted energy for calculation step A+B (delivered - exported)
+    pub we_B: RenNrenCo2,
+}
+
+/// Magnitud intermedia del balance de un vector energético, con la notación de la norma EN ISO 52000-1
+///
+/// Intermediate quantity of the energy balance for a carrier, by timestep and annual, labelled
+/// with its symbol in EN ISO 52000-1 so that results can be audited against the tables of the standard
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct IsoQuantity<'a> {
+    /// Symbol in EN ISO 52000-1 (e.g. `E_del;cr`)
+    pub symbol: &'static str,
+    /// Values in each timestep
+    pub values: &'a [Real],
+    /// Annual value
+    pub annual: Real,
+}
+
+/// Indicadores de autoconsumo de la energía producida de un vector energético
+///
+/// Self-consumption indicators of produced energy (on-site and cogeneration), computed from the
+/// energy balance of each timestep
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct SelfConsumption {
+    /// Produced energy
+    pub produced: Real,
+    /// Produced energy used on site, for EPB (directly or stored) and non EPB uses
+    pub self_consumed: Real,
+    /// Energy used for EPB and non EPB uses
+    pub used: Real,
+    /// Self-consumption ratio: self consumed / produced energy [0, 1]
+    pub self_consumption_ratio: Real,
+    /// Self-sufficiency ratio: self consumed / used energy [0, 1]
+    pub self_sufficiency_ratio: Real,
+}
+
+impl BalanceForCarrier {
+    /// Indicadores de autoconsumo de la energía producida del vector
+    ///
+    /// La energía autoconsumida es la producida que se usa en el edificio en cada paso de
+    /// cálculo, en usos EPB (directamente o tras su almacenamiento) o no EPB, es decir, la que
+    /// no se exporta a la red ni se pierde en el almacenamiento. El índice de autoconsumo es su
+    /// fracción de la energía producida y el de autosuficiencia, su fracción de la energía
+    /// consumida.
+    ///
+    /// Devuelve `None` si no hay producción del vector.
+    pub fn self_consumption(&self) -> Option<SelfConsumption> {
+        let produced: Real = self.produced.iter().sum();
+        if produced <= 0.0 {
+            return None;
+        }
+        let self_consumed =
+            self.produced_used_EPus.iter().sum::<Real>() + self.exported_nEPB.iter().sum::<Real>();
+        let used = self.used_EPB.iter().sum::<Real>() + self.used_nEPB.iter().sum::<Real>();
+        Some(SelfConsumption {
+            produced,
+            self_consumed,
+            used,
+            self_consumption_ratio: self_consumed / produced,
+            self_sufficiency_ratio: if used > 0.0 {
+                self_consumed / used
+            } else {
+                0.0
+            },
+        })
+    }
+
+    /// Calcula la energía ponderada del vector con otra magnitud ponderada, a partir de los flujos de energía
+    ///
+    /// Permite obtener indicadores distintos a los de energía primaria y emisiones (p.e. costes
+    /// o energía primaria total) usando el mismo cálculo del balance que estos. La función `weigh`
+    /// pondera la energía de cada paso de cálculo para una fuente, destino y paso A o B.
+    ///
+    /// * `k_exp`, `k_exp_nepb` - exported energy factors for energy exported to the grid and to nEPB uses [0, 1]
+    pub fn weighted_with<W, F>(
+        &self,
+        k_exp: Real,
+        k_exp_nepb: Real,
+        weigh: F,
+    ) -> Result<WeightedBalanceForCarrier<W>>
+    where
+        W: WeightedQuantity,
+        F: Fn(&[Real], Source, Dest, Step) -> Result<W>,
+    {
+        let used_an: Real = self.used_EPB.iter().sum();
+        let f_us_cr: HashMap<Service, Real> = if used_an != 0.0 {
+            self.used_EPB_an_byuse
+                .iter()
+                .map(|(service, used)| (*service, used / used_an))
+                .collect()
+        } else {
+            HashMap::new()
+        };
+        weigh_balance_for_carrier(self, &f_us_cr, k_exp, k_exp_nepb, weigh)
+    }
+
+    /// Resumen anual de la energía suministrada y exportada y de su energía ponderada
+    pub fn summary(&self) -> CarrierSummary {
+        CarrierSummary {
+            carrier: self.carrier.clone(),
+            delivered_grid: self.delivered_grid_an,
+            delivered_onsite: self.produced_used_EPus.iter().sum(),
+            exported_grid: self.exported_grid_an,
+            exported_nEPB: self.exported_nEPB_an,
+            we_delivered_grid: self.we_delivered_grid_an,
+            we_delivered_onsite: self.we_delivered_prod_an,
+            we_exported_A: self.we_exported_an_A,
+            we_exported_avoided: self.we_exported_an_avoided,
+            we_B: self.we_an,
+        }
+    }
+
+    /// Magnitudes intermedias del balance con la notación de la norma EN ISO 52000-1
+    ///
+    /// Incluye, por paso de cálculo y anual, la energía usada en servicios EPB (E_EPus;cr),
+    /// la energía producida (E_pr;cr), la energía producida usada en servicios EPB
+    /// (E_pr;cr;used;EPus), la exportada a usos no EPB (E_exp;cr;nEPus) y a la red
+    /// (E_exp;cr;grid) y la suministrada por la red (E_del;cr).
+    pub fn iso_quantities(&self) -> Vec<IsoQuantity<'_>> {
+        fn quantity<'a>(symbol: &'static str, values: &'a [Real]) -> IsoQuantity<'a> {
+            IsoQuantity {
+                symbol,
+                values,
+                annual: values.iter().sum(),
+            }
+        }
+        vec![
+            quantity("E_EPus;cr", &self.used_EPB),
+            quantity("E_pr;cr", &self.produced),
+            quantity("E_pr;cr;used;EPus", &self.produced_used_EPus),
+            quantity("E_exp;cr;nEPus", &self.exported_nEPB),
+            quantity("E_exp;cr;grid", &self.exported_grid),
+            quantity("E_del;cr", &self.delivered_grid),
+        ]
+    }
+
+    /// Energía suministrada, por fuente
+    pub fn delivered(&self) -> DeliveredBySource<'_> {
+        DeliveredBySource {
+            grid: &self.delivered_grid,
+            grid_an: self.delivered_grid_an,
+            produced: &self.produced_used_EPus,
+            we_grid_an: self.we_delivered_grid_an,
+            we_produced_an: self.we_delivered_prod_an,
+            we_an: self.we_delivered_an,
+        }
+    }
+
+    /// Energía producida, por origen
+    pub fn produced(&self) -> ProducedByOrigin<'_> {
+        ProducedByOrigin {
+            total: &self.produced,
+            total_an: self.produced_an,
+            by_origin: &self.produced_bygen,
+            by_origin_an: &self.produced_bygen_an,
+            used_EPus: &self.produced_used_EPus,
+            used_EPus_by_origin: &self.produced_used_EPus_bygen,
+            f_match: &self.f_match,
+        }
+    }
+
+    /// Energía exportada, por destino
+    pub fn exported(&self) -> ExportedByDest<'_> {
+        ExportedByDest {
+            total: &self.exported,
+            total_an: self.exported_an,
+            grid: &self.exported_grid,
+            grid_an: self.exported_grid_an,
+            nEPB: &self.exported_nEPB,
+            nEPB_an: self.exported_nEPB_an,
+            by_origin: &self.exported_bygen,
+            by_origin_an: &self.exported_bygen_an,
+            we_A: self.we_exported_an_A,
+            we_grid_AB: self.we_exported_grid_an_AB,
+            we_nEPB_AB: self.we_exported_nEPB_an_AB,
+            we_AB: self.we_exported_an_AB,
+            we_avoided: self.we_exported_an_avoided,
+            we_B: self.we_exported_an,
+        }
+    }
+
+    /// Energía ponderada en los pasos A y B
+    pub fn weighted(&self) -> WeightedBySteps<'_> {
+        WeightedBySteps {
+            delivered: self.we_delivered_an,
+            exported_A: self.we_exported_an_A,
+            exported_B: self.we_exported_an,
+            A: self.we_an_A,
+            A_byuse: &self.we_an_A_byuse,
+            B: self.we_an,
+            B_byuse: &self.we_an_byuse,
+        }
+    }
+}
+
+impl Balance {
+    /// Resultados detallados del balance para un vector energético, si se ha evaluado
+    pub fn carrier(&self, carrier: Carrier) -> Option<&BalanceForCarrier> {
+        self.balance_cr.get(&carrier)
+    }
+
+    /// Resumen anual de la energía suministrada y exportada de cada vector y de su energía
+    /// ponderada, ordenado por vector energético
+    pub fn summary_by_carrier(&self) -> Vec<CarrierSummary> {
+        let mut summary: Vec<CarrierSummary> =
+            self.balance_cr.values().map(|bal| bal.summary()).collect();
+        summary.sort_by(|a, b| a.carrier.cmp(&b.carrier));
+        summary
+    }
+
+    /// Magnitudes intermedias del balance de cada vector con la notación de la norma
+    /// EN ISO 52000-1 (ver `BalanceForCarrier::iso_quantities`), ordenadas por vector energético
+    pub fn iso_quantities_by_carrier(&self) -> Vec<(Carrier, Vec<IsoQuantity<'_>>)> {
+        let mut quantities: Vec<_> = self
+            .balance_cr
+            .values()
+            .map(|bal| (bal.carrier.clone(), bal.iso_quantities()))
+            .collect();
+        quantities.sort_by(|a, b| a.0.cmp(&b.0));
+        quantities
+    }
 }
 
 // --------------------------------------------------------------------
@@ -292,6 +982,204 @@ pub struct BalanceForCarrier {
 
 // ///////////// By Carrier timestep and annual computations ////////////
 
+/// Calcula la eficiencia energética de cada anualidad de un balance plurianual
+///
+/// Compute the energy performance of each annual period of a multi-year balance.
+///
+/// Las anualidades se definen en los metadatos de los componentes (ver
+/// `Components::annual_periods`) y cada una se evalúa con los mismos factores de paso,
+/// factores de exportación y área de referencia que el balance agregado. El propio balance
+/// constituye el agregado de todo el periodo de cálculo.
+///
+/// Devuelve una lista vacía si no se definen anualidades.
+///
+/// # Errors
+///
+/// * Definición incorrecta de las anualidades
+/// * Missing weighting factors needed for balance computation
+///
+pub fn energy_performance_by_year(balance: &Balance) -> Result<Vec<(String, Balance)>> {
+    let periods = match balance.components.annual_periods()? {
+        Some(periods) => periods,
+        None => return Ok(Vec::new()),
+    };
+    periods
+        .into_iter()
+        .map(|(label, steps)| {
+            let components = balance.components.select_steps(steps);
+            let year_balance = energy_performance_with_load_matching(
+                &components,
+                &balance.wfactors,
+                balance.k_exp,
+                balance.k_exp_nepb,
+                balance.arearef,
+                balance.load_matching,
+            )?;
+            Ok((label, year_balance))
+        })
+        .collect()
+}
+
+/// Calcula la eficiencia energética del edificio de referencia definido en los componentes
+///
+/// Compute the energy performance of the reference building described by the components of
+/// fictitious or reference systems (negative system ids, see `Components::reference`).
+///
+/// El edificio de referencia se evalúa con los mismos factores de paso, factores de exportación,
+/// área de referencia y método de cálculo del factor de coincidencia de cargas que el balance.
+///
+/// Devuelve `None` si no hay componentes de referencia.
+///
+/// # Errors
+///
+/// * Missing weighting factors needed for balance computation
+///
+pub fn energy_performance_reference(balance: &Balance) -> Result<Option<Balance>> {
+    balance
+        .components
+        .reference()
+        .map(|components| {
+            energy_performance_with_load_matching(
+                &components,
+                &balance.wfactors,
+                balance.k_exp,
+                balance.k_exp_nepb,
+                balance.arearef,
+                balance.load_matching,
+            )
+        })
+        .transpose()
+}
+
+/// Factores de exportación entre 0.0 y 1.0 con el paso indicado, incluidos ambos extremos
+///
+/// # Errors
+///
+/// * Paso no comprendido en el intervalo (0, 1]
+pub fn kexp_range(step: Real) -> Result<Vec<Real>> {
+    if !(step > 0.0 && step <= 1.0) {
+        return Err(EpbdError::WrongInput(format!(
+            "El paso del factor de exportación debe estar en el intervalo (0, 1] y se encontró {}",
+            step
+        )));
+    }
+    let num_steps = (1.0 / step - 1e-3).ceil() as usize;
+    Ok((0..num_steps)
+        .map(|i| i as Real * step)
+        .chain(std::iter::once(1.0))
+        .collect())
+}
+
+/// Calcula el balance de los mismos datos con distintos factores de exportación
+///
+/// Se usan los componentes, factores de paso, área de referencia y método de cálculo del
+/// factor de coincidencia de cargas del balance y cada factor de exportación se aplica a la
+/// energía exportada a la red y a usos no EPB. Permite evaluar la sensibilidad de los
+/// indicadores al factor de exportación (ver `kexp_range`). Los balances se devuelven con su
+/// factor de exportación y en el mismo orden.
+///
+/// # Errors
+///
+/// * Factor de exportación no comprendido en el intervalo [0, 1]
+/// * Missing weighting factors needed for balance computation
+///
+pub fn energy_performance_by_kexp(
+    balance: &Balance,
+    k_exps: &[Real],
+) -> Result<Vec<(Real, Balance)>> {
+    k_exps
+        .iter()
+        .map(|&k_exp| {
+            if !(0.0..=1.0).contains(&k_exp) {
+                return Err(EpbdError::WrongInput(format!(
+                    "El factor de exportación debe estar en el intervalo [0, 1] y se encontró {}",
+                    k_exp
+                )));
+            }
+            let kexp_balance = energy_performance_with_load_matching(
+                &balance.components,
+                &balance.wfactors,
+                k_exp,
+                k_exp,
+                balance.arearef,
+                balance.load_matching,
+            )?;
+            Ok((k_exp, kexp_balance))
+        })
+        .collect()
+}
+
+/// Escenario de cálculo para la comparación de alternativas
+///
+/// Calculation scenario (weighting factors, exported energy factor and assessment perimeter)
+#[derive(Debug, Clone)]
+pub struct Scenario {
+    /// Scenario name
+    pub name: String,
+    /// Weighting factors
+    pub wfactors: Factors,
+    /// Exported energy factor [0, 1]
+    pub k_exp: Real,
+    /// Assessment perimeter the weighting factors are converted to (`None` to use them as defined)
+    pub perimeter: Option<Perimeter>,
+}
+
+/// Calcula el balance de los mismos componentes en varios escenarios
+///
+/// Cada escenario define sus factores de paso, su factor de exportación y, opcionalmente, el
+/// perímetro de evaluación al que se convierten los factores de paso (ver `Factors::to_perimeter`).
+/// Permite comparar alternativas (p.e. de rehabilitación o de versiones de los factores de paso)
+/// con un mismo cálculo. Los balances se devuelven con el nombre de su escenario y en el mismo
+/// orden (ver `cte::scenarios_comparison`).
+///
+/// # Errors
+///
+/// * Use of an `arearef` less than 1e-3 raises an error
+/// * Conversión no disponible de los factores de paso al perímetro del escenario
+/// * Missing weighting factors needed for balance computation
+///
+pub fn energy_performance_by_scenario(
+    components: &Components,
+    scenarios: &[Scenario],
+    arearef: Real,
+) -> Result<Vec<(String, Balance)>> {
+    scenarios
+        .iter()
+        .map(|scenario| {
+            let converted;
+            let wfactors = match scenario.perimeter {
+                Some(perimeter) => {
+                    converted = scenario.wfactors.to_perimeter(perimeter)?;
+                    &converted
+                }
+                None => &scenario.wfactors,
+            };
+            let balance = energy_performance(components, wfactors, scenario.k_exp, arearef)?;
+            Ok((scenario.name.clone(), balance))
+        })
+        .collect()
+}
+
+/// Opciones de cálculo del balance energético de un vector energético
+#[derive(Debug, Copy, Clone)]
+struct CarrierBalanceSettings<'a> {
+    /// Exported energy factor for energy exported to the grid [0, 1]
+    k_exp_grid: Real,
+    /// Exported energy factor for energy exported to nEPB uses [0, 1]
+    k_exp_nepb: Real,
+    /// Load matching factor calculation method for electricity
+    load_matching: LoadMatching,
+    /// Production origins in priority order to cover EPB uses (`None` for no priority)
+    priority: Option<&'a [CSubtype]>,
+    /// Storage of on-site produced energy, by carrier
+    storages: &'a [(Carrier, Storage)],
+    /// Compensate produced energy service by service instead of for the whole carrier
+    by_service: bool,
+    /// Services in priority order to allocate NDEF production with service by service compensation
+    /// (`None` for allocation proportional to use)
+    service_priority: Option<&'a [Service]>,
+}
+
 /// Calcula balance energético para un vector energético
 ///
 /// Calculate energy balance for carrier.
@@ -300,8 +1188,10 @@ pub struct BalanceForCarrier {
 /// exported and weighted energy balance.
 ///
 /// * `cr_list` - list of components for carrier
-/// * `k_exp` - exported energy factor [0, 1]
-/// * `fp_cr` - weighting factors for carrier
+/// * `wf_index` - weighting factors, indexed by carrier, source, destination and step
+/// * `fs_cr` - time-varying weighting factors for carrier, used instead of the constant ones
+/// * `settings` - exported energy factors, load matching method, production priority, storage and
+///   service by service compensation
 ///
 /// # Errors
 ///
@@ -313,70 +1203,124 @@ pub struct BalanceForCarrier {
 ///   dos generadores como mucho. La norma hace el reparto de la energía producida (14) por generador i
 ///   y si implementamos el soporte generador a generador habría que revisar esto. En particular,
 ///   cómo se calcula f_pr_cr_i.
-/// - Implementar factor de reparto de carga f_match_t
 #[allow(non_snake_case)]
 fn balance_for_carrier(
     carrier: Carrier,
-    cr_list: &[Component],
-    fp_cr: &[Factor],
-    k_exp: f32,
+    cr_list: &[&Component],
+    wf_index: &HashMap<FactorKey, RenNrenCo2>,
+    fs_cr: &[FactorSeries],
+    settings: CarrierBalanceSettings,
 ) -> Result<BalanceForCarrier> {
+    let CarrierBalanceSettings {
+        k_exp_grid,
+        k_exp_nepb,
+        load_matching,
+        priority,
+        storages,
+        by_service,
+        service_priority,
+    } = settings;
+
     // We know all carriers have the same timesteps (see FromStr for Components)
     let num_steps = cr_list[0].values.len();
 
     // * Energy used by technical systems for EPB services, for each time step
-    let E_EPus_cr_t = cr_list
-        .iter()
-        .filter(|e| e.ctype == CType::CONSUMO && e.csubtype == CSubtype::EPB)
-        .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
+    let E_EPus_cr_t = vecitersum(
+        num_steps,
+        cr_list
+            .iter()
+            .filter(|e| e.is_used_energy() && e.csubtype == CSubtype::EPB)
+            .map(|e| &e.values),
+    );
 
     // * Energy used by technical systems for non-EPB services, for each time step
-    let E_nEPus_cr_t = cr_list
-        .iter()
-        .filter(|e| e.ctype == CType::CONSUMO && e.csubtype == CSubtype::NEPB)
-        .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
+    let E_nEPus_cr_t = vecitersum(
+        num_steps,
+        cr_list
+            .iter()
+            .filter(|e| e.is_used_energy() && e.csubtype == CSubtype::NEPB)
+            .map(|e| &e.values),
+    );
 
     // * Produced on-site energy and inside the assessment boundary, by generator i (origin i)
-    let mut E_pr_cr_i_t = HashMap::<CSubtype, Vec<f32>>::new();
+    let mut E_pr_cr_i_t = HashMap::<CSubtype, Vec<Real>>::new();
     for comp in cr_list
         .iter()
         .filter(|comp| comp.ctype == CType::PRODUCCION)
     {
         E_pr_cr_i_t
             .entry(comp.csubtype)
-            .and_modify(|e| *e = vecvecsum(e, &comp.values))
+            .and_modify(|e| vecvecsum_assign(e, &comp.values))
             .or_insert_with(|| comp.values.clone());
     }
 
     // PRODUCED ENERGY GENERATORS (CSubtype::INSITU or CSubtype::COGENERACION)
     // generators are unique in this list
-    let pr_generators: Vec<CSubtype> = E_pr_cr_i_t.keys().cloned().collect(); // INSITU, COGENERACION
+    let mut pr_generators: Vec<CSubtype> = E_pr_cr_i_t.keys().cloned().collect(); // INSITU, COGENERACION
+    pr_generators.sort();
 
     // Annually produced on-site energy from generator i (origin i)
-    let mut E_pr_cr_i_an = HashMap::<CSubtype, f32>::new();
+    let mut E_pr_cr_i_an = HashMap::<CSubtype, Real>::new();
     for gen in &pr_generators {
         E_pr_cr_i_an.insert(*gen, vecsum(&E_pr_cr_i_t[gen]));
     }
 
     // * Energy produced on-site and inside the assessment boundary (formula 30)
-    let mut E_pr_cr_t = vec![0.0; num_steps];
-    for gen in &pr_generators {
-        E_pr_cr_t = vecvecsum(&E_pr_cr_t, &E_pr_cr_i_t[gen])
-    }
+    let E_pr_cr_t = vecitersum(num_steps, pr_generators.iter().map(|gen| &E_pr_cr_i_t[gen]));
     let E_pr_cr_an = vecsum(&E_pr_cr_t);
 
     // * Produced energy from all origins for EPB services for each time step (formula 31)
-    // TODO: f_match_t constant for electricity (formula 32)
-    // TODO: let f_match_t = fmatch(E_pr_cr_t / E_EPus_cr_t)
-    let f_match_t = vec![1.0; num_steps];
+    // Load matching factor for electricity (formula 32, B.32), unit factor for other carriers
+    let f_match_t: Vec<Real> = if carrier == Carrier::ELECTRICIDAD {
+        E_pr_cr_t
+            .iter()
+            .zip(E_EPus_cr_t.iter())
+            .map(|(pr, us)| load_matching.factor(*pr, *us))
+            .collect()
+    } else {
+        vec![1.0; num_steps]
+    };
+
+    // Produced energy directly used for EPB services in the same timestep
+    // With service by service compensation, production only covers the EPB use of its service
+    let (E_pr_cr_direct_EPus_t, E_pr_cr_direct_EPus_an_byuse) = if by_service {
+        produced_used_EPus_by_service(
+            num_steps,
+            cr_list,
+            &f_match_t,
+            service_priority.unwrap_or_default(),
+        )
+    } else {
+        let mut E_pr_cr_direct_EPus_t = E_pr_cr_t.clone();
+        vecvecmul_assign(&mut E_pr_cr_direct_EPus_t, &f_match_t);
+        vecvecmin_assign(&mut E_pr_cr_direct_EPus_t, &E_EPus_cr_t);
+        (E_pr_cr_direct_EPus_t, HashMap::new())
+    };
 
-    let E_pr_cr_used_EPus_t = vecvecmul(&f_match_t, &vecvecmin(&E_EPus_cr_t, &E_pr_cr_t));
+    // Produced energy not directly used for EPB services in the same timestep (stored or exported)
+    let E_pr_cr_surplus_t = vecvecdif(&E_pr_cr_t, &E_pr_cr_direct_EPus_t);
+
+    // * Energy storage (battery or thermal storage): the produced energy not directly used charges
+    // the storage and the stored energy covers EPB uses in later timesteps (empty vectors without storage)
+    let (E_st_cr_in_t, E_st_cr_out_t) = match storages.iter().find(|(c, _)| *c == carrier) {
+        Some((_, storage)) => storage.operate(
+            &E_pr_cr_surplus_t,
+            &vecvecdif(&E_EPus_cr_t, &E_pr_cr_direct_EPus_t),
+        ),
+        _ => (vec![], vec![]),
+    };
 
     // * Exported energy for each time step (produced energy not consumed in EPB uses) (formula 33)
     // E_pr_cr_t = E_pr_cr_used_EPus_t + E_exp_cr_used_nEPus_t + E_exp_cr_grid_t
     // E_exp_cr_t = E_exp_cr_used_nEPus_t + E_exp_cr_grid_t
     // -> E_exp_cr_t = E_pr_cr_t - E_pr_cr_used_EPus_t
-    let E_exp_cr_t = vecvecdif(&E_pr_cr_t, &E_pr_cr_used_EPus_t);
+    // With storage, the energy charged into the storage is not exported
+    let mut E_pr_cr_used_EPus_t = E_pr_cr_direct_EPus_t.clone();
+    let mut E_exp_cr_t = E_pr_cr_surplus_t;
+    if !E_st_cr_in_t.is_empty() {
+        vecvecsum_assign(&mut E_pr_cr_used_EPus_t, &E_st_cr_out_t);
+        vecvecdif_assign(&mut E_exp_cr_t, &E_st_cr_in_t);
+    }
 
     // * Exported energy used for non-EPB uses for each time step (formula 34)
     let E_exp_cr_used_nEPus_t = vecvecmin(&E_exp_cr_t, &E_nEPus_cr_t);
@@ -399,12 +1343,12 @@ fn balance_for_carrier(
     // ** Weighting depending on energy generator **
 
     // Exported energy by generator i (origin) (9.6.6.2)
-    // Implementation WITHOUT priorities on energy use
+    // Implementation WITHOUT priorities on energy use, unless a priority is given (9.6.6.2.3)
 
     // * Fraction of produced energy of type i (origin from generator i) (formula 14)
     // FIXME: ¿El factor de reparto debería hacerse para cada componente de ese tipo y no agrupado por tipo
     // FIXME: aunque la agrupación por tipo sea útil para ver orígenes?
-    let mut f_pr_cr_i = HashMap::<CSubtype, f32>::new();
+    let mut f_pr_cr_i = HashMap::<CSubtype, Real>::new();
     for gen in &pr_generators {
         let f = if E_pr_cr_an > 1e-3 {
             E_pr_cr_i_an[gen] / E_pr_cr_an
@@ -415,60 +1359,383 @@ fn balance_for_carrier(
     }
 
     // * Produced energy with origin from generator i and used for EPB services (formula 15)
-    let mut E_pr_cr_i_used_EPus_t = HashMap::<CSubtype, Vec<f32>>::new();
-    for gen in &pr_generators {
-        E_pr_cr_i_used_EPus_t.insert(*gen, veckmul(&E_pr_cr_used_EPus_t, f_pr_cr_i[gen]));
+    let mut E_pr_cr_i_used_EPus_t = HashMap::<CSubtype, Vec<Real>>::new();
+    match priority {
+        // Production used in EPB uses is allocated to generators in priority order, for each timestep
+        Some(priority) => {
+            let rank = |gen: &CSubtype| {
+                priority
+                    .iter()
+                    .position(|p| p == gen)
+                    .unwrap_or(priority.len())
+            };
+            let mut ordered_generators = pr_generators.clone();
+            ordered_generators.sort_by_key(rank);
+            let mut E_pending_EPus_t = E_pr_cr_direct_EPus_t.clone();
+            for gen in &ordered_generators {
+                let E_pr_cr_gen_used_EPus_t = vecvecmin(&E_pending_EPus_t, &E_pr_cr_i_t[gen]);
+                vecvecdif_assign(&mut E_pending_EPus_t, &E_pr_cr_gen_used_EPus_t);
+                E_pr_cr_i_used_EPus_t.insert(*gen, E_pr_cr_gen_used_EPus_t);
+            }
+        }
+        None => {
+            for gen in &pr_generators {
+                E_pr_cr_i_used_EPus_t.insert(*gen, veckmul(&E_pr_cr_direct_EPus_t, f_pr_cr_i[gen]));
+            }
+        }
     }
 
     // * Exported energy from generator i (origin i) (formula 16)
-    let mut E_exp_cr_i_t = HashMap::<CSubtype, Vec<f32>>::new();
-    for gen in &pr_generators {
-        E_exp_cr_i_t.insert(
-            *gen,
-            vecvecdif(&E_pr_cr_i_t[gen], &E_pr_cr_i_used_EPus_t[gen]),
-        );
+    // With storage, the produced energy not directly used is charged into the storage or exported,
+    // and is allocated to generator i by its share in the surplus of each timestep. The stored energy
+    // discharged for later EPB uses is allocated to generator i by its share in the energy charged
+    // up to that timestep, so that the produced energy used for EPB services by generator adds up to
+    // the produced energy used for EPB services (storage losses are neither used nor exported)
+    let mut E_exp_cr_i_t = HashMap::<CSubtype, Vec<Real>>::new();
+    if E_st_cr_in_t.is_empty() {
+        for gen in &pr_generators {
+            E_exp_cr_i_t.insert(
+                *gen,
+                vecvecdif(&E_pr_cr_i_t[gen], &E_pr_cr_i_used_EPus_t[gen]),
+            );
+        }
+    } else {
+        let mut E_st_cr_i_in_t = HashMap::<CSubtype, Vec<Real>>::new();
+        for gen in &pr_generators {
+            let E_pr_cr_i_direct_t = &E_pr_cr_i_used_EPus_t[gen];
+            let f_surplus_cr_i_t: Vec<Real> = (0..num_steps)
+                .map(|t| {
+                    let surplus = E_pr_cr_t[t] - E_pr_cr_direct_EPus_t[t];
+                    if surplus > 1e-6 {
+                        (E_pr_cr_i_t[gen][t] - E_pr_cr_i_direct_t[t]) / surplus
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+            E_st_cr_i_in_t.insert(*gen, vecvecmul(&E_st_cr_in_t, &f_surplus_cr_i_t));
+            E_exp_cr_i_t.insert(*gen, vecvecmul(&E_exp_cr_t, &f_surplus_cr_i_t));
+        }
+        let mut E_st_cr_in_acc = 0.0;
+        let mut E_st_cr_i_in_acc: HashMap<CSubtype, Real> =
+            pr_generators.iter().map(|gen| (*gen, 0.0)).collect();
+        for t in 0..num_steps {
+            E_st_cr_in_acc += E_st_cr_in_t[t];
+            for gen in &pr_generators {
+                let acc = E_st_cr_i_in_acc.get_mut(gen).unwrap();
+                *acc += E_st_cr_i_in_t[gen][t];
+                if E_st_cr_in_acc > 1e-6 {
+                    if let Some(E_pr_cr_i_used_t) = E_pr_cr_i_used_EPus_t.get_mut(gen) {
+                        E_pr_cr_i_used_t[t] += E_st_cr_out_t[t] * *acc / E_st_cr_in_acc;
+                    }
+                }
+            }
+        }
     }
 
     // * Annually exported energy from generator i (origin i)
-    let mut E_exp_cr_i_an = HashMap::<CSubtype, f32>::new();
+    let mut E_exp_cr_i_an = HashMap::<CSubtype, Real>::new();
     for gen in &pr_generators {
         E_exp_cr_i_an.insert(*gen, vecsum(&E_exp_cr_i_t[gen]));
     }
 
+    // ================ Compute values by use ===============
+    // Compute fraction of used energy by use (for EPB services):
+    // used energy for service_i / used energy for all services)
+    let f_us_cr = compute_factors_by_use_cr(cr_list);
+    // Annual energy use for carrier
+    let E_EPus_cr_an: Real = E_EPus_cr_t.iter().sum();
+
+    // Used (final) energy for each use item (for EPB services)
+    let mut E_Epus_cr_an_byuse: HashMap<Service, Real> = HashMap::new();
+    for service in &SERVICES {
+        let f_us_k_cr = *f_us_cr.get(service).unwrap_or(&0.0);
+        if f_us_k_cr != 0.0 {
+            E_Epus_cr_an_byuse.insert(*service, E_EPus_cr_an * f_us_k_cr);
+        }
+    }
+
+    // Auxiliary energy used for each use item (for EPB services)
+    let mut E_EPus_aux_cr_an_byuse: HashMap<Service, Real> = HashMap::new();
+    for comp in cr_list
+        .iter()
+        .filter(|e| e.aux && e.is_used_energy() && e.csubtype == CSubtype::EPB)
+    {
+        *E_EPus_aux_cr_an_byuse.entry(comp.service).or_default() += vecsum(&comp.values);
+    }
+
+    let mut bal = BalanceForCarrier {
+        carrier: carrier.clone(),
+        used_EPB: E_EPus_cr_t,
+        used_EPB_an_byuse: E_Epus_cr_an_byuse,
+        used_EPB_aux_an_byuse: E_EPus_aux_cr_an_byuse,
+        used_nEPB: E_nEPus_cr_t,
+        produced: E_pr_cr_t,
+        produced_an: E_pr_cr_an,
+        produced_bygen: E_pr_cr_i_t,
+        produced_bygen_an: E_pr_cr_i_an,
+        produced_used_EPus: E_pr_cr_used_EPus_t,
+        produced_used_EPus_bygen: E_pr_cr_i_used_EPus_t,
+        produced_used_EPus_an_byuse: E_pr_cr_direct_EPus_an_byuse,
+        f_match: f_match_t, // load matching factor
+        stored: E_st_cr_in_t,
+        stored_used: E_st_cr_out_t,
+        exported: E_exp_cr_t, // exp_used_nEPus + exp_grid
+        exported_an: E_exp_cr_used_nEPus_an + E_exp_cr_grid_an,
+        exported_bygen: E_exp_cr_i_t,
+        exported_bygen_an: E_exp_cr_i_an,
+        exported_grid: E_exp_cr_grid_t,
+        exported_grid_an: E_exp_cr_grid_an,
+        exported_nEPB: E_exp_cr_used_nEPus_t,
+        exported_nEPB_an: E_exp_cr_used_nEPus_an,
+        delivered_grid: E_del_cr_t,
+        delivered_grid_an: E_del_cr_an,
+        // Weighted energy: { ren, nren, co2 }, computed below
+        we_delivered_grid_an: RenNrenCo2::default(),
+        we_delivered_prod_an: RenNrenCo2::default(),
+        we_delivered_an: RenNrenCo2::default(),
+        we_exported_an_A: RenNrenCo2::default(),
+        we_exported_nEPB_an_AB: RenNrenCo2::default(),
+        we_exported_grid_an_AB: RenNrenCo2::default(),
+        we_exported_an_AB: RenNrenCo2::default(),
+        we_exported_an: RenNrenCo2::default(),
+        we_exported_an_avoided: RenNrenCo2::default(),
+        we_an_A: RenNrenCo2::default(),
+        we_an_A_byuse: HashMap::new(),
+        we_an: RenNrenCo2::default(),
+        we_an_byuse: HashMap::new(),
+        we_delivered_t: Vec::new(),
+        we_exported_A_t: Vec::new(),
+        we_exported_t: Vec::new(),
+    };
+
     // -------- Weighted delivered and exported energy (11.6.2.1, 11.6.2.2, 11.6.2.3 + eq 2, 3)
-    // NOTE: All weighting factors have been considered constant through all timesteps
-    // NOTE: This allows using annual quantities and not timestep expressions
+    // NOTE: Constant weighting factors allow using annual quantities and not timestep expressions
+    // NOTE: Time-varying weighting factors, when available, weight energy timestep by timestep
 
     // Find weighting factor for 'step' of energy exported to 'dest' from the given energy 'source'.
     //
-    // * `fp_cr` - weighting factor list for a given energy carrier where search is done
+    // * `wf_index` - weighting factors index where search is done
+    // * `carrier` - match this energy carrier
     // * `source` - match this energy source (`RED`, `INSITU`, `COGENERACION`)
     // * `dest` - match this energy destination (use)
     // * `step` - match this calculation step
-    fn fp_find(fp_cr: &[Factor], source: Source, dest: Dest, step: Step) -> Result<&Factor> {
-        fp_cr
-            .iter()
-            .find(|fp| fp.source == source && fp.dest == dest && fp.step == step)
-            .ok_or_else(|| {
-                EpbdError::MissingFactor(format!(
-                    "'{}, {}, {}, {}'",
-                    fp_cr[0].carrier, source, dest, step
-                ))
+    fn fp_find(
+        wf_index: &HashMap<FactorKey, RenNrenCo2>,
+        carrier: &Carrier,
+        source: Source,
+        dest: Dest,
+        step: Step,
+    ) -> Result<RenNrenCo2> {
+        wf_index
+            .get(&(carrier.clone(), source, dest, step))
+            .copied()
+            .ok_or_else(|| EpbdError::MissingFactor {
+                carrier: carrier.clone(),
+                source,
+                dest,
+                step,
             })
     }
 
+    // Time-varying weighting factors for 'source', 'dest' and 'step', if available, checking
+    // they have as many values as timesteps the given energy.
+    let find_series = |E_t: &[Real], source: Source, dest: Dest, step: Step| match fs_cr
+        .iter()
+        .find(|fs| fs.source == source && fs.dest == dest && fs.step == step)
+    {
+        Some(fs) if fs.values.len() != E_t.len() => Err(EpbdError::BalanceError(format!(
+            "El factor de paso variable '{}, {}, {}, {}' tiene {} valores y se esperaban {}",
+            carrier,
+            source,
+            dest,
+            step,
+            fs.values.len(),
+            E_t.len()
+        ))),
+        fs => Ok(fs),
+    };
+
+    // Weighted energy for the given energy in each timestep, using time-varying weighting factors
+    // for 'source', 'dest' and 'step' if available, or the constant weighting factor otherwise.
+    let weigh = |E_t: &[Real], source: Source, dest: Dest, step: Step| -> Result<RenNrenCo2> {
+        match find_series(E_t, source, dest, step)? {
+            Some(fs) => Ok(fs.weigh(E_t)),
+            None => Ok(vecsum(E_t) * fp_find(wf_index, &carrier, source, dest, step)?),
+        }
+    };
+
+    // Weighted energy in each timestep for the given energy in each timestep
+    let weigh_t =
+        |E_t: &[Real], source: Source, dest: Dest, step: Step| -> Result<Vec<RenNrenCo2>> {
+            match find_series(E_t, source, dest, step)? {
+                Some(fs) => Ok(E_t.iter().zip(&fs.values).map(|(e, f)| *f * *e).collect()),
+                None => {
+                    let f = fp_find(wf_index, &carrier, source, dest, step)?;
+                    Ok(E_t.iter().map(|e| f * *e).collect())
+                }
+            }
+        };
+
+    let we = weigh_balance_for_carrier(&bal, &f_us_cr, k_exp_grid, k_exp_nepb, weigh)?;
+    bal.we_delivered_grid_an = we.delivered_grid_an;
+    bal.we_delivered_prod_an = we.delivered_prod_an;
+    bal.we_delivered_an = we.delivered_an;
+    bal.we_exported_an_A = we.exported_an_A;
+    bal.we_exported_nEPB_an_AB = we.exported_nEPB_an_AB;
+    bal.we_exported_grid_an_AB = we.exported_grid_an_AB;
+    bal.we_exported_an_AB = we.exported_an_AB;
+    bal.we_exported_an = we.exported_an;
+    bal.we_exported_an_avoided = we.exported_an_avoided;
+    bal.we_an_A = we.an_A;
+    bal.we_an_A_byuse = we.an_A_byuse;
+    bal.we_an = we.an;
+    bal.we_an_byuse = we.an_byuse;
+
+    let (we_delivered_t, we_exported_A_t, we_exported_t) =
+        weigh_balance_for_carrier_t(&bal, k_exp_grid, k_exp_nepb, weigh_t)?;
+    bal.we_delivered_t = we_delivered_t;
+    bal.we_exported_A_t = we_exported_A_t;
+    bal.we_exported_t = we_exported_t;
+
+    Ok(bal)
+}
+
+/// Calcula la energía producida usada directamente en usos EPB, compensando servicio a servicio
+///
+/// Compute produced energy directly used for EPB services when production is compensated service
+/// by service, for each timestep and, annually, by service. Production assigned to a service only
+/// covers the EPB use of that service. Production without an assigned service (NDEF) covers the
+/// remaining EPB use of the services in `priority`, in that order, and the rest is split among the
+/// other services by their share of their annual EPB use of the carrier. Production exceeding the
+/// use of its service is not used.
+///
+/// * `num_steps` - number of timesteps
+/// * `cr_list` - list of components for carrier
+/// * `f_match_t` - load matching factor in each timestep
+/// * `priority` - services in priority order to allocate NDEF production (empty for proportional split)
+#[allow(non_snake_case)]
+fn produced_used_EPus_by_service(
+    num_steps: usize,
+    cr_list: &[&Component],
+    f_match_t: &[Real],
+    priority: &[Service],
+) -> (Vec<Real>, HashMap<Service, Real>) {
+    // * Energy used for EPB services and produced energy, by service
+    let mut E_EPus_cr_s_t = HashMap::<Service, Vec<Real>>::new();
+    let mut E_pr_cr_s_t = HashMap::<Service, Vec<Real>>::new();
+    for comp in cr_list {
+        let by_service = if comp.is_used_energy() && comp.csubtype == CSubtype::EPB {
+            &mut E_EPus_cr_s_t
+        } else if comp.ctype == CType::PRODUCCION {
+            &mut E_pr_cr_s_t
+        } else {
+            continue;
+        };
+        by_service
+            .entry(comp.service)
+            .and_modify(|e| vecvecsum_assign(e, &comp.values))
+            .or_insert_with(|| comp.values.clone());
+    }
+
+    // * Production without an assigned service, available in each timestep
+    let mut E_pr_cr_ndef_t = E_pr_cr_s_t
+        .remove(&Service::NDEF)
+        .unwrap_or_else(|| vec![0.0; num_steps]);
+    vecvecmul_assign(&mut E_pr_cr_ndef_t, f_match_t);
+
+    let mut services: Vec<Service> = E_EPus_cr_s_t.keys().copied().collect();
+    services.sort();
+
+    // * Production assigned to a service covers its EPB use first
+    // Production of services without EPB use is not used
+    let mut E_pr_cr_used_EPus_s_t = HashMap::<Service, Vec<Real>>::new();
+    for service in &services {
+        let mut E_pr_cr_k_t = match E_pr_cr_s_t.get(service) {
+            Some(E_pr_cr_assigned_t) => vecvecmul(E_pr_cr_assigned_t, f_match_t),
+            None => vec![0.0; num_steps],
+        };
+        vecvecmin_assign(&mut E_pr_cr_k_t, &E_EPus_cr_s_t[service]);
+        E_pr_cr_used_EPus_s_t.insert(*service, E_pr_cr_k_t);
+    }
+
+    // * Production without an assigned service covers the remaining EPB use of services in priority order
+    for service in priority {
+        if let (Some(E_EPus_cr_k_t), Some(E_pr_cr_k_t)) = (
+            E_EPus_cr_s_t.get(service),
+            E_pr_cr_used_EPus_s_t.get_mut(service),
+        ) {
+            for ((pr, ndef), us) in E_pr_cr_k_t
+                .iter_mut()
+                .zip(E_pr_cr_ndef_t.iter_mut())
+                .zip(E_EPus_cr_k_t)
+            {
+                let allocated = ndef.min(us - *pr);
+                *pr += allocated;
+                *ndef -= allocated;
+            }
+        }
+    }
+
+    // * ... and the rest is split among the other services by their share of annual EPB use
+    let others: Vec<Service> = services
+        .iter()
+        .filter(|s| !priority.contains(s))
+        .copied()
+        .collect();
+    let E_EPus_cr_others_an: Real = others.iter().map(|s| vecsum(&E_EPus_cr_s_t[s])).sum();
+    if E_EPus_cr_others_an > 0.0 {
+        for service in &others {
+            let E_EPus_cr_k_t = &E_EPus_cr_s_t[service];
+            let f_us_k_cr = vecsum(E_EPus_cr_k_t) / E_EPus_cr_others_an;
+            let E_pr_cr_k_t = E_pr_cr_used_EPus_s_t.get_mut(service).unwrap();
+            for ((pr, ndef), us) in E_pr_cr_k_t
+                .iter_mut()
+                .zip(&E_pr_cr_ndef_t)
+                .zip(E_EPus_cr_k_t)
+            {
+                *pr += (ndef * f_us_k_cr).min(us - *pr);
+            }
+        }
+    }
+
+    let E_pr_cr_used_EPus_t = vecitersum(num_steps, E_pr_cr_used_EPus_s_t.values());
+    let E_pr_cr_used_EPus_an_byuse = E_pr_cr_used_EPus_s_t
+        .iter()
+        .map(|(service, values)| (*service, vecsum(values)))
+        .collect();
+    (E_pr_cr_used_EPus_t, E_pr_cr_used_EPus_an_byuse)
+}
+
+/// Calcula la energía ponderada de un vector energético a partir de sus flujos de energía
+///
+/// Compute weighted energy for a carrier from its energy flows, for any weighted quantity.
+///
+/// * `bal` - energy balance for the carrier (only energy flows are used)
+/// * `f_us_cr` - share of each EPB service in the energy used for the carrier
+/// * `k_exp_grid`, `k_exp_nepb` - exported energy factors for each destination [0, 1]
+/// * `weigh` - weighting function for the energy of each timestep, for a source, destination and step
+#[allow(non_snake_case)]
+fn weigh_balance_for_carrier<W, F>(
+    bal: &BalanceForCarrier,
+    f_us_cr: &HashMap<Service, Real>,
+    k_exp_grid: Real,
+    k_exp_nepb: Real,
+    weigh: F,
+) -> Result<WeightedBalanceForCarrier<W>>
+where
+    W: WeightedQuantity,
+    F: Fn(&[Real], Source, Dest, Step) -> Result<W>,
+{
     // * Weighted energy for delivered energy: the cost of producing that energy
-    let fpA_grid = fp_find(fp_cr, Source::RED, Dest::SUMINISTRO, Step::A)?;
-    let E_we_del_cr_grid_an = E_del_cr_an * fpA_grid.factors(); // formula 19, 39
+    let E_we_del_cr_grid_an = weigh(&bal.delivered_grid, Source::RED, Dest::SUMINISTRO, Step::A)?; // formula 19, 39
 
     // 2) Delivered energy from non cogeneration on-site sources (origin i)
-    let E_we_del_cr_onsite_an = E_pr_cr_i_an
+    let E_we_del_cr_onsite_an = bal
+        .produced_bygen
         .get(&CSubtype::INSITU)
-        .and_then(|E_pr_cr_i| {
-            fp_find(fp_cr, Source::INSITU, Dest::SUMINISTRO, Step::A)
-                .and_then(|fpA_pr_cr_i| Ok(E_pr_cr_i * fpA_pr_cr_i.factors()))
-                .ok()
-        })
+        .map(|E_pr_cr_i| weigh(E_pr_cr_i, Source::INSITU, Dest::SUMINISTRO, Step::A))
+        .transpose()?
         .unwrap_or_default();
 
     // 3) Total delivered energy: grid + all onsite (but non cogeneration)
@@ -476,131 +1743,114 @@ fn balance_for_carrier(
 
     // // * Weighted energy for exported energy: depends on step A or B
 
-    let mut E_we_exp_cr_an_A = RenNrenCo2::default();
-    let mut E_we_exp_cr_an_AB = RenNrenCo2::default();
-    let mut E_we_exp_cr_an = RenNrenCo2::default();
-    let mut E_we_exp_cr_used_nEPus_an_AB = RenNrenCo2::default();
-    let mut E_we_exp_cr_grid_an_AB = RenNrenCo2::default();
+    let mut E_we_exp_cr_an_A = W::default();
+    let mut E_we_exp_cr_an_AB = W::default();
+    let mut E_we_exp_cr_an = W::default();
+    let mut E_we_exp_cr_an_avoided = W::default();
+    let mut E_we_exp_cr_used_nEPus_an_AB = W::default();
+    let mut E_we_exp_cr_grid_an_AB = W::default();
 
-    let E_exp_cr_an = E_exp_cr_used_nEPus_an + E_exp_cr_grid_an;
+    let E_exp_cr_used_nEPus_an = bal.exported_nEPB_an;
+    let E_exp_cr_grid_an = bal.exported_grid_an;
+    let E_exp_cr_an = bal.exported_an;
 
     if E_exp_cr_an != 0.0 {
         // This case implies there is exported energy.
         // If there's no exportation, it's either because the carrier cannot be exported
         // or becuause there's no effective exportation
         // * Step A: weighting depends on exported energy generation (origin generator)
-        // Factors are averaged weighting by production for each origin (no priority, 9.6.6.2.4)
+        // Factors are averaged weighting by exported energy of each origin (9.6.6.2.4)
 
-        // * Fraction of produced energy tipe i (origin from generator i) that is exported (formula 14)
+        // * Fraction of exported energy with origin i (origin from generator i) (formula 14)
         // NOTE: simplified for annual computations (not valid for timestep calculation)
-        let mut f_pr_cr_i = HashMap::<CSubtype, f32>::new();
-        for gen in &pr_generators {
+        let mut pr_generators: Vec<&CSubtype> = bal.exported_bygen_an.keys().collect();
+        pr_generators.sort();
+        let mut f_pr_cr_i = HashMap::<CSubtype, Real>::new();
+        for gen in pr_generators {
             // Do not store generators without generation
-            if E_exp_cr_i_an[gen] != 0.0 {
-                f_pr_cr_i.insert(*gen, vecsum(&E_exp_cr_i_t[gen]) / E_exp_cr_i_an[gen]);
+            if bal.exported_bygen_an[gen] != 0.0 {
+                f_pr_cr_i.insert(*gen, bal.exported_bygen_an[gen] / E_exp_cr_an);
             }
         }
         // Generators (produced energy sources) that are exporting some energy (!= 0)
         let exp_generators: Vec<_> = f_pr_cr_i.keys().collect();
 
-        // Weighting factors for energy exported to nEP uses (step A) (~formula 24)
-        let f_we_exp_cr_stepA_nEPus: RenNrenCo2 = if E_exp_cr_used_nEPus_an == 0.0 {
+        // Weighted exported energy, for the given destination and step, as the sum of the
+        // weighted exported energy of each origin (sum all i (non grid sources))
+        let weigh_exported = |E_exp_t: &[Real], dest: Dest, step: Step| -> Result<W> {
+            exp_generators
+                .iter()
+                .try_fold(W::default(), |acc: W, &gen| -> Result<W> {
+                    let E_exp_i_t = veckmul(E_exp_t, f_pr_cr_i[gen]);
+                    Ok(acc + weigh(&E_exp_i_t, (*gen).try_into()?, dest, step)?)
+                })
+        };
+
+        // Weighted energy exported to nEP uses (step A) (~formula 24)
+        let E_we_exp_cr_used_nEPus_an_A = if E_exp_cr_used_nEPus_an == 0.0 {
             // No exported energy to nEP uses
-            RenNrenCo2::default() // ren: 0.0, nren: 0.0, co2: 0.0
+            W::default()
         } else {
-            exp_generators.iter().fold(
-                Ok(RenNrenCo2::default()),
-                |acc: Result<RenNrenCo2>, &gen| {
-                    let fp = fp_find(fp_cr, (*gen).try_into()?, Dest::A_NEPB, Step::A)?;
-                    Ok(acc? + (fp.factors() * f_pr_cr_i[gen]))
-                },
-            )? // sum all i (non grid sources): fpA_nEPus_i[gen] * f_pr_cr_i[gen]
+            weigh_exported(&bal.exported_nEPB, Dest::A_NEPB, Step::A)?
         };
 
-        // Weighting factors for energy exported to the grid (step A) (~formula 25)
-        let f_we_exp_cr_stepA_grid: RenNrenCo2 = if E_exp_cr_grid_an == 0.0 {
+        // Weighted energy exported to the grid (step A) (~formula 25)
+        let E_we_exp_cr_grid_an_A = if E_exp_cr_grid_an == 0.0 {
             // No energy exported to grid
-            RenNrenCo2::default() // ren: 0.0, nren: 0.0, co2: 0.0
+            W::default()
         } else {
-            exp_generators.iter().fold(
-                Ok(RenNrenCo2::default()),
-                |acc: Result<RenNrenCo2>, &gen| {
-                    let fp = fp_find(fp_cr, (*gen).try_into()?, Dest::A_RED, Step::A)?;
-                    Ok(acc? + (fp.factors() * f_pr_cr_i[gen]))
-                },
-            )? // sum all i (non grid sources): fpA_grid_i[gen] * f_pr_cr_i[gen];
+            weigh_exported(&bal.exported_grid, Dest::A_RED, Step::A)?
         };
 
         // Weighted exported energy according to resources used to generate that energy (formula 23)
-        E_we_exp_cr_an_A = (E_exp_cr_used_nEPus_an * f_we_exp_cr_stepA_nEPus) // formula 24
-            + (E_exp_cr_grid_an * f_we_exp_cr_stepA_grid); // formula 25
+        E_we_exp_cr_an_A = E_we_exp_cr_used_nEPus_an_A + E_we_exp_cr_grid_an_A;
 
         // * Step B: weighting depends on exported energy generation and avoided resources on the grid
 
-        // Factors of contribution for energy exported to nEP uses (step B)
-        let f_we_exp_cr_used_nEPus = if E_exp_cr_used_nEPus_an == 0.0 {
+        // Weighted energy exported to nEP uses (step B)
+        let E_we_exp_cr_used_nEPus_an_B = if E_exp_cr_used_nEPus_an == 0.0 {
             // No energy exported to nEP uses
-            RenNrenCo2::default() // ren: 0.0, nren: 0.0, co2: 0.0
+            W::default()
         } else {
-            exp_generators.iter().fold(
-                Ok(RenNrenCo2::default()),
-                |acc: Result<RenNrenCo2>, &gen| {
-                    let fp = fp_find(fp_cr, (*gen).try_into()?, Dest::A_NEPB, Step::B)?;
-                    Ok(acc? + (fp.factors() * f_pr_cr_i[gen]))
-                },
-            )? // sum all i (non grid sources): fpB_nEPus_i[gen] * f_pr_cr_i[gen]
+            weigh_exported(&bal.exported_nEPB, Dest::A_NEPB, Step::B)?
         };
 
-        // Weighting factors for energy exported to the grid (step B)
-        let f_we_exp_cr_grid = if E_exp_cr_grid_an == 0.0 {
+        // Weighted energy exported to the grid (step B)
+        let E_we_exp_cr_grid_an_B = if E_exp_cr_grid_an == 0.0 {
             // No energy exported to grid
-            RenNrenCo2::default() // ren: 0.0, nren: 0.0, co2: 0.0
+            W::default()
         } else {
-            exp_generators.iter().fold(
-                Ok(RenNrenCo2::default()),
-                |acc: Result<RenNrenCo2>, &gen| {
-                    let fp = fp_find(fp_cr, (*gen).try_into()?, Dest::A_RED, Step::B)?;
-                    Ok(acc? + (fp.factors() * f_pr_cr_i[gen]))
-                },
-            )? // sum all i (non grid sources): fpB_grid_i[gen] * f_pr_cr_i[gen];
+            weigh_exported(&bal.exported_grid, Dest::A_RED, Step::B)?
         };
 
         // Effect of exported energy on weighted energy performance (step B) (formula 26)
 
-        E_we_exp_cr_used_nEPus_an_AB =
-            E_exp_cr_used_nEPus_an * (f_we_exp_cr_used_nEPus - f_we_exp_cr_stepA_nEPus);
-        E_we_exp_cr_grid_an_AB = E_exp_cr_grid_an * (f_we_exp_cr_grid - f_we_exp_cr_stepA_grid);
+        E_we_exp_cr_used_nEPus_an_AB = E_we_exp_cr_used_nEPus_an_B - E_we_exp_cr_used_nEPus_an_A;
+        E_we_exp_cr_grid_an_AB = E_we_exp_cr_grid_an_B - E_we_exp_cr_grid_an_A;
         E_we_exp_cr_an_AB = E_we_exp_cr_used_nEPus_an_AB + E_we_exp_cr_grid_an_AB;
 
         // Contribution of exported energy to the annual weighted energy performance
         // 11.6.2.1, 11.6.2.2, 11.6.2.3
-        E_we_exp_cr_an = E_we_exp_cr_an_A + (k_exp * E_we_exp_cr_an_AB); // (formula 20)
+        // The avoided resources on the grid are scaled by the k_exp factor for each destination
+        E_we_exp_cr_an_avoided =
+            (E_we_exp_cr_used_nEPus_an_AB * k_exp_nepb) + (E_we_exp_cr_grid_an_AB * k_exp_grid);
+        E_we_exp_cr_an = E_we_exp_cr_an_A + E_we_exp_cr_an_avoided; // (formula 20)
     }
 
     // * Total result for step A
     // Partial result for carrier (formula 2)
-    let E_we_cr_an_A: RenNrenCo2 = E_we_del_cr_an - E_we_exp_cr_an_A;
+    let E_we_cr_an_A: W = E_we_del_cr_an - E_we_exp_cr_an_A;
 
     // * Total result for step B
     // Partial result for carrier (formula 2)
-    let E_we_cr_an: RenNrenCo2 = E_we_del_cr_an - E_we_exp_cr_an;
+    let E_we_cr_an: W = E_we_del_cr_an - E_we_exp_cr_an;
 
-    // ================ Compute values by use ===============
-    // Compute fraction of used energy by use (for EPB services):
-    // used energy for service_i / used energy for all services)
-    let f_us_cr = compute_factors_by_use_cr(cr_list);
-    // Annual energy use for carrier
-    let E_EPus_cr_an: f32 = E_EPus_cr_t.iter().sum();
-
-    // Used (final) and Weighted energy for each use item (for EPB services)
-    let mut E_Epus_cr_an_byuse: HashMap<Service, f32> = HashMap::new();
-    let mut E_we_cr_an_A_byuse: HashMap<Service, RenNrenCo2> = HashMap::new();
-    let mut E_we_cr_an_byuse: HashMap<Service, RenNrenCo2> = HashMap::new();
+    // Weighted energy for each use item (for EPB services)
+    let mut E_we_cr_an_A_byuse: HashMap<Service, W> = HashMap::new();
+    let mut E_we_cr_an_byuse: HashMap<Service, W> = HashMap::new();
     for service in &SERVICES {
-        let f_us_k_cr = *f_us_cr.get(service).unwrap_or(&0.0f32);
+        let f_us_k_cr = *f_us_cr.get(service).unwrap_or(&0.0);
         if f_us_k_cr != 0.0 {
-            // Used energy
-            E_Epus_cr_an_byuse.insert(*service, E_EPus_cr_an * f_us_k_cr);
             // Step A
             E_we_cr_an_A_byuse.insert(*service, E_we_cr_an_A * f_us_k_cr);
             // Step B (E.2.6)
@@ -608,44 +1858,117 @@ fn balance_for_carrier(
         }
     }
 
-    Ok(BalanceForCarrier {
-        carrier,
-        used_EPB: E_EPus_cr_t,
-        used_EPB_an_byuse: E_Epus_cr_an_byuse,
-        used_nEPB: E_nEPus_cr_t,
-        produced: E_pr_cr_t,
-        produced_an: E_pr_cr_an,
-        produced_bygen: E_pr_cr_i_t,
-        produced_bygen_an: E_pr_cr_i_an,
-        produced_used_EPus: E_pr_cr_used_EPus_t,
-        produced_used_EPus_bygen: E_pr_cr_i_used_EPus_t,
-        f_match: f_match_t,   // load matching factor
-        exported: E_exp_cr_t, // exp_used_nEPus + exp_grid
-        exported_an: E_exp_cr_an,
-        exported_bygen: E_exp_cr_i_t,
-        exported_bygen_an: E_exp_cr_i_an,
-        exported_grid: E_exp_cr_grid_t,
-        exported_grid_an: E_exp_cr_grid_an,
-        exported_nEPB: E_exp_cr_used_nEPus_t,
-        exported_nEPB_an: E_exp_cr_used_nEPus_an,
-        delivered_grid: E_del_cr_t,
-        delivered_grid_an: E_del_cr_an,
-        // Weighted energy: { ren, nren }
-        we_delivered_grid_an: E_we_del_cr_grid_an,
-        we_delivered_prod_an: E_we_del_cr_onsite_an,
-        we_delivered_an: E_we_del_cr_an,
-        we_exported_an_A: E_we_exp_cr_an_A,
-        we_exported_nEPB_an_AB: E_we_exp_cr_used_nEPus_an_AB,
-        we_exported_grid_an_AB: E_we_exp_cr_grid_an_AB,
-        we_exported_an_AB: E_we_exp_cr_an_AB,
-        we_exported_an: E_we_exp_cr_an,
-        we_an_A: E_we_cr_an_A,
-        we_an_A_byuse: E_we_cr_an_A_byuse,
-        we_an: E_we_cr_an,
-        we_an_byuse: E_we_cr_an_byuse,
+    Ok(WeightedBalanceForCarrier {
+        delivered_grid_an: E_we_del_cr_grid_an,
+        delivered_prod_an: E_we_del_cr_onsite_an,
+        delivered_an: E_we_del_cr_an,
+        exported_an_A: E_we_exp_cr_an_A,
+        exported_nEPB_an_AB: E_we_exp_cr_used_nEPus_an_AB,
+        exported_grid_an_AB: E_we_exp_cr_grid_an_AB,
+        exported_an_AB: E_we_exp_cr_an_AB,
+        exported_an: E_we_exp_cr_an,
+        exported_an_avoided: E_we_exp_cr_an_avoided,
+        an_A: E_we_cr_an_A,
+        an_A_byuse: E_we_cr_an_A_byuse,
+        an: E_we_cr_an,
+        an_byuse: E_we_cr_an_byuse,
     })
 }
 
+/// Calcula la energía ponderada suministrada y exportada de un vector energético en cada paso de cálculo
+///
+/// Compute weighted delivered energy and weighted exported energy (steps A and A+B) for a carrier
+/// in each timestep (E_we_del_cr_t, E_we_exp_cr_A_t, E_we_exp_cr_t). Their sums are the annual
+/// values computed by `weigh_balance_for_carrier`.
+///
+/// * `bal` - energy balance for the carrier (only energy flows are used)
+/// * `k_exp_grid`, `k_exp_nepb` - exported energy factors for each destination [0, 1]
+/// * `weigh_t` - weighting function for the energy of each timestep, for a source, destination and
+///   step, returning the weighted energy in each timestep
+#[allow(non_snake_case, clippy::type_complexity)]
+fn weigh_balance_for_carrier_t<F>(
+    bal: &BalanceForCarrier,
+    k_exp_grid: Real,
+    k_exp_nepb: Real,
+    weigh_t: F,
+) -> Result<(Vec<RenNrenCo2>, Vec<RenNrenCo2>, Vec<RenNrenCo2>)>
+where
+    F: Fn(&[Real], Source, Dest, Step) -> Result<Vec<RenNrenCo2>>,
+{
+    let num_steps = bal.delivered_grid.len();
+    let zeros = || vec![RenNrenCo2::default(); num_steps];
+    // Elementwise linear combination a[i] + k * b[i]
+    let add_k = |a: &[RenNrenCo2], b: &[RenNrenCo2], k: Real| -> Vec<RenNrenCo2> {
+        a.iter().zip(b).map(|(a, b)| *a + *b * k).collect()
+    };
+
+    // * Weighted delivered energy: grid + all onsite (but non cogeneration)
+    let E_we_del_cr_grid_t = weigh_t(&bal.delivered_grid, Source::RED, Dest::SUMINISTRO, Step::A)?;
+    let E_we_del_cr_onsite_t = bal
+        .produced_bygen
+        .get(&CSubtype::INSITU)
+        .map(|E_pr_cr_i| weigh_t(E_pr_cr_i, Source::INSITU, Dest::SUMINISTRO, Step::A))
+        .transpose()?
+        .unwrap_or_else(zeros);
+    let E_we_del_cr_t = add_k(&E_we_del_cr_grid_t, &E_we_del_cr_onsite_t, 1.0);
+
+    if bal.exported_an == 0.0 {
+        return Ok((E_we_del_cr_t, zeros(), zeros()));
+    }
+
+    // * Weighted exported energy, averaging weighting factors by exported energy of each origin
+    let mut pr_generators: Vec<&CSubtype> = bal.exported_bygen_an.keys().collect();
+    pr_generators.sort();
+    let f_pr_cr_i: Vec<(CSubtype, Real)> = pr_generators
+        .into_iter()
+        .filter(|gen| bal.exported_bygen_an[gen] != 0.0)
+        .map(|gen| (*gen, bal.exported_bygen_an[gen] / bal.exported_an))
+        .collect();
+
+    let weigh_exported_t = |E_exp_t: &[Real], E_exp_an: Real, dest: Dest, step: Step| {
+        if E_exp_an == 0.0 {
+            return Ok(zeros());
+        }
+        f_pr_cr_i
+            .iter()
+            .try_fold(zeros(), |acc, (gen, f_pr)| -> Result<Vec<RenNrenCo2>> {
+                let E_exp_i_t = veckmul(E_exp_t, *f_pr);
+                Ok(add_k(
+                    &acc,
+                    &weigh_t(&E_exp_i_t, (*gen).try_into()?, dest, step)?,
+                    1.0,
+                ))
+            })
+    };
+
+    let (E_exp_nEPus_t, E_exp_nEPus_an) = (&bal.exported_nEPB, bal.exported_nEPB_an);
+    let (E_exp_grid_t, E_exp_grid_an) = (&bal.exported_grid, bal.exported_grid_an);
+
+    // Step A
+    let E_we_exp_cr_used_nEPus_A_t =
+        weigh_exported_t(E_exp_nEPus_t, E_exp_nEPus_an, Dest::A_NEPB, Step::A)?;
+    let E_we_exp_cr_grid_A_t = weigh_exported_t(E_exp_grid_t, E_exp_grid_an, Dest::A_RED, Step::A)?;
+    let E_we_exp_cr_A_t = add_k(&E_we_exp_cr_used_nEPus_A_t, &E_we_exp_cr_grid_A_t, 1.0);
+
+    // Step B: avoided resources on the grid, scaled by the k_exp factor for each destination
+    let E_we_exp_cr_used_nEPus_B_t =
+        weigh_exported_t(E_exp_nEPus_t, E_exp_nEPus_an, Dest::A_NEPB, Step::B)?;
+    let E_we_exp_cr_grid_B_t = weigh_exported_t(E_exp_grid_t, E_exp_grid_an, Dest::A_RED, Step::B)?;
+    let E_we_exp_cr_used_nEPus_AB_t = add_k(
+        &E_we_exp_cr_used_nEPus_B_t,
+        &E_we_exp_cr_used_nEPus_A_t,
+        -1.0,
+    );
+    let E_we_exp_cr_grid_AB_t = add_k(&E_we_exp_cr_grid_B_t, &E_we_exp_cr_grid_A_t, -1.0);
+    let E_we_exp_cr_t = add_k(
+        &add_k(&E_we_exp_cr_A_t, &E_we_exp_cr_used_nEPus_AB_t, k_exp_nepb),
+        &E_we_exp_cr_grid_AB_t,
+        k_exp_grid,
+    );
+
+    Ok((E_we_del_cr_t, E_we_exp_cr_A_t, E_we_exp_cr_t))
+}
+
 /// Calcula fracción de cada uso EPB para un vector energético i
 ///
 /// Compute share of each EPB use for a given carrier i
@@ -653,26 +1976,26 @@ fn balance_for_carrier(
 /// It uses the reverse calculation method (E.3.6)
 /// * `cr_list` - components list for the selected carrier i
 ///
-fn compute_factors_by_use_cr(cr_list: &[Component]) -> HashMap<Service, f32> {
-    let mut factors_us_k: HashMap<Service, f32> = HashMap::new();
+fn compute_factors_by_use_cr(cr_list: &[&Component]) -> HashMap<Service, Real> {
+    let mut factors_us_k: HashMap<Service, Real> = HashMap::new();
     // Energy use components (EPB uses) for current carrier i
     let cr_use_list = cr_list
         .iter()
-        .filter(|c| c.ctype == CType::CONSUMO && c.csubtype == CSubtype::EPB);
+        .filter(|c| c.is_used_energy() && c.csubtype == CSubtype::EPB);
     // Energy use for all EPB services and carrier i (Q_Epus_cr)
-    let q_us_all: f32 = cr_use_list
+    let q_us_all: Real = cr_use_list
         .clone()
-        .map(|c| c.values.iter().sum::<f32>())
+        .map(|c| c.values.iter().sum::<Real>())
         .sum();
     if q_us_all != 0.0 {
         // No energy use for this carrier!
         // Collect share of step A weighted energy for each use item (service)
         for us in SERVICES.iter().cloned() {
             // Energy use for use k
-            let q_us_k: f32 = cr_use_list
+            let q_us_k: Real = cr_use_list
                 .clone()
                 .filter(|c| c.service == us)
-                .map(|c| c.values.iter().sum::<f32>())
+                .map(|c| c.values.iter().sum::<Real>())
                 .sum();
             // Factor for use k
             factors_us_k.insert(us, q_us_k / q_us_all);
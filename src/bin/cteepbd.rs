@@ -32,26 +32,37 @@ cteepbd - Implementation of the ISO EN 52000-1 standard
 
   Energy performance of buildings - Overarching EPB assessment - General framework and procedures
   This implementation has used the following assumptions:
-  - weighting factors are constant for all timesteps
-  - no priority is set for energy production (average step A weighting factor f_we_el_stepA)
+  - weighting factors are constant for all timesteps, unless time-varying factors are given
+  - no priority is set for energy production (average step A weighting factor f_we_el_stepA), unless set in metadata
   - all on-site produced energy from non cogeneration sources is considered as delivered
   - on-site produced energy is not compensated on a service by service basis, but on a by carrier basis
-  - the load matching factor is constant and equal to 1.0
-  TODO:
-  - allow other values of the load matching factor (or usign functions) f_match_t (formula 32, B.32)
+  - the load matching factor is constant and equal to 1.0, unless computed using formula B.32
 
 */
 
-use std::fs::{read_to_string, File};
+use std::fs::File;
 use std::io::prelude::*;
 use std::path::Path;
 use std::process::exit;
 use std::str::FromStr;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 
 use cteepbd::{
-    cte, energy_performance,
-    types::{MetaVec, RenNrenCo2, Service},
-    Balance, Components, UserWF,
+    cte, energy_performance_by_kexp, energy_performance_by_year, energy_performance_reference,
+    energy_performance_with_load_matching,
+    error::{Diagnostic, EpbdError},
+    i18n,
+    input::read_to_string,
+    kexp_range, parse_components, parse_service_map,
+    types::{
+        CSubtype, CType, Carrier, Component, Dest, MetaVec, Real, RenNrenCo2, Service, Source,
+        Step, Timestep,
+    },
+    validate_components, validate_wfactors, Balance, Components, CsvFormat, Factors, LoadMatching,
+    NegativeValues, ParseOptions, Tariffs, UserWF,
 };
 
 const APP_TITLE: &str = r#"CteEPBD"#;
@@ -98,7 +109,7 @@ Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>
 fn readfile<P: AsRef<Path>>(path: P) -> String {
     read_to_string(&path).unwrap_or_else(|e| {
         eprintln!(
-            "ERROR: lectura incorrecta del archivo \"{}\": {}",
+            "ERROR [E401]: lectura incorrecta del archivo \"{}\": {}",
             path.as_ref().display(),
             e
         );
@@ -106,11 +117,60 @@ fn readfile<P: AsRef<Path>>(path: P) -> String {
     })
 }
 
+/// Avisos emitidos durante la ejecución, para el registro de avisos
+static WARNINGS: Lazy<Mutex<Vec<String>>> = Lazy::new(|| Mutex::new(Vec::new()));
+
+/// Muestra un aviso y lo incorpora al registro de avisos
+fn warn<T: std::fmt::Display>(code: &str, msg: T) {
+    let line = format!("{} [{}]: {}", i18n::tr("AVISO"), code, msg);
+    println!("{}", line);
+    WARNINGS.lock().unwrap().push(line);
+}
+
+/// Registro de eventos de la librería (correcciones aplicadas y etapas del cálculo)
+///
+/// Los eventos se muestran en la salida de error para no alterar los resultados.
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} [{}]: {}",
+                record.level(),
+                record.target(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Activa el registro de eventos según el nivel de detalle (-v: INFO, -vv: DEBUG, -vvv: TRACE)
+fn init_logger(verbosity: u64) {
+    let level = match verbosity {
+        0 => log::LevelFilter::Off,
+        1 => log::LevelFilter::Info,
+        2 => log::LevelFilter::Debug,
+        _ => log::LevelFilter::Trace,
+    };
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
+
 fn writefile<P: AsRef<Path>>(path: P, content: &[u8]) {
     let mut file = File::create(&path)
         .map_err(|e| {
             eprintln!(
-                "ERROR: no se ha podido crear el archivo \"{}\": {}",
+                "ERROR [E402]: no se ha podido crear el archivo \"{}\": {}",
                 path.as_ref().display(),
                 e
             );
@@ -119,7 +179,7 @@ fn writefile<P: AsRef<Path>>(path: P, content: &[u8]) {
         .unwrap();
     if let Err(e) = file.write_all(content) {
         eprintln!(
-            "ERROR: no se ha podido escribir en el archivo \"{}\": {}",
+            "ERROR [E403]: no se ha podido escribir en el archivo \"{}\": {}",
             path.as_ref().display(),
             e
         );
@@ -130,44 +190,63 @@ fn writefile<P: AsRef<Path>>(path: P, content: &[u8]) {
 // Funciones auxiliares de validación y obtención de valores
 
 /// Comprueba validez del valor del factor de exportación
-fn validate_kexp(kexpstr: &str, orig: &str) -> Option<f32> {
-    let kexp = kexpstr.parse::<f32>().unwrap_or_else(|_| {
+fn validate_kexp(kexpstr: &str, orig: &str) -> Option<Real> {
+    let kexp = kexpstr.parse::<Real>().unwrap_or_else(|_| {
         eprintln!(
-            "ERROR: factor de exportación k_exp incorrecto \"{}\" ({})",
+            "ERROR [E501]: factor de exportación k_exp incorrecto \"{}\" ({})",
             kexpstr, orig
         );
         exit(exitcode::DATAERR);
     });
-    if kexp < 0.0 || kexp > 1.0 {
+    if !(0.0..=1.0).contains(&kexp) {
         eprintln!(
-            "ERROR: factor de exportación k_exp fuera de rango [0.00 - 1.00]: {:.2} ({})",
+            "ERROR [E502]: factor de exportación k_exp fuera de rango [0.00 - 1.00]: {:.2} ({})",
             kexp, orig
         );
         exit(exitcode::DATAERR);
     };
     if kexp != cte::KEXP_DEFAULT {
-        println!(
-            "AVISO: factor de exportación k_exp distinto al reglamentario ({:.2}): {:.2} ({})",
-            cte::KEXP_DEFAULT,
-            kexp,
-            orig
+        warn(
+            "W101",
+            format!(
+                "factor de exportación k_exp distinto al reglamentario ({:.2}): {:.2} ({})",
+                cte::KEXP_DEFAULT,
+                kexp,
+                orig
+            ),
         );
     };
     Some(kexp)
 }
 
+/// Clave de la caché de resultados a partir de los datos de entrada del cálculo
+///
+/// Usa el hash FNV-1a de 64 bits, estable entre versiones del compilador, de la versión
+/// del programa y de los datos de entrada.
+fn cache_key(parts: &[&str]) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for part in [env!("CARGO_PKG_VERSION")].iter().chain(parts.iter()) {
+        // Separador entre partes para evitar colisiones por concatenación
+        for byte in part.bytes().chain(std::iter::once(0xff)) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+    format!("{:016x}", hash)
+}
+
 /// Comprueba validez del dato de area
-fn validate_arearef(arearefstr: &str, orig: &str) -> Option<f32> {
-    let arearef = arearefstr.parse::<f32>().unwrap_or_else(|_| {
+fn validate_arearef(arearefstr: &str, orig: &str) -> Option<Real> {
+    let arearef = arearefstr.parse::<Real>().unwrap_or_else(|_| {
         eprintln!(
-            "ERROR: área de referencia A_ref incorrecta \"{}\" ({})",
+            "ERROR [E503]: área de referencia A_ref incorrecta \"{}\" ({})",
             arearefstr, orig
         );
         exit(exitcode::DATAERR);
     });
     if arearef <= 1e-3 {
         eprintln!(
-            "ERROR: área de referencia A_ref fuera de rango [0.001-]: {:.2} ({})",
+            "ERROR [E504]: área de referencia A_ref fuera de rango [0.001-]: {:.2} ({})",
             arearef, orig
         );
         exit(exitcode::DATAERR);
@@ -183,21 +262,21 @@ fn get_factor(
 ) -> Option<RenNrenCo2> {
     let factor = matches
         .values_of(meta)
-        .and_then(|v| {
+        .map(|v| {
             // Datos desde línea de comandos
-            let vv: Vec<f32> = v
+            let vv: Vec<Real> = v
                 .map(|vv| {
-                    f32::from_str(vv.trim()).unwrap_or_else(|_| {
-                        eprintln!("ERROR: factor de paso incorrecto: \"{}\"", vv);
+                    Real::from_str(vv.trim()).unwrap_or_else(|_| {
+                        eprintln!("ERROR [E505]: factor de paso incorrecto: \"{}\"", vv);
                         exit(exitcode::DATAERR);
                     })
                 })
                 .collect();
-            Some(RenNrenCo2 {
+            RenNrenCo2 {
                 ren: vv[0],
                 nren: vv[1],
                 co2: vv[2],
-            })
+            }
         })
         .or_else(|| components.get_meta_rennren(meta));
     if let Some(factor) = factor {
@@ -209,34 +288,756 @@ fn get_factor(
     factor
 }
 
+/// Guarda en los metadatos (`CTE_REDn`) los factores de paso de las redes de distrito adicionales de la CLI
+fn set_district_factors(matches: &clap::ArgMatches<'_>, components: &mut Components) {
+    let values: Vec<&str> = match matches.values_of("CTE_REDN") {
+        Some(values) => values.collect(),
+        None => return,
+    };
+    for chunk in values.chunks(4) {
+        let n = chunk[0]
+            .trim()
+            .parse::<u8>()
+            .ok()
+            .filter(|n| *n >= 3)
+            .unwrap_or_else(|| {
+                eprintln!(
+                    "ERROR [E505]: número de red de distrito incorrecto (>= 3): \"{}\"",
+                    chunk[0]
+                );
+                exit(exitcode::DATAERR);
+            });
+        let vv: Vec<Real> = chunk[1..]
+            .iter()
+            .map(|vv| {
+                Real::from_str(vv.trim()).unwrap_or_else(|_| {
+                    eprintln!("ERROR [E505]: factor de paso incorrecto: \"{}\"", vv);
+                    exit(exitcode::DATAERR);
+                })
+            })
+            .collect();
+        components.set_rennren(
+            &format!("CTE_RED{}", n),
+            RenNrenCo2::new(vv[0], vv[1], vv[2]),
+        );
+    }
+}
+
 /// Carga componentes desde archivo o devuelve componentes por defecto
-fn get_components(archivo: Option<&str>) -> Components {
+///
+/// Devuelve también los factores de paso incluidos en el archivo de componentes, si existen.
+/// Con `by_service` se compensa la energía producida servicio a servicio (`CTE_COMPENSACION_SERVICIOS`),
+/// lo que se indica antes de normalizar los componentes para conservar el servicio de la producción.
+fn get_components(
+    archivo: Option<&str>,
+    options: &ParseOptions,
+    by_service: bool,
+) -> (Components, Option<Factors>) {
     if let Some(archivo_componentes) = archivo {
         println!("Componentes energéticos: \"{}\"", archivo_componentes);
-        readfile(archivo_componentes)
-            .parse::<Components>()
+        // Los valores negativos se sustituyen por cero tras la interpretación, para mostrar los avisos
+        let parse_options = match options.negative_values {
+            NegativeValues::Clamp => ParseOptions {
+                negative_values: NegativeValues::Allow,
+                ..options.clone()
+            },
+            _ => options.clone(),
+        };
+        let (mut components, embedded_factors) =
+            parse_components(&readfile(archivo_componentes), &parse_options).unwrap_or_else(|e| {
+                eprintln!(
+                    "ERROR [{}]: formato incorrecto del archivo de componentes \"{}\": {}",
+                    e.code(),
+                    archivo_componentes,
+                    e
+                );
+                exit(exitcode::DATAERR);
+            });
+        for warning in components
+            .apply_negative_values(options.negative_values)
+            .unwrap_or_default()
+        {
+            warn(warning.code(), warning);
+        }
+        if by_service {
+            components.set_meta("CTE_COMPENSACION_SERVICIOS", "true");
+        }
+        let (components, corrections) = components
+            .apply_needs()
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "ERROR [{}]: declaración incorrecta de necesidades de energía en el archivo de componentes \"{}\": {}",
+                    e.code(),
+                    archivo_componentes,
+                    e
+                );
+                exit(exitcode::DATAERR);
+            })
+            .calibrate()
             .unwrap_or_else(|e| {
                 eprintln!(
-                    "ERROR: formato incorrecto del archivo de componentes \"{}\": {}",
-                    archivo_componentes, e
+                    "ERROR [{}]: calibración incorrecta de los componentes \"{}\": {}",
+                    e.code(),
+                    archivo_componentes,
+                    e
+                );
+                exit(exitcode::DATAERR);
+            })
+            .apply_conversions()
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "ERROR [{}]: declaración incorrecta de conversiones en el archivo de componentes \"{}\": {}",
+                    e.code(),
+                    archivo_componentes,
+                    e
                 );
                 exit(exitcode::DATAERR);
             })
-            .normalize()
+            .normalize_with_warnings();
+        for warning in &corrections {
+            warn(warning.code(), warning);
+        }
+        for (carrier, factor) in components.calibration_factors().unwrap_or_default() {
+            println!("Calibración de consumos: {} x {:.3}", carrier, factor);
+        }
+        let system_warnings = components.check_systems().unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: declaración incorrecta de sistemas en el archivo de componentes \"{}\": {}",
+                e.code(),
+                archivo_componentes,
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        for warning in components
+            .check_plausibility()
+            .iter()
+            .chain(system_warnings.iter())
+        {
+            warn(warning.code(), warning);
+        }
+        (components, embedded_factors)
     } else {
-        Components::default()
+        (Components::default(), None)
     }
 }
 
-/// Crea aplicación y detecta opciones seleccionadas
-fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
+/// Carga la tabla de equivalencias de nombres de servicio, si se ha indicado
+fn get_service_map(archivo: Option<&str>) -> Vec<(String, Service)> {
+    if let Some(archivo_mapa) = archivo {
+        println!("Equivalencias de servicios: \"{}\"", archivo_mapa);
+        parse_service_map(&readfile(archivo_mapa)).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: formato incorrecto del archivo de equivalencias de servicios \"{}\": {}",
+                e.code(),
+                archivo_mapa,
+                e
+            );
+            exit(exitcode::DATAERR);
+        })
+    } else {
+        Vec::new()
+    }
+}
+
+/// Obtiene el separador de campos y el separador decimal de los archivos de entrada
+///
+/// El separador de campos puede indicarse como un carácter o con el nombre `tab` (tabulador).
+/// Tratamiento de los valores negativos de los componentes de consumo y producción
+fn get_negative_values(matches: &clap::ArgMatches<'_>) -> NegativeValues {
+    matches
+        .value_of("valores_negativos")
+        .and_then(|v| v.parse().ok())
+        .unwrap_or_default()
+}
+
+fn get_csv_format(matches: &clap::ArgMatches<'_>) -> CsvFormat {
+    let mut format = CsvFormat::default();
+    if let Some(delimiter) = matches.value_of("separador") {
+        let mut chars = delimiter.chars();
+        format.delimiter = match (delimiter, chars.next(), chars.next()) {
+            ("tab", _, _) | ("\\t", _, _) => '\t',
+            (_, Some(c), None) => c,
+            _ => {
+                eprintln!(
+                    "ERROR: separador de campos \"{}\" no válido (debe ser un único carácter o tab)",
+                    delimiter
+                );
+                exit(exitcode::USAGE);
+            }
+        };
+    }
+    if let Some(separator) = matches.value_of("separador_decimal") {
+        format.decimal_separator = separator.chars().next().unwrap();
+    }
+    if let Err(e) = format.to_canonical("") {
+        eprintln!("ERROR [{}]: {}", e.code(), e);
+        exit(exitcode::USAGE);
+    }
+    if format != CsvFormat::default() {
+        println!(
+            "Separadores de campos y decimal: \"{}\" y \"{}\"",
+            format.delimiter.escape_default(),
+            format.decimal_separator
+        );
+    }
+    format
+}
+
+// Asistente de inicio (cteepbd init) -----------------------------------------------------------
+
+/// Pregunta un valor al usuario hasta obtener una respuesta válida
+///
+/// Si la respuesta está vacía, o se alcanza el final de la entrada, se usa el valor por defecto.
+fn ask<T, R: BufRead, F: Fn(&str) -> Option<T>>(
+    input: &mut R,
+    question: &str,
+    default: &str,
+    parse: F,
+) -> T {
+    loop {
+        print!("{} [{}]: ", question, default);
+        std::io::stdout().flush().ok();
+        let mut answer = String::new();
+        let eof = input.read_line(&mut answer).unwrap_or(0) == 0;
+        if eof {
+            println!();
+        }
+        let answer = match answer.trim() {
+            "" => default,
+            answer => answer,
+        };
+        match parse(answer) {
+            Some(value) => return value,
+            None if eof => return parse(default).unwrap(),
+            None => warn("W104", format!("valor no válido \"{}\"", answer)),
+        }
+    }
+}
+
+/// Interpreta una lista de elementos separados por comas
+fn parse_list<T: FromStr>(list: &str) -> Option<Vec<T>> {
+    let items = list
+        .split(',')
+        .map(|item| item.trim().parse::<T>().ok())
+        .collect::<Option<Vec<T>>>()?;
+    if items.is_empty() {
+        None
+    } else {
+        Some(items)
+    }
+}
+
+/// Asistente de inicio: pregunta los datos básicos del edificio y genera los archivos iniciales
+///
+/// Genera, en el directorio indicado, un archivo de componentes con los parámetros del cálculo
+/// (localización, área de referencia y k_exp) en sus metadatos y un consumo mensual nulo
+/// por cada vector y servicio, y un archivo de factores de paso de usuario con los factores
+/// de suministro de la localización, comentados, para su posible modificación.
+fn init_wizard(matches: &clap::ArgMatches<'_>) {
+    let dir = Path::new(matches.value_of("directorio").unwrap_or("."));
+    let path_components = dir.join("componentes.csv");
+    let path_factors = dir.join("factores_usuario.csv");
+    for path in &[&path_components, &path_factors] {
+        if path.exists() {
+            eprintln!("ERROR [E404]: el archivo \"{}\" ya existe", path.display());
+            exit(exitcode::CANTCREAT);
+        }
+    }
+
+    println!("** Asistente de inicio de CteEPBD");
+    let stdin = std::io::stdin();
+    let mut input = stdin.lock();
+    let loc = ask(
+        &mut input,
+        "Localización (PENINSULA, BALEARES, CANARIAS, CEUTAMELILLA)",
+        "PENINSULA",
+        |l| {
+            let l = l.to_uppercase();
+            if cte::CTE_LOCS.contains(&l.as_str()) {
+                Some(l)
+            } else {
+                None
+            }
+        },
+    );
+    let arearef = ask(&mut input, "Área de referencia [m2]", "1.0", |a| {
+        a.parse::<Real>().ok().filter(|a| *a > 1e-3)
+    });
+    let kexp = ask(
+        &mut input,
+        "Factor de exportación k_exp [0.00 - 1.00]",
+        "0.0",
+        |k| k.parse::<Real>().ok().filter(|k| (0.0..=1.0).contains(k)),
+    );
+    let services: Vec<Service> = ask(
+        &mut input,
+        "Servicios (separados por comas)",
+        "CAL, ACS",
+        parse_list,
+    );
+    let carriers: Vec<Carrier> = ask(
+        &mut input,
+        "Vectores energéticos (separados por comas)",
+        "ELECTRICIDAD",
+        parse_list,
+    );
+
+    // Componentes: metadatos del cálculo y consumo nulo por vector y servicio
+    let mut components = Components::default();
+    components.set_meta(
+        "Descripcion",
+        "Archivo de componentes generado con cteepbd init",
+    );
+    components.set_meta("CTE_LOCALIZACION", &loc);
+    components.set_meta("CTE_AREAREF", &format!("{:.2}", arearef));
+    components.set_meta("CTE_KEXP", &format!("{:.1}", kexp));
+    for carrier in &carriers {
+        for service in &services {
+            components.cdata.push(Component {
+                id: 0,
+                carrier: carrier.clone(),
+                ctype: CType::CONSUMO,
+                csubtype: CSubtype::EPB,
+                service: *service,
+                aux: false,
+                values: vec![0.0; 12],
+                comment: "Sustituir por los consumos mensuales [kWh]".to_string(),
+            });
+        }
+    }
+
+    // Factores de paso de usuario: factores de suministro de la localización, comentados
+    let mut factors = vec![
+        "# Factores de paso de usuario (opción --fps_usuario)".to_string(),
+        "# Elimine el carácter # inicial de los factores que desee modificar".to_string(),
+    ];
+    let locfactors = &cte::CTE_LOCWF_RITE2014[loc.as_str()];
+    for carrier in &carriers {
+        if let Some(f) = locfactors.wdata.iter().find(|f| {
+            f.carrier == *carrier
+                && f.source == Source::RED
+                && f.dest == Dest::SUMINISTRO
+                && f.step == Step::A
+        }) {
+            factors.push(format!("# {}", f));
+        }
+    }
+    factors.push(String::new());
+
+    writefile(&path_components, format!("{}\n", components).as_bytes());
+    writefile(&path_factors, factors.join("\n").as_bytes());
+    println!("Archivo de componentes: \"{}\"", path_components.display());
+    println!(
+        "Archivo de factores de paso de usuario: \"{}\"",
+        path_factors.display()
+    );
+    println!(
+        "Para calcular el balance: cteepbd balance -c {} --fps_usuario {}",
+        path_components.display(),
+        path_factors.display()
+    );
+}
+
+// Subcomandos de validación, conversión y generación de factores ----------------------------
+
+/// Muestra los problemas detectados en la validación de un archivo
+///
+/// Devuelve `true` si no se ha detectado ningún problema.
+fn report_diagnostics(path: &str, diagnostics: &[Diagnostic]) -> bool {
+    for diagnostic in diagnostics {
+        eprintln!(
+            "ERROR [{}]: archivo \"{}\", {}",
+            diagnostic.code, path, diagnostic
+        );
+    }
+    diagnostics.is_empty()
+}
+
+/// Comprueba los archivos de entrada sin calcular el balance (cteepbd validate)
+///
+/// Se muestran todos los problemas de formato de los archivos de componentes y de factores de
+/// paso, con su número de línea. Si no existen, se realizan el resto de comprobaciones del
+/// cálculo del balance, cuyos errores interrumpen la ejecución, y se muestran sus avisos.
+fn validate(matches: &clap::ArgMatches<'_>) {
+    println!("** Validación de datos de entrada");
+    let options = ParseOptions {
+        keep_unknown: matches.is_present("admite_desconocidos"),
+        service_map: get_service_map(matches.value_of("mapa_servicios")),
+        format: get_csv_format(matches),
+        pad_values: matches.is_present("rellena_valores"),
+        negative_values: get_negative_values(matches),
+    };
+    let mut is_valid = true;
+    if let Some(path) = matches.value_of("archivo_componentes") {
+        is_valid &= report_diagnostics(path, &validate_components(&readfile(path), &options));
+    }
+    if let Some(path) = matches.value_of("archivo_factores") {
+        let diagnostics = match options.format.to_canonical(&readfile(path)) {
+            Ok(s) => validate_wfactors(&s),
+            Err(e) => vec![Diagnostic::new(None, &e)],
+        };
+        is_valid &= report_diagnostics(path, &diagnostics);
+    }
+    if !is_valid {
+        exit(exitcode::DATAERR);
+    }
+    let (components, _) = get_components(matches.value_of("archivo_componentes"), &options, false);
+    if matches.is_present("archivo_componentes") {
+        cte::location_from_components(&components).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: localización incorrecta en los metadatos de los componentes: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+    }
+    if let Some(path) = matches.value_of("archivo_factores") {
+        println!("Factores de paso: \"{}\"", path);
+    }
+    if let Some(path) = matches.value_of("archivo_tarifas") {
+        println!("Tarifas energéticas: \"{}\"", path);
+        readfile(path).parse::<Tariffs>().unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: formato incorrecto del archivo de tarifas \"{}\": {}",
+                e.code(),
+                path,
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+    }
+    let num_warnings = WARNINGS.lock().unwrap().len();
+    println!("Datos de entrada correctos ({} avisos)", num_warnings);
+}
+
+/// Ruta sin la extensión de los archivos comprimidos con gzip (p.e. `datos.csv.gz` -> `datos.csv`)
+fn without_gz(path: &Path) -> std::path::PathBuf {
+    if path
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("gz"))
+    {
+        path.with_extension("")
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Comprueba si el archivo está en formato JSON, según su extensión (sin la de gzip)
+fn is_json_file(path: &str) -> bool {
+    without_gz(Path::new(path))
+        .extension()
+        .map_or(false, |ext| ext.eq_ignore_ascii_case("json"))
+}
+
+/// Convierte un valor a JSON para los subcomandos convert y factors
+fn to_json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string_pretty(value).unwrap_or_else(|e| {
+        eprintln!("ERROR [E601]: conversión incorrecta a JSON: {}", e);
+        exit(exitcode::DATAERR);
+    })
+}
+
+fn to_canonical_json<T: Serialize>(value: &T, decimals: usize) -> String {
+    cte::to_canonical_json(value, decimals).unwrap_or_else(|e| {
+        eprintln!("ERROR [E601]: conversión incorrecta a JSON: {}", e);
+        exit(exitcode::DATAERR);
+    })
+}
+
+/// Escribe el resultado de los subcomandos convert y factors en el archivo de salida o en la salida estándar
+fn write_output(matches: &clap::ArgMatches<'_>, content: &str) {
+    match matches.value_of_os("archivo_salida") {
+        Some(path) => writefile(path, format!("{}\n", content.trim_end()).as_bytes()),
+        None => println!("{}", content.trim_end()),
+    }
+}
+
+/// Convierte archivos de componentes o de factores de paso entre formatos (cteepbd convert)
+///
+/// El formato de entrada se deduce de la extensión del archivo (.json para JSON y texto en otro caso).
+fn convert(matches: &clap::ArgMatches<'_>) {
+    let as_json = matches.value_of("formato") == Some("json");
+    let decimals = matches.value_of("decimales").map(|v| {
+        v.parse::<usize>().unwrap_or_else(|_| {
+            eprintln!(
+                "ERROR [E509]: número de decimales \"{}\" incorrecto, debe ser un número entero no negativo",
+                v
+            );
+            exit(exitcode::USAGE);
+        })
+    });
+    let content = if let Some(path) = matches.value_of("archivo_componentes") {
+        let data = readfile(path);
+        let components = if is_json_file(path) {
+            Components::from_json(&data)
+        } else {
+            data.parse::<Components>()
+        }
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: formato incorrecto del archivo de componentes \"{}\": {}",
+                e.code(),
+                path,
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        match (as_json, decimals) {
+            (true, Some(decimals)) => to_canonical_json(&components.canonical(), decimals),
+            (true, None) => to_json(&components),
+            (false, Some(decimals)) => components.to_canonical_string(decimals),
+            (false, None) => components.to_string(),
+        }
+    } else {
+        let path = matches.value_of("archivo_factores").unwrap();
+        let data = readfile(path);
+        let factors = if is_json_file(path) {
+            Factors::from_json(&data)
+        } else {
+            data.parse::<Factors>()
+        }
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: formato incorrecto del archivo de factores de paso \"{}\": {}",
+                e.code(),
+                path,
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        match (as_json, decimals) {
+            (true, Some(decimals)) => to_canonical_json(&factors.canonical(), decimals),
+            (true, None) => to_json(&factors),
+            (false, Some(decimals)) => factors.to_canonical_string(decimals),
+            (false, None) => factors.to_string(),
+        }
+    };
+    write_output(matches, &content);
+}
+
+/// Genera los factores de paso reglamentarios de una localización (cteepbd factors)
+///
+/// Incorpora los factores de paso de usuario (RED1, RED2, redes de distrito adicionales y
+/// cogeneración) y, opcionalmente, los factores de emisión de CO2 de otra localización.
+fn factors(matches: &clap::ArgMatches<'_>) {
+    let mut components = Components::default();
+    let user_wf = UserWF {
+        red1: get_factor(matches, &mut components, "CTE_RED1"),
+        red2: get_factor(matches, &mut components, "CTE_RED2"),
+        residuos: get_factor(matches, &mut components, "CTE_RESIDUOS"),
+        cogen_to_grid: get_factor(matches, &mut components, "CTE_COGEN"),
+        cogen_to_nepb: get_factor(matches, &mut components, "CTE_COGENNEPB"),
+    };
+    set_district_factors(matches, &mut components);
+    let loc = matches.value_of("fps_loc").unwrap();
+    let locwf = cte::locwf_from_version(cte::CTE_FP_VERSION_DEFAULT).unwrap();
+    let fpdata = match matches.value_of("zona_climatica") {
+        Some(zone) => cte::wfactors_from_loc_zone(loc, zone, locwf, user_wf, cte::CTE_USERWF),
+        None => cte::wfactors_from_loc(loc, locwf, user_wf, cte::CTE_USERWF),
+    };
+    let mut fpdata = fpdata
+        .and_then(|fp| {
+            cte::wfactors_set_districts_from_components(fp, &components, &cte::CTE_USERWF)
+        })
+        .and_then(|fp| fp.normalize(&cte::CTE_USERWF))
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: parámetros incorrectos para generar los factores de paso: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+    if let Some(co2_loc) = matches.value_of("co2_loc") {
+        fpdata = cte::wfactors_set_co2_from_loc(fpdata, co2_loc, locwf).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: no se han podido obtener los factores de emisión: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+    }
+    let content = if matches.value_of("formato") == Some("json") {
+        to_json(&fpdata)
+    } else {
+        fpdata.to_string()
+    };
+    write_output(matches, &content);
+}
+
+// Procesamiento por lotes (cteepbd batch) -----------------------------------------------------
+
+/// Calcula el balance de un archivo de componentes del procesamiento por lotes
+///
+/// Los parámetros del cálculo (área de referencia, factores de exportación, factores de usuario
+/// y demanda anual de ACS) se obtienen de los metadatos de los componentes. Los factores de paso
+/// se toman, por este orden, del archivo de factores compartido, de la localización indicada,
+/// de los factores incluidos en el archivo de componentes o de la localización de los metadatos.
+fn batch_balance(
+    components_str: &str,
+    factors_str: Option<&str>,
+    loc: Option<&str>,
+) -> Result<Balance, cteepbd::error::EpbdError> {
+    let (components, embedded_factors) =
+        parse_components(components_str, &ParseOptions::default())?;
+    let components = components
+        .apply_needs()?
+        .calibrate()?
+        .apply_conversions()?
+        .normalize();
+    let user_wf = UserWF {
+        red1: components.get_rennren("CTE_RED1")?,
+        red2: components.get_rennren("CTE_RED2")?,
+        residuos: components.get_rennren("CTE_RESIDUOS")?,
+        cogen_to_grid: components.get_rennren("CTE_COGEN")?,
+        cogen_to_nepb: components.get_rennren("CTE_COGENNEPB")?,
+    };
+    let locwf = cte::locwf_from_components(&components)?;
+    let fpdata = match (factors_str, loc, embedded_factors) {
+        (Some(factors_str), _, _) => cte::wfactors_from_str(factors_str, user_wf, cte::CTE_USERWF),
+        (None, Some(loc), _) => cte::wfactors_from_loc(loc, locwf, user_wf, cte::CTE_USERWF),
+        (None, None, Some(factors)) => factors
+            .set_user_wfactors(user_wf)
+            .normalize(&cte::CTE_USERWF),
+        (None, None, None) => {
+            cte::wfactors_from_components(&components, locwf, user_wf, cte::CTE_USERWF)
+        }
+    }?;
+    let fpdata =
+        cte::wfactors_set_districts_from_components(fpdata, &components, &cte::CTE_USERWF)?
+            .normalize(&cte::CTE_USERWF)?
+            .strip(&components);
+    let balance = cte::energy_performance_from_meta(&components, &fpdata, None, None)?;
+    Ok(cte::incorpora_demanda_renovable_acs_nrb(
+        balance,
+        components.get_real("CTE_ACS_DEMANDA_ANUAL")?,
+    ))
+}
+
+/// Calcula el balance de todos los archivos de componentes de un directorio (cteepbd batch)
+///
+/// Los resultados de cada archivo se guardan junto a él (`NOMBRE_balance.EXT`, con la extensión
+/// del formato seleccionado) y los indicadores de todos los archivos en un archivo resumen en
+/// formato CSV. Los errores de un archivo no interrumpen el proceso del resto, pero hacen que
+/// el programa termine con error.
+fn batch(matches: &clap::ArgMatches<'_>) {
+    let dir = Path::new(matches.value_of("directorio").unwrap());
+    let factors_path = matches.value_of("archivo_factores").map(Path::new);
+    let factors_str = factors_path.map(readfile);
+    let loc = matches.value_of("fps_loc");
+    let summary_path = matches
+        .value_of("archivo_resumen")
+        .map_or_else(|| dir.join("resumen.csv"), |p| Path::new(p).to_path_buf());
+    let (format, ext) = match matches.value_of("formato_salida") {
+        Some("json") => ("json", "json"),
+        Some("xml") => ("xml", "xml"),
+        Some("markdown") => ("markdown", "md"),
+        _ => ("texto", "txt"),
+    };
+
+    // Archivos de componentes (.csv) del directorio, salvo el resumen y los factores de paso
+    let is_excluded = |path: &Path| {
+        let path = path.canonicalize().ok();
+        let same = |other: &Path| other.canonicalize().ok() == path;
+        same(&summary_path) || factors_path.map_or(false, same)
+    };
+    let mut paths = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [E401]: lectura incorrecta del directorio \"{}\": {}",
+                dir.display(),
+                e
+            );
+            exit(exitcode::IOERR);
+        })
+        .filter_map(|entry| entry.ok().map(|e| e.path()))
+        .filter(|path| {
+            path.is_file()
+                && without_gz(path)
+                    .extension()
+                    .map_or(false, |ext| ext.eq_ignore_ascii_case("csv"))
+                && !is_excluded(path)
+        })
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    println!("** Procesamiento por lotes: \"{}\"", dir.display());
+    let mut summary =
+        vec!["archivo,estado,arearef,k_exp,ep_ren,ep_nren,ep_tot,rer,co2".to_string()];
+    let mut num_errors = 0;
+    for path in &paths {
+        let name = path.file_name().unwrap().to_string_lossy();
+        let result =
+            batch_balance(&readfile(path), factors_str.as_deref(), loc).and_then(|balance| {
+                let out = match format {
+                    "json" => cte::balance_to_json(&balance)?,
+                    "xml" => cte::balance_to_xml(&balance)?,
+                    "markdown" => cte::balance_to_markdown(&balance),
+                    _ => cte::balance_to_plain(&balance),
+                };
+                Ok((balance, out))
+            });
+        match result {
+            Ok((balance, out)) => {
+                let stem = without_gz(path)
+                    .file_stem()
+                    .unwrap()
+                    .to_string_lossy()
+                    .to_string();
+                writefile(
+                    path.with_file_name(format!("{}_balance.{}", stem, ext)),
+                    out.as_bytes(),
+                );
+                let b = balance.balance_m2.B;
+                summary.push(format!(
+                    "{},OK,{:.2},{:.2},{:.2},{:.2},{:.2},{:.3},{:.2}",
+                    name,
+                    balance.arearef,
+                    balance.k_exp,
+                    b.ren,
+                    b.nren,
+                    b.tot(),
+                    b.rer(),
+                    b.co2
+                ));
+                println!(
+                    "{}: C_ep,tot = {:.1} kWh/m2.an, RER = {:.2}",
+                    name,
+                    b.tot(),
+                    b.rer()
+                );
+            }
+            Err(e) => {
+                num_errors += 1;
+                summary.push(format!("{},{},,,,,,,", name, e.code()));
+                eprintln!("ERROR [{}]: archivo \"{}\": {}", e.code(), name, e);
+            }
+        }
+    }
+    writefile(
+        &summary_path,
+        format!("{}\n", summary.join("\n")).as_bytes(),
+    );
+    println!(
+        "Archivos procesados: {} ({} con errores). Resumen: \"{}\"",
+        paths.len(),
+        num_errors,
+        summary_path.display()
+    );
+    if num_errors > 0 {
+        exit(exitcode::DATAERR);
+    }
+}
+
+/// Añade las opciones del cálculo del balance energético (subcomando balance)
+///
+/// Las mismas opciones se admiten sin subcomando, para mantener la compatibilidad.
+fn balance_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
     use clap::Arg;
-    clap::App::new(APP_TITLE)
-        .bin_name("cteepbd")
-        .version(env!("CARGO_PKG_VERSION"))
-        .author(APP_DESCRIPTION)
-        .about(APP_ABOUT)
-        .setting(clap::AppSettings::NextLineHelp)
+    let app = app
         .arg(Arg::with_name("arearef")
             .short("a")
             .long("arearef")
@@ -251,6 +1052,41 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .help("Factor de exportación (k_exp)")
             .takes_value(true)
             .display_order(2))
+        .arg(Arg::with_name("kexp_nepb")
+            .long("kexp_nepb")
+            .value_name("KEXP_NEPB")
+            .help("Factor de exportación para la energía exportada a usos no EPB (por defecto, k_exp)")
+            .takes_value(true)
+            .display_order(2))
+        .arg(Arg::with_name("sensibilidad_kexp")
+            .long("sensibilidad_kexp")
+            .value_name("PASO")
+            .help("Muestra los indicadores para factores de exportación entre 0.0 y 1.0 con el paso indicado (p.e. 0.1)")
+            .takes_value(true)
+            .display_order(2))
+        .arg(Arg::with_name("periodo_compensacion")
+            .long("periodo_compensacion")
+            .value_name("PERIODO")
+            .possible_values(&["PASO", "MENSUAL", "ANUAL"])
+            .help("Periodo en el que la energía producida compensa los consumos (por defecto, el de los metadatos o cada paso de cálculo)")
+            .takes_value(true)
+            .display_order(2))
+        .arg(Arg::with_name("paso_calculo")
+            .long("paso_calculo")
+            .value_name("PASO")
+            .possible_values(&["HORARIO", "MENSUAL", "ANUAL"])
+            .requires("archivo_componentes")
+            .help("Agrega los valores de los componentes al paso de cálculo indicado (p.e. de HORARIO a MENSUAL) antes del cálculo")
+            .takes_value(true)
+            .display_order(2))
+        .arg(Arg::with_name("compensacion_servicios")
+            .long("compensacion_servicios")
+            .help("Compensa la energía producida servicio a servicio en lugar de para todo el vector energético")
+            .display_order(2))
+        .arg(Arg::with_name("factor_coincidencia")
+            .long("factor_coincidencia")
+            .help("Calcula el factor de coincidencia de cargas de la electricidad producida según la fórmula B.32 de la EN ISO 52000-1 (solo pasos mensuales). Por defecto, igual a 1.0")
+            .display_order(2))
         .arg(Arg::with_name("archivo_componentes")
             .short("c")
             .long("archivo_componentes")
@@ -277,12 +1113,27 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .help("Localización que define los factores de paso\n")
             .takes_value(true)
             .display_order(5))
+        .arg(Arg::with_name("archivo_tarifas")
+            .long("tarifas")
+            .value_name("ARCHIVO_TARIFAS")
+            .help("Archivo de tarifas energéticas para estimar la factura anual")
+            .takes_value(true))
+        .arg(Arg::with_name("archivo_factores_usuario")
+            .long("fps_usuario")
+            .value_name("ARCHIVO_FACTORES_USUARIO")
+            .help("Archivo de factores de paso de usuario que sustituyen a los factores de paso con la misma clave")
+            .takes_value(true)
+            .display_order(6))
         // Archivos de salida
         .arg(Arg::with_name("gen_archivo_componentes")
             .long("oc")
             .value_name("GEN_ARCHIVO_COMPONENTES")
             .help("Archivo de salida de los vectores energéticos corregidos")
             .takes_value(true))
+        .arg(Arg::with_name("incluye_generados")
+            .long("incluye_generados")
+            .requires("gen_archivo_componentes")
+            .help("Incluye en el archivo de salida de componentes los componentes generados automáticamente"))
         .arg(Arg::with_name("gen_archivo_factores")
             .long("of")
             .value_name("GEN_ARCHIVO_FACTORES")
@@ -298,12 +1149,103 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .value_name("ARCHIVO_SALIDA_XML")
             .help("Archivo de salida de resultados detallados en formato XML")
             .takes_value(true))
+        .arg(Arg::with_name("archivo_salida_xml_registro")
+            .long("xml_registro")
+            .value_name("ARCHIVO_SALIDA_XML_REGISTRO")
+            .help("Archivo de salida de resultados en formato XML para plataformas de registro de certificados")
+            .takes_value(true))
         .arg(Arg::with_name("archivo_salida_txt")
             .long("txt")
             .value_name("ARCHIVO_SALIDA_TXT")
             .help("Archivo de salida de resultados detallados en formato texto simple")
             .takes_value(true))
-        // Factores definidos por el usuario
+        .arg(Arg::with_name("archivo_salida_csv")
+            .long("csv")
+            .value_name("ARCHIVO_SALIDA_CSV")
+            .help("Archivo de salida de la energía por vector y paso de cálculo en formato CSV")
+            .takes_value(true))
+        .arg(Arg::with_name("formato_salida")
+            .long("formato")
+            .alias("format")
+            .value_name("FORMATO")
+            .possible_values(&["texto", "json", "xml", "markdown"])
+            .default_value("texto")
+            .help("Formato de los resultados del balance mostrados por pantalla y guardados en el archivo de salida (--salida)")
+            .takes_value(true))
+        .arg(Arg::with_name("detalle")
+            .long("detalle")
+            .value_name("NIVEL")
+            .possible_values(&["resumen", "normal", "completo"])
+            .help("Nivel de detalle de los resultados en formato de texto simple: resumen (indicadores globales), normal (por defecto) o completo (con resultados del paso A, por servicio y de energía exportada por paso de cálculo)")
+            .takes_value(true))
+        .arg(Arg::with_name("archivo_salida")
+            .short("o")
+            .long("salida")
+            .value_name("ARCHIVO_SALIDA")
+            .help("Archivo de salida de resultados en el formato seleccionado (--formato)")
+            .takes_value(true))
+        .arg(Arg::with_name("directorio_salida")
+            .long("directorio_salida")
+            .value_name("DIRECTORIO_SALIDA")
+            .help("Directorio de salida de los resultados en todos los formatos (balance.txt, balance.xml, balance.json, balance.csv) y del registro de avisos (avisos.txt)")
+            .takes_value(true))
+        .arg(Arg::with_name("cache")
+            .long("cache")
+            .value_name("DIRECTORIO_CACHE")
+            .help("Directorio de caché de resultados. Se reutiliza el balance de cálculos anteriores con los mismos datos de entrada")
+            .takes_value(true))
+        .arg(Arg::with_name("co2_loc")
+            .long("co2_loc")
+            .value_name("LOCALIZACION_CO2")
+            .possible_values(&["PENINSULA", "CANARIAS", "BALEARES", "CEUTAMELILLA"])
+            .help("Localización que define los factores de emisión de CO2 reglamentarios del suministro desde red\n")
+            .takes_value(true)
+            .display_order(6));
+    csv_format_args(user_factor_args(app))
+        // Cálculo para servicio de ACS y factores en perímetro nearby
+        .arg(Arg::with_name("demanda_anual_acs")
+            .long("demanda_anual_acs")
+            .value_name("DEM_ACS")
+            .help("Demanda anual de ACS [kWh]"))
+        .arg(Arg::with_name("acsnrb") // Obsoleto
+            .short("N")
+            .long("acs_nearby")
+            .hidden(true)
+            .requires("archivo_componentes")
+            .help("Realiza el balance considerando solo el servicio de ACS y el perímetro nearby"))
+        // Simplificación de factores
+        .arg(Arg::with_name("nosimplificafps")
+            .short("F")
+            .long("no_simplifica_fps")
+            .help("Evita la simplificación de los factores de paso según los vectores definidos"))
+        // Componentes de versiones más recientes del formato
+        .arg(Arg::with_name("admite_desconocidos")
+            .long("admite_desconocidos")
+            .requires("archivo_componentes")
+            .help("Conserva los componentes con vectores o servicios desconocidos, excluyéndolos del balance"))
+        .arg(Arg::with_name("mapa_servicios")
+            .long("mapa_servicios")
+            .value_name("ARCHIVO_MAPA_SERVICIOS")
+            .requires("archivo_componentes")
+            .help("Archivo de equivalencias de nombres de servicio (p.e. CALEFACCION, CAL)")
+            .takes_value(true))
+        .arg(Arg::with_name("rellena_valores")
+            .long("rellena_valores")
+            .requires("archivo_componentes")
+            .help("Completa con ceros los componentes con menos valores en lugar de rechazarlos"))
+        .arg(Arg::with_name("valores_negativos")
+            .long("valores_negativos")
+            .value_name("TRATAMIENTO")
+            .possible_values(&["rechaza", "anula", "admite"])
+            .requires("archivo_componentes")
+            .help("Tratamiento de los valores negativos de consumos y producciones: rechaza, anula (con aviso) o admite (por defecto)")
+            .takes_value(true))
+}
+
+/// Añade las opciones de factores de paso definibles por el usuario
+fn user_factor_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    use clap::Arg;
+    app
         .arg(Arg::with_name("CTE_RED1")
             .long("red1")
             .value_names(&["RED1_ren", "RED1_nren", "RED1_co2"])
@@ -316,6 +1258,19 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .help("Factores de paso (ren, nren, co2) de la producción del vector RED2.\nP.e.: --red2 0 1.3 0.3")
             .takes_value(true)
             .number_of_values(3))
+        .arg(Arg::with_name("CTE_RESIDUOS")
+            .long("residuos")
+            .value_names(&["RESIDUOS_ren", "RESIDUOS_nren", "RESIDUOS_co2"])
+            .help("Factores de paso (ren, nren, co2) de la producción del vector RESIDUOS.\nP.e.: --residuos 0 1.3 0.3")
+            .takes_value(true)
+            .number_of_values(3))
+        .arg(Arg::with_name("CTE_REDN")
+            .long("redn")
+            .value_names(&["n", "REDn_ren", "REDn_nren", "REDn_co2"])
+            .help("Factores de paso (ren, nren, co2) de la producción del vector REDn (n >= 3). Puede repetirse.\nP.e.: --redn 3 0 1.3 0.3")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(4))
         .arg(Arg::with_name("CTE_COGEN")
             .long("cogen")
             .value_names(&["COGEN_ren", "COGEN_nren", "COGEN_co2"])
@@ -328,22 +1283,64 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
             .help("Factores de exportación a usos no EPB (ren, nren, co2) de electricidad cogenerada.\nP.e.: --cogennepb 0 2.5 0.3")
             .takes_value(true)
             .number_of_values(3))
-        // Cálculo para servicio de ACS y factores en perímetro nearby
-        .arg(Arg::with_name("demanda_anual_acs")
-            .long("demanda_anual_acs")
-            .value_name("DEM_ACS")
-            .help("Demanda anual de ACS [kWh]"))
-        .arg(Arg::with_name("acsnrb") // Obsoleto
-            .short("N")
-            .long("acs_nearby")
-            .hidden(true)
-            .requires("archivo_componentes")
-            .help("Realiza el balance considerando solo el servicio de ACS y el perímetro nearby"))
-        // Simplificación de factores
-        .arg(Arg::with_name("nosimplificafps")
-            .short("F")
-            .long("no_simplifica_fps")
-            .help("Evita la simplificación de los factores de paso según los vectores definidos"))
+}
+
+/// Añade las opciones de separador de campos y separador decimal de los archivos de entrada
+fn csv_format_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    use clap::Arg;
+    app.arg(
+        Arg::with_name("separador")
+            .long("separador")
+            .value_name("SEPARADOR")
+            .help("Separador de campos de los archivos de componentes y factores de paso (p.e. ; o tab). Por defecto, la coma")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("separador_decimal")
+            .long("separador_decimal")
+            .value_name("SEPARADOR_DECIMAL")
+            .possible_values(&[".", ","])
+            .help("Separador decimal de los archivos de componentes y factores de paso. Por defecto, el punto")
+            .takes_value(true),
+    )
+}
+
+/// Añade las opciones de formato y archivo de salida de los subcomandos convert y factors
+fn output_args<'a, 'b>(app: clap::App<'a, 'b>) -> clap::App<'a, 'b> {
+    use clap::Arg;
+    app.arg(
+        Arg::with_name("formato")
+            .long("formato")
+            .value_name("FORMATO")
+            .possible_values(&["texto", "json"])
+            .default_value("texto")
+            .help("Formato de salida")
+            .takes_value(true),
+    )
+    .arg(
+        Arg::with_name("archivo_salida")
+            .short("o")
+            .long("salida")
+            .value_name("ARCHIVO_SALIDA")
+            .help("Archivo de salida (por defecto, la salida estándar)")
+            .takes_value(true),
+    )
+}
+
+/// Crea aplicación y detecta opciones seleccionadas
+///
+/// Los modos de funcionamiento se organizan en subcomandos (balance, validate, convert,
+/// factors e init). Sin subcomando se calcula el balance (equivale a `cteepbd balance`).
+fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
+    use clap::{Arg, SubCommand};
+    let app = clap::App::new(APP_TITLE)
+        .bin_name("cteepbd")
+        .version(env!("CARGO_PKG_VERSION"))
+        .author(APP_DESCRIPTION)
+        .about(APP_ABOUT)
+        .setting(clap::AppSettings::NextLineHelp)
+        .setting(clap::AppSettings::SubcommandsNegateReqs);
+    balance_args(app)
         // Opciones estándar: licencia y nivel de detalle
         .arg(Arg::with_name("showlicense")
             .short("L")
@@ -352,10 +1349,244 @@ fn start_app_and_get_matches() -> clap::ArgMatches<'static> {
         .arg(Arg::with_name("v")
             .short("v")
             .multiple(true)
+            .global(true)
             .help("Sets the level of verbosity"))
+        .arg(Arg::with_name("idioma")
+            .long("idioma")
+            .value_name("IDIOMA")
+            .possible_values(&["es", "en"])
+            .global(true)
+            .help("Idioma de los avisos, comentarios generados e informes de resultados (es, por defecto, o en)")
+            .takes_value(true))
+        // Cálculo del balance energético
+        .subcommand(balance_args(SubCommand::with_name("balance")
+            .about("Calcula el balance energético (modo por defecto, sin subcomando)")
+            .setting(clap::AppSettings::NextLineHelp)))
+        // Validación de los datos de entrada
+        .subcommand(csv_format_args(SubCommand::with_name("validate")
+            .about("Comprueba los archivos de entrada sin calcular el balance")
+            .arg(Arg::with_name("archivo_componentes")
+                .short("c")
+                .long("archivo_componentes")
+                .value_name("ARCHIVO_COMPONENTES")
+                .help("Archivo de definición de los componentes energéticos")
+                .takes_value(true)
+                .required_unless_one(&["archivo_factores", "archivo_tarifas"]))
+            .arg(Arg::with_name("archivo_factores")
+                .short("f")
+                .long("archivo_factores")
+                .value_name("ARCHIVO_FACTORES")
+                .help("Archivo de definición de los factores de paso")
+                .takes_value(true))
+            .arg(Arg::with_name("archivo_tarifas")
+                .long("tarifas")
+                .value_name("ARCHIVO_TARIFAS")
+                .help("Archivo de tarifas energéticas")
+                .takes_value(true))
+            .arg(Arg::with_name("admite_desconocidos")
+                .long("admite_desconocidos")
+                .requires("archivo_componentes")
+                .help("Conserva los componentes con vectores o servicios desconocidos, excluyéndolos del balance"))
+            .arg(Arg::with_name("mapa_servicios")
+                .long("mapa_servicios")
+                .value_name("ARCHIVO_MAPA_SERVICIOS")
+                .requires("archivo_componentes")
+                .help("Archivo de equivalencias de nombres de servicio (p.e. CALEFACCION, CAL)")
+                .takes_value(true))
+            .arg(Arg::with_name("rellena_valores")
+                .long("rellena_valores")
+                .requires("archivo_componentes")
+                .help("Completa con ceros los componentes con menos valores en lugar de rechazarlos"))
+            .arg(Arg::with_name("valores_negativos")
+                .long("valores_negativos")
+                .value_name("TRATAMIENTO")
+                .possible_values(&["rechaza", "anula", "admite"])
+                .requires("archivo_componentes")
+                .help("Tratamiento de los valores negativos de consumos y producciones: rechaza, anula (con aviso) o admite (por defecto)")
+                .takes_value(true))))
+        // Conversión de formato de los archivos de entrada
+        .subcommand(output_args(SubCommand::with_name("convert")
+            .about("Convierte archivos de componentes o de factores de paso entre los formatos de texto y JSON")
+            .arg(Arg::with_name("archivo_componentes")
+                .short("c")
+                .long("archivo_componentes")
+                .value_name("ARCHIVO_COMPONENTES")
+                .help("Archivo de componentes energéticos (formato JSON si tiene extensión .json)")
+                .takes_value(true))
+            .arg(Arg::with_name("archivo_factores")
+                .short("f")
+                .long("archivo_factores")
+                .value_name("ARCHIVO_FACTORES")
+                .help("Archivo de factores de paso (formato JSON si tiene extensión .json)")
+                .takes_value(true))
+            .arg(Arg::with_name("decimales")
+                .long("decimales")
+                .value_name("DECIMALES")
+                .help("Genera la representación canónica (datos ordenados) con el número de decimales indicado")
+                .takes_value(true))
+            .group(clap::ArgGroup::with_name("entrada")
+                .args(&["archivo_componentes", "archivo_factores"])
+                .required(true))))
+        // Generación de factores de paso
+        .subcommand(output_args(user_factor_args(SubCommand::with_name("factors")
+            .about("Genera los factores de paso reglamentarios de una localización")
+            .arg(Arg::with_name("fps_loc")
+                .short("l")
+                .value_name("LOCALIZACION")
+                .possible_values(&["PENINSULA", "CANARIAS", "BALEARES", "CEUTAMELILLA"])
+                .required(true)
+                .help("Localización que define los factores de paso")
+                .takes_value(true))
+            .arg(Arg::with_name("co2_loc")
+                .long("co2_loc")
+                .value_name("LOCALIZACION_CO2")
+                .possible_values(&["PENINSULA", "CANARIAS", "BALEARES", "CEUTAMELILLA"])
+                .help("Localización que define los factores de emisión de CO2 reglamentarios del suministro desde red")
+                .takes_value(true))
+            .arg(Arg::with_name("zona_climatica")
+                .long("zona_climatica")
+                .value_name("ZONA")
+                .help("Zona climática (p.e. D3), que se comprueba con la localización y se guarda en los metadatos")
+                .takes_value(true)))))
+        // Procesamiento por lotes
+        .subcommand(SubCommand::with_name("batch")
+            .about("Calcula el balance de todos los archivos de componentes (.csv) de un directorio")
+            .arg(Arg::with_name("directorio")
+                .value_name("DIRECTORIO")
+                .required(true)
+                .help("Directorio con los archivos de componentes")
+                .takes_value(true))
+            .arg(Arg::with_name("archivo_factores")
+                .short("f")
+                .long("archivo_factores")
+                .value_name("ARCHIVO_FACTORES")
+                .conflicts_with("fps_loc")
+                .help("Archivo de factores de paso compartido por todos los archivos de componentes")
+                .takes_value(true))
+            .arg(Arg::with_name("fps_loc")
+                .short("l")
+                .value_name("LOCALIZACION")
+                .possible_values(&["PENINSULA", "CANARIAS", "BALEARES", "CEUTAMELILLA"])
+                .help("Localización que define los factores de paso (por defecto, la de los metadatos de cada archivo)")
+                .takes_value(true))
+            .arg(Arg::with_name("formato_salida")
+                .long("formato")
+                .value_name("FORMATO")
+                .possible_values(&["texto", "json", "xml", "markdown"])
+                .default_value("texto")
+                .help("Formato de los resultados de cada archivo")
+                .takes_value(true))
+            .arg(Arg::with_name("archivo_resumen")
+                .long("resumen")
+                .value_name("ARCHIVO_RESUMEN")
+                .help("Archivo resumen de resultados en formato CSV (por defecto, resumen.csv en el directorio)")
+                .takes_value(true)))
+        // Asistente de inicio
+        .subcommand(clap::SubCommand::with_name("init")
+            .about("Asistente para generar los archivos iniciales de un nuevo cálculo")
+            .arg(Arg::with_name("directorio")
+                .value_name("DIRECTORIO")
+                .help("Directorio en el que se generan los archivos (por defecto, el actual)")
+                .takes_value(true)))
         .get_matches()
 }
 
+/// Resultados del cálculo en los distintos formatos de salida
+///
+/// Se guardan en la caché de resultados para reutilizarlos sin repetir el cálculo.
+#[derive(Serialize, Deserialize)]
+struct Results {
+    /// Balance en formato JSON
+    json: String,
+    /// Balance en formato XML
+    xml: String,
+    /// Balance en formato XML de registro o mensaje de error si no se puede generar
+    xml_registro: Result<String, String>,
+    /// Balance en formato de texto simple, incluido el balance por anualidades
+    plain: String,
+    /// Energía por vector y paso de cálculo en formato CSV
+    csv: String,
+    /// Balance en formato Markdown
+    markdown: String,
+}
+
+impl Results {
+    /// Genera los resultados en los distintos formatos de salida a partir del balance
+    ///
+    /// Los resultados en formato de texto simple se generan con el nivel de detalle indicado.
+    fn from_balance(balance: &Balance, detail: cte::PlainDetail) -> Self {
+        let json = cte::balance_to_json(balance).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [E601]: conversión incorrecta del balance energético a JSON: {}",
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        // Los bloques de resultados detallados del XML solo se incluyen con el nivel de detalle completo
+        let xml_detail = if detail == cte::PlainDetail::Full {
+            cte::XmlDetail::Full
+        } else {
+            cte::XmlDetail::Basic
+        };
+        let xml = cte::balance_to_xml_with_detail(balance, xml_detail).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [E602]: conversión incorrecta del balance energético a XML: {}",
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        let xml_registro = cte::balance_to_xml_registro(balance).map_err(|e| {
+            format!(
+                "ERROR [{}]: no se ha podido generar el XML de registro: {}",
+                e.code(),
+                e
+            )
+        });
+        let csv = cte::balance_to_csv(balance);
+        let mut plain = cte::balance_to_plain_with_detail(balance, detail);
+        // Balance por anualidades, si se definen en los metadatos de los componentes
+        let balances_by_year = energy_performance_by_year(balance).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: no se ha podido calcular el balance por anualidades: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        if !balances_by_year.is_empty() {
+            plain = format!(
+                "{}\n{}",
+                plain,
+                cte::balance_by_year_to_plain(&balances_by_year)
+            );
+        }
+        // Balance del edificio de referencia, si se definen componentes con ids negativos
+        let reference = energy_performance_reference(balance).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: no se ha podido calcular el balance del edificio de referencia: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        if let Some(reference) = reference {
+            plain = format!(
+                "{}\n{}",
+                plain,
+                cte::reference_comparison_to_plain(&cte::reference_comparison(balance, &reference))
+            );
+        }
+        Results {
+            json,
+            xml,
+            xml_registro,
+            plain,
+            csv,
+            markdown: cte::balance_to_markdown(balance),
+        }
+    }
+}
+
 // Función principal ------------------------------------------------------------------------------
 
 fn main() {
@@ -366,9 +1597,33 @@ fn main() {
         exit(exitcode::OK);
     }
 
+    // Idioma de los textos generados por la librería
+    if let Some(lang) = matches
+        .subcommand()
+        .1
+        .and_then(|m| m.value_of("idioma"))
+        .or_else(|| matches.value_of("idioma"))
+    {
+        i18n::set_lang(lang.parse().unwrap());
+    }
+
+    match matches.subcommand() {
+        ("init", Some(init_matches)) => init_wizard(init_matches),
+        ("validate", Some(validate_matches)) => validate(validate_matches),
+        ("convert", Some(convert_matches)) => convert(convert_matches),
+        ("factors", Some(factors_matches)) => factors(factors_matches),
+        ("batch", Some(batch_matches)) => batch(batch_matches),
+        ("balance", Some(balance_matches)) => balance(balance_matches),
+        _ => balance(&matches),
+    }
+}
+
+/// Calcula el balance energético (cteepbd balance o sin subcomando)
+fn balance(matches: &clap::ArgMatches<'_>) {
     // Prólogo ------------------------------------------------------------------------------------
 
     let verbosity = matches.occurrences_of("v");
+    init_logger(verbosity);
 
     if verbosity > 2 {
         println!("Opciones indicadas: ----------");
@@ -378,8 +1633,30 @@ fn main() {
 
     println!("** Datos de entrada");
 
+    let csv_format = get_csv_format(matches);
+
     // Componentes energéticos ---------------------------------------------------------------------
-    let mut components = get_components(matches.value_of("archivo_componentes"));
+    let (mut components, embedded_factors) = get_components(
+        matches.value_of("archivo_componentes"),
+        &ParseOptions {
+            keep_unknown: matches.is_present("admite_desconocidos"),
+            service_map: get_service_map(matches.value_of("mapa_servicios")),
+            format: csv_format,
+            pad_values: matches.is_present("rellena_valores"),
+            negative_values: get_negative_values(matches),
+        },
+        matches.is_present("compensacion_servicios"),
+    );
+
+    // Agregación de los componentes al paso de cálculo indicado
+    if let Some(timestep) = matches.value_of("paso_calculo") {
+        let timestep: Timestep = timestep.parse().unwrap();
+        components = components.aggregate(timestep).unwrap_or_else(|e| {
+            eprintln!("ERROR [{}]: paso de cálculo incorrecto: {}", e.code(), e);
+            exit(exitcode::DATAERR);
+        });
+        println!("Paso de cálculo: {}", timestep);
+    }
 
     // Cálculo para servicio de ACS en nearby
     if matches.is_present("acsnrb") {
@@ -397,6 +1674,22 @@ fn main() {
     let kexp_cli = matches
         .value_of("kexp")
         .and_then(|kexpstr| validate_kexp(kexpstr, "usuario"));
+    let kexp_nepb_cli = matches
+        .value_of("kexp_nepb")
+        .and_then(|kexpstr| validate_kexp(kexpstr, "usuario"));
+    let kexp_sweep = matches.value_of("sensibilidad_kexp").map(|stepstr| {
+        stepstr
+            .parse::<Real>()
+            .map_err(|_| EpbdError::ParseError(stepstr.to_string()))
+            .and_then(kexp_range)
+            .unwrap_or_else(|e| {
+                eprintln!(
+                    "ERROR [E508]: paso del análisis de sensibilidad al factor de exportación incorrecto \"{}\": {}",
+                    stepstr, e
+                );
+                exit(exitcode::DATAERR);
+            })
+    });
 
     // Comprobación del parámetro de área de referencia -------------------------------------------
     let arearef_cli = matches
@@ -405,17 +1698,26 @@ fn main() {
 
     // Factores de paso ---------------------------------------------------------------------------
 
-    // 0. Factores por defecto, según modo
-    let default_locwf = &cte::CTE_LOCWF_RITE2014;
+    // 0. Factores por defecto, según la versión (metadatos > predefinida)
+    let default_locwf = cte::locwf_from_components(&components).unwrap_or_else(|e| {
+        eprintln!(
+            "ERROR [{}]: versión incorrecta de los factores de paso reglamentarios: {}",
+            e.code(),
+            e
+        );
+        exit(exitcode::DATAERR);
+    });
     let default_userwf = cte::CTE_USERWF;
 
     // 1. Factores de paso definibles por el usuario (a través de la CLI o de metadatos)
     let user_wf = UserWF {
-        red1: get_factor(&matches, &mut components, "CTE_RED1"),
-        red2: get_factor(&matches, &mut components, "CTE_RED2"),
-        cogen_to_grid: get_factor(&matches, &mut components, "CTE_COGEN"),
-        cogen_to_nepb: get_factor(&matches, &mut components, "CTE_COGENNEPB"),
+        red1: get_factor(matches, &mut components, "CTE_RED1"),
+        red2: get_factor(matches, &mut components, "CTE_RED2"),
+        residuos: get_factor(matches, &mut components, "CTE_RESIDUOS"),
+        cogen_to_grid: get_factor(matches, &mut components, "CTE_COGEN"),
+        cogen_to_nepb: get_factor(matches, &mut components, "CTE_COGENNEPB"),
     };
+    set_district_factors(matches, &mut components);
 
     if verbosity > 2 {
         println!("Factores de paso de usuario:\n{:?}", user_wf)
@@ -425,55 +1727,186 @@ fn main() {
 
     let fp_path_cli = matches.value_of("archivo_factores");
     let loc_cli = matches.value_of("fps_loc");
-    let loc_meta = components.get_meta("CTE_LOCALIZACION");
+    let loc_meta = cte::location_from_components(&components).unwrap_or_else(|e| {
+        eprintln!(
+            "ERROR [{}]: localización incorrecta en los metadatos de los componentes: {}",
+            e.code(),
+            e
+        );
+        exit(exitcode::DATAERR);
+    });
 
-    // CLI path > CLI loc > Meta loc > error
-    let (orig_fp, param_fp, fp_opt) = match (fp_path_cli, loc_cli, loc_meta) {
-        (Some(fp_cli), _, _) => {
-            let fp = cte::wfactors_from_str(&readfile(fp_cli), user_wf, default_userwf);
-            ("archivo", fp_cli.to_string(), fp)
+    if let (Some(l_cli), Some(l_meta)) = (loc_cli, loc_meta) {
+        if l_cli != l_meta {
+            warn(
+                "W106",
+                format!(
+                    "localización en componentes ({}) y de usuario ({}) distintas",
+                    l_meta, l_cli
+                ),
+            );
         }
-        (None, Some(l_cli), _) => {
-            let fp = cte::wfactors_from_loc(&l_cli, &default_locwf, user_wf, default_userwf);
-            ("usuario", l_cli.to_string(), fp)
+    }
+
+    // CLI path > CLI loc > Factores incluidos en componentes > Meta loc > error
+    // Con zona climática en los metadatos se aplica a los factores de paso, salvo que no sea
+    // coherente con la localización de usuario (ver avisos W106 y W107). Una zona climática
+    // incorrecta es un error
+    let from_loc = |loc: &str| {
+        let fp = default_locwf
+            .get(loc)
+            .cloned()
+            .ok_or_else(|| EpbdError::ParseError(format!("Localizacion: {}", loc)))?;
+        match components.get_meta("CTE_ZONACLIMATICA") {
+            Some(zone) if !cte::is_climate_zone(&zone) => {
+                Err(EpbdError::ParseError(format!("Zona climática: {}", zone)))
+            }
+            Some(zone) => Ok(
+                cte::wfactors_set_zone(fp.clone(), &zone).unwrap_or_else(|e| {
+                    warn(
+                        "W107",
+                        format!("zona climática de los componentes no aplicada: {}", e),
+                    );
+                    fp
+                }),
+            ),
+            None => Ok(fp),
         }
-        (None, None, Some(l_meta)) => {
-            let fp = cte::wfactors_from_loc(&l_meta, &default_locwf, user_wf, default_userwf);
-            ("metadatos", l_meta, fp)
+    };
+    let (orig_fp, param_fp, fp_opt) = match (fp_path_cli, loc_cli, embedded_factors, loc_meta) {
+        (Some(fp_cli), _, _, _) => {
+            let fp = Factors::from_str_with_format(&readfile(fp_cli), &csv_format);
+            ("archivo", fp_cli.to_string(), fp)
         }
+        (None, Some(l_cli), _, _) => ("usuario", l_cli.to_string(), from_loc(l_cli)),
+        (None, None, Some(factors), _) => (
+            "componentes",
+            matches.value_of("archivo_componentes").unwrap().to_string(),
+            Ok(factors),
+        ),
+        (None, None, None, Some(l_meta)) => ("metadatos", l_meta.to_string(), from_loc(l_meta)),
         _ => {
-            eprintln!("ERROR: datos insuficientes para determinar los factores de paso");
+            eprintln!("ERROR [E506]: datos insuficientes para determinar los factores de paso");
             exit(exitcode::USAGE);
         }
     };
 
-    let mut fpdata = fp_opt.unwrap_or_else(|e| {
-        eprintln!(
-            "ERROR: parámetros incorrectos para generar los factores de paso: {}",
-            e
-        );
-        exit(exitcode::DATAERR);
-    });
+    let (mut fpdata, corrections) = fp_opt
+        .and_then(|fp| {
+            cte::wfactors_set_districts_from_components(
+                fp.set_user_wfactors(user_wf),
+                &components,
+                &default_userwf,
+            )
+        })
+        .and_then(|fp| fp.normalize_with_warnings(&default_userwf))
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: parámetros incorrectos para generar los factores de paso: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
 
     println!("Factores de paso ({}): {}", orig_fp, param_fp);
+    if verbosity > 0 {
+        for warning in &corrections {
+            warn(warning.code(), warning);
+        }
+    }
+
+    // Factores de paso de usuario que sustituyen a los definidos
+    // Se aplican antes de obtener los factores de la pila de combustible, que pueden depender de ellos
+    // (p.e. el factor de suministro del HIDROGENO, que no está definido en los factores reglamentarios)
+    let mut user_cogen = false;
+    if let Some(path) = matches.value_of("archivo_factores_usuario") {
+        let user_fp = Factors::from_str_with_format(&readfile(path), &csv_format)
+            .unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: formato incorrecto del archivo de factores de paso de usuario \"{}\": {}",
+                e.code(),
+                path,
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        user_cogen = user_fp
+            .wdata
+            .iter()
+            .any(|f| f.carrier == Carrier::ELECTRICIDAD && f.source == Source::COGENERACION);
+        let decisions = fpdata.merge_overriding(&user_fp);
+        println!("Factores de paso de usuario: \"{}\"", path);
+        if verbosity > 0 {
+            for decision in decisions {
+                println!("  {}", decision);
+            }
+        }
+    }
+
+    // Factores de exportación de electricidad cogenerada con pila de combustible
+    // Los factores de cogeneración de usuario tienen prioridad
+    if components.get_meta("CTE_PILA_COMBUSTIBLE").is_some()
+        && user_wf.cogen_to_grid.is_none()
+        && user_wf.cogen_to_nepb.is_none()
+        && !user_cogen
+    {
+        fpdata = cte::wfactors_set_fuel_cell(fpdata, &components).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: no se han podido obtener los factores de la pila de combustible: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        println!(
+            "Factores de electricidad cogenerada (pila de combustible): {}",
+            components.get_meta("CTE_PILA_COMBUSTIBLE").unwrap()
+        );
+    }
+
+    // Factores de emisión de CO2 reglamentarios
+    if let Some(co2_loc) = matches.value_of("co2_loc") {
+        fpdata =
+            cte::wfactors_set_co2_from_loc(fpdata, co2_loc, default_locwf).unwrap_or_else(|e| {
+                eprintln!(
+                    "ERROR [{}]: no se han podido obtener los factores de emisión: {}",
+                    e.code(),
+                    e
+                );
+                exit(exitcode::DATAERR);
+            });
+        println!("Factores de emisión de CO2 (usuario): {}", co2_loc);
+    }
 
     // Simplificación de los factores de paso -----------------------------------------------------
     if !matches.is_present("nosimplificafps") && !components.cdata.is_empty() {
         let oldfplen = fpdata.wdata.len();
-        fpdata = fpdata.strip(&components);
+        let (stripped, removed) = fpdata.strip_with_warnings(&components);
+        fpdata = stripped;
         if verbosity > 1 {
             println!(
                 "Reducción de factores de paso: {} a {}",
                 oldfplen,
                 fpdata.wdata.len()
             );
+            for warning in &removed {
+                warn(warning.code(), warning);
+            }
         }
     }
 
     // Transformación a factores de paso en nearby
     if matches.is_present("acsnrb") {
         // Estamos en cálculo de ACS en nearby
-        fpdata = cte::wfactors_to_nearby(&fpdata);
+        fpdata = cte::wfactors_to_nearby(&fpdata).unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: no se han podido convertir los factores de paso al perímetro próximo: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
     }
 
     // Área de referencia -------------------------------------------------------------------------
@@ -484,7 +1917,7 @@ fn main() {
 
     if let (Some(a_meta), Some(a_cli)) = (arearef_meta, arearef_cli) {
         if (a_meta - a_cli).abs() > 1e-3 {
-            println!("AVISO: área de referencia A_ref en componentes ({:.1}) y de usuario ({:.1}) distintos", a_meta, a_cli);
+            warn("W102", format!("área de referencia A_ref en componentes ({:.1}) y de usuario ({:.1}) distintos", a_meta, a_cli));
         };
     }
 
@@ -508,7 +1941,7 @@ fn main() {
 
     if let (Some(k_meta), Some(k_cli)) = (kexp_meta, kexp_cli) {
         if (k_meta - k_cli).abs() > 1e-3 {
-            println!("AVISO: factor de exportación k_exp en componentes ({:.1}) y de usuario ({:.1}) distintos", k_meta, k_cli);
+            warn("W103", format!("factor de exportación k_exp en componentes ({:.1}) y de usuario ({:.1}) distintos", k_meta, k_cli));
         };
     }
 
@@ -524,13 +1957,92 @@ fn main() {
 
     println!("Factor de exportación ({}) [-]: {:.1}", orig_kexp, kexp);
 
+    // kexp para usos no EPB ----------------------------------------------------------------------
+    // CLI > Metadatos de componentes > k_exp
+    let kexp_nepb = kexp_nepb_cli
+        .or_else(|| {
+            components
+                .get_meta("CTE_KEXP_NEPB")
+                .and_then(|ref kexpstr| validate_kexp(kexpstr, "metadatos"))
+        })
+        .unwrap_or(kexp);
+
+    // Actualiza metadato CTE_KEXP_NEPB al valor seleccionado
+    components.set_meta("CTE_KEXP_NEPB", &format!("{:.1}", kexp_nepb));
+
+    if (kexp_nepb - kexp).abs() > 1e-3 {
+        println!(
+            "Factor de exportación para usos no EPB [-]: {:.1}",
+            kexp_nepb
+        );
+    }
+
+    // Periodo de compensación de la energía producida --------------------------------------------
+    // CLI > Metadatos de componentes > Paso de cálculo
+    if let Some(period) = matches.value_of("periodo_compensacion") {
+        components.set_meta("CTE_PERIODO_COMPENSACION", period);
+    }
+    match components.compensation_period() {
+        Ok(Some(period)) => println!(
+            "Periodo de compensación de la energía producida: {}",
+            period
+        ),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!(
+                "ERROR [{}]: periodo de compensación incorrecto en los metadatos de los componentes: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        }
+    }
+
+    // Compensación de la energía producida servicio a servicio -----------------------------------
+    match components.compensation_by_service() {
+        Ok(true) => match components.service_priority() {
+            Ok(Some(priority)) => println!(
+                "Compensación de la energía producida: por servicio (prioridad de la producción NDEF: {})",
+                priority
+                    .iter()
+                    .map(|s| s.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Ok(None) => println!("Compensación de la energía producida: por servicio"),
+            Err(e) => {
+                eprintln!(
+                    "ERROR [{}]: prioridad de servicios incorrecta en los metadatos de los componentes: {}",
+                    e.code(),
+                    e
+                );
+                exit(exitcode::DATAERR);
+            }
+        },
+        Ok(false) => (),
+        Err(e) => {
+            eprintln!(
+                "ERROR [{}]: compensación por servicio incorrecta en los metadatos de los componentes: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        }
+    }
+
     // Guardado de componentes energéticos --------------------------------------------------------
     if matches.is_present("gen_archivo_componentes") {
         let path = matches.value_of_os("gen_archivo_componentes").unwrap();
         if verbosity > 2 {
             println!("Componentes energéticos:\n{}", components);
         }
-        writefile(&path, components.to_string().as_bytes());
+        // Salvo que se indique, se guardan solo los componentes declarados por el usuario
+        let components_out = if matches.is_present("incluye_generados") {
+            components.to_string()
+        } else {
+            components.clone().strip_generated().to_string()
+        };
+        writefile(path, components_out.as_bytes());
         if verbosity > 0 {
             println!("Guardado archivo de componentes energéticos: {:?}", path);
         }
@@ -542,7 +2054,7 @@ fn main() {
         if verbosity > 2 {
             println!("Factores de paso:\n{}", fpdata);
         }
-        writefile(&path, fpdata.to_string().as_bytes());
+        writefile(path, fpdata.to_string().as_bytes());
         if verbosity > 0 {
             println!("Guardado archivo de factores de paso: {:?}", path);
         }
@@ -552,26 +2064,145 @@ fn main() {
     let maybe_demanda_anual_acs = matches
         .value_of("demanda_anual_acs")
         .and_then(|v| {
-            v.parse::<f32>().ok().or_else(|| {
-                eprintln!("ERROR: demanda anual de ACS con formato incorrecto");
+            v.parse::<Real>().ok().or_else(|| {
+                eprintln!("ERROR [E507]: demanda anual de ACS con formato incorrecto");
                 exit(exitcode::DATAERR);
             })
         })
-        .or_else(|| components.get_meta_f32("CTE_ACS_DEMANDA_ANUAL"))
+        .or_else(|| components.get_meta_real("CTE_ACS_DEMANDA_ANUAL"))
         .or(None);
 
+    // Tarifas energéticas: CLI > None ------------------------------------------------------------
+    let maybe_tariffs = matches.value_of("archivo_tarifas").map(|path| {
+        let tariffs = readfile(path).parse::<Tariffs>().unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: formato incorrecto del archivo de tarifas \"{}\": {}",
+                e.code(),
+                path,
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        if verbosity > 0 {
+            println!("Tarifas energéticas: \"{}\"", path);
+        }
+        tariffs
+    });
+
+    // Factor de coincidencia de cargas ----------------------------------------------------------
+    let load_matching = if matches.is_present("factor_coincidencia") {
+        if verbosity > 0 {
+            println!("Factor de coincidencia de cargas: fórmula B.32");
+        }
+        LoadMatching::B32
+    } else {
+        LoadMatching::Unit
+    };
+
+    // Nivel de detalle de la salida en formato de texto simple -----------------------------------
+    let detail: cte::PlainDetail = matches
+        .value_of("detalle")
+        .and_then(|d| d.parse().ok())
+        .unwrap_or_default();
+
+    // Caché de resultados ------------------------------------------------------------------------
+    // Se reutilizan los resultados de un cálculo anterior con los mismos datos de entrada y
+    // las mismas opciones que modifican los resultados (nivel de detalle e idioma)
+    let cache_path = matches.value_of("cache").map(|dir| {
+        let key = cache_key(&[
+            &components.to_string(),
+            &fpdata.to_string(),
+            &format!(
+                "{:?} {:?} {:?} {:?} {:?} {:?} {:?} {}",
+                kexp,
+                kexp_nepb,
+                arearef,
+                maybe_demanda_anual_acs,
+                load_matching,
+                kexp_sweep,
+                detail,
+                i18n::lang()
+            ),
+            &maybe_tariffs
+                .as_ref()
+                .map(Tariffs::to_string)
+                .unwrap_or_default(),
+        ]);
+        Path::new(dir).join(format!("{}.json", key))
+    });
+    let cached_results = cache_path.as_ref().and_then(|path| {
+        read_to_string(path)
+            .ok()
+            .and_then(|json| serde_json::from_str::<Results>(&json).ok())
+    });
+
     // Cálculo del balance ------------------------------------------------------------------------
-    let balance: Option<Balance> = if !components.cdata.is_empty() {
-        let balance = energy_performance(&components, &fpdata, kexp, arearef)
-            .map(|b| cte::incorpora_demanda_renovable_acs_nrb(b, maybe_demanda_anual_acs))
-            .unwrap_or_else(|e| {
+    let results: Option<Results> = if let Some(results) = cached_results {
+        println!(
+            "Resultados recuperados de la caché: \"{}\"",
+            cache_path.as_ref().unwrap().display()
+        );
+        Some(results)
+    } else if !components.cdata.is_empty() {
+        let balance = energy_performance_with_load_matching(
+            &components,
+            &fpdata,
+            kexp,
+            kexp_nepb,
+            arearef,
+            load_matching,
+        )
+        .map(|b| cte::incorpora_demanda_renovable_acs_nrb(b, maybe_demanda_anual_acs))
+        .and_then(|b| match &maybe_tariffs {
+            Some(tariffs) => cte::incorpora_factura_estimada(b, tariffs),
+            None => Ok(b),
+        })
+        .unwrap_or_else(|e| {
+            eprintln!(
+                "ERROR [{}]: no se ha podido calcular el balance energético: {}",
+                e.code(),
+                e
+            );
+            exit(exitcode::DATAERR);
+        });
+        let mut results = Results::from_balance(&balance, detail);
+        // Sensibilidad de los indicadores al factor de exportación
+        if let Some(k_exps) = &kexp_sweep {
+            let balances = energy_performance_by_kexp(&balance, k_exps).unwrap_or_else(|e| {
                 eprintln!(
-                    "ERROR: no se ha podido calcular el balance energético: {}",
+                    "ERROR [{}]: no se ha podido calcular la sensibilidad al factor de exportación: {}",
+                    e.code(),
                     e
                 );
                 exit(exitcode::DATAERR);
             });
-        Some(balance)
+            results.plain = format!(
+                "{}\n{}",
+                results.plain,
+                cte::balance_by_kexp_to_plain(&balances)
+            );
+        }
+        // Guarda los resultados en la caché. Los errores no impiden continuar
+        if let Some(path) = &cache_path {
+            let saved = path
+                .parent()
+                .map_or(Ok(()), std::fs::create_dir_all)
+                .and_then(|_| {
+                    let json = serde_json::to_string(&results).unwrap_or_default();
+                    std::fs::write(path, json)
+                });
+            if let Err(e) = saved {
+                warn(
+                    "W105",
+                    format!(
+                        "no se han podido guardar los resultados en la caché \"{}\": {}",
+                        path.display(),
+                        e
+                    ),
+                );
+            }
+        }
+        Some(results)
     } else if matches.is_present("gen_archivos_factores") {
         println!(
             "No se calcula el balance pero se ha generado el archivo de factores de paso {:?}",
@@ -584,21 +2215,14 @@ fn main() {
     };
 
     // Salida de resultados -----------------------------------------------------------------------
-    if let Some(balance) = balance {
+    if let Some(results) = results {
         // Guardar balance en formato json
         if matches.is_present("archivo_salida_json") {
             let path = matches.value_of_os("archivo_salida_json").unwrap();
             if verbosity > 0 {
                 println!("Resultados en formato JSON: {:?}", path);
             }
-            let json = serde_json::to_string_pretty(&balance).unwrap_or_else(|e| {
-                eprintln!(
-                    "ERROR: conversión incorrecta del balance energético a JSON: {}",
-                    e
-                );
-                exit(exitcode::DATAERR);
-            });
-            writefile(&path, json.as_bytes());
+            writefile(path, results.json.as_bytes());
         }
         // Guardar balance en formato XML
         if matches.is_present("archivo_salida_xml") {
@@ -606,17 +2230,41 @@ fn main() {
             if verbosity > 0 {
                 println!("Resultados en formato XML: {:?}", path);
             }
-            let xml = cte::balance_to_xml(&balance);
-            writefile(&path, xml.as_bytes());
+            writefile(path, results.xml.as_bytes());
         }
-        // Mostrar siempre en formato de texto plano
+        // Guardar balance en formato XML de registro
+        if matches.is_present("archivo_salida_xml_registro") {
+            let path = matches.value_of_os("archivo_salida_xml_registro").unwrap();
+            if verbosity > 0 {
+                println!("Resultados en formato XML de registro: {:?}", path);
+            }
+            let xml = results.xml_registro.unwrap_or_else(|e| {
+                eprintln!("{}", e);
+                exit(exitcode::DATAERR);
+            });
+            writefile(path, xml.as_bytes());
+        }
+        // Mostrar en el formato seleccionado (por defecto, texto simple)
+        let formatted = match matches.value_of("formato_salida") {
+            Some("json") => &results.json,
+            Some("xml") => &results.xml,
+            Some("markdown") => &results.markdown,
+            _ => &results.plain,
+        };
         if matches.is_present("acsnrb") {
             println!("** Balance energético (servicio de ACS, perímetro próximo)");
         } else {
             println!("** Balance energético");
         }
-        let plain = cte::balance_to_plain(&balance);
-        println!("{}", plain);
+        println!("{}", formatted);
+
+        // Guardar balance en el formato seleccionado
+        if let Some(path) = matches.value_of_os("archivo_salida") {
+            if verbosity > 0 {
+                println!("Resultados en el formato seleccionado: {:?}", path);
+            }
+            writefile(path, formatted.as_bytes());
+        }
 
         // Guardar balance en formato de texto plano
         if matches.is_present("archivo_salida_txt") {
@@ -624,7 +2272,40 @@ fn main() {
             if verbosity > 0 {
                 println!("Resultados en formato XML: {:?}", path);
             }
-            writefile(&path, plain.as_bytes());
+            writefile(path, results.plain.as_bytes());
+        }
+
+        // Guardar balance en formato CSV
+        if matches.is_present("archivo_salida_csv") {
+            let path = matches.value_of_os("archivo_salida_csv").unwrap();
+            if verbosity > 0 {
+                println!("Resultados en formato CSV: {:?}", path);
+            }
+            writefile(path, results.csv.as_bytes());
+        }
+
+        // Guardar todos los formatos de salida y el registro de avisos en un directorio
+        if let Some(dir) = matches.value_of_os("directorio_salida") {
+            let dir = Path::new(dir);
+            if let Err(e) = std::fs::create_dir_all(dir) {
+                eprintln!(
+                    "ERROR [E402]: no se ha podido crear el directorio \"{}\": {}",
+                    dir.display(),
+                    e
+                );
+                exit(exitcode::CANTCREAT);
+            }
+            println!("Resultados en todos los formatos: \"{}\"", dir.display());
+            writefile(dir.join("balance.txt"), results.plain.as_bytes());
+            writefile(dir.join("balance.xml"), results.xml.as_bytes());
+            writefile(dir.join("balance.json"), results.json.as_bytes());
+            writefile(dir.join("balance.csv"), results.csv.as_bytes());
+            let warnings = WARNINGS.lock().unwrap();
+            let log = warnings
+                .iter()
+                .map(|w| format!("{}\n", w))
+                .collect::<String>();
+            writefile(dir.join("avisos.txt"), log.as_bytes());
         }
     };
 }
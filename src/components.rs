@@ -34,21 +34,38 @@ Los componentes modelizan el uso y producción de energía en el periodo de cál
 Hipótesis:
 
 - Se completa automáticamente el consumo de energía procedente del medioambiente con una producción
-- No se permite la producción de electricidad a usos concretos (se asume NDEF) (XXX: se podría eliminar)
+- No se permite la producción de electricidad a usos concretos (se asume NDEF), salvo que se compense
+  la producción servicio a servicio (`CTE_COMPENSACION_SERVICIOS`)
 */
 
-use std::collections::HashSet;
+use std::borrow::Cow;
+use std::collections::{BTreeSet, HashSet};
 use std::fmt;
+use std::ops::Range;
 use std::str;
 
+use log::{debug, info, warn};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    error::EpbdError,
-    types::{CSubtype, CType, Carrier, Component, Meta, MetaVec, Service},
-    vecops::{veclistsum, vecvecdif, vecvecmin, vecvecmul, vecvecsum},
+    error::{Diagnostic, EpbdError, Warning},
+    i18n::{self, tr_format, Lang},
+    types::{
+        CSubtype, CType, Carrier, Component, Conversion, EnergyNeed, Meta, MetaVec, Real, Service,
+        Storage, System, SystemEfficiency, Timestep, UnknownComponent, COMPONENT_DECIMALS,
+        DAYS_PER_MONTH, GENERATED_TAG,
+    },
+    validate_wfactors,
+    vecops::{
+        vecitersum, veclistsum, vecvecdif, vecvecdif_assign, vecvecmin, vecvecmul, vecvecsum,
+        vecvecsum_assign,
+    },
+    Factors,
 };
 
+/// Anualidad: etiqueta e intervalo de pasos de cálculo
+pub type AnnualPeriod = (String, Range<usize>);
+
 /// Lista de datos de componentes con sus metadatos
 ///
 /// List of component data bundled with its metadata
@@ -59,9 +76,323 @@ use crate::{
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Components {
     /// Component list
+    #[serde(default)]
     pub cmeta: Vec<Meta>,
     /// Metadata
     pub cdata: Vec<Component>,
+    /// Components with unknown carrier or service (excluded from the balance)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub cunknown: Vec<UnknownComponent>,
+}
+
+/// Comprueba que los ajustes de consumo no dan lugar a consumos negativos
+///
+/// Para cada vector y subtipo (EPB, NEPB) con ajustes, la suma de los consumos y ajustes
+/// de cada paso de cálculo no puede ser negativa.
+fn check_adjustments(cdata: &[Component]) -> Result<(), EpbdError> {
+    let adjusted: HashSet<_> = cdata
+        .iter()
+        .filter(|c| c.ctype == CType::AJUSTE)
+        .map(|c| (c.carrier.clone(), c.csubtype))
+        .collect();
+    for (carrier, csubtype) in adjusted {
+        let values: Vec<&[Real]> = cdata
+            .iter()
+            .filter(|c| c.is_used_energy() && c.carrier == carrier && c.csubtype == csubtype)
+            .map(|c| c.values.as_slice())
+            .collect();
+        if veclistsum(&values).iter().any(|v| *v < -1e-3) {
+            return Err(EpbdError::WrongInput(format!(
+                "Los ajustes de consumo de {} ({}) dan lugar a consumos negativos",
+                carrier, csubtype
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Vectores verosímiles para el consumo de un servicio
+///
+/// Devuelve `None` cuando no se restringen los vectores del servicio.
+fn plausible_carriers(service: Service) -> Option<&'static [Carrier]> {
+    use Carrier::*;
+    match service {
+        Service::ILU | Service::VEN | Service::BAC | Service::RECARGAVE => Some(&[ELECTRICIDAD]),
+        Service::DHU => Some(&[ELECTRICIDAD, MEDIOAMBIENTE]),
+        Service::REF => Some(&[ELECTRICIDAD, MEDIOAMBIENTE, GASNATURAL, RED1, RED2]),
+        _ => None,
+    }
+}
+
+/// Aviso sobre la verosimilitud de los componentes
+///
+/// Warning about implausible component data
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ComponentWarning {
+    /// Consumo de un vector poco habitual para el servicio
+    ImplausibleService(Carrier, Service),
+    /// Consumo EPB sin servicio asignado (NDEF)
+    UndefinedService(Carrier),
+    /// Componentes asignados a un sistema no declarado
+    UndeclaredSystem(i32),
+    /// Sistema declarado sin componentes asignados
+    UnusedSystem(i32),
+    /// Sistema sin consumo ni producción del vector declarado
+    SystemCarrierMismatch(i32, Carrier),
+    /// Componente con vector o servicio desconocido, excluido del balance
+    UnknownComponent(String),
+}
+
+impl ComponentWarning {
+    /// Código estable del aviso
+    pub fn code(&self) -> &'static str {
+        match self {
+            ComponentWarning::ImplausibleService(..) => "W201",
+            ComponentWarning::UndefinedService(_) => "W202",
+            ComponentWarning::UndeclaredSystem(_) => "W203",
+            ComponentWarning::UnusedSystem(_) => "W204",
+            ComponentWarning::SystemCarrierMismatch(..) => "W205",
+            ComponentWarning::UnknownComponent(_) => "W206",
+        }
+    }
+}
+
+impl fmt::Display for ComponentWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            ComponentWarning::ImplausibleService(carrier, service) => tr_format!(
+                "consumo de {} para el servicio {} poco habitual",
+                carrier,
+                service
+            ),
+            ComponentWarning::UndefinedService(carrier) => {
+                tr_format!("consumo EPB de {} sin servicio asignado (NDEF)", carrier)
+            }
+            ComponentWarning::UndeclaredSystem(id) => {
+                tr_format!("componentes asignados al sistema {} no declarado", id)
+            }
+            ComponentWarning::UnusedSystem(id) => {
+                tr_format!("sistema {} declarado sin componentes asignados", id)
+            }
+            ComponentWarning::SystemCarrierMismatch(id, carrier) => tr_format!(
+                "el sistema {} no tiene consumo ni producción del vector declarado {}",
+                id,
+                carrier
+            ),
+            ComponentWarning::UnknownComponent(identifier) => tr_format!(
+                "componente con vector o servicio desconocido {}, excluido del balance",
+                identifier
+            ),
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// Energía usada en usos EPB asignada a un servicio en cada paso de cálculo
+///
+/// La energía disponible en cada paso (`available_t`) cubre el consumo de los servicios de
+/// `priority` en ese orden y el resto se reparte entre los demás servicios en proporción a su
+/// consumo en el paso de cálculo. Sin orden de prioridad el reparto es proporcional al consumo.
+///
+/// * `used_by_service` - consumo EPB de cada servicio en cada paso de cálculo
+#[allow(non_snake_case)]
+fn allocate_to_service(
+    available_t: &[Real],
+    used_by_service: &[(Service, Vec<Real>)],
+    priority: &[Service],
+    service: Service,
+) -> Vec<Real> {
+    let mut available_t = available_t.to_vec();
+    let mut E_srv_t = vec![0.0; available_t.len()];
+    for prio_service in priority {
+        if let Some((_, used_t)) = used_by_service.iter().find(|(s, _)| s == prio_service) {
+            for ((avail, srv), used) in available_t.iter_mut().zip(&mut E_srv_t).zip(used_t) {
+                let allocated = avail.min(*used);
+                *avail -= allocated;
+                if *prio_service == service {
+                    *srv = allocated;
+                }
+            }
+        }
+    }
+    if priority.contains(&service) {
+        return E_srv_t;
+    }
+    let others_t: Vec<Real> = available_t
+        .iter()
+        .enumerate()
+        .map(|(i, _)| {
+            used_by_service
+                .iter()
+                .filter(|(s, _)| !priority.contains(s))
+                .map(|(_, used_t)| used_t[i])
+                .sum()
+        })
+        .collect();
+    if let Some((_, used_t)) = used_by_service.iter().find(|(s, _)| *s == service) {
+        for (((srv, avail), used), others) in E_srv_t
+            .iter_mut()
+            .zip(&available_t)
+            .zip(used_t)
+            .zip(&others_t)
+        {
+            if used.abs() >= Real::EPSILON {
+                *srv = avail * used / others;
+            }
+        }
+    }
+    E_srv_t
+}
+
+/// Horas de un año (paso de cálculo de las anualidades) [h]
+const HOURS_PER_YEAR: Real = 8760.0;
+
+/// Comprueba que todos los componentes tienen el mismo número de valores que el primero
+///
+/// Los componentes con un número de valores distinto se identifican por su número de línea, si
+/// se conoce, o por su posición en la lista de componentes (empezando en 1).
+fn check_values_len(cdata: &[Component], lines: Option<&[usize]>) -> Result<(), EpbdError> {
+    let num_steps = match cdata.first() {
+        Some(c) => c.values.len(),
+        None => return Ok(()),
+    };
+    let position = |i: usize| match lines {
+        Some(lines) => format!("línea {}", lines[i]),
+        None => format!("componente {}", i + 1),
+    };
+    let offending: Vec<_> = cdata
+        .iter()
+        .enumerate()
+        .filter(|(_, c)| c.values.len() != num_steps)
+        .map(|(i, c)| format!("{} ({} valores)", position(i), c.values.len()))
+        .collect();
+    if offending.is_empty() {
+        return Ok(());
+    }
+    Err(EpbdError::WrongInput(format!(
+        "todos los componentes deben tener el mismo número de valores que el primero ({}, {} valores): {}",
+        position(0),
+        num_steps,
+        offending.join(", ")
+    )))
+}
+
+/// Comprueba que el número de valores de los componentes es coherente con el paso de cálculo declarado
+///
+/// Si se declara el paso de cálculo (metadato `CTE_PASO`), el número de valores de los
+/// componentes debe corresponder a una o varias anualidades completas.
+fn check_timestep(cmeta: &[Meta], cdata: &[Component]) -> Result<(), EpbdError> {
+    let timestep: Timestep = match cmeta.iter().find(|m| m.key == "CTE_PASO") {
+        Some(meta) => meta.value.parse()?,
+        None => return Ok(()),
+    };
+    let num_steps = match cdata.first() {
+        Some(c) => c.values.len(),
+        None => return Ok(()),
+    };
+    if num_steps % timestep.steps_per_year() != 0 {
+        return Err(EpbdError::WrongInput(format!(
+            "los componentes tienen {} valores y el paso de cálculo {} requiere un múltiplo de {}",
+            num_steps,
+            timestep,
+            timestep.steps_per_year()
+        )));
+    }
+    Ok(())
+}
+
+/// Indicadores de utilización de un sistema
+///
+/// Utilization indicators of a system
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemUtilization {
+    /// System id
+    pub id: i32,
+    /// Energy of the declared carrier produced by the system or, if it doesn't produce, consumed [kWh]
+    pub energy: Real,
+    /// Full-load equivalent hours per year [h] (None for systems without nominal power)
+    pub full_load_hours: Option<Real>,
+    /// Capacity factor [-] (only for producing systems with nominal power)
+    pub capacity_factor: Option<Real>,
+}
+
+/// Balance de una conversión de energía en el edificio
+///
+/// Energy balance of an on-site conversion between two systems, for the whole calculation period [kWh]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConversionBalance {
+    /// Conversion declaration
+    pub conversion: Conversion,
+    /// Energy used by the origin system [kWh]
+    pub input: Real,
+    /// Converted energy (input x efficiency) [kWh]
+    pub output: Real,
+    /// Converted energy used by the destination system (intermediate flow) [kWh]
+    pub used: Real,
+    /// Conversion losses (input - output) [kWh]
+    pub losses: Real,
+    /// Converted energy not used by the destination system (output - used) [kWh]
+    pub unused: Real,
+}
+
+/// Comentario de los ajustes generados por una conversión desde el sistema `from`
+fn conversion_comment(from: i32) -> String {
+    tr_format!("{} Conversión desde el sistema {}", GENERATED_TAG, from)
+}
+
+/// Indica si el comentario es el de los ajustes generados por una conversión desde el sistema `from`
+///
+/// Se reconocen los comentarios generados en cualquier idioma (ver `i18n`).
+fn is_conversion_comment(comment: &str, from: i32) -> bool {
+    Lang::ALL.iter().any(|lang| {
+        comment
+            == i18n::format(
+                i18n::tr_lang(*lang, "{} Conversión desde el sistema {}"),
+                &[&GENERATED_TAG, &from],
+            )
+    })
+}
+
+/// Tolerancia para considerar equilibrados el consumo y la producción de energía del medioambiente [kWh]
+///
+/// Evita generar componentes de producción por errores de redondeo.
+const ENV_BALANCE_TOLERANCE: Real = 1e-3;
+
+/// Prefijo del comentario de los componentes generados a partir de las necesidades de energía
+const NEEDS_COMMENT: &str = "Necesidad de energía";
+
+/// Atributo (`clave=valor`) con el servicio de los componentes generados a partir de las necesidades de energía
+const NEEDS_ATTR: &str = "necesidad";
+
+/// Comentario de los componentes generados para la necesidad de `service` atendida por el sistema `id`
+///
+/// Incluye el atributo `NEEDS_ATTR` para identificar los componentes generados en cualquier idioma.
+fn needs_comment(service: Service, id: i32) -> String {
+    format!(
+        "{} {}={}",
+        tr_format!(
+            "{} {} de {} atendida por el sistema {}",
+            GENERATED_TAG,
+            i18n::tr(NEEDS_COMMENT),
+            service,
+            id
+        ),
+        NEEDS_ATTR,
+        service
+    )
+}
+
+/// Diferencia de componentes respecto a un caso base
+///
+/// Difference of components with respect to a baseline (e.g. post-retrofit minus pre-retrofit)
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ComponentsDifference {
+    /// Differences of matching components (current minus baseline) and unmatched components
+    pub components: Components,
+    /// Components present only in the current case
+    pub only_current: Vec<Component>,
+    /// Components present only in the baseline case
+    pub only_baseline: Vec<Component>,
 }
 
 impl MetaVec for Components {
@@ -73,74 +404,2031 @@ impl MetaVec for Components {
     }
 }
 
+/// Representación textual de los componentes
+///
+/// Los valores se muestran con la precisión indicada en el formato (p.e. `{:.4}`) o, si no se
+/// indica, con la precisión por defecto (ver `types::COMPONENT_DECIMALS`).
 impl fmt::Display for Components {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let metalines = self
             .cmeta
             .iter()
-            .map(|v| format!("{}", v))
-            .collect::<Vec<_>>()
-            .join("\n");
-        let datalines = self
+            .map(|v| format!("{}", v))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let decimals = f.precision().unwrap_or(COMPONENT_DECIMALS);
+        let datalines = self
+            .cdata
+            .iter()
+            .map(|v| format!("{:.*}", decimals, v))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if self.cunknown.is_empty() {
+            write!(f, "{}\n{}", metalines, datalines)
+        } else {
+            let unknownlines = self
+                .cunknown
+                .iter()
+                .map(|v| format!("{}", v))
+                .collect::<Vec<_>>()
+                .join("\n");
+            write!(f, "{}\n{}\n{}", metalines, datalines, unknownlines)
+        }
+    }
+}
+
+impl str::FromStr for Components {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Components, Self::Err> {
+        components_from_str(s, &ParseOptions::default())
+    }
+}
+
+/// Opciones de interpretación de los componentes
+///
+/// Parsing options for components
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Keep components with unknown carrier or service instead of failing
+    pub keep_unknown: bool,
+    /// Service names remapping table (legacy or tool-specific name, service)
+    pub service_map: Vec<(String, Service)>,
+    /// Field delimiter and decimal separator of the text
+    pub format: CsvFormat,
+    /// Pad components with fewer values with zeros instead of failing (see `Components::pad_values`)
+    pub pad_values: bool,
+    /// Policy for negative values in consumption and production components
+    pub negative_values: NegativeValues,
+}
+
+/// Tratamiento de los valores negativos de los componentes de consumo y producción
+///
+/// Los valores negativos de consumos y producciones suelen deberse a errores de los datos y
+/// alteran el balance, por lo que pueden rechazarse o sustituirse por cero. Por defecto se admiten,
+/// para no cambiar el resultado de los datos existentes. Los componentes de ajuste (`AJUSTE`)
+/// tienen signo y no se ven afectados.
+///
+/// Policy for negative values in consumption and production components
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum NegativeValues {
+    /// Reject components with negative values
+    Reject,
+    /// Replace negative values with zero, with a warning
+    Clamp,
+    /// Accept negative values
+    #[default]
+    Allow,
+}
+
+impl str::FromStr for NegativeValues {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<NegativeValues, Self::Err> {
+        match s {
+            "rechaza" => Ok(NegativeValues::Reject),
+            "anula" => Ok(NegativeValues::Clamp),
+            "admite" => Ok(NegativeValues::Allow),
+            _ => Err(EpbdError::ParseError(s.into())),
+        }
+    }
+}
+
+/// Aplica a un componente el tratamiento de sus valores negativos
+///
+/// Devuelve el aviso de los valores sustituidos por cero, si existen, o un error que identifica
+/// el componente (`position`, p.e. su número de línea, si se indica) si se rechazan.
+fn apply_negative_values(
+    component: &mut Component,
+    policy: NegativeValues,
+    position: Option<&str>,
+) -> Result<Option<Warning>, EpbdError> {
+    if component.ctype == CType::AJUSTE {
+        return Ok(None);
+    }
+    let count = component.values.iter().filter(|v| **v < 0.0).count();
+    if count == 0 {
+        return Ok(None);
+    }
+    match policy {
+        NegativeValues::Allow => Ok(None),
+        NegativeValues::Reject => Err(EpbdError::WrongInput(match position {
+            Some(position) => format!(
+                "valores negativos ({}, {} valores): {}",
+                position, count, component
+            ),
+            None => format!("valores negativos ({} valores): {}", count, component),
+        })),
+        NegativeValues::Clamp => {
+            component
+                .values
+                .iter_mut()
+                .filter(|v| **v < 0.0)
+                .for_each(|v| *v = 0.0);
+            Ok(Some(Warning::ClampedNegativeValues {
+                component: format!(
+                    "{}, {}, {}, {}",
+                    component.carrier, component.ctype, component.csubtype, component.service
+                ),
+                count,
+            }))
+        }
+    }
+}
+
+/// Formato de los campos y números de los archivos de texto
+///
+/// Por defecto los campos se separan con comas y los números usan el punto decimal, pero las
+/// hojas de cálculo con configuración regional española exportan archivos con campos separados
+/// por punto y coma (`;`) y coma decimal.
+///
+/// Text format for fields and numbers (field delimiter and decimal separator)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CsvFormat {
+    /// Field delimiter
+    pub delimiter: char,
+    /// Decimal separator
+    pub decimal_separator: char,
+}
+
+impl Default for CsvFormat {
+    fn default() -> Self {
+        Self {
+            delimiter: ',',
+            decimal_separator: '.',
+        }
+    }
+}
+
+impl CsvFormat {
+    /// Formato de las hojas de cálculo con configuración regional española (`;` y coma decimal)
+    pub const SPANISH: CsvFormat = CsvFormat {
+        delimiter: ';',
+        decimal_separator: ',',
+    };
+
+    /// Convierte el texto al formato por defecto (campos separados por comas y punto decimal)
+    ///
+    /// Se conserva el número de líneas, de modo que los errores posteriores se localizan en
+    /// la línea del texto original. Se convierten las líneas de datos y de metadatos, pero no
+    /// los comentarios. El separador decimal solo se sustituye entre dos dígitos, para conservar
+    /// el texto de los metadatos, y se eliminan los separadores vacíos al final de las líneas,
+    /// que añaden las hojas de cálculo al exportar filas de distinta longitud.
+    ///
+    /// # Errors
+    ///
+    /// * Separador decimal distinto de `.` o `,`, separador de campos no válido o igual al decimal
+    pub fn to_canonical<'a>(&self, s: &'a str) -> Result<Cow<'a, str>, EpbdError> {
+        let Self {
+            delimiter,
+            decimal_separator,
+        } = *self;
+        if !(decimal_separator == '.' || decimal_separator == ',')
+            || delimiter == decimal_separator
+            || delimiter == '#'
+            || delimiter == ':'
+            || delimiter == '.'
+            || delimiter.is_alphanumeric()
+        {
+            return Err(EpbdError::WrongInput(format!(
+                "formato de texto no válido (separador de campos \"{}\" y separador decimal \"{}\")",
+                delimiter.escape_default(),
+                decimal_separator.escape_default()
+            )));
+        }
+        if *self == Self::default() {
+            return Ok(Cow::Borrowed(s));
+        }
+        let convert = |data: &str| -> String {
+            let chars: Vec<char> = data
+                .trim_end_matches(|c: char| c == delimiter || c.is_whitespace())
+                .chars()
+                .collect();
+            let is_digit = |i: Option<usize>| {
+                i.and_then(|i| chars.get(i))
+                    .map_or(false, char::is_ascii_digit)
+            };
+            chars
+                .iter()
+                .enumerate()
+                .map(|(i, &c)| match c {
+                    c if c == delimiter => ',',
+                    c if c == decimal_separator
+                        && is_digit(i.checked_sub(1))
+                        && is_digit(Some(i + 1)) =>
+                    {
+                        '.'
+                    }
+                    c => c,
+                })
+                .collect()
+        };
+        let s = s.strip_prefix('\u{feff}').unwrap_or(s);
+        let lines: Vec<String> = s
+            .lines()
+            .map(|line| {
+                let trimmed = line.trim_start();
+                if trimmed.starts_with("#META") || trimmed.starts_with("#CTE_") {
+                    convert(line)
+                } else {
+                    match line.find('#') {
+                        Some(pos) => {
+                            let (data, comment) = line.split_at(pos);
+                            format!("{} {}", convert(data), comment).trim().to_string()
+                        }
+                        None => convert(line),
+                    }
+                }
+            })
+            .collect();
+        Ok(Cow::Owned(lines.join("\n")))
+    }
+}
+
+/// Interpreta una tabla de equivalencias de nombres de servicio
+///
+/// Cada línea indica un nombre de servicio (antiguo o propio de una herramienta) y el servicio
+/// al que equivale, separados por una coma (p.e. `CALEFACCION, CAL`). Se ignoran las líneas
+/// vacías y los comentarios (que empiezan por `#`).
+///
+/// # Errors
+///
+/// * Línea con formato incorrecto o servicio desconocido
+pub fn parse_service_map(s: &str) -> Result<Vec<(String, Service)>, EpbdError> {
+    s.lines()
+        .map(str::trim)
+        .filter(|l| !(l.is_empty() || l.starts_with('#')))
+        .map(|l| {
+            let items: Vec<&str> = l.split(',').map(str::trim).collect();
+            match items.as_slice() {
+                [name, service] if !name.is_empty() => Ok((name.to_string(), service.parse()?)),
+                _ => Err(EpbdError::ParseError(l.into())),
+            }
+        })
+        .collect()
+}
+
+/// Sustituye el nombre del servicio de una línea de componente según la tabla de equivalencias
+fn remap_service<'a>(line: &'a str, service_map: &[(String, Service)]) -> Cow<'a, str> {
+    let (data, comment) = match line.find('#') {
+        Some(pos) => line.split_at(pos),
+        None => (line, ""),
+    };
+    let mut items: Vec<String> = data.split(',').map(|v| v.trim().to_string()).collect();
+    let srv_idx = if items[0].parse::<i32>().is_ok() {
+        4
+    } else {
+        3
+    };
+    match items
+        .get(srv_idx)
+        .and_then(|name| service_map.iter().find(|(n, _)| n == name))
+    {
+        Some((_, service)) => {
+            items[srv_idx] = service.to_string();
+            Cow::Owned(
+                format!("{} {}", items.join(", "), comment)
+                    .trim()
+                    .to_string(),
+            )
+        }
+        None => Cow::Borrowed(line),
+    }
+}
+
+/// Marcador de inicio de la sección de factores de paso incluidos en un archivo de componentes
+pub const FACTORS_SECTION: &str = "#FACTORES";
+
+/// Separa el texto de los componentes del de la sección de factores de paso incluidos, si existe
+fn split_factors_section(s: &str) -> (&str, Option<&str>) {
+    let mut start = 0;
+    for line in s.split_inclusive('\n') {
+        if line.trim() == FACTORS_SECTION {
+            return (&s[..start], Some(&s[start + line.len()..]));
+        }
+        start += line.len();
+    }
+    (s, None)
+}
+
+/// Interpreta los componentes y, si existen, los factores de paso incluidos en el mismo texto
+///
+/// Permite intercambiar un caso completo en un único archivo, en el que las líneas de factores
+/// de paso (y sus metadatos) se sitúan tras una línea con el marcador `#FACTORES`. Los factores
+/// se devuelven tal como se definen, sin completar ni normalizar.
+///
+/// # Errors
+///
+/// * Formato incorrecto de los componentes, de los factores de paso o de sus metadatos
+pub fn parse_components(
+    s: &str,
+    options: &ParseOptions,
+) -> Result<(Components, Option<Factors>), EpbdError> {
+    let components = components_from_str(s, options)?;
+    let factors = match split_factors_section(s) {
+        (components_section, Some(factors)) => {
+            // Los factores empiezan tras la línea del marcador #FACTORES
+            let offset = components_section.lines().count() + 1;
+            Some(
+                Factors::from_str_with_format(factors, &options.format)
+                    .map_err(|e| e.with_line_offset(offset))?,
+            )
+        }
+        (_, None) => None,
+    };
+    Ok((components, factors))
+}
+
+/// Metadatos y componentes interpretados línea a línea (ver `parse_lines`)
+struct ParsedLines {
+    /// Components data
+    components: Components,
+    /// Line number (starting at 1) of each component in `components.cdata`
+    cdata_lines: Vec<usize>,
+    /// Errors of the lines that can't be interpreted, with their line number
+    errors: Vec<(usize, EpbdError)>,
+}
+
+/// Interpreta, una a una, las líneas de metadatos y componentes de un texto en formato canónico
+///
+/// No se detiene en el primer error, sino que guarda los errores de cada línea, situados en
+/// ella (ver `EpbdError::at_line`). Aplica la reasignación de servicios y, si se indica en las
+/// opciones, conserva los componentes con vector o servicio desconocido.
+/// Es la interpretación común a `components_from_str` y `validate_components`.
+fn parse_lines(s: &str, options: &ParseOptions) -> ParsedLines {
+    let s_nobom = s.strip_prefix('\u{feff}').unwrap_or(s);
+    let mut parsed = ParsedLines {
+        components: Components::default(),
+        cdata_lines: Vec::new(),
+        errors: Vec::new(),
+    };
+    // Líneas con su número (empezando en 1), para localizar los errores
+    for (i, line) in s_nobom.lines().map(str::trim).enumerate() {
+        let num = i + 1;
+        if line.starts_with("#META") || line.starts_with("#CTE_") {
+            match line.parse::<Meta>() {
+                Ok(meta) => parsed.components.cmeta.push(meta),
+                Err(e) => parsed.errors.push((num, e.at_line(num, line))),
+            }
+            continue;
+        }
+        if line.starts_with('#') || line.starts_with("vector,") || line.is_empty() {
+            continue;
+        }
+        let line = remap_service(line, &options.service_map);
+        match line.parse::<Component>() {
+            Ok(component) => {
+                parsed.components.cdata.push(component);
+                parsed.cdata_lines.push(num);
+            }
+            Err(e) => match line.parse::<UnknownComponent>() {
+                Ok(unknown) if options.keep_unknown => parsed.components.cunknown.push(unknown),
+                _ => parsed.errors.push((num, e.at_line(num, &line))),
+            },
+        }
+    }
+    parsed
+}
+
+/// Interpreta los componentes según las opciones indicadas
+///
+/// Se ignora la sección de factores de paso incluidos, si existe.
+fn components_from_str(s: &str, options: &ParseOptions) -> Result<Components, EpbdError> {
+    let s = options.format.to_canonical(split_factors_section(s).0)?;
+    let ParsedLines {
+        mut components,
+        cdata_lines,
+        errors,
+    } = parse_lines(s.as_ref(), options);
+    if let Some((_, e)) = errors.into_iter().next() {
+        return Err(e);
+    }
+    if options.pad_values {
+        components.pad_values();
+    }
+    for (component, num) in components.cdata.iter_mut().zip(&cdata_lines) {
+        let position = format!("línea {}", num);
+        if let Some(warning) =
+            apply_negative_values(component, options.negative_values, Some(&position))?
+        {
+            warn!("[{}] {}: {}", warning.code(), position, warning);
+        }
+    }
+    check_values_len(&components.cdata, Some(&cdata_lines))?;
+    check_adjustments(&components.cdata)?;
+    check_timestep(&components.cmeta, &components.cdata)?;
+    Ok(components)
+}
+
+/// Comprueba todas las líneas de un texto de componentes y devuelve todos los problemas detectados
+///
+/// A diferencia de la interpretación de los componentes, que se interrumpe en el primer error,
+/// se comprueban todas las líneas de metadatos y componentes (formato, vector, tipo, servicio
+/// y número de valores) y, si no hay errores de formato, los ajustes y el paso de cálculo.
+/// Si existe una sección de factores de paso (`#FACTORES`) se comprueba con
+/// `validate_wfactors`. Los diagnósticos indican la línea (empezando en 1) en la que se
+/// detecta el problema, salvo para los problemas del conjunto de componentes.
+///
+/// Devuelve una lista vacía si los componentes son correctos.
+pub fn validate_components(s: &str, options: &ParseOptions) -> Vec<Diagnostic> {
+    let (s, factors) = split_factors_section(s);
+    let s = match options.format.to_canonical(s) {
+        Ok(s) => s,
+        Err(e) => return vec![Diagnostic::new(None, &e)],
+    };
+    let s = s.as_ref();
+    let ParsedLines {
+        mut components,
+        cdata_lines,
+        errors,
+    } = parse_lines(s, options);
+    let mut diagnostics: Vec<(usize, Diagnostic)> = errors
+        .iter()
+        .map(|(num, e)| (*num, Diagnostic::new(Some(*num), e)))
+        .collect();
+    for (component, num) in components.cdata.iter_mut().zip(&cdata_lines) {
+        if let Err(e) = apply_negative_values(component, options.negative_values, None) {
+            diagnostics.push((*num, Diagnostic::new(Some(*num), &e)));
+        }
+    }
+    if let Some(first) = components.cdata.first() {
+        if !options.pad_values {
+            for (component, num) in components.cdata.iter().zip(&cdata_lines) {
+                if component.values.len() != first.values.len() {
+                    diagnostics.push((
+                        *num,
+                        Diagnostic::new(
+                            Some(*num),
+                            &EpbdError::WrongInput(format!(
+                                "el número de valores ({}) no coincide con el del primer componente (línea {}: {})",
+                                component.values.len(),
+                                cdata_lines[0],
+                                first.values.len()
+                            )),
+                        ),
+                    ));
+                }
+            }
+        }
+    }
+    // Diagnósticos de las líneas en orden (es estable para los de una misma línea)
+    diagnostics.sort_by_key(|(num, _)| *num);
+    let mut diagnostics: Vec<Diagnostic> = diagnostics.into_iter().map(|(_, d)| d).collect();
+    if diagnostics.is_empty() {
+        if options.pad_values {
+            components.pad_values();
+        }
+        if let Err(e) = check_adjustments(&components.cdata)
+            .and_then(|_| check_timestep(&components.cmeta, &components.cdata))
+        {
+            diagnostics.push(Diagnostic::new(None, &e));
+        }
+    }
+    if let Some(factors) = factors {
+        // Los factores empiezan tras la línea del marcador #FACTORES
+        let offset = s.lines().count() + 1;
+        let factors = match options.format.to_canonical(factors) {
+            Ok(factors) => factors,
+            Err(e) => return vec![Diagnostic::new(None, &e)],
+        };
+        diagnostics.extend(validate_wfactors(&factors).into_iter().map(|d| Diagnostic {
+            line: d.line.map(|l| l + offset),
+            ..d
+        }));
+    }
+    diagnostics
+}
+
+impl Components {
+    /// Interpreta los componentes conservando los que tienen un vector o servicio desconocido
+    ///
+    /// Los componentes de versiones más recientes del formato, con vectores energéticos o
+    /// servicios no reconocidos, no producen un error sino que se conservan en `cunknown`,
+    /// se excluyen del balance y generan un aviso (ver `check_plausibility`).
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de los componentes o metadatos
+    pub fn from_str_keep_unknown(s: &str) -> Result<Components, EpbdError> {
+        Self::from_str_with_options(
+            s,
+            &ParseOptions {
+                keep_unknown: true,
+                ..Default::default()
+            },
+        )
+    }
+
+    /// Interpreta los componentes según las opciones indicadas
+    ///
+    /// Además de conservar los componentes desconocidos, permite usar una tabla de
+    /// equivalencias de nombres de servicio (ver `parse_service_map`) para leer archivos con
+    /// nombres de servicio antiguos o propios de otras herramientas (p.e. `CALEFACCION`).
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de los componentes o metadatos
+    pub fn from_str_with_options(s: &str, options: &ParseOptions) -> Result<Components, EpbdError> {
+        components_from_str(s, options)
+    }
+
+    /// Interpreta los componentes desde un documento JSON
+    ///
+    /// El documento tiene la misma estructura que la serialización de `Components` (metadatos
+    /// en `cmeta` y componentes en `cdata`, con los mismos campos que el formato de texto) y se
+    /// realizan las mismas comprobaciones que al interpretar el formato de texto.
+    ///
+    /// # Errors
+    ///
+    /// * Documento JSON incorrecto
+    /// * Componentes incoherentes (tipos, número de valores, ajustes o paso de cálculo)
+    pub fn from_json(s: &str) -> Result<Components, EpbdError> {
+        Self::from_json_with_options(s, &ParseOptions::default())
+    }
+
+    /// Interpreta los componentes desde un documento JSON aplicando el tratamiento de los valores
+    /// negativos de las opciones indicadas (ver `ParseOptions::negative_values`)
+    ///
+    /// # Errors
+    ///
+    /// * Documento JSON incorrecto
+    /// * Componentes incoherentes o con valores negativos, si se rechazan
+    pub fn from_json_with_options(
+        s: &str,
+        options: &ParseOptions,
+    ) -> Result<Components, EpbdError> {
+        let mut components: Components = serde_json::from_str(s)?;
+        components.apply_negative_values_with_log(options.negative_values)?;
+        components.check()?;
+        Ok(components)
+    }
+
+    /// Interpreta los componentes desde un documento YAML
+    ///
+    /// El documento tiene la misma estructura que el formato JSON (ver `Components::from_json`)
+    /// y se realizan las mismas comprobaciones.
+    ///
+    /// # Errors
+    ///
+    /// * Documento YAML incorrecto
+    /// * Componentes incoherentes (tipos, número de valores, ajustes o paso de cálculo)
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(s: &str) -> Result<Components, EpbdError> {
+        Self::from_yaml_with_options(s, &ParseOptions::default())
+    }
+
+    /// Interpreta los componentes desde un documento YAML aplicando el tratamiento de los valores
+    /// negativos de las opciones indicadas (ver `ParseOptions::negative_values`)
+    ///
+    /// # Errors
+    ///
+    /// * Documento YAML incorrecto
+    /// * Componentes incoherentes o con valores negativos, si se rechazan
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml_with_options(
+        s: &str,
+        options: &ParseOptions,
+    ) -> Result<Components, EpbdError> {
+        let mut components: Components = serde_yaml::from_str(s)?;
+        components.apply_negative_values_with_log(options.negative_values)?;
+        components.check()?;
+        Ok(components)
+    }
+
+    /// Convierte los componentes a un documento YAML
+    ///
+    /// # Errors
+    ///
+    /// * Componentes que no se pueden convertir a YAML
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(&self) -> Result<String, EpbdError> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
+    /// Comprueba la coherencia de los componentes
+    ///
+    /// Realiza las mismas comprobaciones que la interpretación del formato de texto, para los
+    /// componentes obtenidos por otros medios (p.e. desde JSON o XML).
+    ///
+    /// # Errors
+    ///
+    /// * Componentes incoherentes (tipos, número de valores, ajustes o paso de cálculo)
+    pub fn check(&self) -> Result<(), EpbdError> {
+        for component in &self.cdata {
+            component.check()?;
+        }
+        self.check_timesteps()?;
+        check_adjustments(&self.cdata)
+    }
+
+    /// Comprueba que todos los componentes tienen el mismo número de pasos de cálculo
+    ///
+    /// El número de valores de todos los componentes debe coincidir y, si se declara el paso
+    /// de cálculo (metadato `CTE_PASO`), corresponder a una o varias anualidades completas.
+    /// El error identifica los componentes con un número de valores distinto al del primero.
+    /// Los componentes con menos valores pueden completarse con ceros con `pad_values`.
+    ///
+    /// # Errors
+    ///
+    /// * Componentes con distinto número de valores o incoherente con el paso de cálculo
+    pub fn check_timesteps(&self) -> Result<(), EpbdError> {
+        check_values_len(&self.cdata, None)?;
+        check_timestep(&self.cmeta, &self.cdata)
+    }
+
+    /// Aplica el tratamiento indicado a los valores negativos de los componentes
+    ///
+    /// Los valores negativos de los componentes de consumo y producción se rechazan, se
+    /// sustituyen por cero, con un aviso por componente, o se admiten (ver `NegativeValues`).
+    ///
+    /// # Errors
+    ///
+    /// * Componentes con valores negativos, si se rechazan
+    pub fn apply_negative_values(
+        &mut self,
+        policy: NegativeValues,
+    ) -> Result<Vec<Warning>, EpbdError> {
+        let mut warnings = Vec::new();
+        for (i, component) in self.cdata.iter_mut().enumerate() {
+            let position = format!("componente {}", i + 1);
+            warnings.extend(apply_negative_values(component, policy, Some(&position))?);
+        }
+        Ok(warnings)
+    }
+
+    /// Aplica el tratamiento de los valores negativos registrando los avisos (ver `apply_negative_values`)
+    pub(crate) fn apply_negative_values_with_log(
+        &mut self,
+        policy: NegativeValues,
+    ) -> Result<(), EpbdError> {
+        for warning in self.apply_negative_values(policy)? {
+            warn!("[{}] {}", warning.code(), warning);
+        }
+        Ok(())
+    }
+
+    /// Completa con ceros los valores de los componentes con menos pasos de cálculo
+    ///
+    /// Todos los componentes pasan a tener el número de valores del componente con más valores.
+    /// Permite calcular componentes con distinto número de valores, que por defecto se rechazan
+    /// (ver `check_timesteps` y `ParseOptions::pad_values`).
+    pub fn pad_values(&mut self) {
+        let num_steps = self.cdata.iter().map(|c| c.values.len()).max().unwrap_or(0);
+        for component in &mut self.cdata {
+            component.values.resize(num_steps, 0.0);
+        }
+    }
+
+    /// Muestra los componentes con los factores de paso incluidos, en un único archivo
+    ///
+    /// Ver `parse_components`.
+    pub fn to_string_with_factors(&self, factors: &Factors) -> String {
+        format!("{}\n{}\n{}", self, FACTORS_SECTION, factors)
+    }
+
+    /// Corrige los componentes de consumo y producción
+    ///
+    /// - Asegura que la energía MEDIOAMBIENTE consumida tiene su producción correspondiente
+    /// - Asegura que la energía eléctrica producida no tiene un uso que no sea NDEF, salvo que se
+    ///   compense servicio a servicio (ver `compensation_by_service`)
+    /// - Clasifica los consumos de recarga de vehículos eléctricos como EPB o NEPB
+    ///   (ver `classify_ev_charging`)
+    ///
+    /// Los componentes añadidos se marcan como generados (ver `Component::is_generated`).
+    ///
+    /// Los metadatos, servicios y coherencia de los vectores se aseguran ya en el parsing
+    pub fn normalize(self) -> Self {
+        self.normalize_with_warnings().0
+    }
+
+    /// Corrige los componentes de consumo y producción e informa de las correcciones realizadas
+    ///
+    /// Igual que `normalize`, pero devuelve también un aviso por cada corrección automática.
+    pub fn normalize_with_warnings(mut self) -> (Self, Vec<Warning>) {
+        let mut warnings = self.force_ndef_use_for_electricity_production();
+        warnings.extend(self.classify_ev_charging());
+        warnings.extend(self.compensate_env_use());
+        if matches!(self.service_priority(), Ok(Some(_)))
+            && matches!(self.compensation_by_service(), Ok(false))
+        {
+            warnings.push(Warning::UnusedServicePriority);
+        }
+        for warning in &warnings {
+            debug!(
+                "Corrección de componentes [{}]: {}",
+                warning.code(),
+                warning
+            );
+        }
+        info!(
+            "Componentes normalizados: {} componentes, {} correcciones",
+            self.cdata.len(),
+            warnings.len()
+        );
+        (self, warnings)
+    }
+
+    /// Elimina los componentes generados automáticamente
+    ///
+    /// Permite guardar únicamente los componentes declarados por el usuario tras normalizar
+    /// los componentes (ver `normalize` y `Component::is_generated`).
+    pub fn strip_generated(mut self) -> Self {
+        self.cdata.retain(|c| !c.is_generated());
+        self
+    }
+
+    /// Ordena los componentes según el orden canónico (id, vector, tipo, subtipo y servicio)
+    ///
+    /// La ordenación es estable, por lo que se mantiene el orden relativo de los componentes equivalentes
+    pub fn sort_canonical(&mut self) {
+        self.cdata
+            .sort_by_key(|c| (c.id, c.carrier.clone(), c.ctype, c.csubtype, c.service));
+    }
+
+    /// Representación textual canónica de los componentes, con el número de decimales indicado
+    ///
+    /// Los metadatos se ordenan por clave y los componentes según el orden canónico (ver
+    /// `sort_canonical`), de modo que la representación no depende del orden de los datos de
+    /// origen y, al volver a interpretarla, se obtiene exactamente el mismo texto. Esto permite
+    /// guardar los archivos de proyecto en sistemas de control de versiones sin cambios espurios.
+    pub fn to_canonical_string(&self, decimals: usize) -> String {
+        format!("{:.*}", decimals, self.canonical())
+    }
+
+    /// Copia de los componentes con los metadatos ordenados por clave y los componentes en orden canónico
+    pub fn canonical(&self) -> Self {
+        let mut components = self.clone();
+        components.cmeta.sort_by(|a, b| a.key.cmp(&b.key));
+        components.sort_canonical();
+        components
+    }
+
+    /// Ordena los componentes usando la clave obtenida con la función `f` (ordenación estable)
+    pub fn sort_by_key<K: Ord, F: FnMut(&Component) -> K>(&mut self, f: F) {
+        self.cdata.sort_by_key(f);
+    }
+
+    /// Detecta combinaciones poco verosímiles de vectores y servicios en los consumos
+    ///
+    /// Permite detectar errores habituales en la asignación de vectores y servicios de datos
+    /// importados de programas de simulación:
+    ///
+    /// - consumos de vectores poco habituales para el servicio (p.e. GASNATURAL para ILU o
+    ///   BIOMASA para REF)
+    /// - consumos EPB sin servicio asignado (NDEF)
+    /// - componentes con vector o servicio desconocido, que se excluyen del balance
+    ///
+    /// Se devuelve, como máximo, un aviso por cada combinación de vector y servicio.
+    pub fn check_plausibility(&self) -> Vec<ComponentWarning> {
+        let mut warnings: Vec<_> = self
+            .cunknown
+            .iter()
+            .map(|c| ComponentWarning::UnknownComponent(c.identifier.clone()))
+            .collect();
+        for c in self.cdata.iter().filter(|c| c.is_used_energy()) {
+            let warning = if c.csubtype == CSubtype::EPB && c.service == Service::NDEF {
+                ComponentWarning::UndefinedService(c.carrier.clone())
+            } else if plausible_carriers(c.service).map_or(false, |crs| {
+                !(crs.contains(&c.carrier)
+                    || (c.service == Service::REF && c.carrier.is_district()))
+            }) {
+                ComponentWarning::ImplausibleService(c.carrier.clone(), c.service)
+            } else {
+                continue;
+            };
+            if !warnings.contains(&warning) {
+                warnings.push(warning);
+            }
+        }
+        warnings
+    }
+
+    /// Factores de calibración de los consumos, por vector, declarados en los metadatos
+    ///
+    /// Cada factor se declara en un metadato `CTE_CALIBRACION` con el formato `vector, factor`
+    /// (p.e. `ELECTRICIDAD, 1.08` para ajustar la electricidad simulada a la facturada).
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto, factor no positivo o vector con más de un factor de calibración
+    pub fn calibration_factors(&self) -> Result<Vec<(Carrier, Real)>, EpbdError> {
+        let mut factors: Vec<(Carrier, Real)> = Vec::new();
+        for meta in self.cmeta.iter().filter(|m| m.key == "CTE_CALIBRACION") {
+            let wrong = || {
+                EpbdError::ParseError(format!(
+                    "Metadato CTE_CALIBRACION incorrecto: \"{}\"",
+                    meta.value
+                ))
+            };
+            let items: Vec<&str> = meta.value.split(',').map(str::trim).collect();
+            if items.len() != 2 {
+                return Err(wrong());
+            }
+            let carrier: Carrier = items[0].parse().map_err(|_| wrong())?;
+            let factor: Real = items[1].parse().map_err(|_| wrong())?;
+            if factor <= 0.0 {
+                return Err(EpbdError::WrongInput(format!(
+                    "El factor de calibración de {} debe ser positivo y se encontró {}",
+                    carrier, factor
+                )));
+            }
+            if factors.iter().any(|(c, _)| *c == carrier) {
+                return Err(EpbdError::WrongInput(format!(
+                    "factor de calibración de {} declarado más de una vez",
+                    carrier
+                )));
+            }
+            factors.push((carrier, factor));
+        }
+        Ok(factors)
+    }
+
+    /// Aplica los factores de calibración a los consumos de cada vector
+    ///
+    /// Multiplica los valores de los componentes de consumo (no los ajustes ni las producciones)
+    /// de los vectores con factor de calibración (ver `calibration_factors`). Los metadatos se
+    /// mantienen para informar de la calibración en los resultados, por lo que solo debe aplicarse
+    /// una vez y antes de normalizar los componentes.
+    ///
+    /// # Errors
+    ///
+    /// * Declaración incorrecta de los factores de calibración
+    /// * Consumos calibrados negativos tras aplicar los ajustes de consumo
+    pub fn calibrate(mut self) -> Result<Self, EpbdError> {
+        for (carrier, factor) in self.calibration_factors()? {
+            for c in self
+                .cdata
+                .iter_mut()
+                .filter(|c| c.carrier == carrier && c.ctype == CType::CONSUMO)
+            {
+                c.scale(factor);
+            }
+        }
+        check_adjustments(&self.cdata)?;
+        Ok(self)
+    }
+
+    /// Sistemas declarados en los metadatos
+    ///
+    /// Cada sistema se declara en un metadato `CTE_SISTEMA` con el formato
+    /// `id, nombre, tipo, vector, potencia nominal [kW]`.
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de la declaración de un sistema
+    /// * Sistemas declarados con el mismo id
+    pub fn systems(&self) -> Result<Vec<System>, EpbdError> {
+        let mut systems: Vec<System> = Vec::new();
+        for meta in self.cmeta.iter().filter(|m| m.key == "CTE_SISTEMA") {
+            let system: System = meta.value.parse()?;
+            if systems.iter().any(|s| s.id == system.id) {
+                return Err(EpbdError::WrongInput(format!(
+                    "sistema {} declarado más de una vez",
+                    system.id
+                )));
+            }
+            systems.push(system);
+        }
+        Ok(systems)
+    }
+
+    /// Comprueba la coherencia de los sistemas declarados con los ids usados por los componentes
+    ///
+    /// Si no se declaran sistemas no se realiza ninguna comprobación. No se comprueban los
+    /// sistemas ficticios o de referencia (ids negativos).
+    ///
+    /// # Errors
+    ///
+    /// * Declaración incorrecta de los sistemas (ver `systems`)
+    pub fn check_systems(&self) -> Result<Vec<ComponentWarning>, EpbdError> {
+        let systems = self.systems()?;
+        if systems.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut warnings = Vec::new();
+        let mut used_ids: Vec<i32> = self
+            .cdata
+            .iter()
+            .map(|c| c.id)
+            .filter(|&id| id > 0)
+            .collect();
+        used_ids.sort_unstable();
+        used_ids.dedup();
+        for id in &used_ids {
+            if !systems.iter().any(|s| s.id == *id) {
+                warnings.push(ComponentWarning::UndeclaredSystem(*id));
+            }
+        }
+        for system in &systems {
+            if !used_ids.contains(&system.id) {
+                warnings.push(ComponentWarning::UnusedSystem(system.id));
+            } else if !self
+                .cdata
+                .iter()
+                .any(|c| c.id == system.id && c.carrier == system.carrier)
+            {
+                warnings.push(ComponentWarning::SystemCarrierMismatch(
+                    system.id,
+                    system.carrier.clone(),
+                ));
+            }
+        }
+        Ok(warnings)
+    }
+
+    /// Orden de prioridad de los orígenes de la energía producida declarado en los metadatos
+    ///
+    /// El metadato `CTE_PRIORIDAD_PRODUCCION` indica la lista de orígenes (`INSITU`,
+    /// `COGENERACION`) en orden de prioridad para cubrir los consumos EPB (p.e. `INSITU, COGENERACION`).
+    /// Los orígenes no incluidos en la lista tienen menor prioridad.
+    ///
+    /// Devuelve `None` si no se declara un orden de prioridad.
+    ///
+    /// # Errors
+    ///
+    /// * Orígenes desconocidos o repetidos
+    pub fn production_priority(&self) -> Result<Option<Vec<CSubtype>>, EpbdError> {
+        let value = match self.get_meta("CTE_PRIORIDAD_PRODUCCION") {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let mut priority: Vec<CSubtype> = Vec::new();
+        for item in value.split(',').map(str::trim) {
+            let origin = match item.parse::<CSubtype>() {
+                Ok(origin @ (CSubtype::INSITU | CSubtype::COGENERACION))
+                    if !priority.contains(&origin) =>
+                {
+                    origin
+                }
+                _ => {
+                    return Err(EpbdError::ParseError(format!(
+                        "origen \"{}\" en el metadato CTE_PRIORIDAD_PRODUCCION",
+                        item
+                    )))
+                }
+            };
+            priority.push(origin);
+        }
+        Ok(Some(priority))
+    }
+
+    /// Periodo de compensación de la energía producida declarado en los metadatos
+    ///
+    /// El metadato `CTE_PERIODO_COMPENSACION` indica si la energía producida compensa los
+    /// consumos solo dentro de cada paso de cálculo (`PASO`, por defecto), dentro de cada mes
+    /// (`MENSUAL`) o en el conjunto de cada anualidad (`ANUAL`).
+    ///
+    /// Devuelve `None` si la compensación se realiza en cada paso de cálculo.
+    ///
+    /// # Errors
+    ///
+    /// * Periodo de compensación desconocido
+    pub fn compensation_period(&self) -> Result<Option<Timestep>, EpbdError> {
+        match self.get_meta("CTE_PERIODO_COMPENSACION").as_deref() {
+            None | Some("PASO") => Ok(None),
+            Some(value @ ("HORARIO" | "MENSUAL" | "ANUAL")) => Ok(Some(value.parse()?)),
+            Some(value) => Err(EpbdError::ParseError(format!(
+                "periodo \"{}\" en el metadato CTE_PERIODO_COMPENSACION",
+                value
+            ))),
+        }
+    }
+
+    /// Indica si la energía producida se compensa servicio a servicio, según los metadatos
+    ///
+    /// Con el metadato `CTE_COMPENSACION_SERVICIOS: true` la energía producida solo compensa el
+    /// consumo EPB del servicio al que se asigna: la producción declarada para un servicio se
+    /// asigna a dicho servicio y la producción sin servicio asignado (NDEF) se reparte entre los
+    /// servicios en proporción a su consumo EPB anual del vector. Por defecto (`false`), la energía
+    /// producida compensa el consumo EPB de todos los servicios del vector.
+    ///
+    /// # Errors
+    ///
+    /// * Valor del metadato que no es un valor lógico
+    pub fn compensation_by_service(&self) -> Result<bool, EpbdError> {
+        Ok(self
+            .get_bool("CTE_COMPENSACION_SERVICIOS")?
+            .unwrap_or(false))
+    }
+
+    /// Orden de prioridad de los servicios para asignar la producción sin servicio asignado (NDEF)
+    ///
+    /// Con compensación servicio a servicio (ver `compensation_by_service`), el metadato
+    /// `CTE_PRIORIDAD_SERVICIOS` indica la lista de servicios en orden de prioridad (p.e. `ACS, CAL`)
+    /// para asignar, en cada paso de cálculo, la producción NDEF al consumo EPB no cubierto de cada
+    /// servicio. La producción restante se reparte entre los servicios no incluidos en la lista en
+    /// proporción a su consumo EPB anual.
+    ///
+    /// Devuelve `None` si no se declara un orden de prioridad (reparto proporcional al consumo).
+    ///
+    /// # Errors
+    ///
+    /// * Servicios desconocidos, repetidos o NDEF
+    pub fn service_priority(&self) -> Result<Option<Vec<Service>>, EpbdError> {
+        let value = match self.get_meta("CTE_PRIORIDAD_SERVICIOS") {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        let mut priority: Vec<Service> = Vec::new();
+        for item in value.split(',').map(str::trim) {
+            let service = match item.parse::<Service>() {
+                Ok(service) if service != Service::NDEF && !priority.contains(&service) => service,
+                _ => {
+                    return Err(EpbdError::ParseError(format!(
+                        "servicio \"{}\" en el metadato CTE_PRIORIDAD_SERVICIOS",
+                        item
+                    )))
+                }
+            };
+            priority.push(service);
+        }
+        Ok(Some(priority))
+    }
+
+    /// Fija el orden de prioridad de los servicios para asignar la producción NDEF
+    ///
+    /// Guarda la lista en el metadato `CTE_PRIORIDAD_SERVICIOS` (ver `service_priority`).
+    pub fn set_service_priority(&mut self, priority: &[Service]) {
+        let value = priority
+            .iter()
+            .map(Service::to_string)
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.set_meta("CTE_PRIORIDAD_SERVICIOS", &value);
+    }
+
+    /// Descripción del reparto de la producción sin servicio asignado entre los servicios
+    ///
+    /// Indica el orden de prioridad de los servicios (ver `service_priority`) o, si no se declara
+    /// o es incorrecto, que el reparto es proporcional al consumo.
+    pub fn service_allocation(&self) -> String {
+        match self.service_priority() {
+            Ok(Some(priority)) => tr_format!(
+                "prioridad {}",
+                priority
+                    .iter()
+                    .map(Service::to_string)
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            ),
+            _ => i18n::tr("proporcional al consumo").to_string(),
+        }
+    }
+
+    /// Batería de almacenamiento de la electricidad producida in situ declarada en los metadatos
+    ///
+    /// Se declara en el metadato `CTE_BATERIA` con el formato `capacidad, rendimiento` (ver
+    /// `Storage`). Devuelve `None` si no se ha declarado ninguna batería.
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de la declaración de la batería
+    pub fn battery(&self) -> Result<Option<Storage>, EpbdError> {
+        self.get_meta("CTE_BATERIA")
+            .map(|value| value.parse())
+            .transpose()
+    }
+
+    /// Almacenamientos térmicos de la energía producida in situ declarados en los metadatos
+    ///
+    /// Cada almacenamiento se declara en un metadato `CTE_ALMACENAMIENTO_TERMICO` con el formato
+    /// `vector, capacidad, rendimiento`, para los vectores de energía térmica (MEDIOAMBIENTE,
+    /// RED1 y RED2), como los depósitos de acumulación estacional de la energía solar térmica.
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de la declaración de un almacenamiento
+    /// * Vector que no es de energía térmica o con más de un almacenamiento declarado
+    pub fn thermal_storages(&self) -> Result<Vec<(Carrier, Storage)>, EpbdError> {
+        let mut storages: Vec<(Carrier, Storage)> = Vec::new();
+        for meta in self
+            .cmeta
+            .iter()
+            .filter(|m| m.key == "CTE_ALMACENAMIENTO_TERMICO")
+        {
+            let (carrier, storage) = meta
+                .value
+                .split_once(',')
+                .ok_or_else(|| EpbdError::ParseError(meta.value.clone()))?;
+            let carrier: Carrier = carrier.trim().parse()?;
+            if !(carrier == Carrier::MEDIOAMBIENTE || carrier.is_district())
+                || storages.iter().any(|(c, _)| *c == carrier)
+            {
+                return Err(EpbdError::WrongInput(format!(
+                    "almacenamiento térmico incorrecto o repetido para el vector {}",
+                    carrier
+                )));
+            }
+            storages.push((carrier, storage.parse()?));
+        }
+        Ok(storages)
+    }
+
+    /// Almacenamientos de la energía producida in situ declarados, por vector energético
+    ///
+    /// Incluye la batería de electricidad (`CTE_BATERIA`) y los almacenamientos térmicos
+    /// (`CTE_ALMACENAMIENTO_TERMICO`).
+    ///
+    /// # Errors
+    ///
+    /// * Declaración incorrecta de algún almacenamiento
+    pub fn storages(&self) -> Result<Vec<(Carrier, Storage)>, EpbdError> {
+        let mut storages = self.thermal_storages()?;
+        if let Some(battery) = self.battery()? {
+            storages.insert(0, (Carrier::ELECTRICIDAD, battery));
+        }
+        Ok(storages)
+    }
+
+    /// Conversiones de energía en el edificio declaradas en los metadatos
+    ///
+    /// Cada conversión se declara en un metadato `CTE_CONVERSION` con el formato
+    /// `id origen, id destino, rendimiento` (ver `Conversion`).
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de la declaración de una conversión
+    /// * Conversiones declaradas más de una vez entre los mismos sistemas
+    pub fn conversions(&self) -> Result<Vec<Conversion>, EpbdError> {
+        let mut conversions: Vec<Conversion> = Vec::new();
+        for meta in self.cmeta.iter().filter(|m| m.key == "CTE_CONVERSION") {
+            let conversion: Conversion = meta.value.parse()?;
+            if conversions
+                .iter()
+                .any(|c| c.from == conversion.from && c.to == conversion.to)
+            {
+                return Err(EpbdError::WrongInput(format!(
+                    "conversión del sistema {} al sistema {} declarada más de una vez",
+                    conversion.from, conversion.to
+                )));
+            }
+            conversions.push(conversion);
+        }
+        Ok(conversions)
+    }
+
+    /// Balances de las conversiones declaradas y ajustes de consumo que evitan la doble contabilidad
+    ///
+    /// Para cada paso de cálculo, la energía convertida (consumo del sistema de origen por el
+    /// rendimiento) cubre los consumos del sistema de destino, que se reducen, en el orden de
+    /// declaración de los componentes, mediante ajustes de consumo generados.
+    fn conversion_flows(&self) -> Result<Vec<(ConversionBalance, Vec<Component>)>, EpbdError> {
+        let conversions = self.conversions()?;
+        let num_steps = self.cdata.first().map(|c| c.values.len()).unwrap_or(0);
+        // Consumos pendientes de cubrir de cada componente de consumo
+        let mut pending: Vec<Vec<Real>> = self
+            .cdata
+            .iter()
+            .map(|c| {
+                if c.ctype == CType::CONSUMO {
+                    c.values.clone()
+                } else {
+                    vec![0.0; c.values.len()]
+                }
+            })
+            .collect();
+        let mut flows = Vec::new();
+        for conversion in conversions {
+            let input = veclistsum(
+                &self
+                    .cdata
+                    .iter()
+                    .filter(|c| c.id == conversion.from && c.is_used_energy() && !c.is_generated())
+                    .map(|c| c.values.as_slice())
+                    .collect::<Vec<_>>(),
+            );
+            let input = if input.len() != num_steps {
+                vec![0.0; num_steps]
+            } else {
+                input
+            };
+            let mut available: Vec<Real> =
+                input.iter().map(|v| v * conversion.efficiency).collect();
+            let mut adjustments = Vec::new();
+            for (c, pending) in self.cdata.iter().zip(pending.iter_mut()) {
+                if c.id != conversion.to || c.ctype != CType::CONSUMO {
+                    continue;
+                }
+                let covered = vecvecmin(&available, pending);
+                if covered.iter().all(|v| *v == 0.0) {
+                    continue;
+                }
+                vecvecdif_assign(pending, &covered);
+                vecvecdif_assign(&mut available, &covered);
+                adjustments.push(Component {
+                    id: c.id,
+                    carrier: c.carrier.clone(),
+                    ctype: CType::AJUSTE,
+                    csubtype: c.csubtype,
+                    service: c.service,
+                    aux: c.aux,
+                    values: covered.iter().map(|v| -v).collect(),
+                    comment: conversion_comment(conversion.from),
+                });
+            }
+            let input: Real = input.iter().sum();
+            let output = input * conversion.efficiency;
+            let unused: Real = available.iter().sum();
+            let balance = ConversionBalance {
+                conversion,
+                input,
+                output,
+                used: output - unused,
+                losses: input - output,
+                unused,
+            };
+            flows.push((balance, adjustments));
+        }
+        Ok(flows)
+    }
+
+    /// Balances de las conversiones de energía declaradas (ver `conversions`)
+    ///
+    /// Informa de la energía de entrada, la energía convertida, la parte aprovechada por el
+    /// sistema de destino, las pérdidas de conversión y la energía convertida no aprovechada.
+    ///
+    /// # Errors
+    ///
+    /// * Declaración incorrecta de las conversiones
+    pub fn conversions_balance(&self) -> Result<Vec<ConversionBalance>, EpbdError> {
+        Ok(self
+            .conversion_flows()?
+            .into_iter()
+            .map(|(balance, _)| balance)
+            .collect())
+    }
+
+    /// Aplica las conversiones de energía declaradas (ver `conversions`)
+    ///
+    /// Los consumos del sistema de destino cubiertos por la energía convertida en el sistema de
+    /// origen son flujos intermedios y se descuentan mediante ajustes de consumo generados (ver
+    /// `Component::is_generated`), de modo que solo se contabiliza la energía consumida por el
+    /// sistema de origen. Los ajustes generados previamente por conversiones se reemplazan, por lo
+    /// que puede aplicarse más de una vez.
+    ///
+    /// # Errors
+    ///
+    /// * Declaración incorrecta de las conversiones
+    pub fn apply_conversions(mut self) -> Result<Self, EpbdError> {
+        let conversions = self.conversions()?;
+        self.cdata.retain(|c| {
+            !(c.ctype == CType::AJUSTE
+                && conversions
+                    .iter()
+                    .any(|conv| is_conversion_comment(&c.comment, conv.from)))
+        });
+        let adjustments: Vec<Component> = self
+            .conversion_flows()?
+            .into_iter()
+            .flat_map(|(_, adjustments)| adjustments)
+            .collect();
+        self.cdata.extend(adjustments);
+        Ok(self)
+    }
+
+    /// Necesidades de energía de los servicios declaradas en los metadatos
+    ///
+    /// Cada necesidad se declara en un metadato `CTE_NECESIDAD` con el formato
+    /// `servicio, valor1, valor2, ...` (ver `EnergyNeed`).
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de la declaración de una necesidad
+    /// * Servicio con más de una necesidad declarada
+    pub fn needs(&self) -> Result<Vec<EnergyNeed>, EpbdError> {
+        let mut needs: Vec<EnergyNeed> = Vec::new();
+        for meta in self.cmeta.iter().filter(|m| m.key == "CTE_NECESIDAD") {
+            let need: EnergyNeed = meta.value.parse()?;
+            if needs.iter().any(|n| n.service == need.service) {
+                return Err(EpbdError::WrongInput(format!(
+                    "necesidad de energía de {} declarada más de una vez",
+                    need.service
+                )));
+            }
+            needs.push(need);
+        }
+        Ok(needs)
+    }
+
+    /// Rendimientos de los sistemas que atienden las necesidades de energía declarados en los metadatos
+    ///
+    /// Cada rendimiento se declara en un metadato `CTE_RENDIMIENTO` con el formato
+    /// `id, servicio, vector, fracción, rendimiento` (ver `SystemEfficiency`).
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de la declaración de un rendimiento
+    pub fn efficiencies(&self) -> Result<Vec<SystemEfficiency>, EpbdError> {
+        self.cmeta
+            .iter()
+            .filter(|m| m.key == "CTE_RENDIMIENTO")
+            .map(|m| m.value.parse())
+            .collect()
+    }
+
+    /// Genera los componentes de consumo a partir de las necesidades de energía declaradas
+    ///
+    /// Para cada necesidad de energía de un servicio (ver `needs`) y cada sistema que la atiende
+    /// (ver `efficiencies`) se genera el consumo EPB del vector del sistema, igual a la fracción
+    /// atendida de la necesidad dividida por el rendimiento. Con rendimientos mayores que la unidad
+    /// (bombas de calor) se genera también el consumo de MEDIOAMBIENTE (energía aportada menos
+    /// consumida). Los consumos de MEDIOAMBIENTE se equilibran con la producción in situ
+    /// correspondiente. Los componentes generados (ver
+    /// `Component::is_generated`) previamente a partir de las necesidades se reemplazan, por lo que
+    /// puede aplicarse más de una vez.
+    ///
+    /// # Errors
+    ///
+    /// * Declaración incorrecta de las necesidades o de los rendimientos
+    /// * Necesidades sin sistemas o cuya suma de fracciones atendidas no es la unidad
+    /// * Necesidades con un número de valores distinto al de los componentes
+    pub fn apply_needs(mut self) -> Result<Self, EpbdError> {
+        let needs = self.needs()?;
+        let efficiencies = self.efficiencies()?;
+        self.cdata
+            .retain(|c| !(c.is_generated() && c.attr(NEEDS_ATTR).is_some()));
+        let num_steps = self.cdata.first().map(|c| c.values.len());
+        for need in &needs {
+            if let Some(n) = num_steps.filter(|n| *n != need.values.len()) {
+                return Err(EpbdError::WrongInput(format!(
+                    "la necesidad de energía de {} tiene {} valores y los componentes {}",
+                    need.service,
+                    need.values.len(),
+                    n
+                )));
+            }
+            let systems: Vec<_> = efficiencies
+                .iter()
+                .filter(|e| e.service == need.service)
+                .collect();
+            let fraction: Real = systems.iter().map(|e| e.fraction).sum();
+            if (fraction - 1.0).abs() > 0.001 {
+                return Err(EpbdError::WrongInput(format!(
+                    "la suma de las fracciones de la necesidad de energía de {} atendidas por los sistemas es {:.3} y debe ser 1",
+                    need.service, fraction
+                )));
+            }
+            for system in systems {
+                let component = |carrier, ctype, csubtype, values| Component {
+                    id: system.id,
+                    carrier,
+                    ctype,
+                    csubtype,
+                    service: need.service,
+                    aux: false,
+                    values,
+                    comment: needs_comment(need.service, system.id),
+                };
+                let supplied: Vec<Real> = need.values.iter().map(|v| v * system.fraction).collect();
+                let used: Vec<Real> = supplied.iter().map(|v| v / system.efficiency).collect();
+                self.cdata.push(component(
+                    system.carrier.clone(),
+                    CType::CONSUMO,
+                    CSubtype::EPB,
+                    used.clone(),
+                ));
+                // Energía procedente del medioambiente, equilibrada con producción in situ
+                let env = if system.carrier == Carrier::MEDIOAMBIENTE {
+                    used
+                } else if system.efficiency > 1.0 {
+                    let env = vecvecdif(&supplied, &used);
+                    self.cdata.push(component(
+                        Carrier::MEDIOAMBIENTE,
+                        CType::CONSUMO,
+                        CSubtype::EPB,
+                        env.clone(),
+                    ));
+                    env
+                } else {
+                    continue;
+                };
+                self.cdata.push(component(
+                    Carrier::MEDIOAMBIENTE,
+                    CType::PRODUCCION,
+                    CSubtype::INSITU,
+                    env,
+                ));
+            }
+        }
+        if !needs.is_empty() {
+            info!(
+                "Componentes generados a partir de {} necesidades de energía y {} rendimientos",
+                needs.len(),
+                efficiencies.len()
+            );
+        }
+        Ok(self)
+    }
+
+    /// Indicadores de utilización de los sistemas declarados
+    ///
+    /// Se calculan a partir de la energía anual del vector declarado del sistema, que es la
+    /// energía producida, en sistemas de producción (p.e. fotovoltaica), o la energía consumida,
+    /// en el resto de casos:
+    ///
+    /// - horas equivalentes a plena carga = energía anual / potencia nominal
+    /// - factor de capacidad (sistemas de producción) = horas equivalentes / 8760
+    ///
+    /// Si se definen anualidades (ver `annual_periods`) se usa la media anual.
+    ///
+    /// # Errors
+    ///
+    /// * Declaración incorrecta de los sistemas o de las anualidades
+    pub fn systems_utilization(&self) -> Result<Vec<SystemUtilization>, EpbdError> {
+        let num_years = self.annual_periods()?.map(|p| p.len()).unwrap_or(1) as Real;
+        let utilization = self
+            .systems()?
+            .iter()
+            .map(|system| {
+                let energy_of = |is_produced: bool| -> Real {
+                    self.building_cdata()
+                        .filter(|c| {
+                            c.id == system.id
+                                && c.carrier == system.carrier
+                                && (c.ctype == CType::PRODUCCION) == is_produced
+                        })
+                        .flat_map(|c| c.values.iter())
+                        .sum()
+                };
+                let produced = energy_of(true);
+                let is_producer = produced > 0.0;
+                let energy = if is_producer {
+                    produced
+                } else {
+                    energy_of(false)
+                };
+                let full_load_hours = if system.power > 0.0 {
+                    Some(energy / num_years / system.power)
+                } else {
+                    None
+                };
+                let capacity_factor = full_load_hours
+                    .filter(|_| is_producer)
+                    .map(|h| h / HOURS_PER_YEAR);
+                SystemUtilization {
+                    id: system.id,
+                    energy,
+                    full_load_hours,
+                    capacity_factor,
+                }
+            })
+            .collect();
+        Ok(utilization)
+    }
+
+    /// Anualidades definidas en los metadatos, como etiqueta e intervalo de pasos de cálculo
+    ///
+    /// El metadato `CTE_ANUALIDADES` indica el número de pasos de cálculo de cada anualidad
+    /// consecutiva (p.e. `12, 12, 12`) y el metadato opcional `CTE_ANUALIDAD_INICIAL` el año
+    /// de la primera anualidad, usado para etiquetarlas. En su ausencia se numeran desde 1.
+    ///
+    /// Devuelve `None` si no se definen anualidades.
+    ///
+    /// # Errors
+    ///
+    /// * Formato incorrecto de los metadatos
+    /// * El total de pasos de las anualidades no coincide con el número de pasos de los componentes
+    pub fn annual_periods(&self) -> Result<Option<Vec<AnnualPeriod>>, EpbdError> {
+        let periods = match self.get_meta("CTE_ANUALIDADES") {
+            Some(periods) => periods,
+            None => return Ok(None),
+        };
+        let steps = periods
+            .split(',')
+            .map(|v| match v.trim().parse::<usize>() {
+                Ok(n) if n > 0 => Ok(n),
+                _ => Err(EpbdError::ParseError(format!(
+                    "anualidades \"{}\" en el metadato CTE_ANUALIDADES",
+                    periods
+                ))),
+            })
+            .collect::<Result<Vec<usize>, _>>()?;
+        let first_year = match self.get_meta("CTE_ANUALIDAD_INICIAL") {
+            Some(year) => Some(year.trim().parse::<i32>().map_err(|_| {
+                EpbdError::ParseError(format!(
+                    "año \"{}\" en el metadato CTE_ANUALIDAD_INICIAL",
+                    year
+                ))
+            })?),
+            None => None,
+        };
+        let num_steps = self.cdata.first().map(|c| c.values.len()).unwrap_or(0);
+        let total_steps: usize = steps.iter().sum();
+        if total_steps != num_steps {
+            return Err(EpbdError::WrongInput(format!(
+                "las anualidades suman {} pasos de cálculo y los componentes tienen {}",
+                total_steps, num_steps
+            )));
+        }
+        let mut start = 0;
+        let periods = steps
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                let label = match first_year {
+                    Some(year) => (year + i as i32).to_string(),
+                    None => (i + 1).to_string(),
+                };
+                let range = start..start + n;
+                start += n;
+                (label, range)
+            })
+            .collect();
+        Ok(Some(periods))
+    }
+
+    /// Paso de cálculo de los componentes
+    ///
+    /// Es el declarado en el metadato `CTE_PASO` o, en su ausencia, el que corresponde al número
+    /// de valores de los componentes (8760 horario, 12 mensual, 1 anual). Devuelve `None` si no
+    /// se declara y no corresponde a ninguno de ellos.
+    ///
+    /// # Errors
+    ///
+    /// * Paso de cálculo declarado desconocido
+    pub fn timestep(&self) -> Result<Option<Timestep>, EpbdError> {
+        match self.get_meta("CTE_PASO") {
+            Some(value) => Ok(Some(value.parse()?)),
+            None => Ok(self
+                .cdata
+                .first()
+                .and_then(|c| Timestep::from_num_steps(c.values.len()))),
+        }
+    }
+
+    /// Agrega los componentes horarios a pasos de cálculo mensuales
+    ///
+    /// Ver `Components::aggregate`.
+    ///
+    /// # Errors
+    ///
+    /// * Paso de cálculo desconocido o no horario
+    pub fn to_monthly(&self) -> Result<Self, EpbdError> {
+        self.aggregate(Timestep::MENSUAL)
+    }
+
+    /// Agrega los componentes horarios o mensuales a pasos de cálculo anuales
+    ///
+    /// Ver `Components::aggregate`.
+    ///
+    /// # Errors
+    ///
+    /// * Paso de cálculo desconocido
+    pub fn to_annual(&self) -> Result<Self, EpbdError> {
+        self.aggregate(Timestep::ANUAL)
+    }
+
+    /// Agrega los valores de los componentes al paso de cálculo indicado
+    ///
+    /// Los valores de cada anualidad se suman por meses (con años de 365 días) o por años. Se
+    /// actualiza el paso de cálculo (`CTE_PASO`) y, si existen, las anualidades
+    /// (`CTE_ANUALIDADES`). Los componentes desconocidos se conservan sin modificar.
+    ///
+    /// # Errors
+    ///
+    /// * Paso de cálculo desconocido o más largo que el indicado
+    pub fn aggregate(&self, timestep: Timestep) -> Result<Self, EpbdError> {
+        let current = self.timestep()?.ok_or_else(|| {
+            EpbdError::WrongInput(
+                "no se puede determinar el paso de cálculo de los componentes".to_string(),
+            )
+        })?;
+        let groups: Vec<usize> = match (current, timestep) {
+            (from, to) if from == to => return Ok(self.clone()),
+            (Timestep::HORARIO, Timestep::MENSUAL) => {
+                DAYS_PER_MONTH.iter().map(|days| days * 24).collect()
+            }
+            (from, Timestep::ANUAL) => vec![from.steps_per_year()],
+            (from, to) => {
+                return Err(EpbdError::WrongInput(format!(
+                    "no se pueden agregar los componentes de paso {} a paso {}",
+                    from, to
+                )))
+            }
+        };
+        let aggregate_values = |values: &[Real]| -> Vec<Real> {
+            let mut result = Vec::new();
+            let mut start = 0;
+            for size in groups.iter().cycle() {
+                if start >= values.len() {
+                    break;
+                }
+                let end = (start + size).min(values.len());
+                result.push(values[start..end].iter().sum());
+                start = end;
+            }
+            result
+        };
+        Ok(self.map_values(current, timestep, aggregate_values))
+    }
+
+    /// Cambia el paso de cálculo de los componentes al indicado
+    ///
+    /// Los pasos de cálculo más largos se obtienen por agregación (ver `Components::aggregate`).
+    /// Los pasos de cálculo más cortos se obtienen repartiendo el valor de cada paso de forma
+    /// uniforme entre sus horas, de modo que un valor mensual se reparte por igual entre las horas
+    /// del mes y un valor anual se reparte entre los meses en proporción a sus días. Se conserva
+    /// así la energía total de cada anualidad, pero no su distribución real, por lo que los
+    /// resultados con pasos más cortos deben usarse con precaución.
+    ///
+    /// # Errors
+    ///
+    /// * Paso de cálculo desconocido
+    pub fn resample(&self, timestep: Timestep) -> Result<Self, EpbdError> {
+        let current = self.timestep()?.ok_or_else(|| {
+            EpbdError::WrongInput(
+                "no se puede determinar el paso de cálculo de los componentes".to_string(),
+            )
+        })?;
+        if timestep >= current {
+            return self.aggregate(timestep);
+        }
+        let hours: Vec<usize> = match current {
+            Timestep::HORARIO => vec![1],
+            Timestep::MENSUAL => DAYS_PER_MONTH.iter().map(|days| days * 24).collect(),
+            Timestep::ANUAL => vec![HOURS_PER_YEAR as usize],
+        };
+        let disaggregate_values = |values: &[Real]| -> Vec<Real> {
+            values
+                .iter()
+                .zip(hours.iter().cycle())
+                .flat_map(|(v, &n)| std::iter::repeat(v / n as Real).take(n))
+                .collect()
+        };
+        self.map_values(current, Timestep::HORARIO, disaggregate_values)
+            .aggregate(timestep)
+    }
+
+    /// Transforma los valores de los componentes al cambiar su paso de cálculo
+    ///
+    /// Actualiza el paso de cálculo (`CTE_PASO`) y, si existen, las anualidades
+    /// (`CTE_ANUALIDADES`). Los componentes desconocidos se conservan sin modificar.
+    fn map_values<F: Fn(&[Real]) -> Vec<Real>>(
+        &self,
+        current: Timestep,
+        timestep: Timestep,
+        map_values: F,
+    ) -> Self {
+        let cdata = self
+            .cdata
+            .iter()
+            .map(|c| Component {
+                values: map_values(&c.values),
+                ..c.clone()
+            })
+            .collect();
+        let mut components = Self {
+            cmeta: self.cmeta.clone(),
+            cdata,
+            cunknown: self.cunknown.clone(),
+        };
+        components.update_timestep_meta(current, timestep);
+        components
+    }
+
+    /// Actualiza el paso de cálculo (`CTE_PASO`) y, si existen, las anualidades
+    /// (`CTE_ANUALIDADES`) tras cambiar el paso de cálculo de los valores
+    fn update_timestep_meta(&mut self, current: Timestep, timestep: Timestep) {
+        self.set_meta("CTE_PASO", &timestep.to_string());
+        if let Some(periods) = self.get_meta("CTE_ANUALIDADES") {
+            let periods = periods
+                .split(',')
+                .map(|n| {
+                    let n: usize = n.trim().parse().unwrap_or(0);
+                    (n / current.steps_per_year() * timestep.steps_per_year()).to_string()
+                })
+                .collect::<Vec<String>>()
+                .join(", ");
+            self.set_meta("CTE_ANUALIDADES", &periods);
+        }
+    }
+
+    /// Expande los componentes anuales a pasos de cálculo mensuales según perfiles mensuales
+    ///
+    /// El valor de cada anualidad de un componente se reparte entre los meses según el perfil
+    /// que se obtiene para el componente (ver `Component::expand_annual`), lo que permite, p.e.,
+    /// usar perfiles distintos para cada servicio (ver `cte::expand_annual_components`). A
+    /// diferencia de `Components::resample`, que reparte los valores en proporción a los días de
+    /// cada mes, se puede reproducir así la variación estacional de los consumos. Se actualiza el
+    /// paso de cálculo (`CTE_PASO`) y, si existen, las anualidades (`CTE_ANUALIDADES`). Los
+    /// componentes desconocidos se conservan sin modificar.
+    ///
+    /// # Errors
+    ///
+    /// * Componentes sin paso de cálculo anual
+    /// * Perfil mensual incorrecto
+    pub fn expand_annual<F: Fn(&Component) -> Vec<Real>>(
+        &self,
+        profile: F,
+    ) -> Result<Self, EpbdError> {
+        let current = self.timestep()?;
+        if current != Some(Timestep::ANUAL) {
+            return Err(EpbdError::WrongInput(format!(
+                "solo se pueden expandir a valores mensuales componentes de paso {} (paso actual: {})",
+                Timestep::ANUAL,
+                current.map_or_else(|| "desconocido".to_string(), |t| t.to_string())
+            )));
+        }
+        let cdata = self
+            .cdata
+            .iter()
+            .map(|c| c.expand_annual(&profile(c)))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut components = Self {
+            cmeta: self.cmeta.clone(),
+            cdata,
+            cunknown: self.cunknown.clone(),
+        };
+        components.update_timestep_meta(Timestep::ANUAL, Timestep::MENSUAL);
+        Ok(components)
+    }
+
+    /// Multiplica por un factor los valores de los componentes que cumplen una condición
+    ///
+    /// Permite, p.e., expresar los componentes por m² de superficie (con el factor `1 / área`)
+    /// o ajustar los consumos de un vector a los facturados:
+    ///
+    /// `components.scale(1.08, |c| c.carrier == Carrier::ELECTRICIDAD && c.ctype == CType::CONSUMO)`
+    ///
+    /// Los metadatos no se modifican.
+    pub fn scale<F: Fn(&Component) -> bool>(mut self, factor: Real, filter: F) -> Self {
+        self.cdata
+            .iter_mut()
+            .filter(|c| filter(c))
+            .for_each(|c| c.scale(factor));
+        self
+    }
+
+    /// Selecciona los pasos de cálculo indicados de todos los componentes
+    ///
+    /// Se mantienen los metadatos, salvo los de anualidades
+    pub fn select_steps(&self, steps: Range<usize>) -> Self {
+        let cdata = self
+            .cdata
+            .iter()
+            .map(|c| Component {
+                values: c.values[steps.clone()].to_vec(),
+                ..c.clone()
+            })
+            .collect();
+        let cmeta = self
+            .cmeta
+            .iter()
+            .filter(|m| m.key != "CTE_ANUALIDADES" && m.key != "CTE_ANUALIDAD_INICIAL")
+            .cloned()
+            .collect();
+        Self {
+            cmeta,
+            cdata,
+            cunknown: Vec::new(),
+        }
+    }
+
+    /// Componentes con el atributo `clave=valor` indicado en el comentario
+    ///
+    /// Ver `Component::attr`.
+    pub fn by_attr<'a>(
+        &'a self,
+        key: &'a str,
+        value: &'a str,
+    ) -> impl Iterator<Item = &'a Component> {
+        self.cdata
+            .iter()
+            .filter(move |c| c.attr(key) == Some(value))
+    }
+
+    /// Componentes del edificio calculado, sin los de sistemas ficticios o de referencia (id negativo)
+    ///
+    /// Los cálculos del edificio (balance, indicadores, resúmenes e informes por sistema) usan
+    /// solamente estos componentes. Los de referencia se obtienen con `reference`.
+    pub fn building_cdata(&self) -> impl Iterator<Item = &Component> + Clone {
+        self.cdata.iter().filter(|c| !c.is_reference())
+    }
+
+    /// Componentes del edificio de referencia, definidos con ids de sistema negativos
+    ///
+    /// Los componentes de los sistemas ficticios o de referencia (id negativo) describen el
+    /// edificio de referencia en el mismo archivo que el edificio calculado. Se devuelven con el
+    /// id cambiado de signo, de modo que el sistema de referencia `-n` corresponde al sistema `n`
+    /// del edificio calculado, y con los mismos metadatos, salvo las declaraciones de sistemas.
+    ///
+    /// Devuelve `None` si no hay componentes de referencia.
+    pub fn reference(&self) -> Option<Self> {
+        let cdata: Vec<Component> = self
             .cdata
             .iter()
-            .map(|v| format!("{}", v))
-            .collect::<Vec<_>>()
-            .join("\n");
-        write!(f, "{}\n{}", metalines, datalines)
+            .filter(|c| c.is_reference())
+            .map(|c| Component {
+                id: -c.id,
+                ..c.clone()
+            })
+            .collect();
+        if cdata.is_empty() {
+            return None;
+        }
+        let cmeta = self
+            .cmeta
+            .iter()
+            .filter(|m| m.key != "CTE_SISTEMA")
+            .cloned()
+            .collect();
+        Some(Self {
+            cmeta,
+            cdata,
+            cunknown: Vec::new(),
+        })
     }
-}
-
-impl str::FromStr for Components {
-    type Err = EpbdError;
 
-    fn from_str(s: &str) -> Result<Components, Self::Err> {
-        let s_nobom = if s.starts_with("\u{feff}") {
-            &s[3..]
-        } else {
-            s
+    /// Diferencia de los componentes respecto a los de un caso base
+    ///
+    /// Permite el análisis de ahorros (p.e. estado reformado menos estado previo a la reforma).
+    ///
+    /// Los componentes se emparejan por sistema, vector, tipo, subtipo y servicio, sumando
+    /// previamente los componentes equivalentes de cada caso. Los componentes sin pareja se
+    /// incluyen en la diferencia como si la pareja fuese nula (con signo negativo los del caso
+    /// base) y se señalan en las listas `only_current` y `only_baseline`.
+    ///
+    /// Se mantienen los metadatos del caso actual.
+    ///
+    /// # Errors
+    ///
+    /// * Distinto número de pasos de cálculo en los componentes de ambos casos
+    pub fn subtract(&self, baseline: &Components) -> Result<ComponentsDifference, EpbdError> {
+        let key = |c: &Component| (c.id, c.carrier.clone(), c.ctype, c.csubtype, c.service);
+        // Agrupa los componentes equivalentes, manteniendo el orden de aparición
+        let aggregate = |cdata: &[Component]| {
+            let mut grouped: Vec<Component> = Vec::new();
+            for c in cdata {
+                match grouped.iter_mut().find(|g| key(g) == key(c)) {
+                    Some(g) => g.values = vecvecsum(&g.values, &c.values),
+                    None => grouped.push(c.clone()),
+                }
+            }
+            grouped
         };
-        let lines: Vec<&str> = s_nobom.lines().map(str::trim).collect();
-        let metalines = lines
-            .iter()
-            .filter(|l| l.starts_with("#META") || l.starts_with("#CTE_"));
-        let datalines = lines
+        let current = aggregate(&self.cdata);
+        let base = aggregate(&baseline.cdata);
+
+        let num_steps = |cdata: &[Component]| cdata.first().map(|c| c.values.len());
+        if let (Some(n_cur), Some(n_base)) = (num_steps(&current), num_steps(&base)) {
+            if n_cur != n_base {
+                return Err(EpbdError::WrongInput(format!(
+                    "Número de pasos de cálculo distinto en el caso actual ({}) y en el caso base ({})",
+                    n_cur, n_base
+                )));
+            }
+        }
+
+        let mut cdata = Vec::new();
+        let mut only_current = Vec::new();
+        for c in &current {
+            match base.iter().find(|b| key(b) == key(c)) {
+                Some(b) => cdata.push(Component {
+                    values: vecvecdif(&c.values, &b.values),
+                    comment: String::new(),
+                    ..c.clone()
+                }),
+                None => {
+                    cdata.push(c.clone());
+                    only_current.push(c.clone());
+                }
+            }
+        }
+        let mut only_baseline = Vec::new();
+        for b in base
             .iter()
-            .filter(|l| !(l.starts_with('#') || l.starts_with("vector,") || l.is_empty()));
-        let cmeta = metalines
-            .map(|e| e.parse())
-            .collect::<Result<Vec<Meta>, _>>()?;
-        let cdata = datalines
-            .map(|e| e.parse())
-            .collect::<Result<Vec<Component>, _>>()?;
+            .filter(|b| !current.iter().any(|c| key(c) == key(b)))
         {
-            let cdata_lens: Vec<_> = cdata.iter().map(|e| e.values.len()).collect();
-            if cdata_lens.iter().max().unwrap() != cdata_lens.iter().min().unwrap() {
-                return Err(EpbdError::ParseError(s.into()));
-            }
+            cdata.push(Component {
+                values: b.values.iter().map(|v| -v).collect(),
+                ..b.clone()
+            });
+            only_baseline.push(b.clone());
         }
-        Ok(Components { cmeta, cdata })
+
+        Ok(ComponentsDifference {
+            components: Components {
+                cmeta: self.cmeta.clone(),
+                cdata,
+                cunknown: Vec::new(),
+            },
+            only_current,
+            only_baseline,
+        })
     }
-}
 
-impl Components {
-    /// Corrige los componentes de consumo y producción
+    /// Combina estos componentes con los de otro conjunto
     ///
-    /// - Asegura que la energía MEDIOAMBIENTE consumida tiene su producción correspondiente
-    /// - Asegura que la energía eléctrica producida no tiene un uso que no sea NDEF
+    /// Permite componer un edificio a partir de datos procedentes de varias fuentes (p.e.
+    /// consumos de un programa de simulación y producciones de otro).
     ///
-    /// Los metadatos, servicios y coherencia de los vectores se aseguran ya en el parsing
-    pub fn normalize(mut self) -> Self {
-        self.force_ndef_use_for_electricity_production();
-        self.compensate_env_use();
-        self
+    /// Los componentes (y los componentes desconocidos) se concatenan, manteniendo primero los
+    /// propios. Los identificadores de sistema no se renumeran, por lo que un mismo sistema debe
+    /// declararse igual en ambos conjuntos (`CTE_SISTEMA`).
+    ///
+    /// Los metadatos se resuelven del siguiente modo:
+    ///
+    /// * los metadatos que solo aparecen en uno de los conjuntos se incorporan al resultado
+    /// * los metadatos que admiten varias entradas (`CTE_CALIBRACION`, `CTE_SISTEMA`,
+    ///   `CTE_ALMACENAMIENTO_TERMICO` y `CTE_CONVERSION`) se acumulan, eliminando duplicados
+    /// * en el resto de metadatos con valores distintos se conserva el valor propio y se
+    ///   emite un aviso `Warning::MetaConflict`
+    ///
+    /// # Errors
+    ///
+    /// * Distinto número de pasos de cálculo en los componentes de ambos conjuntos
+    /// * Distinto paso de cálculo (`CTE_PASO`) en ambos conjuntos
+    /// * Sistema con el mismo id y distinta declaración (`CTE_SISTEMA`) en ambos conjuntos
+    pub fn merge(self, other: Components) -> Result<(Self, Vec<Warning>), EpbdError> {
+        const MULTIVALUED_KEYS: [&str; 4] = [
+            "CTE_CALIBRACION",
+            "CTE_SISTEMA",
+            "CTE_ALMACENAMIENTO_TERMICO",
+            "CTE_CONVERSION",
+        ];
+
+        let num_steps = |cdata: &[Component]| cdata.first().map(|c| c.values.len());
+        if let (Some(n_self), Some(n_other)) = (num_steps(&self.cdata), num_steps(&other.cdata)) {
+            if n_self != n_other {
+                return Err(EpbdError::WrongInput(format!(
+                    "Número de pasos de cálculo distinto en los componentes a combinar ({} y {})",
+                    n_self, n_other
+                )));
+            }
+        }
+
+        let Components {
+            mut cmeta,
+            mut cdata,
+            mut cunknown,
+        } = self;
+        let mut warnings = Vec::new();
+        let system_id = |value: &str| value.split(',').next().map(|id| id.trim().to_string());
+        for meta in other.cmeta {
+            if meta.key == "CTE_SISTEMA" {
+                if let Some(m) = cmeta.iter().find(|m| {
+                    m.key == meta.key
+                        && m.value != meta.value
+                        && system_id(&m.value) == system_id(&meta.value)
+                }) {
+                    return Err(EpbdError::WrongInput(format!(
+                        "Sistema declarado de forma distinta en los componentes a combinar ({} y {})",
+                        m.value, meta.value
+                    )));
+                }
+            }
+            if MULTIVALUED_KEYS.contains(&meta.key.as_str()) {
+                if !cmeta
+                    .iter()
+                    .any(|m| m.key == meta.key && m.value == meta.value)
+                {
+                    cmeta.push(meta);
+                }
+                continue;
+            }
+            match cmeta.iter().find(|m| m.key == meta.key) {
+                None => cmeta.push(meta),
+                Some(m) if m.value == meta.value => (),
+                Some(m) if m.key == "CTE_PASO" => {
+                    return Err(EpbdError::WrongInput(format!(
+                        "Paso de cálculo distinto en los componentes a combinar ({} y {})",
+                        m.value, meta.value
+                    )))
+                }
+                Some(m) => warnings.push(Warning::MetaConflict {
+                    key: meta.key,
+                    kept: m.value.clone(),
+                    discarded: meta.value,
+                }),
+            }
+        }
+        cdata.extend(other.cdata);
+        cunknown.extend(other.cunknown);
+        check_timestep(&cmeta, &cdata)?;
+
+        Ok((
+            Components {
+                cmeta,
+                cdata,
+                cunknown,
+            },
+            warnings,
+        ))
     }
 
     /// Filtra Componentes relacionados con un servicio EPB
     ///
     /// 1. Se seleccionan todos los consumos y producciones asignados al servicio
     /// 2. Se toman las producciones eléctricas
-    /// 3. Reparto de las producciones eléctricas en proporción al consumo del servicio respecto al consumo EPB
+    /// 3. Reparto de las producciones eléctricas usadas en usos EPB entre los servicios. Con un
+    ///    orden de prioridad de servicios (metadato `CTE_PRIORIDAD_SERVICIOS`, ver `service_priority`)
+    ///    la producción cubre, en cada paso de cálculo, el consumo de los servicios en ese orden y el
+    ///    resto se reparte entre los demás servicios en proporción a su consumo. Sin orden de prioridad,
+    ///    el reparto es proporcional al consumo del servicio respecto al consumo EPB.
+    ///    El reparto usado se indica en el comentario de los componentes de producción reasignados.
+    ///
+    /// Un orden de prioridad incorrecto no se tiene en cuenta (reparto proporcional al consumo), ya
+    /// que produce un error al calcular el balance.
     ///
     /// *Nota*: los componentes deben estar normalizados (ver método normalize) para asegurar que:
     /// - los consumos de MEDIOAMBIENTE de un servicio ya están equilibrados
@@ -148,7 +2436,7 @@ impl Components {
     #[allow(non_snake_case)]
     pub fn filter_by_epb_service(&self, service: Service) -> Self {
         let num_steps = self.cdata[0].values.len(); // Pasos de cálculo
-        let cdata = self.cdata.iter(); // Componentes
+        let cdata = self.building_cdata(); // Componentes del edificio calculado
 
         // 1. Consumos y producciones del servicio, salvo la producción eléctrica
         let mut cdata_srv: Vec<_> = cdata
@@ -164,62 +2452,57 @@ impl Components {
         let E_pr_el_t = cdata
             .clone()
             .filter(|c| c.carrier == Carrier::ELECTRICIDAD && c.ctype == CType::PRODUCCION);
-        let E_pr_el_an: f32 = E_pr_el_t.clone().flat_map(|c| c.values.iter()).sum();
+        let E_pr_el_an: Real = E_pr_el_t.clone().flat_map(|c| c.values.iter()).sum();
 
-        // 3. Reparto de la producción electrica en proporción al consumo de usos EPB
+        // 3. Reparto de la producción electrica entre los servicios EPB
         // Energía eléctrica consumida en usos EPB
         let E_EPus_el_t = cdata.clone().filter(|c| {
-            c.carrier == Carrier::ELECTRICIDAD
-                && c.ctype == CType::CONSUMO
-                && c.csubtype == CSubtype::EPB
+            c.carrier == Carrier::ELECTRICIDAD && c.is_used_energy() && c.csubtype == CSubtype::EPB
         });
 
         // Energía eléctrica consumida en el servicio srv
-        let E_srv_el_t = E_EPus_el_t.clone().filter(|c| c.service == service);
-        let E_srv_el_an: f32 = E_srv_el_t.clone().flat_map(|c| c.values.iter()).sum();
+        let E_srv_el_an: Real = E_EPus_el_t
+            .clone()
+            .filter(|c| c.service == service)
+            .flat_map(|c| c.values.iter())
+            .sum();
 
         // Si hay consumo y producción de electricidad, se reparte el consumo
         if E_srv_el_an > 0.0 && E_pr_el_an > 0.0 {
-            // Energía eléctrica consumida en usos EPB
-            let E_EPus_el_t_tot = E_EPus_el_t
-                .clone()
-                .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
-
-            // Fracción del consumo EPB que representa el servicio srv
-            let E_srv_el_t_tot = E_srv_el_t
-                .clone()
-                .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
-            let f_srv_t = E_srv_el_t_tot
-                .iter()
-                .zip(&E_EPus_el_t_tot)
-                .map(|(v, t)| if v.abs() < f32::EPSILON { 0.0 } else { v / t })
-                .collect::<Vec<_>>();
-
-            // Repartimos la producción eléctrica
+            // Energía eléctrica consumida en usos EPB, por servicio
+            let mut E_EPus_el_s_t: Vec<(Service, Vec<Real>)> = Vec::new();
+            for c in E_EPus_el_t.clone() {
+                match E_EPus_el_s_t.iter_mut().find(|(s, _)| *s == c.service) {
+                    Some((_, values)) => vecvecsum_assign(values, &c.values),
+                    None => E_EPus_el_s_t.push((c.service, c.values.clone())),
+                }
+            }
+            let E_EPus_el_t_tot = vecitersum(num_steps, E_EPus_el_t.clone().map(|e| &e.values));
 
             // Energía eléctrica producida y consumida en usos EPB, corregida por f_match_t
             let f_match_t = vec![1.0; num_steps]; // TODO: implementar f_match_t
-            let E_pr_el_t_tot = E_pr_el_t
-                .clone()
-                .fold(vec![0.0; num_steps], |acc, e| vecvecsum(&acc, &e.values));
+            let E_pr_el_t_tot = vecitersum(num_steps, E_pr_el_t.clone().map(|e| &e.values));
             let E_pr_el_used_EPus_t =
                 vecvecmul(&f_match_t, &vecvecmin(&E_EPus_el_t_tot, &E_pr_el_t_tot));
 
+            // Energía eléctrica producida y consumida asignada al servicio srv
+            let priority = self.service_priority().ok().flatten().unwrap_or_default();
+            let E_pr_el_srv_t =
+                allocate_to_service(&E_pr_el_used_EPus_t, &E_EPus_el_s_t, &priority, service);
+            let allocation = self.service_allocation();
+
             // Para cada generador i
             for mut E_pr_el_i in E_pr_el_t.cloned() {
                 // Fracción de la producción total que corresponde al generador i
-                let f_pr_el_i: f32 = E_pr_el_i.values.iter().sum::<f32>() / E_pr_el_an;
+                let f_pr_el_i: Real = E_pr_el_i.values.iter().sum::<Real>() / E_pr_el_an;
 
-                // Reparto proporcional a la producción del generador i y al consumo del servicio srv
-                E_pr_el_i.values = (&E_pr_el_used_EPus_t)
-                    .iter()
-                    .zip(&f_srv_t)
-                    .map(|(v, f_srv)| v * f_pr_el_i * f_srv)
-                    .collect();
+                // Reparto proporcional a la producción del generador i
+                E_pr_el_i.values = E_pr_el_srv_t.iter().map(|v| v * f_pr_el_i).collect();
                 E_pr_el_i.service = service;
-                E_pr_el_i.comment = format!(
-                    "{} Producción eléctrica reasignada al servicio",
-                    E_pr_el_i.comment
+                E_pr_el_i.comment = tr_format!(
+                    "{} Producción eléctrica reasignada al servicio (reparto: {})",
+                    E_pr_el_i.comment,
+                    allocation
                 );
                 cdata_srv.push(E_pr_el_i);
             }
@@ -229,6 +2512,7 @@ impl Components {
         let mut newcomponents = Self {
             cdata: cdata_srv,
             cmeta,
+            cunknown: Vec::new(),
         };
         newcomponents.set_meta("CTE_SERVICIO", &service.to_string());
 
@@ -238,15 +2522,57 @@ impl Components {
     /// Asegura que la energía eléctrica producida no tiene un uso que no sea NDEF
     ///
     /// Esta restricción es propia de la implementación y de cómo hace el reparto de la producción,
-    /// solamente en base al consumo de cada servicio y sin tener en cuenta si se define un destino
-    ///XXX: *Esta restricción debería eliminarse*
-    fn force_ndef_use_for_electricity_production(&mut self) {
-        // Localiza componentes de energía procedente del medioambiente
+    /// solamente en base al consumo de cada servicio y sin tener en cuenta si se define un destino.
+    /// No se aplica cuando la producción se compensa servicio a servicio (ver `compensation_by_service`),
+    /// ya que entonces se respeta el servicio al que se asigna la producción.
+    fn force_ndef_use_for_electricity_production(&mut self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        if matches!(self.compensation_by_service(), Ok(true)) {
+            return warnings;
+        }
+        // Localiza componentes de producción eléctrica con servicio asignado
         for component in &mut self.cdata {
-            if component.carrier == Carrier::ELECTRICIDAD && component.ctype == CType::PRODUCCION {
+            if component.carrier == Carrier::ELECTRICIDAD
+                && component.ctype == CType::PRODUCCION
+                && component.service != Service::NDEF
+            {
+                warnings.push(Warning::ProductionServiceToNdef(component.service));
                 component.service = Service::NDEF
             }
         }
+        warnings
+    }
+
+    /// Clasifica los consumos de recarga de vehículos eléctricos (servicio RECARGAVE) como EPB o NEPB
+    ///
+    /// Según los criterios del CTE, la recarga de vehículos eléctricos no es un servicio EPB, por lo
+    /// que sus consumos y ajustes se consideran, por defecto, no EPB (NEPB) y quedan excluidos de los
+    /// indicadores EPB. Con el metadato `CTE_RECARGAVE_EPB: true` se incluyen como consumos EPB.
+    /// Un valor incorrecto del metadato se interpreta como `false`, con un aviso.
+    fn classify_ev_charging(&mut self) -> Vec<Warning> {
+        let mut warnings = Vec::new();
+        let include_epb = self.get_bool("CTE_RECARGAVE_EPB").unwrap_or_else(|_| {
+            warnings.push(Warning::InvalidMeta {
+                key: "CTE_RECARGAVE_EPB".to_string(),
+                value: self.get_meta("CTE_RECARGAVE_EPB").unwrap_or_default(),
+            });
+            None
+        });
+        let csubtype = if include_epb == Some(true) {
+            CSubtype::EPB
+        } else {
+            CSubtype::NEPB
+        };
+        for component in &mut self.cdata {
+            if component.service == Service::RECARGAVE
+                && component.is_used_energy()
+                && component.csubtype != csubtype
+            {
+                warnings.push(Warning::EvChargingSubtype(csubtype));
+                component.csubtype = csubtype;
+            }
+        }
+        warnings
     }
 
     /// Asegura que la energía MEDIOAMBIENTE consumida está equilibrada por una producción in situ
@@ -254,30 +2580,33 @@ impl Components {
     /// Completa el balance de las producciones in situ de energía procedente del medioambiente
     /// cuando el consumo de esos vectores supera la producción. Es solamente una comodidad, para no
     /// tener que declarar las producciones de MEDIOAMBIENTE, solo los consumos.
-    fn compensate_env_use(&mut self) {
+    fn compensate_env_use(&mut self) -> Vec<Warning> {
         // Localiza componentes de energía procedente del medioambiente
         let envcomps: Vec<_> = self
             .cdata
             .iter()
+            .filter(|&c| c.carrier == Carrier::MEDIOAMBIENTE)
             .cloned()
-            .filter(|c| c.carrier == Carrier::MEDIOAMBIENTE)
             .collect();
-        // Identifica servicios
-        let services: HashSet<_> = envcomps.iter().map(|c| c.service).collect();
+        // Identifica servicios, por separado para el edificio calculado y el de referencia,
+        // en orden para que los componentes generados no dependan del orden de iteración
+        let services: BTreeSet<_> = envcomps
+            .iter()
+            .map(|c| (c.service, c.is_reference()))
+            .collect();
 
         // Asegura que la producción eléctrica no tiene un uso definido (es NDEF)
 
         // Genera componentes de consumo no compensados con producción
         let mut balancecomps: Vec<Component> = services
             .iter()
-            .map(|&service| {
+            .filter_map(|&(service, is_reference)| {
                 // Componentes para el servicio
-                let ecomps = envcomps.iter().filter(|c| c.service == service);
+                let ecomps = envcomps
+                    .iter()
+                    .filter(|c| c.service == service && c.is_reference() == is_reference);
                 // Componentes de consumo del servicio
-                let consumed: Vec<_> = ecomps
-                    .clone()
-                    .filter(|c| c.ctype == CType::CONSUMO)
-                    .collect();
+                let consumed: Vec<_> = ecomps.clone().filter(|c| c.is_used_energy()).collect();
                 // Si no hay consumo que compensar con producción retornamos None
                 if consumed.is_empty() {
                     return None;
@@ -304,29 +2633,38 @@ impl Components {
                     );
                     unbalanced_values = vecvecdif(&unbalanced_values, &totproduced)
                         .iter()
-                        .map(|&v| if v > 0.0 { v } else { 0.0 })
+                        .map(|&v| if v > ENV_BALANCE_TOLERANCE { v } else { 0.0 })
                         .collect();
                 }
-                // Si no hay desequilibrio retornamos None
-                if unbalanced_values.iter().sum::<f32>() == 0.0 {
+                // Si no hay desequilibrio (salvo errores de redondeo) retornamos None
+                if unbalanced_values.iter().sum::<Real>() < ENV_BALANCE_TOLERANCE {
                     return None;
                 };
 
-                // Si hay desequilibrio agregamos un componente de producción
+                // Si hay desequilibrio agregamos un componente de producción, del edificio de
+                // referencia si compensa consumos de referencia
                 Some(Component {
+                    id: if is_reference { consumed[0].id } else { 0 },
                     carrier: Carrier::MEDIOAMBIENTE,
                     ctype: CType::PRODUCCION,
                     csubtype: CSubtype::INSITU,
                     service,
+                    aux: false,
                     values: unbalanced_values,
-                    comment: "Equilibrado de consumo sin producción declarada".into(),
+                    comment: tr_format!(
+                        "{} Equilibrado de consumo sin producción declarada",
+                        GENERATED_TAG
+                    ),
                 })
             })
-            .filter(std::option::Option::is_some)
-            .collect::<Option<Vec<_>>>()
-            .unwrap_or_else(Vec::new);
+            .collect::<Vec<_>>();
+        let warnings = balancecomps
+            .iter()
+            .map(|c| Warning::GeneratedEnvProduction(c.service))
+            .collect();
         // Agrega componentes no compensados
         self.cdata.append(&mut balancecomps);
+        warnings
     }
 }
 
@@ -347,15 +2685,15 @@ ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 8.20, 6.56, 4.10, 3.69, 2.05, 2.46, 3.28
 ELECTRICIDAD, CONSUMO, EPB, REF, 16.39, 13.11, 8.20, 7.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 13.11
 ELECTRICIDAD, CONSUMO, EPB, CAL, 16.39, 13.11, 8.20, 7.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 13.11
 MEDIOAMBIENTE, CONSUMO, EPB, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11
-MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11 # Equilibrado de consumo sin producción declarada";
+MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11 # CTEEPBD_GENERADO Equilibrado de consumo sin producción declarada";
 
     // La producción se debe repartir al 50% entre los usos EPB
     const TCOMPSRES2: &str = "#META CTE_AREAREF: 100.5
 #META CTE_SERVICIO: CAL
 ELECTRICIDAD, CONSUMO, EPB, CAL, 16.39, 13.11, 8.20, 7.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 13.11
 MEDIOAMBIENTE, CONSUMO, EPB, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11
-MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11 # Equilibrado de consumo sin producción declarada
-ELECTRICIDAD, PRODUCCION, INSITU, CAL, 4.10, 3.28, 2.05, 1.85, 1.02, 1.23, 1.64, 1.43, 1.02, 1.64, 2.46, 3.28 #  Producción eléctrica reasignada al servicio";
+MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 6.39, 3.11, 8.20, 17.38, 4.10, 4.92, 6.56, 5.74, 4.10, 6.56, 9.84, 3.11 # CTEEPBD_GENERADO Equilibrado de consumo sin producción declarada
+ELECTRICIDAD, PRODUCCION, INSITU, CAL, 4.10, 3.28, 2.05, 1.85, 1.02, 1.23, 1.64, 1.43, 1.02, 1.64, 2.46, 3.28 #  Producción eléctrica reasignada al servicio (reparto: proporcional al consumo)";
 
     // La producción se debe repartir al 50% entre los usos EPB y sin excesos
     const TCOMPS2: &str = "#META CTE_AREAREF: 1.0
@@ -368,8 +2706,8 @@ MEDIOAMBIENTE, CONSUMO, EPB, CAL, 2.00, 2.00, 2.00";
 #META CTE_SERVICIO: CAL
 ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00, 1.00
 MEDIOAMBIENTE, CONSUMO, EPB, CAL, 2.00, 2.00, 2.00
-MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 2.00, 2.00, 2.00 # Equilibrado de consumo sin producción declarada
-ELECTRICIDAD, PRODUCCION, INSITU, CAL, 1.00, 2.00, 1.00 #  Producción eléctrica reasignada al servicio";
+MEDIOAMBIENTE, PRODUCCION, INSITU, CAL, 2.00, 2.00, 2.00 # CTEEPBD_GENERADO Equilibrado de consumo sin producción declarada
+ELECTRICIDAD, PRODUCCION, INSITU, CAL, 1.00, 2.00, 1.00 #  Producción eléctrica reasignada al servicio (reparto: proporcional al consumo)";
 
     #[test]
     fn tcomponents_parse() {
@@ -378,10 +2716,982 @@ ELECTRICIDAD, PRODUCCION, INSITU, CAL, 1.00, 2.00, 1.00 #  Producción eléctric
         assert_eq!(tcomps.to_string(), TCOMPS1);
     }
 
+    #[test]
+    fn tcomponents_sort() {
+        let mut tcomps = TCOMPS1.parse::<Components>().unwrap();
+        tcomps.sort_canonical();
+        let services: Vec<_> = tcomps
+            .cdata
+            .iter()
+            .map(|c| (c.carrier.clone(), c.ctype, c.service))
+            .collect();
+        assert_eq!(
+            services,
+            vec![
+                (Carrier::ELECTRICIDAD, CType::PRODUCCION, Service::CAL),
+                (Carrier::ELECTRICIDAD, CType::CONSUMO, Service::CAL),
+                (Carrier::ELECTRICIDAD, CType::CONSUMO, Service::REF),
+                (Carrier::MEDIOAMBIENTE, CType::CONSUMO, Service::CAL),
+            ]
+        );
+        tcomps.sort_by_key(|c| std::cmp::Reverse(c.service));
+        assert_eq!(tcomps.cdata[0].service, Service::REF);
+
+        // Los componentes de distintos sistemas se ordenan por su id
+        let mut tcomps = "2, ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00
+1, ELECTRICIDAD, CONSUMO, EPB, CAL, 2.00
+ELECTRICIDAD, CONSUMO, EPB, CAL, 3.00"
+            .parse::<Components>()
+            .unwrap();
+        tcomps.sort_canonical();
+        let ids: Vec<_> = tcomps.cdata.iter().map(|c| c.id).collect();
+        assert_eq!(ids, vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn tcomponents_annual_periods() {
+        let tcomps = "#META CTE_ANUALIDADES: 2, 1
+#META CTE_ANUALIDAD_INICIAL: 2018
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00, 3.00"
+            .parse::<Components>()
+            .unwrap();
+        let periods = tcomps.annual_periods().unwrap().unwrap();
+        assert_eq!(
+            periods,
+            vec![("2018".to_string(), 0..2), ("2019".to_string(), 2..3)]
+        );
+        let year = tcomps.select_steps(periods[1].1.clone());
+        assert_eq!(year.cdata[0].values, vec![3.0]);
+        assert!(!year.has_meta("CTE_ANUALIDADES"));
+        assert!(year.annual_periods().unwrap().is_none());
+
+        let mut tcomps = tcomps;
+        tcomps.set_meta("CTE_ANUALIDADES", "2, 2");
+        assert!(tcomps.annual_periods().is_err());
+    }
+
+    #[test]
+    fn tcomponents_adjustments() {
+        let tcomps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.00, 5.00
+ELECTRICIDAD, AJUSTE, EPB, CAL, -2.00, 1.00 # Corrección de lectura"
+            .parse::<Components>()
+            .unwrap();
+        assert_eq!(tcomps.cdata[1].ctype, CType::AJUSTE);
+        assert_eq!(tcomps.cdata[1].comment, "Corrección de lectura");
+        assert_eq!(
+            tcomps.cdata[1].to_string(),
+            "ELECTRICIDAD, AJUSTE, EPB, CAL, -2.00, 1.00 # Corrección de lectura"
+        );
+        // Los ajustes necesitan un motivo
+        assert!("ELECTRICIDAD, CONSUMO, EPB, CAL, 10.00
+ELECTRICIDAD, AJUSTE, EPB, CAL, -2.00"
+            .parse::<Components>()
+            .is_err());
+        // Los ajustes solo afectan a consumos
+        assert!("ELECTRICIDAD, AJUSTE, INSITU, NDEF, -2.00 # Producción"
+            .parse::<Components>()
+            .is_err());
+        // Los ajustes no pueden dar lugar a consumos negativos
+        assert!("ELECTRICIDAD, CONSUMO, EPB, CAL, 10.00, 5.00
+ELECTRICIDAD, AJUSTE, EPB, CAL, -2.00, -6.00 # Deducción estimada"
+            .parse::<Components>()
+            .is_err());
+    }
+
+    #[test]
+    fn tcomponents_calibrate() {
+        let tcomps = "#META CTE_CALIBRACION: ELECTRICIDAD, 1.10
+ELECTRICIDAD, CONSUMO, EPB, CAL, 10.00, 20.00
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 5.00, 5.00
+GASNATURAL, CONSUMO, EPB, CAL, 10.00, 20.00"
+            .parse::<Components>()
+            .unwrap();
+        assert_eq!(
+            tcomps.calibration_factors().unwrap(),
+            vec![(Carrier::ELECTRICIDAD, 1.1)]
+        );
+        let tcomps = tcomps.calibrate().unwrap();
+        assert_eq!(tcomps.cdata[0].values, vec![11.0, 22.0]);
+        assert_eq!(tcomps.cdata[1].values, vec![5.0, 5.0]);
+        assert_eq!(tcomps.cdata[2].values, vec![10.0, 20.0]);
+
+        // Factores duplicados o no positivos
+        assert!("#META CTE_CALIBRACION: ELECTRICIDAD, 1.10
+#META CTE_CALIBRACION: ELECTRICIDAD, 1.20
+ELECTRICIDAD, CONSUMO, EPB, CAL, 10.00"
+            .parse::<Components>()
+            .unwrap()
+            .calibrate()
+            .is_err());
+        assert!("#META CTE_CALIBRACION: ELECTRICIDAD, -1.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 10.00"
+            .parse::<Components>()
+            .unwrap()
+            .calibrate()
+            .is_err());
+    }
+
+    #[test]
+    fn tcomponents_subtract() {
+        let baseline = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.00, 20.00
+ELECTRICIDAD, CONSUMO, EPB, CAL, 5.00, 5.00
+GASNATURAL, CONSUMO, EPB, ACS, 8.00, 6.00"
+            .parse::<Components>()
+            .unwrap();
+        let current = "#META CTE_AREAREF: 100.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 9.00, 12.00
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 3.00, 4.00"
+            .parse::<Components>()
+            .unwrap();
+        let diff = current.subtract(&baseline).unwrap();
+        assert_eq!(
+            diff.components.to_string(),
+            "#META CTE_AREAREF: 100.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, -6.00, -13.00
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 3.00, 4.00
+GASNATURAL, CONSUMO, EPB, ACS, -8.00, -6.00"
+        );
+        assert_eq!(diff.only_current.len(), 1);
+        assert_eq!(diff.only_current[0].ctype, CType::PRODUCCION);
+        assert_eq!(diff.only_baseline.len(), 1);
+        assert_eq!(diff.only_baseline[0].carrier, Carrier::GASNATURAL);
+        // Distinto número de pasos de cálculo
+        let other = "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00"
+            .parse::<Components>()
+            .unwrap();
+        assert!(current.subtract(&other).is_err());
+    }
+
+    #[test]
+    fn tcomponents_service_map() {
+        let service_map = parse_service_map(
+            "# Equivalencias de servicios
+CALEFACCION, CAL
+REFRIGERACION, REF",
+        )
+        .unwrap();
+        assert_eq!(service_map[1], ("REFRIGERACION".to_string(), Service::REF));
+        assert!(parse_service_map("CALEFACCION, XXX").is_err());
+        assert!(parse_service_map("CALEFACCION").is_err());
+        let tcomps = "ELECTRICIDAD, CONSUMO, EPB, CALEFACCION, 1.00, 2.00 # Bomba de calor
+1, GASNATURAL, CONSUMO, EPB, REFRIGERACION, 1.00, 2.00
+ELECTRICIDAD, CONSUMO, EPB, ILU, 1.00, 2.00";
+        assert!(tcomps.parse::<Components>().is_err());
+        let options = ParseOptions {
+            service_map,
+            ..Default::default()
+        };
+        let comps = Components::from_str_with_options(tcomps, &options).unwrap();
+        assert_eq!(
+            comps.to_string(),
+            "
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00 # Bomba de calor
+1, GASNATURAL, CONSUMO, EPB, REF, 1.00, 2.00
+ELECTRICIDAD, CONSUMO, EPB, ILU, 1.00, 2.00"
+        );
+    }
+
+    #[test]
+    fn tcomponents_csv_format() {
+        let tcomps = "\u{feff}#META CTE_AREAREF: 100,5;;
+#META CTE_COMENTARIO: Caso exportado, con coma
+ELECTRICIDAD; CONSUMO; EPB; CAL; 1,50; 2 # Bomba de calor; 1,5
+GASNATURAL;CONSUMO;EPB;ACS;1;2,25;;;
+#FACTORES
+ELECTRICIDAD; RED; SUMINISTRO; A; 0,414; 1,954; 0,331";
+        assert!(tcomps.parse::<Components>().is_err());
+        let options = ParseOptions {
+            format: CsvFormat::SPANISH,
+            ..Default::default()
+        };
+        let (comps, factors) = parse_components(tcomps, &options).unwrap();
+        assert_eq!(
+            comps.to_string(),
+            "#META CTE_AREAREF: 100.5
+#META CTE_COMENTARIO: Caso exportado, con coma
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.50, 2.00 # Bomba de calor; 1,5
+GASNATURAL, CONSUMO, EPB, ACS, 1.00, 2.25"
+        );
+        assert_eq!(
+            factors.unwrap().wdata[0].to_string(),
+            "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331"
+        );
+        assert!(validate_components(tcomps, &options).is_empty());
+        // Los errores se localizan en la línea del texto original
+        let err = Components::from_str_with_options("#META CTE_AREAREF: 1\nXX;1,0", &options)
+            .unwrap_err();
+        assert!(err.to_string().contains("línea 2"));
+        let wrong = CsvFormat {
+            delimiter: ',',
+            decimal_separator: ',',
+        };
+        assert!(wrong
+            .to_canonical("ELECTRICIDAD,CONSUMO,EPB,CAL,1,5")
+            .is_err());
+        let tab = CsvFormat {
+            delimiter: '\t',
+            ..Default::default()
+        };
+        assert_eq!(
+            tab.to_canonical("ELECTRICIDAD\tCONSUMO\tEPB\tCAL\t1.5\t\t")
+                .unwrap(),
+            "ELECTRICIDAD,CONSUMO,EPB,CAL,1.5"
+        );
+    }
+
+    #[test]
+    fn tcomponents_embedded_factors() {
+        let tcomps = "#META CTE_AREAREF: 100.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00
+#FACTORES
+#META CTE_FUENTE: USUARIO
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.500, 2.000, 0.300";
+        // Los componentes ignoran la sección de factores
+        let comps = tcomps.parse::<Components>().unwrap();
+        assert_eq!(comps.cmeta.len(), 1);
+        assert_eq!(comps.cdata.len(), 1);
+        let (comps, factors) = parse_components(tcomps, &ParseOptions::default()).unwrap();
+        let factors = factors.unwrap();
+        assert_eq!(factors.wmeta.len(), 1);
+        assert_eq!(factors.wdata.len(), 1);
+        // Ida y vuelta como archivo único
+        let (comps2, factors2) =
+            parse_components(&comps.to_string_with_factors(&factors), &Default::default()).unwrap();
+        assert_eq!(comps2.to_string(), comps.to_string());
+        assert_eq!(factors2.unwrap().to_string(), factors.to_string());
+        // Sin sección de factores
+        let (_, factors) = parse_components(TCOMPS1, &ParseOptions::default()).unwrap();
+        assert!(factors.is_none());
+    }
+
+    #[test]
+    fn tcomponents_keep_unknown() {
+        let tcomps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00
+AMONIACO, CONSUMO, EPB, CAL, 1.00, 2.00 # Vector futuro
+ELECTRICIDAD, CONSUMO, EPB, PISCINA, 1.00, 2.00";
+        // Sin admitir desconocidos se produce un error
+        assert!(tcomps.parse::<Components>().is_err());
+        let comps = Components::from_str_keep_unknown(tcomps).unwrap();
+        assert_eq!(comps.cdata.len(), 1);
+        assert_eq!(comps.cunknown.len(), 2);
+        assert_eq!(comps.cunknown[0].identifier, "AMONIACO");
+        assert_eq!(comps.cunknown[1].identifier, "PISCINA");
+        assert_eq!(
+            comps.check_plausibility(),
+            vec![
+                ComponentWarning::UnknownComponent("AMONIACO".into()),
+                ComponentWarning::UnknownComponent("PISCINA".into()),
+            ]
+        );
+        // Se conservan en la serialización
+        assert!(comps
+            .to_string()
+            .ends_with("AMONIACO, CONSUMO, EPB, CAL, 1.00, 2.00 # Vector futuro\nELECTRICIDAD, CONSUMO, EPB, PISCINA, 1.00, 2.00"));
+        let json = serde_json::to_string(&comps).unwrap();
+        let comps2: Components = serde_json::from_str(&json).unwrap();
+        assert_eq!(comps2.cunknown, comps.cunknown);
+        // Los errores de formato no se confunden con identificadores desconocidos
+        assert!(
+            Components::from_str_keep_unknown("ELECTRICIDAD, CONSUMO, XXX, CAL, 1.00").is_err()
+        );
+        assert!(Components::from_str_keep_unknown("AMONIACO, CONSUMO, EPB, CAL, 1.0a").is_err());
+    }
+
+    #[test]
+    fn tcomponents_check_plausibility() {
+        let tcomps = "GASNATURAL, CONSUMO, EPB, ILU, 1.00
+GASNATURAL, CONSUMO, EPB, ILU, 2.00
+BIOMASA, CONSUMO, EPB, REF, 1.00
+ELECTRICIDAD, CONSUMO, EPB, NDEF, 1.00
+ELECTRICIDAD, CONSUMO, NEPB, NDEF, 1.00
+GASNATURAL, CONSUMO, EPB, CAL, 1.00
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 1.00"
+            .parse::<Components>()
+            .unwrap();
+        let warnings = tcomps.check_plausibility();
+        assert_eq!(
+            warnings,
+            vec![
+                ComponentWarning::ImplausibleService(Carrier::GASNATURAL, Service::ILU),
+                ComponentWarning::ImplausibleService(Carrier::BIOMASA, Service::REF),
+                ComponentWarning::UndefinedService(Carrier::ELECTRICIDAD),
+            ]
+        );
+        assert_eq!(warnings[0].code(), "W201");
+        assert_eq!(
+            warnings[1].to_string(),
+            "consumo de BIOMASA para el servicio REF poco habitual"
+        );
+        assert!(TCOMPS1
+            .parse::<Components>()
+            .unwrap()
+            .check_plausibility()
+            .is_empty());
+    }
+
+    #[test]
+    fn tcomponents_check_systems() {
+        let tcomps = "#META CTE_SISTEMA: 1, Caldera de gas, CALDERA, GASNATURAL, 24.0
+#META CTE_SISTEMA: 2, Bomba de calor, BDC, ELECTRICIDAD, 8.0
+#META CTE_SISTEMA: 3, Caldera de biomasa, CALDERA, BIOMASA, 20.0
+1, GASNATURAL, CONSUMO, EPB, CAL, 10.00
+2, GASNATURAL, CONSUMO, EPB, ACS, 1.00
+4, ELECTRICIDAD, CONSUMO, EPB, ILU, 1.00
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00"
+            .parse::<Components>()
+            .unwrap();
+        assert_eq!(tcomps.systems().unwrap().len(), 3);
+        assert_eq!(
+            tcomps.check_systems().unwrap(),
+            vec![
+                ComponentWarning::UndeclaredSystem(4),
+                ComponentWarning::SystemCarrierMismatch(2, Carrier::ELECTRICIDAD),
+                ComponentWarning::UnusedSystem(3),
+            ]
+        );
+        // Sin sistemas declarados no hay comprobaciones
+        assert!(TCOMPS1
+            .parse::<Components>()
+            .unwrap()
+            .check_systems()
+            .unwrap()
+            .is_empty());
+        // Sistemas repetidos
+        let mut tcomps = tcomps;
+        tcomps.cmeta.push(Meta::new(
+            "CTE_SISTEMA",
+            "1, Otra caldera, CALDERA, GASNATURAL, 24.0",
+        ));
+        assert!(tcomps.systems().is_err());
+    }
+
+    #[test]
+    fn tcomponents_systems_utilization() {
+        let tcomps = "#META CTE_SISTEMA: 1, Caldera de gas, CALDERA, GASNATURAL, 20.0
+#META CTE_SISTEMA: 2, Fotovoltaica, FOTOVOLTAICA, ELECTRICIDAD, 5.0
+#META CTE_SISTEMA: 3, Bomba de calor, BDC, ELECTRICIDAD, 0.0
+1, GASNATURAL, CONSUMO, EPB, CAL, 10000.00, 6000.00
+2, ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 4000.00, 2570.00
+3, ELECTRICIDAD, CONSUMO, EPB, REF, 100.00, 100.00"
+            .parse::<Components>()
+            .unwrap();
+        let utilization = tcomps.systems_utilization().unwrap();
+        assert_eq!(utilization[0].energy, 16000.0);
+        assert_eq!(utilization[0].full_load_hours, Some(800.0));
+        assert_eq!(utilization[0].capacity_factor, None);
+        assert_eq!(utilization[1].full_load_hours, Some(1314.0));
+        assert_eq!(utilization[1].capacity_factor, Some(0.15));
+        assert_eq!(utilization[2].energy, 200.0);
+        assert_eq!(utilization[2].full_load_hours, None);
+
+        // Media anual con anualidades
+        let mut tcomps = tcomps;
+        tcomps.set_meta("CTE_ANUALIDADES", "1, 1");
+        assert_eq!(
+            tcomps.systems_utilization().unwrap()[0].full_load_hours,
+            Some(400.0)
+        );
+    }
+
+    #[test]
+    fn tcomponents_from_json() {
+        let comps = Components::from_json(
+            r#"{
+            "cmeta": [{"key": "CTE_AREAREF", "value": "100.0"}],
+            "cdata": [
+                {"carrier": "ELECTRICIDAD", "ctype": "CONSUMO", "csubtype": "EPB", "service": "CAL", "values": [1.0, 2.0]},
+                {"id": 1, "carrier": "ELECTRICIDAD", "ctype": "PRODUCCION", "csubtype": "INSITU", "values": [0.5, 0.5], "comment": "PV"}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(comps.get_meta("CTE_AREAREF"), Some("100.0".to_string()));
+        assert_eq!(comps.cdata[1].service, Service::NDEF);
+        assert_eq!(comps.cdata[1].id, 1);
+        // Misma estructura que la serialización
+        let comps2 = Components::from_json(&serde_json::to_string(&comps).unwrap()).unwrap();
+        assert_eq!(comps2.to_string(), comps.to_string());
+        // Mismas comprobaciones que el formato de texto
+        assert!(Components::from_json(
+            r#"{"cdata": [{"carrier": "GASNATURAL", "ctype": "PRODUCCION", "csubtype": "INSITU", "values": [1.0]}]}"#
+        )
+        .is_err());
+        assert!(Components::from_json(
+            r#"{"cdata": [
+                {"carrier": "ELECTRICIDAD", "ctype": "CONSUMO", "csubtype": "EPB", "values": [1.0]},
+                {"carrier": "ELECTRICIDAD", "ctype": "CONSUMO", "csubtype": "EPB", "values": [1.0, 2.0]}
+            ]}"#
+        )
+        .is_err());
+        assert!(Components::from_json("{").is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn tcomponents_yaml() {
+        let comps = Components::from_yaml(
+            "cmeta:\n  - key: CTE_AREAREF\n    value: \"100\"\ncdata:\n  - carrier: ELECTRICIDAD\n    ctype: CONSUMO\n    csubtype: EPB\n    service: CAL\n    values: [1.0, 2.0]\n",
+        )
+        .unwrap();
+        assert_eq!(comps.cdata[0].values, vec![1.0, 2.0]);
+        assert_eq!(comps.get_meta("CTE_AREAREF"), Some("100".to_string()));
+        let comps2 = Components::from_yaml(&comps.to_yaml().unwrap()).unwrap();
+        assert_eq!(comps2.to_string(), comps.to_string());
+        assert!(Components::from_yaml(
+            "cdata:\n  - carrier: GASNATURAL\n    ctype: PRODUCCION\n    csubtype: INSITU\n    values: [1.0]\n"
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn tcomponents_parse_error_location() {
+        let err = "#META CTE_AREAREF: 100\nELECTRICIDAD, CONSUMO, EPB, CAL, 1.0\nELECTRICIDAD, CONSUMO, EPB, XXX, 1.0"
+            .parse::<Components>()
+            .unwrap_err();
+        match err {
+            EpbdError::ParseLineError {
+                line,
+                column,
+                token,
+            } => assert_eq!((line, column, token.as_str()), (3, 29, "XXX")),
+            _ => panic!("error sin posición: {:?}", err),
+        }
+        // Línea de la sección de factores de paso en el texto completo
+        let err = parse_components(
+            "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0\n#FACTORES\nELECTRICIDAD, RED, SUMINISTRO, A, xx, 1.954, 0.331",
+            &ParseOptions::default(),
+        )
+        .unwrap_err();
+        match err {
+            EpbdError::ParseLineError {
+                line,
+                column,
+                token,
+            } => assert_eq!((line, column, token.as_str()), (3, 35, "xx")),
+            _ => panic!("error sin posición: {:?}", err),
+        }
+    }
+
+    #[test]
+    fn tvalidate_components() {
+        let text = "#META CTE_AREAREF: 100
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0, 2.0
+XXX, CONSUMO, EPB, CAL, 1.0, 2.0
+GASNATURAL, CONSUMO, EPB, ACS, 1.0
+GASNATURAL, PRODUCCION, INSITU, 1.0, 2.0
+#FACTORES
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, xx, 1.190, 0.252";
+        let diagnostics = validate_components(text, &ParseOptions::default());
+        let lines: Vec<_> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![Some(3), Some(4), Some(5), Some(8)]);
+        assert_eq!(diagnostics[1].code, "E301");
+        assert!(diagnostics[1].to_string().starts_with("línea 4: "));
+        // Componentes correctos y componentes desconocidos conservados
+        assert!(validate_components(
+            "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0\nHIDROGENO, CONSUMO, EPB, CAL, 1.0",
+            &ParseOptions {
+                keep_unknown: true,
+                ..Default::default()
+            }
+        )
+        .is_empty());
+        // Problemas del conjunto de componentes, sin línea
+        let diagnostics = validate_components(
+            "#META CTE_PASO: HORARIO\nELECTRICIDAD, CONSUMO, EPB, CAL, 1.0, 2.0",
+            &ParseOptions::default(),
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, None);
+    }
+
+    #[test]
+    fn tcomponents_resample_scale() {
+        let comps = "#META CTE_PASO: ANUAL
+#META CTE_ANUALIDADES: 1, 1
+ELECTRICIDAD, CONSUMO, EPB, CAL, 365.00, 730.00
+GASNATURAL, CONSUMO, EPB, ACS, 100.00, 100.00"
+            .parse::<Components>()
+            .unwrap();
+        // Reparto de valores anuales en meses, en proporción a sus días
+        let monthly = comps.resample(Timestep::MENSUAL).unwrap();
+        assert_eq!(monthly.get_meta("CTE_PASO").unwrap(), "MENSUAL");
+        assert_eq!(monthly.get_meta("CTE_ANUALIDADES").unwrap(), "12, 12");
+        let values = &monthly.cdata[0].values;
+        assert_eq!(values.len(), 24);
+        assert!((values[0] - 31.0).abs() < 0.01);
+        assert!((values[1] - 28.0).abs() < 0.01);
+        assert!((values[12] - 62.0).abs() < 0.01);
+        // Reparto horario y vuelta al paso de cálculo original
+        let hourly = monthly.resample(Timestep::HORARIO).unwrap();
+        assert_eq!(hourly.cdata[0].values.len(), 2 * 8760);
+        assert!((hourly.cdata[0].values[0] - 365.0 / 8760.0).abs() < 0.0001);
+        let annual = hourly.resample(Timestep::ANUAL).unwrap();
+        assert!((annual.cdata[0].values[0] - 365.0).abs() < 0.1);
+        assert!((annual.cdata[1].values[1] - 100.0).abs() < 0.1);
+        // Escalado de los consumos de un vector
+        let scaled = comps.scale(1.1, |c| c.carrier == Carrier::ELECTRICIDAD);
+        assert!((scaled.cdata[0].values[1] - 803.0).abs() < 0.01);
+        assert_eq!(scaled.cdata[1].values, vec![100.0, 100.0]);
+    }
+
+    #[test]
+    fn tcomponents_timestep() {
+        let hourly = vec!["1.0"; 8760].join(", ");
+        let comps = format!(
+            "#META CTE_PASO: HORARIO\nELECTRICIDAD, CONSUMO, EPB, CAL, {}",
+            hourly
+        )
+        .parse::<Components>()
+        .unwrap();
+        assert_eq!(comps.timestep().unwrap(), Some(Timestep::HORARIO));
+        // Agregación mensual y anual
+        let monthly = comps.to_monthly().unwrap();
+        assert_eq!(monthly.timestep().unwrap(), Some(Timestep::MENSUAL));
+        assert_eq!(monthly.cdata[0].values.len(), 12);
+        assert_eq!(monthly.cdata[0].values[0], 744.0);
+        assert_eq!(monthly.cdata[0].values[1], 672.0);
+        assert_eq!(monthly.to_annual().unwrap().cdata[0].values, vec![8760.0]);
+        assert_eq!(comps.to_annual().unwrap().cdata[0].values, vec![8760.0]);
+        assert!(monthly.aggregate(Timestep::HORARIO).is_err());
+        // Varias anualidades
+        let comps = format!(
+            "#META CTE_ANUALIDADES: 8760, 8760\nELECTRICIDAD, CONSUMO, EPB, CAL, {}, {}",
+            hourly, hourly
+        )
+        .parse::<Components>()
+        .unwrap();
+        assert!(comps.timestep().unwrap().is_none());
+        let mut comps = comps;
+        comps.set_meta("CTE_PASO", "HORARIO");
+        let monthly = comps.to_monthly().unwrap();
+        assert_eq!(monthly.cdata[0].values.len(), 24);
+        assert_eq!(
+            monthly.get_meta("CTE_ANUALIDADES"),
+            Some("12, 12".to_string())
+        );
+        // Número de valores incoherente con el paso declarado
+        assert!(
+            "#META CTE_PASO: HORARIO\nELECTRICIDAD, CONSUMO, EPB, CAL, 1.0, 2.0"
+                .parse::<Components>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn tcomponents_conversions() {
+        let tcomps = "#META CTE_CONVERSION: 1, 2, 0.9
+1, ELECTRICIDAD, CONSUMO, EPB, CAL, 100.00, 100.00
+2, RED1, CONSUMO, EPB, CAL, 80.00, 120.00"
+            .parse::<Components>()
+            .unwrap();
+        let balance = &tcomps.conversions_balance().unwrap()[0];
+        assert_eq!(balance.input, 200.0);
+        assert_eq!(balance.output, 180.0);
+        assert_eq!(balance.used, 170.0);
+        assert_eq!(balance.losses, 20.0);
+        assert_eq!(balance.unused, 10.0);
+
+        // El flujo intermedio se descuenta del consumo del sistema de destino
+        let tcomps = tcomps.apply_conversions().unwrap();
+        let adjustment = tcomps.cdata.last().unwrap();
+        assert_eq!(adjustment.ctype, CType::AJUSTE);
+        assert_eq!(adjustment.carrier, Carrier::RED1);
+        assert_eq!(adjustment.values, vec![-80.0, -90.0]);
+        assert!(adjustment.is_generated());
+        // Se puede aplicar de nuevo sin duplicar los ajustes
+        assert_eq!(tcomps.clone().apply_conversions().unwrap().cdata.len(), 3);
+        assert_eq!(tcomps.conversions_balance().unwrap()[0], *balance);
+
+        // Conversiones duplicadas
+        let mut tcomps = tcomps;
+        tcomps.cmeta.push(Meta::new("CTE_CONVERSION", "1, 2, 0.8"));
+        assert!(tcomps.conversions().is_err());
+    }
+
+    #[test]
+    fn tcomponents_needs() {
+        let tcomps = "#META CTE_NECESIDAD: CAL, 300.00, 150.00
+#META CTE_NECESIDAD: ACS, 100.00, 100.00
+#META CTE_RENDIMIENTO: 1, CAL, ELECTRICIDAD, 1.0, 3.0
+#META CTE_RENDIMIENTO: 2, ACS, GASNATURAL, 0.5, 0.8
+#META CTE_RENDIMIENTO: 3, ACS, MEDIOAMBIENTE, 0.5, 1.0
+ELECTRICIDAD, CONSUMO, EPB, ILU, 10.00, 10.00"
+            .parse::<Components>()
+            .unwrap();
+        assert_eq!(tcomps.needs().unwrap().len(), 2);
+        assert_eq!(
+            tcomps.efficiencies().unwrap()[1].to_string(),
+            "2, ACS, GASNATURAL, 0.500, 0.800"
+        );
+        let tcomps = tcomps.apply_needs().unwrap();
+        let generated = tcomps
+            .cdata
+            .iter()
+            .filter(|c| c.is_generated())
+            .map(|c| {
+                (
+                    c.id,
+                    c.carrier.clone(),
+                    c.ctype,
+                    c.service,
+                    c.values.clone(),
+                )
+            })
+            .collect::<Vec<_>>();
+        use CType::*;
+        use Carrier::*;
+        assert_eq!(
+            generated,
+            vec![
+                (1, ELECTRICIDAD, CONSUMO, Service::CAL, vec![100.0, 50.0]),
+                (1, MEDIOAMBIENTE, CONSUMO, Service::CAL, vec![200.0, 100.0]),
+                (
+                    1,
+                    MEDIOAMBIENTE,
+                    PRODUCCION,
+                    Service::CAL,
+                    vec![200.0, 100.0]
+                ),
+                (2, GASNATURAL, CONSUMO, Service::ACS, vec![62.5, 62.5]),
+                (3, MEDIOAMBIENTE, CONSUMO, Service::ACS, vec![50.0, 50.0]),
+                (3, MEDIOAMBIENTE, PRODUCCION, Service::ACS, vec![50.0, 50.0]),
+            ]
+        );
+        // Los componentes generados identifican la necesidad con un atributo
+        assert!(tcomps
+            .cdata
+            .iter()
+            .filter(|c| c.is_generated())
+            .all(|c| c.attr("necesidad") == Some(&c.service.to_string())));
+        // Se puede aplicar de nuevo sin duplicar los componentes y no requiere más equilibrado
+        let tcomps = tcomps.apply_needs().unwrap();
+        assert_eq!(tcomps.cdata.len(), 7);
+        let (normalized, warnings) = tcomps.clone().normalize_with_warnings();
+        assert_eq!(normalized.cdata.len(), 7);
+        assert!(warnings.is_empty());
+
+        // Fracciones que no suman la unidad, necesidades duplicadas o con distinto número de valores
+        let mut wrong = tcomps.clone();
+        wrong
+            .cmeta
+            .push(Meta::new("CTE_RENDIMIENTO", "4, CAL, GASNATURAL, 0.2, 0.9"));
+        assert!(wrong.apply_needs().is_err());
+        let mut wrong = tcomps.clone();
+        wrong
+            .cmeta
+            .push(Meta::new("CTE_NECESIDAD", "CAL, 1.0, 1.0"));
+        assert!(wrong.needs().is_err());
+        let mut wrong = tcomps;
+        wrong.cmeta.retain(|m| m.key != "CTE_NECESIDAD");
+        wrong
+            .cmeta
+            .push(Meta::new("CTE_NECESIDAD", "CAL, 1.0, 1.0, 1.0"));
+        assert!(wrong.apply_needs().is_err());
+        assert!("ACS, GASNATURAL, 0.5, 0.8"
+            .parse::<SystemEfficiency>()
+            .is_err());
+        assert!("1, ACS, GASNATURAL, 1.5, 0.8"
+            .parse::<SystemEfficiency>()
+            .is_err());
+    }
+
     #[test]
     fn tcomponents_normalize() {
         let tcompsnorm = TCOMPS1.parse::<Components>().unwrap().normalize();
         assert_eq!(tcompsnorm.to_string(), TCOMPSRES1);
+        // Los componentes generados se marcan y pueden eliminarse
+        assert_eq!(
+            tcompsnorm.cdata.iter().filter(|c| c.is_generated()).count(),
+            1
+        );
+        assert_eq!(
+            tcompsnorm.strip_generated().to_string(),
+            TCOMPS1
+                .parse::<Components>()
+                .unwrap()
+                .normalize()
+                .to_string()
+                .lines()
+                .filter(|l| !l.contains(GENERATED_TAG))
+                .collect::<Vec<_>>()
+                .join("\n")
+        );
+        // La normalización de componentes con componentes generados no genera otros nuevos
+        let renormalized = TCOMPSRES1.parse::<Components>().unwrap().normalize();
+        assert_eq!(renormalized.to_string(), TCOMPSRES1);
+    }
+
+    #[test]
+    fn tcomponents_normalize_with_warnings() {
+        let (tcompsnorm, warnings) = TCOMPS1
+            .parse::<Components>()
+            .unwrap()
+            .normalize_with_warnings();
+        assert_eq!(tcompsnorm.to_string(), TCOMPSRES1);
+        let codes: Vec<_> = warnings.iter().map(Warning::code).collect();
+        assert_eq!(codes, vec!["W301", "W302"]);
+        assert!(matches!(
+            warnings[0],
+            Warning::ProductionServiceToNdef(Service::CAL)
+        ));
+        assert!(matches!(
+            warnings[1],
+            Warning::GeneratedEnvProduction(Service::CAL)
+        ));
+        // Sin correcciones no hay avisos
+        let (_, warnings) = TCOMPSRES1
+            .parse::<Components>()
+            .unwrap()
+            .normalize_with_warnings();
+        assert!(warnings.is_empty());
+        // Producción de medioambiente generada en orden de servicio y sin generar componentes
+        // por diferencias de redondeo
+        let (_, warnings) = "#META CTE_AREAREF: 1.0
+MEDIOAMBIENTE, CONSUMO, EPB, REF, 10.0
+MEDIOAMBIENTE, CONSUMO, EPB, ACS, 10.0
+MEDIOAMBIENTE, CONSUMO, EPB, CAL, 10.0
+MEDIOAMBIENTE, CONSUMO, EPB, VEN, 10.0
+MEDIOAMBIENTE, PRODUCCION, INSITU, VEN, 9.9999"
+            .parse::<Components>()
+            .unwrap()
+            .normalize_with_warnings();
+        let services: Vec<_> = warnings
+            .iter()
+            .filter_map(|w| match w {
+                Warning::GeneratedEnvProduction(service) => Some(*service),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(services, vec![Service::ACS, Service::CAL, Service::REF]);
+    }
+
+    #[test]
+    fn tcomponents_merge() {
+        let consumption = "#META CTE_AREAREF: 100.0
+#META CTE_ZONACLIMATICA: D3
+#META CTE_SISTEMA: 1, CALDERA, 20.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 9.00, 12.00"
+            .parse::<Components>()
+            .unwrap();
+        let production = "#META CTE_AREAREF: 100.0
+#META CTE_ZONACLIMATICA: E1
+#META CTE_SISTEMA: 1, CALDERA, 20.0
+#META CTE_SISTEMA: 2, PV, 5.0
+#META CTE_LOCALIZACION: PENINSULA
+2, ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 3.00, 4.00"
+            .parse::<Components>()
+            .unwrap();
+        let (merged, warnings) = consumption.clone().merge(production).unwrap();
+        assert_eq!(
+            merged.to_string(),
+            "#META CTE_AREAREF: 100.0
+#META CTE_ZONACLIMATICA: D3
+#META CTE_SISTEMA: 1, CALDERA, 20.0
+#META CTE_SISTEMA: 2, PV, 5.0
+#META CTE_LOCALIZACION: PENINSULA
+ELECTRICIDAD, CONSUMO, EPB, CAL, 9.00, 12.00
+2, ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 3.00, 4.00"
+        );
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code(), "W307");
+        assert_eq!(
+            warnings[0].to_string(),
+            "metadato CTE_ZONACLIMATICA con valores distintos al combinar componentes: se conserva \"D3\" y se descarta \"E1\""
+        );
+        // Distinto número de pasos de cálculo
+        let other = "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00"
+            .parse::<Components>()
+            .unwrap();
+        assert!(consumption.clone().merge(other).is_err());
+        // Distinto paso de cálculo
+        let hourly = "#META CTE_PASO: HORARIO".parse::<Components>().unwrap();
+        let monthly = "#META CTE_PASO: MENSUAL".parse::<Components>().unwrap();
+        assert!(hourly.merge(monthly).is_err());
+        // Mismo id de sistema con distinta declaración
+        let other = "#META CTE_SISTEMA: 1, BDC, 8.0"
+            .parse::<Components>()
+            .unwrap();
+        assert!(consumption.clone().merge(other).is_err());
+    }
+
+    #[test]
+    fn tcomponents_ev_charging() {
+        let tcomps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00
+EV_CHARGING, CONSUMO, EPB, RECARGAVE, 1.00, 2.00";
+        assert!(tcomps.parse::<Components>().is_err());
+        let tcomps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00
+ELECTRICIDAD, CONSUMO, EPB, EV_CHARGING, 1.00, 2.00";
+        // Por defecto, la recarga de vehículos eléctricos no es un servicio EPB
+        let (comps, warnings) = tcomps
+            .parse::<Components>()
+            .unwrap()
+            .normalize_with_warnings();
+        assert_eq!(comps.cdata[1].service, Service::RECARGAVE);
+        assert_eq!(comps.cdata[1].csubtype, CSubtype::NEPB);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].code(), "W306");
+        // Inclusión en los indicadores EPB
+        let (comps, warnings) = format!("#META CTE_RECARGAVE_EPB: sí\n{}", tcomps)
+            .parse::<Components>()
+            .unwrap()
+            .normalize_with_warnings();
+        assert_eq!(comps.cdata[1].csubtype, CSubtype::EPB);
+        assert!(warnings.is_empty());
+        // Valor incorrecto del metadato, con aviso
+        let (comps, warnings) = format!("#META CTE_RECARGAVE_EPB: quizás\n{}", tcomps)
+            .parse::<Components>()
+            .unwrap()
+            .normalize_with_warnings();
+        assert_eq!(comps.cdata[1].csubtype, CSubtype::NEPB);
+        assert_eq!(warnings[0].code(), "W310");
+        assert_eq!(
+            warnings[0].to_string(),
+            "valor incorrecto del metadato CTE_RECARGAVE_EPB: \"quizás\", se usa el valor por defecto"
+        );
+    }
+
+    #[test]
+    fn tcomponents_timesteps() {
+        let tcomps = "#META CTE_AREAREF: 100.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0, 2.0, 3.0
+GASNATURAL, CONSUMO, EPB, ACS, 1.0, 2.0
+ELECTRICIDAD, CONSUMO, EPB, REF, 1.0, 2.0, 3.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 1.0";
+        // El error identifica las líneas con distinto número de valores
+        let err = tcomps.parse::<Components>().unwrap_err();
+        assert_eq!(err.code(), "E301");
+        let msg = err.to_string();
+        assert!(msg.contains("(línea 2, 3 valores)"));
+        assert!(msg.contains("línea 3 (2 valores), línea 5 (1 valores)"));
+        // Opción para completar con ceros los valores
+        let comps = Components::from_str_with_options(
+            tcomps,
+            &ParseOptions {
+                pad_values: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(comps.cdata[1].values, vec![1.0, 2.0, 0.0]);
+        assert_eq!(comps.cdata[3].values, vec![1.0, 0.0, 0.0]);
+        assert!(comps.check_timesteps().is_ok());
+        // Componentes creados sin interpretar un texto
+        let mut comps = comps;
+        comps.cdata[0].values.push(4.0);
+        let msg = comps.check_timesteps().unwrap_err().to_string();
+        assert!(msg.contains("(componente 1, 4 valores)"));
+        assert!(msg.contains("componente 2 (3 valores)"));
+        comps.pad_values();
+        assert!(comps.check_timesteps().is_ok());
+        // Paso de cálculo declarado
+        comps.set_meta("CTE_PASO", "MENSUAL");
+        assert!(comps.check_timesteps().is_err());
+    }
+
+    #[test]
+    fn tcomponents_negative_values() {
+        let tcomps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0, -2.0
+ELECTRICIDAD, AJUSTE, EPB, CAL, -1.0, 3.0 # Corrección de lectura
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, -1.0, -1.0";
+        let options = |negative_values| ParseOptions {
+            negative_values,
+            ..Default::default()
+        };
+        // Por defecto se admiten y, si se rechazan, se indica la línea
+        assert!(tcomps.parse::<Components>().is_ok());
+        let err = Components::from_str_with_options(tcomps, &options(NegativeValues::Reject))
+            .unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("valores negativos (línea 1, 1 valores)"));
+        // Sustitución por cero
+        let comps =
+            Components::from_str_with_options(tcomps, &options(NegativeValues::Clamp)).unwrap();
+        assert_eq!(comps.cdata[0].values, vec![1.0, 0.0]);
+        assert_eq!(comps.cdata[1].values, vec![-1.0, 3.0]);
+        assert_eq!(comps.cdata[2].values, vec![0.0, 0.0]);
+        // Admisión y tratamiento posterior, con avisos
+        let mut comps =
+            Components::from_str_with_options(tcomps, &options(NegativeValues::Allow)).unwrap();
+        assert_eq!(comps.cdata[0].values, vec![1.0, -2.0]);
+        assert!(comps
+            .clone()
+            .apply_negative_values(NegativeValues::Reject)
+            .is_err());
+        let warnings = comps.apply_negative_values(NegativeValues::Clamp).unwrap();
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[1].code(), "W308");
+        assert_eq!(
+            warnings[1].to_string(),
+            "2 valores negativos sustituidos por cero en el componente ELECTRICIDAD, PRODUCCION, INSITU, NDEF"
+        );
+        // Entrada JSON
+        let json = serde_json::to_string(&comps).unwrap();
+        comps.cdata[0].values = vec![1.0, -2.0];
+        let json_negative = serde_json::to_string(&comps).unwrap();
+        assert!(Components::from_json(&json_negative).is_ok());
+        assert!(Components::from_json_with_options(
+            &json_negative,
+            &options(NegativeValues::Reject)
+        )
+        .is_err());
+        let clamped =
+            Components::from_json_with_options(&json_negative, &options(NegativeValues::Clamp))
+                .unwrap();
+        assert_eq!(clamped.cdata[0].values, vec![1.0, 0.0]);
+        assert!(
+            Components::from_json_with_options(&json, &options(NegativeValues::Reject)).is_ok()
+        );
+        // Entrada YAML
+        #[cfg(feature = "yaml")]
+        {
+            let yaml_negative = comps.to_yaml().unwrap();
+            assert!(Components::from_yaml(&yaml_negative).is_ok());
+            assert!(Components::from_yaml_with_options(
+                &yaml_negative,
+                &options(NegativeValues::Reject)
+            )
+            .is_err());
+            let clamped =
+                Components::from_yaml_with_options(&yaml_negative, &options(NegativeValues::Clamp))
+                    .unwrap();
+            assert_eq!(clamped.cdata[0].values, vec![1.0, 0.0]);
+        }
+        // Validación
+        let diagnostics = validate_components(tcomps, &options(NegativeValues::Reject));
+        let lines: Vec<_> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![Some(1), Some(3)]);
+        assert!(validate_components(tcomps, &ParseOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn tcomponents_canonical_string() {
+        let tcomps = "#META CTE_AREAREF: 100.0
+#META CTE_AREAREF_EDIFICIO: 120.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 1.25, 2.5
+GASNATURAL, CONSUMO, EPB, CAL, 1.004, 2.006 # Caldera
+ELECTRICIDAD, CONSUMO, EPB, CAL, 3.1234, 4.5678"
+            .parse::<Components>()
+            .unwrap();
+        let canonical = tcomps.to_canonical_string(3);
+        assert_eq!(
+            canonical,
+            "#META CTE_AREAREF: 100.0
+#META CTE_AREAREF_EDIFICIO: 120.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 1.250, 2.500
+ELECTRICIDAD, CONSUMO, EPB, CAL, 3.123, 4.568
+GASNATURAL, CONSUMO, EPB, CAL, 1.004, 2.006 # Caldera"
+        );
+        // Representación estable al volver a interpretarla
+        let reparsed = canonical.parse::<Components>().unwrap();
+        assert_eq!(reparsed.to_canonical_string(3), canonical);
+        // Independiente del orden de los datos
+        let mut reversed = tcomps.clone();
+        reversed.cdata.reverse();
+        reversed.cmeta.reverse();
+        assert_eq!(reversed.to_canonical_string(3), canonical);
+        // La precisión por defecto no cambia
+        assert!(tcomps.to_string().contains("3.12, 4.57"));
     }
 
     #[test]
@@ -391,7 +3701,44 @@ ELECTRICIDAD, PRODUCCION, INSITU, CAL, 1.00, 2.00, 1.00 #  Producción eléctric
             .unwrap()
             .normalize()
             .filter_by_epb_service(Service::CAL);
-        assert_eq!(tcompsnormfilt.to_string(), TCOMPSRES2);
+        // Con doble precisión cambia el redondeo de algunos valores en el límite (1.845 y 1.435)
+        let expected = if cfg!(feature = "f64") {
+            TCOMPSRES2
+                .replace("1.85, 1.02", "1.84, 1.02")
+                .replace("1.43, 1.02", "1.44, 1.02")
+        } else {
+            TCOMPSRES2.to_string()
+        };
+        assert_eq!(tcompsnormfilt.to_string(), expected);
+    }
+
+    #[test]
+    fn tcomponents_filter_by_epb_service_priority() {
+        let mut tcomps = TCOMPS1.parse::<Components>().unwrap();
+        tcomps.set_service_priority(&[Service::CAL]);
+        let (tcomps, warnings) = tcomps.normalize_with_warnings();
+        // Sin compensación servicio a servicio la prioridad no se aplica al balance
+        assert!(warnings.iter().any(|w| w.code() == "W309"));
+        // La producción eléctrica cubre primero el consumo de CAL
+        let produced = |components: &Components| -> Vec<Component> {
+            components
+                .cdata
+                .iter()
+                .filter(|c| c.carrier == Carrier::ELECTRICIDAD && c.ctype == CType::PRODUCCION)
+                .cloned()
+                .collect()
+        };
+        let cal = produced(&tcomps.filter_by_epb_service(Service::CAL));
+        assert_eq!(cal[0].values, tcomps.cdata[0].values);
+        assert!(cal[0].comment.ends_with("(reparto: prioridad CAL)"));
+        let refri = produced(&tcomps.filter_by_epb_service(Service::REF));
+        assert!(refri[0].values.iter().all(|v| *v == 0.0));
+        // Con compensación servicio a servicio no hay aviso
+        let mut tcomps = TCOMPS1.parse::<Components>().unwrap();
+        tcomps.set_service_priority(&[Service::CAL]);
+        tcomps.set_meta("CTE_COMPENSACION_SERVICIOS", "true");
+        let (_, warnings) = tcomps.normalize_with_warnings();
+        assert!(!warnings.iter().any(|w| w.code() == "W309"));
     }
 
     #[test]
@@ -30,38 +30,81 @@ Utilidades para el cumplimiento reglamentario (compliance utilities)
 Utilidades para el manejo de balances energéticos para el CTE:
 
 - valores reglamentarios
+- comprobación de los valores límite de la sección HE0 (módulo `he0`)
+- comprobación de la contribución renovable mínima para ACS de la sección HE4 (módulo `he4`)
 - generación y transformación de factores de paso
+    - locwf_from_version
+    - locwf_from_components
     - wfactors_from_str
     - wfactors_from_loc
+    - wfactors_from_loc_zone
+    - wfactors_set_zone
+    - location_from_components
+    - wfactors_from_components
+    - wfactors_set_districts_from_components
     - wfactors_to_nearby
+    - co2_catalog_from_loc
+    - wfactors_set_co2_from_loc
+    - wfactors_set_fuel_cell
+- cálculo con parámetros de los metadatos
+    - energy_performance_from_meta
+    - nearby_balances_by_service
+- indicadores adicionales
+    - incorpora_demanda_renovable_acs_nrb
+    - incorpora_factura_estimada
+- desagregación por viviendas de bloques residenciales
+    - dwelling_shares
+    - indicators_by_dwelling
+    - components_for_dwelling
+- comparación de escenarios
+    - scenarios_comparison
+    - scenarios_to_csv
+- comparación con el edificio de referencia
+    - reference_comparison
+    - reference_comparison_to_plain
 - salida/visualización de balances
     - balance_to_plain
+    - balance_by_year_to_plain
+    - balance_by_kexp_to_plain
+    - balance_to_csv
     - balance_to_XML
+    - balance_to_xml_registro
 */
 
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::str;
 
 use crate::{
+    energy_bill,
     error::EpbdError,
+    i18n::{tr, tr_format},
     types::*,
-    vecops::{vecvecmin, vecvecsum},
-    Balance, Components, Factors, UserWF,
+    vecops::vecitersum,
+    Balance, CarrierSummary, Components, ConversionBalance, Factors, MiscValue, ParseOptions,
+    SelfConsumption, SystemUtilization, Tariffs, UserWF,
 };
 
-/**
+pub mod he0;
+pub mod he4;
+
+/*
 Constantes y valores generales
 */
 
 /// Valor por defecto del área de referencia.
-pub const AREAREF_DEFAULT: f32 = 1.0;
+pub const AREAREF_DEFAULT: Real = 1.0;
 /// Valor predefinido del factor de exportación. Valor reglamentario.
-pub const KEXP_DEFAULT: f32 = 0.0;
+pub const KEXP_DEFAULT: Real = 0.0;
 /// Localizaciones válidas para CTE
 pub const CTE_LOCS: [&str; 4] = ["PENINSULA", "BALEARES", "CANARIAS", "CEUTAMELILLA"];
+/// Espacio de nombres del formato XML para plataformas de registro de certificados
+pub const CTE_REGISTRO_XMLNS: &str = "urn:cteepbd:registro:1.0";
 
 // Valores bien conocidos de metadatos:
 // CTE_LOCALIZACION -> str
+// CTE_FP_VERSION -> str
 
 /// Vectores considerados dentro del perímetro NEARBY (a excepción de la ELECTRICIDAD in situ).
 pub const CTE_NRBY: [Carrier; 5] = [
@@ -72,10 +115,19 @@ pub const CTE_NRBY: [Carrier; 5] = [
     Carrier::MEDIOAMBIENTE,
 ]; // Ver B.23. Solo biomasa sólida
 
+/// Indica si el vector está dentro del perímetro NEARBY
+///
+/// Incluye los vectores de `CTE_NRBY`, todas las redes de distrito (RED1, RED2, RED3, ...)
+/// y las redes alimentadas con residuos (RESIDUOS).
+pub fn is_nearby_carrier(carrier: Carrier) -> bool {
+    CTE_NRBY.contains(&carrier) || carrier == Carrier::RESIDUOS || carrier.is_district()
+}
+
 /// Factores de paso definibles por el usuario usados por defecto
 pub const CTE_USERWF: UserWF<RenNrenCo2> = UserWF {
     red1: RenNrenCo2::new(0.0, 1.3, 0.3),
     red2: RenNrenCo2::new(0.0, 1.3, 0.3),
+    residuos: RenNrenCo2::new(0.0, 1.3, 0.3),
     cogen_to_grid: RenNrenCo2::new(0.0, 2.5, 0.3),
     cogen_to_nepb: RenNrenCo2::new(0.0, 2.5, 0.3),
 };
@@ -109,7 +161,9 @@ pub static CTE_LOCWF_RITE2014: Lazy<HashMap<&'static str, Factors>> = Lazy::new(
             Factor::new(ELECTRICIDAD, INSITU, SUMINISTRO, A, (1.000, 0.000, 0.000).into(), "Recursos usados para producir electricidad in situ"),
             Factor::new(ELECTRICIDAD, COGENERACION, SUMINISTRO, A, (0.000, 0.000, 0.000).into(), "Recursos usados para suministrar la energía (0 porque se contabiliza el vector que alimenta el cogenerador)"),
             // Factor::new(ELECTRICIDAD, RED, SUMINISTRO, A, (ren, nren, co2), "Recursos usados para el suministro desde la red")
-        ]};
+        ],
+        wseries: vec![],
+    };
     let mut wfpen = wf.clone();
     wfpen.set_meta("CTE_LOCALIZACION", "PENINSULA");
     wfpen.wdata.push(Factor::new(
@@ -163,7 +217,53 @@ pub static CTE_LOCWF_RITE2014: Lazy<HashMap<&'static str, Factors>> = Lazy::new(
     m
 });
 
-/**
+/// Versiones disponibles de los factores de paso reglamentarios
+///
+/// La versión se identifica con el valor del metadato `CTE_FUENTE` de los factores. Por ahora
+/// solo se dispone de los factores del documento reconocido del RITE.
+pub const CTE_FP_VERSIONS: [&str; 1] = ["RITE2014"];
+/// Versión predefinida de los factores de paso reglamentarios
+pub const CTE_FP_VERSION_DEFAULT: &str = "RITE2014";
+
+/// Factores de paso reglamentarios, por localización, de una versión
+///
+/// Permite reproducir los resultados con los factores vigentes en la fecha del proyecto.
+/// Ver `CTE_FP_VERSIONS`.
+///
+/// # Errors
+///
+/// * Versión de factores de paso desconocida
+pub fn locwf_from_version(
+    version: &str,
+) -> Result<&'static HashMap<&'static str, Factors>, EpbdError> {
+    match version.trim().to_uppercase().as_str() {
+        "RITE2014" => Ok(&CTE_LOCWF_RITE2014),
+        _ => Err(EpbdError::WrongInput(format!(
+            "Versión de factores de paso desconocida: {} (disponibles: {})",
+            version,
+            CTE_FP_VERSIONS.join(", ")
+        ))),
+    }
+}
+
+/// Factores de paso reglamentarios, por localización, de la versión definida en los componentes
+///
+/// Se usa la versión del metadato `CTE_FP_VERSION` o, en su defecto, `CTE_FP_VERSION_DEFAULT`.
+///
+/// # Errors
+///
+/// * Versión de factores de paso desconocida
+pub fn locwf_from_components(
+    components: &Components,
+) -> Result<&'static HashMap<&'static str, Factors>, EpbdError> {
+    locwf_from_version(
+        components
+            .get_str("CTE_FP_VERSION")?
+            .unwrap_or(CTE_FP_VERSION_DEFAULT),
+    )
+}
+
+/*
 Manejo de factores de paso para el CTE
 --------------------------------------
 
@@ -200,36 +300,367 @@ pub fn wfactors_from_loc(
         .normalize(&userdefaults)
 }
 
+/// Genera factores de paso a partir de localización y zona climática.
+///
+/// Igual que `wfactors_from_loc`, pero aplica a los factores de la localización las correcciones
+/// de la zona climática (ver `wfactors_set_zone`), que se guarda en el metadato `CTE_ZONACLIMATICA`.
+///
+/// # Errors
+///
+/// * Localización desconocida
+/// * Zona climática incorrecta o incoherente con la localización
+pub fn wfactors_from_loc_zone(
+    loc: &str,
+    zone: &str,
+    locmap: &HashMap<&'static str, Factors>,
+    user: UserWF<Option<RenNrenCo2>>,
+    userdefaults: UserWF<RenNrenCo2>,
+) -> Result<Factors, EpbdError> {
+    let wfactors = locmap
+        .get(loc)
+        .ok_or_else(|| EpbdError::ParseError(format!("Localizacion: {}", loc)))?
+        .clone();
+    wfactors_set_zone(wfactors, zone)?
+        .set_user_wfactors(user)
+        .normalize(&userdefaults)
+}
+
+/// Aplica a los factores de paso de una localización una zona climática
+///
+/// Comprueba que la zona climática es correcta y coherente con la localización de los factores
+/// (metadato `CTE_LOCALIZACION`) y guarda la zona en el metadato `CTE_ZONACLIMATICA`.
+/// Los factores de paso reglamentarios (`RITE2014`) no publican factores distintos por zona
+/// climática, por lo que los factores de la localización no se modifican.
+///
+/// # Errors
+///
+/// * Zona climática incorrecta o incoherente con la localización
+pub fn wfactors_set_zone(mut wfactors: Factors, zone: &str) -> Result<Factors, EpbdError> {
+    let zone = zone.trim();
+    if !is_climate_zone(zone) {
+        return Err(EpbdError::ParseError(format!("Zona climática: {}", zone)));
+    }
+    if let Some(loc) = wfactors.get_meta("CTE_LOCALIZACION") {
+        if !is_consistent_loc_zone(&loc, zone) {
+            return Err(EpbdError::WrongInput(format!(
+                "La localización {} no es coherente con la zona climática {}",
+                loc, zone
+            )));
+        }
+    }
+    wfactors.set_meta("CTE_ZONACLIMATICA", zone);
+    Ok(wfactors)
+}
+
+/// Indica si la zona climática tiene un formato correcto
+///
+/// La zona climática se compone de la zona de invierno (`α`, `A`, `B`, `C`, `D` o `E`), la zona
+/// de verano (`1` a `4`) y, en Canarias, el sufijo `c` (p.e. `D3`, `α3`, `A2c`).
+pub fn is_climate_zone(zone: &str) -> bool {
+    let zone = zone.trim();
+    let summer = zone
+        .strip_prefix('α')
+        .or_else(|| zone.strip_prefix("alfa"))
+        .or_else(|| zone.strip_prefix(&['A', 'B', 'C', 'D', 'E'][..]));
+    matches!(
+        summer.map(|s| s.strip_suffix('c').unwrap_or(s)),
+        Some("1" | "2" | "3" | "4")
+    )
+}
+
+/// Indica si la localización es coherente con la zona climática
+///
+/// Las zonas de Canarias solo corresponden a la localización CANARIAS y el resto de zonas a las
+/// demás localizaciones.
+fn is_consistent_loc_zone(loc: &str, zone: &str) -> bool {
+    match location_from_climate_zone(zone) {
+        Some(zone_loc) => zone_loc == loc,
+        None => loc != "CANARIAS",
+    }
+}
+
+/// Localización correspondiente a una zona climática, si es posible determinarla
+///
+/// Solo las zonas climáticas de Canarias (zonas alfa, p.e. `α3`, y zonas con el sufijo `c`,
+/// p.e. `A2c`) permiten identificar la localización. El resto de zonas son comunes a la
+/// península, Baleares y Ceuta y Melilla, por lo que se devuelve `None`.
+pub fn location_from_climate_zone(zone: &str) -> Option<&'static str> {
+    let zone = zone.trim();
+    let is_canarias_zone = zone.starts_with('α')
+        || zone.to_lowercase().starts_with("alfa")
+        || (zone.len() == 3 && zone.ends_with('c'));
+    if is_canarias_zone {
+        Some("CANARIAS")
+    } else {
+        None
+    }
+}
+
+/// Localización definida en los metadatos de los componentes
+///
+/// Se obtiene del metadato `CTE_LOCALIZACION` o, en su defecto, de la zona climática
+/// (metadato `CTE_ZONACLIMATICA`) cuando esta permite determinarla (ver
+/// `location_from_climate_zone`). Devuelve `None` si no es posible determinar la localización.
+///
+/// # Errors
+///
+/// * Localización desconocida o incoherente con la zona climática
+pub fn location_from_components(
+    components: &Components,
+) -> Result<Option<&'static str>, EpbdError> {
+    let zone_loc = components
+        .get_meta("CTE_ZONACLIMATICA")
+        .and_then(|zone| location_from_climate_zone(&zone));
+    match components.get_meta("CTE_LOCALIZACION") {
+        Some(loc) => {
+            let loc = CTE_LOCS
+                .iter()
+                .find(|l| **l == loc.trim().to_uppercase())
+                .ok_or_else(|| EpbdError::ParseError(format!("Localizacion: {}", loc)))?;
+            let consistent = match components.get_meta("CTE_ZONACLIMATICA") {
+                Some(zone) => is_consistent_loc_zone(loc, &zone),
+                None => true,
+            };
+            if !consistent {
+                return Err(EpbdError::WrongInput(format!(
+                    "La localización {} no es coherente con la zona climática {}",
+                    loc,
+                    components.get_meta("CTE_ZONACLIMATICA").unwrap_or_default()
+                )));
+            }
+            Ok(Some(loc))
+        }
+        None => Ok(zone_loc),
+    }
+}
+
+/// Genera factores de paso a partir de la localización definida en los metadatos de los componentes
+///
+/// Si los componentes declaran la zona climática (metadato `CTE_ZONACLIMATICA`) se aplican sus
+/// correcciones. Ver `location_from_components`, `wfactors_from_loc` y `wfactors_from_loc_zone`.
+///
+/// # Errors
+///
+/// * No se puede determinar la localización o es incorrecta
+pub fn wfactors_from_components(
+    components: &Components,
+    locmap: &HashMap<&'static str, Factors>,
+    user: UserWF<Option<RenNrenCo2>>,
+    userdefaults: UserWF<RenNrenCo2>,
+) -> Result<Factors, EpbdError> {
+    let loc = location_from_components(components)?.ok_or_else(|| {
+        EpbdError::WrongInput(
+            "No se puede determinar la localización a partir de los metadatos (CTE_LOCALIZACION o CTE_ZONACLIMATICA)".into(),
+        )
+    })?;
+    match components.get_meta("CTE_ZONACLIMATICA") {
+        Some(zone) => wfactors_from_loc_zone(loc, &zone, locmap, user, userdefaults),
+        None => wfactors_from_loc(loc, locmap, user, userdefaults),
+    }
+}
+
+/// Incorpora los factores de suministro de las redes de distrito adicionales (RED3, RED4, ...)
+///
+/// Se consideran las redes de distrito adicionales usadas en los componentes o con factores
+/// definidos en el metadato `CTE_REDn` de los componentes (p.e. `CTE_RED3: 0.0, 1.3, 0.3`).
+/// Los factores de los metadatos sustituyen a los existentes y, si no hay factores de suministro
+/// para la red, se usan los factores por defecto de las redes de distrito (ver
+/// `Factors::ensure_district_supply`, que también aplica `Factors::normalize`).
+/// Para las redes de distrito con producción de calor in situ en los componentes se asegura
+/// también el factor de esa producción (ver `Factors::ensure_district_onsite_heat`).
+/// Debe aplicarse antes de normalizar los factores de paso para completar los factores de
+/// exportación de calor a estas redes.
+///
+/// # Errors
+///
+/// * Factores de paso de los metadatos con formato incorrecto
+pub fn wfactors_set_districts_from_components(
+    mut wfactors: Factors,
+    components: &Components,
+    userdefaults: &UserWF<RenNrenCo2>,
+) -> Result<Factors, EpbdError> {
+    let meta_districts = components.cmeta.iter().filter_map(|m| {
+        m.key
+            .strip_prefix("CTE_RED")
+            .filter(|n| !n.starts_with('0'))
+            .and_then(|n| n.parse::<u8>().ok())
+            .and_then(DistrictNumber::new)
+            .map(Carrier::REDN)
+    });
+    let mut districts: Vec<Carrier> = components
+        .cdata
+        .iter()
+        .map(|c| c.carrier.clone())
+        .filter(|c| matches!(c, Carrier::REDN(_)))
+        .chain(meta_districts)
+        .collect();
+    districts.sort();
+    districts.dedup();
+
+    for carrier in districts {
+        let key = format!("CTE_{}", carrier);
+        match components.get_rennren(&key)? {
+            Some(value) => wfactors.update_wfactor(
+                carrier,
+                Source::RED,
+                Dest::SUMINISTRO,
+                Step::A,
+                value,
+                "Factor de usuario",
+            ),
+            None => wfactors.ensure_district_supply(&carrier, userdefaults),
+        }
+    }
+    for c in &components.cdata {
+        if c.carrier.is_district() && c.csubtype == CSubtype::INSITU {
+            wfactors.ensure_district_onsite_heat(&c.carrier);
+        }
+    }
+    Ok(wfactors)
+}
+
 /// Convierte factores de paso con perímetro "distant" a factores de paso "nearby".
 ///
 /// Los elementos que tiene origen en la RED (!= INSITU, != COGENERACION)
 /// y no están en la lista CTE_NRBY cambian sus factores de paso
 /// de forma que ren' = 0 y nren' = ren + nren.
 /// **ATENCIÓN**: ¡¡La producción eléctrica de la cogeneración entra con (factores ren:0, nren:0)!!
-pub fn wfactors_to_nearby(wfactors: &Factors) -> Factors {
-    let wmeta = wfactors.wmeta.clone();
-    let mut wdata: Vec<Factor> = Vec::new();
-
-    for f in wfactors.wdata.iter().cloned() {
-        if f.source == Source::INSITU
-            || f.source == Source::COGENERACION
-            || CTE_NRBY.contains(&f.carrier)
-        {
-            wdata.push(f)
-        } else {
-            wdata.push(Factor::new(
-                f.carrier,
-                f.source,
-                f.dest,
-                f.step,
-                RenNrenCo2::new(0.0, f.ren + f.nren, f.co2), // ¿Esto es lo que tiene más sentido?
-                format!("Perímetro nearby: {}", f.comment),
-            ))
+///
+/// Ver `Factors::to_perimeter`. Los factores que ya están en el perímetro próximo no se modifican.
+///
+/// # Errors
+///
+/// * Perímetro desconocido o distinto del perímetro distante o próximo
+pub fn wfactors_to_nearby(wfactors: &Factors) -> Result<Factors, EpbdError> {
+    wfactors.to_perimeter(Perimeter::NEARBY)
+}
+
+/// Catálogo de factores de emisión de CO2 (kg_CO2e/kWh_f) del suministro desde red, por vector.
+///
+/// Obtiene los factores de emisión reglamentarios de la localización indicada
+/// (PENINSULA, CANARIAS, BALEARES, CEUTAMELILLA) a partir del catálogo `locmap`
+/// (p.e. CTE_LOCWF_RITE2014). Los vectores se devuelven ordenados.
+pub fn co2_catalog_from_loc(
+    loc: &str,
+    locmap: &HashMap<&'static str, Factors>,
+) -> Result<Vec<(Carrier, Real)>, EpbdError> {
+    let mut catalog: Vec<(Carrier, Real)> = locmap
+        .get(loc)
+        .ok_or_else(|| EpbdError::ParseError(format!("Localizacion: {}", loc)))?
+        .wdata
+        .iter()
+        .filter(|f| f.source == Source::RED && f.dest == Dest::SUMINISTRO && f.step == Step::A)
+        .map(|f| (f.carrier.clone(), f.co2))
+        .collect();
+    catalog.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(catalog)
+}
+
+/// Sustituye los factores de emisión de CO2 del suministro desde red por los reglamentarios de la localización.
+///
+/// Permite usar factores de paso de energía primaria propios con las emisiones reglamentarias.
+/// Los vectores que no figuran en el catálogo mantienen su factor de emisión.
+pub fn wfactors_set_co2_from_loc(
+    mut wfactors: Factors,
+    loc: &str,
+    locmap: &HashMap<&'static str, Factors>,
+) -> Result<Factors, EpbdError> {
+    let catalog = co2_catalog_from_loc(loc, locmap)?;
+    let fuente = locmap[loc]
+        .get_meta("CTE_FUENTE")
+        .unwrap_or_else(|| "-".to_string());
+    for f in wfactors
+        .wdata
+        .iter_mut()
+        .filter(|f| f.source == Source::RED && f.dest == Dest::SUMINISTRO && f.step == Step::A)
+    {
+        if let Some((_, co2)) = catalog.iter().find(|(c, _)| *c == f.carrier) {
+            f.co2 = *co2;
         }
     }
-    let mut factors = Factors { wmeta, wdata };
-    factors.set_meta("CTE_PERIMETRO", "NEARBY");
-    factors
+    wfactors.set_meta("CTE_FUENTE_CO2", &format!("{}, {}", fuente, loc));
+    Ok(wfactors)
+}
+
+/// Factores de paso de la electricidad producida por una pila de combustible.
+///
+/// Se reparten los recursos usados para suministrar el combustible (`fuel`, p.e. HIDROGENO o GASNATURAL)
+/// entre la electricidad y el calor producidos en proporción a la energía obtenida, de modo que el factor
+/// de la electricidad es el del combustible dividido por la suma de los rendimientos eléctrico (`eff_el`)
+/// y térmico (`eff_th`) de la pila.
+pub fn fuel_cell_cogen_factors(
+    wfactors: &Factors,
+    fuel: Carrier,
+    eff_el: Real,
+    eff_th: Real,
+) -> Result<RenNrenCo2, EpbdError> {
+    if eff_el <= 0.0 || eff_th < 0.0 || eff_el + eff_th > 1.0 {
+        return Err(EpbdError::WrongInput(format!(
+            "Rendimientos de la pila de combustible incorrectos (eléctrico: {:.2}, térmico: {:.2})",
+            eff_el, eff_th
+        )));
+    }
+    let fuel_factor = wfactors
+        .wdata
+        .iter()
+        .find(|f| {
+            f.carrier == fuel
+                && f.source == Source::RED
+                && f.dest == Dest::SUMINISTRO
+                && f.step == Step::A
+        })
+        .ok_or(EpbdError::MissingFactor {
+            carrier: fuel,
+            source: Source::RED,
+            dest: Dest::SUMINISTRO,
+            step: Step::A,
+        })?;
+    Ok(fuel_factor.factors() * (1.0 / (eff_el + eff_th)))
+}
+
+/// Establece los factores de exportación de la electricidad cogenerada por una pila de combustible.
+///
+/// Usa el metadato `CTE_PILA_COMBUSTIBLE` de los componentes (vector combustible, rendimiento eléctrico
+/// y rendimiento térmico, p.e. "HIDROGENO, 0.50, 0.35") para obtener los factores de paso
+/// de la electricidad cogenerada exportada a la red y a usos no EPB.
+/// Si no existe el metadato no se modifican los factores de paso.
+pub fn wfactors_set_fuel_cell(
+    mut wfactors: Factors,
+    components: &Components,
+) -> Result<Factors, EpbdError> {
+    let meta = match components.get_meta("CTE_PILA_COMBUSTIBLE") {
+        Some(meta) => meta,
+        None => return Ok(wfactors),
+    };
+    let wrong = || {
+        EpbdError::ParseError(format!(
+            "Metadato CTE_PILA_COMBUSTIBLE incorrecto: \"{}\"",
+            meta
+        ))
+    };
+    let items: Vec<&str> = meta.split(',').map(str::trim).collect();
+    if items.len() != 3 {
+        return Err(wrong());
+    }
+    let fuel: Carrier = items[0].parse().map_err(|_| wrong())?;
+    let eff_el: Real = items[1].parse().map_err(|_| wrong())?;
+    let eff_th: Real = items[2].parse().map_err(|_| wrong())?;
+    let values = fuel_cell_cogen_factors(&wfactors, fuel.clone(), eff_el, eff_th)?;
+    let comment = format!(
+        "Factor de pila de combustible ({}, rendimientos {:.2}, {:.2})",
+        fuel, eff_el, eff_th
+    );
+    for dest in &[Dest::A_RED, Dest::A_NEPB] {
+        wfactors.update_wfactor(
+            Carrier::ELECTRICIDAD,
+            Source::COGENERACION,
+            *dest,
+            Step::A,
+            values,
+            &comment,
+        );
+    }
+    Ok(wfactors)
 }
 
 /*
@@ -240,7 +671,7 @@ Porcentaje renovable de la demanda de ACS en el perímetro próximo
 // Funciones auxiliares ----------
 
 /// Cálculo de la fracción que supone el factor de paso a energía primaria renovable respecto a la energía primaria total
-fn get_fp_ren_fraction(c: Carrier, wfactors: &Factors) -> Result<f32, EpbdError> {
+fn get_fp_ren_fraction(c: Carrier, wfactors: &Factors) -> Result<Real, EpbdError> {
     // El origen es la red, salvo para la electricidad producida in situ
     let src = match c {
         Carrier::ELECTRICIDAD => Source::INSITU,
@@ -250,35 +681,38 @@ fn get_fp_ren_fraction(c: Carrier, wfactors: &Factors) -> Result<f32, EpbdError>
         .wdata
         .iter()
         .find(|f| f.carrier == c && f.source == src)
-        .ok_or_else(|| {
-            EpbdError::WrongInput(format!("No se encuentra el factor de paso para \"{}\"", c))
+        .ok_or(EpbdError::MissingFactor {
+            carrier: c,
+            source: src,
+            dest: Dest::SUMINISTRO,
+            step: Step::A,
         })
-        .and_then(|f| Ok(f.ren / (f.ren + f.nren)))
+        .map(|f| f.ren / (f.ren + f.nren))
 }
 
 #[allow(non_snake_case)]
-/// Demanda total y renovable de los consumos de ACS de RED1, RED2 o MEDIOAMBIENTE
+/// Demanda total y renovable de los consumos de ACS de RED1, RED2, RESIDUOS o MEDIOAMBIENTE
 ///
 /// Podemos obtener la parte renovable, con la fracción que supone su factor de paso ren respecto al total y
 /// suponiendo que la conversión de consumo a demanda es con rendimiento 1.0 (de modo que demanda = consumo para estos vectores)
 fn Q_district_and_env_an(
     cr_list: &Vec<&Component>,
     wfactors: &Factors,
-) -> Result<(f32, f32), EpbdError> {
-    use Carrier::{MEDIOAMBIENTE, RED1, RED2};
+) -> Result<(Real, Real), EpbdError> {
+    use Carrier::{MEDIOAMBIENTE, RESIDUOS};
 
     let value = cr_list
         .iter()
         .filter(|c| {
-            c.ctype == CType::CONSUMO
-                && (c.carrier == RED1 || c.carrier == RED2 || c.carrier == MEDIOAMBIENTE)
+            c.is_used_energy()
+                && (c.carrier.is_district() || [RESIDUOS, MEDIOAMBIENTE].contains(&c.carrier))
         })
         .map(|c| {
-            let tot = c.values.iter().sum::<f32>();
-            let ren = tot * get_fp_ren_fraction(c.carrier, wfactors)?;
+            let tot = c.total();
+            let ren = tot * get_fp_ren_fraction(c.carrier.clone(), wfactors)?;
             Ok((tot, ren))
         })
-        .collect::<Result<Vec<(f32, f32)>, EpbdError>>()?
+        .collect::<Result<Vec<(Real, Real)>, EpbdError>>()?
         .iter()
         .fold((0.0, 0.0), |(ac_tot, ac_ren), &(elem_tot, elem_ren)| {
             (ac_tot + elem_tot, ac_ren + elem_ren)
@@ -290,14 +724,99 @@ fn Q_district_and_env_an(
 fn get_used_carriers(cr_list: &Vec<&Component>) -> Vec<Carrier> {
     let mut used_carriers = cr_list
         .iter()
-        .filter(|c| c.ctype == CType::CONSUMO)
-        .map(|c| c.carrier)
+        .filter(|c| c.is_used_energy())
+        .map(|c| c.carrier.clone())
         .collect::<Vec<_>>();
     used_carriers.sort_unstable();
     used_carriers.dedup();
     used_carriers
 }
 
+/// Calcula la eficiencia energética obteniendo el factor de exportación y el área de referencia de los metadatos
+///
+/// Los valores se obtienen, por orden de prioridad, del argumento, si se indica, de los metadatos de
+/// los componentes (`CTE_KEXP` y `CTE_AREAREF`) o de los valores predefinidos (`KEXP_DEFAULT` y
+/// `AREAREF_DEFAULT`). El factor de exportación para la energía exportada a usos no EPB se obtiene
+/// del metadato `CTE_KEXP_NEPB` o, si no se define, es igual a `k_exp`.
+///
+/// El origen de los valores usados (`usuario`, `metadatos` o `predefinido`) se registra en los
+/// indicadores adicionales del balance (`origen_kexp` y `origen_arearef`).
+///
+/// # Errors
+///
+/// * Metadatos con valores incorrectos o fuera de rango
+/// * Errores del cálculo del balance (ver `energy_performance`)
+pub fn energy_performance_from_meta(
+    components: &Components,
+    wfactors: &Factors,
+    k_exp: Option<Real>,
+    arearef: Option<Real>,
+) -> Result<Balance, EpbdError> {
+    let resolve = |arg: Option<Real>, key: &str, default: Real| -> Result<_, EpbdError> {
+        Ok(match (arg, components.get_real(key)?) {
+            (Some(v), _) => (v, "usuario"),
+            (None, Some(v)) => (v, "metadatos"),
+            (None, None) => (default, "predefinido"),
+        })
+    };
+    let (k_exp, k_exp_origin) = resolve(k_exp, "CTE_KEXP", KEXP_DEFAULT)?;
+    let (arearef, arearef_origin) = resolve(arearef, "CTE_AREAREF", AREAREF_DEFAULT)?;
+    let k_exp_nepb = components.get_real("CTE_KEXP_NEPB")?.unwrap_or(k_exp);
+    for (name, k) in &[("k_exp", k_exp), ("k_exp_nepb", k_exp_nepb)] {
+        if !(0.0..=1.0).contains(k) {
+            return Err(EpbdError::WrongInput(format!(
+                "El factor de exportación {} debe estar entre 0.0 y 1.0 y se encontró {}",
+                name, k
+            )));
+        }
+    }
+
+    let mut balance =
+        crate::energy_performance_by_dest(components, wfactors, k_exp, k_exp_nepb, arearef)?;
+    balance
+        .misc
+        .insert("origen_kexp".to_string(), k_exp_origin.into());
+    balance
+        .misc
+        .insert("origen_arearef".to_string(), arearef_origin.into());
+    Ok(balance)
+}
+
+/// Calcula los balances en el perímetro próximo (nearby) de todos los servicios EPB de los componentes
+///
+/// Para cada servicio con consumos EPB se seleccionan sus componentes (ver
+/// `Components::filter_by_epb_service`) y se calcula el balance con los factores de paso del
+/// perímetro próximo (ver `wfactors_to_nearby`), lo que permite obtener, p.e., la fracción
+/// renovable de cada servicio.
+///
+/// Los componentes deben estar normalizados (ver `Components::normalize`).
+///
+/// * `components` - energy components
+/// * `wfactors` - weighting factors (distant perimeter)
+/// * `k_exp` - exported energy factor [0, 1]
+/// * `arearef` - reference area used for computing energy performance ratios
+pub fn nearby_balances_by_service(
+    components: &Components,
+    wfactors: &Factors,
+    k_exp: Real,
+    arearef: Real,
+) -> Result<HashMap<Service, Balance>, EpbdError> {
+    let services: HashSet<_> = components
+        .building_cdata()
+        .filter(|c| c.is_used_energy() && c.csubtype == CSubtype::EPB)
+        .map(|c| c.service)
+        .collect();
+    let wfactors_nrb = wfactors_to_nearby(wfactors)?;
+    services
+        .into_iter()
+        .map(|service| {
+            let components_srv = components.filter_by_epb_service(service);
+            crate::energy_performance(&components_srv, &wfactors_nrb, k_exp, arearef)
+                .map(|balance| (service, balance))
+        })
+        .collect()
+}
+
 #[allow(non_snake_case)]
 /// Fracción de la demanda de ACS con origen renovable, considerando el perímetro próximo
 ///
@@ -305,10 +824,10 @@ fn get_used_carriers(cr_list: &Vec<&Component>) -> Vec<Carrier> {
 ///
 /// 1. si hay biomasa (o biomasa densificada), esta y otros vectores insitu o de distrito cubren el 100% de la demanda
 /// 2. no se permite el consumo de electricidad cogenerada para producir ACS (solo la parte térmica) aunque podría provenir de BIOMASA / BIOMASADENSIFICADA
-///     Si se pudiese usar electricidad y existiese cogeneración tendríamos 2 vectores no insitu (BIOMASA, ELECTRICIDAD)
-///     y, si no se usase la parte térmica, no sabríamos si tiene procedencia renovable o no.
-/// 3. el rendimiento térmico de la contribución renovable de vectores RED1, RED2 y MEDIOAMBIENTE es 1.0. (demanda == consumo)
-/// 4. las únicas aportaciones nearby son biomasa (cualquiera), RED1, RED2, ELECTRICIDAD insitu y MEDIOAMBIENTE (insitu)
+///    Si se pudiese usar electricidad y existiese cogeneración tendríamos 2 vectores no insitu (BIOMASA, ELECTRICIDAD)
+///    y, si no se usase la parte térmica, no sabríamos si tiene procedencia renovable o no.
+/// 3. el rendimiento térmico de la contribución renovable de vectores RED1, RED2, RESIDUOS y MEDIOAMBIENTE es 1.0. (demanda == consumo)
+/// 4. las únicas aportaciones nearby son biomasa (cualquiera), RED1, RED2, RESIDUOS, ELECTRICIDAD insitu y MEDIOAMBIENTE (insitu)
 ///
 /// Casos que no podemos calcular:
 /// - Cuando hay electricidad cogenerada
@@ -319,18 +838,19 @@ fn get_used_carriers(cr_list: &Vec<&Component>) -> Vec<Carrier> {
 /// - Cuando necesitaríamos conocer el % de la demanda anual de ACS satisfecha por el vector BIOMASA y BIOMASADENSIFICADA porque
 ///     - Hay más de un vector BIOMASA o BIOMASADENSIFICADA
 ///     - Hay BIOMASA o BIOMASADENSIFICADA y otro vector que no sea insitu o de distrito.
+///
 /// TODO:
-///     - Añadir metadatos CTE_DEMANDA_ACS_PCT_BIOMASA, CTE_DEMANDA_ACS_PCT_BIOMASADENSIFICADA
-///     - Como esos son los únicos vectores para los que necesitamos saber el porcentaje de producción de ACS que suponen, nos bastaría para
-///       hacer el cálculo (ahora lo obtenemos por sustracción de las aportaciones en las que consumo === demanda) aún en presencia
-///       de más de un vector no in situ.
+/// - Añadir metadatos CTE_DEMANDA_ACS_PCT_BIOMASA, CTE_DEMANDA_ACS_PCT_BIOMASADENSIFICADA
+/// - Como esos son los únicos vectores para los que necesitamos saber el porcentaje de producción de ACS que suponen, nos bastaría para
+///   hacer el cálculo (ahora lo obtenemos por sustracción de las aportaciones en las que consumo === demanda) aún en presencia
+///   de más de un vector no in situ.
 pub fn fraccion_renovable_acs_nrb(
     components: &Components,
     wfactors: &Factors,
-    demanda_anual_acs: f32,
-) -> Result<f32, EpbdError> {
-    use CType::{CONSUMO, PRODUCCION};
-    use Carrier::{BIOMASA, BIOMASADENSIFICADA, ELECTRICIDAD, MEDIOAMBIENTE, RED1, RED2};
+    demanda_anual_acs: Real,
+) -> Result<Real, EpbdError> {
+    use CType::PRODUCCION;
+    use Carrier::{BIOMASA, BIOMASADENSIFICADA, ELECTRICIDAD, MEDIOAMBIENTE, RESIDUOS};
 
     // Lista de componentes para ACS y filtrados excluidos de participar en el cálculo de la demanda renovable
     let components = &components.filter_by_epb_service(Service::ACS);
@@ -338,7 +858,7 @@ pub fn fraccion_renovable_acs_nrb(
         .cdata
         .iter()
         .filter(|c| {
-            !((c.carrier == ELECTRICIDAD && c.comment.contains("CTEEPBD_EXCLUYE_AUX_ACS"))
+            !((c.carrier == ELECTRICIDAD && c.aux)
                 || (c.carrier == MEDIOAMBIENTE && c.comment.contains("CTEEPBD_EXCLUYE_SCOP_ACS")))
         })
         .collect();
@@ -349,7 +869,7 @@ pub fn fraccion_renovable_acs_nrb(
     };
 
     // Demanda anual de ACS nula
-    if demanda_anual_acs.abs() < f32::EPSILON {
+    if demanda_anual_acs.abs() < Real::EPSILON {
         return Err(EpbdError::WrongInput(
             "Demanda anual de ACS nula o casi nula".to_string(),
         ));
@@ -377,7 +897,7 @@ pub fn fraccion_renovable_acs_nrb(
 
     // Comprobaremos las condiciones para poder calcular las aportaciones renovables a la demanda
     //
-    // 1. Las aportaciones de redes de distrito RED1 y RED2 y MEDIOAMBIENTE son aportaciones renovables según sus factores de paso (fp_ren / fp_tot)
+    // 1. Las aportaciones de redes de distrito RED1, RED2 y RESIDUOS y MEDIOAMBIENTE son aportaciones renovables según sus factores de paso (fp_ren / fp_tot)
     // 2. La biomasa (o biomasa densificada)
     //  - si solo se consume uno de esos vectores o vectores insitu o de distrito, y se cubre el 100% de la demanda podemos calcular
     //  - si tenemos el porcentaje de demanda cubierto por la biomasa o biomasa in situ, podemos calcular la demanda renovable.
@@ -385,7 +905,7 @@ pub fn fraccion_renovable_acs_nrb(
     // 3. La ELECTRICIDAD consumida en ACS y producida in situ se toma como renovable en un 100% (rendimiento térmico == 1 y demanda == consumo).
 
     // 1. == Energía ambiente y distrito ==
-    // Demanda total y renovable de los consumos de ACS de RED1, RED2 o MEDIOAMBIENTE (demanda == consumo)
+    // Demanda total y renovable de los consumos de ACS de RED1, RED2, RESIDUOS o MEDIOAMBIENTE (demanda == consumo)
     let (Q_district_and_env_an_tot, Q_district_and_env_acs_an_ren) =
         Q_district_and_env_an(cr_list, wfactors)?;
 
@@ -398,7 +918,7 @@ pub fn fraccion_renovable_acs_nrb(
     let has_only_one_type_of_biomass =
         (has_biomass || has_dens_biomass) && !(has_biomass && has_dens_biomass);
     let has_only_biomass_or_onsite_or_district = !used_carriers.iter().any(|c| {
-        *c != MEDIOAMBIENTE && *c != RED1 && *c != RED2 && *c != BIOMASA && *c != BIOMASADENSIFICADA
+        !(c.is_district() || [MEDIOAMBIENTE, RESIDUOS, BIOMASA, BIOMASADENSIFICADA].contains(c))
     });
 
     let Q_biomass_an_ren = if has_only_one_type_of_biomass && has_only_biomass_or_onsite_or_district
@@ -406,18 +926,18 @@ pub fn fraccion_renovable_acs_nrb(
         // Solo hay un tipo de biomasa y no hay otros vectores que no sean de distrito o energía ambiente
         // entonces podemos calcular el % de la demanda de ACS abastecida por la biomasa
         let Q_any_biomass_acs_an = demanda_anual_acs - Q_district_and_env_an_tot;
-        let Q_any_biomass_acs_an_ren = if has_biomass {
+
+        if has_biomass {
             Q_any_biomass_acs_an * get_fp_ren_fraction(BIOMASA, wfactors)?
         } else {
             Q_any_biomass_acs_an * get_fp_ren_fraction(BIOMASADENSIFICADA, wfactors)?
-        };
-        Q_any_biomass_acs_an_ren
+        }
     } else if has_any_biomass {
         // Además de biomasa hay otros vectores que no son de distrito o insitu y necesitamos saber qué cantidad de ACS produce la biomasa
         let Q_biomass_an_ren = if has_biomass {
             let fp_ren_fraction_biomass = get_fp_ren_fraction(BIOMASA, wfactors)?;
             let Q_biomass_an_pct = components
-                .get_meta_f32("CTE_DEMANDA_ACS_PCT_BIOMASA")
+                .get_real("CTE_DEMANDA_ACS_PCT_BIOMASA")?
                 .ok_or_else(|| {
                     EpbdError::WrongInput(
                         "No se ha especificado el porcentaje de la demanda de ACS abastecida por BIOMASA en el metadato 'CTE_DEMANDA_ACS_PCT_BIOMASA'"
@@ -431,7 +951,7 @@ pub fn fraccion_renovable_acs_nrb(
         let Q_dens_biomass_an_ren = if has_dens_biomass {
             let fp_ren_fraction_dens_biomass = get_fp_ren_fraction(BIOMASADENSIFICADA, wfactors)?;
             let Q_dens_biomass_an_pct = components
-                .get_meta_f32("CTE_DEMANDA_ACS_PCT_BIOMASADENSIFICADA")
+                .get_real("CTE_DEMANDA_ACS_PCT_BIOMASADENSIFICADA")?
                 .ok_or_else(|| {
                     EpbdError::WrongInput(
                         "No se ha especificado el porcentaje de la demanda de ACS abastecida por BIOMASADENSIFICADA en el metadato 'CTE_DEMANDA_ACS_PCT_BIOMASADENSIFICADA'"
@@ -452,19 +972,29 @@ pub fn fraccion_renovable_acs_nrb(
     let num_steps = cr_list[0].values.len();
 
     // a. Total de consumo de electricidad para ACS, de cualquier origen
-    let E_EPus_el_t = cr_list
-        .iter()
-        .filter(|c| c.carrier == ELECTRICIDAD)
-        .filter(|c| c.ctype == CONSUMO && c.csubtype == CSubtype::EPB)
-        .fold(vec![0.0; num_steps], |acc, c| vecvecsum(&acc, &c.values));
+    let E_EPus_el_t = vecitersum(
+        num_steps,
+        cr_list
+            .iter()
+            .filter(|c| c.carrier == ELECTRICIDAD)
+            .filter(|c| c.is_used_energy() && c.csubtype == CSubtype::EPB)
+            .map(|c| &c.values),
+    );
     // b. Total de producción de electricidad in situ asignada, en principio, a ACS
-    let E_pr_el_onsite_t = cr_list
-        .iter()
-        .filter(|c| c.carrier == ELECTRICIDAD)
-        .filter(|c| c.ctype == PRODUCCION && c.csubtype == CSubtype::INSITU)
-        .fold(vec![0.0; num_steps], |acc, c| vecvecsum(&acc, &c.values));
+    let E_pr_el_onsite_t = vecitersum(
+        num_steps,
+        cr_list
+            .iter()
+            .filter(|c| c.carrier == ELECTRICIDAD)
+            .filter(|c| c.ctype == PRODUCCION && c.csubtype == CSubtype::INSITU)
+            .map(|c| &c.values),
+    );
     // c. Consumo efectivo de electricidad renovable en ACS (Mínimo entre el consumo y la producción in situ) (consumo == demanda)
-    let Q_el_an_ren: f32 = vecvecmin(&E_EPus_el_t, &E_pr_el_onsite_t).iter().sum();
+    let Q_el_an_ren: Real = E_EPus_el_t
+        .iter()
+        .zip(&E_pr_el_onsite_t)
+        .map(|(us, pr)| us.min(*pr))
+        .sum();
 
     // === Total de demanda renovable ==
     let Q_an_ren = Q_district_and_env_acs_an_ren + Q_biomass_an_ren + Q_el_an_ren;
@@ -475,16 +1005,13 @@ pub fn fraccion_renovable_acs_nrb(
 /// Devuelve balance con datos de demanda renovable de ACS en perímetro próximo incorporados
 pub fn incorpora_demanda_renovable_acs_nrb(
     mut balance: Balance,
-    demanda_anual_acs: Option<f32>,
+    demanda_anual_acs: Option<Real>,
 ) -> Balance {
-    // Añadir a balance.misc un diccionario, si no existe, con datos:
-    let mut map = balance.misc.unwrap_or_else(HashMap::<String, String>::new);
+    // Añadir a balance.misc los datos:
+    let map = &mut balance.misc;
     match demanda_anual_acs {
         Some(demanda_anual_acs) => {
-            map.insert(
-                "demanda_anual_acs".to_string(),
-                format!("{:.1}", demanda_anual_acs),
-            );
+            map.insert("demanda_anual_acs".to_string(), demanda_anual_acs.into());
 
             match fraccion_renovable_acs_nrb(
                 &balance.components,
@@ -494,9 +1021,9 @@ pub fn incorpora_demanda_renovable_acs_nrb(
                 Ok(fraccion_renovable_acs_nrb) => {
                     map.insert(
                         "fraccion_renovable_demanda_acs_nrb".to_string(),
-                        format!("{:.3}", fraccion_renovable_acs_nrb),
+                        fraccion_renovable_acs_nrb.into(),
                     );
-                    map.remove("error_acs");
+                    map.shift_remove("error_acs");
                 }
                 Err(e) => {
                     map.insert(
@@ -504,121 +1031,1637 @@ pub fn incorpora_demanda_renovable_acs_nrb(
                         format!(
                             "ERROR: no se puede calcular la demanda renovable de ACS \"{}\"",
                             e
-                        ),
+                        )
+                        .into(),
                     );
-                    map.remove("fraccion_renovable_demanda_acs_nrb");
+                    map.shift_remove("fraccion_renovable_demanda_acs_nrb");
                 }
             }
         }
         _ => {
             map.insert(
                 "error_acs".to_string(),
-                "ERROR: demanda anual de ACS no definida".to_string(),
+                "ERROR: demanda anual de ACS no definida".into(),
             );
         }
     }
-    balance.misc = Some(map);
     balance
 }
 
+/// Devuelve balance con la factura energética anual estimada incorporada [€/año]
+///
+/// Se añaden a balance.misc la factura total (`factura_anual_estimada`) y la de cada vector
+/// con tarifa (`factura_anual_estimada_VECTOR`) y, si existe, la compensación total de la energía
+/// exportada a la red (`compensacion_exportacion_estimada`), ya descontada de la factura.
+///
+/// # Errors
+///
+/// * Tarifas con periodos que usan pasos de cálculo que no existen en el balance
+///
+pub fn incorpora_factura_estimada(
+    mut balance: Balance,
+    tariffs: &Tariffs,
+) -> Result<Balance, EpbdError> {
+    let bill = energy_bill(&balance, tariffs)?;
+    let map = &mut balance.misc;
+    for b in &bill.by_carrier {
+        map.insert(
+            format!("factura_anual_estimada_{}", b.carrier),
+            b.total.into(),
+        );
+    }
+    let exported = bill.exported();
+    if exported != 0.0 {
+        map.insert(
+            "compensacion_exportacion_estimada".to_string(),
+            exported.into(),
+        );
+    }
+    map.insert("factura_anual_estimada".to_string(), bill.total.into());
+    Ok(balance)
+}
+
 /*
-Utilidades para visualización del balance
------------------------------------------
+Perfiles mensuales tipo
+-----------------------
 */
 
-/// Muestra el balance (paso B) en formato de texto simple.
-pub fn balance_to_plain(balance: &Balance) -> String {
-    let Balance {
-        k_exp,
-        arearef,
-        balance_m2,
-        ..
-    } = balance;
-
-    let RenNrenCo2 { ren, nren, co2 } = balance_m2.B;
-    let tot = balance_m2.B.tot();
-    let rer = balance_m2.B.rer();
-
-    // Final
-    let mut use_byuse = balance_m2
-        .used_EPB_byuse
-        .iter()
-        .map(|(k, v)| format!("{}: {:.2}", k, v))
-        .collect::<Vec<String>>();
-    use_byuse.sort();
+/// Perfil mensual tipo de calefacción (y humidificación), de enero a diciembre
+const PROFILE_CAL: [Real; 12] = [
+    0.19, 0.16, 0.13, 0.08, 0.03, 0.0, 0.0, 0.0, 0.0, 0.04, 0.13, 0.24,
+];
+/// Perfil mensual tipo de refrigeración (y deshumidificación), de enero a diciembre
+const PROFILE_REF: [Real; 12] = [
+    0.0, 0.0, 0.0, 0.0, 0.04, 0.14, 0.28, 0.28, 0.18, 0.08, 0.0, 0.0,
+];
+/// Perfil mensual tipo de ACS, de enero a diciembre
+const PROFILE_ACS: [Real; 12] = [
+    0.093, 0.083, 0.088, 0.082, 0.081, 0.076, 0.076, 0.076, 0.075, 0.082, 0.088, 0.100,
+];
+/// Perfil mensual tipo de iluminación, de enero a diciembre
+const PROFILE_ILU: [Real; 12] = [
+    0.095, 0.084, 0.086, 0.079, 0.077, 0.072, 0.073, 0.075, 0.077, 0.085, 0.092, 0.105,
+];
 
-    // Ponderada por m2 (por uso)
-    let mut b_byuse = balance_m2
-        .B_byuse
-        .iter()
-        .map(|(k, v)| {
-            format!(
-                "{}: ren {:.2}, nren {:.2}, co2: {:.2}",
-                k, v.ren, v.nren, v.co2
-            )
-        })
-        .collect::<Vec<String>>();
-    b_byuse.sort();
+/// Perfil mensual tipo de un servicio, con la fracción del consumo anual de cada mes
+///
+/// Son perfiles simplificados para estimar la distribución mensual de datos exclusivamente
+/// anuales (p.e. facturas), con valores que suman la unidad:
+/// - la calefacción y la humidificación se concentran en los meses de invierno;
+/// - la refrigeración y la deshumidificación se concentran en los meses de verano;
+/// - el ACS y la iluminación aumentan ligeramente en invierno;
+/// - el resto de servicios se reparte en proporción a los días de cada mes.
+///
+/// Ver `Component::expand_annual` y `expand_annual_components`.
+pub fn cte_monthly_profile(service: Service) -> [Real; 12] {
+    match service {
+        Service::CAL | Service::HU => PROFILE_CAL,
+        Service::REF | Service::DHU => PROFILE_REF,
+        Service::ACS => PROFILE_ACS,
+        Service::ILU => PROFILE_ILU,
+        _ => {
+            let mut profile = [0.0; 12];
+            for (p, days) in profile.iter_mut().zip(DAYS_PER_MONTH.iter()) {
+                *p = *days as Real / 365.0;
+            }
+            profile
+        }
+    }
+}
 
-    let out = format!(
-        "Area_ref = {:.2} [m2]
-k_exp = {:.2}
-C_ep [kWh/m2.an]: ren = {:.1}, nren = {:.1}, tot = {:.1}, RER = {:.2}
-E_CO2 [kg_CO2e/m2.an]: {:.2}
+/// Expande los componentes anuales a pasos de cálculo mensuales con los perfiles tipo
+///
+/// Los valores anuales de cada componente se reparten entre los meses según el perfil mensual
+/// tipo de su servicio (ver `cte_monthly_profile` y `Components::expand_annual`).
+///
+/// # Errors
+///
+/// * Componentes sin paso de cálculo anual
+pub fn expand_annual_components(components: &Components) -> Result<Components, EpbdError> {
+    components.expand_annual(|c| cte_monthly_profile(c.service).to_vec())
+}
 
-** Energía final (todos los vectores) [kWh/m2.an]:
-{}
+/*
+Desagregación por viviendas
+---------------------------
+*/
 
-** Energía primaria (ren, nren) [kWh/m2.an] y emisiones [kg_CO2e/m2.an] por servicios:
-{}
-",
-        arearef,
-        k_exp,
-        ren,
-        nren,
-        tot,
-        rer,
-        co2,
-        use_byuse.join("\n"),
-        b_byuse.join("\n")
-    );
-    // Añade parámetros de demanda HE4 si existen
-    if let Some(map) = &balance.misc {
-        let demanda = map
-            .get("demanda_anual_acs")
-            .and_then(|v| v.parse::<f32>().and_then(|r| Ok(format!("{:.1}", r))).ok())
-            .unwrap_or_else(|| "-".to_string());
-        let pct_ren = map
-            .get("fraccion_renovable_demanda_acs_nrb")
-            .and_then(|v| {
-                v.parse::<f32>()
-                    .and_then(|r| Ok(format!("{:.1}", r * 100.0)))
-                    .ok()
-            })
-            .unwrap_or_else(|| "-".to_string());
-        format!(
-            "{}
-** Indicadores adicionales
-Demanda total de ACS: {} [kWh]
-Porcentaje renovable de la demanda de ACS (perímetro próximo): {} [%]
-",
-            out, demanda, pct_ren
-        )
-    } else {
-        out
-    }
+/// Indicadores estimados de una vivienda de un bloque de viviendas
+///
+/// Estimated indicators for a dwelling of a residential block
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DwellingIndicators {
+    /// Dwelling number (1-based)
+    pub number: usize,
+    /// Share of the dwelling in the block [0, 1]
+    pub share: Real,
+    /// Reference area of the dwelling [m2]
+    pub arearef: Real,
+    /// Used EPB energy of the dwelling [kWh/an]
+    pub used_epb: Real,
+    /// Weighted energy (step B) of the dwelling [kWh/an] and [kg_CO2e/an]
+    pub we: RenNrenCo2,
 }
 
-/// Muestra el balance (paso B) en formato XML
+/// Cuotas de las viviendas de un bloque residencial, definidas en los metadatos de los componentes
 ///
-/// Esta función usa un formato compatible con el formato XML del certificado de eficiencia
-/// energética del edificio definido en el documento de apoyo de la certificación energética
-/// correspondiente.
-pub fn balance_to_xml(balanceobj: &Balance) -> String {
+/// Se definen mediante el número de viviendas (metadato `CTE_VIVIENDAS`), que se reparten
+/// a partes iguales, o mediante la cuota de cada vivienda (metadato `CTE_CUOTAS_VIVIENDAS`,
+/// con una lista de valores separados por comas que debe sumar 1.0). Si se indican ambos, el
+/// número de cuotas debe coincidir con el número de viviendas.
+///
+/// Devuelve `None` si no se define ninguno de los metadatos.
+///
+/// # Errors
+///
+/// * Número de viviendas o cuotas incorrectos
+pub fn dwelling_shares(components: &Components) -> Result<Option<Vec<Real>>, EpbdError> {
+    let count = match components.get_meta("CTE_VIVIENDAS") {
+        Some(v) => match v.trim().parse::<usize>() {
+            Ok(n) if n > 0 => Some(n),
+            _ => {
+                return Err(EpbdError::ParseError(format!(
+                    "Metadato CTE_VIVIENDAS incorrecto: \"{}\"",
+                    v
+                )))
+            }
+        },
+        None => None,
+    };
+    let shares = match components.get_meta("CTE_CUOTAS_VIVIENDAS") {
+        Some(v) => {
+            let shares = v
+                .split(',')
+                .map(|s| s.trim().parse::<Real>())
+                .collect::<Result<Vec<Real>, _>>()
+                .map_err(|_| {
+                    EpbdError::ParseError(format!(
+                        "Metadato CTE_CUOTAS_VIVIENDAS incorrecto: \"{}\"",
+                        v
+                    ))
+                })?;
+            if shares.iter().any(|s| *s <= 0.0) || (shares.iter().sum::<Real>() - 1.0).abs() > 0.01
+            {
+                return Err(EpbdError::WrongInput(format!(
+                    "Las cuotas de las viviendas deben ser positivas y sumar 1.0: \"{}\"",
+                    v
+                )));
+            }
+            Some(shares)
+        }
+        None => None,
+    };
+    match (count, shares) {
+        (Some(n), Some(shares)) if n != shares.len() => Err(EpbdError::WrongInput(format!(
+            "El número de viviendas ({}) no coincide con el número de cuotas ({})",
+            n,
+            shares.len()
+        ))),
+        (_, Some(shares)) => Ok(Some(shares)),
+        (Some(n), None) => Ok(Some(vec![1.0 / n as Real; n])),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Indicadores estimados de cada vivienda de un bloque residencial
+///
+/// Se reparten el área de referencia, la energía consumida EPB y la energía ponderada
+/// (paso B) del bloque según las cuotas de las viviendas (ver `dwelling_shares`), por lo que
+/// los indicadores por superficie de cada vivienda coinciden con los del bloque.
+///
+/// Devuelve una lista vacía si no se definen viviendas.
+///
+/// # Errors
+///
+/// * Número de viviendas o cuotas incorrectos
+pub fn indicators_by_dwelling(balance: &Balance) -> Result<Vec<DwellingIndicators>, EpbdError> {
+    let shares = dwelling_shares(&balance.components)?.unwrap_or_default();
+    let used_epb: Real = balance.balance.used_EPB_byuse.values().sum();
+    Ok(shares
+        .iter()
+        .enumerate()
+        .map(|(i, share)| DwellingIndicators {
+            number: i + 1,
+            share: *share,
+            arearef: share * balance.arearef,
+            used_epb: share * used_epb,
+            we: *share * balance.balance.B,
+        })
+        .collect())
+}
+
+/// Componentes de una vivienda de un bloque residencial, con sus sistemas compartidos repartidos
+///
+/// Los valores de los componentes y el área de referencia (metadato `CTE_AREAREF`) se
+/// multiplican por la cuota de la vivienda y se eliminan los metadatos de viviendas, de modo
+/// que el resultado puede evaluarse como el de una vivienda independiente.
+pub fn components_for_dwelling(components: &Components, share: Real) -> Components {
+    let mut dwelling = components.clone();
+    for c in dwelling.cdata.iter_mut() {
+        c.values = c.values.iter().map(|v| v * share).collect();
+    }
+    dwelling
+        .cmeta
+        .retain(|m| m.key != "CTE_VIVIENDAS" && m.key != "CTE_CUOTAS_VIVIENDAS");
+    if let Some(arearef) = dwelling.get_meta_real("CTE_AREAREF") {
+        dwelling.set_meta("CTE_AREAREF", &format!("{:.2}", arearef * share));
+    }
+    dwelling
+}
+
+/*
+Utilidades para visualización del balance
+-----------------------------------------
+*/
+
+/// Claves de balance.misc usadas para el indicador de porcentaje renovable de la demanda de ACS
+const MISC_ACS_KEYS: [&str; 3] = [
+    "demanda_anual_acs",
+    "fraccion_renovable_demanda_acs_nrb",
+    "error_acs",
+];
+
+/// Energía suministrada por la red para cada vector, por paso de cálculo y anual [kWh]
+///
+/// Los vectores se devuelven ordenados.
+fn delivered_by_carrier(balance: &Balance) -> Vec<(Carrier, &[Real], Real)> {
+    let mut delivered = balance
+        .balance_cr
+        .iter()
+        .map(|(carrier, bal)| {
+            (
+                carrier.clone(),
+                bal.delivered_grid.as_slice(),
+                bal.delivered_grid_an,
+            )
+        })
+        .collect::<Vec<_>>();
+    delivered.sort_by(|a, b| a.0.cmp(&b.0));
+    delivered
+}
+
+/*
+Comparación de escenarios
+-------------------------
+*/
+
+/// Indicadores de un escenario de cálculo, para su comparación con el escenario de referencia
+///
+/// Indicators of a calculation scenario, compared to the first (reference) scenario
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ScenarioIndicators {
+    /// Scenario name
+    pub name: String,
+    /// Exported energy factor [0, 1]
+    pub k_exp: Real,
+    /// Assessment perimeter of the weighting factors
+    pub perimeter: Perimeter,
+    /// Weighted energy (step B) by reference area [kWh/m2.an] and [kg_CO2e/m2.an]
+    pub ep: RenNrenCo2,
+    /// Renewable energy ratio (step B)
+    pub rer: Real,
+    /// Total weighted energy difference with the reference scenario [kWh/m2.an]
+    pub diff_tot: Real,
+    /// Non renewable weighted energy difference with the reference scenario [kWh/m2.an]
+    pub diff_nren: Real,
+}
+
+/// Tabla de indicadores (paso B) de los balances de varios escenarios
+///
+/// Las diferencias se calculan respecto al primer escenario, que se toma como referencia.
+/// Ver `energy_performance_by_scenario`.
+///
+/// # Errors
+///
+/// * Perímetro desconocido en el metadato `CTE_PERIMETRO` de los factores de paso
+pub fn scenarios_comparison(
+    balances: &[(String, Balance)],
+) -> Result<Vec<ScenarioIndicators>, EpbdError> {
+    let reference = match balances.first() {
+        Some((_, balance)) => balance.balance_m2.B,
+        None => return Ok(Vec::new()),
+    };
+    balances
+        .iter()
+        .map(|(name, balance)| {
+            let ep = balance.balance_m2.B;
+            Ok(ScenarioIndicators {
+                name: name.clone(),
+                k_exp: balance.k_exp,
+                perimeter: balance.wfactors.perimeter()?,
+                ep,
+                rer: ep.rer(),
+                diff_tot: ep.tot() - reference.tot(),
+                diff_nren: ep.nren - reference.nren,
+            })
+        })
+        .collect()
+}
+
+/// Muestra la tabla de comparación de escenarios en formato CSV
+///
+/// Incluye una fila por escenario con sus indicadores por superficie de referencia (paso B)
+/// y las diferencias respecto al escenario de referencia. Ver `scenarios_comparison`.
+pub fn scenarios_to_csv(indicators: &[ScenarioIndicators]) -> String {
+    let mut lines = vec![
+        "escenario,k_exp,perimetro,ep_ren,ep_nren,ep_tot,rer,co2,dif_ep_tot,dif_ep_nren"
+            .to_string(),
+    ];
+    lines.extend(indicators.iter().map(|i| {
+        format!(
+            "{},{:.2},{},{:.2},{:.2},{:.2},{:.3},{:.2},{:.2},{:.2}",
+            i.name,
+            i.k_exp,
+            i.perimeter,
+            i.ep.ren,
+            i.ep.nren,
+            i.ep.tot(),
+            i.rer,
+            i.ep.co2,
+            i.diff_tot,
+            i.diff_nren
+        )
+    }));
+    format!("{}\n", lines.join("\n"))
+}
+
+/*
+Comparación con el edificio de referencia
+-----------------------------------------
+*/
+
+/// Indicadores del edificio calculado y del edificio de referencia, y su cociente
+///
+/// Indicators (step B) of the calculated and reference buildings, and their ratios
+/// (calculated / reference, `None` when the reference indicator is zero)
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ReferenceIndicators {
+    /// Weighted energy (step B) of the calculated building by reference area [kWh/m2.an] and [kg_CO2e/m2.an]
+    pub ep: RenNrenCo2,
+    /// Weighted energy (step B) of the reference building by reference area [kWh/m2.an] and [kg_CO2e/m2.an]
+    pub ep_ref: RenNrenCo2,
+    /// Non renewable weighted energy ratio
+    pub ratio_nren: Option<Real>,
+    /// Total weighted energy ratio
+    pub ratio_tot: Option<Real>,
+    /// CO2 emissions ratio
+    pub ratio_co2: Option<Real>,
+}
+
+/// Compara los indicadores (paso B) del edificio calculado con los del edificio de referencia
+///
+/// Ver `energy_performance_reference`.
+pub fn reference_comparison(balance: &Balance, reference: &Balance) -> ReferenceIndicators {
+    let ep = balance.balance_m2.B;
+    let ep_ref = reference.balance_m2.B;
+    let ratio = |value: Real, reference: Real| {
+        if reference.abs() > 1e-3 {
+            Some(value / reference)
+        } else {
+            None
+        }
+    };
+    ReferenceIndicators {
+        ep,
+        ep_ref,
+        ratio_nren: ratio(ep.nren, ep_ref.nren),
+        ratio_tot: ratio(ep.tot(), ep_ref.tot()),
+        ratio_co2: ratio(ep.co2, ep_ref.co2),
+    }
+}
+
+/// Muestra la comparación con el edificio de referencia en formato de texto simple
+///
+/// Ver `reference_comparison`.
+pub fn reference_comparison_to_plain(indicators: &ReferenceIndicators) -> String {
+    let ReferenceIndicators { ep, ep_ref, .. } = indicators;
+    let ratio = |value: Option<Real>| match value {
+        Some(value) => format!("{:.2}", value),
+        None => "-".to_string(),
+    };
+    format!(
+        "** Comparación con el edificio de referencia:
+Edificio calculado: C_ep [kWh/m2.an]: ren = {:.1}, nren = {:.1}, tot = {:.1}, RER = {:.2}; E_CO2 [kg_CO2e/m2.an]: {:.2}
+Edificio de referencia: C_ep [kWh/m2.an]: ren = {:.1}, nren = {:.1}, tot = {:.1}, RER = {:.2}; E_CO2 [kg_CO2e/m2.an]: {:.2}
+Cociente calculado / referencia: C_ep_nren = {}, C_ep_tot = {}, E_CO2 = {}
+",
+        ep.ren,
+        ep.nren,
+        ep.tot(),
+        ep.rer(),
+        ep.co2,
+        ep_ref.ren,
+        ep_ref.nren,
+        ep_ref.tot(),
+        ep_ref.rer(),
+        ep_ref.co2,
+        ratio(indicators.ratio_nren),
+        ratio(indicators.ratio_tot),
+        ratio(indicators.ratio_co2)
+    )
+}
+
+/// Emisiones de CO2 por vector (paso B), por superficie de referencia [kg_CO2e/m2.an]
+///
+/// Incluyen el efecto de la energía exportada. Los vectores se devuelven ordenados.
+pub fn emissions_by_carrier(balance: &Balance) -> Vec<(Carrier, Real)> {
+    let k_area = 1.0 / balance.arearef;
+    let mut emissions = balance
+        .balance_cr
+        .iter()
+        .map(|(carrier, bal)| (carrier.clone(), k_area * bal.we_an.co2))
+        .collect::<Vec<_>>();
+    emissions.sort_by(|a, b| a.0.cmp(&b.0));
+    emissions
+}
+
+/// Energía exportada por cada vector, por paso de cálculo y anual [kWh]
+///
+/// Solo se incluyen los vectores con exportación y se devuelven ordenados.
+fn exported_steps_by_carrier(balance: &Balance) -> Vec<(Carrier, &[Real], Real)> {
+    let mut exported = balance
+        .balance_cr
+        .iter()
+        .filter(|(_, bal)| bal.exported_an > 0.0)
+        .map(|(carrier, bal)| (carrier.clone(), bal.exported.as_slice(), bal.exported_an))
+        .collect::<Vec<_>>();
+    exported.sort_by(|a, b| a.0.cmp(&b.0));
+    exported
+}
+
+/// Energía ponderada de la exportación por vector, paso A y recursos evitados a la red (paso B) [kWh/m2.an]
+///
+/// El término de recursos evitados es el crédito de exportación escalado por k_exp que se
+/// añade al paso A para obtener el paso B. Solo se incluyen los vectores con exportación y
+/// se devuelven ordenados.
+fn exported_by_carrier(balance: &Balance) -> Vec<(Carrier, RenNrenCo2, RenNrenCo2)> {
+    let k_area = 1.0 / balance.arearef;
+    let mut exported = balance
+        .balance_cr
+        .iter()
+        .filter(|(_, bal)| bal.exported_an != 0.0)
+        .map(|(carrier, bal)| {
+            (
+                carrier.clone(),
+                k_area * bal.we_exported_an_A,
+                k_area * bal.we_exported_an_avoided,
+            )
+        })
+        .collect::<Vec<_>>();
+    exported.sort_by(|a, b| a.0.cmp(&b.0));
+    exported
+}
+
+/// Factores de calibración de los consumos por vector y consumo calibrado por superficie de referencia [kWh/m2.an]
+///
+/// Si la declaración de factores de calibración es incorrecta no se devuelve ningún vector.
+fn calibrated_by_carrier(balance: &Balance) -> Vec<(Carrier, Real, Real)> {
+    let k_area = 1.0 / balance.arearef;
+    balance
+        .components
+        .calibration_factors()
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(carrier, factor)| {
+            let used: Real = balance
+                .components
+                .building_cdata()
+                .filter(|c| c.carrier == carrier && c.ctype == CType::CONSUMO)
+                .flat_map(|c| c.values.iter())
+                .sum();
+            (carrier, factor, k_area * used)
+        })
+        .collect()
+}
+
+/// Ajustes de consumo, con su valor anual por superficie de referencia [kWh/m2.an]
+///
+/// Se devuelven en el orden de los componentes.
+fn adjustments(balance: &Balance) -> Vec<(&Component, Real)> {
+    let k_area = 1.0 / balance.arearef;
+    balance
+        .components
+        .building_cdata()
+        .filter(|c| c.ctype == CType::AJUSTE)
+        .map(|c| (c, k_area * c.total()))
+        .collect()
+}
+
+/// Energía producida usada directamente por servicio, por vector, por superficie de referencia [kWh/m2.an]
+///
+/// Solo existe con compensación de la energía producida servicio a servicio. Los vectores y
+/// servicios se devuelven ordenados.
+fn produced_used_by_service(balance: &Balance) -> Vec<(Carrier, Vec<(Service, Real)>)> {
+    let k_area = 1.0 / balance.arearef;
+    let mut carriers = balance
+        .balance_cr
+        .iter()
+        .filter(|(_, bal)| !bal.produced_used_EPus_an_byuse.is_empty())
+        .map(|(carrier, bal)| {
+            let mut byuse = bal
+                .produced_used_EPus_an_byuse
+                .iter()
+                .map(|(service, v)| (*service, k_area * v))
+                .collect::<Vec<_>>();
+            byuse.sort_by_key(|(service, _)| *service);
+            (carrier.clone(), byuse)
+        })
+        .collect::<Vec<_>>();
+    carriers.sort_by(|a, b| a.0.cmp(&b.0));
+    carriers
+}
+
+/// Datos de un sistema para los informes: sistema, energía consumida por vector e indicadores de utilización
+type SystemReport = (System, Vec<(Carrier, Real)>, SystemUtilization);
+
+/// Sistemas declarados, su energía consumida por vector, por superficie de referencia [kWh/m2.an],
+/// y sus indicadores de utilización
+///
+/// Los vectores se devuelven ordenados.
+///
+/// # Errors
+///
+/// * Declaración incorrecta de los sistemas
+fn used_by_system(balance: &Balance) -> Result<Vec<SystemReport>, EpbdError> {
+    let k_area = 1.0 / balance.arearef;
+    let systems = balance.components.systems()?;
+    let utilization = balance.components.systems_utilization()?;
+    Ok(systems
+        .into_iter()
+        .zip(utilization)
+        .map(|(system, utilization)| {
+            let mut used: Vec<(Carrier, Real)> = Vec::new();
+            for c in balance
+                .components
+                .building_cdata()
+                .filter(|c| c.id == system.id && c.is_used_energy())
+            {
+                let value = k_area * c.total();
+                match used.iter_mut().find(|(carrier, _)| *carrier == c.carrier) {
+                    Some((_, v)) => *v += value,
+                    None => used.push((c.carrier.clone(), value)),
+                }
+            }
+            used.sort_by(|a, b| a.0.cmp(&b.0));
+            (system, used, utilization)
+        })
+        .collect())
+}
+
+/// Balances de las conversiones de energía en el edificio, por superficie de referencia [kWh/m2.an]
+///
+/// # Errors
+///
+/// * Declaración incorrecta de las conversiones
+fn conversions_by_area(balance: &Balance) -> Result<Vec<ConversionBalance>, EpbdError> {
+    let k_area = 1.0 / balance.arearef;
+    Ok(balance
+        .components
+        .conversions_balance()?
+        .into_iter()
+        .map(|b| ConversionBalance {
+            input: k_area * b.input,
+            output: k_area * b.output,
+            used: k_area * b.used,
+            losses: k_area * b.losses,
+            unused: k_area * b.unused,
+            ..b
+        })
+        .collect())
+}
+
+/// Resumen anual del balance de cada vector, por superficie de referencia [kWh/m2.an]
+///
+/// Los vectores se devuelven ordenados.
+fn summary_by_area(balance: &Balance) -> Vec<CarrierSummary> {
+    let k_area = 1.0 / balance.arearef;
+    balance
+        .summary_by_carrier()
+        .into_iter()
+        .map(|s| CarrierSummary {
+            delivered_grid: k_area * s.delivered_grid,
+            delivered_onsite: k_area * s.delivered_onsite,
+            exported_grid: k_area * s.exported_grid,
+            exported_nEPB: k_area * s.exported_nEPB,
+            we_delivered_grid: k_area * s.we_delivered_grid,
+            we_delivered_onsite: k_area * s.we_delivered_onsite,
+            we_exported_A: k_area * s.we_exported_A,
+            we_exported_avoided: k_area * s.we_exported_avoided,
+            we_B: k_area * s.we_B,
+            ..s
+        })
+        .collect()
+}
+
+/// Energía cargada y descargada de cada almacenamiento, por superficie de referencia [kWh/m2.an]
+///
+/// Devuelve, para cada vector con almacenamiento declarado y balance, el almacenamiento, la
+/// energía cargada y la energía descargada.
+///
+/// # Errors
+///
+/// * Declaración incorrecta de los almacenamientos
+fn storages_by_area(balance: &Balance) -> Result<Vec<(Carrier, Storage, Real, Real)>, EpbdError> {
+    let k_area = 1.0 / balance.arearef;
+    Ok(balance
+        .components
+        .storages()?
+        .into_iter()
+        .filter_map(|(carrier, storage)| {
+            let bal_cr = balance.balance_cr.get(&carrier)?;
+            Some((
+                carrier,
+                storage,
+                k_area * bal_cr.stored.iter().sum::<Real>(),
+                k_area * bal_cr.stored_used.iter().sum::<Real>(),
+            ))
+        })
+        .collect())
+}
+
+/// Indicadores de autoconsumo de la electricidad producida, con la energía por superficie [kWh/m2.an]
+fn self_consumption_by_area(balance: &Balance) -> Option<SelfConsumption> {
+    let k_area = 1.0 / balance.arearef;
+    balance.self_consumption.map(|s| SelfConsumption {
+        produced: k_area * s.produced,
+        self_consumed: k_area * s.self_consumed,
+        used: k_area * s.used,
+        ..s
+    })
+}
+
+/// Nivel de detalle de la salida del balance en formato de texto simple
+///
+/// Detail level of the plain text balance output
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum PlainDetail {
+    /// Global indicators (step B) only
+    Summary,
+    /// Global indicators and results by service, carrier, system, etc (step B)
+    #[default]
+    Standard,
+    /// Standard output plus step A results, results by service for steps A and B and exported
+    /// energy by carrier and timestep
+    Full,
+}
+
+impl str::FromStr for PlainDetail {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<PlainDetail, Self::Err> {
+        match s {
+            "resumen" => Ok(PlainDetail::Summary),
+            "normal" => Ok(PlainDetail::Standard),
+            "completo" => Ok(PlainDetail::Full),
+            _ => Err(EpbdError::ParseError(s.into())),
+        }
+    }
+}
+
+/// Muestra el balance (paso B) en formato de texto simple.
+///
+/// Equivale a `balance_to_plain_with_detail` con el nivel de detalle `PlainDetail::Standard`.
+pub fn balance_to_plain(balance: &Balance) -> String {
+    balance_to_plain_with_detail(balance, PlainDetail::Standard)
+}
+
+/// Muestra el balance en formato de texto simple con el nivel de detalle indicado
+///
+/// El nivel `PlainDetail::Summary` muestra solo los indicadores globales (paso B) y el nivel
+/// `PlainDetail::Full` añade a la salida habitual los resultados del paso A, los indicadores
+/// de cada servicio en los pasos A y B y la energía exportada por vector y paso de cálculo.
+pub fn balance_to_plain_with_detail(balance: &Balance, detail: PlainDetail) -> String {
+    let Balance {
+        k_exp,
+        k_exp_nepb,
+        arearef,
+        balance_m2,
+        ..
+    } = balance;
+
+    // Factor de exportación para usos no EPB, solo si difiere de k_exp
+    let k_exp_dest = if (k_exp_nepb - k_exp).abs() > 1e-3 {
+        tr_format!("k_exp (usos no EPB) = {:.2}\n", k_exp_nepb)
+    } else {
+        String::new()
+    };
+
+    let RenNrenCo2 { ren, nren, co2 } = balance_m2.B;
+    let tot = balance_m2.B.tot();
+    let rer = balance_m2.B.rer();
+
+    let header = format!(
+        "Area_ref = {:.2} [m2]
+k_exp = {:.2}
+{}C_ep [kWh/m2.an]: ren = {:.1}, nren = {:.1}, tot = {:.1}, RER = {:.2}
+E_CO2 [kg_CO2e/m2.an]: {:.2}
+",
+        arearef, k_exp, k_exp_dest, ren, nren, tot, rer, co2
+    );
+    if detail == PlainDetail::Summary {
+        return header;
+    }
+
+    // Final
+    let mut use_byuse = balance_m2
+        .used_EPB_byuse
+        .iter()
+        .map(|(k, v)| format!("{}: {:.2}", k, v))
+        .collect::<Vec<String>>();
+    use_byuse.sort();
+
+    // Ponderada por m2 (por uso)
+    let mut b_byuse = balance_m2
+        .B_byuse
+        .iter()
+        .map(|(k, v)| {
+            format!(
+                "{}: ren {:.2}, nren {:.2}, co2: {:.2}",
+                k, v.ren, v.nren, v.co2
+            )
+        })
+        .collect::<Vec<String>>();
+    b_byuse.sort();
+
+    // Energía suministrada por vector y paso de cálculo
+    let delivered = delivered_by_carrier(balance)
+        .iter()
+        .map(|(carrier, values, total)| {
+            let values = values
+                .iter()
+                .map(|v| format!("{:.2}", v))
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!("{}: {} (total: {:.2})", carrier, values, total)
+        })
+        .collect::<Vec<String>>();
+
+    // Energía exportada: paso A y recursos evitados a la red (paso B)
+    let mut exported = exported_by_carrier(balance)
+        .iter()
+        .map(|(carrier, we_a, avoided)| {
+            tr_format!(
+                "{}: paso A: ren {:.2}, nren {:.2}, co2 {:.2}; recursos evitados: ren {:.2}, nren {:.2}, co2 {:.2}",
+                carrier, we_a.ren, we_a.nren, we_a.co2, avoided.ren, avoided.nren, avoided.co2
+            )
+        })
+        .collect::<Vec<String>>();
+    let avoided = balance_m2.we_exp_avoided;
+    exported.push(tr_format!(
+        "TOTAL recursos evitados: ren {:.2}, nren {:.2}, co2 {:.2}",
+        avoided.ren,
+        avoided.nren,
+        avoided.co2
+    ));
+
+    // Emisiones por vector
+    let emissions = emissions_by_carrier(balance)
+        .iter()
+        .map(|(carrier, co2)| format!("{}: {:.2}", carrier, co2))
+        .collect::<Vec<String>>();
+
+    // Energía auxiliar (incluida en la energía final)
+    let mut aux_byuse = balance_m2
+        .used_EPB_aux_byuse
+        .iter()
+        .map(|(k, v)| format!("{}: {:.2}", k, v))
+        .collect::<Vec<String>>();
+    aux_byuse.sort();
+    let aux_byuse = if aux_byuse.is_empty() {
+        String::new()
+    } else {
+        tr_format!(
+            "\n** Energía auxiliar (incluida en la energía final) [kWh/m2.an]:\n{}\n",
+            aux_byuse.join("\n")
+        )
+    };
+
+    // Energía producida usada directamente por servicio (compensación servicio a servicio)
+    let produced_byuse = produced_used_by_service(balance)
+        .iter()
+        .map(|(carrier, byuse)| {
+            let byuse = byuse
+                .iter()
+                .map(|(service, v)| format!("{}: {:.2}", service, v))
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!("{}: {}", carrier, byuse)
+        })
+        .collect::<Vec<String>>();
+    let produced_byuse = if produced_byuse.is_empty() {
+        String::new()
+    } else {
+        let allocation = balance.components.service_allocation();
+        tr_format!(
+            "\n** Energía producida usada por servicio (reparto de la producción NDEF: {}) [kWh/m2.an]:\n{}\n",
+            allocation,
+            produced_byuse.join("\n")
+        )
+    };
+
+    // Sistemas declarados, energía consumida por sistema e indicadores de utilización
+    let systems = used_by_system(balance)
+        .unwrap_or_default()
+        .iter()
+        .map(|(s, used, utilization)| {
+            let used = if used.is_empty() {
+                tr("sin consumo").to_string()
+            } else {
+                used.iter()
+                    .map(|(carrier, v)| format!("{} {:.2}", carrier, v))
+                    .collect::<Vec<String>>()
+                    .join(", ")
+            };
+            let hours = utilization
+                .full_load_hours
+                .map(|h| tr_format!("; horas equivalentes: {:.1} h", h))
+                .unwrap_or_default();
+            let capacity = utilization
+                .capacity_factor
+                .map(|f| tr_format!("; factor de capacidad: {:.3}", f))
+                .unwrap_or_default();
+            format!(
+                "{} - {} ({}, {}, {:.2} kW): {}{}{}",
+                s.id, s.name, s.stype, s.carrier, s.power, used, hours, capacity
+            )
+        })
+        .collect::<Vec<String>>();
+    let systems = if systems.is_empty() {
+        String::new()
+    } else {
+        tr_format!(
+            "\n** Energía consumida por sistema [kWh/m2.an]:\n{}\n",
+            systems.join("\n")
+        )
+    };
+
+    // Ajustes de consumo
+    let adjustments = adjustments(balance)
+        .iter()
+        .map(|(c, v)| {
+            format!(
+                "{}, {}, {}: {:.2} ({})",
+                c.carrier, c.csubtype, c.service, v, c.comment
+            )
+        })
+        .collect::<Vec<String>>();
+    let adjustments = if adjustments.is_empty() {
+        String::new()
+    } else {
+        tr_format!(
+            "\n** Ajustes de consumo [kWh/m2.an]:\n{}\n",
+            adjustments.join("\n")
+        )
+    };
+
+    // Conversiones de energía en el edificio
+    let conversions = conversions_by_area(balance)
+        .unwrap_or_default()
+        .iter()
+        .map(|b| {
+            tr_format!(
+                "{} -> {} (rendimiento {:.3}): entrada {:.2}, convertida {:.2}, aprovechada {:.2}, pérdidas {:.2}, no aprovechada {:.2}",
+                b.conversion.from,
+                b.conversion.to,
+                b.conversion.efficiency,
+                b.input,
+                b.output,
+                b.used,
+                b.losses,
+                b.unused
+            )
+        })
+        .collect::<Vec<String>>();
+    let conversions = if conversions.is_empty() {
+        String::new()
+    } else {
+        tr_format!(
+            "\n** Conversiones de energía en el edificio [kWh/m2.an]:\n{}\n",
+            conversions.join("\n")
+        )
+    };
+
+    // Balance anual por vector: energía suministrada y exportada y su energía ponderada
+    let rnc = |v: RenNrenCo2| format!("{:.2}/{:.2}/{:.2}", v.ren, v.nren, v.co2);
+    let summary = summary_by_area(balance)
+        .iter()
+        .map(|s| {
+            tr_format!(
+                "{}: suministrada red {:.2}, suministrada in situ {:.2}, exportada red {:.2}, exportada no EPB {:.2}\n    ponderada (ren/nren/co2): suministrada red {}, suministrada in situ {}, exportada paso A {}, recursos evitados {}, paso B {}",
+                s.carrier,
+                s.delivered_grid,
+                s.delivered_onsite,
+                s.exported_grid,
+                s.exported_nEPB,
+                rnc(s.we_delivered_grid),
+                rnc(s.we_delivered_onsite),
+                rnc(s.we_exported_A),
+                rnc(s.we_exported_avoided),
+                rnc(s.we_B)
+            )
+        })
+        .collect::<Vec<String>>();
+
+    // Almacenamiento de energía
+    let storage = storages_by_area(balance)
+        .unwrap_or_default()
+        .iter()
+        .map(|(carrier, storage, charged, discharged)| {
+            tr_format!(
+                "{}: capacidad {:.2} kWh, rendimiento {:.3}: cargada {:.2}, descargada {:.2}, pérdidas {:.2}",
+                carrier,
+                storage.capacity,
+                storage.efficiency,
+                charged,
+                discharged,
+                charged - discharged
+            )
+        })
+        .collect::<Vec<String>>();
+    let storage = if storage.is_empty() {
+        String::new()
+    } else {
+        tr_format!(
+            "\n** Almacenamiento de energía [kWh/m2.an]:\n{}\n",
+            storage.join("\n")
+        )
+    };
+
+    // Autoconsumo de la electricidad producida
+    let self_consumption = match self_consumption_by_area(balance) {
+        Some(s) => tr_format!(
+            "\n** Autoconsumo de electricidad producida [kWh/m2.an]:\nproducida {:.2}, autoconsumida {:.2}, consumida {:.2}; índice de autoconsumo {:.2}, índice de autosuficiencia {:.2}\n",
+            s.produced,
+            s.self_consumed,
+            s.used,
+            s.self_consumption_ratio,
+            s.self_sufficiency_ratio
+        ),
+        None => String::new(),
+    };
+
+    // Calibración de consumos
+    let calibration = calibrated_by_carrier(balance)
+        .iter()
+        .map(|(carrier, factor, used)| {
+            tr_format!(
+                "{}: factor {:.3}, consumo calibrado {:.2} (sin calibrar: {:.2})",
+                carrier,
+                factor,
+                used,
+                used / factor
+            )
+        })
+        .collect::<Vec<String>>();
+    let calibration = if calibration.is_empty() {
+        String::new()
+    } else {
+        tr_format!(
+            "\n** Calibración de consumos [kWh/m2.an]:\n{}\n",
+            calibration.join("\n")
+        )
+    };
+
+    // Indicadores estimados por vivienda
+    let dwellings = indicators_by_dwelling(balance)
+        .unwrap_or_default()
+        .iter()
+        .map(|d| {
+            tr_format!(
+                "Vivienda {}: cuota {:.3}, A_ref {:.2} m2, consumo EPB {:.1} kWh/an, C_ep ren {:.1}, nren {:.1} kWh/an, E_CO2 {:.1} kg_CO2e/an",
+                d.number, d.share, d.arearef, d.used_epb, d.we.ren, d.we.nren, d.we.co2
+            )
+        })
+        .collect::<Vec<String>>();
+    let dwellings = if dwellings.is_empty() {
+        String::new()
+    } else {
+        tr_format!(
+            "\n** Indicadores por vivienda (estimación por cuotas):\n{}\n",
+            dwellings.join("\n")
+        )
+    };
+
+    // Resultados del paso A, indicadores por servicio y energía exportada por paso de cálculo
+    let full = if detail == PlainDetail::Full {
+        plain_full_detail(balance)
+    } else {
+        String::new()
+    };
+
+    let out = tr_format!(
+        "{}
+** Energía final (todos los vectores) [kWh/m2.an]:
+{}
+
+** Energía primaria (ren, nren) [kWh/m2.an] y emisiones [kg_CO2e/m2.an] por servicios:
+{}
+
+** Energía suministrada por vector y paso de cálculo [kWh]:
+{}
+
+** Energía exportada ponderada (paso A) y recursos evitados a la red (paso B, k_exp) [kWh/m2.an] y [kg_CO2e/m2.an]:
+{}
+
+** Emisiones por vector (paso B) [kg_CO2e/m2.an]:
+{}
+
+** Balance anual por vector [kWh/m2.an] y energía ponderada [kWh/m2.an] y [kg_CO2e/m2.an]:
+{}
+{}{}{}{}{}{}{}{}{}{}",
+        header,
+        use_byuse.join("\n"),
+        b_byuse.join("\n"),
+        delivered.join("\n"),
+        exported.join("\n"),
+        emissions.join("\n"),
+        summary.join("\n"),
+        aux_byuse,
+        produced_byuse,
+        systems,
+        adjustments,
+        conversions,
+        storage,
+        self_consumption,
+        calibration,
+        dwellings,
+        full
+    );
+    // Añade parámetros de demanda HE4 e indicadores adicionales si existen
+    let map = &balance.misc;
+    if map.is_empty() {
+        return out;
+    }
+    let demanda = map
+        .get("demanda_anual_acs")
+        .and_then(MiscValue::as_real)
+        .map(|r| format!("{:.1}", r))
+        .unwrap_or_else(|| "-".to_string());
+    let pct_ren = map
+        .get("fraccion_renovable_demanda_acs_nrb")
+        .and_then(MiscValue::as_real)
+        .map(|r| format!("{:.1}", r * 100.0))
+        .unwrap_or_else(|| "-".to_string());
+    let others = map
+        .iter()
+        .filter(|(k, _)| !MISC_ACS_KEYS.contains(&k.as_str()))
+        .map(|(k, v)| match v {
+            MiscValue::Number(n) => format!("{}: {:.3}\n", k, n),
+            MiscValue::Text(t) => format!("{}: {}\n", k, t),
+        })
+        .collect::<String>();
+    tr_format!(
+        "{}
+** Indicadores adicionales
+Demanda total de ACS: {} [kWh]
+Porcentaje renovable de la demanda de ACS (perímetro próximo): {} [%]
+{}",
+        out,
+        demanda,
+        pct_ren,
+        others
+    )
+}
+
+/// Secciones adicionales de la salida en formato de texto simple con el nivel de detalle completo
+///
+/// Ver `balance_to_plain_with_detail`.
+fn plain_full_detail(balance: &Balance) -> String {
+    let rnc = |v: &RenNrenCo2| {
+        format!(
+            "ren {:.2}, nren {:.2}, tot {:.2}, RER {:.2}, co2 {:.2}",
+            v.ren,
+            v.nren,
+            v.tot(),
+            v.rer(),
+            v.co2
+        )
+    };
+    let balance_m2 = &balance.balance_m2;
+
+    // Indicadores por servicio en los pasos A y B
+    let mut services = balance_m2.B_byuse.keys().collect::<Vec<_>>();
+    services.sort();
+    let byuse = services
+        .iter()
+        .map(|service| {
+            let a = balance_m2.A_byuse.get(service).copied().unwrap_or_default();
+            tr_format!(
+                "{}: paso A: {}; paso B: {}",
+                service,
+                rnc(&a),
+                rnc(&balance_m2.B_byuse[service])
+            )
+        })
+        .collect::<Vec<String>>();
+
+    // Energía exportada por vector y paso de cálculo
+    let exported = exported_steps_by_carrier(balance)
+        .iter()
+        .map(|(carrier, values, total)| {
+            let values = values
+                .iter()
+                .map(|v| format!("{:.2}", v))
+                .collect::<Vec<String>>()
+                .join(", ");
+            format!("{}: {} (total: {:.2})", carrier, values, total)
+        })
+        .collect::<Vec<String>>();
+    let exported = if exported.is_empty() {
+        String::new()
+    } else {
+        tr_format!(
+            "\n** Energía exportada por vector y paso de cálculo [kWh]:\n{}\n",
+            exported.join("\n")
+        )
+    };
+
+    tr_format!(
+        "\n** Energía ponderada y emisiones (paso A) [kWh/m2.an] y [kg_CO2e/m2.an]:\n{}\n\n** Energía ponderada y emisiones por servicio (pasos A y B) [kWh/m2.an] y [kg_CO2e/m2.an]:\n{}\n{}",
+        rnc(&balance_m2.A),
+        byuse.join("\n"),
+        exported
+    )
+}
+
+/// Muestra los indicadores (paso B) de cada anualidad y su media anual en formato de texto simple
+///
+/// Ver `energy_performance_by_year`.
+pub fn balance_by_year_to_plain(balances: &[(String, Balance)]) -> String {
+    if balances.is_empty() {
+        return String::new();
+    }
+    let line = |label: &str, b: &RenNrenCo2| {
+        format!(
+            "{}: C_ep [kWh/m2.an]: ren = {:.1}, nren = {:.1}, tot = {:.1}, RER = {:.2}; E_CO2 [kg_CO2e/m2.an]: {:.2}",
+            label,
+            b.ren,
+            b.nren,
+            b.tot(),
+            b.rer(),
+            b.co2
+        )
+    };
+    let mut lines = balances
+        .iter()
+        .map(|(label, bal)| line(label, &bal.balance_m2.B))
+        .collect::<Vec<String>>();
+    let mean = balances
+        .iter()
+        .fold(RenNrenCo2::default(), |acc, (_, bal)| {
+            acc + bal.balance_m2.B
+        })
+        * (1.0 / balances.len() as Real);
+    lines.push(line("Media anual", &mean));
+    format!("** Balance por anualidades:\n{}\n", lines.join("\n"))
+}
+
+/// Muestra los indicadores (paso B) para cada factor de exportación en formato de texto simple
+///
+/// Ver `energy_performance_by_kexp`.
+pub fn balance_by_kexp_to_plain(balances: &[(Real, Balance)]) -> String {
+    if balances.is_empty() {
+        return String::new();
+    }
+    let lines = balances
+        .iter()
+        .map(|(k_exp, bal)| {
+            let b = bal.balance_m2.B;
+            format!(
+                "k_exp = {:.2}: C_ep [kWh/m2.an]: ren = {:.1}, nren = {:.1}, tot = {:.1}, RER = {:.2}; E_CO2 [kg_CO2e/m2.an]: {:.2}",
+                k_exp,
+                b.ren,
+                b.nren,
+                b.tot(),
+                b.rer(),
+                b.co2
+            )
+        })
+        .collect::<Vec<String>>();
+    format!(
+        "** Sensibilidad al factor de exportación:\n{}\n",
+        lines.join("\n")
+    )
+}
+
+/// Muestra el balance (paso B) en formato Markdown
+///
+/// Incluye los datos generales, los indicadores globales, el desglose por servicios EPB, el
+/// balance anual por vector y, si existen, los indicadores adicionales, en forma de tablas.
+pub fn balance_to_markdown(balance: &Balance) -> String {
+    let Balance {
+        k_exp,
+        arearef,
+        balance_m2,
+        ..
+    } = balance;
+    let b = balance_m2.B;
+
+    // Servicios EPB: energía final y ponderada
+    let mut services = balance_m2
+        .used_EPB_byuse
+        .iter()
+        .map(|(service, used)| {
+            let we = balance_m2.B_byuse.get(service).copied().unwrap_or_default();
+            format!(
+                "| {} | {:.2} | {:.2} | {:.2} | {:.2} |",
+                service, used, we.ren, we.nren, we.co2
+            )
+        })
+        .collect::<Vec<String>>();
+    services.sort();
+
+    // Balance anual por vector
+    let carriers = summary_by_area(balance)
+        .iter()
+        .map(|s| {
+            format!(
+                "| {} | {:.2} | {:.2} | {:.2} | {:.2} | {:.2} | {:.2} | {:.2} |",
+                s.carrier,
+                s.delivered_grid,
+                s.delivered_onsite,
+                s.exported_grid,
+                s.exported_nEPB,
+                s.we_B.ren,
+                s.we_B.nren,
+                s.we_B.co2
+            )
+        })
+        .collect::<Vec<String>>();
+
+    let mut out = format!(
+        "# Balance energético
+
+## Datos generales
+
+| Parámetro | Valor |
+|---|---:|
+| Área de referencia [m2] | {:.2} |
+| Factor de exportación k_exp [-] | {:.2} |
+
+## Indicadores (paso B)
+
+| Indicador | Valor |
+|---|---:|
+| C_ep,ren [kWh/m2.an] | {:.1} |
+| C_ep,nren [kWh/m2.an] | {:.1} |
+| C_ep,tot [kWh/m2.an] | {:.1} |
+| RER [-] | {:.2} |
+| E_CO2 [kg_CO2e/m2.an] | {:.2} |
+
+## Servicios EPB
+
+| Servicio | Energía final [kWh/m2.an] | C_ep,ren [kWh/m2.an] | C_ep,nren [kWh/m2.an] | E_CO2 [kg_CO2e/m2.an] |
+|---|---:|---:|---:|---:|
+{}
+
+## Balance anual por vector [kWh/m2.an]
+
+| Vector | Suministrada red | Suministrada in situ | Exportada red | Exportada no EPB | Paso B ren | Paso B nren | Paso B co2 |
+|---|---:|---:|---:|---:|---:|---:|---:|
+{}
+",
+        arearef,
+        k_exp,
+        b.ren,
+        b.nren,
+        b.tot(),
+        b.rer(),
+        b.co2,
+        services.join("\n"),
+        carriers.join("\n")
+    );
+
+    // Autoconsumo de la electricidad producida
+    if let Some(s) = self_consumption_by_area(balance) {
+        out.push_str(&format!(
+            "
+## Autoconsumo de electricidad producida
+
+| Indicador | Valor |
+|---|---:|
+| Producida [kWh/m2.an] | {:.2} |
+| Autoconsumida [kWh/m2.an] | {:.2} |
+| Consumida [kWh/m2.an] | {:.2} |
+| Índice de autoconsumo [-] | {:.2} |
+| Índice de autosuficiencia [-] | {:.2} |
+",
+            s.produced, s.self_consumed, s.used, s.self_consumption_ratio, s.self_sufficiency_ratio
+        ));
+    }
+
+    // Indicadores adicionales
+    if !balance.misc.is_empty() {
+        let misc = balance
+            .misc
+            .iter()
+            .map(|(k, v)| match v {
+                MiscValue::Number(n) => format!("| {} | {:.3} |", k, n),
+                MiscValue::Text(t) => format!("| {} | {} |", k, t),
+            })
+            .collect::<Vec<String>>();
+        out.push_str(&format!(
+            "\n## Indicadores adicionales\n\n| Indicador | Valor |\n|---|---:|\n{}\n",
+            misc.join("\n")
+        ));
+    }
+    out
+}
+
+/// Muestra la energía de cada vector por paso de cálculo en formato CSV [kWh]
+///
+/// Cada fila contiene el vector, la magnitud (consumo EPB y no EPB, producción, suministro de
+/// la red y exportación a la red y a usos no EPB), su total y el valor de cada paso de cálculo.
+/// Los vectores se devuelven ordenados.
+pub fn balance_to_csv(balance: &Balance) -> String {
+    let num_steps = balance
+        .balance_cr
+        .values()
+        .next()
+        .map(|b| b.used_EPB.len())
+        .unwrap_or(0);
+    let header = std::iter::once("vector,magnitud,total".to_string())
+        .chain((1..=num_steps).map(|i| i.to_string()))
+        .collect::<Vec<String>>()
+        .join(",");
+    let mut carriers = balance.balance_cr.keys().collect::<Vec<_>>();
+    carriers.sort();
+    let mut lines = vec![header];
+    for carrier in carriers {
+        let bal = &balance.balance_cr[carrier];
+        let rows: [(&str, &[Real]); 6] = [
+            ("CONSUMO_EPB", &bal.used_EPB),
+            ("CONSUMO_NEPB", &bal.used_nEPB),
+            ("PRODUCCION", &bal.produced),
+            ("SUMINISTRO_RED", &bal.delivered_grid),
+            ("EXPORTACION_RED", &bal.exported_grid),
+            ("EXPORTACION_NEPB", &bal.exported_nEPB),
+        ];
+        for (name, values) in rows.iter() {
+            let total: Real = values.iter().sum();
+            let values = values
+                .iter()
+                .map(|v| format!("{:.2}", v))
+                .collect::<Vec<String>>()
+                .join(",");
+            lines.push(format!("{},{},{:.2},{}", carrier, name, total, values));
+        }
+    }
+    lines.join("\n")
+}
+
+/// Escapa los símbolos reservados de XML
+fn escape_xml(unescaped: &str) -> String {
+    unescaped
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('\'', "&apos;")
+        .replace('"', "&quot;")
+}
+
+/// Recupera el texto de un valor escapado en XML
+fn unescape_xml(escaped: &str) -> String {
+    escaped
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&gt;", ">")
+        .replace("&lt;", "<")
+        .replace("&amp;", "&")
+}
+
+/// Contenido de los elementos XML con la etiqueta indicada, en orden de aparición
+///
+/// No admite elementos anidados con la misma etiqueta.
+fn xml_elements<'a>(s: &'a str, tag: &str) -> Vec<&'a str> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut elements = Vec::new();
+    let mut rest = s;
+    while let Some(start) = rest.find(&open) {
+        let content = &rest[start + open.len()..];
+        match content.find(&close) {
+            Some(end) => {
+                elements.push(&content[..end]);
+                rest = &content[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+    elements
+}
+
+/// Texto del primer elemento XML con la etiqueta indicada
+fn xml_text(s: &str, tag: &str) -> Result<String, EpbdError> {
+    xml_elements(s, tag)
+        .first()
+        .map(|v| unescape_xml(v.trim()))
+        .ok_or_else(|| EpbdError::ParseError(format!("elemento <{}> no encontrado", tag)))
+}
+
+/// Metadatos de la sección XML de factores de paso o componentes
+fn xml_meta(section: &str) -> Result<Vec<Meta>, EpbdError> {
+    xml_elements(section, "Metadato")
+        .iter()
+        .map(|m| Ok(Meta::new(xml_text(m, "Clave")?, xml_text(m, "Valor")?)))
+        .collect()
+}
+
+/// Datos de entrada de un balance energético
+///
+/// Input data of an energy balance, as recovered from its XML output
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BalanceInput {
+    /// Energy components
+    pub components: Components,
+    /// Weighting factors
+    pub wfactors: Factors,
+    /// Exported energy factor [0, 1]
+    pub k_exp: Real,
+    /// Exported energy factor for energy exported to nEPB uses [0, 1]
+    pub k_exp_nepb: Real,
+    /// Reference area [m2]
+    pub arearef: Real,
+}
+
+/// Interpreta los datos de entrada de un balance en el formato XML de `balance_to_xml`
+///
+/// Reconstruye los componentes, factores de paso, factores de exportación y área de referencia
+/// a partir del documento XML, de modo que se pueda volver a calcular el balance. Si el documento
+/// no incluye el factor de exportación a usos no EPB (`kexp_nepb`), se usa el de la red. Los valores
+/// se recuperan con la precisión con la que se escriben en el XML (2 decimales para los
+/// componentes y 3 para los factores de paso) y no se incluyen los factores de paso variables
+/// en el tiempo. El resto de resultados del documento se ignora.
+///
+/// # Errors
+///
+/// * Documento sin los elementos de datos de entrada o con valores incorrectos
+/// * Componentes incoherentes (ver `Components::check`)
+pub fn balance_input_from_xml(s: &str) -> Result<BalanceInput, EpbdError> {
+    balance_input_from_xml_with_options(s, &ParseOptions::default())
+}
+
+/// Interpreta los datos de entrada de un balance en formato XML aplicando el tratamiento de los
+/// valores negativos de los componentes de las opciones indicadas (ver `ParseOptions::negative_values`)
+///
+/// # Errors
+///
+/// * Documento sin los elementos de datos de entrada o con valores incorrectos
+/// * Componentes incoherentes o con valores negativos, si se rechazan
+pub fn balance_input_from_xml_with_options(
+    s: &str,
+    options: &ParseOptions,
+) -> Result<BalanceInput, EpbdError> {
+    let section = |tag: &str| -> Result<&str, EpbdError> {
+        xml_elements(s, tag)
+            .first()
+            .copied()
+            .ok_or_else(|| EpbdError::ParseError(format!("elemento <{}> no encontrado", tag)))
+    };
+    let data = |section: &str| -> Vec<String> {
+        xml_elements(section, "Datos")
+            .first()
+            .map(|d| {
+                xml_elements(d, "Dato")
+                    .iter()
+                    .map(|v| v.to_string())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    // Factores de paso
+    let wsection = section("FactoresDePaso")?;
+    let wdata = data(wsection)
+        .iter()
+        .map(|d| {
+            Ok(Factor {
+                carrier: xml_text(d, "Vector")?.parse()?,
+                source: xml_text(d, "Origen")?.parse()?,
+                dest: xml_text(d, "Destino")?.parse()?,
+                step: xml_text(d, "Paso")?.parse()?,
+                ren: xml_text(d, "ren")?.parse()?,
+                nren: xml_text(d, "nren")?.parse()?,
+                co2: xml_text(d, "co2")?.parse()?,
+                comment: xml_text(d, "Comentario")?,
+            })
+        })
+        .collect::<Result<Vec<Factor>, EpbdError>>()?;
+    let wfactors = Factors {
+        wmeta: xml_meta(xml_elements(wsection, "Metadatos").first().unwrap_or(&""))?,
+        wdata,
+        wseries: Vec::new(),
+    };
+
+    // Componentes
+    let csection = section("Componentes")?;
+    let cdata = data(csection)
+        .iter()
+        .map(|d| {
+            let values = xml_text(d, "Valores")?;
+            let values = if values.is_empty() {
+                Vec::new()
+            } else {
+                values
+                    .split(',')
+                    .map(|v| v.trim().parse::<Real>())
+                    .collect::<Result<Vec<Real>, _>>()?
+            };
+            let id = match xml_elements(d, "Id").first() {
+                Some(id) => id
+                    .trim()
+                    .parse()
+                    .map_err(|_| EpbdError::ParseError(format!("id de sistema \"{}\"", id)))?,
+                None => 0,
+            };
+            let comment = xml_text(d, "Comentario")?;
+            Ok(Component {
+                id,
+                carrier: xml_text(d, "Vector")?.parse()?,
+                ctype: xml_text(d, "Tipo")?.parse()?,
+                csubtype: xml_text(d, "Subtipo")?.parse()?,
+                service: xml_text(d, "Servicio")?.parse()?,
+                aux: xml_elements(d, "Auxiliar")
+                    .first()
+                    .map(|v| v.trim() == "true")
+                    .unwrap_or(false),
+                values,
+                comment,
+            })
+        })
+        .collect::<Result<Vec<Component>, EpbdError>>()?;
+    let mut components = Components {
+        cmeta: xml_meta(xml_elements(csection, "Metadatos").first().unwrap_or(&""))?,
+        cdata,
+        cunknown: Vec::new(),
+    };
+    components.apply_negative_values_with_log(options.negative_values)?;
+    components.check()?;
+
+    let k_exp = xml_text(s, "kexp")?.parse()?;
+    let k_exp_nepb = match xml_elements(s, "kexp_nepb").first() {
+        Some(_) => xml_text(s, "kexp_nepb")?.parse()?,
+        None => k_exp,
+    };
+
+    Ok(BalanceInput {
+        components,
+        wfactors,
+        k_exp,
+        k_exp_nepb,
+        arearef: xml_text(s, "AreaRef")?.parse()?,
+    })
+}
+
+/// Nivel de detalle de la salida del balance en formato XML
+///
+/// Detail level of the XML balance output
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum XmlDetail {
+    /// Input data (weighting factors and components), k_exp, k_exp_nepb, reference area and global indicators (step B)
+    #[default]
+    Basic,
+    /// Basic output plus the blocks of results by carrier, system, adjustment, conversion, storage,
+    /// dwelling, etc, written only when they have data
+    Full,
+}
+
+/// Muestra el balance (paso B) en formato XML
+///
+/// Esta función usa un formato compatible con el formato XML del certificado de eficiencia
+/// energética del edificio definido en el documento de apoyo de la certificación energética
+/// correspondiente. Equivale a `balance_to_xml_with_detail` con el nivel de detalle
+/// `XmlDetail::Basic`.
+///
+/// # Errors
+///
+/// * Datos de los componentes incorrectos para generar los resultados (ver `balance_to_xml_with_detail`)
+pub fn balance_to_xml(balanceobj: &Balance) -> Result<String, EpbdError> {
+    balance_to_xml_with_detail(balanceobj, XmlDetail::Basic)
+}
+
+/// Muestra el balance (paso B) en formato XML con el nivel de detalle indicado
+///
+/// El nivel `XmlDetail::Basic` incluye los datos de entrada (factores de paso y componentes),
+/// los factores de exportación (a la red y a usos no EPB), el área de referencia y los indicadores globales. El nivel
+/// `XmlDetail::Full` añade los bloques de resultados (energía suministrada y exportada por
+/// vector, magnitudes de la EN ISO 52000-1, emisiones, sistemas, ajustes, conversiones,
+/// almacenamiento, autoconsumo, calibración, viviendas e indicadores adicionales), que solo se
+/// escriben cuando tienen datos.
+///
+/// # Errors
+///
+/// * Declaración incorrecta de los sistemas, conversiones, almacenamientos o viviendas de los
+///   componentes (solo con `XmlDetail::Full`)
+pub fn balance_to_xml_with_detail(
+    balanceobj: &Balance,
+    detail: XmlDetail,
+) -> Result<String, EpbdError> {
     let Balance {
         components,
         wfactors,
         k_exp,
+        k_exp_nepb,
         arearef,
         balance_m2,
         ..
@@ -631,16 +2674,6 @@ pub fn balance_to_xml(balanceobj: &Balance) -> String {
     let wmeta = &wfactors.wmeta;
     let wdata = &wfactors.wdata;
 
-    /// Helper function -> XML escape symbols
-    fn escape_xml(unescaped: &str) -> String {
-        unescaped
-            .replace('&', "&amp;")
-            .replace('<', "&lt;")
-            .replace('>', "&gt;")
-            .replace('\\', "&apos;")
-            .replace('"', "&quot;")
-    }
-
     // Formatting
     let wmetastring = wmeta
         .iter()
@@ -686,37 +2719,56 @@ pub fn balance_to_xml(balanceobj: &Balance) -> String {
         .iter()
         .map(|c| {
             let Component {
+                id,
                 carrier,
                 ctype,
                 csubtype,
                 service,
+                aux,
                 values,
                 comment,
+                ..
             } = c;
             let vals = values
                 .iter()
                 .map(|v| format!("{:.2}", v))
                 .collect::<Vec<String>>()
                 .join(",");
+            let id = if *id != 0 {
+                format!("<Id>{}</Id>", id)
+            } else {
+                String::new()
+            };
+            let aux = if *aux {
+                "<Auxiliar>true</Auxiliar>"
+            } else {
+                ""
+            };
             format!(
                 "      <Dato>
-            <Vector>{}</Vector><Tipo>{}</Tipo><Subtipo>{}</Subtipo><Servicio>{}</Servicio>
+            {}<Vector>{}</Vector><Tipo>{}</Tipo><Subtipo>{}</Subtipo><Servicio>{}</Servicio>{}
             <Valores>{}</Valores>
             <Comentario>{}</Comentario>
         </Dato>",
+                id,
                 carrier,
                 ctype,
                 csubtype,
                 service,
+                aux,
                 vals,
                 escape_xml(comment)
             )
         })
         .collect::<Vec<String>>()
         .join("\n");
+    let detailstring = match detail {
+        XmlDetail::Basic => String::new(),
+        XmlDetail::Full => xml_detail_blocks(balanceobj)?,
+    };
 
     // Final assembly
-    format!(
+    Ok(format!(
         "<BalanceEPB>
     <FactoresDePaso>
         <Metadatos>
@@ -735,19 +2787,551 @@ pub fn balance_to_xml(balanceobj: &Balance) -> String {
         </Datos>
     </Componentes>
     <kexp>{:.2}</kexp>
+    <kexp_nepb>{:.2}</kexp_nepb>
     <AreaRef>{:.2}</AreaRef><!-- área de referencia [m2] -->
     <Epm2><!-- C_ep [kWh/m2.an] -->
         <tot>{:.1}</tot>
         <nren>{:.1}</nren>
-    </Epm2>
+    </Epm2>{}
 </BalanceEPB>",
         wmetastring,
         wdatastring,
         cmetastring,
         cdatastring,
         k_exp,
+        k_exp_nepb,
         arearef,
         ren + nren,
-        nren
+        nren,
+        detailstring
+    ))
+}
+
+/// Bloque XML de resultados con sus filas, o texto vacío si no hay filas
+fn xml_block(tag: &str, comment: &str, rows: &[String]) -> String {
+    if rows.is_empty() {
+        return String::new();
+    }
+    let comment = if comment.is_empty() {
+        String::new()
+    } else {
+        format!("<!-- {} -->", comment)
+    };
+    format!(
+        "\n    <{tag}>{comment}\n{rows}\n    </{tag}>",
+        tag = tag,
+        comment = comment,
+        rows = rows.join("\n")
     )
 }
+
+/// Bloques XML de resultados del nivel de detalle `XmlDetail::Full`, solo los que tienen datos
+///
+/// # Errors
+///
+/// * Declaración incorrecta de los sistemas, conversiones, almacenamientos o viviendas de los componentes
+fn xml_detail_blocks(balanceobj: &Balance) -> Result<String, EpbdError> {
+    let emissionsstring = emissions_by_carrier(balanceobj)
+        .iter()
+        .map(|(carrier, co2)| {
+            format!(
+                "        <Dato><Vector>{}</Vector><co2>{:.2}</co2></Dato>",
+                carrier, co2
+            )
+        })
+        .collect::<Vec<String>>();
+    let rnc = |v: RenNrenCo2| {
+        format!(
+            "<ren>{:.2}</ren><nren>{:.2}</nren><co2>{:.2}</co2>",
+            v.ren, v.nren, v.co2
+        )
+    };
+    let summarystring = summary_by_area(balanceobj)
+        .iter()
+        .map(|s| {
+            format!(
+                "        <Dato><Vector>{}</Vector><SuministradaRed>{:.2}</SuministradaRed><SuministradaInSitu>{:.2}</SuministradaInSitu><ExportadaRed>{:.2}</ExportadaRed><ExportadaNoEPB>{:.2}</ExportadaNoEPB><PonderadaSuministradaRed>{}</PonderadaSuministradaRed><PonderadaSuministradaInSitu>{}</PonderadaSuministradaInSitu><PonderadaExportadaPasoA>{}</PonderadaExportadaPasoA><RecursosEvitados>{}</RecursosEvitados><PasoB>{}</PasoB></Dato>",
+                s.carrier,
+                s.delivered_grid,
+                s.delivered_onsite,
+                s.exported_grid,
+                s.exported_nEPB,
+                rnc(s.we_delivered_grid),
+                rnc(s.we_delivered_onsite),
+                rnc(s.we_exported_A),
+                rnc(s.we_exported_avoided),
+                rnc(s.we_B)
+            )
+        })
+        .collect::<Vec<String>>();
+    let deliveredstring = delivered_by_carrier(balanceobj)
+        .iter()
+        .map(|(carrier, values, total)| {
+            let vals = values
+                .iter()
+                .map(|v| format!("{:.2}", v))
+                .collect::<Vec<String>>()
+                .join(",");
+            format!(
+                "        <Dato><Vector>{}</Vector><Valores>{}</Valores><Total>{:.2}</Total></Dato>",
+                carrier, vals, total
+            )
+        })
+        .collect::<Vec<String>>();
+    let isostring = balanceobj
+        .iso_quantities_by_carrier()
+        .iter()
+        .map(|(carrier, quantities)| {
+            let quantities = quantities
+                .iter()
+                .map(|q| {
+                    let vals = q
+                        .values
+                        .iter()
+                        .map(|v| format!("{:.2}", v))
+                        .collect::<Vec<String>>()
+                        .join(",");
+                    format!(
+                        "<Magnitud><Simbolo>{}</Simbolo><Valores>{}</Valores><Total>{:.2}</Total></Magnitud>",
+                        q.symbol, vals, q.annual
+                    )
+                })
+                .collect::<String>();
+            format!(
+                "        <Dato><Vector>{}</Vector>{}</Dato>",
+                carrier, quantities
+            )
+        })
+        .collect::<Vec<String>>();
+    let exportedstring = exported_by_carrier(balanceobj)
+        .iter()
+        .map(|(carrier, we_a, avoided)| {
+            format!(
+                "        <Dato><Vector>{}</Vector><PasoA><ren>{:.2}</ren><nren>{:.2}</nren><co2>{:.2}</co2></PasoA><RecursosEvitados><ren>{:.2}</ren><nren>{:.2}</nren><co2>{:.2}</co2></RecursosEvitados></Dato>",
+                carrier, we_a.ren, we_a.nren, we_a.co2, avoided.ren, avoided.nren, avoided.co2
+            )
+        })
+        .collect::<Vec<String>>();
+    let systemsstring = used_by_system(balanceobj)?
+        .iter()
+        .map(|(s, used, utilization)| {
+            let mut used = used
+                .iter()
+                .map(|(carrier, v)| {
+                    format!(
+                        "<Consumo><Vector>{}</Vector><Valor>{:.2}</Valor></Consumo>",
+                        carrier, v
+                    )
+                })
+                .collect::<String>();
+            if let Some(hours) = utilization.full_load_hours {
+                used.push_str(&format!("<HorasEquivalentes>{:.1}</HorasEquivalentes>", hours));
+            }
+            if let Some(capacity) = utilization.capacity_factor {
+                used.push_str(&format!("<FactorCapacidad>{:.3}</FactorCapacidad>", capacity));
+            }
+            format!(
+                "        <Sistema><Id>{}</Id><Nombre>{}</Nombre><Tipo>{}</Tipo><Vector>{}</Vector><Potencia>{:.2}</Potencia>{}</Sistema>",
+                s.id,
+                escape_xml(&s.name),
+                escape_xml(&s.stype),
+                s.carrier,
+                s.power,
+                used
+            )
+        })
+        .collect::<Vec<String>>();
+    let adjustmentsstring = adjustments(balanceobj)
+        .iter()
+        .map(|(c, v)| {
+            format!(
+                "        <Ajuste><Vector>{}</Vector><Subtipo>{}</Subtipo><Servicio>{}</Servicio><Valor>{:.2}</Valor><Motivo>{}</Motivo></Ajuste>",
+                c.carrier,
+                c.csubtype,
+                c.service,
+                v,
+                escape_xml(&c.comment)
+            )
+        })
+        .collect::<Vec<String>>();
+    let conversionsstring = conversions_by_area(balanceobj)?
+        .iter()
+        .map(|b| {
+            format!(
+                "        <Conversion><Origen>{}</Origen><Destino>{}</Destino><Rendimiento>{:.3}</Rendimiento><Entrada>{:.2}</Entrada><Convertida>{:.2}</Convertida><Aprovechada>{:.2}</Aprovechada><Perdidas>{:.2}</Perdidas><NoAprovechada>{:.2}</NoAprovechada></Conversion>",
+                b.conversion.from,
+                b.conversion.to,
+                b.conversion.efficiency,
+                b.input,
+                b.output,
+                b.used,
+                b.losses,
+                b.unused
+            )
+        })
+        .collect::<Vec<String>>();
+    let storagestring = storages_by_area(balanceobj)?
+        .iter()
+        .map(|(carrier, storage, charged, discharged)| {
+            format!(
+                "        <Dato><Vector>{}</Vector><Capacidad>{:.2}</Capacidad><Rendimiento>{:.3}</Rendimiento><Cargada>{:.2}</Cargada><Descargada>{:.2}</Descargada><Perdidas>{:.2}</Perdidas></Dato>",
+                carrier,
+                storage.capacity,
+                storage.efficiency,
+                charged,
+                discharged,
+                charged - discharged
+            )
+        })
+        .collect::<Vec<String>>();
+    let selfconsumptionstring: Vec<String> = self_consumption_by_area(balanceobj)
+        .map(|s| {
+            format!(
+                "        <Producida>{:.2}</Producida><Autoconsumida>{:.2}</Autoconsumida><Consumida>{:.2}</Consumida><IndiceAutoconsumo>{:.3}</IndiceAutoconsumo><IndiceAutosuficiencia>{:.3}</IndiceAutosuficiencia>",
+                s.produced,
+                s.self_consumed,
+                s.used,
+                s.self_consumption_ratio,
+                s.self_sufficiency_ratio
+            )
+        })
+        .into_iter()
+        .collect();
+    let calibrationstring = calibrated_by_carrier(balanceobj)
+        .iter()
+        .map(|(carrier, factor, used)| {
+            format!(
+                "        <Dato><Vector>{}</Vector><Factor>{:.3}</Factor><ConsumoCalibrado>{:.2}</ConsumoCalibrado></Dato>",
+                carrier, factor, used
+            )
+        })
+        .collect::<Vec<String>>();
+    let dwellingsstring = indicators_by_dwelling(balanceobj)?
+        .iter()
+        .map(|d| {
+            format!(
+                "        <Vivienda><Numero>{}</Numero><Cuota>{:.3}</Cuota><AreaRef>{:.2}</AreaRef><ConsumoEPB>{:.1}</ConsumoEPB><EP_ren>{:.1}</EP_ren><EP_nren>{:.1}</EP_nren><CO2>{:.1}</CO2></Vivienda>",
+                d.number, d.share, d.arearef, d.used_epb, d.we.ren, d.we.nren, d.we.co2
+            )
+        })
+        .collect::<Vec<String>>();
+    let miscstring = balanceobj
+        .misc
+        .iter()
+        .map(|(k, v)| {
+            let v = match v {
+                MiscValue::Number(n) => format!("{:.3}", n),
+                MiscValue::Text(t) => escape_xml(t),
+            };
+            format!(
+                "        <Indicador><Clave>{}</Clave><Valor>{}</Valor></Indicador>",
+                escape_xml(k),
+                v
+            )
+        })
+        .collect::<Vec<String>>();
+
+    Ok([
+        xml_block(
+            "EnergiaSuministrada",
+            "por vector y paso de cálculo [kWh]",
+            &deliveredstring,
+        ),
+        xml_block(
+            "EnergiaExportada",
+            "ponderada por m2, paso A y recursos evitados (paso B) [kWh/m2.an]",
+            &exportedstring,
+        ),
+        xml_block(
+            "MagnitudesISO",
+            "magnitudes intermedias por vector con la notación de EN ISO 52000-1, por paso de cálculo y anual [kWh]",
+            &isostring,
+        ),
+        xml_block(
+            "Emisiones",
+            "por vector, paso B [kg_CO2e/m2.an]",
+            &emissionsstring,
+        ),
+        xml_block(
+            "BalanceVectores",
+            "balance anual por vector [kWh/m2.an] y energía ponderada [kWh/m2.an] y [kg_CO2e/m2.an]",
+            &summarystring,
+        ),
+        xml_block("Sistemas", "consumo por sistema [kWh/m2.an]", &systemsstring),
+        xml_block("Ajustes", "ajustes de consumo [kWh/m2.an]", &adjustmentsstring),
+        xml_block(
+            "Conversiones",
+            "conversiones de energía en el edificio [kWh/m2.an]",
+            &conversionsstring,
+        ),
+        xml_block(
+            "Almacenamiento",
+            "almacenamiento de energía [kWh/m2.an]",
+            &storagestring,
+        ),
+        xml_block(
+            "Autoconsumo",
+            "electricidad producida, autoconsumida y consumida [kWh/m2.an] e índices de autoconsumo y autosuficiencia [-]",
+            &selfconsumptionstring,
+        ),
+        xml_block(
+            "Calibracion",
+            "factores de calibración y consumo calibrado [kWh/m2.an]",
+            &calibrationstring,
+        ),
+        xml_block(
+            "Viviendas",
+            "estimación por cuotas [m2], [kWh/an] y [kg_CO2e/an]",
+            &dwellingsstring,
+        ),
+        xml_block("Indicadores", "", &miscstring),
+    ]
+    .concat())
+}
+
+/// Muestra el balance en formato JSON
+///
+/// Genera un documento con todos los datos del balance (componentes, factores de paso,
+/// metadatos y resultados detallados por vector) al que se añaden los indicadores globales por
+/// m2 (`indicadores`), con los pasos A y B y el desglose por servicios EPB, el resumen anual
+/// por vector por m2 (`resumen_vectores`) y las magnitudes intermedias de cada vector con la
+/// notación de la norma EN ISO 52000-1 (`magnitudes_iso`).
+///
+/// # Errors
+///
+/// * Balance que no se puede convertir a JSON
+pub fn balance_to_json(balance: &Balance) -> Result<String, EpbdError> {
+    let doc = balance_to_value(balance)?;
+    serde_json::to_string_pretty(&doc).map_err(|e| EpbdError::WrongInput(e.to_string()))
+}
+
+/// Muestra el balance en formato JSON canónico, con el número de decimales indicado
+///
+/// El documento tiene el mismo contenido que el generado por `balance_to_json`, pero las claves
+/// de los objetos se ordenan alfabéticamente y los valores numéricos se redondean al número de
+/// decimales indicado, de modo que el mismo balance genera siempre el mismo texto. Esto permite
+/// guardar los resultados en sistemas de control de versiones sin cambios espurios.
+///
+/// # Errors
+///
+/// * Balance que no se puede convertir a JSON
+pub fn balance_to_canonical_json(balance: &Balance, decimals: usize) -> Result<String, EpbdError> {
+    let doc = canonical_value(balance_to_value(balance)?, decimals);
+    serde_json::to_string_pretty(&doc).map_err(|e| EpbdError::WrongInput(e.to_string()))
+}
+
+/// Muestra un valor serializable (p.e. componentes o factores de paso) en formato JSON canónico
+///
+/// Las claves de los objetos se ordenan alfabéticamente y los valores numéricos se redondean al
+/// número de decimales indicado (ver `balance_to_canonical_json`).
+///
+/// # Errors
+///
+/// * Valor que no se puede convertir a JSON
+pub fn to_canonical_json<T: Serialize>(value: &T, decimals: usize) -> Result<String, EpbdError> {
+    let to_err = |e: serde_json::Error| EpbdError::WrongInput(e.to_string());
+    let doc = canonical_value(serde_json::to_value(value).map_err(to_err)?, decimals);
+    serde_json::to_string_pretty(&doc).map_err(to_err)
+}
+
+/// Documento con las claves ordenadas y los números redondeados al número de decimales indicado
+fn canonical_value(value: serde_json::Value, decimals: usize) -> serde_json::Value {
+    use serde_json::Value;
+    match value {
+        Value::Number(n) if n.is_f64() => {
+            let scale = 10f64.powi(decimals as i32);
+            let rounded = (n.as_f64().unwrap_or_default() * scale).round() / scale;
+            // Evita el cero negativo
+            serde_json::json!(if rounded == 0.0 { 0.0 } else { rounded })
+        }
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|v| canonical_value(v, decimals))
+                .collect(),
+        ),
+        Value::Object(map) => {
+            let mut entries: Vec<_> = map.into_iter().collect();
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+            Value::Object(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k, canonical_value(v, decimals)))
+                    .collect(),
+            )
+        }
+        other => other,
+    }
+}
+
+/// Muestra el balance en formato YAML
+///
+/// El documento tiene el mismo contenido que el generado por `balance_to_json`.
+///
+/// # Errors
+///
+/// * Balance que no se puede convertir a YAML
+#[cfg(feature = "yaml")]
+pub fn balance_to_yaml(balance: &Balance) -> Result<String, EpbdError> {
+    let doc = balance_to_value(balance)?;
+    serde_yaml::to_string(&doc).map_err(|e| EpbdError::WrongInput(e.to_string()))
+}
+
+/// Interpreta un balance desde un documento YAML
+///
+/// Admite los documentos generados por `balance_to_yaml`. Los indicadores y resúmenes
+/// añadidos se ignoran, ya que se pueden obtener a partir del propio balance.
+///
+/// # Errors
+///
+/// * Documento YAML incorrecto
+#[cfg(feature = "yaml")]
+pub fn balance_from_yaml(s: &str) -> Result<Balance, EpbdError> {
+    Ok(serde_yaml::from_str(s)?)
+}
+
+/// Documento con los datos del balance y los indicadores globales y por vector
+fn balance_to_value(balance: &Balance) -> Result<serde_json::Value, EpbdError> {
+    let to_err = |e: serde_json::Error| EpbdError::WrongInput(e.to_string());
+    let balance_m2 = &balance.balance_m2;
+    let mut by_service = serde_json::Map::new();
+    for service in &SERVICES {
+        if let Some(used) = balance_m2.used_EPB_byuse.get(service) {
+            let b = balance_m2.B_byuse.get(service).copied().unwrap_or_default();
+            by_service.insert(
+                service.to_string(),
+                serde_json::json!({
+                    "consumo_epb": used,
+                    "ren": b.ren,
+                    "nren": b.nren,
+                    "co2": b.co2,
+                }),
+            );
+        }
+    }
+    let indicators = serde_json::json!({
+        "arearef": balance.arearef,
+        "k_exp": balance.k_exp,
+        "consumo_epb": balance_m2.used_EPB_byuse.values().sum::<Real>(),
+        "paso_A": balance_m2.A,
+        "paso_B": balance_m2.B,
+        "ep_tot": balance_m2.B.tot(),
+        "rer": balance_m2.B.rer(),
+        "servicios": by_service,
+    });
+    let mut doc = serde_json::to_value(balance).map_err(to_err)?;
+    if let serde_json::Value::Object(map) = &mut doc {
+        map.insert("indicadores".to_string(), indicators);
+        map.insert(
+            "resumen_vectores".to_string(),
+            serde_json::to_value(summary_by_area(balance)).map_err(to_err)?,
+        );
+        let iso_quantities: serde_json::Map<String, serde_json::Value> = balance
+            .iso_quantities_by_carrier()
+            .into_iter()
+            .map(|(carrier, quantities)| {
+                Ok((carrier.to_string(), serde_json::to_value(quantities)?))
+            })
+            .collect::<Result<_, serde_json::Error>>()
+            .map_err(to_err)?;
+        map.insert(
+            "magnitudes_iso".to_string(),
+            serde_json::Value::Object(iso_quantities),
+        );
+    }
+    Ok(doc)
+}
+
+/// Muestra el balance (paso B) en formato XML para plataformas de registro de certificados
+///
+/// Genera un documento XML con el espacio de nombres `CTE_REGISTRO_XMLNS`, con los datos
+/// generales obligatorios (localización, área de referencia y factor de exportación),
+/// los indicadores globales por m2 y el desglose por servicios EPB.
+///
+/// # Errors
+///
+/// * Falta la localización (metadato CTE_LOCALIZACION en factores de paso o componentes)
+pub fn balance_to_xml_registro(balance: &Balance) -> Result<String, EpbdError> {
+    let Balance {
+        components,
+        wfactors,
+        k_exp,
+        arearef,
+        balance_m2,
+        misc,
+        ..
+    } = balance;
+
+    let localizacion = wfactors
+        .get_meta("CTE_LOCALIZACION")
+        .or_else(|| components.get_meta("CTE_LOCALIZACION"))
+        .ok_or_else(|| {
+            EpbdError::WrongInput(
+                "falta la localización (CTE_LOCALIZACION), obligatoria para el registro".into(),
+            )
+        })?;
+
+    let b = balance_m2.B;
+    let fraccion_acs = misc
+        .get("fraccion_renovable_demanda_acs_nrb")
+        .and_then(MiscValue::as_real)
+        .map(|v| {
+            format!(
+                "\n        <FraccionRenovableDemandaACS>{:.3}</FraccionRenovableDemandaACS>",
+                v
+            )
+        })
+        .unwrap_or_default();
+
+    let servicios = SERVICES
+        .iter()
+        .filter_map(|s| {
+            let used = balance_m2.used_EPB_byuse.get(s)?;
+            let we = balance_m2.B_byuse.get(s).copied().unwrap_or_default();
+            Some(format!(
+                "        <Servicio codigo=\"{}\">
+            <EnergiaFinal>{:.2}</EnergiaFinal>
+            <EnergiaPrimariaNoRenovable>{:.2}</EnergiaPrimariaNoRenovable>
+            <EnergiaPrimariaTotal>{:.2}</EnergiaPrimariaTotal>
+            <EmisionesCO2>{:.2}</EmisionesCO2>
+        </Servicio>",
+                s,
+                used,
+                we.nren,
+                we.tot(),
+                we.co2
+            ))
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+
+    Ok(format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>
+<RegistroEficienciaEnergetica xmlns=\"{}\" version=\"1.0\">
+    <DatosGenerales>
+        <Localizacion>{}</Localizacion>
+        <AreaReferencia>{:.2}</AreaReferencia>
+        <FactorExportacion>{:.2}</FactorExportacion>
+    </DatosGenerales>
+    <Indicadores><!-- [kWh/m2.an], [kg_CO2e/m2.an] -->
+        <EnergiaPrimariaNoRenovable>{:.2}</EnergiaPrimariaNoRenovable>
+        <EnergiaPrimariaTotal>{:.2}</EnergiaPrimariaTotal>
+        <EmisionesCO2>{:.2}</EmisionesCO2>
+        <RER>{:.3}</RER>{}
+    </Indicadores>
+    <Servicios>
+{}
+    </Servicios>
+</RegistroEficienciaEnergetica>",
+        CTE_REGISTRO_XMLNS,
+        escape_xml(&localizacion),
+        arearef,
+        k_exp,
+        b.nren,
+        b.tot(),
+        b.co2,
+        b.rer(),
+        fraccion_acs,
+        servicios
+    ))
+}
@@ -0,0 +1,344 @@
+// Copyright (c) 2018-2019  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+
+/*!
+Limitación del consumo energético (DB-HE0)
+==========================================
+
+Comprobación de los valores límite de la sección HE0 del DB-HE (2019):
+
+- consumo de energía primaria no renovable, C_ep,nren (tablas 3.1.a-HE0 y 3.1.b-HE0)
+- consumo de energía primaria total, C_ep,tot (tablas 3.2.a-HE0 y 3.2.b-HE0)
+
+Los valores límite dependen de la zona climática de invierno, del uso del edificio (residencial
+privado u otros usos, en función de la carga interna media, CFI) y del tipo de intervención
+(edificios nuevos y ampliaciones o cambios de uso y reformas). En territorio extrapeninsular los
+valores límite se incrementan con un factor que depende del uso y del indicador.
+*/
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::EpbdError,
+    i18n::{tr, tr_format},
+    types::Real,
+    Balance,
+};
+
+use super::CTE_LOCS;
+
+/// Zonas climáticas de invierno, en el orden de las tablas de valores límite
+const WINTER_ZONES: [&str; 6] = ["α", "A", "B", "C", "D", "E"];
+
+/// Valores límite de C_ep,nren para uso residencial privado [kWh/m²·año] (Tabla 3.1.a-HE0)
+///
+/// Filas: edificios nuevos y ampliaciones, cambios de uso y reformas. Columnas: zonas α a E.
+const CEP_NREN_LIM_RESIDENTIAL: [[Real; 6]; 2] = [
+    [20.0, 25.0, 28.0, 32.0, 38.0, 43.0],
+    [40.0, 50.0, 55.0, 65.0, 70.0, 80.0],
+];
+
+/// Término constante de los valores límite de C_ep,nren para otros usos [kWh/m²·año] (Tabla 3.1.b-HE0)
+///
+/// El valor límite es `valor + 8·CFI`.
+const CEP_NREN_LIM_NONRESIDENTIAL: [[Real; 6]; 2] = [
+    [70.0, 55.0, 50.0, 35.0, 20.0, 10.0],
+    [80.0, 60.0, 55.0, 45.0, 30.0, 15.0],
+];
+
+/// Valores límite de C_ep,tot para uso residencial privado [kWh/m²·año] (Tabla 3.2.a-HE0)
+const CEP_TOT_LIM_RESIDENTIAL: [[Real; 6]; 2] = [
+    [40.0, 50.0, 56.0, 64.0, 76.0, 86.0],
+    [55.0, 75.0, 80.0, 90.0, 105.0, 115.0],
+];
+
+/// Término constante de los valores límite de C_ep,tot para otros usos [kWh/m²·año] (Tabla 3.2.b-HE0)
+///
+/// El valor límite es `valor + 9·CFI`.
+const CEP_TOT_LIM_NONRESIDENTIAL: [[Real; 6]; 2] = [
+    [165.0, 155.0, 150.0, 140.0, 130.0, 120.0],
+    [155.0, 150.0, 140.0, 130.0, 120.0, 110.0],
+];
+
+/// Factores de los valores límite en territorio extrapeninsular (C_ep,nren y C_ep,tot)
+const EXTRAPENINSULAR_RESIDENTIAL: (Real, Real) = (1.25, 1.15);
+const EXTRAPENINSULAR_NONRESIDENTIAL: (Real, Real) = (1.40, 1.25);
+
+/// Uso del edificio a efectos de la sección HE0
+///
+/// Building use
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub enum BuildingUse {
+    /// Uso residencial privado
+    Residential,
+    /// Otros usos, con su carga interna media (CFI) [W/m²]
+    NonResidential {
+        /// Mean internal load (CFI) [W/m²]
+        cfi: Real,
+    },
+}
+
+/// Tipo de intervención a efectos de la sección HE0
+///
+/// Intervention type
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Intervention {
+    /// Edificios nuevos y ampliaciones
+    New,
+    /// Cambios de uso y reformas
+    Reform,
+}
+
+/// Comprobación de un indicador frente a su valor límite
+///
+/// Check of an indicator against its limit value
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct He0Check {
+    /// Indicator name (`C_ep,nren` or `C_ep,tot`)
+    pub indicator: String,
+    /// Indicator value [kWh/m²·año]
+    pub value: Real,
+    /// Limit value [kWh/m²·año]
+    pub limit: Real,
+    /// Margin to the limit value (limit - value) [kWh/m²·año]
+    pub margin: Real,
+    /// Whether the indicator complies with the limit
+    pub passes: bool,
+}
+
+impl He0Check {
+    fn new(indicator: &str, value: Real, limit: Real) -> Self {
+        He0Check {
+            indicator: indicator.to_string(),
+            value,
+            limit,
+            margin: limit - value,
+            passes: value <= limit,
+        }
+    }
+}
+
+impl fmt::Display for He0Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&tr_format!(
+            "{}: {:.2} kWh/m²·año, límite {:.2} kWh/m²·año, margen {:.2} kWh/m²·año: {}",
+            self.indicator,
+            self.value,
+            self.limit,
+            self.margin,
+            tr(if self.passes { "CUMPLE" } else { "NO CUMPLE" })
+        ))
+    }
+}
+
+/// Informe de cumplimiento de la sección HE0
+///
+/// HE0 compliance report
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct He0Report {
+    /// Winter climate zone (α, A, B, C, D, E)
+    pub winter_zone: String,
+    /// Location (PENINSULA, BALEARES, CANARIAS, CEUTAMELILLA)
+    pub location: String,
+    /// Building use
+    pub building_use: BuildingUse,
+    /// Intervention type
+    pub intervention: Intervention,
+    /// Non renewable primary energy check (C_ep,nren)
+    pub ep_nren: He0Check,
+    /// Total primary energy check (C_ep,tot)
+    pub ep_tot: He0Check,
+}
+
+impl He0Report {
+    /// Indica si se cumplen todos los valores límite
+    pub fn passes(&self) -> bool {
+        self.ep_nren.passes && self.ep_tot.passes
+    }
+}
+
+impl fmt::Display for He0Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&tr_format!(
+            "HE0 (zona {}, {}): {}\n- {}\n- {}",
+            self.winter_zone,
+            self.location,
+            tr(if self.passes() { "CUMPLE" } else { "NO CUMPLE" }),
+            self.ep_nren,
+            self.ep_tot
+        ))
+    }
+}
+
+/// Zona climática de invierno de una zona climática (p.e. `D3` -> `D`, `α3` -> `α`, `A2c` -> `A`)
+pub fn winter_zone(zone: &str) -> Result<&'static str, EpbdError> {
+    let zone = zone.trim();
+    let first = if zone.to_lowercase().starts_with("alfa") {
+        "α".to_string()
+    } else {
+        zone.chars()
+            .next()
+            .map(|c| c.to_string())
+            .unwrap_or_default()
+    };
+    WINTER_ZONES
+        .iter()
+        .find(|z| **z == first)
+        .copied()
+        .ok_or_else(|| EpbdError::WrongInput(format!("Zona climática desconocida: {}", zone)))
+}
+
+/// Valores límite de C_ep,nren y C_ep,tot [kWh/m²·año]
+///
+/// * `zone` - zona climática (p.e. `D3`) o de invierno (p.e. `D`)
+/// * `location` - localización (PENINSULA, BALEARES, CANARIAS, CEUTAMELILLA)
+/// * `building_use` - uso del edificio
+/// * `intervention` - tipo de intervención
+pub fn he0_limits(
+    zone: &str,
+    location: &str,
+    building_use: BuildingUse,
+    intervention: Intervention,
+) -> Result<(Real, Real), EpbdError> {
+    if !CTE_LOCS.contains(&location) {
+        return Err(EpbdError::WrongInput(format!(
+            "Localización desconocida: {}",
+            location
+        )));
+    }
+    let winter_zone = winter_zone(zone)?;
+    let col = WINTER_ZONES.iter().position(|z| *z == winter_zone).unwrap();
+    let row = match intervention {
+        Intervention::New => 0,
+        Intervention::Reform => 1,
+    };
+    let extrapeninsular = location != "PENINSULA";
+    let ((nren_lim, tot_lim), (k_nren, k_tot)) = match building_use {
+        BuildingUse::Residential => (
+            (
+                CEP_NREN_LIM_RESIDENTIAL[row][col],
+                CEP_TOT_LIM_RESIDENTIAL[row][col],
+            ),
+            EXTRAPENINSULAR_RESIDENTIAL,
+        ),
+        BuildingUse::NonResidential { cfi } => {
+            if cfi < 0.0 {
+                return Err(EpbdError::WrongInput(format!(
+                    "La carga interna media no puede ser negativa y se encontró {}",
+                    cfi
+                )));
+            }
+            (
+                (
+                    CEP_NREN_LIM_NONRESIDENTIAL[row][col] + 8.0 * cfi,
+                    CEP_TOT_LIM_NONRESIDENTIAL[row][col] + 9.0 * cfi,
+                ),
+                EXTRAPENINSULAR_NONRESIDENTIAL,
+            )
+        }
+    };
+    if extrapeninsular {
+        Ok((nren_lim * k_nren, tot_lim * k_tot))
+    } else {
+        Ok((nren_lim, tot_lim))
+    }
+}
+
+/// Comprueba los valores límite de la sección HE0 para un balance
+///
+/// Se comparan los consumos de energía primaria no renovable y total por unidad de área de
+/// referencia del balance (paso B) con sus valores límite (ver `he0_limits`).
+pub fn check_he0(
+    balance: &Balance,
+    zone: &str,
+    location: &str,
+    building_use: BuildingUse,
+    intervention: Intervention,
+) -> Result<He0Report, EpbdError> {
+    let (nren_lim, tot_lim) = he0_limits(zone, location, building_use, intervention)?;
+    let ep = balance.balance_m2.B;
+    Ok(He0Report {
+        winter_zone: winter_zone(zone)?.to_string(),
+        location: location.to_string(),
+        building_use,
+        intervention,
+        ep_nren: He0Check::new("C_ep,nren", ep.nren, nren_lim),
+        ep_tot: He0Check::new("C_ep,tot", ep.tot(), tot_lim),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn he0_winter_zone() {
+        assert_eq!(winter_zone("D3").unwrap(), "D");
+        assert_eq!(winter_zone("α3").unwrap(), "α");
+        assert_eq!(winter_zone("alfa3").unwrap(), "α");
+        assert_eq!(winter_zone("A2c").unwrap(), "A");
+        assert!(winter_zone("F1").is_err());
+        assert!(winter_zone("").is_err());
+    }
+
+    #[test]
+    fn he0_limits_tables() {
+        use BuildingUse::*;
+        use Intervention::*;
+        assert_eq!(
+            he0_limits("D3", "PENINSULA", Residential, New).unwrap(),
+            (38.0, 76.0)
+        );
+        assert_eq!(
+            he0_limits("E1", "PENINSULA", Residential, Reform).unwrap(),
+            (80.0, 115.0)
+        );
+        assert_eq!(
+            he0_limits("B3", "PENINSULA", NonResidential { cfi: 5.0 }, New).unwrap(),
+            (90.0, 195.0)
+        );
+        // Territorio extrapeninsular
+        assert_eq!(
+            he0_limits("C3", "BALEARES", Residential, New).unwrap(),
+            (40.0, 73.6)
+        );
+        assert!(he0_limits("D3", "MARTE", Residential, New).is_err());
+        assert!(he0_limits("D3", "PENINSULA", NonResidential { cfi: -1.0 }, New).is_err());
+    }
+
+    #[test]
+    fn he0_check_report() {
+        let check = He0Check::new("C_ep,nren", 30.0, 38.0);
+        assert!(check.passes);
+        assert_eq!(check.margin, 8.0);
+        assert_eq!(
+            check.to_string(),
+            "C_ep,nren: 30.00 kWh/m²·año, límite 38.00 kWh/m²·año, margen 8.00 kWh/m²·año: CUMPLE"
+        );
+        assert!(!He0Check::new("C_ep,tot", 80.0, 76.0).passes);
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2018-2019  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+
+/*!
+Contribución renovable mínima para ACS (DB-HE4)
+===============================================
+
+Comprobación de la contribución renovable mínima para cubrir la demanda de agua caliente
+sanitaria de la sección HE4 del DB-HE (2019):
+
+- la demanda de ACS cubierta con energía procedente de fuentes renovables (perímetro próximo)
+  debe ser al menos el 70% de la demanda anual de ACS
+- el porcentaje mínimo se reduce al 60% cuando la demanda de ACS es inferior a 5.000 l/d
+
+La fracción renovable de la demanda de ACS se calcula con `fraccion_renovable_acs_nrb`.
+*/
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    error::EpbdError,
+    i18n::{tr, tr_format},
+    types::{MetaVec, Real},
+    Balance,
+};
+
+use super::fraccion_renovable_acs_nrb;
+
+/// Contribución renovable mínima general para ACS (fracción de la demanda anual)
+pub const HE4_MIN_RENEWABLE_FRACTION: Real = 0.70;
+/// Contribución renovable mínima para ACS con demanda inferior a `HE4_LOW_DEMAND` (fracción de la demanda anual)
+pub const HE4_MIN_RENEWABLE_FRACTION_LOW_DEMAND: Real = 0.60;
+/// Demanda de ACS por debajo de la cual se reduce la contribución renovable mínima [l/d]
+pub const HE4_LOW_DEMAND: Real = 5000.0;
+
+/// Informe de cumplimiento de la sección HE4
+///
+/// HE4 compliance report
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct He4Report {
+    /// Daily DHW demand [l/d]
+    pub daily_demand: Real,
+    /// Annual DHW demand [kWh/año]
+    pub annual_demand: Real,
+    /// Renewable fraction of the annual DHW demand (nearby perimeter) [-]
+    pub renewable_fraction: Real,
+    /// Minimum renewable fraction [-]
+    pub min_renewable_fraction: Real,
+    /// Margin to the minimum (renewable_fraction - min_renewable_fraction) [-]
+    pub margin: Real,
+    /// Whether the renewable contribution complies with the minimum
+    pub passes: bool,
+}
+
+impl fmt::Display for He4Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&tr_format!(
+            "HE4 (demanda de ACS {:.0} l/d): contribución renovable {:.1}%, mínimo {:.1}%, margen {:.1}%: {}",
+            self.daily_demand,
+            self.renewable_fraction * 100.0,
+            self.min_renewable_fraction * 100.0,
+            self.margin * 100.0,
+            tr(if self.passes { "CUMPLE" } else { "NO CUMPLE" })
+        ))
+    }
+}
+
+/// Contribución renovable mínima para ACS según la demanda diaria de ACS [l/d]
+pub fn he4_min_renewable_fraction(daily_demand: Real) -> Real {
+    if daily_demand < HE4_LOW_DEMAND {
+        HE4_MIN_RENEWABLE_FRACTION_LOW_DEMAND
+    } else {
+        HE4_MIN_RENEWABLE_FRACTION
+    }
+}
+
+/// Comprueba la contribución renovable mínima para ACS de la sección HE4 para un balance
+///
+/// La demanda anual de ACS [kWh/año] se obtiene de los indicadores adicionales del balance
+/// (`demanda_anual_acs`, ver `incorpora_demanda_renovable_acs_nrb`) o del metadato
+/// `CTE_ACS_DEMANDA_ANUAL` de los componentes.
+///
+/// * `daily_demand` - demanda diaria de ACS [l/d]
+///
+/// # Errors
+///
+/// * Demanda anual de ACS no definida o no válida
+/// * Casos en los que no se puede calcular la fracción renovable (ver `fraccion_renovable_acs_nrb`)
+pub fn check_he4(balance: &Balance, daily_demand: Real) -> Result<He4Report, EpbdError> {
+    let annual_demand = match balance
+        .misc
+        .get("demanda_anual_acs")
+        .and_then(|v| v.as_real())
+    {
+        Some(demand) => demand,
+        None => balance
+            .components
+            .get_real("CTE_ACS_DEMANDA_ANUAL")?
+            .ok_or_else(|| {
+                EpbdError::WrongInput(
+                    "No se ha definido la demanda anual de ACS (metadato CTE_ACS_DEMANDA_ANUAL)"
+                        .to_string(),
+                )
+            })?,
+    };
+    if annual_demand <= 0.0 || daily_demand < 0.0 {
+        return Err(EpbdError::WrongInput(format!(
+            "Demanda de ACS no válida: {} kWh/año, {} l/d",
+            annual_demand, daily_demand
+        )));
+    }
+    let renewable_fraction =
+        fraccion_renovable_acs_nrb(&balance.components, &balance.wfactors, annual_demand)?;
+    let min_renewable_fraction = he4_min_renewable_fraction(daily_demand);
+    Ok(He4Report {
+        daily_demand,
+        annual_demand,
+        renewable_fraction,
+        min_renewable_fraction,
+        margin: renewable_fraction - min_renewable_fraction,
+        passes: renewable_fraction >= min_renewable_fraction,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn he4_min_fraction() {
+        assert_eq!(he4_min_renewable_fraction(1000.0), 0.60);
+        assert_eq!(he4_min_renewable_fraction(5000.0), 0.70);
+        assert_eq!(he4_min_renewable_fraction(12000.0), 0.70);
+    }
+}
@@ -28,10 +28,31 @@ Gestión de errores (error handling)
 ===================================
 
 Tipos y funciones para la gestión de errores
+
+Cada error tiene un código estable, accesible mediante `EpbdError::code`, que permite
+a las aplicaciones asociarle un mensaje localizado:
+
+- `E101`: factor de paso no encontrado (`MissingFactor`)
+- `E102`: datos incoherentes durante el cálculo del balance (`BalanceError`)
+- `E201`: error al interpretar un valor o línea de datos (`ParseError` y `ParseLineError`)
+- `E301`: valor de entrada incorrecto (`WrongInput`)
+
+Los errores incluyen los datos necesarios para tratarlos sin analizar el mensaje, como la
+posición y el texto no interpretado de una línea de datos (`ParseLineError`) o la clave del
+factor de paso no encontrado (`MissingFactor`).
+
+Las situaciones no fatales, como las correcciones automáticas de los datos, se comunican
+mediante avisos (`Warning`), que también disponen de un código estable (`Warning::code`).
 */
 
 use std::fmt;
 
+use serde::{Deserialize, Serialize};
+
+use crate::i18n::{tr, tr_format};
+use crate::types::{CSubtype, Carrier, Dest, Factor, Service, Source, Step};
+use crate::{ComponentWarning, StripReason};
+
 /// Resultado que usa el tipo de error personalizado
 pub type Result<T> = std::result::Result<T, EpbdError>;
 
@@ -40,10 +61,91 @@ pub type Result<T> = std::result::Result<T, EpbdError>;
 pub enum EpbdError {
     /// Error al interpretar un valor
     ParseError(String),
+    /// Error al interpretar una línea de datos
+    ParseLineError {
+        /// Line number (starting at 1)
+        line: usize,
+        /// Column (starting at 1) of the offending token
+        column: usize,
+        /// Offending token (or whole line if it can't be identified)
+        token: String,
+    },
     /// Error para un valor de entrada incorrecto (formato o rango incorrecto)
     WrongInput(String),
     /// Error cuando falta un factor de conversión
-    MissingFactor(String),
+    MissingFactor {
+        /// Energy carrier
+        carrier: Carrier,
+        /// Energy source
+        source: Source,
+        /// Energy destination
+        dest: Dest,
+        /// Calculation step
+        step: Step,
+    },
+    /// Error por datos incoherentes durante el cálculo del balance
+    BalanceError(String),
+}
+
+impl EpbdError {
+    /// Código estable del error
+    ///
+    /// Stable error code, suitable for mapping to localized messages
+    pub fn code(&self) -> &'static str {
+        use EpbdError::*;
+        match self {
+            MissingFactor { .. } => "E101",
+            BalanceError(_) => "E102",
+            ParseError(_) | ParseLineError { .. } => "E201",
+            WrongInput(_) => "E301",
+        }
+    }
+
+    /// Línea de datos en la que se produce el error, si se conoce
+    pub fn line(&self) -> Option<usize> {
+        match self {
+            EpbdError::ParseLineError { line, .. } => Some(*line),
+            _ => None,
+        }
+    }
+
+    /// Sitúa un error de interpretación en una línea de datos
+    ///
+    /// Los errores de interpretación (`ParseError`) se convierten en errores con posición
+    /// (`ParseLineError`), usando la columna del texto no interpretado en la línea `text`.
+    /// El resto de errores no se modifican.
+    pub fn at_line(self, line: usize, text: &str) -> Self {
+        match self {
+            EpbdError::ParseError(token) => {
+                let column = text.find(token.as_str()).map_or(1, |pos| pos + 1);
+                EpbdError::ParseLineError {
+                    line,
+                    column,
+                    token,
+                }
+            }
+            error => error,
+        }
+    }
+
+    /// Desplaza la línea de un error de interpretación con posición
+    ///
+    /// Permite situar los errores de una sección en el texto completo (p.e. la sección de
+    /// factores de paso de un archivo de componentes).
+    pub fn with_line_offset(self, offset: usize) -> Self {
+        match self {
+            EpbdError::ParseLineError {
+                line,
+                column,
+                token,
+            } => EpbdError::ParseLineError {
+                line: line + offset,
+                column,
+                token,
+            },
+            error => error,
+        }
+    }
 }
 
 impl fmt::Display for EpbdError {
@@ -51,8 +153,27 @@ impl fmt::Display for EpbdError {
         use EpbdError::*;
         match self {
             ParseError(v) => write!(f, "No se ha podido interpretar {}", v),
+            ParseLineError {
+                line,
+                column,
+                token,
+            } => write!(
+                f,
+                "No se ha podido interpretar {} (línea {}, columna {})",
+                token, line, column
+            ),
             WrongInput(v) => write!(f, "Valor de entrada incorrecto: {}", v),
-            MissingFactor(v) => write!(f, "Factor de paso no encontrado: {}", v),
+            MissingFactor {
+                carrier,
+                source,
+                dest,
+                step,
+            } => write!(
+                f,
+                "Factor de paso no encontrado: {}, {}, {}, {}",
+                carrier, source, dest, step
+            ),
+            BalanceError(v) => write!(f, "Error en el cálculo del balance: {}", v),
         }
     }
 }
@@ -64,3 +185,240 @@ impl From<std::num::ParseFloatError> for EpbdError {
         EpbdError::ParseError(err.to_string())
     }
 }
+
+impl From<serde_json::Error> for EpbdError {
+    fn from(err: serde_json::Error) -> Self {
+        EpbdError::ParseError(err.to_string())
+    }
+}
+
+/// Problema detectado al validar un archivo de datos de entrada
+///
+/// Problem found when validating an input data file (see `validate_components` and `validate_wfactors`)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    /// Line number (starting at 1), if the problem is linked to a line
+    pub line: Option<usize>,
+    /// Stable error code (see `EpbdError::code`)
+    pub code: String,
+    /// Error message
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Diagnóstico a partir de un error, asociado o no a una línea
+    ///
+    /// Si no se indica la línea se usa la del error, si la tiene.
+    pub fn new(line: Option<usize>, error: &EpbdError) -> Self {
+        Diagnostic {
+            line: line.or_else(|| error.line()),
+            code: error.code().to_string(),
+            message: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "línea {}: {}", line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// Aviso no fatal producido al interpretar, corregir o calcular los datos
+///
+/// Non-fatal warning, such as an automatic correction of the input data
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum Warning {
+    /// Aviso sobre la verosimilitud de los componentes
+    Component(ComponentWarning),
+    /// Producción de electricidad con un servicio distinto de NDEF, asignada a NDEF
+    ProductionServiceToNdef(Service),
+    /// Producción de MEDIOAMBIENTE generada para equilibrar el consumo de un servicio
+    GeneratedEnvProduction(Service),
+    /// Factor de paso añadido automáticamente
+    AddedFactor(Factor),
+    /// Factor de paso con valores sustituidos automáticamente
+    ReplacedFactor {
+        /// Original factor
+        old: Factor,
+        /// Factor with the new values
+        new: Factor,
+    },
+    /// Factor de paso eliminado por no usarse en los componentes
+    StrippedFactor {
+        /// Removed factor
+        factor: Factor,
+        /// Reason for the removal
+        reason: StripReason,
+    },
+    /// Consumo de recarga de vehículos eléctricos reclasificado como EPB o NEPB
+    EvChargingSubtype(CSubtype),
+    /// Valores negativos de un componente de consumo o producción sustituidos por cero
+    ClampedNegativeValues {
+        /// Component identifier (carrier, type, subtype and service)
+        component: String,
+        /// Number of negative values
+        count: usize,
+    },
+    /// Metadato con valores distintos al combinar componentes, se conserva el primero
+    MetaConflict {
+        /// Metadata key
+        key: String,
+        /// Value kept
+        kept: String,
+        /// Value discarded
+        discarded: String,
+    },
+    /// Prioridad de servicios sin compensación servicio a servicio, que no se aplica al balance
+    UnusedServicePriority,
+    /// Metadato con un valor incorrecto, que se ignora y se usa el valor por defecto
+    InvalidMeta {
+        /// Metadata key
+        key: String,
+        /// Invalid value
+        value: String,
+    },
+}
+
+impl Warning {
+    /// Código estable del aviso
+    pub fn code(&self) -> &'static str {
+        match self {
+            Warning::Component(w) => w.code(),
+            Warning::ProductionServiceToNdef(_) => "W301",
+            Warning::GeneratedEnvProduction(_) => "W302",
+            Warning::AddedFactor(_) => "W303",
+            Warning::ReplacedFactor { .. } => "W304",
+            Warning::StrippedFactor { .. } => "W305",
+            Warning::EvChargingSubtype(_) => "W306",
+            Warning::MetaConflict { .. } => "W307",
+            Warning::ClampedNegativeValues { .. } => "W308",
+            Warning::UnusedServicePriority => "W309",
+            Warning::InvalidMeta { .. } => "W310",
+        }
+    }
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            Warning::Component(w) => w.to_string(),
+            Warning::ProductionServiceToNdef(service) => tr_format!(
+                "producción de ELECTRICIDAD con servicio {} asignada al servicio NDEF",
+                service
+            ),
+            Warning::GeneratedEnvProduction(service) => tr_format!(
+                "producción de MEDIOAMBIENTE generada para equilibrar el consumo del servicio {}",
+                service
+            ),
+            Warning::AddedFactor(factor) => tr_format!("factor de paso añadido: {}", factor),
+            Warning::ReplacedFactor { old, new } => tr_format!(
+                "factor de paso {}, {}, {}, {} sustituido: {:.3}, {:.3}, {:.3} -> {:.3}, {:.3}, {:.3}",
+                old.carrier,
+                old.source,
+                old.dest,
+                old.step,
+                old.ren,
+                old.nren,
+                old.co2,
+                new.ren,
+                new.nren,
+                new.co2
+            ),
+            Warning::StrippedFactor { factor, reason } => tr_format!(
+                "factor de paso eliminado por no usarse ({}): {}",
+                reason,
+                factor
+            ),
+            Warning::EvChargingSubtype(csubtype) => tr_format!(
+                "consumo de recarga de vehículos eléctricos (RECARGAVE) considerado {}",
+                csubtype
+            ),
+            Warning::ClampedNegativeValues { component, count } => tr_format!(
+                "{} valores negativos sustituidos por cero en el componente {}",
+                count,
+                component
+            ),
+            Warning::MetaConflict {
+                key,
+                kept,
+                discarded,
+            } => tr_format!(
+                "metadato {} con valores distintos al combinar componentes: se conserva \"{}\" y se descarta \"{}\"",
+                key,
+                kept,
+                discarded
+            ),
+            Warning::UnusedServicePriority => tr(
+                "prioridad de servicios (CTE_PRIORIDAD_SERVICIOS) sin compensación servicio a servicio (CTE_COMPENSACION_SERVICIOS): no se aplica al balance y solo se usa al repartir la producción eléctrica por servicio",
+            )
+            .to_string(),
+            Warning::InvalidMeta { key, value } => tr_format!(
+                "valor incorrecto del metadato {}: \"{}\", se usa el valor por defecto",
+                key,
+                value
+            ),
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+impl From<ComponentWarning> for Warning {
+    fn from(warning: ComponentWarning) -> Self {
+        Warning::Component(warning)
+    }
+}
+
+#[cfg(feature = "yaml")]
+impl From<serde_yaml::Error> for EpbdError {
+    fn from(err: serde_yaml::Error) -> Self {
+        EpbdError::ParseError(err.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn error_codes() {
+        let missing = EpbdError::MissingFactor {
+            carrier: Carrier::ELECTRICIDAD,
+            source: Source::RED,
+            dest: Dest::SUMINISTRO,
+            step: Step::A,
+        };
+        assert_eq!(missing.code(), "E101");
+        assert_eq!(
+            missing.to_string(),
+            "Factor de paso no encontrado: ELECTRICIDAD, RED, SUMINISTRO, A"
+        );
+        assert_eq!(EpbdError::BalanceError("x".into()).code(), "E102");
+        assert_eq!(EpbdError::ParseError("x".into()).code(), "E201");
+        assert_eq!(EpbdError::WrongInput("x".into()).code(), "E301");
+    }
+
+    #[test]
+    fn error_location() {
+        let err = EpbdError::ParseError("XXX".into()).at_line(3, "ELECTRICIDAD, XXX, EPB");
+        assert_eq!(err.code(), "E201");
+        assert_eq!(err.line(), Some(3));
+        match err.with_line_offset(10) {
+            EpbdError::ParseLineError {
+                line,
+                column,
+                token,
+            } => {
+                assert_eq!((line, column, token.as_str()), (13, 15, "XXX"));
+            }
+            _ => panic!("error sin posición"),
+        }
+        // Otros errores no cambian
+        let err = EpbdError::WrongInput("x".into()).at_line(3, "x");
+        assert_eq!(err.line(), None);
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright (c) 2018-2019  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Interfaz C (ffi)
+================
+
+Interfaz `extern "C"` estable del núcleo de cálculo, para usar la librería desde aplicaciones
+escritas en otros lenguajes sin tener que ejecutar el programa `cteepbd`.
+
+Los componentes, los factores de paso y el balance se manejan como punteros opacos, creados
+por las funciones `cteepbd_parse_components`, `cteepbd_parse_wfactors` y
+`cteepbd_energy_performance`, y que deben liberarse con la función `*_free` correspondiente.
+Las cadenas de texto se intercambian en UTF-8 terminadas en nulo y las cadenas devueltas por la
+librería deben liberarse con `cteepbd_string_free`.
+
+Cuando una función falla devuelve un puntero nulo y el error queda disponible, en el hilo que
+realizó la llamada, con `cteepbd_last_error_code` (p.e. `E201`) y `cteepbd_last_error_message`.
+
+La cabecera C correspondiente se encuentra en `include/cteepbd.h`.
+
+Esta funcionalidad está disponible con la característica opcional `ffi`. Para obtener una
+biblioteca enlazable se indica el tipo de *crate* al compilar, con el perfil `ffi`:
+
+```text
+$ cargo rustc --lib --profile ffi --features ffi --crate-type cdylib
+```
+
+El perfil `ffi` es el de `release` con `panic = "unwind"`. Los errores internos (pánicos) solo
+se capturan y devuelven como error con ese perfil: con `panic = "abort"` (perfil `release`)
+abortan la aplicación que usa la librería.
+*/
+
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_double};
+use std::panic::{catch_unwind, UnwindSafe};
+use std::ptr;
+
+use crate::{
+    balance::{energy_performance, Balance},
+    components::{parse_components, Components, ParseOptions},
+    cte::{balance_to_json, CTE_USERWF},
+    error::{EpbdError, Result},
+    types::Real,
+    wfactors::Factors,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<EpbdError>> = const { RefCell::new(None) };
+}
+
+/// Guarda el error para su consulta desde `cteepbd_last_error_*`
+fn set_last_error(err: EpbdError) {
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(err));
+}
+
+/// Ejecuta la función, guarda el error (o el pánico) producido y devuelve un valor nulo en ese caso
+///
+/// Los pánicos solo se capturan si la librería se compila con `panic = "unwind"` (perfil `ffi`).
+fn guard<T, F: FnOnce() -> Result<T> + UnwindSafe>(f: F) -> Option<T> {
+    LAST_ERROR.with(|e| *e.borrow_mut() = None);
+    match catch_unwind(f) {
+        Ok(Ok(value)) => Some(value),
+        Ok(Err(err)) => {
+            set_last_error(err);
+            None
+        }
+        Err(_) => {
+            set_last_error(EpbdError::WrongInput(
+                "error interno de la librería".to_string(),
+            ));
+            None
+        }
+    }
+}
+
+/// Cadena de Rust a partir de una cadena C
+///
+/// # Safety
+///
+/// `s` debe ser nulo o apuntar a una cadena terminada en nulo válida
+unsafe fn str_from_c<'a>(s: *const c_char) -> Result<&'a str> {
+    if s.is_null() {
+        return Err(EpbdError::WrongInput("cadena nula".to_string()));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|e| EpbdError::WrongInput(format!("cadena UTF-8 incorrecta: {}", e)))
+}
+
+/// Referencia a un objeto a partir de un puntero opaco
+///
+/// # Safety
+///
+/// `p` debe ser nulo o un puntero devuelto por esta librería y no liberado
+unsafe fn ref_from_ptr<'a, T>(p: *const T, name: &str) -> Result<&'a T> {
+    p.as_ref()
+        .ok_or_else(|| EpbdError::WrongInput(format!("puntero nulo de {}", name)))
+}
+
+/// Cadena C, propiedad del llamante, a partir de una cadena de Rust
+fn string_to_c(s: String) -> Result<*mut c_char> {
+    CString::new(s)
+        .map(CString::into_raw)
+        .map_err(|e| EpbdError::WrongInput(e.to_string()))
+}
+
+/// Convierte un objeto a JSON
+fn to_json<T: serde::Serialize>(value: &T) -> Result<String> {
+    serde_json::to_string_pretty(value).map_err(|e| EpbdError::WrongInput(e.to_string()))
+}
+
+/// Interpreta y normaliza los componentes energéticos
+///
+/// Se ignora la sección de factores de paso incluidos en el archivo, si existe.
+/// Devuelve un puntero nulo en caso de error.
+///
+/// # Safety
+///
+/// `s` debe ser una cadena UTF-8 terminada en nulo válida
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_parse_components(s: *const c_char) -> *mut Components {
+    guard(|| {
+        let (components, _) = parse_components(str_from_c(s)?, &ParseOptions::default())?;
+        Ok(Box::into_raw(Box::new(components.normalize())))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Libera los componentes energéticos
+///
+/// # Safety
+///
+/// `components` debe ser nulo o un puntero devuelto por `cteepbd_parse_components` no liberado
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_components_free(components: *mut Components) {
+    if !components.is_null() {
+        drop(Box::from_raw(components));
+    }
+}
+
+/// Interpreta y normaliza los factores de paso
+///
+/// Los factores de paso de usuario no definidos toman los valores por defecto
+/// (`cte::CTE_USERWF`). Devuelve un puntero nulo en caso de error.
+///
+/// # Safety
+///
+/// `s` debe ser una cadena UTF-8 terminada en nulo válida
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_parse_wfactors(s: *const c_char) -> *mut Factors {
+    guard(|| {
+        let wfactors = str_from_c(s)?.parse::<Factors>()?.normalize(&CTE_USERWF)?;
+        Ok(Box::into_raw(Box::new(wfactors)))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Libera los factores de paso
+///
+/// # Safety
+///
+/// `wfactors` debe ser nulo o un puntero devuelto por `cteepbd_parse_wfactors` no liberado
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_wfactors_free(wfactors: *mut Factors) {
+    if !wfactors.is_null() {
+        drop(Box::from_raw(wfactors));
+    }
+}
+
+/// Calcula el balance energético (ver `energy_performance`)
+///
+/// Devuelve un puntero nulo en caso de error.
+///
+/// # Safety
+///
+/// `components` y `wfactors` deben ser punteros devueltos por esta librería y no liberados
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_energy_performance(
+    components: *const Components,
+    wfactors: *const Factors,
+    k_exp: c_double,
+    arearef: c_double,
+) -> *mut Balance {
+    guard(|| {
+        let components = ref_from_ptr(components, "componentes")?;
+        let wfactors = ref_from_ptr(wfactors, "factores de paso")?;
+        let balance = energy_performance(components, wfactors, k_exp as Real, arearef as Real)?;
+        Ok(Box::into_raw(Box::new(balance)))
+    })
+    .unwrap_or(ptr::null_mut())
+}
+
+/// Libera el balance energético
+///
+/// # Safety
+///
+/// `balance` debe ser nulo o un puntero devuelto por `cteepbd_energy_performance` no liberado
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_balance_free(balance: *mut Balance) {
+    if !balance.is_null() {
+        drop(Box::from_raw(balance));
+    }
+}
+
+/// Componentes energéticos en formato JSON
+///
+/// La cadena devuelta debe liberarse con `cteepbd_string_free`. Devuelve un puntero nulo en
+/// caso de error.
+///
+/// # Safety
+///
+/// `components` debe ser un puntero devuelto por `cteepbd_parse_components` no liberado
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_components_to_json(components: *const Components) -> *mut c_char {
+    guard(|| string_to_c(to_json(ref_from_ptr(components, "componentes")?)?))
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Factores de paso en formato JSON
+///
+/// La cadena devuelta debe liberarse con `cteepbd_string_free`. Devuelve un puntero nulo en
+/// caso de error.
+///
+/// # Safety
+///
+/// `wfactors` debe ser un puntero devuelto por `cteepbd_parse_wfactors` no liberado
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_wfactors_to_json(wfactors: *const Factors) -> *mut c_char {
+    guard(|| string_to_c(to_json(ref_from_ptr(wfactors, "factores de paso")?)?))
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Balance energético en formato JSON (ver `cte::balance_to_json`)
+///
+/// La cadena devuelta debe liberarse con `cteepbd_string_free`. Devuelve un puntero nulo en
+/// caso de error.
+///
+/// # Safety
+///
+/// `balance` debe ser un puntero devuelto por `cteepbd_energy_performance` no liberado
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_balance_to_json(balance: *const Balance) -> *mut c_char {
+    guard(|| string_to_c(balance_to_json(ref_from_ptr(balance, "balance")?)?))
+        .unwrap_or(ptr::null_mut())
+}
+
+/// Libera una cadena devuelta por la librería
+///
+/// # Safety
+///
+/// `s` debe ser nulo o una cadena devuelta por esta librería no liberada
+#[no_mangle]
+pub unsafe extern "C" fn cteepbd_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+/// Código del último error producido en el hilo actual (p.e. `E201`)
+///
+/// La cadena devuelta debe liberarse con `cteepbd_string_free`. Devuelve un puntero nulo si la
+/// última llamada no produjo un error.
+#[no_mangle]
+pub extern "C" fn cteepbd_last_error_code() -> *mut c_char {
+    LAST_ERROR.with(|e| match e.borrow().as_ref() {
+        Some(err) => string_to_c(err.code().to_string()).unwrap_or(ptr::null_mut()),
+        None => ptr::null_mut(),
+    })
+}
+
+/// Mensaje del último error producido en el hilo actual
+///
+/// La cadena devuelta debe liberarse con `cteepbd_string_free`. Devuelve un puntero nulo si la
+/// última llamada no produjo un error.
+#[no_mangle]
+pub extern "C" fn cteepbd_last_error_message() -> *mut c_char {
+    LAST_ERROR.with(|e| match e.borrow().as_ref() {
+        Some(err) => string_to_c(err.to_string()).unwrap_or(ptr::null_mut()),
+        None => ptr::null_mut(),
+    })
+}
+
+/// Versión de la librería
+///
+/// La cadena devuelta es estática y no debe liberarse.
+#[no_mangle]
+pub extern "C" fn cteepbd_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_c(s: &str) -> CString {
+        CString::new(std::fs::read_to_string(s).unwrap()).unwrap()
+    }
+
+    unsafe fn take_string(s: *mut c_char) -> String {
+        assert!(!s.is_null());
+        let string = CStr::from_ptr(s).to_str().unwrap().to_string();
+        cteepbd_string_free(s);
+        string
+    }
+
+    #[test]
+    fn ffi_energy_performance() {
+        unsafe {
+            let components =
+                cteepbd_parse_components(read_c("test_data/cte_test_carriers.csv").as_ptr());
+            let wfactors =
+                cteepbd_parse_wfactors(read_c("test_data/factores_paso_test.csv").as_ptr());
+            assert!(!components.is_null());
+            assert!(!wfactors.is_null());
+
+            let balance = cteepbd_energy_performance(components, wfactors, 0.0, 200.0);
+            assert!(!balance.is_null());
+            assert!(cteepbd_last_error_code().is_null());
+            let json = take_string(cteepbd_balance_to_json(balance));
+            let doc: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert!(doc.get("indicadores").is_some());
+            assert_eq!(doc["arearef"], 200.0);
+
+            let json = take_string(cteepbd_components_to_json(components));
+            assert!(Components::from_json(&json).is_ok());
+            let json = take_string(cteepbd_wfactors_to_json(wfactors));
+            assert!(Factors::from_json(&json).is_ok());
+
+            cteepbd_balance_free(balance);
+            cteepbd_wfactors_free(wfactors);
+            cteepbd_components_free(components);
+        }
+    }
+
+    #[test]
+    fn ffi_errors() {
+        unsafe {
+            let components =
+                cteepbd_parse_components(CString::new("ELECTRICIDAD, XXX").unwrap().as_ptr());
+            assert!(components.is_null());
+            assert_eq!(take_string(cteepbd_last_error_code()), "E201");
+            assert!(!take_string(cteepbd_last_error_message()).is_empty());
+
+            let balance = cteepbd_energy_performance(ptr::null(), ptr::null(), 0.0, 1.0);
+            assert!(balance.is_null());
+            assert_eq!(take_string(cteepbd_last_error_code()), "E301");
+
+            assert!(cteepbd_parse_wfactors(ptr::null()).is_null());
+            cteepbd_components_free(ptr::null_mut());
+            cteepbd_string_free(ptr::null_mut());
+            assert_eq!(
+                CStr::from_ptr(cteepbd_version()).to_str().unwrap(),
+                crate::VERSION
+            );
+        }
+    }
+
+    #[test]
+    fn ffi_guard_panic() {
+        let value: Option<()> = guard(|| panic!("pánico de prueba"));
+        assert!(value.is_none());
+        unsafe {
+            assert_eq!(take_string(cteepbd_last_error_code()), "E301");
+            assert!(take_string(cteepbd_last_error_message()).contains("error interno"));
+        }
+        // Una llamada correcta posterior borra el error
+        assert_eq!(guard(|| Ok(1)), Some(1));
+        assert!(cteepbd_last_error_code().is_null());
+    }
+}
@@ -0,0 +1,548 @@
+// Copyright (c) 2018-2019  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Idioma de los textos generados (i18n)
+=====================================
+
+Traducción de los textos que genera la biblioteca: comentarios de los componentes y factores de
+paso generados automáticamente, avisos e informes (p.e. `cte::balance_to_plain`).
+
+Los textos se escriben en español en el código y se traducen, si el idioma seleccionado es otro,
+buscando el texto original (o la plantilla con los marcadores `{}` y `{:.N}`) en la tabla de
+traducciones del idioma. Los textos sin traducción se muestran en español.
+
+El idioma se selecciona con `set_lang` para el hilo actual, de modo que los cálculos
+simultáneos en distintos hilos pueden usar idiomas distintos. Los identificadores del formato
+de datos (vectores, servicios, metadatos, etc) y los mensajes de error no se traducen.
+*/
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use std::str;
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+
+use crate::error::EpbdError;
+
+/// Idioma de los textos generados
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+pub enum Lang {
+    /// Spanish (default)
+    #[default]
+    ES,
+    /// English
+    EN,
+}
+
+impl Lang {
+    /// Idiomas disponibles
+    pub const ALL: [Lang; 2] = [Lang::ES, Lang::EN];
+}
+
+impl str::FromStr for Lang {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Lang, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "es" => Ok(Lang::ES),
+            "en" => Ok(Lang::EN),
+            _ => Err(EpbdError::ParseError(format!("idioma desconocido: {}", s))),
+        }
+    }
+}
+
+impl fmt::Display for Lang {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = match self {
+            Lang::ES => "es",
+            Lang::EN => "en",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+thread_local! {
+    static LANG: Cell<Lang> = const { Cell::new(Lang::ES) };
+}
+
+/// Selecciona el idioma de los textos generados en el hilo actual
+pub fn set_lang(lang: Lang) {
+    LANG.with(|l| l.set(lang));
+}
+
+/// Idioma de los textos generados en el hilo actual
+pub fn lang() -> Lang {
+    LANG.with(Cell::get)
+}
+
+/// Traduce un texto al idioma del hilo actual
+///
+/// Devuelve el texto original si no existe traducción.
+pub fn tr(text: &str) -> &str {
+    tr_lang(lang(), text)
+}
+
+/// Traduce un texto al idioma indicado
+///
+/// Devuelve el texto original si no existe traducción.
+pub fn tr_lang(lang: Lang, text: &str) -> &str {
+    match lang {
+        Lang::ES => text,
+        Lang::EN => EN.get(text).copied().unwrap_or(text),
+    }
+}
+
+/// Sustituye los marcadores de una plantilla (`{}` y `{:.N}`) por los argumentos, en orden
+///
+/// Las llaves se escriben duplicadas (`{{` y `}}`), como en `format!`. Los marcadores sin
+/// argumento se omiten.
+pub fn format(template: &str, args: &[&dyn fmt::Display]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut args = args.iter();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let spec: String = chars.by_ref().take_while(|c| *c != '}').collect();
+                if let Some(arg) = args.next() {
+                    let _ = match spec.strip_prefix(":.").and_then(|p| p.parse().ok()) {
+                        Some(precision) => write!(out, "{:.*}", precision, arg),
+                        None => write!(out, "{}", arg),
+                    };
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+/// Equivalente a `format!` con la plantilla traducida al idioma del hilo actual
+///
+/// Solo admite los marcadores `{}` y `{:.N}`.
+macro_rules! tr_format {
+    ($template:expr $(, $arg:expr)* $(,)?) => {
+        $crate::i18n::format(
+            $crate::i18n::tr($template),
+            &[$(&$arg as &dyn std::fmt::Display),*],
+        )
+    };
+}
+pub(crate) use tr_format;
+
+/// Traducciones al inglés (texto o plantilla original, traducción)
+const EN_TEXTS: &[(&str, &str)] = &[
+    // Informe del balance (cte::balance_to_plain)
+    (
+        "k_exp (usos no EPB) = {:.2}\n",
+        "k_exp (non EPB uses) = {:.2}\n",
+    ),
+    (
+        "{}: paso A: ren {:.2}, nren {:.2}, co2 {:.2}; recursos evitados: ren {:.2}, nren {:.2}, co2 {:.2}",
+        "{}: step A: ren {:.2}, nren {:.2}, co2 {:.2}; avoided resources: ren {:.2}, nren {:.2}, co2 {:.2}",
+    ),
+    (
+        "TOTAL recursos evitados: ren {:.2}, nren {:.2}, co2 {:.2}",
+        "TOTAL avoided resources: ren {:.2}, nren {:.2}, co2 {:.2}",
+    ),
+    (
+        "\n** Energía auxiliar (incluida en la energía final) [kWh/m2.an]:\n{}\n",
+        "\n** Auxiliary energy (included in final energy) [kWh/m2.an]:\n{}\n",
+    ),
+    ("prioridad {}", "priority {}"),
+    ("proporcional al consumo", "proportional to energy use"),
+    (
+        "\n** Energía producida usada por servicio (reparto de la producción NDEF: {}) [kWh/m2.an]:\n{}\n",
+        "\n** Produced energy used by service (NDEF production allocation: {}) [kWh/m2.an]:\n{}\n",
+    ),
+    ("sin consumo", "no energy use"),
+    ("; horas equivalentes: {:.1} h", "; full load hours: {:.1} h"),
+    ("; factor de capacidad: {:.3}", "; capacity factor: {:.3}"),
+    (
+        "\n** Energía consumida por sistema [kWh/m2.an]:\n{}\n",
+        "\n** Energy used by system [kWh/m2.an]:\n{}\n",
+    ),
+    (
+        "\n** Ajustes de consumo [kWh/m2.an]:\n{}\n",
+        "\n** Energy use adjustments [kWh/m2.an]:\n{}\n",
+    ),
+    (
+        "{} -> {} (rendimiento {:.3}): entrada {:.2}, convertida {:.2}, aprovechada {:.2}, pérdidas {:.2}, no aprovechada {:.2}",
+        "{} -> {} (efficiency {:.3}): input {:.2}, converted {:.2}, used {:.2}, losses {:.2}, unused {:.2}",
+    ),
+    (
+        "\n** Conversiones de energía en el edificio [kWh/m2.an]:\n{}\n",
+        "\n** Energy conversions in the building [kWh/m2.an]:\n{}\n",
+    ),
+    (
+        "{}: suministrada red {:.2}, suministrada in situ {:.2}, exportada red {:.2}, exportada no EPB {:.2}\n    ponderada (ren/nren/co2): suministrada red {}, suministrada in situ {}, exportada paso A {}, recursos evitados {}, paso B {}",
+        "{}: delivered grid {:.2}, delivered on-site {:.2}, exported grid {:.2}, exported non EPB {:.2}\n    weighted (ren/nren/co2): delivered grid {}, delivered on-site {}, exported step A {}, avoided resources {}, step B {}",
+    ),
+    (
+        "{}: capacidad {:.2} kWh, rendimiento {:.3}: cargada {:.2}, descargada {:.2}, pérdidas {:.2}",
+        "{}: capacity {:.2} kWh, efficiency {:.3}: charged {:.2}, discharged {:.2}, losses {:.2}",
+    ),
+    (
+        "\n** Almacenamiento de energía [kWh/m2.an]:\n{}\n",
+        "\n** Energy storage [kWh/m2.an]:\n{}\n",
+    ),
+    (
+        "\n** Autoconsumo de electricidad producida [kWh/m2.an]:\nproducida {:.2}, autoconsumida {:.2}, consumida {:.2}; índice de autoconsumo {:.2}, índice de autosuficiencia {:.2}\n",
+        "\n** Self-consumption of produced electricity [kWh/m2.an]:\nproduced {:.2}, self-consumed {:.2}, used {:.2}; self-consumption ratio {:.2}, self-sufficiency ratio {:.2}\n",
+    ),
+    (
+        "{}: factor {:.3}, consumo calibrado {:.2} (sin calibrar: {:.2})",
+        "{}: factor {:.3}, calibrated energy use {:.2} (uncalibrated: {:.2})",
+    ),
+    (
+        "\n** Calibración de consumos [kWh/m2.an]:\n{}\n",
+        "\n** Energy use calibration [kWh/m2.an]:\n{}\n",
+    ),
+    (
+        "Vivienda {}: cuota {:.3}, A_ref {:.2} m2, consumo EPB {:.1} kWh/an, C_ep ren {:.1}, nren {:.1} kWh/an, E_CO2 {:.1} kg_CO2e/an",
+        "Dwelling {}: share {:.3}, A_ref {:.2} m2, EPB energy use {:.1} kWh/an, C_ep ren {:.1}, nren {:.1} kWh/an, E_CO2 {:.1} kg_CO2e/an",
+    ),
+    (
+        "\n** Indicadores por vivienda (estimación por cuotas):\n{}\n",
+        "\n** Indicators by dwelling (estimated from shares):\n{}\n",
+    ),
+    (
+        "{}
+** Energía final (todos los vectores) [kWh/m2.an]:
+{}
+
+** Energía primaria (ren, nren) [kWh/m2.an] y emisiones [kg_CO2e/m2.an] por servicios:
+{}
+
+** Energía suministrada por vector y paso de cálculo [kWh]:
+{}
+
+** Energía exportada ponderada (paso A) y recursos evitados a la red (paso B, k_exp) [kWh/m2.an] y [kg_CO2e/m2.an]:
+{}
+
+** Emisiones por vector (paso B) [kg_CO2e/m2.an]:
+{}
+
+** Balance anual por vector [kWh/m2.an] y energía ponderada [kWh/m2.an] y [kg_CO2e/m2.an]:
+{}
+{}{}{}{}{}{}{}{}{}{}",
+        "{}
+** Final energy (all carriers) [kWh/m2.an]:
+{}
+
+** Primary energy (ren, nren) [kWh/m2.an] and emissions [kg_CO2e/m2.an] by service:
+{}
+
+** Delivered energy by carrier and timestep [kWh]:
+{}
+
+** Weighted exported energy (step A) and avoided grid resources (step B, k_exp) [kWh/m2.an] and [kg_CO2e/m2.an]:
+{}
+
+** Emissions by carrier (step B) [kg_CO2e/m2.an]:
+{}
+
+** Annual balance by carrier [kWh/m2.an] and weighted energy [kWh/m2.an] and [kg_CO2e/m2.an]:
+{}
+{}{}{}{}{}{}{}{}{}{}",
+    ),
+    (
+        "{}: paso A: {}; paso B: {}",
+        "{}: step A: {}; step B: {}",
+    ),
+    (
+        "\n** Energía exportada por vector y paso de cálculo [kWh]:\n{}\n",
+        "\n** Exported energy by carrier and timestep [kWh]:\n{}\n",
+    ),
+    (
+        "\n** Energía ponderada y emisiones (paso A) [kWh/m2.an] y [kg_CO2e/m2.an]:\n{}\n\n** Energía ponderada y emisiones por servicio (pasos A y B) [kWh/m2.an] y [kg_CO2e/m2.an]:\n{}\n{}",
+        "\n** Weighted energy and emissions (step A) [kWh/m2.an] and [kg_CO2e/m2.an]:\n{}\n\n** Weighted energy and emissions by service (steps A and B) [kWh/m2.an] and [kg_CO2e/m2.an]:\n{}\n{}",
+    ),
+    (
+        "{}
+** Indicadores adicionales
+Demanda total de ACS: {} [kWh]
+Porcentaje renovable de la demanda de ACS (perímetro próximo): {} [%]
+{}",
+        "{}
+** Additional indicators
+Total DHW demand: {} [kWh]
+Renewable share of DHW demand (nearby perimeter): {} [%]
+{}",
+    ),
+    // Informes de cumplimiento (cte::he0, cte::he4)
+    ("CUMPLE", "PASSES"),
+    ("NO CUMPLE", "FAILS"),
+    (
+        "{}: {:.2} kWh/m²·año, límite {:.2} kWh/m²·año, margen {:.2} kWh/m²·año: {}",
+        "{}: {:.2} kWh/m²·yr, limit {:.2} kWh/m²·yr, margin {:.2} kWh/m²·yr: {}",
+    ),
+    ("HE0 (zona {}, {}): {}\n- {}\n- {}", "HE0 (zone {}, {}): {}\n- {}\n- {}"),
+    (
+        "HE4 (demanda de ACS {:.0} l/d): contribución renovable {:.1}%, mínimo {:.1}%, margen {:.1}%: {}",
+        "HE4 (DHW demand {:.0} l/d): renewable contribution {:.1}%, minimum {:.1}%, margin {:.1}%: {}",
+    ),
+    // Factura estimada
+    (
+        "{}: fijo {:.2}, energía {:.2}, exportación {:.2}, total {:.2}",
+        "{}: fixed {:.2}, energy {:.2}, export {:.2}, total {:.2}",
+    ),
+    // Avisos
+    ("AVISO", "WARNING"),
+    (
+        "producción de ELECTRICIDAD con servicio {} asignada al servicio NDEF",
+        "ELECTRICIDAD production with service {} assigned to service NDEF",
+    ),
+    (
+        "producción de MEDIOAMBIENTE generada para equilibrar el consumo del servicio {}",
+        "MEDIOAMBIENTE production generated to balance the energy use of service {}",
+    ),
+    ("factor de paso añadido: {}", "weighting factor added: {}"),
+    (
+        "factor de paso {}, {}, {}, {} sustituido: {:.3}, {:.3}, {:.3} -> {:.3}, {:.3}, {:.3}",
+        "weighting factor {}, {}, {}, {} replaced: {:.3}, {:.3}, {:.3} -> {:.3}, {:.3}, {:.3}",
+    ),
+    (
+        "factor de paso eliminado por no usarse ({}): {}",
+        "unused weighting factor removed ({}): {}",
+    ),
+    (
+        "consumo de recarga de vehículos eléctricos (RECARGAVE) considerado {}",
+        "electric vehicle charging energy use (RECARGAVE) considered {}",
+    ),
+    (
+        "{} valores negativos sustituidos por cero en el componente {}",
+        "{} negative values replaced by zero in component {}",
+    ),
+    (
+        "metadato {} con valores distintos al combinar componentes: se conserva \"{}\" y se descarta \"{}\"",
+        "metadata {} with different values when merging components: \"{}\" is kept and \"{}\" is discarded",
+    ),
+    (
+        "prioridad de servicios (CTE_PRIORIDAD_SERVICIOS) sin compensación servicio a servicio (CTE_COMPENSACION_SERVICIOS): no se aplica al balance y solo se usa al repartir la producción eléctrica por servicio",
+        "service priority (CTE_PRIORIDAD_SERVICIOS) without service by service compensation (CTE_COMPENSACION_SERVICIOS): it is not applied to the balance and only used to allocate electricity production by service",
+    ),
+    (
+        "valor incorrecto del metadato {}: \"{}\", se usa el valor por defecto",
+        "wrong value of metadata {}: \"{}\", the default value is used",
+    ),
+    (
+        "consumo de {} para el servicio {} poco habitual",
+        "unusual energy use of {} for service {}",
+    ),
+    (
+        "consumo EPB de {} sin servicio asignado (NDEF)",
+        "EPB energy use of {} without assigned service (NDEF)",
+    ),
+    (
+        "componentes asignados al sistema {} no declarado",
+        "components assigned to undeclared system {}",
+    ),
+    (
+        "sistema {} declarado sin componentes asignados",
+        "system {} declared without assigned components",
+    ),
+    (
+        "el sistema {} no tiene consumo ni producción del vector declarado {}",
+        "system {} has no energy use or production of its declared carrier {}",
+    ),
+    (
+        "componente con vector o servicio desconocido {}, excluido del balance",
+        "component with unknown carrier or service {}, excluded from the balance",
+    ),
+    ("vector no usado", "unused carrier"),
+    ("sin producción por cogeneración", "no cogeneration production"),
+    ("sin usos no EPB", "no non EPB uses"),
+    (
+        "sin producción de electricidad in situ",
+        "no on-site electricity production",
+    ),
+    ("sin producción de calor in situ", "no on-site heat production"),
+    // Cobertura y combinación de factores de paso
+    ("definido", "defined"),
+    ("por defecto", "default"),
+    ("no disponible", "not available"),
+    ("Sustituido: {} -> {}", "Replaced: {} -> {}"),
+    ("Añadido: {}", "Added: {}"),
+    // Comentarios de los componentes generados
+    (
+        "{} Conversión desde el sistema {}",
+        "{} Conversion from system {}",
+    ),
+    ("Necesidad de energía", "Energy need"),
+    (
+        "{} {} de {} atendida por el sistema {}",
+        "{} {} of {} met by system {}",
+    ),
+    (
+        "{} Equilibrado de consumo sin producción declarada",
+        "{} Balancing of energy use without declared production",
+    ),
+    (
+        "{} Producción eléctrica reasignada al servicio (reparto: {})",
+        "{} Electricity production reassigned to the service (allocation: {})",
+    ),
+    // Comentarios de los factores de paso generados
+    ("Factor de usuario", "User factor"),
+    ("Perímetro nearby: {}", "Nearby perimeter: {}"),
+    (
+        "Recursos usados para obtener energía térmica del medioambiente",
+        "Resources used to obtain thermal energy from the environment",
+    ),
+    (
+        "Recursos usados para obtener energía térmica del medioambiente (red ficticia)",
+        "Resources used to obtain thermal energy from the environment (fictitious grid)",
+    ),
+    (
+        "Recursos usados para generar electricidad in situ",
+        "Resources used to generate on-site electricity",
+    ),
+    (
+        "Factor de paso generado (el impacto de la cogeneración se tiene en cuenta en el vector de suministro)",
+        "Generated weighting factor (the impact of cogeneration is accounted for in the supplied carrier)",
+    ),
+    (
+        "Recursos usados para producir la energía exportada a la red",
+        "Resources used to produce the energy exported to the grid",
+    ),
+    (
+        "Recursos usados para producir la energía exportada a usos no EPB",
+        "Resources used to produce the energy exported to non EPB uses",
+    ),
+    (
+        "Recursos usados para producir la energía exportada a la red. Valor predefinido",
+        "Resources used to produce the energy exported to the grid. Default value",
+    ),
+    (
+        "Recursos usados para producir la energía exportada a usos no EPB. Valor predefinido",
+        "Resources used to produce the energy exported to non EPB uses. Default value",
+    ),
+    (
+        "Recursos ahorrados a la red por la energía producida in situ y exportada a la red",
+        "Grid resources saved by the on-site produced energy exported to the grid",
+    ),
+    (
+        "Recursos ahorrados a la red por la energía producida in situ y exportada a usos no EPB",
+        "Grid resources saved by the on-site produced energy exported to non EPB uses",
+    ),
+    (
+        "Recursos usados para suministrar energía de la red de distrito 1 (definible por el usuario)",
+        "Resources used to supply energy from district network 1 (user definable)",
+    ),
+    (
+        "Recursos usados para suministrar energía de la red de distrito 2 (definible por el usuario)",
+        "Resources used to supply energy from district network 2 (user definable)",
+    ),
+    (
+        "Recursos usados para producir in situ energía térmica (p.e. excedentes solares)",
+        "Resources used to produce on-site thermal energy (e.g. solar surplus)",
+    ),
+    (
+        "Recursos usados para producir la energía térmica exportada a la red de distrito",
+        "Resources used to produce the thermal energy exported to the district network",
+    ),
+    (
+        "Recursos usados para producir la energía térmica exportada a usos no EPB",
+        "Resources used to produce the thermal energy exported to non EPB uses",
+    ),
+    (
+        "Recursos ahorrados a la red de distrito por la energía térmica exportada a la red",
+        "District network resources saved by the thermal energy exported to the network",
+    ),
+    (
+        "Recursos ahorrados a la red de distrito por la energía térmica exportada a usos no EPB",
+        "District network resources saved by the thermal energy exported to non EPB uses",
+    ),
+];
+
+/// Tabla de traducciones al inglés
+static EN: Lazy<HashMap<&'static str, &'static str>> =
+    Lazy::new(|| EN_TEXTS.iter().copied().collect());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Marcadores de una plantilla
+    fn placeholders(template: &str) -> Vec<String> {
+        template
+            .replace("{{", "")
+            .split('{')
+            .skip(1)
+            .map(|s| s.split('}').next().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn tformat() {
+        let values: [&dyn fmt::Display; 3] = [&"ELECTRICIDAD", &1.23456, &2];
+        assert_eq!(
+            format("{}: {:.2} {{x}} {}", &values),
+            "ELECTRICIDAD: 1.23 {x} 2"
+        );
+        assert_eq!(format("{} {}", &values[..1]), "ELECTRICIDAD ");
+    }
+
+    #[test]
+    fn tlang() {
+        assert_eq!("EN".parse::<Lang>().unwrap(), Lang::EN);
+        assert_eq!(" es ".parse::<Lang>().unwrap(), Lang::ES);
+        assert!("fr".parse::<Lang>().is_err());
+        assert_eq!(lang(), Lang::ES);
+        assert_eq!(tr("vector no usado"), "vector no usado");
+        set_lang(Lang::EN);
+        assert_eq!(tr("vector no usado"), "unused carrier");
+        assert_eq!(tr("texto sin traducción"), "texto sin traducción");
+        assert_eq!(
+            tr_format!("factor de paso añadido: {}", "X"),
+            "weighting factor added: X"
+        );
+        set_lang(Lang::ES);
+        assert_eq!(
+            tr_format!("factor de paso añadido: {}", "X"),
+            "factor de paso añadido: X"
+        );
+    }
+
+    #[test]
+    fn ttranslations() {
+        // Las traducciones conservan los marcadores de las plantillas originales
+        for (es, en) in EN_TEXTS {
+            assert_eq!(placeholders(es), placeholders(en), "{}", es);
+        }
+        assert_eq!(EN.len(), EN_TEXTS.len());
+    }
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2018-2019  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Lectura de archivos de entrada
+==============================
+
+Lectura de archivos de datos (componentes, factores de paso, tarifas...) que pueden estar
+comprimidos con gzip (p.e. `.csv.gz`), algo habitual en los datos horarios, que ocupan mucho espacio.
+
+Los archivos comprimidos se detectan por su contenido (cabecera gzip) y no por su extensión, y
+se descomprimen a medida que se leen, por lo que no se guarda en memoria el archivo comprimido.
+El contenido descomprimido, sin embargo, se guarda completo en memoria (como texto), ya que la
+interpretación de los componentes y factores de paso parte del texto completo: un archivo horario
+comprimido ocupa en memoria lo mismo que sin comprimir.
+La descompresión requiere la característica `gzip`.
+
+Este módulo requiere la característica `fs` (activada por las características `cli` y `gzip`), ya que
+es la única parte de la librería que accede al sistema de archivos.
+*/
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::Path;
+
+/// Cabecera (números mágicos) de los archivos comprimidos con gzip
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Lee el contenido de un archivo como texto, descomprimiéndolo si está comprimido con gzip
+///
+/// Equivalente a `std::fs::read_to_string` para archivos sin comprimir. El contenido
+/// descomprimido se devuelve completo, por lo que se guarda en memoria en su totalidad.
+///
+/// # Errors
+///
+/// * Error de lectura del archivo o contenido que no es texto UTF-8
+/// * Archivo comprimido con gzip incorrecto o sin soporte de gzip (característica `gzip`)
+pub fn read_to_string<P: AsRef<Path>>(path: P) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut content = String::new();
+    if reader.fill_buf()?.starts_with(&GZIP_MAGIC) {
+        gzip_reader(reader)?.read_to_string(&mut content)?;
+    } else {
+        reader.read_to_string(&mut content)?;
+    }
+    Ok(content)
+}
+
+/// Lector que descomprime el contenido gzip (admite varios miembros concatenados)
+#[cfg(feature = "gzip")]
+fn gzip_reader<R: BufRead>(reader: R) -> io::Result<impl Read> {
+    Ok(flate2::bufread::MultiGzDecoder::new(reader))
+}
+
+/// Lector que descomprime el contenido gzip (no disponible sin la característica `gzip`)
+#[cfg(not(feature = "gzip"))]
+fn gzip_reader<R: BufRead>(_reader: R) -> io::Result<io::Empty> {
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "archivo comprimido con gzip y soporte de gzip no disponible",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn input_read_to_string() {
+        let dir = std::env::temp_dir();
+        let plain = dir.join(format!("cteepbd_input_{}.csv", std::process::id()));
+        std::fs::write(&plain, "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0\n").unwrap();
+        assert_eq!(
+            read_to_string(&plain).unwrap(),
+            "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0\n"
+        );
+        std::fs::remove_file(&plain).ok();
+        assert!(read_to_string(dir.join("cteepbd_no_existe.csv")).is_err());
+    }
+
+    #[cfg(feature = "gzip")]
+    #[test]
+    fn input_read_to_string_gzip() {
+        use std::io::Write;
+
+        let path =
+            std::env::temp_dir().join(format!("cteepbd_input_{}.csv.gz", std::process::id()));
+        let mut encoder =
+            flate2::write::GzEncoder::new(File::create(&path).unwrap(), Default::default());
+        encoder
+            .write_all(b"ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0\n")
+            .unwrap();
+        encoder.finish().unwrap();
+        assert_eq!(
+            read_to_string(&path).unwrap(),
+            "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0\n"
+        );
+        std::fs::remove_file(&path).ok();
+    }
+}
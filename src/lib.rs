@@ -37,17 +37,16 @@ best suited for that purpose.
 
 It also holds the following assumptions:
 
-- constant weighting factors through all timesteps
-- no priority is defined for energy production (average step A weighting factor f_we_el_stepA)
+- constant weighting factors through all timesteps, unless time-varying factors are given
+- no priority is defined for energy production (average step A weighting factor f_we_el_stepA), unless set in the components metadata
 - all on-site produced energy from non cogeneration sources is considered as delivered
-- on-site produced energy is not compensated on a service by service basis, but on a carrier basis
-- unit and constant load matching factor
+- on-site produced energy is not compensated on a service by service basis, but on a carrier basis, unless service by service compensation is set in the components metadata. In that case, production covers less EPB use, so that delivered and exported energy increase, and weighted energy by service is still allocated by the share of energy use of each service
+- on-site produced energy offsets consumption within each timestep, unless a monthly or annual compensation period is set
+- unit and constant load matching factor by default (optionally, computed following formula B.32 in appendix B)
 
 Some restrictions may be lifted in the future. Specifically:
 
-- implement a load matching factor (f_match_t) following formula B.32 in appendix B
 - allow the imputation to a specific service for produced energy
-- allow setting priorities for energy production
 
 Este *crate* proporciona una biblioteca y un programa que **implementa una parte sustancial del
 estándar EN ISO 52000-1**: *Eficiencia energética de los edificios - Evaluación global de la EPB -
@@ -59,17 +58,16 @@ así, adopta nomenclatura y valores por defecto adaptados a ese propósito.
 
 También realiza los siguientes supuestos:
 
-- factores de paso constantes en todo el periodo de cálculo
-- no se definen prioridades para la producción de energía
+- factores de paso constantes en todo el periodo de cálculo, salvo que se definan factores de paso variables (`FactorSeries`)
+- no se definen prioridades para la producción de energía, salvo que se indiquen en los metadatos de los componentes (`CTE_PRIORIDAD_PRODUCCION`)
 - se considera como suministrada toda la energía producida procedente de fuentes distintas a la cogeneración
-- la energía producida in situ se compensa por vector energético y no por servicios
-- factor de coincidencia de cargas igual a la unidad
+- la energía producida in situ se compensa por vector energético y no por servicios, salvo que se indique la compensación servicio a servicio (`CTE_COMPENSACION_SERVICIOS`). En ese caso la producción cubre menos consumos EPB, por lo que aumentan la energía suministrada y la exportada, y la energía ponderada de cada servicio se sigue repartiendo en proporción a su consumo
+- la energía producida compensa los consumos en cada paso de cálculo, salvo que se indique un periodo de compensación mensual o anual (`CTE_PERIODO_COMPENSACION`)
+- factor de coincidencia de cargas igual a la unidad, salvo que se calcule según la fórmula B.32 del apéndice B (ver `LoadMatching`)
 
 Algunas restricciones pueden revisarse en el futuro, tales como:
 
-- implementación del factor de coincidencia de cargas según fórmula B.32 del apéndice B
 - imputación de energía generada a servicios específicos
-- fijación de prioridades para la producción de energía
 
 # Ejemplo
 
@@ -87,6 +85,7 @@ let components = read_to_string("test_data/cte_test_carriers.csv")
 let user_wf = UserWF {
     red1: Some((1.0, 0.0, 0.0).into()),
     red2: None,
+    residuos: None,
     cogen_to_grid: None,
     cogen_to_nepb: None,
 }; // Factores definidos por el usuario
@@ -122,12 +121,21 @@ mod balance;
 mod components;
 pub mod cte;
 pub mod error;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod i18n;
+#[cfg(feature = "fs")]
+pub mod input;
+#[cfg(feature = "montecarlo")]
+pub mod montecarlo;
+mod tariffs;
 pub mod types;
 mod vecops;
 mod wfactors;
 
 pub use balance::*;
 pub use components::*;
+pub use tariffs::*;
 pub use wfactors::*;
 
 /// Número de versión de la librería
@@ -0,0 +1,437 @@
+// Copyright (c) 2018-2019  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Propagación de la incertidumbre de los factores de paso (montecarlo)
+====================================================================
+
+Estima la distribución de los indicadores de energía primaria (C_ep,tot y C_ep,nren, paso B)
+cuando los factores de paso renovable y no renovable se conocen con incertidumbre, mediante
+el método de Montecarlo: se muestrean los factores de paso dentro de los intervalos declarados
+y se repite el cálculo del balance con cada muestra.
+
+La incertidumbre de cada factor de paso se indica como la semiamplitud relativa de un intervalo
+de distribución uniforme, centrado en el valor del factor, para su parte renovable y no renovable
+(p.e. 0.10 para ±10%). En cada muestra se obtiene un valor aleatorio para la parte renovable y otro
+para la no renovable de cada factor de paso con incertidumbre. Los factores de paso que la
+normalización obtiene a partir de otros (p.e. los recursos evitados a la red en el paso B) se
+recalculan en cada muestra con `Factors::normalize`, que también mantiene los factores de valor fijo
+(p.e. MEDIOAMBIENTE). Los factores de paso variables en el tiempo (`FactorSeries`) no se muestrean.
+
+Formato:
+
+```text
+vector, fuente, uso, step, ren, nren
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.10, 0.05 # ±10% ren y ±5% nren
+GASNATURAL, RED, SUMINISTRO, A, 0.00, 0.03
+```
+
+Esta funcionalidad está disponible con la característica opcional `montecarlo`. Los resultados
+son reproducibles, ya que el generador de números aleatorios se inicializa con una semilla.
+*/
+
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    balance::{energy_performance_with_load_matching, Balance},
+    cte::CTE_USERWF,
+    error::{EpbdError, Result},
+    types::{Carrier, Dest, FactorKey, Real, Source, Step},
+    wfactors::Factors,
+};
+
+/// Incertidumbre de un factor de paso
+///
+/// Weighting factor uncertainty, as the relative half-width of a uniform distribution
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FactorUncertainty {
+    /// Energy carrier
+    pub carrier: Carrier,
+    /// Carrier source (`RED`, `INSITU` or `COGENERACION`)
+    pub source: Source,
+    /// Destination use of the energy (`SUMINISTRO`, `A_RED`, `A_NEPB`)
+    pub dest: Dest,
+    /// Evaluation step
+    pub step: Step,
+    /// Relative half-width of the renewable factor range [0, 1]
+    pub ren: Real,
+    /// Relative half-width of the non renewable factor range [0, 1]
+    pub nren: Real,
+}
+
+impl FactorUncertainty {
+    /// Clave del factor de paso al que se aplica la incertidumbre
+    pub fn key(&self) -> FactorKey {
+        (self.carrier.clone(), self.source, self.dest, self.step)
+    }
+}
+
+impl str::FromStr for FactorUncertainty {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> std::result::Result<FactorUncertainty, Self::Err> {
+        let data = s.split('#').next().unwrap_or("");
+        let items: Vec<&str> = data.split(',').map(str::trim).collect();
+        if items.len() != 6 {
+            return Err(EpbdError::ParseError(s.into()));
+        }
+        let uncertainty = FactorUncertainty {
+            carrier: items[0].parse()?,
+            source: items[1].parse()?,
+            dest: items[2].parse()?,
+            step: items[3].parse()?,
+            ren: items[4].parse()?,
+            nren: items[5].parse()?,
+        };
+        if !((0.0..=1.0).contains(&uncertainty.ren) && (0.0..=1.0).contains(&uncertainty.nren)) {
+            return Err(EpbdError::WrongInput(format!(
+                "La incertidumbre relativa de los factores de paso debe estar en el intervalo [0, 1]: \"{}\"",
+                s
+            )));
+        }
+        Ok(uncertainty)
+    }
+}
+
+/// Interpreta la lista de incertidumbres de los factores de paso
+///
+/// Se ignoran las líneas vacías, los comentarios (que empiezan por `#`) y la línea de cabecera.
+///
+/// # Errors
+///
+/// * Línea con formato incorrecto o incertidumbre fuera del intervalo [0, 1]
+pub fn parse_uncertainties(s: &str) -> Result<Vec<FactorUncertainty>> {
+    let s_nobom = s.strip_prefix('\u{feff}').unwrap_or(s);
+    s_nobom
+        .lines()
+        .map(str::trim)
+        .enumerate()
+        .filter(|(_, l)| !(l.starts_with('#') || l.starts_with("vector,") || l.is_empty()))
+        .map(|(i, l)| l.parse().map_err(|e: EpbdError| e.at_line(i + 1, l)))
+        .collect()
+}
+
+/// Distribución de los valores de un indicador
+///
+/// Indicator distribution (sample statistics)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Distribution {
+    /// Mean value
+    pub mean: Real,
+    /// Standard deviation
+    pub std_dev: Real,
+    /// Minimum value
+    pub min: Real,
+    /// Maximum value
+    pub max: Real,
+    /// 5th percentile
+    pub p5: Real,
+    /// Median (50th percentile)
+    pub p50: Real,
+    /// 95th percentile
+    pub p95: Real,
+}
+
+impl Distribution {
+    /// Estadísticos de una lista de valores no vacía
+    fn from_values(mut values: Vec<Real>) -> Self {
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let n = values.len() as Real;
+        let mean = values.iter().sum::<Real>() / n;
+        let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<Real>() / n;
+        Self {
+            mean,
+            std_dev: variance.sqrt(),
+            min: values[0],
+            max: values[values.len() - 1],
+            p5: percentile(&values, 0.05),
+            p50: percentile(&values, 0.5),
+            p95: percentile(&values, 0.95),
+        }
+    }
+}
+
+/// Percentil (interpolación lineal) de una lista ordenada y no vacía de valores
+fn percentile(sorted: &[Real], p: Real) -> Real {
+    let pos = p * (sorted.len() - 1) as Real;
+    let (lower, upper) = (pos.floor() as usize, pos.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (pos - lower as Real)
+}
+
+/// Resultados de la propagación de la incertidumbre de los factores de paso
+///
+/// Distributions of the energy performance indicators (step B, by reference area)
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UncertaintyResults {
+    /// Number of samples
+    pub samples: usize,
+    /// Total primary energy distribution (C_ep,tot) [kWh/m2.an]
+    pub ep_tot: Distribution,
+    /// Non renewable primary energy distribution (C_ep,nren) [kWh/m2.an]
+    pub ep_nren: Distribution,
+}
+
+/// Factores de paso sin aquellos que la normalización obtiene a partir de otros
+///
+/// Se conservan los factores con incertidumbre y los que la normalización no reproduce al
+/// eliminarlos (p.e. los factores de suministro de la red), de modo que, al normalizar los
+/// factores de una muestra, los factores derivados se calculan con los valores muestreados.
+fn sampling_template(wfactors: &Factors, uncertainties: &[FactorUncertainty]) -> Factors {
+    let is_derived = |i: usize| {
+        let f = &wfactors.wdata[i];
+        if uncertainties.iter().any(|u| u.key() == f.key()) {
+            return false;
+        }
+        let mut others = wfactors.clone();
+        others.wdata.remove(i);
+        others
+            .normalize_factors(&CTE_USERWF)
+            .map_or(false, |normalized| {
+                normalized
+                    .wdata
+                    .iter()
+                    .any(|g| g.key() == f.key() && g.factors() == f.factors())
+            })
+    };
+    let wdata = (0..wfactors.wdata.len())
+        .filter(|&i| !is_derived(i))
+        .map(|i| wfactors.wdata[i].clone())
+        .collect();
+    Factors {
+        wdata,
+        ..wfactors.clone()
+    }
+}
+
+/// Propaga la incertidumbre de los factores de paso a los indicadores del balance
+///
+/// Se repite el cálculo del balance, con los mismos componentes, factores de exportación, área
+/// de referencia y método de cálculo del factor de coincidencia de cargas, para el número de
+/// muestras indicado. En cada muestra, los factores renovable y no renovable con incertidumbre
+/// se multiplican por un valor aleatorio uniforme en el intervalo [1 - u, 1 + u] y se vuelven a
+/// normalizar los factores de paso, para recalcular los que dependen de los muestreados. La semilla
+/// inicializa el generador de números aleatorios, de modo que el resultado es reproducible.
+///
+/// # Errors
+///
+/// * Número de muestras nulo
+/// * Incertidumbre de un factor de paso no definido en el balance
+/// * Missing weighting factors needed for balance computation
+pub fn energy_performance_montecarlo(
+    balance: &Balance,
+    uncertainties: &[FactorUncertainty],
+    samples: usize,
+    seed: u64,
+) -> Result<UncertaintyResults> {
+    if samples == 0 {
+        return Err(EpbdError::WrongInput(
+            "El número de muestras debe ser mayor que cero".into(),
+        ));
+    }
+    let template = sampling_template(&balance.wfactors, uncertainties);
+    // Posiciones de los factores de paso afectados por cada incertidumbre
+    let positions = uncertainties
+        .iter()
+        .map(|u| {
+            let positions: Vec<usize> = template
+                .wdata
+                .iter()
+                .enumerate()
+                .filter(|(_, f)| f.key() == u.key())
+                .map(|(i, _)| i)
+                .collect();
+            if positions.is_empty() {
+                return Err(EpbdError::WrongInput(format!(
+                    "Incertidumbre de un factor de paso no definido: {}, {}, {}, {}",
+                    u.carrier, u.source, u.dest, u.step
+                )));
+            }
+            Ok(positions)
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut rng = oorandom::Rand64::new(seed.into());
+    let mut sample = |u: Real| 1.0 + u * (2.0 * rng.rand_float() as Real - 1.0);
+    let mut ep_tot = Vec::with_capacity(samples);
+    let mut ep_nren = Vec::with_capacity(samples);
+    for _ in 0..samples {
+        let mut wfactors = template.clone();
+        for (u, positions) in uncertainties.iter().zip(&positions) {
+            let (k_ren, k_nren) = (sample(u.ren), sample(u.nren));
+            for &i in positions {
+                let orig = &template.wdata[i];
+                wfactors.wdata[i].ren = orig.ren * k_ren;
+                wfactors.wdata[i].nren = orig.nren * k_nren;
+            }
+        }
+        let wfactors = wfactors.normalize_factors(&CTE_USERWF)?;
+        let sample_balance = energy_performance_with_load_matching(
+            &balance.components,
+            &wfactors,
+            balance.k_exp,
+            balance.k_exp_nepb,
+            balance.arearef,
+            balance.load_matching,
+        )?;
+        ep_tot.push(sample_balance.balance_m2.B.tot());
+        ep_nren.push(sample_balance.balance_m2.B.nren);
+    }
+    Ok(UncertaintyResults {
+        samples,
+        ep_tot: Distribution::from_values(ep_tot),
+        ep_nren: Distribution::from_values(ep_nren),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{energy_performance, Components, Factors};
+    use pretty_assertions::assert_eq;
+
+    const TFACTORS: &str = "vector, fuente, uso, step, ren, nren, co2
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.190, 0.252";
+
+    fn get_balance() -> Balance {
+        let components = "#META CTE_AREAREF: 100.0
+ELECTRICIDAD, CONSUMO, EPB, REF, 1000.0
+GASNATURAL, CONSUMO, EPB, CAL, 2000.0"
+            .parse::<Components>()
+            .unwrap();
+        let wfactors = TFACTORS.parse::<Factors>().unwrap();
+        energy_performance(&components, &wfactors, 0.0, 100.0).unwrap()
+    }
+
+    #[test]
+    fn tuncertainties() {
+        let uncertainties = parse_uncertainties(
+            "vector, fuente, uso, step, ren, nren
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.10, 0.05 # Comentario",
+        )
+        .unwrap();
+        assert_eq!(uncertainties.len(), 1);
+        assert_eq!(uncertainties[0].nren, 0.05);
+        assert!(parse_uncertainties("ELECTRICIDAD, RED, SUMINISTRO, A, 0.10").is_err());
+        assert!(parse_uncertainties("ELECTRICIDAD, RED, SUMINISTRO, A, 0.10, 1.5").is_err());
+        let err =
+            parse_uncertainties("\nELECTRICIDAD, XXX, SUMINISTRO, A, 0.10, 0.05").unwrap_err();
+        assert_eq!(err.line(), Some(2));
+    }
+
+    #[test]
+    fn tmontecarlo() {
+        let balance = get_balance();
+        let b = balance.balance_m2.B;
+        // Sin incertidumbre se reproduce el balance
+        let uncertainties =
+            parse_uncertainties("ELECTRICIDAD, RED, SUMINISTRO, A, 0.0, 0.0").unwrap();
+        let results = energy_performance_montecarlo(&balance, &uncertainties, 10, 1).unwrap();
+        assert!((results.ep_tot.mean - b.tot()).abs() < 0.01);
+        assert!(results.ep_nren.std_dev < 0.01);
+
+        let uncertainties = parse_uncertainties(
+            "ELECTRICIDAD, RED, SUMINISTRO, A, 0.10, 0.10
+GASNATURAL, RED, SUMINISTRO, A, 0.0, 0.05",
+        )
+        .unwrap();
+        let results = energy_performance_montecarlo(&balance, &uncertainties, 500, 42).unwrap();
+        let d = &results.ep_nren;
+        assert!(d.min <= d.p5 && d.p5 <= d.p50 && d.p50 <= d.p95 && d.p95 <= d.max);
+        assert!(d.std_dev > 0.0);
+        // Media próxima al valor central y valores dentro del intervalo máximo
+        assert!((d.mean - b.nren).abs() / b.nren < 0.01);
+        let max_nren = 1.1 * 10.0 * 1.954 + 1.05 * 20.0 * 1.190;
+        assert!(d.max <= max_nren);
+        // Resultados reproducibles con la misma semilla
+        assert_eq!(
+            results,
+            energy_performance_montecarlo(&balance, &uncertainties, 500, 42).unwrap()
+        );
+
+        let unknown = parse_uncertainties("BIOMASA, RED, SUMINISTRO, A, 0.1, 0.1").unwrap();
+        assert!(energy_performance_montecarlo(&balance, &unknown, 10, 1).is_err());
+        assert!(energy_performance_montecarlo(&balance, &uncertainties, 0, 1).is_err());
+    }
+
+    #[test]
+    fn tsampling_template() {
+        let wfactors = TFACTORS
+            .parse::<Factors>()
+            .unwrap()
+            .normalize(&CTE_USERWF)
+            .unwrap();
+        let has = |f: &Factors, c: Carrier, s: Source, d: Dest, st: Step| {
+            f.wdata.iter().any(|f| f.key() == (c.clone(), s, d, st))
+        };
+        let uncertainties =
+            parse_uncertainties("ELECTRICIDAD, RED, SUMINISTRO, A, 0.10, 0.10").unwrap();
+        let template = sampling_template(&wfactors, &uncertainties);
+        // Se eliminan los factores derivados y se conservan los muestreados y los de la red
+        assert!(has(
+            &wfactors,
+            Carrier::ELECTRICIDAD,
+            Source::INSITU,
+            Dest::A_RED,
+            Step::B
+        ));
+        assert!(!has(
+            &template,
+            Carrier::ELECTRICIDAD,
+            Source::INSITU,
+            Dest::A_RED,
+            Step::B
+        ));
+        assert!(has(
+            &template,
+            Carrier::ELECTRICIDAD,
+            Source::RED,
+            Dest::SUMINISTRO,
+            Step::A
+        ));
+        assert!(has(
+            &template,
+            Carrier::GASNATURAL,
+            Source::RED,
+            Dest::SUMINISTRO,
+            Step::A
+        ));
+        // La normalización recupera los factores eliminados
+        assert_eq!(
+            template.normalize_factors(&CTE_USERWF).unwrap().wdata.len(),
+            wfactors.wdata.len()
+        );
+    }
+
+    #[test]
+    fn tpercentile() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(percentile(&values, 0.5), 3.0);
+        assert_eq!(percentile(&values, 0.05), 1.2);
+        assert_eq!(percentile(&[2.0], 0.95), 2.0);
+    }
+}
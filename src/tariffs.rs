@@ -0,0 +1,453 @@
+// Copyright (c) 2018-2019  Ministerio de Fomento
+//                          Instituto de Ciencias de la Construcción Eduardo Torroja (IETcc-CSIC)
+
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
+// SOFTWARE.
+
+// Author(s): Rafael Villar Burke <pachi@ietcc.csic.es>,
+//            Daniel Jiménez González <dani@ietcc.csic.es>,
+//            Marta Sorribes Gil <msorribes@ietcc.csic.es>
+
+/*!
+Tarifas energéticas (tariffs)
+=============================
+
+Define el tipo Tariffs (lista de tarifas por vector + metadatos) y el cálculo de la factura
+energética anual estimada a partir de la energía suministrada por la red y exportada a la red en
+cada paso de cálculo.
+
+Cada tarifa tiene un término fijo anual (€/año), un término de energía (€/kWh), un precio opcional
+de compensación de la energía exportada a la red (€/kWh) y, para la electricidad, periodos horarios
+opcionales con un precio propio (€/kWh) para los pasos de cálculo indicados. Los pasos de cálculo
+que no pertenecen a ningún periodo usan el término de energía.
+
+Formato:
+
+```text
+#META CTE_TARIFA: Tarifa de ejemplo
+ELECTRICIDAD, FIJO, 150.00 # €/año
+ELECTRICIDAD, ENERGIA, 0.140 # €/kWh
+ELECTRICIDAD, PERIODO, P1, 0.200, 1-3 12 # €/kWh en los pasos 1, 2, 3 y 12
+ELECTRICIDAD, EXPORTACION, 0.050 # €/kWh exportado a la red
+GASNATURAL, FIJO, 90.00
+GASNATURAL, ENERGIA, 0.060
+```
+
+Los pasos de cálculo de los periodos se numeran desde 1 y pueden indicarse de forma individual
+o como intervalos (inicio-fin), separados por espacios. Los pasos se cuentan desde el primer paso
+de cálculo de toda la serie de valores, por lo que, con datos de varias anualidades
+(`CTE_ANUALIDADES`), los periodos solo cubren los pasos indicados (p.e. los de la primera anualidad)
+y el resto de pasos usa el término de energía.
+*/
+
+use std::collections::HashSet;
+use std::fmt;
+use std::str;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    balance::Balance,
+    error::{EpbdError, Result},
+    i18n::tr_format,
+    types::{Carrier, Meta, MetaVec, Real},
+};
+
+/// Periodo horario de una tarifa eléctrica
+///
+/// Time-of-use period, with its own price for the given timesteps
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TariffPeriod {
+    /// Period name
+    pub name: String,
+    /// Energy price for the period [€/kWh]
+    pub price: Real,
+    /// Timesteps of the period (starting at 1 from the first timestep of the whole series)
+    pub steps: Vec<usize>,
+}
+
+impl fmt::Display for TariffPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let steps = self
+            .steps
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(" ");
+        write!(f, "{}, {:.3}, {}", self.name, self.price, steps)
+    }
+}
+
+/// Tarifa de un vector energético
+///
+/// Carrier tariff
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tariff {
+    /// Carrier name
+    pub carrier: Carrier,
+    /// Fixed term [€/year]
+    pub fixed: Real,
+    /// Energy term [€/kWh]
+    pub energy: Real,
+    /// Compensation price for energy exported to the grid [€/kWh]
+    #[serde(default)]
+    pub export: Real,
+    /// Time-of-use periods (only for electricity)
+    pub periods: Vec<TariffPeriod>,
+}
+
+impl Tariff {
+    /// Tarifa vacía para un vector
+    pub fn new(carrier: Carrier) -> Self {
+        Self {
+            carrier,
+            fixed: 0.0,
+            energy: 0.0,
+            export: 0.0,
+            periods: Vec::new(),
+        }
+    }
+
+    /// Precio de la energía en un paso de cálculo (numerados desde 1) [€/kWh]
+    pub fn price_at(&self, step: usize) -> Real {
+        self.periods
+            .iter()
+            .find(|p| p.steps.contains(&step))
+            .map(|p| p.price)
+            .unwrap_or(self.energy)
+    }
+
+    /// Precios de la energía de los pasos de cálculo 1 a `num_steps` [€/kWh]
+    ///
+    /// Los pasos de los periodos que superan `num_steps` se ignoran.
+    pub fn prices(&self, num_steps: usize) -> Vec<Real> {
+        let mut prices = vec![self.energy; num_steps];
+        for period in &self.periods {
+            for &step in &period.steps {
+                if let Some(price) = step.checked_sub(1).and_then(|i| prices.get_mut(i)) {
+                    *price = period.price;
+                }
+            }
+        }
+        prices
+    }
+}
+
+impl fmt::Display for Tariff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, FIJO, {:.2}\n{}, ENERGIA, {:.3}",
+            self.carrier, self.fixed, self.carrier, self.energy
+        )?;
+        for period in &self.periods {
+            write!(f, "\n{}, PERIODO, {}", self.carrier, period)?;
+        }
+        if self.export != 0.0 {
+            write!(f, "\n{}, EXPORTACION, {:.3}", self.carrier, self.export)?;
+        }
+        Ok(())
+    }
+}
+
+/// Lista de tarifas por vector energético y metadatos
+///
+/// List of carrier tariffs and metadata
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Tariffs {
+    /// Metadata
+    pub tmeta: Vec<Meta>,
+    /// Tariff list
+    pub tdata: Vec<Tariff>,
+}
+
+impl MetaVec for Tariffs {
+    fn get_metavec(&self) -> &Vec<Meta> {
+        &self.tmeta
+    }
+    fn get_mut_metavec(&mut self) -> &mut Vec<Meta> {
+        &mut self.tmeta
+    }
+}
+
+impl fmt::Display for Tariffs {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let metalines = self
+            .tmeta
+            .iter()
+            .map(|v| format!("{}", v))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let datalines = self
+            .tdata
+            .iter()
+            .map(|v| format!("{}", v))
+            .collect::<Vec<_>>()
+            .join("\n");
+        write!(f, "{}\n{}", metalines, datalines)
+    }
+}
+
+/// Interpreta los pasos de cálculo de un periodo (p.e. "1-3 12")
+fn parse_steps(s: &str) -> Result<Vec<usize>> {
+    let mut steps = Vec::new();
+    for item in s.split_whitespace() {
+        let parse_step = |v: &str| -> Result<usize> {
+            match v.trim().parse::<usize>() {
+                Ok(step) if step > 0 => Ok(step),
+                _ => Err(EpbdError::ParseError(format!("paso de cálculo \"{}\"", v))),
+            }
+        };
+        match item.split_once('-') {
+            Some((start, end)) => {
+                let (start, end) = (parse_step(start)?, parse_step(end)?);
+                if start > end {
+                    return Err(EpbdError::ParseError(format!(
+                        "intervalo de pasos de cálculo \"{}\"",
+                        item
+                    )));
+                }
+                steps.extend(start..=end);
+            }
+            None => steps.push(parse_step(item)?),
+        }
+    }
+    Ok(steps)
+}
+
+impl str::FromStr for Tariffs {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> std::result::Result<Tariffs, Self::Err> {
+        let s_nobom = s.strip_prefix('\u{feff}').unwrap_or(s);
+        let lines: Vec<&str> = s_nobom.lines().map(str::trim).collect();
+        let metalines = lines
+            .iter()
+            .filter(|l| l.starts_with("#META") || l.starts_with("#CTE_"));
+        let datalines = lines
+            .iter()
+            .filter(|l| !(l.starts_with('#') || l.starts_with("vector,") || l.is_empty()));
+        let tmeta = metalines
+            .map(|e| e.parse())
+            .collect::<std::result::Result<Vec<Meta>, _>>()?;
+
+        let mut tdata: Vec<Tariff> = Vec::new();
+        for line in datalines {
+            let data = line.split('#').next().unwrap_or("");
+            let items: Vec<&str> = data.split(',').map(str::trim).collect();
+            if items.len() < 3 {
+                return Err(EpbdError::ParseError(line.to_string()));
+            }
+            let carrier: Carrier = items[0]
+                .parse()
+                .map_err(|_| EpbdError::ParseError(items[0].into()))?;
+            let idx = match tdata.iter().position(|t| t.carrier == carrier) {
+                Some(idx) => idx,
+                None => {
+                    tdata.push(Tariff::new(carrier.clone()));
+                    tdata.len() - 1
+                }
+            };
+            let tariff = &mut tdata[idx];
+            match (items[1], items.len()) {
+                ("FIJO", 3) => tariff.fixed = items[2].parse()?,
+                ("ENERGIA", 3) => tariff.energy = items[2].parse()?,
+                ("EXPORTACION", 3) => tariff.export = items[2].parse()?,
+                ("PERIODO", 5) if carrier == Carrier::ELECTRICIDAD => {
+                    let steps = parse_steps(items[4])?;
+                    let used: HashSet<usize> = tariff
+                        .periods
+                        .iter()
+                        .flat_map(|p| p.steps.iter().cloned())
+                        .collect();
+                    if steps.iter().any(|s| used.contains(s)) {
+                        return Err(EpbdError::ParseError(format!(
+                            "periodo con pasos de cálculo repetidos \"{}\"",
+                            line
+                        )));
+                    }
+                    tariff.periods.push(TariffPeriod {
+                        name: items[2].to_string(),
+                        price: items[3].parse()?,
+                        steps,
+                    });
+                }
+                _ => return Err(EpbdError::ParseError(line.to_string())),
+            }
+        }
+        Ok(Tariffs { tmeta, tdata })
+    }
+}
+
+/// Factura energética estimada de un vector energético
+///
+/// Estimated energy bill for a carrier
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BillForCarrier {
+    /// Carrier name
+    pub carrier: Carrier,
+    /// Fixed term [€/year]
+    pub fixed: Real,
+    /// Energy term, from delivered energy [€/year]
+    pub energy: Real,
+    /// Compensation for energy exported to the grid [€/year]
+    #[serde(default)]
+    pub exported: Real,
+    /// Total bill (fixed + energy - exported) [€/year]
+    pub total: Real,
+}
+
+/// Factura energética anual estimada
+///
+/// Estimated annual energy bill
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct Bill {
+    /// Bill for each carrier with a tariff, sorted by carrier
+    pub by_carrier: Vec<BillForCarrier>,
+    /// Total bill [€/year]
+    pub total: Real,
+}
+
+impl Bill {
+    /// Compensación total de la energía exportada a la red [€/año]
+    pub fn exported(&self) -> Real {
+        self.by_carrier.iter().map(|b| b.exported).sum()
+    }
+}
+
+impl fmt::Display for Bill {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for b in &self.by_carrier {
+            writeln!(
+                f,
+                "{}",
+                tr_format!(
+                    "{}: fijo {:.2}, energía {:.2}, exportación {:.2}, total {:.2}",
+                    b.carrier,
+                    b.fixed,
+                    b.energy,
+                    -b.exported,
+                    b.total
+                )
+            )?;
+        }
+        write!(f, "TOTAL: {:.2}", self.total)
+    }
+}
+
+/// Calcula la factura energética anual estimada a partir de la energía suministrada por la red
+///
+/// Compute the estimated annual energy bill from the delivered energy of each timestep, minus
+/// the compensation for the energy exported to the grid.
+///
+/// Solo se facturan los vectores del balance que tienen tarifa definida.
+///
+/// # Errors
+///
+/// * Periodos con pasos de cálculo que no existen en el balance
+///
+pub fn energy_bill(balance: &Balance, tariffs: &Tariffs) -> Result<Bill> {
+    let mut by_carrier = Vec::new();
+    for tariff in &tariffs.tdata {
+        let bal = match balance.balance_cr.get(&tariff.carrier) {
+            Some(bal) => bal,
+            None => continue,
+        };
+        let num_steps = bal.delivered_grid.len();
+        if let Some(step) = tariff
+            .periods
+            .iter()
+            .flat_map(|p| p.steps.iter())
+            .find(|&&s| s > num_steps)
+        {
+            return Err(EpbdError::WrongInput(format!(
+                "paso de cálculo {} de la tarifa de {} fuera de rango (1 - {})",
+                step, tariff.carrier, num_steps
+            )));
+        }
+        let energy: Real = bal
+            .delivered_grid
+            .iter()
+            .zip(tariff.prices(num_steps))
+            .map(|(v, price)| v * price)
+            .sum();
+        let exported = bal.exported_grid_an * tariff.export;
+        by_carrier.push(BillForCarrier {
+            carrier: tariff.carrier.clone(),
+            fixed: tariff.fixed,
+            energy,
+            exported,
+            total: tariff.fixed + energy - exported,
+        });
+    }
+    by_carrier.sort_by(|a, b| a.carrier.cmp(&b.carrier));
+    let total = by_carrier.iter().map(|b| b.total).sum();
+    Ok(Bill { by_carrier, total })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn ttariffs() {
+        let ttariffs1 = "#META CTE_TARIFA: Tarifa de ejemplo
+ELECTRICIDAD, FIJO, 150.00
+ELECTRICIDAD, ENERGIA, 0.140
+ELECTRICIDAD, PERIODO, P1, 0.200, 1 2 3 12
+GASNATURAL, FIJO, 90.00
+GASNATURAL, ENERGIA, 0.060";
+
+        // roundtrip building from/to string
+        let tariffs = ttariffs1.parse::<Tariffs>().unwrap();
+        assert_eq!(tariffs.to_string(), ttariffs1);
+        assert_eq!(
+            "ELECTRICIDAD, PERIODO, P1, 0.2, 1-3 12 # Punta"
+                .parse::<Tariffs>()
+                .unwrap()
+                .tdata[0]
+                .periods[0]
+                .steps,
+            vec![1, 2, 3, 12]
+        );
+        assert_eq!(tariffs.tdata[0].price_at(2), 0.2);
+        assert_eq!(tariffs.tdata[0].price_at(5), 0.14);
+        assert_eq!(tariffs.tdata[0].prices(5), vec![0.2, 0.2, 0.2, 0.14, 0.14]);
+
+        // Periodos solo para electricidad, sin pasos repetidos
+        assert!("GASNATURAL, PERIODO, P1, 0.2, 1"
+            .parse::<Tariffs>()
+            .is_err());
+        assert!(
+            "ELECTRICIDAD, PERIODO, P1, 0.2, 1-3\nELECTRICIDAD, PERIODO, P2, 0.1, 3"
+                .parse::<Tariffs>()
+                .is_err()
+        );
+        assert!("ELECTRICIDAD, PERIODO, P1, 0.2, 0"
+            .parse::<Tariffs>()
+            .is_err());
+        assert!("ELECTRICIDAD, OTRO, 0.2".parse::<Tariffs>().is_err());
+
+        // Compensación de la energía exportada
+        let ttariffs2 = "\nELECTRICIDAD, FIJO, 0.00\nELECTRICIDAD, ENERGIA, 0.140\nELECTRICIDAD, EXPORTACION, 0.050";
+        let tariffs = ttariffs2.parse::<Tariffs>().unwrap();
+        assert_eq!(tariffs.tdata[0].export, 0.05);
+        assert_eq!(tariffs.to_string(), ttariffs2);
+    }
+}
@@ -27,17 +27,26 @@ use std::convert::TryFrom;
 use std::fmt;
 use std::str;
 
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
-use crate::{error::EpbdError, types::RenNrenCo2};
+use crate::{
+    error::EpbdError,
+    types::{Real, RenNrenCo2},
+};
 
 // ==================== Common types (components + weighting factors)
 
 // -------------------- Carrier
 
 /// Vector energético (energy carrier).
+///
+/// Además de los vectores predefinidos, admite vectores definidos por el usuario
+/// (`USUARIO_<NOMBRE>`, p.e. `USUARIO_CALORRESIDUAL`), que se consideran suministrados
+/// desde la red y deben disponer de factores de paso definidos por el usuario.
+///
+/// Los vectores de usuario guardan su nombre, por lo que `Carrier` no es `Copy` y debe clonarse.
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum Carrier {
     /// Electricity
     ELECTRICIDAD,
@@ -61,6 +70,107 @@ pub enum Carrier {
     RED1,
     /// Generic energy carrier 2
     RED2,
+    /// Generic energy carrier n (n >= 3)
+    REDN(DistrictNumber),
+    /// Waste and refuse-derived fuels (waste-to-energy district systems)
+    RESIDUOS,
+    /// Hydrogen (e.g. for fuel cells)
+    HIDROGENO,
+    /// User defined carrier
+    USUARIO(UserCarrier),
+}
+
+/// Número de una red de distrito adicional (n >= 3)
+///
+/// Las redes 1 y 2 son los vectores `RED1` y `RED2`, por lo que solo pueden crearse números
+/// a partir de 3 (ver `DistrictNumber::new` y `Carrier::district`).
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct DistrictNumber(u8);
+
+impl DistrictNumber {
+    /// Número de red de distrito adicional. Devuelve `None` para n < 3
+    pub fn new(n: u8) -> Option<Self> {
+        if n >= 3 {
+            Some(DistrictNumber(n))
+        } else {
+            None
+        }
+    }
+
+    /// Valor del número de red de distrito
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl fmt::Display for DistrictNumber {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl Carrier {
+    /// Vector de la red de distrito n (RED1, RED2, RED3, ...)
+    ///
+    /// Devuelve `None` para n = 0.
+    pub fn district(n: u8) -> Option<Self> {
+        match n {
+            0 => None,
+            1 => Some(Carrier::RED1),
+            2 => Some(Carrier::RED2),
+            _ => DistrictNumber::new(n).map(Carrier::REDN),
+        }
+    }
+
+    /// Número de la red de distrito (1 para RED1, 2 para RED2, n para REDn) o `None` si no es una red de distrito
+    pub fn district_number(&self) -> Option<u8> {
+        match self {
+            Carrier::RED1 => Some(1),
+            Carrier::RED2 => Some(2),
+            Carrier::REDN(n) => Some(n.get()),
+            _ => None,
+        }
+    }
+
+    /// Indica si el vector corresponde a una red de distrito (RED1, RED2, RED3, ...)
+    pub fn is_district(&self) -> bool {
+        matches!(self, Carrier::RED1 | Carrier::RED2 | Carrier::REDN(_))
+    }
+}
+
+/// Prefijo de los vectores energéticos definidos por el usuario
+pub const USER_CARRIER_PREFIX: &str = "USUARIO_";
+
+/// Vector energético definido por el usuario (user defined energy carrier)
+///
+/// Se identifica por su nombre, formado por letras mayúsculas, números y guiones bajos.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct UserCarrier(String);
+
+impl UserCarrier {
+    /// Vector definido por el usuario con el nombre indicado (sin el prefijo `USUARIO_`)
+    ///
+    /// # Errors
+    ///
+    /// * Nombre vacío o con caracteres distintos de letras mayúsculas, números y guiones bajos
+    pub fn new(name: &str) -> Result<Self, EpbdError> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_');
+        if !valid {
+            return Err(EpbdError::ParseError(format!(
+                "Nombre de vector de usuario incorrecto: {}",
+                name
+            )));
+        }
+        Ok(UserCarrier(name.to_string()))
+    }
+
+    /// Nombre del vector (sin el prefijo `USUARIO_`)
+    pub fn name(&self) -> &str {
+        &self.0
+    }
 }
 
 impl str::FromStr for Carrier {
@@ -79,14 +189,57 @@ impl str::FromStr for Carrier {
             "GLP" => Ok(Carrier::GLP),
             "RED1" => Ok(Carrier::RED1),
             "RED2" => Ok(Carrier::RED2),
-            _ => Err(EpbdError::ParseError(s.into())),
+            "RESIDUOS" => Ok(Carrier::RESIDUOS),
+            "HIDROGENO" => Ok(Carrier::HIDROGENO),
+            // Alias en inglés (EN ISO 52000-1)
+            "ELECTRICITY" => Ok(Carrier::ELECTRICIDAD),
+            "ENVIRONMENT" => Ok(Carrier::MEDIOAMBIENTE),
+            "BIOFUEL" => Ok(Carrier::BIOCARBURANTE),
+            "BIOMASS" => Ok(Carrier::BIOMASA),
+            "DENSIFIED_BIOMASS" => Ok(Carrier::BIOMASADENSIFICADA),
+            "COAL" => Ok(Carrier::CARBON),
+            "NATURAL_GAS" => Ok(Carrier::GASNATURAL),
+            "DIESEL_OIL" => Ok(Carrier::GASOLEO),
+            "LPG" => Ok(Carrier::GLP),
+            "DISTRICT1" => Ok(Carrier::RED1),
+            "DISTRICT2" => Ok(Carrier::RED2),
+            "WASTE" => Ok(Carrier::RESIDUOS),
+            "HYDROGEN" => Ok(Carrier::HIDROGENO),
+            _ => {
+                if let Some(name) = s.strip_prefix(USER_CARRIER_PREFIX) {
+                    return Ok(Carrier::USUARIO(UserCarrier::new(name)?));
+                }
+                // Redes de distrito adicionales (RED3, RED4, ...)
+                s.strip_prefix("RED")
+                    .filter(|n| !n.starts_with('0'))
+                    .and_then(|n| n.parse::<u8>().ok())
+                    .and_then(Carrier::district)
+                    .ok_or_else(|| EpbdError::ParseError(s.into()))
+            }
         }
     }
 }
 
 impl std::fmt::Display for Carrier {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:?}", self)
+        match self {
+            Carrier::REDN(n) => write!(f, "RED{}", n),
+            Carrier::USUARIO(c) => write!(f, "{}{}", USER_CARRIER_PREFIX, c.name()),
+            _ => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl Serialize for Carrier {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Carrier {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
     }
 }
 
@@ -94,14 +247,16 @@ impl std::fmt::Display for Carrier {
 
 // -------------------- CType
 
-/// Tipo del componente (energía consumida o producida)
+/// Tipo del componente (energía consumida o producida, o ajuste de la energía consumida)
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum CType {
     /// Produced energy
     PRODUCCION,
     /// Consumed energy
     CONSUMO,
+    /// Adjustment (signed) of consumed energy (metering corrections, estimated deductions)
+    AJUSTE,
 }
 
 impl str::FromStr for CType {
@@ -111,6 +266,11 @@ impl str::FromStr for CType {
         match s {
             "PRODUCCION" => Ok(CType::PRODUCCION),
             "CONSUMO" => Ok(CType::CONSUMO),
+            "AJUSTE" => Ok(CType::AJUSTE),
+            // Alias en inglés (EN ISO 52000-1)
+            "PRODUCTION" => Ok(CType::PRODUCCION),
+            "CONSUMPTION" => Ok(CType::CONSUMO),
+            "ADJUSTMENT" => Ok(CType::AJUSTE),
             _ => Err(EpbdError::ParseError(s.into())),
         }
     }
@@ -122,11 +282,66 @@ impl std::fmt::Display for CType {
     }
 }
 
+// -------------------- Timestep
+
+/// Duración del paso de cálculo de los componentes
+///
+/// Se declara en el metadato `CTE_PASO` de los componentes. Los valores de cada componente
+/// corresponden a una o varias anualidades completas con este paso de cálculo.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Timestep {
+    /// Hourly timesteps (8760 values per year)
+    HORARIO,
+    /// Monthly timesteps (12 values per year)
+    MENSUAL,
+    /// Annual timesteps (1 value per year)
+    ANUAL,
+}
+
+impl Timestep {
+    /// Número de pasos de cálculo de una anualidad
+    pub fn steps_per_year(self) -> usize {
+        match self {
+            Timestep::HORARIO => 8760,
+            Timestep::MENSUAL => 12,
+            Timestep::ANUAL => 1,
+        }
+    }
+
+    /// Paso de cálculo que corresponde a un número de valores de una anualidad, si existe
+    pub fn from_num_steps(num_steps: usize) -> Option<Timestep> {
+        [Timestep::HORARIO, Timestep::MENSUAL, Timestep::ANUAL]
+            .iter()
+            .copied()
+            .find(|t| t.steps_per_year() == num_steps)
+    }
+}
+
+impl str::FromStr for Timestep {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Timestep, Self::Err> {
+        match s {
+            "HORARIO" => Ok(Timestep::HORARIO),
+            "MENSUAL" => Ok(Timestep::MENSUAL),
+            "ANUAL" => Ok(Timestep::ANUAL),
+            _ => Err(EpbdError::ParseError(s.into())),
+        }
+    }
+}
+
+impl std::fmt::Display for Timestep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
 // -------------------- CSubtype
 
 /// Subtipo del componente (origen o destino de la energía)
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum CSubtype {
     /// on site energy source
     INSITU,
@@ -147,6 +362,9 @@ impl str::FromStr for CSubtype {
             "COGENERACION" => Ok(CSubtype::COGENERACION),
             "EPB" => Ok(CSubtype::EPB),
             "NEPB" => Ok(CSubtype::NEPB),
+            // Alias en inglés (EN ISO 52000-1)
+            "ONSITE" => Ok(CSubtype::INSITU),
+            "COGENERATION" => Ok(CSubtype::COGENERACION),
             _ => Err(EpbdError::ParseError(s.into())),
         }
     }
@@ -162,7 +380,9 @@ impl std::fmt::Display for CSubtype {
 
 /// Uso al que está destinada la energía
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Default,
+)]
 pub enum Service {
     /// DHW
     ACS,
@@ -180,12 +400,15 @@ pub enum Service {
     DHU,
     /// Building automation and control
     BAC,
+    /// Electric vehicle charging
+    RECARGAVE,
     /// Undefined or generic use
+    #[default]
     NDEF,
 }
 
 /// Lista de usos disponibles
-pub const SERVICES: [Service; 9] = [
+pub const SERVICES: [Service; 10] = [
     Service::ACS,
     Service::CAL,
     Service::REF,
@@ -194,6 +417,7 @@ pub const SERVICES: [Service; 9] = [
     Service::HU,
     Service::DHU,
     Service::BAC,
+    Service::RECARGAVE,
     Service::NDEF,
 ];
 
@@ -214,7 +438,16 @@ impl str::FromStr for Service {
             "HU" => Ok(Service::HU),
             "DHU" => Ok(Service::DHU),
             "BAC" => Ok(Service::BAC),
+            "RECARGAVE" => Ok(Service::RECARGAVE),
             "NDEF" => Ok(Service::NDEF),
+            // Alias en inglés (EN ISO 52000-1)
+            "DHW" => Ok(Service::ACS),
+            "VENTILATION" => Ok(Service::VEN),
+            "LIGHTING" => Ok(Service::ILU),
+            "HUMIDIFICATION" => Ok(Service::HU),
+            "DEHUMIDIFICATION" => Ok(Service::DHU),
+            "BACS" => Ok(Service::BAC),
+            "EV_CHARGING" => Ok(Service::RECARGAVE),
             "" => Ok(Service::default()),
             _ => Err(EpbdError::ParseError(s.into())),
         }
@@ -227,9 +460,43 @@ impl std::fmt::Display for Service {
     }
 }
 
-impl Default for Service {
-    fn default() -> Service {
-        Service::NDEF
+// -------------------- Perimeter
+
+/// Perímetro de evaluación de la energía (assessment boundary)
+///
+/// Los factores de paso se definen, por defecto, en el perímetro distante. El perímetro
+/// próximo considera renovable únicamente la energía procedente del edificio, su entorno
+/// próximo y los vectores próximos (p.e. biomasa o redes de distrito).
+#[allow(non_camel_case_types)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, Default,
+)]
+pub enum Perimeter {
+    /// Distant perimeter
+    #[default]
+    DISTANT,
+    /// Nearby perimeter
+    NEARBY,
+    /// On-site perimeter
+    ONSITE,
+}
+
+impl str::FromStr for Perimeter {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Perimeter, Self::Err> {
+        match s {
+            "DISTANT" | "DISTANTE" => Ok(Perimeter::DISTANT),
+            "NEARBY" | "PROXIMO" => Ok(Perimeter::NEARBY),
+            "ONSITE" | "INSITU" => Ok(Perimeter::ONSITE),
+            _ => Err(EpbdError::ParseError(s.into())),
+        }
+    }
+}
+
+impl std::fmt::Display for Perimeter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}", self)
     }
 }
 
@@ -237,57 +504,356 @@ impl Default for Service {
 // Define basic Component and Components (Compoment list + Metadata) types
 
 /// Componente de energía.
-/// 
+///
 /// Representa la producción o consumo de energía para cada paso de cálculo
 /// y a lo largo del periodo de cálculo, para cada tipo, subtipo y uso de la energía.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Component {
+    /// System id (0 when the component is not assigned to a system, negative for fictitious or reference systems)
+    #[serde(default)]
+    pub id: i32,
     /// Carrier name
     pub carrier: Carrier,
-    /// Produced (`PRODUCCION`) or consumed (`CONSUMO`) component type
+    /// Produced (`PRODUCCION`), consumed (`CONSUMO`) or consumption adjustment (`AJUSTE`) component type
     pub ctype: CType,
-    /// Energy origin (`INSITU` or `COGENERACION`) for produced component types or end use type (`EPB` or `NEPB`) for consumed component types
+    /// Energy origin (`INSITU` or `COGENERACION`) for produced component types or end use type (`EPB` or `NEPB`) for consumed and adjustment component types
     pub csubtype: CSubtype,
     /// End use
+    #[serde(default)]
     pub service: Service,
+    /// Auxiliary energy use (pumps, fans, controls...) for consumed and adjustment component types
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub aux: bool,
     /// List of energy values, one value for each timestep
-    pub values: Vec<f32>,
-    /// Descriptive comment string
+    pub values: Vec<Real>,
+    /// Descriptive comment string (reason of the adjustment for `AJUSTE` components), with optional `key=value` attributes
+    #[serde(default)]
     pub comment: String,
 }
 
+/// Etiqueta del comentario de los componentes generados automáticamente por el programa
+pub const GENERATED_TAG: &str = "CTEEPBD_GENERADO";
+
+/// Atributos `clave=valor` de un comentario, en orden de aparición
+///
+/// Los atributos se separan por espacios, por lo que ni la clave ni el valor pueden contener
+/// espacios (p.e. `# Bomba circuito=primario origen=HULC`). La clave no puede estar vacía.
+pub fn parse_attrs(comment: &str) -> Vec<(String, String)> {
+    attr_tokens(comment)
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Atributo `clave=valor` de un elemento del comentario, si lo es
+fn attr_token(token: &str) -> Option<(&str, &str)> {
+    token.split_once('=').filter(|(key, _)| !key.is_empty())
+}
+
+/// Atributos `clave=valor` de un comentario, en orden de aparición (ver `parse_attrs`)
+fn attr_tokens(comment: &str) -> impl Iterator<Item = (&str, &str)> {
+    comment.split_whitespace().filter_map(attr_token)
+}
+
+/// Indica si el texto puede usarse como clave o valor de un atributo `clave=valor`
+///
+/// No puede contener espacios ni los caracteres `=` o `#`.
+fn is_valid_attr_text(text: &str) -> bool {
+    !text.contains(|c: char| c.is_whitespace() || c == '=' || c == '#')
+}
+
+/// Marca de los componentes de energía auxiliar en formato de texto, tras el servicio
+pub const AUX_MARK: &str = "AUX";
+
+/// Etiqueta del comentario que marcaba, en versiones anteriores, los consumos auxiliares eléctricos
+///
+/// Se interpreta como la marca de energía auxiliar (`AUX_MARK`) al leer los componentes.
+pub const AUX_LEGACY_TAG: &str = "CTEEPBD_EXCLUYE_AUX_ACS";
+
+impl Component {
+    /// Indica si el componente contribuye a la energía consumida (consumos y ajustes de consumo)
+    pub fn is_used_energy(&self) -> bool {
+        matches!(self.ctype, CType::CONSUMO | CType::AJUSTE)
+    }
+
+    /// Indica si el componente corresponde a un sistema ficticio o de referencia (id negativo)
+    ///
+    /// Estos componentes no se incluyen en el balance del edificio, sino en el del edificio de
+    /// referencia (ver `Components::reference`).
+    pub fn is_reference(&self) -> bool {
+        self.id < 0
+    }
+
+    /// Indica si el componente ha sido generado automáticamente (p.e. al normalizar los componentes)
+    ///
+    /// Los componentes generados incluyen la etiqueta `GENERATED_TAG` en el comentario.
+    pub fn is_generated(&self) -> bool {
+        self.comment.contains(GENERATED_TAG)
+    }
+
+    /// Atributos `clave=valor` del comentario, en orden de aparición (ver `parse_attrs`)
+    pub fn attrs(&self) -> Vec<(&str, &str)> {
+        attr_tokens(&self.comment).collect()
+    }
+
+    /// Valor del atributo `clave=valor` del comentario con la clave indicada, si existe
+    pub fn attr(&self, key: &str) -> Option<&str> {
+        attr_tokens(&self.comment)
+            .find(|(k, _)| *k == key)
+            .map(|(_, v)| v)
+    }
+
+    /// Actualiza o añade un atributo `clave=valor` del componente
+    ///
+    /// Los atributos se guardan en el comentario, por lo que se conservan al guardar los
+    /// componentes. Los atributos nuevos se añaden al final del comentario.
+    ///
+    /// # Errors
+    ///
+    /// * Clave vacía o clave o valor con espacios o con los caracteres `=` o `#`
+    pub fn set_attr(&mut self, key: &str, value: &str) -> Result<(), EpbdError> {
+        if key.is_empty() || !is_valid_attr_text(key) || !is_valid_attr_text(value) {
+            return Err(EpbdError::WrongInput(format!(
+                "Atributo de componente incorrecto: {}={}",
+                key, value
+            )));
+        }
+        let token = format!("{}={}", key, value);
+        if self.attr(key).is_some() {
+            let mut replaced = false;
+            self.comment = self
+                .comment
+                .split_whitespace()
+                .map(|t| match attr_token(t) {
+                    Some((k, _)) if k == key && !replaced => {
+                        replaced = true;
+                        token.as_str()
+                    }
+                    _ => t,
+                })
+                .collect::<Vec<_>>()
+                .join(" ");
+        } else {
+            self.comment = format!("{} {}", self.comment, token).trim().to_string();
+        }
+        Ok(())
+    }
+
+    /// Multiplica los valores del componente por un factor
+    pub fn scale(&mut self, factor: Real) {
+        self.values.iter_mut().for_each(|v| *v *= factor);
+    }
+
+    /// Componente con valores mensuales obtenido repartiendo sus valores anuales según un perfil
+    ///
+    /// Cada valor del componente corresponde a una anualidad y se reparte entre los 12 meses en
+    /// proporción a los valores del perfil, que no es necesario que sumen la unidad (p.e. se
+    /// pueden usar los días de cada mes o un consumo mensual tipo). Permite usar datos
+    /// exclusivamente anuales (p.e. facturas) junto con datos mensuales. Ver
+    /// `cte::cte_monthly_profile` para los perfiles mensuales tipo de cada servicio.
+    ///
+    /// # Errors
+    ///
+    /// * Perfil sin 12 valores, con valores negativos o con suma nula
+    pub fn expand_annual(&self, profile: &[Real]) -> Result<Component, EpbdError> {
+        let total: Real = profile.iter().sum();
+        if profile.len() != 12 || profile.iter().any(|v| *v < 0.0) || total <= 0.0 {
+            return Err(EpbdError::WrongInput(format!(
+                "perfil mensual incorrecto ({} valores, suma {}): se necesitan 12 valores no negativos de suma positiva",
+                profile.len(),
+                total
+            )));
+        }
+        let values = self
+            .values
+            .iter()
+            .flat_map(|v| profile.iter().map(move |p| v * p / total))
+            .collect();
+        Ok(Component {
+            values,
+            ..self.clone()
+        })
+    }
+}
+
+/// Número de decimales por defecto de los valores de energía de los componentes
+pub const COMPONENT_DECIMALS: usize = 2;
+
+/// Número de decimales por defecto de los factores de paso
+pub const FACTOR_DECIMALS: usize = 3;
+
+/// Representación textual del componente
+///
+/// Los valores se muestran con la precisión indicada en el formato (p.e. `{:.4}`) o, si no se
+/// indica, con `COMPONENT_DECIMALS` decimales.
 impl fmt::Display for Component {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let decimals = f.precision().unwrap_or(COMPONENT_DECIMALS);
         let valuelist = self
             .values
             .iter()
-            .map(|v| format!("{:.2}", v))
+            .map(|v| format!("{:.*}", decimals, v))
             .collect::<Vec<_>>()
             .join(", ");
-        let comment = if self.comment != "" {
+        let comment = if !self.comment.is_empty() {
             format!(" # {}", self.comment)
         } else {
             "".to_owned()
         };
+        let id = if self.id != 0 {
+            format!("{}, ", self.id)
+        } else {
+            "".to_owned()
+        };
+        let aux = if self.aux {
+            format!("{}, ", AUX_MARK)
+        } else {
+            "".to_owned()
+        };
         write!(
             f,
-            "{}, {}, {}, {}, {}{}",
-            self.carrier, self.ctype, self.csubtype, self.service, valuelist, comment
+            "{}{}, {}, {}, {}, {}{}{}",
+            id, self.carrier, self.ctype, self.csubtype, self.service, aux, valuelist, comment
         )
     }
 }
 
+/// Días de cada mes de un año no bisiesto
+pub(crate) const DAYS_PER_MONTH: [usize; 12] = [31, 28, 31, 30, 31, 30, 31, 31, 30, 31, 30, 31];
+
+/// Trait común para elementos con una lista de valores por paso de cálculo
+///
+/// Incluye las operaciones de resumen más habituales de los valores para informes y gráficas.
+pub trait HasValues {
+    /// Get list of values, one for each timestep
+    fn values(&self) -> &[Real];
+
+    /// Suma de todos los valores
+    fn total(&self) -> Real {
+        self.values().iter().sum()
+    }
+
+    /// Totales mensuales de los valores horarios (8760 valores por anualidad) o mensuales (12
+    /// valores por anualidad), con 12 valores por anualidad
+    ///
+    /// Los valores horarios se agrupan por meses con años de 365 días. Devuelve `None` si el
+    /// número de valores no corresponde a anualidades horarias o mensuales completas.
+    fn monthly_totals(&self) -> Option<Vec<Real>> {
+        let values = self.values();
+        let hours_per_year = Timestep::HORARIO.steps_per_year();
+        let months_per_year = Timestep::MENSUAL.steps_per_year();
+        if values.is_empty() {
+            None
+        } else if values.len() % hours_per_year == 0 {
+            let mut totals = Vec::with_capacity(values.len() / hours_per_year * months_per_year);
+            let mut start = 0;
+            for days in DAYS_PER_MONTH.iter().cycle() {
+                if start >= values.len() {
+                    break;
+                }
+                let end = start + days * 24;
+                totals.push(values[start..end].iter().sum());
+                start = end;
+            }
+            Some(totals)
+        } else if values.len() % months_per_year == 0 {
+            Some(values.to_vec())
+        } else {
+            None
+        }
+    }
+
+    /// Valor máximo, o `None` si no hay valores
+    fn peak(&self) -> Option<Real> {
+        self.values()
+            .iter()
+            .copied()
+            .fold(None, |max, v| match max {
+                Some(m) if m >= v => Some(m),
+                _ => Some(v),
+            })
+    }
+
+    /// Valor medio por paso de cálculo, o `None` si no hay valores
+    fn mean(&self) -> Option<Real> {
+        let values = self.values();
+        if values.is_empty() {
+            None
+        } else {
+            Some(self.total() / values.len() as Real)
+        }
+    }
+
+    /// Número de pasos de cálculo con valor no nulo
+    fn nonzero_count(&self) -> usize {
+        self.values().iter().filter(|v| **v != 0.0).count()
+    }
+}
+
+impl HasValues for Component {
+    fn values(&self) -> &[Real] {
+        &self.values
+    }
+}
+
+/// Comprueba la compatibilidad del vector, tipo y subtipo de un componente
+///
+/// Los ajustes deben indicar su motivo en el comentario.
+fn is_valid_component_type(
+    carrier: Carrier,
+    ctype: CType,
+    csubtype: CSubtype,
+    comment: &str,
+) -> bool {
+    use self::CSubtype::*;
+    use self::CType::*;
+    use self::Carrier::{ELECTRICIDAD, MEDIOAMBIENTE};
+
+    match ctype {
+        CONSUMO => matches!(csubtype, EPB | NEPB),
+        AJUSTE => matches!(csubtype, EPB | NEPB) && !comment.is_empty(),
+        PRODUCCION => match csubtype {
+            INSITU => matches!(carrier, ELECTRICIDAD | MEDIOAMBIENTE) || carrier.is_district(),
+            COGENERACION => carrier == ELECTRICIDAD || carrier.is_district(),
+            _ => false,
+        },
+    }
+}
+
+impl Component {
+    /// Comprueba la coherencia del vector, tipo y subtipo del componente
+    ///
+    /// Realiza las mismas comprobaciones que la interpretación de un componente en formato de
+    /// texto, para los componentes obtenidos por otros medios (p.e. desde JSON).
+    ///
+    /// # Errors
+    ///
+    /// * Tipo o subtipo incompatibles o ajustes sin motivo
+    pub fn check(&self) -> Result<(), EpbdError> {
+        if is_valid_component_type(
+            self.carrier.clone(),
+            self.ctype,
+            self.csubtype,
+            &self.comment,
+        ) {
+            Ok(())
+        } else {
+            Err(EpbdError::ParseError(self.to_string()))
+        }
+    }
+}
+
 impl str::FromStr for Component {
     type Err = EpbdError;
 
     fn from_str(s: &str) -> Result<Component, Self::Err> {
-        use self::CSubtype::*;
-        use self::CType::*;
-        use self::Carrier::{ELECTRICIDAD, MEDIOAMBIENTE};
-
         let items: Vec<&str> = s.trim().splitn(2, '#').map(str::trim).collect();
         let comment = items.get(1).unwrap_or(&"").to_string();
         let items: Vec<&str> = items[0].split(',').map(str::trim).collect();
+        // Optional system id as first item
+        let (id, items) = match items[0].parse::<i32>() {
+            Ok(id) => (id, &items[1..]),
+            Err(_) => (0, &items[..]),
+        };
         if items.len() < 4 {
             return Err(EpbdError::ParseError(s.into()));
         };
@@ -302,18 +868,7 @@ impl str::FromStr for Component {
         let csubtype: CSubtype = items[2]
             .parse()
             .map_err(|_| EpbdError::ParseError(items[2].into()))?;
-        let carrier_ok = match ctype {
-            CONSUMO => match csubtype {
-                EPB | NEPB => true,
-                _ => false,
-            },
-            PRODUCCION => match csubtype {
-                INSITU => carrier == ELECTRICIDAD || carrier == MEDIOAMBIENTE,
-                COGENERACION => carrier == ELECTRICIDAD,
-                _ => false,
-            },
-        };
-        if !carrier_ok {
+        if !is_valid_component_type(carrier.clone(), ctype, csubtype, &comment) {
             return Err(EpbdError::ParseError(s.into()));
         }
         //This accounts for the legacy version, which may not have a service type
@@ -322,28 +877,385 @@ impl str::FromStr for Component {
             Ok(s) => (4, s),
             Err(_) => (3, Service::default()),
         };
+        // Optional auxiliary energy mark after the service (or the legacy comment tag)
+        let (valuesidx, aux) = if items.get(valuesidx) == Some(&AUX_MARK) {
+            (valuesidx + 1, true)
+        } else {
+            (valuesidx, comment.contains(AUX_LEGACY_TAG))
+        };
+        if aux && ctype == CType::PRODUCCION {
+            return Err(EpbdError::ParseError(s.into()));
+        }
         let values = items[valuesidx..]
             .iter()
-            .map(|v| v.parse::<f32>())
-            .collect::<Result<Vec<f32>, _>>()?;
+            .map(|v| {
+                v.parse::<Real>()
+                    .map_err(|_| EpbdError::ParseError((*v).into()))
+            })
+            .collect::<Result<Vec<Real>, _>>()?;
         Ok(Component {
+            id,
             carrier,
             ctype,
             csubtype,
             service,
+            aux,
             values,
             comment,
         })
     }
 }
 
+// -------------------- UnknownComponent
+
+/// Componente con un vector energético o servicio no reconocido
+///
+/// Permite conservar los componentes de versiones más recientes del formato, que se
+/// excluyen del balance pero se mantienen en la serialización.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct UnknownComponent {
+    /// Unrecognized carrier or service identifier
+    pub identifier: String,
+    /// Original component line
+    pub line: String,
+}
+
+impl fmt::Display for UnknownComponent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.line)
+    }
+}
+
+impl str::FromStr for UnknownComponent {
+    type Err = EpbdError;
+
+    /// Identifica una línea de componente con un vector o servicio no reconocido
+    ///
+    /// Falla si el vector y el servicio son conocidos o si la línea no tiene el formato de un
+    /// componente (tipo y subtipo válidos y valores numéricos).
+    fn from_str(s: &str) -> Result<UnknownComponent, Self::Err> {
+        let is_identifier = |v: &str| {
+            !v.is_empty()
+                && v.parse::<Real>().is_err()
+                && v.chars().all(|c| c.is_alphanumeric() || c == '_')
+        };
+        let items: Vec<&str> = s.trim().splitn(2, '#').map(str::trim).collect();
+        let items: Vec<&str> = items[0].split(',').map(str::trim).collect();
+        let items = match items[0].parse::<i32>() {
+            Ok(_) => &items[1..],
+            Err(_) => &items[..],
+        };
+        if items.len() < 5
+            || items[1].parse::<CType>().is_err()
+            || items[2].parse::<CSubtype>().is_err()
+            || items[4..].iter().any(|v| v.parse::<Real>().is_err())
+        {
+            return Err(EpbdError::ParseError(s.into()));
+        }
+        let identifier = if items[0].parse::<Carrier>().is_err() && is_identifier(items[0]) {
+            items[0]
+        } else if items[3].parse::<Service>().is_err() && is_identifier(items[3]) {
+            items[3]
+        } else {
+            return Err(EpbdError::ParseError(s.into()));
+        };
+        Ok(UnknownComponent {
+            identifier: identifier.to_string(),
+            line: s.trim().to_string(),
+        })
+    }
+}
+
+// -------------------- System
+
+/// Sistema técnico del edificio
+///
+/// Describe un sistema al que pueden asignarse componentes mediante su id.
+/// Formato: `id, nombre, tipo, vector, potencia nominal [kW]`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct System {
+    /// System id (> 0)
+    pub id: i32,
+    /// System name
+    pub name: String,
+    /// System type (e.g. CALDERA, BDC, FOTOVOLTAICA)
+    pub stype: String,
+    /// Main carrier (fuel) used by the system
+    pub carrier: Carrier,
+    /// Nominal power [kW]
+    pub power: Real,
+}
+
+impl fmt::Display for System {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {}, {}, {}, {:.2}",
+            self.id, self.name, self.stype, self.carrier, self.power
+        )
+    }
+}
+
+impl str::FromStr for System {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<System, Self::Err> {
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        if items.len() != 5 {
+            return Err(EpbdError::ParseError(s.into()));
+        };
+        let id = match items[0].parse::<i32>() {
+            Ok(id) if id > 0 => id,
+            _ => return Err(EpbdError::ParseError(items[0].into())),
+        };
+        let carrier: Carrier = items[3]
+            .parse()
+            .map_err(|_| EpbdError::ParseError(items[3].into()))?;
+        let power: Real = items[4].parse()?;
+        Ok(System {
+            id,
+            name: items[1].to_string(),
+            stype: items[2].to_string(),
+            carrier,
+            power,
+        })
+    }
+}
+
+// -------------------- Conversion
+
+/// Conversión de energía en el edificio entre dos sistemas
+///
+/// El sistema de origen transforma la energía que consume (p.e. electricidad en una caldera
+/// eléctrica o calor residual recuperado) en energía que aprovecha el sistema de destino, con el
+/// rendimiento indicado.
+/// Formato: `id origen, id destino, rendimiento`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Conversion {
+    /// Id of the system that converts the energy (origin)
+    pub from: i32,
+    /// Id of the system that uses the converted energy (destination)
+    pub to: i32,
+    /// Conversion efficiency (output / input) [-]
+    pub efficiency: Real,
+}
+
+impl fmt::Display for Conversion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}, {}, {:.3}", self.from, self.to, self.efficiency)
+    }
+}
+
+impl str::FromStr for Conversion {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Conversion, Self::Err> {
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        if items.len() != 3 {
+            return Err(EpbdError::ParseError(s.into()));
+        };
+        let parse_id = |v: &str| match v.parse::<i32>() {
+            Ok(id) if id > 0 => Ok(id),
+            _ => Err(EpbdError::ParseError(v.into())),
+        };
+        let from = parse_id(items[0])?;
+        let to = parse_id(items[1])?;
+        let efficiency: Real = items[2].parse()?;
+        if from == to || efficiency <= 0.0 {
+            return Err(EpbdError::ParseError(s.into()));
+        }
+        Ok(Conversion {
+            from,
+            to,
+            efficiency,
+        })
+    }
+}
+
+// -------------------- Storage
+
+/// Almacenamiento de energía producida in situ (batería o depósito de acumulación térmica)
+///
+/// La energía producida que no se usa en el mismo paso de cálculo para servicios EPB carga
+/// el almacenamiento, hasta su capacidad, y la energía almacenada cubre los usos EPB de pasos de
+/// cálculo posteriores. Las pérdidas del almacenamiento se imputan en la carga según el rendimiento.
+/// Formato: `capacidad, rendimiento`
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Storage {
+    /// Usable storage capacity [kWh]
+    pub capacity: Real,
+    /// Round-trip efficiency (discharged / charged energy) [-]
+    pub efficiency: Real,
+}
+
+impl Storage {
+    /// Carga y descarga del almacenamiento en cada paso de cálculo
+    ///
+    /// Parte del almacenamiento vacío y, en cada paso de cálculo, almacena el excedente de
+    /// producción (`surplus`) que cabe en él y descarga la energía almacenada necesaria para
+    /// cubrir la demanda no satisfecha (`deficit`). Devuelve la energía cargada y descargada en
+    /// cada paso de cálculo.
+    pub fn operate(&self, surplus: &[Real], deficit: &[Real]) -> (Vec<Real>, Vec<Real>) {
+        let mut stored = 0.0;
+        let mut charged = Vec::with_capacity(surplus.len());
+        let mut discharged = Vec::with_capacity(surplus.len());
+        for (&surplus, &deficit) in surplus.iter().zip(deficit.iter()) {
+            let charge = surplus
+                .max(0.0)
+                .min((self.capacity - stored) / self.efficiency);
+            stored += charge * self.efficiency;
+            let discharge = deficit.max(0.0).min(stored);
+            stored -= discharge;
+            charged.push(charge);
+            discharged.push(discharge);
+        }
+        (charged, discharged)
+    }
+}
+
+impl fmt::Display for Storage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:.2}, {:.3}", self.capacity, self.efficiency)
+    }
+}
+
+impl str::FromStr for Storage {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<Storage, Self::Err> {
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        if items.len() != 2 {
+            return Err(EpbdError::ParseError(s.into()));
+        };
+        let capacity: Real = items[0].parse()?;
+        let efficiency: Real = items[1].parse()?;
+        if capacity < 0.0 || efficiency <= 0.0 || efficiency > 1.0 {
+            return Err(EpbdError::ParseError(s.into()));
+        }
+        Ok(Storage {
+            capacity,
+            efficiency,
+        })
+    }
+}
+
+// -------------------- EnergyNeed
+
+/// Necesidad de energía de un servicio
+///
+/// Energía que deben aportar los sistemas para atender un servicio (p.e. la demanda de
+/// calefacción o de ACS) en cada paso de cálculo.
+/// Formato: `servicio, valor1, valor2, ...`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EnergyNeed {
+    /// End use
+    pub service: Service,
+    /// Energy need in each timestep [kWh]
+    pub values: Vec<Real>,
+}
+
+impl fmt::Display for EnergyNeed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let values = self
+            .values
+            .iter()
+            .map(|v| format!("{:.2}", v))
+            .collect::<Vec<_>>();
+        write!(f, "{}, {}", self.service, values.join(", "))
+    }
+}
+
+impl str::FromStr for EnergyNeed {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<EnergyNeed, Self::Err> {
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        if items.len() < 2 {
+            return Err(EpbdError::ParseError(s.into()));
+        };
+        let service: Service = items[0].parse()?;
+        let values = items[1..]
+            .iter()
+            .map(|v| v.parse::<Real>())
+            .collect::<Result<Vec<Real>, _>>()?;
+        if service == Service::NDEF || values.iter().any(|v| *v < 0.0) {
+            return Err(EpbdError::ParseError(s.into()));
+        }
+        Ok(EnergyNeed { service, values })
+    }
+}
+
+// -------------------- SystemEfficiency
+
+/// Rendimiento de un sistema que atiende la necesidad de energía de un servicio
+///
+/// El sistema aporta la fracción indicada de la necesidad del servicio consumiendo el vector
+/// energético indicado con el rendimiento (o COP) indicado. Con rendimientos mayores que la
+/// unidad (bombas de calor), la diferencia entre la energía aportada y la consumida procede del
+/// medioambiente.
+/// Formato: `id, servicio, vector, fracción, rendimiento`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SystemEfficiency {
+    /// System id (0 when not assigned to a declared system)
+    pub id: i32,
+    /// End use
+    pub service: Service,
+    /// Carrier used by the system
+    pub carrier: Carrier,
+    /// Share of the energy need of the service supplied by the system (0, 1]
+    pub fraction: Real,
+    /// Seasonal efficiency or COP (supplied energy / used energy) [-]
+    pub efficiency: Real,
+}
+
+impl fmt::Display for SystemEfficiency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}, {}, {}, {:.3}, {:.3}",
+            self.id, self.service, self.carrier, self.fraction, self.efficiency
+        )
+    }
+}
+
+impl str::FromStr for SystemEfficiency {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<SystemEfficiency, Self::Err> {
+        let items: Vec<&str> = s.split(',').map(str::trim).collect();
+        if items.len() != 5 {
+            return Err(EpbdError::ParseError(s.into()));
+        };
+        let id = match items[0].parse::<i32>() {
+            Ok(id) if id >= 0 => id,
+            _ => return Err(EpbdError::ParseError(items[0].into())),
+        };
+        let service: Service = items[1].parse()?;
+        let carrier: Carrier = items[2]
+            .parse()
+            .map_err(|_| EpbdError::ParseError(items[2].into()))?;
+        let fraction: Real = items[3].parse()?;
+        let efficiency: Real = items[4].parse()?;
+        if service == Service::NDEF || fraction <= 0.0 || fraction > 1.0 || efficiency <= 0.0 {
+            return Err(EpbdError::ParseError(s.into()));
+        }
+        Ok(SystemEfficiency {
+            id,
+            service,
+            carrier,
+            fraction,
+            efficiency,
+        })
+    }
+}
+
 // ==================== Weighting factors
 
 // -------------------- Source
 
 /// Fuente de origen de la energía
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum Source {
     /// Grid source
     RED,
@@ -361,6 +1273,10 @@ impl str::FromStr for Source {
             "RED" => Ok(Source::RED),
             "INSITU" => Ok(Source::INSITU),
             "COGENERACION" => Ok(Source::COGENERACION),
+            // Alias en inglés (EN ISO 52000-1)
+            "GRID" => Ok(Source::RED),
+            "ONSITE" => Ok(Source::INSITU),
+            "COGENERATION" => Ok(Source::COGENERACION),
             _ => Err(EpbdError::ParseError(s.into())),
         }
     }
@@ -378,7 +1294,10 @@ impl TryFrom<CSubtype> for Source {
         match subtype {
             CSubtype::INSITU => Ok(Self::INSITU),
             CSubtype::COGENERACION => Ok(Self::COGENERACION),
-            _ => Err(EpbdError::ParseError(format!("CSubtype as Source {}", subtype))),
+            _ => Err(EpbdError::ParseError(format!(
+                "CSubtype as Source {}",
+                subtype
+            ))),
         }
     }
 }
@@ -387,7 +1306,7 @@ impl TryFrom<CSubtype> for Source {
 
 /// Destino de la energía
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum Dest {
     /// Building delivery destination
     SUMINISTRO,
@@ -409,6 +1328,10 @@ impl str::FromStr for Dest {
             "to_grid" => Ok(Dest::A_RED),
             "to_nEPB" => Ok(Dest::A_NEPB),
             "input" => Ok(Dest::SUMINISTRO),
+            // Alias en inglés (EN ISO 52000-1)
+            "DELIVERY" => Ok(Dest::SUMINISTRO),
+            "EXPORTED_TO_GRID" => Ok(Dest::A_RED),
+            "EXPORTED_TO_NEPB" => Ok(Dest::A_NEPB),
             _ => Err(EpbdError::ParseError(s.into())),
         }
     }
@@ -424,7 +1347,7 @@ impl std::fmt::Display for Dest {
 
 /// Paso de cálculo para el que se define el factor de paso
 #[allow(non_camel_case_types)]
-#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
 pub enum Step {
     /// Calculation step A
     A,
@@ -467,15 +1390,20 @@ pub struct Factor {
     /// Evaluation step
     pub step: Step,
     /// Renewable primary energy for each end use unit of this carrier
-    pub ren: f32,
+    pub ren: Real,
     /// Non renewable primary energy for each end use unit of this carrier
-    pub nren: f32,
+    pub nren: Real,
     /// CO2 emissions for each end use unit of this carrier
-    pub co2: f32,
+    #[serde(default)]
+    pub co2: Real,
     /// Descriptive comment string for the weighting factor
+    #[serde(default)]
     pub comment: String,
 }
 
+/// Clave que identifica un factor de paso: vector, fuente, destino y paso
+pub type FactorKey = (Carrier, Source, Dest, Step);
+
 impl Factor {
     /// Constructor
     pub fn new<T: Into<String>>(
@@ -498,6 +1426,11 @@ impl Factor {
         }
     }
 
+    /// Clave del factor de paso (vector, fuente, destino y paso)
+    pub fn key(&self) -> FactorKey {
+        (self.carrier.clone(), self.source, self.dest, self.step)
+    }
+
     /// Obtener los factores de paso como estructura RenNrenCo2
     pub fn factors(&self) -> RenNrenCo2 {
         RenNrenCo2 {
@@ -515,17 +1448,32 @@ impl Factor {
     }
 }
 
+/// Representación textual del factor de paso
+///
+/// Los valores se muestran con la precisión indicada en el formato (p.e. `{:.4}`) o, si no se
+/// indica, con `FACTOR_DECIMALS` decimales.
 impl fmt::Display for Factor {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        let comment = if self.comment != "" {
+        let decimals = f.precision().unwrap_or(FACTOR_DECIMALS);
+        let comment = if !self.comment.is_empty() {
             format!(" # {}", self.comment)
         } else {
             "".to_owned()
         };
         write!(
             f,
-            "{}, {}, {}, {}, {:.3}, {:.3}, {:.3}{}",
-            self.carrier, self.source, self.dest, self.step, self.ren, self.nren, self.co2, comment
+            "{}, {}, {}, {}, {:.*}, {:.*}, {:.*}{}",
+            self.carrier,
+            self.source,
+            self.dest,
+            self.step,
+            decimals,
+            self.ren,
+            decimals,
+            self.nren,
+            decimals,
+            self.co2,
+            comment
         )
     }
 }
@@ -537,7 +1485,8 @@ impl str::FromStr for Factor {
         let items: Vec<&str> = s.trim().splitn(2, '#').map(str::trim).collect();
         let comment = items.get(1).unwrap_or(&"").to_string();
         let items: Vec<&str> = items[0].split(',').map(str::trim).collect();
-        if items.len() < 7 {
+        // El factor de emisiones es opcional (formato anterior con solo ren y nren)
+        if items.len() < 6 {
             return Err(EpbdError::ParseError(s.into()));
         };
         let carrier: Carrier = items[0]
@@ -552,9 +1501,16 @@ impl str::FromStr for Factor {
         let step: Step = items[3]
             .parse()
             .map_err(|_| EpbdError::ParseError(items[3].into()))?;
-        let ren: f32 = items[4].parse()?;
-        let nren: f32 = items[5].parse()?;
-        let co2: f32 = items[6].parse()?;
+        let parse_value = |v: &str| {
+            v.parse::<Real>()
+                .map_err(|_| EpbdError::ParseError(v.into()))
+        };
+        let ren = parse_value(items[4])?;
+        let nren = parse_value(items[5])?;
+        let co2 = match items.get(6) {
+            Some(co2) => parse_value(co2)?,
+            None => 0.0,
+        };
         Ok(Factor {
             carrier,
             source,
@@ -568,6 +1524,131 @@ impl str::FromStr for Factor {
     }
 }
 
+// -------------------- FactorSeries
+
+/// Marcador de los factores de paso variables por paso de cálculo
+pub const FACTOR_SERIES_TAG: &str = "SERIE";
+
+/// Factor de paso variable en el tiempo, con valores para cada paso de cálculo
+///
+/// Time-varying weighting factor, with ren, nren and co2 values for each timestep.
+/// Formato: `vector, fuente, uso, paso, SERIE, ren nren co2, ren nren co2, ...`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FactorSeries {
+    /// Energy carrier
+    pub carrier: Carrier,
+    /// Carrier source (`RED`, `INSITU` or `COGENERACION`)
+    pub source: Source,
+    /// Destination use of the energy (`SUMINISTRO`, `A_RED`, `A_NEPB`)
+    pub dest: Dest,
+    /// Evaluation step
+    pub step: Step,
+    /// Weighting factors for each timestep
+    pub values: Vec<RenNrenCo2>,
+    /// Descriptive comment string for the weighting factor
+    pub comment: String,
+}
+
+impl FactorSeries {
+    /// Factor de paso medio ponderado por la energía de cada paso de cálculo
+    ///
+    /// Si la energía total es nula se usa la media aritmética de los factores.
+    pub fn weighted_mean(&self, energy: &[Real]) -> RenNrenCo2 {
+        let total: Real = energy.iter().sum();
+        if total.abs() > Real::EPSILON {
+            self.weigh(energy) * (1.0 / total)
+        } else if self.values.is_empty() {
+            RenNrenCo2::default()
+        } else {
+            self.values
+                .iter()
+                .fold(RenNrenCo2::default(), |acc, v| acc + *v)
+                * (1.0 / self.values.len() as Real)
+        }
+    }
+
+    /// Energía ponderada con los factores de cada paso de cálculo
+    pub fn weigh(&self, energy: &[Real]) -> RenNrenCo2 {
+        energy
+            .iter()
+            .zip(self.values.iter())
+            .fold(RenNrenCo2::default(), |acc, (e, f)| acc + *f * *e)
+    }
+}
+
+impl fmt::Display for FactorSeries {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let decimals = f.precision().unwrap_or(FACTOR_DECIMALS);
+        let comment = if !self.comment.is_empty() {
+            format!(" # {}", self.comment)
+        } else {
+            "".to_owned()
+        };
+        let valuelist = self
+            .values
+            .iter()
+            .map(|v| {
+                format!(
+                    "{:.*} {:.*} {:.*}",
+                    decimals, v.ren, decimals, v.nren, decimals, v.co2
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+        write!(
+            f,
+            "{}, {}, {}, {}, {}, {}{}",
+            self.carrier, self.source, self.dest, self.step, FACTOR_SERIES_TAG, valuelist, comment
+        )
+    }
+}
+
+impl str::FromStr for FactorSeries {
+    type Err = EpbdError;
+
+    fn from_str(s: &str) -> Result<FactorSeries, Self::Err> {
+        let items: Vec<&str> = s.trim().splitn(2, '#').map(str::trim).collect();
+        let comment = items.get(1).unwrap_or(&"").to_string();
+        let items: Vec<&str> = items[0].split(',').map(str::trim).collect();
+        if items.len() < 6 || items[4] != FACTOR_SERIES_TAG {
+            return Err(EpbdError::ParseError(s.into()));
+        };
+        let carrier: Carrier = items[0]
+            .parse()
+            .map_err(|_| EpbdError::ParseError(items[0].into()))?;
+        let source: Source = items[1]
+            .parse()
+            .map_err(|_| EpbdError::ParseError(items[1].into()))?;
+        let dest: Dest = items[2]
+            .parse()
+            .map_err(|_| EpbdError::ParseError(items[2].into()))?;
+        let step: Step = items[3]
+            .parse()
+            .map_err(|_| EpbdError::ParseError(items[3].into()))?;
+        let values = items[5..]
+            .iter()
+            .map(|v| {
+                let v = v
+                    .split_whitespace()
+                    .map(str::parse::<Real>)
+                    .collect::<Result<Vec<Real>, _>>()?;
+                match v[..] {
+                    [ren, nren, co2] => Ok(RenNrenCo2 { ren, nren, co2 }),
+                    _ => Err(EpbdError::ParseError(s.into())),
+                }
+            })
+            .collect::<Result<Vec<RenNrenCo2>, EpbdError>>()?;
+        Ok(FactorSeries {
+            carrier,
+            source,
+            dest,
+            step,
+            values,
+            comment,
+        })
+    }
+}
+
 // ========================== Tests
 
 #[cfg(test)]
@@ -578,10 +1659,12 @@ mod tests {
     #[test]
     fn tcomponent() {
         let component1 = Component {
+            id: 0,
             carrier: "ELECTRICIDAD".parse().unwrap(),
             ctype: "CONSUMO".parse().unwrap(),
             csubtype: "EPB".parse().unwrap(),
             service: "REF".parse().unwrap(),
+            aux: false,
             values: vec![
                 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 12.0,
             ],
@@ -589,10 +1672,12 @@ mod tests {
         };
         let component1str = "ELECTRICIDAD, CONSUMO, EPB, REF, 1.00, 2.00, 3.00, 4.00, 5.00, 6.00, 7.00, 8.00, 9.00, 10.00, 11.00, 12.00 # Comentario cons 1";
         let component2 = Component {
+            id: 0,
             carrier: "ELECTRICIDAD".parse().unwrap(),
             ctype: "PRODUCCION".parse().unwrap(),
             csubtype: "INSITU".parse().unwrap(),
             service: "NDEF".parse().unwrap(),
+            aux: false,
             values: vec![
                 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0, 9.0, 10.0, 11.0, 12.0,
             ],
@@ -612,6 +1697,24 @@ mod tests {
             component2str.parse::<Component>().unwrap().to_string(),
             component2str
         );
+        // roundtrip building from/to string with system id
+        let component3str = "2, GASNATURAL, CONSUMO, EPB, CAL, 1.00, 2.00";
+        let component3 = component3str.parse::<Component>().unwrap();
+        assert_eq!(component3.id, 2);
+        assert_eq!(component3.to_string(), component3str);
+        // roundtrip building from/to string with auxiliary energy mark
+        let component4str = "ELECTRICIDAD, CONSUMO, EPB, ACS, AUX, 1.00, 2.00 # Bomba";
+        let component4 = component4str.parse::<Component>().unwrap();
+        assert!(component4.aux);
+        assert_eq!(component4.to_string(), component4str);
+        // legacy comment tag for auxiliary energy
+        let component5 = "ELECTRICIDAD, CONSUMO, EPB, ACS, 1.00 # Bomba CTEEPBD_EXCLUYE_AUX_ACS"
+            .parse::<Component>()
+            .unwrap();
+        assert!(component5.aux);
+        assert!("ELECTRICIDAD, PRODUCCION, INSITU, NDEF, AUX, 1.00"
+            .parse::<Component>()
+            .is_err());
         // roundtrip building from/to string for legacy format
         assert_eq!(
             component2strlegacy
@@ -622,6 +1725,229 @@ mod tests {
         );
     }
 
+    #[test]
+    fn component_attrs() {
+        let componentstr =
+            "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00 # Bomba circuito=primario origen=HULC";
+        let mut component = componentstr.parse::<Component>().unwrap();
+        assert_eq!(
+            component.attrs(),
+            vec![("circuito", "primario"), ("origen", "HULC")]
+        );
+        assert_eq!(component.attr("origen"), Some("HULC"));
+        assert_eq!(component.attr("Bomba"), None);
+        // Los atributos se conservan al guardar los componentes
+        assert_eq!(component.to_string(), componentstr);
+        // Actualización y adición de atributos
+        component.set_attr("circuito", "secundario").unwrap();
+        component.set_attr("zona", "Z1").unwrap();
+        assert_eq!(component.attr("circuito"), Some("secundario"));
+        assert_eq!(
+            component.comment,
+            "Bomba circuito=secundario origen=HULC zona=Z1"
+        );
+        let roundtrip = component.to_string().parse::<Component>().unwrap();
+        assert_eq!(roundtrip.attrs(), component.attrs());
+        // Los atributos se obtienen siempre del comentario
+        component.comment = "Bomba\tcircuito=terciario".to_string();
+        assert_eq!(component.attr("circuito"), Some("terciario"));
+        component.set_attr("circuito", "primario").unwrap();
+        assert_eq!(component.comment, "Bomba circuito=primario");
+        // Valores no admitidos
+        for value in &["con espacio", "a=b", "a#b"] {
+            assert!(component.set_attr("clave", value).is_err());
+        }
+        assert!(component.set_attr("", "valor").is_err());
+        assert_eq!(component.comment, "Bomba circuito=primario");
+        // Claves vacías
+        assert!(parse_attrs("=valor a=b").len() == 1);
+    }
+
+    #[test]
+    fn component_statistics() {
+        let component = "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 0.00, 3.00, 0.00, 5.00, 6.00, 0.00, 0.00, 0.00, 2.00, 4.00, 3.00"
+            .parse::<Component>()
+            .unwrap();
+        assert_eq!(component.total(), 24.0);
+        assert_eq!(component.peak(), Some(6.0));
+        assert_eq!(component.mean(), Some(2.0));
+        assert_eq!(component.nonzero_count(), 7);
+        assert_eq!(component.monthly_totals().unwrap(), component.values);
+        // Valores horarios
+        let hourly = format!(
+            "ELECTRICIDAD, CONSUMO, EPB, CAL, {}",
+            vec!["1.0"; 8760].join(", ")
+        )
+        .parse::<Component>()
+        .unwrap();
+        let monthly = hourly.monthly_totals().unwrap();
+        assert_eq!(monthly.len(), 12);
+        assert_eq!(monthly[0], 744.0);
+        assert_eq!(monthly[1], 672.0);
+        // Número de valores no mensual ni horario y sin valores
+        let component = "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.00, 2.00"
+            .parse::<Component>()
+            .unwrap();
+        assert_eq!(component.monthly_totals(), None);
+        let empty = Component {
+            values: vec![],
+            ..component
+        };
+        assert_eq!(empty.peak(), None);
+        assert_eq!(empty.mean(), None);
+    }
+
+    #[test]
+    fn english_aliases() {
+        assert_eq!(
+            "ELECTRICITY, PRODUCTION, ONSITE, NDEF, 1.00, 2.00 # Comentario"
+                .parse::<Component>()
+                .unwrap()
+                .to_string(),
+            "ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 1.00, 2.00 # Comentario"
+        );
+        assert_eq!(
+            "NATURAL_GAS, CONSUMPTION, EPB, DHW, 1.00, 2.00"
+                .parse::<Component>()
+                .unwrap()
+                .to_string(),
+            "GASNATURAL, CONSUMO, EPB, ACS, 1.00, 2.00"
+        );
+        assert_eq!(
+            "ELECTRICITY, COGENERATION, EXPORTED_TO_GRID, A, 0.0, 2.5, 0.3"
+                .parse::<Factor>()
+                .unwrap()
+                .to_string(),
+            "ELECTRICIDAD, COGENERACION, A_RED, A, 0.000, 2.500, 0.300"
+        );
+        assert_eq!(
+            "DISTRICT1, GRID, DELIVERY, A, 0.0, 1.3, 0.3"
+                .parse::<Factor>()
+                .unwrap()
+                .to_string(),
+            "RED1, RED, SUMINISTRO, A, 0.000, 1.300, 0.300"
+        );
+    }
+
+    #[test]
+    fn tdistrict_carriers() {
+        let red = |n| Carrier::district(n).unwrap();
+        assert_eq!("RED3".parse::<Carrier>().unwrap(), red(3));
+        assert!("DISTRICT12".parse::<Carrier>().is_err());
+        assert_eq!(red(12).to_string(), "RED12");
+        assert_eq!(red(12).district_number(), Some(12));
+        assert_eq!(Carrier::district(1), Some(Carrier::RED1));
+        assert_eq!(Carrier::district(0), None);
+        // Las redes 1 y 2 solo pueden representarse como RED1 y RED2
+        assert!(DistrictNumber::new(2).is_none());
+        assert!(red(3).is_district() && Carrier::RED2.is_district());
+        assert!(!Carrier::GASNATURAL.is_district());
+        assert!("RED0".parse::<Carrier>().is_err());
+        assert!("RED03".parse::<Carrier>().is_err());
+        assert!("RED256".parse::<Carrier>().is_err());
+        assert!(Carrier::RED2 < red(3));
+        // Producción in situ en redes de distrito adicionales
+        assert!("RED3, PRODUCCION, INSITU, NDEF, 1.00"
+            .parse::<Component>()
+            .is_ok());
+    }
+
+    #[test]
+    fn tuser_carrier() {
+        let carrier: Carrier = "USUARIO_CALORRESIDUAL".parse().unwrap();
+        assert_eq!(
+            carrier,
+            Carrier::USUARIO(UserCarrier::new("CALORRESIDUAL").unwrap())
+        );
+        assert_eq!(carrier.to_string(), "USUARIO_CALORRESIDUAL");
+        assert_ne!(carrier, "USUARIO_CALOR2".parse::<Carrier>().unwrap());
+        assert!("USUARIO_".parse::<Carrier>().is_err());
+        assert!("USUARIO_calor residual".parse::<Carrier>().is_err());
+        assert!("AMONIACO".parse::<Carrier>().is_err());
+        // No se pueden producir in situ
+        assert!("USUARIO_CALORRESIDUAL, PRODUCCION, INSITU, NDEF, 1.00"
+            .parse::<Component>()
+            .is_err());
+        // Serialización como cadena
+        let json = serde_json::to_string(&carrier).unwrap();
+        assert_eq!(json, "\"USUARIO_CALORRESIDUAL\"");
+        assert_eq!(serde_json::from_str::<Carrier>(&json).unwrap(), carrier);
+        assert_eq!(
+            serde_json::to_string(&Carrier::ELECTRICIDAD).unwrap(),
+            "\"ELECTRICIDAD\""
+        );
+    }
+
+    #[test]
+    fn tsystem() {
+        let system1str = "1, Caldera de gas, CALDERA, GASNATURAL, 24.00";
+        let system1 = system1str.parse::<System>().unwrap();
+        assert_eq!(system1.carrier, Carrier::GASNATURAL);
+        assert_eq!(system1.to_string(), system1str);
+        assert!("0, Caldera, CALDERA, GASNATURAL, 24.0"
+            .parse::<System>()
+            .is_err());
+        assert!("1, Caldera, CALDERA, GASNATURAL".parse::<System>().is_err());
+    }
+
+    #[test]
+    fn tfactor_series() {
+        let series1str =
+            "ELECTRICIDAD, RED, SUMINISTRO, A, SERIE, 0.400 2.000 0.300, 0.500 1.500 0.200 # Horaria";
+        let series1 = series1str.parse::<FactorSeries>().unwrap();
+        assert_eq!(series1.values.len(), 2);
+        assert_eq!(series1.to_string(), series1str);
+        assert_eq!(series1.weigh(&[10.0, 20.0]).nren, 50.0);
+        assert_eq!(series1.weighted_mean(&[0.0, 0.0]).nren, 1.75);
+        assert!("ELECTRICIDAD, RED, SUMINISTRO, A, 0.4, 2.0, 0.3"
+            .parse::<FactorSeries>()
+            .is_err());
+        assert!("ELECTRICIDAD, RED, SUMINISTRO, A, SERIE, 0.4 2.0"
+            .parse::<FactorSeries>()
+            .is_err());
+    }
+
+    #[test]
+    fn tconversion() {
+        let conversion1str = "1, 2, 0.950";
+        let conversion1 = conversion1str.parse::<Conversion>().unwrap();
+        assert_eq!(conversion1.to, 2);
+        assert_eq!(conversion1.to_string(), conversion1str);
+        assert!("1, 1, 0.95".parse::<Conversion>().is_err());
+        assert!("1, 2, 0.0".parse::<Conversion>().is_err());
+        assert!("1, 2".parse::<Conversion>().is_err());
+    }
+
+    #[test]
+    fn ttimestep() {
+        assert_eq!("HORARIO".parse::<Timestep>().unwrap(), Timestep::HORARIO);
+        assert_eq!(Timestep::MENSUAL.to_string(), "MENSUAL");
+        assert_eq!(Timestep::HORARIO.steps_per_year(), 8760);
+        assert_eq!(Timestep::from_num_steps(12), Some(Timestep::MENSUAL));
+        assert_eq!(Timestep::from_num_steps(24), None);
+        assert!("DIARIO".parse::<Timestep>().is_err());
+    }
+
+    #[test]
+    fn tstorage() {
+        let battery1str = "10.00, 0.900";
+        let battery1 = battery1str.parse::<Storage>().unwrap();
+        assert_eq!(battery1.capacity, 10.0);
+        assert_eq!(battery1.to_string(), battery1str);
+        assert!("10.0, 1.5".parse::<Storage>().is_err());
+        assert!("-1.0, 0.9".parse::<Storage>().is_err());
+        assert!("10.0".parse::<Storage>().is_err());
+
+        // Carga limitada por la capacidad y descarga limitada por la energía almacenada
+        let battery = Storage {
+            capacity: 5.0,
+            efficiency: 0.5,
+        };
+        let (charged, discharged) = battery.operate(&[4.0, 8.0, 0.0, 0.0], &[0.0, 0.0, 3.0, 3.0]);
+        assert_eq!(charged, vec![4.0, 6.0, 0.0, 0.0]);
+        assert_eq!(discharged, vec![0.0, 0.0, 3.0, 2.0]);
+    }
+
     #[test]
     fn tfactor() {
         let factor1 = Factor {
@@ -646,5 +1972,14 @@ mod tests {
             factor2str.parse::<Component>().unwrap().to_string(),
             factor2str
         );
+
+        // factor de emisiones opcional
+        let factor3 = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954 # Sin emisiones"
+            .parse::<Factor>()
+            .unwrap();
+        assert_eq!(factor3.co2, 0.0);
+        assert!("ELECTRICIDAD, RED, SUMINISTRO, A, 0.414"
+            .parse::<Factor>()
+            .is_err());
     }
 }
@@ -38,3 +38,17 @@ mod tmeta;
 pub use common::*;
 pub use rennrenco2::*;
 pub use tmeta::*;
+
+/// Tipo numérico de los valores de energía, factores y resultados
+///
+/// Por defecto es `f32`. La característica `f64` calcula con doble precisión, lo que reduce los
+/// errores de redondeo al acumular datos horarios de gran tamaño.
+#[cfg(not(feature = "f64"))]
+pub type Real = f32;
+
+/// Tipo numérico de los valores de energía, factores y resultados
+///
+/// Por defecto es `f32`. La característica `f64` calcula con doble precisión, lo que reduce los
+/// errores de redondeo al acumular datos horarios de gran tamaño.
+#[cfg(feature = "f64")]
+pub type Real = f64;
@@ -28,51 +28,55 @@ Energy performance
 ==================
 
 Energy performance type as a tuple to represent energy or emission values.
+
+The `WeightedQuantity` trait describes the operations needed to weight energy in the balance,
+so that the same balance code can carry other indicators: scalar values (`Real`, e.g. costs),
+the `RenNrenCo2` tuple or a generic set of `N` channels (`Weighted<N>`).
 */
 
 use std::fmt;
-use std::ops::{Add, AddAssign, Mul, MulAssign, Sub, SubAssign};
+use std::ops::{Add, AddAssign, Index, IndexMut, Mul, MulAssign, Sub, SubAssign};
 
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 
-use crate::error::EpbdError;
+use crate::{error::EpbdError, types::Real};
 
 /// Tupla que representa los factores de energía primaria renovable, no renovable y de emisión
-/// 
+///
 /// Energy pairs representing renewable and non renewable energy quantities or factors.
 #[derive(Debug, Copy, Clone, PartialEq, Default, Serialize, Deserialize)]
 pub struct RenNrenCo2 {
     /// Renewable energy or factor
     #[serde(serialize_with = "round_serialize_3")]
-    pub ren: f32,
+    pub ren: Real,
     /// Non Renewable energy or factor
     #[serde(serialize_with = "round_serialize_3")]
-    pub nren: f32,
+    pub nren: Real,
     /// Non Renewable energy or factor
     #[serde(serialize_with = "round_serialize_3")]
-    pub co2: f32,
+    pub co2: Real,
 }
 
-fn round_serialize_3<S>(x: &f32, s: S) -> Result<S::Ok, S::Error>
+fn round_serialize_3<S>(x: &Real, s: S) -> Result<S::Ok, S::Error>
 where
     S: serde::Serializer,
 {
-    s.serialize_f32((x * 1000.0).round() / 1000.0)
+    ((x * 1000.0).round() / 1000.0).serialize(s)
 }
 
 impl RenNrenCo2 {
     /// Default constructor -> { ren: 0.0, nren: 0.0 }
-    pub const fn new(ren: f32, nren: f32, co2: f32) -> Self {
+    pub const fn new(ren: Real, nren: Real, co2: Real) -> Self {
         Self { ren, nren, co2 }
     }
 
     /// Total renewable + non renewable energy
-    pub fn tot(self) -> f32 {
+    pub fn tot(self) -> Real {
         self.ren + self.nren
     }
 
     /// Renewable energy ratio
-    pub fn rer(self) -> f32 {
+    pub fn rer(self) -> Real {
         let tot = self.tot();
         if tot == 0.0 {
             0.0
@@ -83,8 +87,8 @@ impl RenNrenCo2 {
 }
 
 // Conversión desde tupla a RenNrenCo2
-impl std::convert::From<(f32, f32, f32)> for RenNrenCo2 {
-    fn from((ren, nren, co2): (f32, f32, f32)) -> Self {
+impl std::convert::From<(Real, Real, Real)> for RenNrenCo2 {
+    fn from((ren, nren, co2): (Real, Real, Real)) -> Self {
         Self { ren, nren, co2 }
     }
 }
@@ -119,7 +123,7 @@ impl std::str::FromStr for RenNrenCo2 {
                         _ => ("Error", "0.0"),
                     };
                     //let haskey = ["ren", "nren", "co2"].contains(&key);
-                    match (key, f32::from_str(val)) {
+                    match (key, Real::from_str(val)) {
                         ("ren", Ok(v)) => res.ren = v,
                         ("nren", Ok(v)) => res.nren = v,
                         ("co2", Ok(v)) => res.co2 = v,
@@ -131,8 +135,8 @@ impl std::str::FromStr for RenNrenCo2 {
             let vals = s
                 .split(',')
                 .map(str::trim)
-                .map(f32::from_str)
-                .collect::<Result<Vec<f32>, _>>()
+                .map(Real::from_str)
+                .collect::<Result<Vec<Real>, _>>()
                 .map_err(|_| EpbdError::ParseError(s.into()))?;
 
             match *vals.as_slice() {
@@ -161,7 +165,7 @@ impl Add for RenNrenCo2 {
     }
 }
 
-impl<'a> Add for &'a RenNrenCo2 {
+impl Add for &RenNrenCo2 {
     type Output = RenNrenCo2;
 
     fn add(self, other: &RenNrenCo2) -> RenNrenCo2 {
@@ -197,7 +201,7 @@ impl Sub for RenNrenCo2 {
     }
 }
 
-impl<'a> Sub for &'a RenNrenCo2 {
+impl Sub for &RenNrenCo2 {
     type Output = RenNrenCo2;
 
     fn sub(self, other: &RenNrenCo2) -> RenNrenCo2 {
@@ -220,12 +224,12 @@ impl SubAssign for RenNrenCo2 {
     }
 }
 
-// Implement multiplication by a f32
-// rennren * f32
-impl Mul<f32> for RenNrenCo2 {
+// Implement multiplication by a Real
+// rennren * Real
+impl Mul<Real> for RenNrenCo2 {
     type Output = RenNrenCo2;
 
-    fn mul(self, rhs: f32) -> RenNrenCo2 {
+    fn mul(self, rhs: Real) -> RenNrenCo2 {
         RenNrenCo2 {
             ren: self.ren * rhs,
             nren: self.nren * rhs,
@@ -234,11 +238,11 @@ impl Mul<f32> for RenNrenCo2 {
     }
 }
 
-// rennren * &f32
-impl<'a> Mul<&'a f32> for RenNrenCo2 {
+// rennren * &Real
+impl Mul<&Real> for RenNrenCo2 {
     type Output = RenNrenCo2;
 
-    fn mul(self, rhs: &f32) -> RenNrenCo2 {
+    fn mul(self, rhs: &Real) -> RenNrenCo2 {
         RenNrenCo2 {
             ren: self.ren * rhs,
             nren: self.nren * rhs,
@@ -247,11 +251,11 @@ impl<'a> Mul<&'a f32> for RenNrenCo2 {
     }
 }
 
-// &rennren * f32
-impl<'a> Mul<f32> for &'a RenNrenCo2 {
+// &rennren * Real
+impl Mul<Real> for &RenNrenCo2 {
     type Output = RenNrenCo2;
 
-    fn mul(self, rhs: f32) -> RenNrenCo2 {
+    fn mul(self, rhs: Real) -> RenNrenCo2 {
         RenNrenCo2 {
             ren: self.ren * rhs,
             nren: self.nren * rhs,
@@ -260,10 +264,10 @@ impl<'a> Mul<f32> for &'a RenNrenCo2 {
     }
 }
 
-// TODO: &rennren * &f32 -> impl<'a, 'b> Mul<&'b f32> for &'a RenNRenPair
+// TODO: &rennren * &Real -> impl<'a, 'b> Mul<&'b Real> for &'a RenNRenPair
 
-// f32 * rennren
-impl Mul<RenNrenCo2> for f32 {
+// Real * rennren
+impl Mul<RenNrenCo2> for Real {
     type Output = RenNrenCo2;
 
     fn mul(self, rhs: RenNrenCo2) -> RenNrenCo2 {
@@ -275,8 +279,8 @@ impl Mul<RenNrenCo2> for f32 {
     }
 }
 
-// &f32 * rennren
-impl<'a> Mul<RenNrenCo2> for &'a f32 {
+// &Real * rennren
+impl Mul<RenNrenCo2> for &Real {
     type Output = RenNrenCo2;
 
     fn mul(self, rhs: RenNrenCo2) -> RenNrenCo2 {
@@ -288,8 +292,8 @@ impl<'a> Mul<RenNrenCo2> for &'a f32 {
     }
 }
 
-// f32 * &rennren
-impl<'a> Mul<&'a RenNrenCo2> for f32 {
+// Real * &rennren
+impl Mul<&RenNrenCo2> for Real {
     type Output = RenNrenCo2;
 
     fn mul(self, rhs: &RenNrenCo2) -> RenNrenCo2 {
@@ -301,11 +305,11 @@ impl<'a> Mul<&'a RenNrenCo2> for f32 {
     }
 }
 
-// TODO: &f32 * &rennren -> impl<'a, 'b> Mul<&'b RenNRenPair> for &'a f32
+// TODO: &Real * &rennren -> impl<'a, 'b> Mul<&'b RenNRenPair> for &'a Real
 
-// Implement RenNren *= f32
-impl MulAssign<f32> for RenNrenCo2 {
-    fn mul_assign(&mut self, rhs: f32) {
+// Implement RenNren *= Real
+impl MulAssign<Real> for RenNrenCo2 {
+    fn mul_assign(&mut self, rhs: Real) {
         *self = RenNrenCo2 {
             ren: self.ren * rhs,
             nren: self.nren * rhs,
@@ -314,10 +318,137 @@ impl MulAssign<f32> for RenNrenCo2 {
     }
 }
 
+/// Magnitud ponderada que puede usarse en el cálculo del balance
+///
+/// Weighted quantity (energy, emissions, cost...) that can be accumulated and scaled in the balance.
+/// It is implemented for every type with the required operations, such as `Real`, `RenNrenCo2` and `Weighted<N>`.
+pub trait WeightedQuantity:
+    Copy + Default + Add<Output = Self> + Sub<Output = Self> + Mul<Real, Output = Self>
+{
+}
+
+impl<T> WeightedQuantity for T where
+    T: Copy + Default + Add<Output = T> + Sub<Output = T> + Mul<Real, Output = T>
+{
+}
+
+/// Magnitud ponderada con `N` canales (p.e. energía primaria renovable, no renovable, total, emisiones y coste)
+///
+/// Weighted quantity with N channels, for indicators beyond ren, nren and co2.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Weighted<const N: usize>(pub [Real; N]);
+
+impl<const N: usize> Weighted<N> {
+    /// Constructor a partir de los valores de los canales
+    pub const fn new(values: [Real; N]) -> Self {
+        Self(values)
+    }
+
+    /// Valores de los canales
+    pub fn values(&self) -> &[Real; N] {
+        &self.0
+    }
+
+    /// Aplica una operación a cada canal de dos magnitudes
+    fn zip_with(self, other: Self, f: impl Fn(Real, Real) -> Real) -> Self {
+        let mut res = self;
+        for (r, o) in res.0.iter_mut().zip(other.0.iter()) {
+            *r = f(*r, *o);
+        }
+        res
+    }
+}
+
+impl<const N: usize> Default for Weighted<N> {
+    fn default() -> Self {
+        Self([0.0; N])
+    }
+}
+
+impl<const N: usize> Index<usize> for Weighted<N> {
+    type Output = Real;
+
+    fn index(&self, i: usize) -> &Real {
+        &self.0[i]
+    }
+}
+
+impl<const N: usize> IndexMut<usize> for Weighted<N> {
+    fn index_mut(&mut self, i: usize) -> &mut Real {
+        &mut self.0[i]
+    }
+}
+
+impl<const N: usize> Add for Weighted<N> {
+    type Output = Self;
+
+    fn add(self, other: Self) -> Self {
+        self.zip_with(other, |a, b| a + b)
+    }
+}
+
+impl<const N: usize> AddAssign for Weighted<N> {
+    fn add_assign(&mut self, other: Self) {
+        *self = *self + other;
+    }
+}
+
+impl<const N: usize> Sub for Weighted<N> {
+    type Output = Self;
+
+    fn sub(self, other: Self) -> Self {
+        self.zip_with(other, |a, b| a - b)
+    }
+}
+
+impl<const N: usize> SubAssign for Weighted<N> {
+    fn sub_assign(&mut self, other: Self) {
+        *self = *self - other;
+    }
+}
+
+impl<const N: usize> Mul<Real> for Weighted<N> {
+    type Output = Self;
+
+    fn mul(self, rhs: Real) -> Self {
+        let mut res = self;
+        res.0.iter_mut().for_each(|v| *v *= rhs);
+        res
+    }
+}
+
+impl<const N: usize> Mul<Weighted<N>> for Real {
+    type Output = Weighted<N>;
+
+    fn mul(self, rhs: Weighted<N>) -> Weighted<N> {
+        rhs * self
+    }
+}
+
+impl<const N: usize> MulAssign<Real> for Weighted<N> {
+    fn mul_assign(&mut self, rhs: Real) {
+        *self = *self * rhs;
+    }
+}
+
+// Conversión entre RenNrenCo2 y Weighted<3> (canales ren, nren, co2)
+impl From<RenNrenCo2> for Weighted<3> {
+    fn from(v: RenNrenCo2) -> Self {
+        Self([v.ren, v.nren, v.co2])
+    }
+}
+
+impl From<Weighted<3>> for RenNrenCo2 {
+    fn from(v: Weighted<3>) -> Self {
+        let [ren, nren, co2] = v.0;
+        Self { ren, nren, co2 }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use pretty_assertions::{assert_eq};
+    use pretty_assertions::assert_eq;
 
     #[test]
     fn add() {
@@ -467,4 +598,17 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn weighted() {
+        let a = Weighted::new([1.0, 2.0, 3.0, 4.0]);
+        let b = Weighted::new([1.0, 1.0, 1.0, 1.0]);
+        assert_eq!(a + b, Weighted::new([2.0, 3.0, 4.0, 5.0]));
+        assert_eq!(a - b, Weighted::new([0.0, 1.0, 2.0, 3.0]));
+        assert_eq!(2.0 * a, Weighted::new([2.0, 4.0, 6.0, 8.0]));
+        assert_eq!(a[3], 4.0);
+        assert_eq!(Weighted::<4>::default(), Weighted::new([0.0; 4]));
+        let v = RenNrenCo2::new(1.0, 2.0, 3.0);
+        assert_eq!(RenNrenCo2::from(Weighted::from(v)), v);
+    }
 }
@@ -34,14 +34,17 @@ use std::fmt;
 use std::str;
 use std::str::FromStr;
 
-use serde::{Serialize, Deserialize};
+use serde::{Deserialize, Serialize};
 
-use crate::{error::EpbdError, types::RenNrenCo2};
+use crate::{
+    error::EpbdError,
+    types::{Real, RenNrenCo2},
+};
 
 // ==================== Metadata types
 
 /// Metadatos de los componentes o de los factores de paso
-/// 
+///
 /// Metadata of components or weighting factors
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Meta {
@@ -122,15 +125,21 @@ pub trait MetaVec {
             .map(|v| v.value.clone())
     }
 
-    /// Get (optional) metadata value (f32) by key as f32
-    fn get_meta_f32(&self, key: &str) -> Option<f32> {
+    /// Get (optional) metadata value (Real) by key as Real
+    fn get_meta_real(&self, key: &str) -> Option<Real> {
         self.get_metavec()
             .iter()
             .find(|m| m.key == key)
-            .and_then(|v| f32::from_str(v.value.trim()).ok())
+            .and_then(|v| Real::from_str(v.value.trim()).ok())
     }
 
-    /// Get (optional) metadata value (f32, f32) by key as RenNrenCo2 struct
+    /// Get (optional) metadata value (Real) by key as Real
+    #[deprecated(note = "usar `get_meta_real`")]
+    fn get_meta_f32(&self, key: &str) -> Option<Real> {
+        self.get_meta_real(key)
+    }
+
+    /// Get (optional) metadata value (Real, Real) by key as RenNrenCo2 struct
     fn get_meta_rennren(&self, key: &str) -> Option<RenNrenCo2> {
         self.get_metavec()
             .iter()
@@ -138,15 +147,80 @@ pub trait MetaVec {
             .and_then(|v| {
                 v.value
                     .parse::<RenNrenCo2>()
-                    .map_err(|e| {
+                    .map_err(|_e| {
                         eprintln!("No se puede transformar el metadato a RenNrenCo2: {:?}", v);
-                        e
                     })
                     .ok()
             })
     }
 
+    /// Get metadata value by key as str
+    ///
+    /// Devuelve `Ok(None)` si no se ha definido el metadato y un error si su valor está vacío.
+    fn get_str(&self, key: &str) -> Result<Option<&str>, EpbdError> {
+        match self.get_metavec().iter().find(|m| m.key == key) {
+            Some(m) if m.value.trim().is_empty() => Err(EpbdError::WrongInput(format!(
+                "el metadato {} no tiene valor",
+                key
+            ))),
+            Some(m) => Ok(Some(m.value.trim())),
+            None => Ok(None),
+        }
+    }
+
+    /// Get metadata value by key as Real
+    ///
+    /// Devuelve `Ok(None)` si no se ha definido el metadato y un error si su valor no es un número.
+    fn get_real(&self, key: &str) -> Result<Option<Real>, EpbdError> {
+        self.get_str(key)?
+            .map(|v| {
+                Real::from_str(v).map_err(|_| {
+                    EpbdError::WrongInput(format!(
+                        "el valor del metadato {} no es un número: \"{}\"",
+                        key, v
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    /// Get metadata value by key as RenNrenCo2 struct
+    ///
+    /// Devuelve `Ok(None)` si no se ha definido el metadato y un error si su valor no tiene
+    /// el formato `ren, nren, co2`.
+    fn get_rennren(&self, key: &str) -> Result<Option<RenNrenCo2>, EpbdError> {
+        self.get_str(key)?
+            .map(|v| {
+                v.parse::<RenNrenCo2>().map_err(|_| {
+                    EpbdError::WrongInput(format!(
+                        "el valor del metadato {} no tiene el formato ren, nren, co2: \"{}\"",
+                        key, v
+                    ))
+                })
+            })
+            .transpose()
+    }
+
+    /// Get metadata value by key as bool
+    ///
+    /// Admite los valores `true`, `si`, `sí`, `1` y `false`, `no`, `0` (sin distinguir mayúsculas).
+    /// Devuelve `Ok(None)` si no se ha definido el metadato y un error si su valor no es ninguno de ellos.
+    fn get_bool(&self, key: &str) -> Result<Option<bool>, EpbdError> {
+        self.get_str(key)?
+            .map(|v| match v.to_lowercase().as_str() {
+                "true" | "si" | "sí" | "1" => Ok(true),
+                "false" | "no" | "0" => Ok(false),
+                _ => Err(EpbdError::WrongInput(format!(
+                    "el valor del metadato {} no es un valor lógico (true o false): \"{}\"",
+                    key, v
+                ))),
+            })
+            .transpose()
+    }
+
     /// Update metadata value for key or insert new metadata.
+    ///
+    /// Existing metadata keep their position and new metadata are appended (insertion order).
     fn set_meta(&mut self, key: &str, value: &str) {
         let wmeta = self.get_mut_metavec();
         let metapos = wmeta.iter().position(|m| m.key == key);
@@ -156,6 +230,24 @@ pub trait MetaVec {
             wmeta.push(Meta::new(key, value));
         };
     }
+
+    /// Update or insert Real metadata value for key (see `set_meta`)
+    fn set_real(&mut self, key: &str, value: Real) {
+        self.set_meta(key, &value.to_string());
+    }
+
+    /// Update or insert RenNrenCo2 metadata value for key, as `ren, nren, co2` (see `set_meta`)
+    fn set_rennren(&mut self, key: &str, value: RenNrenCo2) {
+        self.set_meta(
+            key,
+            &format!("{:.3}, {:.3}, {:.3}", value.ren, value.nren, value.co2),
+        );
+    }
+
+    /// Update or insert bool metadata value for key, as `true` or `false` (see `set_meta`)
+    fn set_bool(&mut self, key: &str, value: bool) {
+        self.set_meta(key, &value.to_string());
+    }
 }
 
 // ========================== Tests
@@ -163,7 +255,7 @@ pub trait MetaVec {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use pretty_assertions::{assert_eq};
+    use pretty_assertions::assert_eq;
 
     #[test]
     fn tmeta() {
@@ -177,4 +269,61 @@ mod tests {
         assert_eq!(format!("{}", meta2), metastr);
         assert_eq!(format!("{}", metastr.parse::<Meta>().unwrap()), metastr);
     }
+
+    struct TMetas(Vec<Meta>);
+
+    impl MetaVec for TMetas {
+        fn get_metavec(&self) -> &Vec<Meta> {
+            &self.0
+        }
+        fn get_mut_metavec(&mut self) -> &mut Vec<Meta> {
+            &mut self.0
+        }
+    }
+
+    #[test]
+    fn tmeta_typed() {
+        let mut metas = TMetas(vec![
+            Meta::new("CTE_AREAREF", "100.5"),
+            Meta::new("CTE_COGEN", "0, 2.5, 0.3"),
+            Meta::new("CTE_ACTIVO", "Sí"),
+            Meta::new("CTE_VACIO", ""),
+        ]);
+        assert_eq!(metas.get_real("CTE_AREAREF").unwrap(), Some(100.5));
+        assert_eq!(metas.get_real("CTE_KEXP").unwrap(), None);
+        assert_eq!(
+            metas.get_rennren("CTE_COGEN").unwrap(),
+            Some(RenNrenCo2::new(0.0, 2.5, 0.3))
+        );
+        assert_eq!(metas.get_bool("CTE_ACTIVO").unwrap(), Some(true));
+        assert_eq!(metas.get_str("CTE_ACTIVO").unwrap(), Some("Sí"));
+        // Valores incorrectos
+        let err = metas.get_real("CTE_COGEN").unwrap_err();
+        assert_eq!(err.code(), "E301");
+        assert_eq!(
+            err.to_string(),
+            "Valor de entrada incorrecto: el valor del metadato CTE_COGEN no es un número: \"0, 2.5, 0.3\""
+        );
+        assert!(metas.get_rennren("CTE_AREAREF").is_err());
+        assert!(metas.get_bool("CTE_AREAREF").is_err());
+        assert!(metas.get_str("CTE_VACIO").is_err());
+        // Las modificaciones mantienen el orden de inserción
+        metas.set_real("CTE_AREAREF", 80.0);
+        metas.set_bool("CTE_ACTIVO", false);
+        metas.set_rennren("CTE_RED1", RenNrenCo2::new(0.0, 1.3, 0.3));
+        let keys: Vec<_> = metas.0.iter().map(|m| m.key.as_str()).collect();
+        assert_eq!(
+            keys,
+            vec![
+                "CTE_AREAREF",
+                "CTE_COGEN",
+                "CTE_ACTIVO",
+                "CTE_VACIO",
+                "CTE_RED1"
+            ]
+        );
+        assert_eq!(metas.get_real("CTE_AREAREF").unwrap(), Some(80.0));
+        assert_eq!(metas.get_bool("CTE_ACTIVO").unwrap(), Some(false));
+        assert_eq!(metas.get_meta("CTE_RED1").unwrap(), "0.000, 1.300, 0.300");
+    }
 }
@@ -28,6 +28,12 @@ Vector utilities
 ================
 
 Helper utilities for vector handling, mostly elementwise ops.
+
+Operations returning a new `Vec` allocate their result. The `*_assign` variants work in place
+and should be preferred when chaining operations over long (e.g. hourly) vectors.
+
+Elementwise operations on two vectors panic if their lengths differ: all the components of a
+balance have the same number of values, which is checked when they are read.
 */
 
 use num::{Float, Zero};
@@ -35,15 +41,83 @@ use std::iter::Sum;
 use std::ops::Mul;
 
 /// Elementwise sum res[i] = vec1[i] + vec2[i] + ... + vecj[i]
+///
+/// Shorter vectors are padded with zeros.
 pub fn veclistsum<T: Float>(veclist: &[&[T]]) -> Vec<T> {
-    let maxlen: usize = veclist.iter().map(|lst| lst.len()).max().unwrap_or(0_usize);
-    veclist.iter().fold(vec![Zero::zero()], |acc, ref x| {
-        (0..maxlen)
-            .map(|idx| {
-                *acc.get(idx).unwrap_or(&Zero::zero()) + *x.get(idx).unwrap_or(&Zero::zero())
-            })
-            .collect()
-    })
+    let maxlen: usize = veclist.iter().map(|lst| lst.len()).max().unwrap_or(1_usize);
+    let mut res = vec![Zero::zero(); maxlen];
+    for vec in veclist {
+        for (r, v) in res.iter_mut().zip(vec.iter()) {
+            *r = *r + *v;
+        }
+    }
+    res
+}
+
+/// Elementwise sum of a sequence of vectors of length len, allocating only the result
+///
+/// # Panics
+///
+/// Panics if any of the vectors has a length other than `len`.
+pub fn vecitersum<T, V, I>(len: usize, iter: I) -> Vec<T>
+where
+    T: Float,
+    V: AsRef<[T]>,
+    I: IntoIterator<Item = V>,
+{
+    iter.into_iter()
+        .fold(vec![Zero::zero(); len], |mut acc, vec| {
+            vecvecsum_assign(&mut acc, vec.as_ref());
+            acc
+        })
+}
+
+/// Elementwise in-place sum acc[i] = acc[i] + vec[i]
+///
+/// # Panics
+///
+/// Panics if `acc` and `vec` have different lengths.
+pub fn vecvecsum_assign<T: Float>(acc: &mut [T], vec: &[T]) {
+    assert_eq!(acc.len(), vec.len());
+    acc.iter_mut()
+        .zip(vec.iter())
+        .for_each(|(a, b)| *a = *a + *b);
+}
+
+/// Elementwise in-place difference acc[i] = acc[i] - vec[i]
+///
+/// # Panics
+///
+/// Panics if `acc` and `vec` have different lengths.
+pub fn vecvecdif_assign<T: Float>(acc: &mut [T], vec: &[T]) {
+    assert_eq!(acc.len(), vec.len());
+    acc.iter_mut()
+        .zip(vec.iter())
+        .for_each(|(a, b)| *a = *a - *b);
+}
+
+/// Elementwise in-place multiplication acc[i] = acc[i] * vec[i]
+///
+/// # Panics
+///
+/// Panics if `acc` and `vec` have different lengths.
+pub fn vecvecmul_assign<T: Float>(acc: &mut [T], vec: &[T]) {
+    assert_eq!(acc.len(), vec.len());
+    acc.iter_mut()
+        .zip(vec.iter())
+        .for_each(|(a, b)| *a = *a * *b);
+}
+
+/// Elementwise in-place minimum acc[i] = min(acc[i], vec[i])
+///
+/// # Panics
+///
+/// Panics if `acc` and `vec` have different lengths.
+pub fn vecvecmin_assign<T: Float>(acc: &mut [T], vec: &[T]) {
+    assert_eq!(acc.len(), vec.len());
+    acc.iter_mut()
+        .zip(vec.iter())
+        .for_each(|(a, b)| *a = a.min(*b));
 }
 
 /// Elementwise minimum min res[i] = min(vec1[i], vec2[i])
@@ -103,14 +177,37 @@ mod tests {
         );
         assert_eq!(
             vec![6.0, 6.0, 6.0],
-            veclistsum(&[
-                &vec![1.0, 1.0, 1.0],
-                &vec![2.0, 2.0, 2.0],
-                &vec![3.0, 3.0, 3.0],
-            ])
+            veclistsum(&[&[1.0, 1.0, 1.0], &[2.0, 2.0, 2.0], &[3.0, 3.0, 3.0],])
         );
     }
 
+    #[test]
+    fn vecops_vecitersum() {
+        let vecs = vec![vec![1.0, 1.0, 1.0], vec![2.0, 2.0, 2.0]];
+        assert_eq!(vec![3.0, 3.0, 3.0], vecitersum(3, &vecs));
+        assert_eq!(vec![0.0, 0.0], vecitersum::<f32, &[f32], _>(2, vec![]));
+    }
+
+    #[test]
+    fn vecops_assign() {
+        let mut acc = vec![2.0, 3.0, 4.0];
+        vecvecsum_assign(&mut acc, &[1.0, 1.0, 1.0]);
+        assert_eq!(vec![3.0, 4.0, 5.0], acc);
+        vecvecdif_assign(&mut acc, &[1.0, 2.0, 3.0]);
+        assert_eq!(vec![2.0, 2.0, 2.0], acc);
+        vecvecmul_assign(&mut acc, &[1.0, 2.0, 3.0]);
+        assert_eq!(vec![2.0, 4.0, 6.0], acc);
+        vecvecmin_assign(&mut acc, &[3.0, 3.0, 3.0]);
+        assert_eq!(vec![2.0, 3.0, 3.0], acc);
+    }
+
+    #[test]
+    #[should_panic]
+    fn vecops_assign_length_mismatch() {
+        let mut acc = vec![2.0, 3.0, 4.0];
+        vecvecsum_assign(&mut acc, &[1.0, 1.0]);
+    }
+
     #[test]
     fn vecops_vecvecmin() {
         assert_eq!(
@@ -145,7 +242,7 @@ mod tests {
 
     #[test]
     fn vecops_veckmul() {
-        assert_eq!(vec![2.0, 4.0, 6.0], veckmul(&[1.0, 2.0, 3.0], 2.0));
+        assert_eq!(vec![2.0, 4.0, 6.0], veckmul([1.0, 2.0, 3.0], 2.0));
         assert_eq!(vec![2.0, 4.0, 6.0], veckmul(vec![1.0, 2.0, 3.0], 2.0));
     }
 
@@ -31,16 +31,22 @@ Define el tipo Factors (lista de Factores + Metadatos).
 
 */
 
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
 use std::fmt;
 use std::str;
 
+use log::{debug, info};
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    error::EpbdError,
-    types::{CSubtype, Carrier, Dest, Factor, Meta, MetaVec, RenNrenCo2, Source, Step},
-    Components,
+    error::{Diagnostic, EpbdError, Warning},
+    i18n::{tr, tr_format},
+    types::{
+        CSubtype, CType, Carrier, Dest, Factor, FactorKey, FactorSeries, Meta, MetaVec, Perimeter,
+        RenNrenCo2, Source, Step, FACTOR_DECIMALS, FACTOR_SERIES_TAG,
+    },
+    Components, CsvFormat,
 };
 
 // --------------------------- Factors
@@ -51,12 +57,62 @@ use crate::{
 #[derive(Debug, Default, Clone, Serialize, Deserialize)]
 pub struct Factors {
     /// Weighting factors list
+    #[serde(default)]
     pub wmeta: Vec<Meta>,
     /// Metadata
     pub wdata: Vec<Factor>,
+    /// Time-varying weighting factors, used instead of the constant factors with the same key
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub wseries: Vec<FactorSeries>,
 }
 
 impl Factors {
+    /// Interpreta los factores de paso desde un documento JSON
+    ///
+    /// El documento tiene la misma estructura que la serialización de `Factors` (metadatos en
+    /// `wmeta`, factores en `wdata` y factores variables en el tiempo, opcionales, en `wseries`),
+    /// con los mismos campos que el formato de texto.
+    ///
+    /// # Errors
+    ///
+    /// * Documento JSON incorrecto
+    pub fn from_json(s: &str) -> Result<Factors, EpbdError> {
+        Ok(serde_json::from_str(s)?)
+    }
+
+    /// Interpreta los factores de paso con el formato de texto indicado
+    ///
+    /// Permite leer archivos con campos separados por punto y coma y coma decimal (ver `CsvFormat`).
+    ///
+    /// # Errors
+    ///
+    /// * Formato de texto no válido o formato incorrecto de los factores de paso o sus metadatos
+    pub fn from_str_with_format(s: &str, format: &CsvFormat) -> Result<Factors, EpbdError> {
+        format.to_canonical(s)?.parse()
+    }
+
+    /// Interpreta los factores de paso desde un documento YAML
+    ///
+    /// El documento tiene la misma estructura que el formato JSON (ver `Factors::from_json`).
+    ///
+    /// # Errors
+    ///
+    /// * Documento YAML incorrecto
+    #[cfg(feature = "yaml")]
+    pub fn from_yaml(s: &str) -> Result<Factors, EpbdError> {
+        Ok(serde_yaml::from_str(s)?)
+    }
+
+    /// Convierte los factores de paso a un documento YAML
+    ///
+    /// # Errors
+    ///
+    /// * Factores que no se pueden convertir a YAML
+    #[cfg(feature = "yaml")]
+    pub fn to_yaml(&self) -> Result<String, EpbdError> {
+        Ok(serde_yaml::to_string(self)?)
+    }
+
     /// Elimina factores no EPB de la lista de factores
     ///
     /// Remove non EPB weighting factors from the factor list
@@ -64,6 +120,51 @@ impl Factors {
         self.wdata.retain(|e| e.dest != Dest::A_NEPB);
     }
 
+    /// Ordena los factores de paso según el orden canónico (vector, fuente, destino y paso)
+    ///
+    /// La ordenación es estable, por lo que se mantiene el orden relativo de los factores equivalentes
+    pub fn sort_canonical(&mut self) {
+        self.wdata.sort_by_key(Factor::key);
+    }
+
+    /// Representación textual canónica de los factores de paso, con el número de decimales indicado
+    ///
+    /// Los metadatos se ordenan por clave y los factores de paso, y sus series, según el orden
+    /// canónico (ver `sort_canonical`), de modo que al volver a interpretar la representación se
+    /// obtiene exactamente el mismo texto.
+    pub fn to_canonical_string(&self, decimals: usize) -> String {
+        format!("{:.*}", decimals, self.canonical())
+    }
+
+    /// Copia de los factores de paso con los metadatos ordenados por clave y los factores, y sus
+    /// series, en orden canónico
+    pub fn canonical(&self) -> Self {
+        let mut factors = self.clone();
+        factors.wmeta.sort_by(|a, b| a.key.cmp(&b.key));
+        factors.sort_canonical();
+        factors
+            .wseries
+            .sort_by_key(|s| (s.carrier.clone(), s.source, s.dest, s.step));
+        factors
+    }
+
+    /// Ordena los factores de paso usando la clave obtenida con la función `f` (ordenación estable)
+    pub fn sort_by_key<K: Ord, F: FnMut(&Factor) -> K>(&mut self, f: F) {
+        self.wdata.sort_by_key(f);
+    }
+
+    /// Índice de los valores de los factores de paso por clave (vector, fuente, destino y paso)
+    ///
+    /// Permite localizar los factores de paso (o detectar su ausencia) sin recorrer la lista. Si
+    /// hay factores con la misma clave se usa el primero, como en las búsquedas en la lista.
+    pub fn index(&self) -> HashMap<FactorKey, RenNrenCo2> {
+        let mut index = HashMap::with_capacity(self.wdata.len());
+        for f in &self.wdata {
+            index.entry(f.key()).or_insert_with(|| f.factors());
+        }
+        index
+    }
+
     /// Actualiza o establece valores de un factor de paso
     pub fn update_wfactor(
         &mut self,
@@ -79,8 +180,14 @@ impl Factors {
         }) {
             factor.set_values(&values);
         } else {
-            self.wdata
-                .push(Factor::new(carrier, source, dest, step, values, comment));
+            self.wdata.push(Factor::new(
+                carrier,
+                source,
+                dest,
+                step,
+                values,
+                tr(comment),
+            ));
         };
     }
 
@@ -99,14 +206,153 @@ impl Factors {
             .iter()
             .any(|f| f.carrier == carrier && f.source == source && f.step == step && f.dest == dest)
         {
-            self.wdata
-                .push(Factor::new(carrier, source, dest, step, values, comment));
+            self.wdata.push(Factor::new(
+                carrier,
+                source,
+                dest,
+                step,
+                values,
+                tr(comment),
+            ));
+        };
+    }
+
+    /// Asegura que existe el factor de suministro de una red de distrito (VECTOR, RED, SUMINISTRO, A)
+    ///
+    /// Si no existe, se usan los factores por defecto de la red de distrito 1 (`defaults.red1`)
+    /// o 2 (`defaults.red2`). Las redes de distrito adicionales (RED3, RED4, ...) usan los de la red 1.
+    /// No hace nada si el vector no es una red de distrito.
+    pub fn ensure_district_supply(&mut self, carrier: &Carrier, defaults: &UserWF<RenNrenCo2>) {
+        let (values, n) = match carrier.district_number() {
+            Some(2) => (defaults.red2, 2),
+            Some(n) => (defaults.red1, n),
+            None => return,
+        };
+        self.ensure_wfactor(
+            carrier.clone(),
+            Source::RED,
+            Dest::SUMINISTRO,
+            Step::A,
+            values,
+            &format!(
+                "Recursos usados para suministrar energía de la red de distrito {} (definible por el usuario)",
+                n
+            ),
+        );
+    }
+
+    /// Asegura que existe el factor de producción de calor in situ de una red de distrito (VECTOR, INSITU, SUMINISTRO, A)
+    ///
+    /// El calor producido in situ (p.e. excedentes solares) es, por defecto, renovable. Al normalizar
+    /// se completan a partir de este factor los de exportación del calor a la red y a usos no EPB.
+    /// No hace nada si el vector no es una red de distrito.
+    pub fn ensure_district_onsite_heat(&mut self, carrier: &Carrier) {
+        if !carrier.is_district() {
+            return;
+        }
+        self.ensure_wfactor(
+            carrier.clone(),
+            Source::INSITU,
+            Dest::SUMINISTRO,
+            Step::A,
+            RenNrenCo2::new(1.0, 0.0, 0.0),
+            "Recursos usados para producir in situ energía térmica (p.e. excedentes solares)",
+        );
+    }
+
+    /// Perímetro de evaluación de los factores de paso
+    ///
+    /// Se obtiene del metadato `CTE_PERIMETRO`. Sin metadato se considera el perímetro distante.
+    ///
+    /// # Errors
+    ///
+    /// * Perímetro desconocido en el metadato `CTE_PERIMETRO`
+    pub fn perimeter(&self) -> Result<Perimeter, EpbdError> {
+        self.get_str("CTE_PERIMETRO")?
+            .map_or(Ok(Perimeter::DISTANT), |p| p.to_uppercase().parse())
+    }
+
+    /// Convierte los factores de paso al perímetro de evaluación indicado
+    ///
+    /// Al pasar del perímetro distante al próximo, los factores de los vectores suministrados por
+    /// la red (fuente distinta de INSITU y COGENERACION) que no son vectores próximos (ver
+    /// `cte::is_nearby_carrier`) pasan a ser ren' = 0 y nren' = ren + nren. El perímetro resultante
+    /// se registra en el metadato `CTE_PERIMETRO`.
+    ///
+    /// Si los factores ya están en el perímetro indicado se devuelven sin cambios, de modo que
+    /// la conversión no se aplica dos veces.
+    ///
+    /// # Errors
+    ///
+    /// * Perímetro desconocido en el metadato `CTE_PERIMETRO`
+    /// * Conversión no disponible (a un perímetro más amplio o al perímetro in situ)
+    pub fn to_perimeter(&self, perimeter: Perimeter) -> Result<Self, EpbdError> {
+        let current = self.perimeter()?;
+        match (current, perimeter) {
+            (current, perimeter) if current == perimeter => Ok(self.clone()),
+            (Perimeter::DISTANT, Perimeter::NEARBY) => {
+                let mut factors = self.grid_to_nonrenewable(crate::cte::is_nearby_carrier);
+                factors.set_meta("CTE_PERIMETRO", &perimeter.to_string());
+                Ok(factors)
+            }
+            _ => Err(EpbdError::WrongInput(format!(
+                "No es posible convertir los factores de paso del perímetro {} al perímetro {}",
+                current, perimeter
+            ))),
+        }
+    }
+
+    /// Convierte en no renovables los factores de suministro desde la red de los vectores no conservados
+    ///
+    /// Los factores de vectores con fuente INSITU o COGENERACION y los vectores para los que
+    /// `keep` es verdadero no se modifican. El resto pasa a ser ren' = 0 y nren' = ren + nren.
+    fn grid_to_nonrenewable(&self, keep: fn(Carrier) -> bool) -> Self {
+        let convert = |carrier: &Carrier, source: Source| {
+            !(source == Source::INSITU || source == Source::COGENERACION || keep(carrier.clone()))
         };
+        let wdata = self
+            .wdata
+            .iter()
+            .cloned()
+            .map(|f| {
+                if convert(&f.carrier, f.source) {
+                    Factor::new(
+                        f.carrier,
+                        f.source,
+                        f.dest,
+                        f.step,
+                        RenNrenCo2::new(0.0, f.ren + f.nren, f.co2), // ¿Esto es lo que tiene más sentido?
+                        tr_format!("Perímetro nearby: {}", f.comment),
+                    )
+                } else {
+                    f
+                }
+            })
+            .collect();
+        let wseries = self
+            .wseries
+            .iter()
+            .cloned()
+            .map(|mut f| {
+                if convert(&f.carrier, f.source) {
+                    f.values
+                        .iter_mut()
+                        .for_each(|v| *v = RenNrenCo2::new(0.0, v.ren + v.nren, v.co2));
+                    f.comment = tr_format!("Perímetro nearby: {}", f.comment);
+                }
+                f
+            })
+            .collect();
+        Factors {
+            wmeta: self.wmeta.clone(),
+            wdata,
+            wseries,
+        }
     }
 
     /// Actualiza los factores definibles por el usuario (cogen_to_grid, cogen_to_nepb, red1 y red2)
     pub fn set_user_wfactors(mut self, user: UserWF<Option<RenNrenCo2>>) -> Self {
-        use Carrier::{ELECTRICIDAD, RED1, RED2};
+        use Carrier::{ELECTRICIDAD, RED1, RED2, RESIDUOS};
         use Dest::{A_NEPB, A_RED, SUMINISTRO};
         use Source::{COGENERACION, RED};
         use Step::A;
@@ -130,11 +376,19 @@ impl Factors {
             ),
             (RED1, RED, SUMINISTRO, A, user.red1, "Factor de usuario"),
             (RED2, RED, SUMINISTRO, A, user.red2, "Factor de usuario"),
+            (
+                RESIDUOS,
+                RED,
+                SUMINISTRO,
+                A,
+                user.residuos,
+                "Factor de usuario",
+            ),
         ]
         .iter()
         .for_each(|(carrier, source, dest, step, uservalue, comment)| {
             if let Some(value) = *uservalue {
-                self.update_wfactor(*carrier, *source, *dest, *step, value, comment)
+                self.update_wfactor(carrier.clone(), *source, *dest, *step, value, comment)
             }
         });
 
@@ -149,16 +403,65 @@ impl Factors {
     /// - asegura que factor paso A para suministro de cogeneración es 0.0 (se considera en vector original)
     /// - asegura definición de factores a la red para vectores con exportación
     /// - asegura que existe RED1 | RED2 en suministro
+    /// - asegura que existe RESIDUOS en suministro (valor por defecto `defaults.residuos`)
+    /// - asegura definición de factores de exportación de calor a las redes de distrito RED1 | RED2 | REDn
+    ///   con factores de producción in situ o cogenerada (ver `ensure_district_onsite_heat`)
     ///
     /// TODO: se deberían separar algunos de estos pasos como métodos de CteFactorsExt
-    pub fn normalize(mut self, defaults: &UserWF<RenNrenCo2>) -> Result<Self, EpbdError> {
+    pub fn normalize(self, defaults: &UserWF<RenNrenCo2>) -> Result<Self, EpbdError> {
+        self.normalize_with_warnings(defaults)
+            .map(|(factors, _)| factors)
+    }
+
+    /// Asegura consistencia de factores de paso e informa de las correcciones realizadas
+    ///
+    /// Igual que `normalize`, pero devuelve también un aviso por cada factor de paso
+    /// añadido o cuyos valores se han sustituido.
+    pub fn normalize_with_warnings(
+        self,
+        defaults: &UserWF<RenNrenCo2>,
+    ) -> Result<(Self, Vec<Warning>), EpbdError> {
+        let original = self.wdata.clone();
+        let normalized = self.normalize_factors(defaults)?;
+        let warnings = normalized
+            .wdata
+            .iter()
+            .filter_map(|new| match original.iter().find(|old| same_key(old, new)) {
+                None => Some(Warning::AddedFactor(new.clone())),
+                Some(old) if old.factors() != new.factors() => Some(Warning::ReplacedFactor {
+                    old: old.clone(),
+                    new: new.clone(),
+                }),
+                Some(_) => None,
+            })
+            .collect::<Vec<_>>();
+        for warning in &warnings {
+            debug!(
+                "Corrección de factores de paso [{}]: {}",
+                warning.code(),
+                warning
+            );
+        }
+        info!(
+            "Factores de paso normalizados: {} factores, {} añadidos o sustituidos",
+            normalized.wdata.len(),
+            warnings.len()
+        );
+        Ok((normalized, warnings))
+    }
+
+    /// Completa y corrige los factores de paso (ver `normalize`), sin registrar las correcciones
+    pub(crate) fn normalize_factors(
+        mut self,
+        defaults: &UserWF<RenNrenCo2>,
+    ) -> Result<Self, EpbdError> {
         use Carrier::*;
         use Dest::*;
         use Source::*;
         use Step::*;
 
         // Vectores existentes
-        let wf_carriers: HashSet<_> = self.wdata.iter().map(|f| f.carrier).collect();
+        let wf_carriers: HashSet<_> = self.wdata.iter().map(|f| f.carrier.clone()).collect();
 
         // Asegura que existe MEDIOAMBIENTE, INSITU, SUMINISTRO, A, 1.0, 0.0
         self.update_wfactor(
@@ -192,19 +495,22 @@ impl Factors {
             );
         }
 
+        // Índice de los factores de suministro existentes. Los factores que se añaden a continuación
+        // son de exportación (A_RED, A_NEPB) y no afectan a las búsquedas de factores de suministro
+        let index = self.index();
+
         // Asegura definición de factores de red para todos los vectores energéticos
-        let has_grid_factors_for_all_carriers = wf_carriers.iter().all(|&c| {
-            self.wdata.iter().any(|f| {
-                f.carrier == c
-                    && f.source == Source::RED
-                    && f.dest == Dest::SUMINISTRO
-                    && f.step == Step::A
-            })
-        });
-        if !has_grid_factors_for_all_carriers {
-            return Err(EpbdError::MissingFactor(
-                "Factores de red VECTOR, INSITU, SUMINISTRO, A, fren?, fnren?".into(),
-            ));
+        // (salvo las redes de distrito, que tienen factores por defecto)
+        let carrier_without_grid_factors = wf_carriers
+            .iter()
+            .find(|c| !c.is_district() && !index.contains_key(&((*c).clone(), RED, SUMINISTRO, A)));
+        if let Some(carrier) = carrier_without_grid_factors {
+            return Err(EpbdError::MissingFactor {
+                carrier: carrier.clone(),
+                source: RED,
+                dest: SUMINISTRO,
+                step: A,
+            });
         }
 
         // En paso A, el factor SUMINISTRO de cogeneración es 0.0, 0.0 ya que el impacto se tiene en cuenta en el suministro del vector de generación
@@ -226,21 +532,12 @@ impl Factors {
         for (c, s) in &exp_carriers {
             if *s != Source::COGENERACION {
                 // Asegura que existe VECTOR, SRC, A_RED | A_NEPB, A, ren, nren
-                let fp_a_input = self
-                    .wdata
-                    .iter()
-                    .find(|f| {
-                        f.carrier == *c
-                            && f.source == *s
-                            && f.step == Step::A
-                            && f.dest == Dest::SUMINISTRO
-                    })
-                    .map(|f| f.factors());
+                let fp_a_input = index.get(&(c.clone(), *s, SUMINISTRO, A)).copied();
 
                 if let Some(factors) = fp_a_input {
                     // VECTOR, SRC, A_RED, A, ren, nren === VECTOR, SRC, SUMINISTRO, A, ren, nren
                     self.ensure_wfactor(
-                        *c,
+                        c.clone(),
                         *s,
                         A_RED,
                         A,
@@ -249,7 +546,7 @@ impl Factors {
                     );
                     // VECTOR, SRC, A_NEPB, A, ren, nren == VECTOR, SRC, SUMINISTRO, A, ren, nren
                     self.ensure_wfactor(
-                        *c,
+                        c.clone(),
                         *s,
                         A_NEPB,
                         A,
@@ -282,21 +579,12 @@ impl Factors {
             }
 
             // Asegura que existe VECTOR, SRC, A_RED | A_NEPB, B, ren, nren
-            let fp_a_red_input = self
-                .wdata
-                .iter()
-                .find(|f| {
-                    f.carrier == *c
-                        && f.source == Source::RED
-                        && f.dest == Dest::SUMINISTRO
-                        && f.step == Step::A
-                })
-                .map(|f| f.factors());
+            let fp_a_red_input = index.get(&(c.clone(), RED, SUMINISTRO, A)).copied();
 
             if let Some(factors) = fp_a_red_input {
                 // VECTOR, SRC, A_RED, B, ren, nren == VECTOR, RED, SUMINISTRO, A, ren, nren
                 self.ensure_wfactor(
-                    *c,
+                    c.clone(),
                     *s,
                     A_RED,
                     B,
@@ -305,7 +593,7 @@ impl Factors {
                 );
                 // VECTOR, SRC, A_NEPB, B, ren, nren == VECTOR, RED, SUMINISTRO, A, ren, nren
                 self.ensure_wfactor(
-                    *c,
+                    c.clone(),
                     *s,
                     A_NEPB,
                     B,
@@ -313,29 +601,99 @@ impl Factors {
                     "Recursos ahorrados a la red por la energía producida in situ y exportada a usos no EPB",
                 );
             } else {
-                return Err(EpbdError::MissingFactor(format!("{}, SUMINISTRO, A", c)));
+                return Err(EpbdError::MissingFactor {
+                    carrier: c.clone(),
+                    source: *s,
+                    dest: SUMINISTRO,
+                    step: A,
+                });
             }
         }
 
-        // Asegura que existe RED1 | RED2, RED, SUMINISTRO, A, ren, nren
-        self.ensure_wfactor(
-            RED1,
-            RED,
-            SUMINISTRO,
-            A,
-            defaults.red1,
-            "Recursos usados para suministrar energía de la red de distrito 1 (definible por el usuario)",
-        );
+        // Asegura que existe RED1 | RED2 | REDn, RED, SUMINISTRO, A, ren, nren
+        let mut districts: Vec<_> = wf_carriers
+            .iter()
+            .filter(|c| c.is_district())
+            .cloned()
+            .chain([RED1, RED2])
+            .collect();
+        districts.sort();
+        districts.dedup();
+        for c in &districts {
+            self.ensure_district_supply(c, defaults);
+        }
 
+        // Asegura que existe RESIDUOS, RED, SUMINISTRO, A, ren, nren
+        // No está definido en el documento reconocido del RITE y se usa el valor por defecto
         self.ensure_wfactor(
-            RED2,
+            RESIDUOS,
             RED,
             SUMINISTRO,
             A,
-            defaults.red2,
-            "Recursos usados para suministrar energía de la red de distrito 2 (definible por el usuario)",
+            defaults.residuos,
+            "Recursos usados para suministrar energía de redes alimentadas con residuos (definible por el usuario)",
         );
 
+        // Asegura factores de exportación de calor producido en el edificio a las redes de distrito RED1 | RED2 | REDn
+        // - solo para las redes con factores de producción in situ o cogenerada (ver `ensure_district_onsite_heat`)
+        // - el calor cogenerado exportado necesita factores de usuario (VECTOR, COGENERACION, A_RED, A, ren, nren)
+        for c in &districts {
+            let fp_red = self
+                .wdata
+                .iter()
+                .find(|f| f.carrier == *c && f.source == RED && f.dest == SUMINISTRO && f.step == A)
+                .map(|f| f.factors())
+                .unwrap_or_default();
+            for s in &[INSITU, COGENERACION] {
+                let fp_a = self
+                    .wdata
+                    .iter()
+                    .find(|f| {
+                        f.carrier == *c
+                            && f.source == *s
+                            && f.step == A
+                            && (f.dest == SUMINISTRO || f.dest == A_RED)
+                    })
+                    .map(|f| f.factors());
+                let fp_a = match fp_a {
+                    Some(fp_a) => fp_a,
+                    None => continue,
+                };
+                self.ensure_wfactor(
+                    c.clone(),
+                    *s,
+                    A_RED,
+                    A,
+                    fp_a,
+                    "Recursos usados para producir la energía térmica exportada a la red de distrito",
+                );
+                self.ensure_wfactor(
+                    c.clone(),
+                    *s,
+                    A_NEPB,
+                    A,
+                    fp_a,
+                    "Recursos usados para producir la energía térmica exportada a usos no EPB",
+                );
+                self.ensure_wfactor(
+                    c.clone(),
+                    *s,
+                    A_RED,
+                    B,
+                    fp_red,
+                    "Recursos ahorrados a la red de distrito por la energía térmica exportada a la red",
+                );
+                self.ensure_wfactor(
+                    c.clone(),
+                    *s,
+                    A_NEPB,
+                    B,
+                    fp_red,
+                    "Recursos ahorrados a la red de distrito por la energía térmica exportada a usos no EPB",
+                );
+            }
+        }
+
         Ok(self)
     }
 
@@ -346,33 +704,399 @@ impl Factors {
     ///  - de cogeneración si no hay cogeneración
     ///  - para exportación a usos no EPB si no se aparecen en los datos
     ///  - de electricidad in situ si no aparece una producción de ese tipo
-    pub fn strip(mut self, components: &Components) -> Self {
-        let wf_carriers: HashSet<_> = components.cdata.iter().map(|c| c.carrier).collect();
-        // Mantenemos factores para todos los vectores usados
-        self.wdata.retain(|f| wf_carriers.contains(&f.carrier));
-        // Mantenemos factores para cogeneración sólo si hay cogeneración
+    ///  - de calor in situ de redes de distrito si no aparece una producción de ese tipo
+    pub fn strip(self, components: &Components) -> Self {
+        self.strip_with_warnings(components).0
+    }
+
+    /// Elimina factores de paso no usados e informa de los factores eliminados
+    ///
+    /// Igual que `strip`, pero devuelve también un aviso por cada factor de paso eliminado,
+    /// con el motivo de la eliminación (ver `StripReason`). Permite comparar los factores
+    /// de paso efectivos con los declarados.
+    pub fn strip_with_warnings(self, components: &Components) -> (Self, Vec<Warning>) {
+        let (stripped, removed) = self.strip_factors(components);
+        let warnings = removed
+            .into_iter()
+            .map(|(factor, reason)| Warning::StrippedFactor { factor, reason })
+            .collect::<Vec<_>>();
+        for warning in &warnings {
+            debug!(
+                "Eliminación de factores de paso [{}]: {}",
+                warning.code(),
+                warning
+            );
+        }
+        info!(
+            "Factores de paso no usados eliminados: {} factores, {} eliminados",
+            stripped.wdata.len(),
+            warnings.len()
+        );
+        (stripped, warnings)
+    }
+
+    /// Elimina factores de paso no usados (ver `strip`) y devuelve los factores eliminados
+    fn strip_factors(mut self, components: &Components) -> (Self, Vec<(Factor, StripReason)>) {
+        let wf_carriers: HashSet<_> = components.cdata.iter().map(|c| c.carrier.clone()).collect();
         let has_cogen = components
             .cdata
             .iter()
             .any(|c| c.csubtype == CSubtype::COGENERACION);
-        self.wdata
-            .retain(|f| f.source != Source::COGENERACION || has_cogen);
-        // Mantenemos factores a usos no EPB si hay uso de no EPB
         let has_nepb = components
             .cdata
             .iter()
             .any(|c| c.csubtype == CSubtype::NEPB);
-        self.wdata.retain(|f| f.dest != Dest::A_NEPB || has_nepb);
-        // Mantenemos factores de electricidad in situ si no hay producción de ese tipo
         let has_elec_insitu = components
             .cdata
             .iter()
             .any(|c| c.carrier == Carrier::ELECTRICIDAD && c.csubtype == CSubtype::INSITU);
-        self.wdata.retain(|f| {
-            f.carrier != Carrier::ELECTRICIDAD || f.source != Source::INSITU || has_elec_insitu
+        let heat_insitu: HashSet<_> = components
+            .cdata
+            .iter()
+            .filter(|c| c.carrier.is_district() && c.csubtype == CSubtype::INSITU)
+            .map(|c| c.carrier.clone())
+            .collect();
+
+        let strip_reason = |f: &Factor| {
+            if !wf_carriers.contains(&f.carrier) {
+                // Mantenemos factores para todos los vectores usados
+                Some(StripReason::UnusedCarrier)
+            } else if f.source == Source::COGENERACION && !has_cogen {
+                // Mantenemos factores para cogeneración sólo si hay cogeneración
+                Some(StripReason::NoCogeneration)
+            } else if f.dest == Dest::A_NEPB && !has_nepb {
+                // Mantenemos factores a usos no EPB si hay uso de no EPB
+                Some(StripReason::NoNepbUse)
+            } else if f.carrier == Carrier::ELECTRICIDAD
+                && f.source == Source::INSITU
+                && !has_elec_insitu
+            {
+                // Mantenemos factores de electricidad in situ si hay producción de ese tipo
+                Some(StripReason::NoOnsiteElectricity)
+            } else if f.carrier.is_district()
+                && f.source == Source::INSITU
+                && !heat_insitu.contains(&f.carrier)
+            {
+                // Mantenemos factores de calor in situ de redes de distrito solo si hay producción de ese tipo
+                Some(StripReason::NoOnsiteHeat)
+            } else {
+                None
+            }
+        };
+
+        let mut removed = Vec::new();
+        self.wdata.retain(|f| match strip_reason(f) {
+            Some(reason) => {
+                removed.push((f.clone(), reason));
+                false
+            }
+            None => true,
         });
-        self
+        self.wseries.retain(|f| wf_carriers.contains(&f.carrier));
+        (self, removed)
     }
+
+    /// Incorpora factores de usuario, que sustituyen a los existentes con la misma clave
+    ///
+    /// Los factores se identifican por vector, fuente, destino y paso. Los factores de usuario
+    /// sin equivalente se añaden y el resto de factores existentes se mantienen.
+    /// Los metadatos de usuario sustituyen también a los existentes con la misma clave.
+    ///
+    /// Devuelve la lista de decisiones tomadas durante la combinación.
+    pub fn merge_overriding(&mut self, user_factors: &Factors) -> Vec<MergeDecision> {
+        for m in &user_factors.wmeta {
+            self.set_meta(&m.key, &m.value);
+        }
+        let mut decisions = Vec::new();
+        for new in &user_factors.wdata {
+            if let Some(old) = self.wdata.iter_mut().find(|f| {
+                f.carrier == new.carrier
+                    && f.source == new.source
+                    && f.dest == new.dest
+                    && f.step == new.step
+            }) {
+                decisions.push(MergeDecision::Replaced {
+                    old: old.clone(),
+                    new: new.clone(),
+                });
+                *old = new.clone();
+            } else {
+                decisions.push(MergeDecision::Added(new.clone()));
+                self.wdata.push(new.clone());
+            }
+        }
+        decisions
+    }
+
+    /// Compara con otro conjunto de factores de paso y devuelve las diferencias
+    ///
+    /// Los factores se identifican por vector, fuente, destino y paso. Se consideran
+    /// modificados los factores cuyos valores (ren, nren, co2) difieren.
+    ///
+    /// Compare with `other` weighting factors and report added, removed and changed factors
+    pub fn diff(&self, other: &Factors) -> FactorsDiff {
+        let find =
+            |wdata: &[Factor], f: &Factor| wdata.iter().find(|o| o.key() == f.key()).cloned();
+
+        let mut diff = FactorsDiff::default();
+        for old in &self.wdata {
+            match find(&other.wdata, old) {
+                None => diff.removed.push(old.clone()),
+                Some(new) => {
+                    let delta = new.factors() - old.factors();
+                    if delta != RenNrenCo2::default() {
+                        diff.changed.push(FactorChange {
+                            old: old.clone(),
+                            new,
+                            delta,
+                        });
+                    }
+                }
+            }
+        }
+        diff.added = other
+            .wdata
+            .iter()
+            .filter(|new| find(&self.wdata, new).is_none())
+            .cloned()
+            .collect();
+        diff
+    }
+
+    /// Cobertura de los factores de paso necesarios para evaluar los componentes
+    ///
+    /// Determina, antes del cálculo, los factores de paso (vector, fuente, destino, paso) que
+    /// requieren los componentes y si están definidos (como factor constante o como serie
+    /// variable en el tiempo), si se generarían al normalizar los factores de paso (ver `normalize`)
+    /// o si faltan y el cálculo del balance produciría un error.
+    ///
+    /// Se consideran necesarios:
+    ///  - los factores de suministro desde la red de todos los vectores de los componentes
+    ///  - los factores de suministro de la producción in situ
+    ///  - los factores de exportación a la red (pasos A y B) de la producción de cada origen,
+    ///    salvo de MEDIOAMBIENTE, y a usos no EPB si hay consumos no EPB del vector
+    ///
+    /// Report which weighting factors are required by the components and whether they are defined,
+    /// would be generated by normalization or are missing.
+    pub fn coverage(&self, components: &Components) -> FactorCoverage {
+        use Dest::*;
+        use Step::*;
+
+        let mut required = Vec::new();
+        for c in &components.cdata {
+            required.push((c.carrier.clone(), Source::RED, SUMINISTRO, A));
+            if c.ctype != CType::PRODUCCION {
+                continue;
+            }
+            let source: Source = match c.csubtype.try_into() {
+                Ok(source) => source,
+                Err(_) => continue,
+            };
+            if source == Source::INSITU {
+                required.push((c.carrier.clone(), source, SUMINISTRO, A));
+            }
+            if c.carrier == Carrier::MEDIOAMBIENTE {
+                continue;
+            }
+            let has_nepb = components.cdata.iter().any(|o| {
+                o.carrier == c.carrier && o.is_used_energy() && o.csubtype == CSubtype::NEPB
+            });
+            for step in [A, B] {
+                required.push((c.carrier.clone(), source, A_RED, step));
+                if has_nepb {
+                    required.push((c.carrier.clone(), source, A_NEPB, step));
+                }
+            }
+        }
+        required.sort();
+        required.dedup();
+
+        // Los valores por defecto no afectan a los factores generados, solo a sus valores
+        let defaults = UserWF {
+            red1: RenNrenCo2::default(),
+            red2: RenNrenCo2::default(),
+            residuos: RenNrenCo2::default(),
+            cogen_to_grid: RenNrenCo2::default(),
+            cogen_to_nepb: RenNrenCo2::default(),
+        };
+        let normalized: HashSet<FactorKey> = self
+            .clone()
+            .normalize_factors(&defaults)
+            .map(|normalized| normalized.wdata.iter().map(Factor::key).collect())
+            .unwrap_or_default();
+        let defined: HashSet<FactorKey> = self
+            .wdata
+            .iter()
+            .map(Factor::key)
+            .chain(
+                self.wseries
+                    .iter()
+                    .map(|fs| (fs.carrier.clone(), fs.source, fs.dest, fs.step)),
+            )
+            .collect();
+
+        let factors = required
+            .into_iter()
+            .map(|key| {
+                let status = if defined.contains(&key) {
+                    CoverageStatus::Defined
+                } else if normalized.contains(&key) {
+                    CoverageStatus::Defaulted
+                } else {
+                    CoverageStatus::Missing
+                };
+                (key, status)
+            })
+            .collect();
+        FactorCoverage { factors }
+    }
+}
+
+/// Estado de un factor de paso necesario para evaluar los componentes (ver `Factors::coverage`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CoverageStatus {
+    /// Factor defined in the weighting factors (constant or time-varying)
+    Defined,
+    /// Factor generated with a default value when normalizing the weighting factors
+    Defaulted,
+    /// Factor not available
+    Missing,
+}
+
+impl fmt::Display for CoverageStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            CoverageStatus::Defined => "definido",
+            CoverageStatus::Defaulted => "por defecto",
+            CoverageStatus::Missing => "no disponible",
+        };
+        write!(f, "{}", tr(msg))
+    }
+}
+
+/// Cobertura de los factores de paso necesarios para evaluar unos componentes
+///
+/// Coverage of the weighting factors required by a set of components
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FactorCoverage {
+    /// Factores necesarios (vector, fuente, destino, paso), ordenados, y su estado
+    pub factors: Vec<(FactorKey, CoverageStatus)>,
+}
+
+impl FactorCoverage {
+    /// Factores necesarios que no están disponibles
+    pub fn missing(&self) -> impl Iterator<Item = &FactorKey> {
+        self.with_status(CoverageStatus::Missing)
+    }
+
+    /// Factores necesarios que se generan con valores por defecto
+    pub fn defaulted(&self) -> impl Iterator<Item = &FactorKey> {
+        self.with_status(CoverageStatus::Defaulted)
+    }
+
+    /// Indica si están disponibles, definidos o por defecto, todos los factores necesarios
+    pub fn is_complete(&self) -> bool {
+        self.missing().next().is_none()
+    }
+
+    fn with_status(&self, status: CoverageStatus) -> impl Iterator<Item = &FactorKey> {
+        self.factors
+            .iter()
+            .filter(move |(_, s)| *s == status)
+            .map(|(key, _)| key)
+    }
+}
+
+impl fmt::Display for FactorCoverage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let lines = self
+            .factors
+            .iter()
+            .map(|((carrier, source, dest, step), status)| {
+                format!("{}, {}, {}, {}: {}", carrier, source, dest, step, status)
+            })
+            .collect::<Vec<_>>();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// Decisión tomada al combinar factores de paso con `Factors::merge_overriding`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum MergeDecision {
+    /// Factor existente sustituido por un factor de usuario
+    Replaced {
+        /// Factor original
+        old: Factor,
+        /// Factor de usuario
+        new: Factor,
+    },
+    /// Factor de usuario añadido, sin equivalente previo
+    Added(Factor),
+}
+
+impl fmt::Display for MergeDecision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeDecision::Replaced { old, new } => {
+                f.write_str(&tr_format!("Sustituido: {} -> {}", old, new))
+            }
+            MergeDecision::Added(new) => f.write_str(&tr_format!("Añadido: {}", new)),
+        }
+    }
+}
+
+/// Diferencias entre dos conjuntos de factores de paso
+///
+/// Differences between two sets of weighting factors
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct FactorsDiff {
+    /// Factores que solo existen en el nuevo conjunto
+    pub added: Vec<Factor>,
+    /// Factores que solo existen en el conjunto original
+    pub removed: Vec<Factor>,
+    /// Factores cuyos valores han cambiado
+    pub changed: Vec<FactorChange>,
+}
+
+impl FactorsDiff {
+    /// Indica si no existen diferencias
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for FactorsDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let added = self.added.iter().map(|v| format!("+ {}", v));
+        let removed = self.removed.iter().map(|v| format!("- {}", v));
+        let changed = self.changed.iter().map(|v| {
+            format!(
+                "~ {}, {}, {}, {}: ren {:+.3}, nren {:+.3}, co2 {:+.3}",
+                v.new.carrier,
+                v.new.source,
+                v.new.dest,
+                v.new.step,
+                v.delta.ren,
+                v.delta.nren,
+                v.delta.co2
+            )
+        });
+        let lines = removed.chain(added).chain(changed).collect::<Vec<_>>();
+        write!(f, "{}", lines.join("\n"))
+    }
+}
+
+/// Cambio de valores de un factor de paso
+///
+/// Change in the values of a weighting factor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FactorChange {
+    /// Factor original
+    pub old: Factor,
+    /// Factor nuevo
+    pub new: Factor,
+    /// Diferencia de valores (nuevo - original)
+    pub delta: RenNrenCo2,
 }
 
 impl MetaVec for Factors {
@@ -384,6 +1108,10 @@ impl MetaVec for Factors {
     }
 }
 
+/// Representación textual de los factores de paso
+///
+/// Los valores se muestran con la precisión indicada en el formato (p.e. `{:.4}`) o, si no se
+/// indica, con la precisión por defecto (ver `types::FACTOR_DECIMALS`).
 impl fmt::Display for Factors {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let metalines = self
@@ -392,39 +1120,163 @@ impl fmt::Display for Factors {
             .map(|v| format!("{}", v))
             .collect::<Vec<_>>()
             .join("\n");
+        let decimals = f.precision().unwrap_or(FACTOR_DECIMALS);
         let datalines = self
             .wdata
             .iter()
-            .map(|v| format!("{}", v))
+            .map(|v| format!("{:.*}", decimals, v))
             .collect::<Vec<_>>()
             .join("\n");
-        write!(f, "{}\n{}", metalines, datalines)
+        if self.wseries.is_empty() {
+            write!(f, "{}\n{}", metalines, datalines)
+        } else {
+            let serieslines = self
+                .wseries
+                .iter()
+                .map(|v| format!("{:.*}", decimals, v))
+                .collect::<Vec<_>>()
+                .join("\n");
+            write!(f, "{}\n{}\n{}", metalines, datalines, serieslines)
+        }
     }
 }
 
+/// Indica si una línea de datos de factores de paso define un factor variable en el tiempo
+fn is_series_line(line: &str) -> bool {
+    line.split(',').nth(4).map(str::trim) == Some(FACTOR_SERIES_TAG)
+}
+
+/// Indica si dos factores de paso tienen la misma clave (vector, fuente, destino y paso)
+fn same_key(a: &Factor, b: &Factor) -> bool {
+    a.key() == b.key()
+}
+
+/// Comprueba todas las líneas de un texto de factores de paso y devuelve todos los problemas detectados
+///
+/// A diferencia de la interpretación de los factores de paso, que se interrumpe en el primer
+/// error, se comprueban todas las líneas de metadatos, factores y series de factores y, si no
+/// hay errores de formato, que existen los factores de suministro desde la red (`RED,
+/// SUMINISTRO, A`) de todos los vectores, salvo MEDIOAMBIENTE, que se completa automáticamente.
+/// Los diagnósticos indican la línea (empezando en 1) en la que se detecta el problema.
+///
+/// Devuelve una lista vacía si los factores de paso son correctos.
+pub fn validate_wfactors(s: &str) -> Vec<Diagnostic> {
+    let mut diagnostics = Vec::new();
+    let mut wdata: Vec<(usize, Factor)> = Vec::new();
+    for (i, line) in s.lines().map(str::trim).enumerate() {
+        let num = i + 1;
+        let result = if line.starts_with("#META") || line.starts_with("#CTE_") {
+            line.parse::<Meta>().map(|_| ())
+        } else if line.starts_with('#') || line.starts_with("vector,") || line.is_empty() {
+            Ok(())
+        } else if is_series_line(line) {
+            line.parse::<FactorSeries>().map(|_| ())
+        } else {
+            line.parse::<Factor>().map(|f| wdata.push((num, f)))
+        };
+        if let Err(e) = result {
+            diagnostics.push(Diagnostic::new(Some(num), &e));
+        }
+    }
+    if diagnostics.is_empty() {
+        let mut carriers: Vec<(usize, Carrier)> = Vec::new();
+        for (num, f) in &wdata {
+            if f.carrier != Carrier::MEDIOAMBIENTE && !carriers.iter().any(|(_, c)| *c == f.carrier)
+            {
+                carriers.push((*num, f.carrier.clone()));
+            }
+        }
+        for (num, carrier) in carriers {
+            let has_grid_factor = wdata.iter().any(|(_, f)| {
+                f.carrier == carrier
+                    && f.source == Source::RED
+                    && f.dest == Dest::SUMINISTRO
+                    && f.step == Step::A
+            });
+            if !has_grid_factor {
+                diagnostics.push(Diagnostic::new(
+                    Some(num),
+                    &EpbdError::MissingFactor {
+                        carrier,
+                        source: Source::RED,
+                        dest: Dest::SUMINISTRO,
+                        step: Step::A,
+                    },
+                ));
+            }
+        }
+    }
+    diagnostics
+}
+
 impl str::FromStr for Factors {
     type Err = EpbdError;
 
     fn from_str(s: &str) -> Result<Factors, Self::Err> {
-        let lines: Vec<&str> = s.lines().map(str::trim).collect();
+        // Líneas con su número (empezando en 1), para localizar los errores
+        let lines: Vec<(usize, &str)> = s
+            .lines()
+            .map(str::trim)
+            .enumerate()
+            .map(|(i, l)| (i + 1, l))
+            .collect();
         let metalines = lines
             .iter()
-            .filter(|l| l.starts_with("#META") || l.starts_with("#CTE_"));
+            .filter(|(_, l)| l.starts_with("#META") || l.starts_with("#CTE_"));
         let datalines = lines
             .iter()
-            .filter(|l| !(l.starts_with('#') || l.starts_with("vector,") || l.is_empty()));
+            .filter(|(_, l)| !(l.starts_with('#') || l.starts_with("vector,") || l.is_empty()));
         let wmeta = metalines
-            .map(|e| e.parse())
+            .map(|(num, l)| l.parse().map_err(|e: EpbdError| e.at_line(*num, l)))
             .collect::<Result<Vec<Meta>, _>>()?;
+        let (serieslines, datalines): (Vec<_>, Vec<_>) =
+            datalines.partition(|(_, l)| is_series_line(l));
         let wdata = datalines
-            .map(|e| e.parse())
+            .iter()
+            .map(|(num, l)| l.parse().map_err(|e: EpbdError| e.at_line(*num, l)))
             .collect::<Result<Vec<Factor>, _>>()?;
-        Ok(Factors { wmeta, wdata })
+        let wseries = serieslines
+            .iter()
+            .map(|(num, l)| l.parse().map_err(|e: EpbdError| e.at_line(*num, l)))
+            .collect::<Result<Vec<FactorSeries>, _>>()?;
+        Ok(Factors {
+            wmeta,
+            wdata,
+            wseries,
+        })
+    }
+}
+
+/// Motivo de la eliminación de un factor de paso no usado (ver `Factors::strip`)
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StripReason {
+    /// Carrier not used in the components
+    UnusedCarrier,
+    /// Cogeneration factor without cogeneration production
+    NoCogeneration,
+    /// Factor for exports to non EPB uses without non EPB uses
+    NoNepbUse,
+    /// Onsite electricity factor without onsite electricity production
+    NoOnsiteElectricity,
+    /// Onsite heat factor of a district network without onsite heat production
+    NoOnsiteHeat,
+}
+
+impl fmt::Display for StripReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let msg = match self {
+            StripReason::UnusedCarrier => "vector no usado",
+            StripReason::NoCogeneration => "sin producción por cogeneración",
+            StripReason::NoNepbUse => "sin usos no EPB",
+            StripReason::NoOnsiteElectricity => "sin producción de electricidad in situ",
+            StripReason::NoOnsiteHeat => "sin producción de calor in situ",
+        };
+        write!(f, "{}", tr(msg))
     }
 }
 
 /// Estructura para definir valores por defecto y valores de usuario
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub struct UserWF<T = RenNrenCo2> {
     /// Factores de paso de redes de distrito 1.
     /// RED1, RED, SUMINISTRO, A, ren, nren
@@ -432,6 +1284,9 @@ pub struct UserWF<T = RenNrenCo2> {
     /// Factores de paso de redes de distrito 2.
     /// RED2, RED, SUMINISTRO, A, ren, nren
     pub red2: T,
+    /// Factores de paso de redes alimentadas con residuos.
+    /// RESIDUOS, RED, SUMINISTRO, A, ren, nren
+    pub residuos: T,
     /// Factores de paso para exportación a la red (paso A) de electricidad cogenerada.
     /// ELECTRICIDAD, COGENERACION, A_RED, A, ren, nren
     pub cogen_to_grid: T,
@@ -456,6 +1311,133 @@ ELECTRICIDAD, INSITU, SUMINISTRO, A, 1.000, 0.000, 0.000 # Recursos usados para
         assert_eq!(tfactors1.parse::<Factors>().unwrap().to_string(), tfactors1);
     }
 
+    #[test]
+    fn tvalidate_wfactors() {
+        let diagnostics = validate_wfactors(
+            "#META CTE_FUENTE: USUARIO
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+ELECTRICIDAD, XXX, SUMINISTRO, A, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, 0.005",
+        );
+        let lines: Vec<_> = diagnostics.iter().map(|d| d.line).collect();
+        assert_eq!(lines, vec![Some(3), Some(4)]);
+        // Falta el factor de suministro desde la red
+        let diagnostics = validate_wfactors(
+            "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+MEDIOAMBIENTE, INSITU, SUMINISTRO, A, 1.0, 0.0, 0.0
+GASNATURAL, INSITU, SUMINISTRO, A, 0.005, 1.190, 0.252",
+        );
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].line, Some(3));
+        assert_eq!(diagnostics[0].code, "E101");
+    }
+
+    #[test]
+    fn tfactors_from_json() {
+        let factors = Factors::from_json(
+            r#"{"wdata": [
+                {"carrier": "ELECTRICIDAD", "source": "RED", "dest": "SUMINISTRO", "step": "A", "ren": 0.414, "nren": 1.954, "co2": 0.331},
+                {"carrier": "ELECTRICIDAD", "source": "INSITU", "dest": "SUMINISTRO", "step": "A", "ren": 1.0, "nren": 0.0}
+            ]}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            factors.to_string(),
+            "\nELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331\nELECTRICIDAD, INSITU, SUMINISTRO, A, 1.000, 0.000, 0.000"
+        );
+        assert!(Factors::from_json(r#"{"wdata": [{"carrier": "XXX"}]}"#).is_err());
+    }
+
+    #[cfg(feature = "yaml")]
+    #[test]
+    fn tfactors_yaml() {
+        let factors = Factors::from_yaml(
+            "wdata:\n  - carrier: ELECTRICIDAD\n    source: RED\n    dest: SUMINISTRO\n    step: A\n    ren: 0.414\n    nren: 1.954\n    co2: 0.331\n",
+        )
+        .unwrap();
+        assert_eq!(
+            factors.to_string(),
+            "\nELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331"
+        );
+        let factors2 = Factors::from_yaml(&factors.to_yaml().unwrap()).unwrap();
+        assert_eq!(factors2.to_string(), factors.to_string());
+        assert!(Factors::from_yaml("wdata:\n  - carrier: XXX\n").is_err());
+    }
+
+    #[test]
+    fn tfactors_diff() {
+        let old = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.190, 0.252
+GASOLEO, RED, SUMINISTRO, A, 0.003, 1.179, 0.311"
+            .parse::<Factors>()
+            .unwrap();
+        let new = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.090, 0.252
+GLP, RED, SUMINISTRO, A, 0.003, 1.201, 0.254"
+            .parse::<Factors>()
+            .unwrap();
+        let diff = old.diff(&new);
+        assert_eq!(
+            diff.to_string(),
+            "- GASOLEO, RED, SUMINISTRO, A, 0.003, 1.179, 0.311
++ GLP, RED, SUMINISTRO, A, 0.003, 1.201, 0.254
+~ GASNATURAL, RED, SUMINISTRO, A: ren +0.000, nren -0.100, co2 +0.000"
+        );
+        assert!(old.diff(&old).is_empty());
+    }
+
+    #[test]
+    fn tfactors_merge_overriding() {
+        let mut catalog = "#META CTE_FUENTE: RITE2014
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.190, 0.252"
+            .parse::<Factors>()
+            .unwrap();
+        let user = "#META CTE_FUENTE: USUARIO
+GASNATURAL, RED, SUMINISTRO, A, 0.000, 1.100, 0.220 # Usuario
+RED1, RED, SUMINISTRO, A, 0.000, 1.300, 0.300"
+            .parse::<Factors>()
+            .unwrap();
+        let decisions = catalog.merge_overriding(&user);
+        assert_eq!(
+            decisions
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<_>>()
+                .join("\n"),
+            "Sustituido: GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.190, 0.252 -> GASNATURAL, RED, SUMINISTRO, A, 0.000, 1.100, 0.220 # Usuario
+Añadido: RED1, RED, SUMINISTRO, A, 0.000, 1.300, 0.300"
+        );
+        assert_eq!(
+            catalog.to_string(),
+            "#META CTE_FUENTE: USUARIO
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, 0.000, 1.100, 0.220 # Usuario
+RED1, RED, SUMINISTRO, A, 0.000, 1.300, 0.300"
+        );
+    }
+
+    #[test]
+    fn tfactors_sort() {
+        let mut tfactors = "GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.190, 0.252
+ELECTRICIDAD, INSITU, A_RED, B, 0.414, 1.954, 0.331
+ELECTRICIDAD, INSITU, A_RED, A, 1.000, 0.000, 0.000
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331"
+            .parse::<Factors>()
+            .unwrap();
+        tfactors.sort_canonical();
+        assert_eq!(
+            tfactors.to_string(),
+            "
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+ELECTRICIDAD, INSITU, A_RED, A, 1.000, 0.000, 0.000
+ELECTRICIDAD, INSITU, A_RED, B, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.190, 0.252"
+        );
+        tfactors.sort_by_key(|f| std::cmp::Reverse(f.carrier.clone()));
+        assert_eq!(tfactors.wdata[0].carrier, Carrier::GASNATURAL);
+    }
+
     #[test]
     fn set_user_factors() {
         let tfactors1 = "#META CTE_FUENTE: RITE2014
@@ -476,6 +1458,7 @@ RED2, RED, SUMINISTRO, A, 0.125, 0.100, 0.500 # Factor de usuario";
                 .set_user_wfactors(UserWF {
                     red1: Some(RenNrenCo2::new(0.1, 0.125, 0.5)),
                     red2: Some(RenNrenCo2::new(0.125, 0.1, 0.5)),
+                    residuos: None,
                     cogen_to_grid: Some(RenNrenCo2::new(0.125, 0.5, 1.0)),
                     cogen_to_nepb: Some(RenNrenCo2::new(0.5, 0.125, 2.0)),
                 })
@@ -510,7 +1493,8 @@ MEDIOAMBIENTE, INSITU, A_NEPB, A, 1.000, 0.000, 0.000 # Recursos usados para pro
 MEDIOAMBIENTE, INSITU, A_RED, B, 1.000, 0.000, 0.000 # Recursos ahorrados a la red por la energía producida in situ y exportada a la red
 MEDIOAMBIENTE, INSITU, A_NEPB, B, 1.000, 0.000, 0.000 # Recursos ahorrados a la red por la energía producida in situ y exportada a usos no EPB
 RED1, RED, SUMINISTRO, A, 0.000, 1.300, 0.300 # Recursos usados para suministrar energía de la red de distrito 1 (definible por el usuario)
-RED2, RED, SUMINISTRO, A, 0.000, 1.300, 0.300 # Recursos usados para suministrar energía de la red de distrito 2 (definible por el usuario)";
+RED2, RED, SUMINISTRO, A, 0.000, 1.300, 0.300 # Recursos usados para suministrar energía de la red de distrito 2 (definible por el usuario)
+RESIDUOS, RED, SUMINISTRO, A, 0.000, 1.300, 0.300 # Recursos usados para suministrar energía de redes alimentadas con residuos (definible por el usuario)";
         let tcomps = "ELECTRICIDAD, CONSUMO, EPB, NDEF, 1 # Solo consume electricidad de red"
             .parse::<Components>()
             .unwrap();
@@ -518,8 +1502,8 @@ RED2, RED, SUMINISTRO, A, 0.000, 1.300, 0.300 # Recursos usados para suministrar
 #META CTE_FUENTE_COMENTARIO: Factores de paso del documento reconocido del IDAE de 20/07/2014
 ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331 # Recursos usados para suministrar electricidad (peninsular) desde la red";
 
-        let tfactors_normalized = tfactors
-            .normalize(&UserWF {
+        let (tfactors_normalized, warnings) = tfactors
+            .normalize_with_warnings(&UserWF {
                 red1: RenNrenCo2 {
                     ren: 0.0,
                     nren: 1.3,
@@ -530,6 +1514,11 @@ ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331 # Recursos usados para sum
                     nren: 1.3,
                     co2: 0.3,
                 },
+                residuos: RenNrenCo2 {
+                    ren: 0.0,
+                    nren: 1.3,
+                    co2: 0.3,
+                },
                 cogen_to_grid: RenNrenCo2 {
                     ren: 0.0,
                     nren: 2.5,
@@ -542,12 +1531,174 @@ ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331 # Recursos usados para sum
                 },
             })
             .unwrap();
-        let tfactors_normalized_stripped = tfactors_normalized.clone().strip(&tcomps);
+        let (tfactors_normalized_stripped, removed) =
+            tfactors_normalized.clone().strip_with_warnings(&tcomps);
 
         assert_eq!(tfactors_normalized.to_string(), tfactors_normalized_str);
         assert_eq!(
             tfactors_normalized_stripped.to_string(),
             tfactors_normalized_stripped_str
         );
+        // Un aviso por cada factor añadido o eliminado
+        assert_eq!(warnings.len(), tfactors_normalized.wdata.len() - 2);
+        assert!(warnings.iter().all(|w| w.code() == "W303"));
+        assert_eq!(removed.len(), tfactors_normalized.wdata.len() - 1);
+        assert!(removed.iter().all(|w| w.code() == "W305"));
+        assert!(removed.iter().any(|w| matches!(
+            w,
+            Warning::StrippedFactor {
+                reason: StripReason::UnusedCarrier,
+                ..
+            }
+        )));
+        assert!(removed.iter().any(|w| matches!(
+            w,
+            Warning::StrippedFactor {
+                reason: StripReason::NoOnsiteElectricity,
+                ..
+            }
+        )));
+        assert!(removed.iter().any(|w| matches!(
+            w,
+            Warning::StrippedFactor {
+                reason: StripReason::NoCogeneration,
+                ..
+            }
+        )));
+    }
+
+    #[test]
+    fn normalize_replaced_factor_warning() {
+        let (_, warnings) = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+MEDIOAMBIENTE, INSITU, SUMINISTRO, A, 0.500, 0.500, 0.000"
+            .parse::<Factors>()
+            .unwrap()
+            .normalize_with_warnings(&UserWF {
+                red1: RenNrenCo2::new(0.0, 1.3, 0.3),
+                red2: RenNrenCo2::new(0.0, 1.3, 0.3),
+                residuos: RenNrenCo2::new(0.0, 1.3, 0.3),
+                cogen_to_grid: RenNrenCo2::new(0.0, 2.5, 0.3),
+                cogen_to_nepb: RenNrenCo2::new(0.0, 2.5, 0.3),
+            })
+            .unwrap();
+        let replaced: Vec<_> = warnings.iter().filter(|w| w.code() == "W304").collect();
+        assert_eq!(replaced.len(), 1);
+        assert_eq!(
+            replaced[0].to_string(),
+            "factor de paso MEDIOAMBIENTE, INSITU, SUMINISTRO, A sustituido: 0.500, 0.500, 0.000 -> 1.000, 0.000, 0.000"
+        );
+    }
+
+    #[test]
+    fn factors_index() {
+        let factors = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+ELECTRICIDAD, RED, SUMINISTRO, A, 1.000, 1.000, 1.000
+GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.190, 0.252"
+            .parse::<Factors>()
+            .unwrap();
+        let index = factors.index();
+        assert_eq!(index.len(), 2);
+        // Se usa el primer factor con la misma clave
+        assert_eq!(
+            index[&(
+                Carrier::ELECTRICIDAD,
+                Source::RED,
+                Dest::SUMINISTRO,
+                Step::A
+            )],
+            RenNrenCo2::new(0.414, 1.954, 0.331)
+        );
+        assert_eq!(
+            index.get(&(Carrier::GASNATURAL, Source::RED, Dest::A_RED, Step::A)),
+            None
+        );
+        assert_eq!(
+            factors.wdata[2].key(),
+            (Carrier::GASNATURAL, Source::RED, Dest::SUMINISTRO, Step::A)
+        );
+    }
+
+    #[test]
+    fn to_perimeter() {
+        let tfactors = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+ELECTRICIDAD, INSITU, SUMINISTRO, A, 1.000, 0.000, 0.000
+BIOMASA, RED, SUMINISTRO, A, 1.003, 0.034, 0.018"
+            .parse::<Factors>()
+            .unwrap();
+        assert_eq!(tfactors.perimeter().unwrap(), Perimeter::DISTANT);
+        let nrb = tfactors.to_perimeter(Perimeter::NEARBY).unwrap();
+        assert_eq!(nrb.perimeter().unwrap(), Perimeter::NEARBY);
+        assert_eq!(nrb.wdata[0].factors(), RenNrenCo2::new(0.0, 2.368, 0.331));
+        assert_eq!(nrb.wdata[1].factors(), tfactors.wdata[1].factors());
+        assert_eq!(nrb.wdata[2].factors(), tfactors.wdata[2].factors());
+        // No se aplica dos veces la conversión
+        let nrb2 = nrb.to_perimeter(Perimeter::NEARBY).unwrap();
+        assert_eq!(nrb2.to_string(), nrb.to_string());
+        // Conversiones no disponibles y perímetros desconocidos
+        assert!(nrb.to_perimeter(Perimeter::DISTANT).is_err());
+        assert!(tfactors.to_perimeter(Perimeter::ONSITE).is_err());
+        let mut unknown = tfactors.clone();
+        unknown.set_meta("CTE_PERIMETRO", "LEJANO");
+        assert!(unknown.to_perimeter(Perimeter::NEARBY).is_err());
+    }
+
+    #[test]
+    fn factors_canonical_string() {
+        let tfactors = "#META CTE_FUENTE: RITE2014
+#META CTE_COMENTARIO: Factores de prueba
+GASNATURAL, RED, SUMINISTRO, A, 0.006, 1.19, 0.252
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331"
+            .parse::<Factors>()
+            .unwrap();
+        let canonical = tfactors.to_canonical_string(2);
+        assert_eq!(
+            canonical,
+            "#META CTE_COMENTARIO: Factores de prueba
+#META CTE_FUENTE: RITE2014
+ELECTRICIDAD, RED, SUMINISTRO, A, 0.41, 1.95, 0.33
+GASNATURAL, RED, SUMINISTRO, A, 0.01, 1.19, 0.25"
+        );
+        let reparsed = canonical.parse::<Factors>().unwrap();
+        assert_eq!(reparsed.to_canonical_string(2), canonical);
+        assert_eq!(
+            format!("{:.1}", tfactors.wdata[1]),
+            "ELECTRICIDAD, RED, SUMINISTRO, A, 0.4, 2.0, 0.3"
+        );
+    }
+
+    #[test]
+    fn factors_coverage() {
+        let tfactors = "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331"
+            .parse::<Factors>()
+            .unwrap();
+        let tcomps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 5.0
+GASNATURAL, CONSUMO, EPB, ACS, 20.0"
+            .parse::<Components>()
+            .unwrap();
+        let coverage = tfactors.coverage(&tcomps);
+        assert_eq!(
+            coverage.to_string(),
+            "ELECTRICIDAD, RED, SUMINISTRO, A: definido
+ELECTRICIDAD, INSITU, SUMINISTRO, A: por defecto
+ELECTRICIDAD, INSITU, A_RED, A: por defecto
+ELECTRICIDAD, INSITU, A_RED, B: por defecto
+GASNATURAL, RED, SUMINISTRO, A: no disponible"
+        );
+        assert!(!coverage.is_complete());
+        assert_eq!(
+            coverage.missing().collect::<Vec<_>>(),
+            vec![&(Carrier::GASNATURAL, Source::RED, Dest::SUMINISTRO, Step::A)]
+        );
+        assert_eq!(coverage.defaulted().count(), 3);
+
+        // Con consumos no EPB se necesitan los factores de exportación a usos no EPB
+        let tcomps = "ELECTRICIDAD, CONSUMO, NEPB, NDEF, 1.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 5.0"
+            .parse::<Components>()
+            .unwrap();
+        let coverage = tfactors.coverage(&tcomps);
+        assert!(coverage.is_complete());
+        assert_eq!(coverage.factors.len(), 6);
     }
 }
@@ -1,4 +1,6 @@
-use assert_cli;
+#![cfg(feature = "cli")]
+
+use std::path::Path;
 
 #[test]
 fn ejemplo_j1_loc() {
@@ -144,6 +146,21 @@ fn ejemplo_testcarriers_loc() {
         .unwrap();
 }
 
+#[test]
+fn ejemplo_testcarriers_gzip() {
+    // Componentes y factores de paso comprimidos con gzip
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/cte_test_carriers.csv.gz",
+            "-f",
+            "test_data/factores_paso_test.csv.gz",
+        ])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 25.4, nren = 19.4, tot = 44.8, RER = 0.57")
+        .unwrap();
+}
+
 #[test]
 fn ejemplo_testcarriers_loc_nearby() {
     assert_cli::Assert::main_binary()
@@ -204,3 +221,1153 @@ fn ejemplo_acs_demanda_ren_con_nepb_con_exclusion_aux_meta() {
         .contains("Porcentaje renovable de la demanda de ACS (perímetro próximo): 96.7 [%]")
         .unwrap();
 }
+
+#[test]
+fn error_code_kexp_fuera_de_rango() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "-l",
+            "PENINSULA",
+            "-k",
+            "2.0",
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("ERROR [E502]")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_j1_co2_loc() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "-f",
+            "test_data/factores_paso_test.csv",
+            "--co2_loc",
+            "CANARIAS",
+        ])
+        .stdout()
+        .contains("E_CO2 [kg_CO2e/m2.an]: 77.60")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_j3_kexp_nepb() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ3_basePVexcess.csv",
+            "-l",
+            "PENINSULA",
+            "-k",
+            "0",
+            "--kexp_nepb",
+            "1",
+        ])
+        .stdout()
+        .contains("k_exp (usos no EPB) = 1.00")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 100.0, nren = 0.0, tot = 100.0, RER = 1.00")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_j5_tarifas() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ5_gasPV.csv",
+            "-l",
+            "PENINSULA",
+            "--tarifas",
+            "test_data/tarifas_test.txt",
+        ])
+        .stdout()
+        .contains("factura_anual_estimada_GASNATURAL: 101.400")
+        .stdout()
+        .contains("factura_anual_estimada: 251.400")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_plurianual() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_plurianual.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("** Balance por anualidades:")
+        .stdout()
+        .contains("2019: C_ep [kWh/m2.an]: ren = 40.0, nren = 259.0, tot = 299.0, RER = 0.13")
+        .unwrap();
+}
+
+#[test]
+fn aviso_componentes_inverosimiles() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/componentes_inverosimiles.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .stdout()
+        .contains("AVISO [W201]: consumo de GASNATURAL para el servicio ILU poco habitual")
+        .stdout()
+        .contains("AVISO [W201]: consumo de BIOMASA para el servicio REF poco habitual")
+        .unwrap();
+}
+
+#[test]
+fn correcciones_automaticas() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/acs_demanda_ren_con_nepb.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .stdout()
+        .contains("AVISO [W302]: producción de MEDIOAMBIENTE generada para equilibrar el consumo del servicio ACS")
+        .stdout()
+        .doesnt_contain("AVISO [W303]")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-vv",
+            "-c",
+            "test_data/acs_demanda_ren_con_nepb.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .stdout()
+        .contains("AVISO [W303]: factor de paso añadido: MEDIOAMBIENTE, INSITU, A_RED, A")
+        .stdout()
+        .contains("AVISO [W305]: factor de paso eliminado por no usarse (vector no usado): RED1, RED, SUMINISTRO, A")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_sistemas() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_sistemas.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("AVISO [W203]: componentes asignados al sistema 3 no declarado")
+        .stdout()
+        .contains(
+            "1 - Caldera de gas (CALDERA, GASNATURAL, 24.00 kW): ELECTRICIDAD 0.50, GASNATURAL 15.00; horas equivalentes: 62.5 h",
+        )
+        .stdout()
+        .contains("4 - Fotovoltaica (FOTOVOLTAICA, ELECTRICIDAD, 2.00 kW): sin consumo; horas equivalentes: 1314.0 h; factor de capacidad: 0.150")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_conversiones() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_conversiones.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("RED1: 250.00 (total: 250.00)")
+        .stdout()
+        .contains("1 -> 2 (rendimiento 0.950): entrada 10.00, convertida 9.50, aprovechada 9.50, pérdidas 0.50, no aprovechada 0.00")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_factor_coincidencia() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ9_electr.csv",
+            "-l",
+            "PENINSULA",
+            "--factor_coincidencia",
+        ])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 867.4, nren = 1175.8, tot = 2043.2, RER = 0.42")
+        .unwrap();
+    // Con pasos de cálculo no mensuales no se puede aplicar la fórmula B.32
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ5_gasPV.csv",
+            "-l",
+            "PENINSULA",
+            "--factor_coincidencia",
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("requiere un paso de cálculo mensual")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_j5_fps_usuario() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ5_gasPV.csv",
+            "-l",
+            "PENINSULA",
+            "--fps_usuario",
+            "test_data/factores_paso_usuario.csv",
+        ])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 20.0, nren = 190.0, tot = 210.0, RER = 0.10")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_pila_combustible() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_pila_combustible.csv",
+            "-l",
+            "PENINSULA",
+            "--fps_usuario",
+            "test_data/factores_paso_hidrogeno.csv",
+        ])
+        .stdout()
+        .contains(
+            "Factores de electricidad cogenerada (pila de combustible): HIDROGENO, 0.50, 0.35",
+        )
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 0.0, nren = 180.0, tot = 180.0, RER = 0.00")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_pila_combustible_sin_factores_hidrogeno() {
+    // Los factores reglamentarios no definen el HIDROGENO y debe indicarlos el usuario
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_pila_combustible.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .fails()
+        .stderr()
+        .contains("HIDROGENO")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_exportacion_calor() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_exportacion_calor.csv",
+            "-l",
+            "PENINSULA",
+            "--kexp",
+            "1.0",
+        ])
+        .stdout()
+        .contains("RED1: paso A: ren 50.00, nren 0.00, co2 0.00; recursos evitados: ren -50.00, nren 65.00, co2 15.00")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 154.1, nren = -45.5, tot = 108.7, RER = 1.42")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_ajustes() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_ajustes.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("** Ajustes de consumo [kWh/m2.an]:")
+        .stdout()
+        .contains("GASNATURAL, EPB, ACS: -0.90 (Deducción estimada del consumo de cocina)")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 4.8, nren = 31.9, tot = 36.7, RER = 0.13")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_desconocidos() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_desconocidos.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("ERROR [E201]")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_desconocidos.csv",
+            "-l",
+            "PENINSULA",
+            "--admite_desconocidos",
+        ])
+        .stdout()
+        .contains("AVISO [W206]: componente con vector o servicio desconocido AMONIACO")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 0.5, nren = 2.3, tot = 2.8, RER = 0.17")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_mapa_servicios() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_mapa_servicios.csv",
+            "-l",
+            "PENINSULA",
+            "--mapa_servicios",
+            "test_data/mapa_servicios.csv",
+        ])
+        .stdout()
+        .contains("REF: ren 0.10, nren 0.47, co2: 0.08")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 0.6, nren = 3.5, tot = 4.1, RER = 0.15")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_periodo_compensacion() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ9_electr.csv",
+            "-l",
+            "PENINSULA",
+            "--periodo_compensacion",
+            "ANUAL",
+        ])
+        .stdout()
+        .contains("Periodo de compensación de la energía producida: ANUAL")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 1220.0, nren = 0.0, tot = 1220.0, RER = 1.00")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_compensacion_servicios() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ9_electr.csv",
+            "-l",
+            "PENINSULA",
+            "--compensacion_servicios",
+        ])
+        .stdout()
+        .contains("Compensación de la energía producida: por servicio")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_edificio_referencia() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_referencia.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains(
+            "Cociente calculado / referencia: C_ep_nren = 0.50, C_ep_tot = 0.71, E_CO2 = 0.50",
+        )
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_sensibilidad_kexp() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ3_basePVexcess.csv",
+            "-l",
+            "PENINSULA",
+            "--sensibilidad_kexp",
+            "0.25",
+        ])
+        .stdout()
+        .contains("** Sensibilidad al factor de exportación:")
+        .stdout()
+        .contains(
+            "k_exp = 1.00: C_ep [kWh/m2.an]: ren = 123.4, nren = -78.2, tot = 45.3, RER = 2.73",
+        )
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ3_basePVexcess.csv",
+            "-l",
+            "PENINSULA",
+            "--sensibilidad_kexp",
+            "0",
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("ERROR [E508]")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_punto_y_coma() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_punto_y_coma.csv",
+            "-f",
+            "test_data/factores_paso_test_punto_y_coma.csv",
+            "--separador",
+            ";",
+            "--separador_decimal",
+            ",",
+        ])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 75.0, nren = 100.0, tot = 175.0, RER = 0.43")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_punto_y_coma.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .fails()
+        .unwrap();
+}
+
+#[test]
+fn validate_punto_y_coma() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "validate",
+            "-c",
+            "test_data/ejemplo_punto_y_coma.csv",
+            "-f",
+            "test_data/factores_paso_test_punto_y_coma.csv",
+            "--separador",
+            ";",
+            "--separador_decimal",
+            ",",
+        ])
+        .succeeds()
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "validate",
+            "-c",
+            "test_data/ejemplo_punto_y_coma.csv",
+            "--separador",
+            ",",
+            "--separador_decimal",
+            ",",
+        ])
+        .fails()
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_viviendas() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_viviendas.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("** Indicadores por vivienda (estimación por cuotas):")
+        .stdout()
+        .contains("Vivienda 2: cuota 0.300, A_ref 120.00 m2, consumo EPB 2820.0 kWh/an, C_ep ren 207.1, nren 3350.5 kWh/an, E_CO2 694.6 kg_CO2e/an")
+        .unwrap();
+}
+
+#[test]
+fn localizacion_metadatos() {
+    // Localización a partir de la zona climática
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_zona_climatica.csv"])
+        .stdout()
+        .contains("Factores de paso (metadatos): CANARIAS")
+        .unwrap();
+    // Aviso de localización de usuario distinta a la de los componentes
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_zona_climatica.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .stdout()
+        .contains("AVISO [W106]")
+        .stdout()
+        .contains("AVISO [W107]")
+        .stdout()
+        .contains("Factores de paso (usuario): PENINSULA")
+        .unwrap();
+    // Zona climática incorrecta
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_zona_climatica_incorrecta.csv"])
+        .fails()
+        .and()
+        .stderr()
+        .contains("Zona climática: Z9")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_archivo_unico() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_archivo_unico.csv"])
+        .stdout()
+        .contains("Factores de paso (componentes): test_data/ejemplo_archivo_unico.csv")
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 6.0, nren = 34.7, tot = 40.8, RER = 0.15")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_vector_usuario() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_vector_usuario.csv"])
+        .stdout()
+        .contains("USUARIO_CALORRESIDUAL: suministrada red 24.00")
+        .unwrap();
+    // Sin factores de paso para el vector de usuario
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_vector_usuario.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("Factor de paso no encontrado: USUARIO_CALORRESIDUAL")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_redes_distrito() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemplo_redes_distrito.csv",
+            "--redn",
+            "4",
+            "1",
+            "0",
+            "0",
+        ])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 22.2, nren = 17.9, tot = 40.1, RER = 0.55")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_recarga_ve() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_recarga_ve.csv"])
+        .stdout()
+        .contains(
+            "AVISO [W306]: consumo de recarga de vehículos eléctricos (RECARGAVE) considerado NEPB",
+        )
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 1.2, nren = 5.9, tot = 7.1, RER = 0.17")
+        .unwrap();
+}
+
+#[test]
+fn ejemplo_calibracion() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_calibracion.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("Calibración de consumos: ELECTRICIDAD x 1.080")
+        .stdout()
+        .contains("ELECTRICIDAD: 756.00, 540.00, 324.00 (total: 1620.00)")
+        .stdout()
+        .contains("ELECTRICIDAD: factor 1.080, consumo calibrado 16.20 (sin calibrar: 15.00)")
+        .unwrap();
+}
+
+#[test]
+fn init_asistente() {
+    let dir = std::env::temp_dir().join(format!("cteepbd_init_{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let dirstr = dir.to_str().unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["init", dirstr])
+        .stdin("CANARIAS\n120\n\nCAL, ILU\nELECTRICIDAD, GASNATURAL\n")
+        .stdout()
+        .contains("Archivo de componentes:")
+        .unwrap();
+    let components = std::fs::read_to_string(dir.join("componentes.csv")).unwrap();
+    assert!(components.contains("#META CTE_LOCALIZACION: CANARIAS"));
+    assert!(components.contains("#META CTE_AREAREF: 120.00"));
+    assert!(components.contains("GASNATURAL, CONSUMO, EPB, ILU, 0.00"));
+    let factors = std::fs::read_to_string(dir.join("factores_usuario.csv")).unwrap();
+    assert!(factors.contains("# GASNATURAL, RED, SUMINISTRO, A"));
+    // Los archivos generados permiten el cálculo
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            dir.join("componentes.csv").to_str().unwrap(),
+            "--fps_usuario",
+            dir.join("factores_usuario.csv").to_str().unwrap(),
+        ])
+        .stdout()
+        .contains("Factores de paso (metadatos): CANARIAS")
+        .unwrap();
+    // No se sobrescriben archivos existentes
+    assert_cli::Assert::main_binary()
+        .with_args(&["init", dirstr])
+        .fails()
+        .and()
+        .stderr()
+        .contains("ERROR [E404]")
+        .unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn cache_resultados() {
+    let dir = std::env::temp_dir().join(format!("cteepbd_cache_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let dirstr = dir.to_str().unwrap();
+    let args = [
+        "-c",
+        "test_data/ejemplo_sistemas.csv",
+        "-l",
+        "PENINSULA",
+        "--cache",
+        dirstr,
+    ];
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .stdout()
+        .doesnt_contain("Resultados recuperados de la caché")
+        .unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    // La segunda ejecución reutiliza los resultados guardados
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .stdout()
+        .contains("Resultados recuperados de la caché")
+        .unwrap();
+    // Otro nivel de detalle u otro idioma no reutilizan los resultados guardados
+    let mut args_detail = args.to_vec();
+    args_detail.extend(&["--detalle", "completo"]);
+    assert_cli::Assert::main_binary()
+        .with_args(&args_detail)
+        .stdout()
+        .doesnt_contain("Resultados recuperados de la caché")
+        .unwrap();
+    let mut args_lang = args.to_vec();
+    args_lang.extend(&["--idioma", "en"]);
+    assert_cli::Assert::main_binary()
+        .with_args(&args_lang)
+        .stdout()
+        .doesnt_contain("Resultados recuperados de la caché")
+        .unwrap();
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn directorio_salida() {
+    let dir = std::env::temp_dir().join(format!("cteepbd_salida_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "-l",
+            "PENINSULA",
+            "-k",
+            "1.0",
+            "--directorio_salida",
+            dir.to_str().unwrap(),
+        ])
+        .stdout()
+        .contains("Resultados en todos los formatos:")
+        .unwrap();
+    for name in &[
+        "balance.txt",
+        "balance.xml",
+        "balance.json",
+        "balance.csv",
+        "avisos.txt",
+    ] {
+        assert!(dir.join(name).exists(), "falta {}", name);
+    }
+    let plain = std::fs::read_to_string(dir.join("balance.txt")).unwrap();
+    assert!(plain.contains("C_ep [kWh/m2.an]"));
+    let warnings = std::fs::read_to_string(dir.join("avisos.txt")).unwrap();
+    assert!(warnings.contains("AVISO [W202]"));
+    assert!(warnings.contains("AVISO [W101]"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn salida_xml_detalle() {
+    // La salida XML básica no incluye los bloques de resultados detallados, salvo con el
+    // nivel de detalle completo
+    let path = std::env::temp_dir().join(format!("cteepbd_xml_{}.xml", std::process::id()));
+    let args = [
+        "-c",
+        "test_data/ejemploJ1_base.csv",
+        "-l",
+        "PENINSULA",
+        "--xml",
+        path.to_str().unwrap(),
+    ];
+    assert_cli::Assert::main_binary().with_args(&args).unwrap();
+    let basic = std::fs::read_to_string(&path).unwrap();
+    assert!(basic.contains("<Epm2>") && !basic.contains("<MagnitudesISO>"));
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .with_args(&["--detalle", "completo"])
+        .unwrap();
+    let full = std::fs::read_to_string(&path).unwrap();
+    assert!(full.contains("<MagnitudesISO>"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn subcomando_balance() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "balance",
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]: ren = 41.4, nren = 195.4, tot = 236.8, RER = 0.17")
+        .unwrap();
+}
+
+#[test]
+fn subcomando_validate() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "validate",
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "-f",
+            "test_data/factores_paso_test.csv",
+        ])
+        .stdout()
+        .contains("Datos de entrada correctos (1 avisos)")
+        .and()
+        .stdout()
+        .doesnt_contain("C_ep")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["validate", "-f", "test_data/ejemploJ1_base.csv"])
+        .fails()
+        .unwrap();
+    // Se muestran todos los problemas, con su número de línea
+    let path = std::env::temp_dir().join(format!("cteepbd_validate_{}.csv", std::process::id()));
+    std::fs::write(
+        &path,
+        "ELECTRICIDAD, CONSUMO, EPB, CAL, 1.0\nXXX, CONSUMO, EPB, CAL, 1.0\nGASNATURAL, CONSUMO, EPB, CAL, 1.0, 2.0\n",
+    )
+    .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["validate", "-c", path.to_str().unwrap()])
+        .fails()
+        .and()
+        .stderr()
+        .contains("línea 2: No se ha podido interpretar XXX")
+        .and()
+        .stderr()
+        .contains("ERROR [E301]")
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn subcomando_convert() {
+    let path = std::env::temp_dir().join(format!("cteepbd_convert_{}.json", std::process::id()));
+    let pathstr = path.to_str().unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "convert",
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "--formato",
+            "json",
+            "-o",
+            pathstr,
+        ])
+        .unwrap();
+    assert!(std::fs::read_to_string(&path)
+        .unwrap()
+        .contains("\"cdata\""));
+    // Conversión de vuelta al formato de texto
+    assert_cli::Assert::main_binary()
+        .with_args(&["convert", "-c", pathstr])
+        .stdout()
+        .contains("ELECTRICIDAD, CONSUMO, EPB, NDEF, 100.00")
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn subcomando_convert_canonico() {
+    let path = std::env::temp_dir().join(format!("cteepbd_canonico_{}.csv", std::process::id()));
+    let pathstr = path.to_str().unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "convert",
+            "-c",
+            "test_data/ejemplo_sistemas.csv",
+            "--decimales",
+            "3",
+            "-o",
+            pathstr,
+        ])
+        .unwrap();
+    let canonical = std::fs::read_to_string(&path).unwrap();
+    // La representación canónica no cambia al volver a generarla
+    assert_cli::Assert::main_binary()
+        .with_args(&["convert", "-c", pathstr, "--decimales", "3"])
+        .stdout()
+        .is(canonical.trim_end())
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "convert",
+            "-f",
+            "test_data/factores_paso_test.csv",
+            "--decimales",
+            "x",
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("ERROR [E509]")
+        .unwrap();
+}
+
+#[test]
+fn subcomando_factors() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["factors", "-l", "CANARIAS", "--red1", "0", "1.3", "0.3"])
+        .stdout()
+        .contains("#META CTE_LOCALIZACION: CANARIAS")
+        .and()
+        .stdout()
+        .contains("RED1, RED, SUMINISTRO, A, 0.000, 1.300, 0.300")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "factors",
+            "-l",
+            "PENINSULA",
+            "--residuos",
+            "0.1",
+            "0.5",
+            "0.1",
+        ])
+        .stdout()
+        .contains("RESIDUOS, RED, SUMINISTRO, A, 0.100, 0.500, 0.100")
+        .unwrap();
+}
+
+#[test]
+fn subcomando_factors_zona_climatica() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["factors", "-l", "CANARIAS", "--zona_climatica", "α3"])
+        .stdout()
+        .contains("#META CTE_ZONACLIMATICA: α3")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["factors", "-l", "PENINSULA", "--zona_climatica", "α3"])
+        .fails()
+        .and()
+        .stderr()
+        .contains("no es coherente con la zona climática")
+        .unwrap();
+}
+
+#[test]
+fn formato_salida() {
+    let path = std::env::temp_dir().join(format!("cteepbd_formato_{}.md", std::process::id()));
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "-l",
+            "PENINSULA",
+            "--formato",
+            "markdown",
+            "-o",
+            path.to_str().unwrap(),
+        ])
+        .stdout()
+        .contains("| C_ep,tot [kWh/m2.an] | 236.8 |")
+        .and()
+        .stdout()
+        .doesnt_contain("C_ep [kWh/m2.an]: ren")
+        .unwrap();
+    let md = std::fs::read_to_string(&path).unwrap();
+    assert!(md.starts_with("# Balance energético"));
+    std::fs::remove_file(&path).unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ1_base.csv",
+            "-l",
+            "PENINSULA",
+            "--format",
+            "json",
+        ])
+        .stdout()
+        .contains("\"indicadores\"")
+        .unwrap();
+}
+
+#[test]
+fn subcomando_batch() {
+    let dir = std::env::temp_dir().join(format!("cteepbd_batch_{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    for name in &["ejemploJ1_base.csv", "cte_test_carriers.csv"] {
+        std::fs::copy(Path::new("test_data").join(name), dir.join(name)).unwrap();
+    }
+    let args = ["batch", dir.to_str().unwrap(), "-l", "PENINSULA"];
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .stdout()
+        .contains("Archivos procesados: 2 (0 con errores)")
+        .unwrap();
+    assert!(dir.join("ejemploJ1_base_balance.txt").exists());
+    let summary = std::fs::read_to_string(dir.join("resumen.csv")).unwrap();
+    assert!(summary.contains("ejemploJ1_base.csv,OK,1.00,0.00,41.40,195.40,236.80"));
+    // El resumen no se procesa como archivo de componentes y los errores no interrumpen el proceso
+    std::fs::write(dir.join("incorrecto.csv"), "XXX, 1").unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&args)
+        .fails()
+        .and()
+        .stdout()
+        .contains("Archivos procesados: 3 (1 con errores)")
+        .unwrap();
+    let summary = std::fs::read_to_string(dir.join("resumen.csv")).unwrap();
+    assert!(summary.contains("incorrecto.csv,E201"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn registro_eventos_detalle() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ2_basePV.csv",
+            "-l",
+            "PENINSULA",
+            "-vv",
+        ])
+        .stderr()
+        .contains("DEBUG [cteepbd::balance]: Balance de ELECTRICIDAD")
+        .stderr()
+        .contains("INFO [cteepbd::wfactors]: Factores de paso no usados eliminados")
+        .unwrap();
+    // Sin la opción -v no se muestran los eventos
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemploJ2_basePV.csv", "-l", "PENINSULA"])
+        .stderr()
+        .doesnt_contain("INFO [cteepbd::balance]")
+        .unwrap();
+}
+
+#[test]
+fn componentes_distinto_numero_valores() {
+    let path = std::env::temp_dir().join(format!("cteepbd_valores_{}.csv", std::process::id()));
+    std::fs::write(
+        &path,
+        "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0, 20.0\nGASNATURAL, CONSUMO, EPB, ACS, 100.0\n",
+    )
+    .unwrap();
+    let pathstr = path.to_str().unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", pathstr, "-l", "PENINSULA"])
+        .fails()
+        .and()
+        .stderr()
+        .contains("línea 2 (1 valores)")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", pathstr, "-l", "PENINSULA", "--rellena_valores"])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]")
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn componentes_valores_negativos() {
+    let path = std::env::temp_dir().join(format!("cteepbd_negativos_{}.csv", std::process::id()));
+    std::fs::write(
+        &path,
+        "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0, -20.0\nGASNATURAL, CONSUMO, EPB, ACS, 100.0, 0.0\n",
+    )
+    .unwrap();
+    let pathstr = path.to_str().unwrap();
+    // Por defecto se admiten, como en versiones anteriores
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", pathstr, "-l", "PENINSULA"])
+        .stdout()
+        .doesnt_contain("AVISO [W308]")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            pathstr,
+            "-l",
+            "PENINSULA",
+            "--valores_negativos",
+            "rechaza",
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("valores negativos (línea 1, 1 valores)")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", pathstr, "-l", "PENINSULA", "--valores_negativos", "anula"])
+        .stdout()
+        .contains("AVISO [W308]: 1 valores negativos sustituidos por cero en el componente ELECTRICIDAD, CONSUMO, EPB, CAL")
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn componentes_desde_necesidades() {
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/ejemplo_necesidades.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("ELECTRICIDAD: 150.00, 100.00 (total: 250.00)")
+        .and()
+        .stdout()
+        .contains("GASNATURAL: 77.78, 77.78 (total: 155.56)")
+        .and()
+        .stdout()
+        .doesnt_contain("AVISO [W302]")
+        .unwrap();
+}
+
+#[test]
+fn componentes_paso_calculo() {
+    // Agregación de componentes horarios a pasos de cálculo mensuales y anuales
+    let path = std::env::temp_dir().join(format!("cteepbd_horario_{}.csv", std::process::id()));
+    let values = vec!["1.0"; 8760].join(", ");
+    std::fs::write(
+        &path,
+        format!(
+            "#META CTE_PASO: HORARIO\n#META CTE_AREAREF: 1.0\nELECTRICIDAD, CONSUMO, EPB, ILU, {}\n",
+            values
+        ),
+    )
+    .unwrap();
+    let path_str = path.to_str().unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            path_str,
+            "-l",
+            "PENINSULA",
+            "--paso_calculo",
+            "MENSUAL",
+        ])
+        .stdout()
+        .contains("Paso de cálculo: MENSUAL")
+        .and()
+        .stdout()
+        .contains("ELECTRICIDAD: 744.00, 672.00, 744.00, 720.00")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", path_str, "-l", "PENINSULA", "--paso_calculo", "ANUAL"])
+        .stdout()
+        .contains("ELECTRICIDAD: 8760.00 (total: 8760.00)")
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    // No se pueden obtener pasos de cálculo más cortos
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/cte_test_carriers.csv",
+            "-l",
+            "PENINSULA",
+            "--paso_calculo",
+            "HORARIO",
+        ])
+        .fails()
+        .and()
+        .stderr()
+        .contains("paso de cálculo incorrecto")
+        .unwrap();
+}
+
+#[test]
+fn idioma_salida() {
+    // Informe de resultados, avisos y comentarios generados en inglés
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "--idioma",
+            "en",
+            "-c",
+            "test_data/cte_test_carriers.csv",
+            "-l",
+            "PENINSULA",
+        ])
+        .stdout()
+        .contains("** Delivered energy by carrier and timestep [kWh]:")
+        .and()
+        .stdout()
+        .doesnt_contain("** Energía suministrada por vector")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&["-c", "test_data/cte_test_carriers.csv", "-l", "PENINSULA"])
+        .stdout()
+        .contains("** Energía suministrada por vector y paso de cálculo [kWh]:")
+        .unwrap();
+}
+
+#[test]
+fn detalle_salida() {
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ3_basePVexcess.csv",
+            "-l",
+            "PENINSULA",
+            "--detalle",
+            "completo",
+        ])
+        .stdout()
+        .contains("** Energía ponderada y emisiones (paso A)")
+        .and()
+        .stdout()
+        .contains("** Energía exportada por vector y paso de cálculo [kWh]:")
+        .unwrap();
+    assert_cli::Assert::main_binary()
+        .with_args(&[
+            "-c",
+            "test_data/ejemploJ3_basePVexcess.csv",
+            "-l",
+            "PENINSULA",
+            "--detalle",
+            "resumen",
+        ])
+        .stdout()
+        .contains("C_ep [kWh/m2.an]")
+        .and()
+        .stdout()
+        .doesnt_contain("** Energía final")
+        .unwrap();
+}
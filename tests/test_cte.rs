@@ -1,9 +1,6 @@
 #![allow(non_snake_case)]
 
 use std::collections::HashMap;
-use std::fs::File;
-use std::io::prelude::*;
-use std::path::Path;
 
 use pretty_assertions::assert_eq;
 
@@ -64,12 +61,13 @@ ELECTRICIDAD, COGENERACION, A_RED, B, 0.5, 2.0, 0.42
 ELECTRICIDAD, COGENERACION, A_NEPB, B, 0.5, 2.0, 0.42
 ";
 
-const TESTKEXP: f32 = 1.0;
+const TESTKEXP: Real = 1.0;
 
 fn get_ctefp_peninsula() -> Factors {
     let user_wf = UserWF {
         red1: None,
         red2: None,
+        residuos: None,
         cogen_to_grid: None,
         cogen_to_nepb: None,
     };
@@ -85,8 +83,10 @@ fn get_energydatalist() -> Components {
     //3 PV BdC_normativo
     Components {
         cmeta: vec![],
+        cunknown: vec![],
         cdata: vec![
             Component {
+                id: 0,
                 values: vec![
                     9.67, 7.74, 4.84, 4.35, 2.42, 2.9, 3.87, 3.39, 2.42, 3.87, 5.8, 7.74,
                 ],
@@ -94,9 +94,11 @@ fn get_energydatalist() -> Components {
                 ctype: CONSUMO,
                 csubtype: EPB,
                 service: NDEF,
+                aux: false,
                 comment: "".into(),
             },
             Component {
+                id: 0,
                 values: vec![
                     1.13, 1.42, 1.99, 2.84, 4.82, 5.39, 5.67, 5.11, 4.54, 3.40, 2.27, 1.42,
                 ],
@@ -104,9 +106,11 @@ fn get_energydatalist() -> Components {
                 ctype: PRODUCCION,
                 csubtype: INSITU,
                 service: NDEF,
+                aux: false,
                 comment: "".into(),
             },
             Component {
+                id: 0,
                 values: vec![
                     21.48, 17.18, 10.74, 9.66, 5.37, 6.44, 8.59, 7.52, 5.37, 8.59, 12.89, 17.18,
                 ],
@@ -114,9 +118,11 @@ fn get_energydatalist() -> Components {
                 ctype: CONSUMO,
                 csubtype: EPB,
                 service: NDEF,
+                aux: false,
                 comment: "".into(),
             },
             Component {
+                id: 0,
                 values: vec![
                     21.48, 17.18, 10.74, 9.66, 5.37, 6.44, 8.59, 7.52, 5.37, 8.59, 12.89, 17.18,
                 ],
@@ -124,6 +130,7 @@ fn get_energydatalist() -> Components {
                 ctype: PRODUCCION,
                 csubtype: INSITU,
                 service: NDEF,
+                aux: false,
                 comment: "".into(),
             },
         ],
@@ -131,21 +138,16 @@ fn get_energydatalist() -> Components {
 }
 
 fn components_from_file(path: &str) -> Components {
-    let path = Path::new(path);
-    let mut f = File::open(path).unwrap();
-    let mut componentsstring = String::new();
-    f.read_to_string(&mut componentsstring).unwrap();
+    let componentsstring = std::fs::read_to_string(path).unwrap();
     componentsstring.parse::<Components>().unwrap().normalize()
 }
 
 fn wfactors_from_file(path: &str) -> Factors {
-    let path = Path::new(path);
-    let mut f = File::open(path).unwrap();
-    let mut wfactors_string = String::new();
-    f.read_to_string(&mut wfactors_string).unwrap();
+    let wfactors_string = std::fs::read_to_string(path).unwrap();
     let user_wf = UserWF {
         red1: None,
         red2: None,
+        residuos: None,
         cogen_to_grid: None,
         cogen_to_nepb: None,
     };
@@ -184,6 +186,1544 @@ fn cte_balance_from_data() {
     ));
 }
 
+#[test]
+fn cte_balance_misc_indicators() {
+    let ENERGYDATALIST = get_energydatalist();
+    let FP = get_ctefp_peninsula();
+    let mut bal = energy_performance(&ENERGYDATALIST, &FP, TESTKEXP, 1.0).unwrap();
+    bal.misc.insert("rer_nrb".to_string(), 0.5.into());
+    bal.misc.insert("nota".to_string(), "A & B".into());
+    let plain = balance_to_plain(&bal);
+    assert!(plain.contains("rer_nrb: 0.500\nnota: A & B\n"));
+    let xml = balance_to_xml_with_detail(&bal, XmlDetail::Full).unwrap();
+    assert!(xml.contains("<Indicador><Clave>nota</Clave><Valor>A &amp; B</Valor></Indicador>"));
+    let json = serde_json::to_string(&bal).unwrap();
+    assert!(json.contains(r#""misc":{"rer_nrb":0.5,"nota":"A & B"}"#));
+}
+
+#[test]
+fn cte_balance_delivered_by_carrier() {
+    let comps = components_from_file("test_data/ejemploJ1_base.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let plain = balance_to_plain(&bal);
+    assert!(plain.contains(
+        "** Energía suministrada por vector y paso de cálculo [kWh]:
+ELECTRICIDAD: 100.00 (total: 100.00)"
+    ));
+    let xml = balance_to_xml_with_detail(&bal, XmlDetail::Full).unwrap();
+    assert!(xml.contains(
+        "<Dato><Vector>ELECTRICIDAD</Vector><Valores>100.00</Valores><Total>100.00</Total></Dato>"
+    ));
+}
+
+#[test]
+fn cte_balance_input_from_xml() {
+    let mut comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    comps.cdata[0].comment = "Consumo de 'bomba' & <auxiliares>".to_string();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 0.5, 150.0).unwrap();
+    let xml = balance_to_xml(&bal).unwrap();
+    // Recupera los datos de entrada y recalcula el balance
+    let input = balance_input_from_xml(&xml).unwrap();
+    assert_eq!(input.k_exp, 0.5);
+    assert_eq!(input.arearef, 150.0);
+    assert_eq!(input.components.cdata.len(), comps.cdata.len());
+    assert_eq!(input.components.cdata[0].comment, comps.cdata[0].comment);
+    assert_eq!(input.wfactors.wdata.len(), FP.wdata.len());
+    assert_eq!(input.wfactors.to_string(), FP.to_string());
+    let bal2 = energy_performance(
+        &input.components,
+        &input.wfactors,
+        input.k_exp,
+        input.arearef,
+    )
+    .unwrap();
+    assert!(approx_equal(bal.balance_m2.B, bal2.balance_m2.B));
+    // Factor de exportación a usos no EPB distinto del de la red
+    let comps_nepb = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0
+ELECTRICIDAD, CONSUMO, NEPB, NDEF, 50.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 100.0"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance_by_dest(&comps_nepb, &FP, 0.5, 0.2, 150.0).unwrap();
+    let bal_kexp = energy_performance(&comps_nepb, &FP, 0.5, 150.0).unwrap();
+    assert!(!approx_equal(bal.balance_m2.B, bal_kexp.balance_m2.B));
+    let xml = balance_to_xml(&bal).unwrap();
+    assert!(xml.contains("<kexp_nepb>0.20</kexp_nepb>"));
+    let input = balance_input_from_xml(&xml).unwrap();
+    assert_eq!((input.k_exp, input.k_exp_nepb), (0.5, 0.2));
+    let bal2 = energy_performance_by_dest(
+        &input.components,
+        &input.wfactors,
+        input.k_exp,
+        input.k_exp_nepb,
+        input.arearef,
+    )
+    .unwrap();
+    assert!(approx_equal(bal.balance_m2.B, bal2.balance_m2.B));
+    // Sin factor de exportación a usos no EPB se usa el de la red
+    let input = balance_input_from_xml(&xml.replace("<kexp_nepb>0.20</kexp_nepb>", "")).unwrap();
+    assert_eq!(input.k_exp_nepb, 0.5);
+    // Documentos sin datos de entrada
+    assert!(balance_input_from_xml("<BalanceEPB></BalanceEPB>").is_err());
+    // Tratamiento de los valores negativos de los componentes
+    comps.cdata[0].values[0] = -1.0;
+    let bal = energy_performance(&comps, &FP, 0.5, 150.0).unwrap();
+    let xml = balance_to_xml(&bal).unwrap();
+    assert!(balance_input_from_xml(&xml).is_ok());
+    let options = |negative_values| ParseOptions {
+        negative_values,
+        ..Default::default()
+    };
+    assert!(balance_input_from_xml_with_options(&xml, &options(NegativeValues::Reject)).is_err());
+    let input = balance_input_from_xml_with_options(&xml, &options(NegativeValues::Clamp)).unwrap();
+    assert_eq!(input.components.cdata[0].values[0], 0.0);
+}
+
+#[test]
+fn cte_balance_exported_avoided() {
+    let comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    // El paso B descuenta del paso A los recursos evitados a la red
+    assert!(approx_equal(
+        bal.balance_m2.A - bal.balance_m2.B,
+        bal.balance_m2.we_exp_avoided
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(-20.0, 80.0, 16.8),
+        bal.balance_m2.we_exp_avoided
+    ));
+    let plain = balance_to_plain(&bal);
+    assert!(plain.contains("TOTAL recursos evitados: ren -20.00, nren 80.00, co2 16.80"));
+    let xml = balance_to_xml_with_detail(&bal, XmlDetail::Full).unwrap();
+    assert!(xml.contains(
+        "<RecursosEvitados><ren>-20.00</ren><nren>80.00</nren><co2>16.80</co2></RecursosEvitados>"
+    ));
+    // Sin exportación (k_exp = 0) no hay recursos evitados
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::default(),
+        bal.balance_m2.we_exp_avoided
+    ));
+}
+
+#[test]
+fn cte_balance_load_matching() {
+    let comps = "#META CTE_AREAREF: 1.0
+#META CTE_PASO: MENSUAL
+ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0, 100.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 100.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    // Factor unidad: toda la producción coincidente se aprovecha
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert_eq!(el.f_match, vec![1.0; 12]);
+    assert!((100.0 - el.delivered_grid_an).abs() < 0.01);
+    // Fórmula B.32: con x = 1, f_match = 2 - 2^(1/2)
+    let bal = energy_performance_with_load_matching(&comps, &FP, 1.0, 1.0, 1.0, LoadMatching::B32)
+        .unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((2.0 - Real::sqrt(2.0) - el.f_match[0]).abs() < 0.01);
+    assert!((1.0 - el.f_match[1]).abs() < 0.01);
+    assert!((141.421 - el.delivered_grid_an).abs() < 0.01);
+    assert!((41.421 - el.exported_an).abs() < 0.01);
+    // Las anualidades mantienen el método de cálculo
+    assert_eq!(bal.load_matching, LoadMatching::B32);
+    // La fórmula B.32 solo es aplicable a pasos de cálculo mensuales
+    let annual = "ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 100.0"
+        .parse::<Components>()
+        .unwrap();
+    assert!(
+        energy_performance_with_load_matching(&annual, &FP, 1.0, 1.0, 1.0, LoadMatching::B32)
+            .is_err()
+    );
+    assert!(
+        energy_performance_with_load_matching(&annual, &FP, 1.0, 1.0, 1.0, LoadMatching::Unit)
+            .is_ok()
+    );
+}
+
+#[test]
+fn cte_balance_production_priority() {
+    let comps = "#META CTE_AREAREF: 1.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 100.0
+ELECTRICIDAD, PRODUCCION, COGENERACION, NDEF, 100.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    // Sin prioridad: reparto proporcional a la producción de cada origen
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.produced_used_EPus_bygen[&CSubtype::INSITU][0] - 50.0).abs() < 0.01);
+    assert!((el.exported_bygen_an[&CSubtype::COGENERACION] - 50.0).abs() < 0.01);
+    let no_priority = el.we_exported_an_A;
+    // Prioridad de la producción in situ frente a la cogeneración
+    let mut comps = comps;
+    comps.set_meta("CTE_PRIORIDAD_PRODUCCION", "INSITU, COGENERACION");
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.produced_used_EPus_bygen[&CSubtype::INSITU][0] - 100.0).abs() < 0.01);
+    assert!((el.produced_used_EPus_bygen[&CSubtype::COGENERACION][0]).abs() < 0.01);
+    assert!((el.exported_bygen_an[&CSubtype::INSITU]).abs() < 0.01);
+    assert!((el.exported_bygen_an[&CSubtype::COGENERACION] - 100.0).abs() < 0.01);
+    assert!((el.exported_an - 100.0).abs() < 0.01);
+    assert!((el.we_exported_an_A.nren - no_priority.nren).abs() > 0.01);
+    // Orígenes incorrectos
+    comps.set_meta("CTE_PRIORIDAD_PRODUCCION", "INSITU, RED");
+    assert!(energy_performance(&comps, &FP, 1.0, 1.0).is_err());
+}
+
+#[test]
+fn cte_balance_battery() {
+    let comps = "#META CTE_AREAREF: 1.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 0.0, 50.0, 50.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 60.0, 0.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    // Sin batería la producción se exporta
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.delivered_grid_an - 100.0).abs() < 0.01);
+    assert!((el.exported_an - 60.0).abs() < 0.01);
+    assert!(el.stored.is_empty());
+    // Con batería la producción almacenada cubre consumos posteriores
+    let mut comps = comps;
+    comps.set_meta("CTE_BATERIA", "40.0, 0.5");
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert_eq!(el.stored, vec![60.0, 0.0, 0.0]);
+    assert_eq!(el.stored_used, vec![0.0, 30.0, 0.0]);
+    assert_eq!(el.delivered_grid, vec![0.0, 20.0, 50.0]);
+    assert!(el.exported_an.abs() < 0.01);
+    assert!((el.produced_used_EPus_bygen[&CSubtype::INSITU][1] - 30.0).abs() < 0.01);
+    assert!(balance_to_plain(&bal).contains("cargada 60.00, descargada 30.00, pérdidas 30.00"));
+    // La energía usada y exportada por origen suma la total en cada paso, también con varios orígenes
+    let comps_gen = "#META CTE_AREAREF: 1.0
+#META CTE_BATERIA: 40.0, 0.5
+ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0, 50.0, 50.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 60.0, 0.0, 0.0
+ELECTRICIDAD, PRODUCCION, COGENERACION, NDEF, 30.0, 0.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(
+        &comps_gen,
+        &FP.clone().normalize(&CTE_USERWF).unwrap(),
+        1.0,
+        1.0,
+    )
+    .unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    for t in 0..3 {
+        let used: Real = el.produced_used_EPus_bygen.values().map(|v| v[t]).sum();
+        assert!((used - el.produced_used_EPus[t]).abs() < 0.01);
+        let exported: Real = el.exported_bygen.values().map(|v| v[t]).sum();
+        assert!((exported - el.exported[t]).abs() < 0.01);
+    }
+    assert!((el.produced_used_EPus[1] - 40.0).abs() < 0.01);
+    assert!((el.produced_used_EPus_bygen[&CSubtype::INSITU][1] - 26.67).abs() < 0.01);
+    // Declaración incorrecta de la batería
+    comps.set_meta("CTE_BATERIA", "40.0, 1.5");
+    assert!(energy_performance(&comps, &FP, 1.0, 1.0).is_err());
+}
+
+#[test]
+fn cte_balance_thermal_storage() {
+    // Energía solar térmica con depósito de acumulación estacional
+    let comps = "#META CTE_AREAREF: 1.0
+#META CTE_ALMACENAMIENTO_TERMICO: MEDIOAMBIENTE, 100.0, 0.8
+MEDIOAMBIENTE, CONSUMO, EPB, ACS, 10.0, 50.0
+MEDIOAMBIENTE, PRODUCCION, INSITU, ACS, 60.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let ma = bal.carrier(Carrier::MEDIOAMBIENTE).unwrap();
+    assert_eq!(ma.stored, vec![50.0, 0.0]);
+    assert_eq!(ma.stored_used, vec![0.0, 40.0]);
+    assert_eq!(ma.delivered_grid, vec![0.0, 10.0]);
+    assert!(ma.exported_an.abs() < 0.01);
+    assert!(balance_to_plain(&bal).contains("MEDIOAMBIENTE: capacidad 100.00 kWh"));
+    // Solo se admiten vectores de energía térmica, sin repetir
+    let mut comps = comps;
+    comps.set_meta("CTE_ALMACENAMIENTO_TERMICO", "GASNATURAL, 100.0, 0.8");
+    assert!(energy_performance(&comps, &FP, 1.0, 1.0).is_err());
+}
+
+#[test]
+fn cte_balance_time_varying_factors() {
+    let comps = "#META CTE_AREAREF: 1.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0, 50.0"
+        .parse::<Components>()
+        .unwrap();
+    let mut FP = get_ctefp_peninsula();
+    FP.wseries.push(
+        "ELECTRICIDAD, RED, SUMINISTRO, A, SERIE, 0.5 1.0 0.1, 0.0 3.0 0.5"
+            .parse()
+            .unwrap(),
+    );
+    // La serie se conserva en la conversión a texto de los factores
+    let FP: Factors = FP.to_string().parse().unwrap();
+    assert_eq!(FP.wseries.len(), 1);
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(50.0, 250.0, 35.0),
+        bal.balance.B
+    ));
+    // Número de valores distinto al de pasos de cálculo
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0, 50.0, 10.0"
+        .parse::<Components>()
+        .unwrap();
+    assert!(energy_performance(&comps, &FP, 1.0, 1.0).is_err());
+    // También en la serie de la producción in situ
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0, 50.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 10.0, 10.0"
+        .parse::<Components>()
+        .unwrap();
+    let mut FP = get_ctefp_peninsula();
+    FP.wseries.push(
+        "ELECTRICIDAD, INSITU, SUMINISTRO, A, SERIE, 1.0 0.0 0.0, 1.0 0.0 0.0, 1.0 0.0 0.0"
+            .parse()
+            .unwrap(),
+    );
+    assert!(energy_performance(&comps, &FP, 1.0, 1.0).is_err());
+}
+
+#[test]
+fn cte_balance_weighted_timesteps() {
+    let comps = "#META CTE_AREAREF: 1.0
+ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0, 50.0"
+        .parse::<Components>()
+        .unwrap();
+    let mut FP = get_ctefp_peninsula();
+    FP.wseries.push(
+        "ELECTRICIDAD, RED, SUMINISTRO, A, SERIE, 0.5 1.0 0.1, 0.0 3.0 0.5"
+            .parse()
+            .unwrap(),
+    );
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert_eq!(bal_el.we_delivered_t.len(), 2);
+    assert!(approx_equal(
+        RenNrenCo2::new(50.0, 100.0, 10.0),
+        bal_el.we_delivered_t[0]
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 150.0, 25.0),
+        bal_el.we_delivered_t[1]
+    ));
+    assert!(bal_el
+        .we_exported_t
+        .iter()
+        .all(|v| *v == RenNrenCo2::default()));
+
+    // Las series suman los valores anuales, también con energía exportada
+    let comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let sum = |v: &[RenNrenCo2]| v.iter().fold(RenNrenCo2::default(), |acc, x| acc + *x);
+    for bal_cr in bal.balance_cr.values() {
+        assert_eq!(bal_cr.we_exported_t.len(), bal_cr.exported.len());
+        assert!(approx_equal(
+            bal_cr.we_delivered_an,
+            sum(&bal_cr.we_delivered_t)
+        ));
+        assert!(approx_equal(
+            bal_cr.we_exported_an_A,
+            sum(&bal_cr.we_exported_A_t)
+        ));
+        assert!(approx_equal(
+            bal_cr.we_exported_an,
+            sum(&bal_cr.we_exported_t)
+        ));
+    }
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert!(bal_el.we_exported_an_A != RenNrenCo2::default());
+}
+
+#[test]
+fn cte_balance_iso_quantities() {
+    let comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let quantities = bal.iso_quantities_by_carrier();
+    assert_eq!(quantities.len(), bal.balance_cr.len());
+    let (carrier, el) = &quantities[0];
+    assert_eq!(*carrier, Carrier::ELECTRICIDAD);
+    let symbols: Vec<_> = el.iter().map(|q| q.symbol).collect();
+    assert_eq!(
+        symbols,
+        [
+            "E_EPus;cr",
+            "E_pr;cr",
+            "E_pr;cr;used;EPus",
+            "E_exp;cr;nEPus",
+            "E_exp;cr;grid",
+            "E_del;cr"
+        ]
+    );
+    let bal_el = &bal.balance_cr[&Carrier::ELECTRICIDAD];
+    assert_eq!(el[4].values, bal_el.exported_grid.as_slice());
+    assert!((el[4].annual - bal_el.exported_grid_an).abs() < 0.01);
+    assert!((el[5].annual - bal_el.delivered_grid_an).abs() < 0.01);
+    // Salidas XML y JSON
+    let xml = balance_to_xml_with_detail(&bal, XmlDetail::Full).unwrap();
+    assert!(xml.contains("<MagnitudesISO>"));
+    // La salida básica mantiene el formato del certificado, sin bloques de resultados
+    let basic = balance_to_xml(&bal).unwrap();
+    assert!(basic.ends_with("</Epm2>\n</BalanceEPB>"));
+    assert!(!basic.contains("<MagnitudesISO>"));
+    // Solo se escriben los bloques con datos (hay producción eléctrica pero no viviendas)
+    assert!(xml.contains("<Autoconsumo>") && !xml.contains("<Viviendas>"));
+    assert!(xml.contains(&format!(
+        "<Magnitud><Simbolo>E_del;cr</Simbolo><Valores>{}</Valores><Total>{:.2}</Total></Magnitud>",
+        bal_el
+            .delivered_grid
+            .iter()
+            .map(|v| format!("{:.2}", v))
+            .collect::<Vec<_>>()
+            .join(","),
+        bal_el.delivered_grid_an
+    )));
+    let json: serde_json::Value = serde_json::from_str(&balance_to_json(&bal).unwrap()).unwrap();
+    assert_eq!(
+        json["magnitudes_iso"]["ELECTRICIDAD"][4]["symbol"],
+        "E_exp;cr;grid"
+    );
+}
+
+#[test]
+fn cte_balance_compensation_period() {
+    let mut comps = components_from_file("test_data/ejemploJ9_electr.csv");
+    let FP = get_ctefp_peninsula();
+    // Sin compensación de la energía exportada (k_exp = 0)
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    // Datos mensuales: la compensación mensual coincide con la de cada paso de cálculo
+    comps.set_meta("CTE_PERIODO_COMPENSACION", "MENSUAL");
+    let bal_month = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    assert!(approx_equal(bal_month.balance.B, bal.balance.B));
+    // La compensación anual equivale al cálculo con los componentes anuales
+    comps.set_meta("CTE_PERIODO_COMPENSACION", "ANUAL");
+    let bal_year = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let annual = energy_performance(&comps.to_annual().unwrap(), &FP, 0.0, 1.0).unwrap();
+    assert!(approx_equal(bal_year.balance.B, annual.balance.B));
+    // El balance conserva los componentes originales, sin agregar
+    assert_eq!(bal_year.components.cdata[0].values.len(), 12);
+    assert!(bal_year.balance.B.nren < bal.balance.B.nren);
+    // No se admiten factores de paso variables en el tiempo ni periodos desconocidos
+    let mut FP_series = FP.clone();
+    FP_series.wseries.push(
+        "ELECTRICIDAD, RED, SUMINISTRO, A, SERIE, 0.5 1.0 0.1, 0.0 3.0 0.5"
+            .parse()
+            .unwrap(),
+    );
+    assert!(energy_performance(&comps, &FP_series, 0.0, 1.0).is_err());
+    comps.set_meta("CTE_PERIODO_COMPENSACION", "SEMANAL");
+    assert!(energy_performance(&comps, &FP, 0.0, 1.0).is_err());
+}
+
+#[test]
+fn cte_balance_compensation_by_service() {
+    let mut comps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0, 0.0
+ELECTRICIDAD, CONSUMO, EPB, ACS, 0.0, 100.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 50.0, 50.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    // Compensación por vector: toda la producción cubre consumos EPB
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.delivered_grid_an - 100.0).abs() < 0.01);
+    assert!(el.exported_an.abs() < 0.01);
+    // Compensación por servicio: la producción NDEF se reparte según el consumo anual (50% cada servicio)
+    comps.set_meta("CTE_COMPENSACION_SERVICIOS", "true");
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.delivered_grid_an - 150.0).abs() < 0.01);
+    assert!((el.exported_an - 50.0).abs() < 0.01);
+    // La producción asignada a un servicio se conserva al normalizar y solo cubre ese servicio
+    let comps = "#META CTE_COMPENSACION_SERVICIOS: true
+ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0, 0.0
+ELECTRICIDAD, CONSUMO, EPB, ACS, 0.0, 100.0
+ELECTRICIDAD, PRODUCCION, INSITU, CAL, 100.0, 50.0"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    assert_eq!(comps.cdata[2].service, Service::CAL);
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.delivered_grid_an - 100.0).abs() < 0.01);
+    assert!((el.exported_an - 50.0).abs() < 0.01);
+}
+
+#[test]
+fn cte_balance_service_priority() {
+    let mut comps = "#META CTE_COMPENSACION_SERVICIOS: true
+ELECTRICIDAD, CONSUMO, EPB, CAL, 100.0, 100.0
+ELECTRICIDAD, CONSUMO, EPB, ACS, 50.0, 100.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 100.0, 100.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    // Sin prioridad, la producción NDEF se reparte según el consumo anual (CAL 200/350, ACS 150/350)
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.produced_used_EPus_an_byuse[&Service::CAL] - 114.29).abs() < 0.01);
+    assert!((el.produced_used_EPus_an_byuse[&Service::ACS] - 85.71).abs() < 0.01);
+    // Con prioridad, la producción NDEF cubre primero el consumo de ACS y el resto el de CAL
+    comps.set_service_priority(&[Service::ACS]);
+    assert_eq!(comps.service_priority().unwrap(), Some(vec![Service::ACS]));
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.produced_used_EPus_an_byuse[&Service::ACS] - 150.0).abs() < 0.01);
+    assert!((el.produced_used_EPus_an_byuse[&Service::CAL] - 50.0).abs() < 0.01);
+    assert!((el.delivered_grid_an - 150.0).abs() < 0.01);
+    assert!(el.exported_an.abs() < 0.01);
+    let out = balance_to_plain(&bal);
+    assert!(out.contains(
+        "** Energía producida usada por servicio (reparto de la producción NDEF: prioridad ACS) [kWh/m2.an]:
+ELECTRICIDAD: ACS: 150.00, CAL: 50.00"
+    ));
+    // Sin compensación servicio a servicio no se registra el reparto por servicio
+    comps.set_meta("CTE_COMPENSACION_SERVICIOS", "false");
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!(el.produced_used_EPus_an_byuse.is_empty());
+    // No se admiten servicios desconocidos, repetidos o NDEF
+    for priority in &["ACS, XXX", "ACS, ACS", "NDEF"] {
+        comps.set_meta("CTE_PRIORIDAD_SERVICIOS", priority);
+        assert!(energy_performance(&comps, &FP, 0.0, 1.0).is_err());
+    }
+}
+
+#[test]
+fn cte_balance_reference_building() {
+    let comps = components_from_file("test_data/ejemplo_referencia.csv");
+    let FP = get_ctefp_peninsula();
+    // Los componentes de referencia (id negativo) no forman parte del balance del edificio
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.delivered_grid_an - 50.0).abs() < 0.01);
+    let reference = energy_performance_reference(&bal).unwrap().unwrap();
+    let el_ref = reference.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el_ref.delivered_grid_an - 100.0).abs() < 0.01);
+    assert!(el_ref.produced_an.abs() < 0.01);
+    assert_eq!(reference.components.cdata[0].id, 1);
+    let indicators = reference_comparison(&bal, &reference);
+    assert!((indicators.ratio_nren.unwrap() - 0.5).abs() < 0.01);
+    assert!((indicators.ratio_co2.unwrap() - 0.5).abs() < 0.01);
+    // Sin componentes de referencia
+    let comps = components_from_file("test_data/ejemploJ2_basePV.csv");
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    assert!(energy_performance_reference(&bal).unwrap().is_none());
+}
+
+#[test]
+fn cte_energy_performance_from_meta() {
+    let comps = components_from_file("test_data/cte_test_carriers.csv");
+    let FP = get_ctefp_peninsula();
+    // Valores de los metadatos (CTE_KEXP: 0, CTE_AREAREF: 200.0)
+    let bal = energy_performance_from_meta(&comps, &FP, None, None).unwrap();
+    assert_eq!(bal.k_exp, 0.0);
+    assert_eq!(bal.arearef, 200.0);
+    assert_eq!(bal.misc["origen_kexp"], MiscValue::from("metadatos"));
+    assert_eq!(bal.misc["origen_arearef"], MiscValue::from("metadatos"));
+    let expected = energy_performance(&comps, &FP, 0.0, 200.0).unwrap();
+    assert!(approx_equal(expected.balance_m2.B, bal.balance_m2.B));
+    // Los argumentos tienen prioridad sobre los metadatos
+    let bal = energy_performance_from_meta(&comps, &FP, Some(1.0), Some(100.0)).unwrap();
+    assert_eq!(bal.k_exp, 1.0);
+    assert_eq!(bal.arearef, 100.0);
+    assert_eq!(bal.misc["origen_kexp"], MiscValue::from("usuario"));
+    // Valores predefinidos sin metadatos
+    let mut comps = comps;
+    comps
+        .cmeta
+        .retain(|m| m.key != "CTE_KEXP" && m.key != "CTE_AREAREF");
+    let bal = energy_performance_from_meta(&comps, &FP, None, None).unwrap();
+    assert_eq!(bal.k_exp, KEXP_DEFAULT);
+    assert_eq!(bal.arearef, AREAREF_DEFAULT);
+    assert_eq!(bal.misc["origen_arearef"], MiscValue::from("predefinido"));
+    // Metadatos incorrectos
+    comps.set_meta("CTE_KEXP", "1.5");
+    assert!(energy_performance_from_meta(&comps, &FP, None, None).is_err());
+    comps.set_meta("CTE_KEXP", "no");
+    assert!(energy_performance_from_meta(&comps, &FP, None, None).is_err());
+}
+
+#[test]
+fn cte_he0_report() {
+    use cteepbd::cte::he0::*;
+    let comps = components_from_file("test_data/cte_test_carriers.csv");
+    let bal = energy_performance(&comps, &get_ctefp_peninsula(), 0.0, 200.0).unwrap();
+    let report = check_he0(
+        &bal,
+        "D3",
+        "PENINSULA",
+        BuildingUse::Residential,
+        Intervention::New,
+    )
+    .unwrap();
+    assert_eq!(report.winter_zone, "D");
+    assert_eq!(report.ep_nren.value, bal.balance_m2.B.nren);
+    assert_eq!(report.ep_nren.limit, 38.0);
+    assert_eq!(report.ep_tot.value, bal.balance_m2.B.tot());
+    assert_eq!(report.ep_tot.limit, 76.0);
+    assert_eq!(
+        report.passes(),
+        bal.balance_m2.B.nren <= 38.0 && bal.balance_m2.B.tot() <= 76.0
+    );
+    assert!(report.to_string().starts_with("HE0 (zona D, PENINSULA)"));
+}
+
+#[test]
+fn cte_emissions_by_carrier() {
+    let comps = components_from_file("test_data/cte_test_carriers.csv");
+    let bal = energy_performance(&comps, &get_ctefp_peninsula(), 0.0, 200.0).unwrap();
+    let emissions = emissions_by_carrier(&bal);
+    assert_eq!(emissions[0].0, Carrier::ELECTRICIDAD);
+    let total: Real = emissions.iter().map(|(_, co2)| co2).sum();
+    assert!((total - bal.balance_m2.B.co2).abs() < 0.01);
+}
+
+#[test]
+fn cte_balance_weighted_with() {
+    let comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 0.5, 200.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    let factor = |source: Source, dest: Dest, step: Step| {
+        bal.wfactors
+            .wdata
+            .iter()
+            .find(|f| {
+                f.carrier == Carrier::ELECTRICIDAD
+                    && f.source == source
+                    && f.dest == dest
+                    && f.step == step
+            })
+            .map(|f| f.factors())
+            .ok_or_else(|| cteepbd::error::EpbdError::WrongInput("factor".into()))
+    };
+    // Canales ren, nren, co2 y total, con el mismo cálculo del balance
+    let we = el
+        .weighted_with(0.5, 0.5, |E_t: &[Real], source, dest, step| {
+            let fp = factor(source, dest, step)?;
+            let E: Real = E_t.iter().sum();
+            Ok(Weighted::new([fp.ren, fp.nren, fp.co2, fp.tot()]) * E)
+        })
+        .unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::from(Weighted::new([we.an[0], we.an[1], we.an[2]])),
+        el.we_an
+    ));
+    assert!((we.an[3] - el.we_an.tot()).abs() < 0.01);
+    // Indicador escalar (energía primaria total)
+    let tot = el
+        .weighted_with(0.5, 0.5, |E_t: &[Real], source, dest, step| {
+            Ok(factor(source, dest, step)?.tot() * E_t.iter().sum::<Real>())
+        })
+        .unwrap();
+    assert!((tot.an_A - el.we_an_A.tot()).abs() < 0.01);
+    assert!((tot.an - el.we_an.tot()).abs() < 0.01);
+}
+
+#[test]
+fn cte_balance_carrier_views() {
+    let comps = components_from_file("test_data/cte_test_carriers.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 1.0, 200.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!(bal.carrier(Carrier::CARBON).is_none());
+    // Energía suministrada por fuente
+    let delivered = el.delivered();
+    assert_eq!(delivered.grid_an, el.delivered_grid.iter().sum::<Real>());
+    assert!(approx_equal(
+        delivered.we_grid_an + delivered.we_produced_an,
+        delivered.we_an
+    ));
+    // Energía producida por origen
+    let produced = el.produced();
+    let by_origin_an: Real = produced.by_origin_an.values().sum();
+    assert!((produced.total_an - by_origin_an).abs() < 0.01);
+    // Energía exportada por destino
+    let exported = el.exported();
+    assert!((exported.total_an - exported.grid_an - exported.nEPB_an).abs() < 0.01);
+    assert!(approx_equal(
+        exported.we_grid_AB + exported.we_nEPB_AB,
+        exported.we_AB
+    ));
+    assert!(approx_equal(
+        exported.we_A + exported.we_avoided,
+        exported.we_B
+    ));
+    // Energía ponderada en pasos A y B
+    let weighted = el.weighted();
+    assert!(approx_equal(
+        weighted.delivered - weighted.exported_A,
+        weighted.A
+    ));
+    assert!(approx_equal(
+        weighted.delivered - weighted.exported_B,
+        weighted.B
+    ));
+    // Resumen anual por vector
+    let summary = bal.summary_by_carrier();
+    assert_eq!(summary.len(), bal.balance_cr.len());
+    let el_summary = summary
+        .iter()
+        .find(|s| s.carrier == Carrier::ELECTRICIDAD)
+        .unwrap();
+    assert_eq!(el_summary.delivered_grid, el.delivered_grid_an);
+    assert!((el_summary.exported_grid + el_summary.exported_nEPB - el.exported_an).abs() < 0.01);
+    assert!(approx_equal(
+        el_summary.we_delivered_grid + el_summary.we_delivered_onsite
+            - el_summary.we_exported_A
+            - el_summary.we_exported_avoided,
+        el_summary.we_B
+    ));
+    assert!(balance_to_plain(&bal).contains("** Balance anual por vector"));
+}
+
+#[test]
+fn cte_results_serde() {
+    let comps = components_from_file("test_data/cte_test_carriers.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 1.0, 200.0).unwrap();
+    // Balance completo
+    let json = serde_json::to_string(&bal).unwrap();
+    let bal2: Balance = serde_json::from_str(&json).unwrap();
+    assert!(approx_equal(bal.balance_m2.B, bal2.balance_m2.B));
+    assert_eq!(bal2.balance_cr.len(), bal.balance_cr.len());
+    // Vistas y resúmenes de resultados
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    let delivered = serde_json::to_value(el.delivered()).unwrap();
+    assert_eq!(
+        delivered["grid_an"],
+        serde_json::json!(el.delivered_grid_an)
+    );
+    assert!(serde_json::to_value(el.exported()).unwrap()["we_B"].is_object());
+    let summary = serde_json::to_string(&bal.summary_by_carrier()).unwrap();
+    let summary: Vec<CarrierSummary> = serde_json::from_str(&summary).unwrap();
+    assert_eq!(summary.len(), bal.balance_cr.len());
+    let warnings = serde_json::to_string(&comps.check_plausibility()).unwrap();
+    let _: Vec<ComponentWarning> = serde_json::from_str(&warnings).unwrap();
+}
+
+#[cfg(feature = "yaml")]
+#[test]
+fn cte_balance_yaml() {
+    let comps = components_from_file("test_data/cte_test_carriers.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 1.0, 200.0).unwrap();
+    let yaml = balance_to_yaml(&bal).unwrap();
+    assert!(yaml.contains("indicadores:"));
+    let bal2 = balance_from_yaml(&yaml).unwrap();
+    assert!(approx_equal(bal.balance_m2.B, bal2.balance_m2.B));
+    assert_eq!(bal2.components.to_string(), bal.components.to_string());
+}
+
+#[test]
+fn cte_balance_to_markdown() {
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0, 20.0
+GASNATURAL, CONSUMO, EPB, ACS, 100.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 0.0, 2.0).unwrap();
+    let md = balance_to_markdown(&bal);
+    assert!(md.starts_with("# Balance energético"));
+    assert!(md.contains("| Área de referencia [m2] | 2.00 |"));
+    assert!(md.contains(&format!(
+        "| C_ep,nren [kWh/m2.an] | {:.1} |",
+        bal.balance_m2.B.nren
+    )));
+    assert!(md.contains("| ACS | 50.00 |"));
+    assert!(md.contains("| ELECTRICIDAD | 15.00 |"));
+    assert!(!md.contains("## Indicadores adicionales"));
+}
+
+#[test]
+fn cte_balance_to_json() {
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0, 20.0
+GASNATURAL, CONSUMO, EPB, ACS, 100.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 0.0, 2.0).unwrap();
+    let json = balance_to_json(&bal).unwrap();
+    let doc: serde_json::Value = serde_json::from_str(&json).unwrap();
+    // Datos completos del balance
+    assert!(doc["components"]["cdata"].is_array());
+    assert!(doc["wfactors"]["wdata"].is_array());
+    // Indicadores globales y por servicio, por m2
+    let indicators = &doc["indicadores"];
+    assert_eq!(indicators["arearef"], 2.0);
+    let nren = indicators["paso_B"]["nren"].as_f64().unwrap() as Real;
+    assert!((nren - bal.balance_m2.B.nren).abs() < 0.01);
+    assert!((indicators["consumo_epb"].as_f64().unwrap() - 65.0).abs() < 0.01);
+    assert!(
+        (indicators["servicios"]["ACS"]["consumo_epb"]
+            .as_f64()
+            .unwrap()
+            - 50.0)
+            .abs()
+            < 0.01
+    );
+    // Resumen por vector
+    assert_eq!(doc["resumen_vectores"].as_array().unwrap().len(), 2);
+    assert_eq!(doc["resumen_vectores"][0]["carrier"], "ELECTRICIDAD");
+}
+
+#[test]
+fn cte_balance_distinct_timesteps() {
+    let mut comps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0, 20.0
+GASNATURAL, CONSUMO, EPB, ACS, 100.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    // Los componentes creados por otros medios no se completan con ceros en el balance
+    comps.cdata[1].values.pop();
+    let FP = get_ctefp_peninsula();
+    let err = energy_performance(&comps, &FP, 0.0, 1.0).unwrap_err();
+    assert!(err.to_string().contains("componente 2 (1 valores)"));
+    comps.pad_values();
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    assert_eq!(
+        bal.balance_cr[&Carrier::GASNATURAL].used_EPB,
+        vec![100.0, 0.0]
+    );
+}
+
+#[test]
+fn cte_balance_to_canonical_json() {
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0, 20.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 15.0, 5.0
+GASNATURAL, CONSUMO, EPB, ACS, 100.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 0.0, 3.0).unwrap();
+    let json = balance_to_canonical_json(&bal, 2).unwrap();
+    // Mismo texto para el mismo balance, aunque se vuelva a calcular
+    let bal2 = energy_performance(&comps, &FP, 0.0, 3.0).unwrap();
+    assert_eq!(balance_to_canonical_json(&bal2, 2).unwrap(), json);
+    // Valores redondeados al número de decimales indicado
+    let doc: serde_json::Value = serde_json::from_str(&json).unwrap();
+    let nren = doc["indicadores"]["paso_B"]["nren"].as_f64().unwrap();
+    assert_eq!(nren, (bal.balance_m2.B.nren as f64 * 100.0).round() / 100.0);
+    // Claves ordenadas
+    let keys: Vec<_> = doc.as_object().unwrap().keys().cloned().collect();
+    let mut sorted = keys.clone();
+    sorted.sort();
+    assert_eq!(keys, sorted);
+}
+
+#[test]
+fn cte_balance_to_csv() {
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, NDEF, 10.0, 20.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 15.0, 5.0
+GASNATURAL, CONSUMO, EPB, CAL, 100.0, 0.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let csv = balance_to_csv(&bal);
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(lines.len(), 13);
+    assert_eq!(lines[0], "vector,magnitud,total,1,2");
+    assert_eq!(lines[3], "ELECTRICIDAD,PRODUCCION,20.00,15.00,5.00");
+    assert_eq!(lines[4], "ELECTRICIDAD,SUMINISTRO_RED,15.00,0.00,15.00");
+    assert_eq!(lines[5], "ELECTRICIDAD,EXPORTACION_RED,5.00,5.00,0.00");
+    assert_eq!(lines[7], "GASNATURAL,CONSUMO_EPB,100.00,100.00,0.00");
+}
+
+#[test]
+fn cte_kexp_by_dest() {
+    let comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    // Toda la exportación es a la red, luego solo afecta k_exp
+    let bal = energy_performance_by_dest(&comps, &FP, 1.0, 0.0, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(120.0, -80.0, -16.8),
+        bal.balance_m2.B
+    ));
+    let bal = energy_performance_by_dest(&comps, &FP, 0.0, 1.0, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(100.0, 0.0, 0.0),
+        bal.balance_m2.B
+    ));
+    assert_eq!(bal.k_exp, 0.0);
+    assert_eq!(bal.k_exp_nepb, 1.0);
+    assert!(balance_to_plain(&bal).contains("k_exp (usos no EPB) = 1.00"));
+    // Con un único k_exp se usa el mismo valor para ambos destinos
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    assert_eq!(bal.k_exp_nepb, 1.0);
+    assert!(!balance_to_plain(&bal).contains("k_exp (usos no EPB)"));
+}
+
+#[test]
+fn cte_heat_export() {
+    // Excedentes solares (in situ) exportados a la red de distrito
+    let comps = components_from_file("test_data/ejemplo_exportacion_calor.csv");
+    // Los factores de calor in situ de la red solo se añaden al considerar los componentes
+    assert!(energy_performance(&comps, &get_ctefp_peninsula(), 1.0, 1.0).is_err());
+    let FP = wfactors_set_districts_from_components(get_ctefp_peninsula(), &comps, &CTE_USERWF)
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let red1 = &bal.balance_cr[&Carrier::RED1];
+    assert_eq!(red1.exported_grid_an, 50.0);
+    assert!(approx_equal(
+        RenNrenCo2::new(50.0, 0.0, 0.0),
+        red1.we_exported_an_A
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(-50.0, 65.0, 15.0),
+        red1.we_exported_an_avoided
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(154.1, -45.5, -11.7),
+        bal.balance_m2.B
+    ));
+
+    // Calor cogenerado exportado: necesita factores de exportación de usuario
+    let comps = "RED1, CONSUMO, EPB, CAL, 100
+RED1, PRODUCCION, COGENERACION, NDEF, 150
+GASNATURAL, CONSUMO, EPB, NDEF, 300"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    assert!(energy_performance(&comps, &get_ctefp_peninsula(), 1.0, 1.0).is_err());
+    let FP = wfactors_from_str(
+        "ELECTRICIDAD, RED, SUMINISTRO, A, 0.414, 1.954, 0.331
+GASNATURAL, RED, SUMINISTRO, A, 0.005, 1.190, 0.252
+RED1, RED, SUMINISTRO, A, 0.0, 1.3, 0.3
+RED1, COGENERACION, A_RED, A, 0.0, 1.0, 0.2",
+        UserWF {
+            red1: None,
+            red2: None,
+            residuos: None,
+            cogen_to_grid: None,
+            cogen_to_nepb: None,
+        },
+        CTE_USERWF,
+    )
+    .unwrap();
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let red1 = &bal.balance_cr[&Carrier::RED1];
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 50.0, 10.0),
+        red1.we_exported_an_A
+    ));
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 15.0, 5.0),
+        red1.we_exported_an_avoided
+    ));
+}
+
+#[test]
+fn cte_adjustments() {
+    let comps = components_from_file("test_data/ejemplo_ajustes.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 0.0, 100.0).unwrap();
+    assert_eq!(
+        bal.balance_cr[&Carrier::ELECTRICIDAD].delivered_grid_an,
+        1140.0
+    );
+    assert_eq!(
+        bal.balance_cr[&Carrier::GASNATURAL].delivered_grid_an,
+        810.0
+    );
+    assert!(approx_equal(
+        RenNrenCo2::new(4.8, 31.9, 5.8),
+        bal.balance_m2.B
+    ));
+    // Los ajustes se listan por separado
+    assert!(balance_to_plain(&bal)
+        .contains("ELECTRICIDAD, EPB, CAL: -0.60 (Corrección de lectura del contador)"));
+    assert!(balance_to_xml_with_detail(&bal, XmlDetail::Full).unwrap().contains("<Ajuste><Vector>GASNATURAL</Vector><Subtipo>EPB</Subtipo><Servicio>ACS</Servicio><Valor>-0.90</Valor><Motivo>Deducción estimada del consumo de cocina</Motivo></Ajuste>"));
+}
+
+#[test]
+fn cte_dwellings() {
+    let comps = components_from_file("test_data/ejemplo_viviendas.csv");
+    assert_eq!(
+        dwelling_shares(&comps).unwrap(),
+        Some(vec![0.20, 0.30, 0.25, 0.25])
+    );
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, 0.0, 400.0).unwrap();
+    let dwellings = indicators_by_dwelling(&bal).unwrap();
+    assert_eq!(dwellings.len(), 4);
+    assert_eq!(dwellings[1].number, 2);
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 0.0, 0.0),
+        (1.0 / dwellings[1].arearef) * dwellings[1].we - bal.balance_m2.B
+    ));
+    assert!((dwellings[1].used_epb - 0.3 * 9400.0).abs() < 0.1);
+    // La evaluación independiente de cada vivienda coincide con su estimación
+    let comps_2 = components_for_dwelling(&comps, 0.3);
+    assert!(!comps_2.has_meta("CTE_VIVIENDAS"));
+    assert_eq!(comps_2.get_meta_real("CTE_AREAREF"), Some(120.0));
+    let bal_2 = energy_performance(&comps_2, &FP, 0.0, 120.0).unwrap();
+    assert!(approx_equal(dwellings[1].we, bal_2.balance.B));
+    assert!(balance_to_plain(&bal).contains("** Indicadores por vivienda (estimación por cuotas):"));
+    assert!(balance_to_xml_with_detail(&bal, XmlDetail::Full)
+        .unwrap()
+        .contains("<Vivienda><Numero>1</Numero><Cuota>0.200</Cuota><AreaRef>80.00</AreaRef>"));
+    // Viviendas con cuotas iguales y datos incorrectos
+    let comps = "#META CTE_VIVIENDAS: 4
+ELECTRICIDAD, CONSUMO, EPB, ILU, 1.0"
+        .parse::<Components>()
+        .unwrap();
+    assert_eq!(dwelling_shares(&comps).unwrap(), Some(vec![0.25; 4]));
+    let comps = "#META CTE_VIVIENDAS: 3
+#META CTE_CUOTAS_VIVIENDAS: 0.5, 0.5
+ELECTRICIDAD, CONSUMO, EPB, ILU, 1.0"
+        .parse::<Components>()
+        .unwrap();
+    assert!(dwelling_shares(&comps).is_err());
+    let comps = "#META CTE_CUOTAS_VIVIENDAS: 0.5, 0.6
+ELECTRICIDAD, CONSUMO, EPB, ILU, 1.0"
+        .parse::<Components>()
+        .unwrap();
+    assert!(dwelling_shares(&comps).is_err());
+}
+
+#[test]
+fn cte_energy_bill() {
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, NDEF, 10.0, 20.0, 30.0
+GASNATURAL, CONSUMO, EPB, CAL, 100.0, 0.0, 100.0"
+        .parse::<Components>()
+        .unwrap();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let tariffs = "ELECTRICIDAD, FIJO, 100.0
+ELECTRICIDAD, ENERGIA, 0.1
+ELECTRICIDAD, PERIODO, P1, 0.2, 2-3
+GASNATURAL, ENERGIA, 0.05
+BIOMASA, FIJO, 50.0"
+        .parse::<Tariffs>()
+        .unwrap();
+    let bill = energy_bill(&bal, &tariffs).unwrap();
+    // Solo se facturan los vectores del balance
+    assert_eq!(bill.by_carrier.len(), 2);
+    assert!((bill.by_carrier[0].total - (100.0 + 1.0 + 4.0 + 6.0)).abs() < 0.001);
+    assert!((bill.by_carrier[1].total - 10.0).abs() < 0.001);
+    assert!((bill.total - 121.0).abs() < 0.001);
+    let bal = incorpora_factura_estimada(bal, &tariffs).unwrap();
+    assert_eq!(
+        bal.misc
+            .get("factura_anual_estimada")
+            .and_then(MiscValue::as_real),
+        Some(121.0)
+    );
+    // Compensación de la energía exportada a la red
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, NDEF, 10.0, 20.0, 30.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 20.0, 20.0, 20.0"
+        .parse::<Components>()
+        .unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let tariffs = "ELECTRICIDAD, ENERGIA, 0.1
+ELECTRICIDAD, EXPORTACION, 0.05"
+        .parse::<Tariffs>()
+        .unwrap();
+    let bill = energy_bill(&bal, &tariffs).unwrap();
+    assert!((bill.by_carrier[0].energy - 1.0).abs() < 0.001);
+    assert!((bill.by_carrier[0].exported - 0.5).abs() < 0.001);
+    assert!((bill.total - 0.5).abs() < 0.001);
+    // Periodos con pasos de cálculo inexistentes
+    let tariffs = "ELECTRICIDAD, PERIODO, P1, 0.2, 4"
+        .parse::<Tariffs>()
+        .unwrap();
+    assert!(energy_bill(&bal, &tariffs).is_err());
+}
+
+#[test]
+fn cte_balance_by_year() {
+    let comps = components_from_file("test_data/ejemplo_plurianual.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let years = energy_performance_by_year(&bal).unwrap();
+    assert_eq!(
+        years.iter().map(|(l, _)| l.as_str()).collect::<Vec<_>>(),
+        vec!["2017", "2018", "2019"]
+    );
+    // El agregado es la suma de las anualidades
+    let sum = years
+        .iter()
+        .fold(RenNrenCo2::default(), |acc, (_, b)| acc + b.balance.B);
+    assert!(approx_equal(sum, bal.balance.B));
+    assert_eq!(
+        years[1].1.balance_cr[&Carrier::ELECTRICIDAD].delivered_grid_an,
+        144.0
+    );
+    assert!(balance_by_year_to_plain(&years).contains("Media anual: C_ep [kWh/m2.an]"));
+    // Sin anualidades no hay balances por año
+    let comps = components_from_file("test_data/ejemploJ1_base.csv");
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(energy_performance_by_year(&bal).unwrap().is_empty());
+}
+
+#[test]
+fn cte_balance_self_consumption() {
+    // La producción del primer paso supera al consumo y se exporta el exceso
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, CAL, 10.0, 10.0
+ELECTRICIDAD, CONSUMO, NEPB, NDEF, 2.0, 2.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 20.0, 0.0"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let s = bal.self_consumption.unwrap();
+    assert_eq!(s.produced, 20.0);
+    assert_eq!(s.self_consumed, 12.0);
+    assert_eq!(s.used, 24.0);
+    assert!((s.self_consumption_ratio - 0.6).abs() < 1e-5);
+    assert!((s.self_sufficiency_ratio - 0.5).abs() < 1e-5);
+    assert!(balance_to_plain(&bal).contains(
+        "producida 20.00, autoconsumida 12.00, consumida 24.00; índice de autoconsumo 0.60, índice de autosuficiencia 0.50"
+    ));
+    assert!(balance_to_markdown(&bal).contains("| Índice de autosuficiencia [-] | 0.50 |"));
+    assert!(balance_to_xml_with_detail(&bal, XmlDetail::Full)
+        .unwrap()
+        .contains("<IndiceAutoconsumo>0.600</IndiceAutoconsumo>"));
+    // Sin producción de electricidad no hay indicadores de autoconsumo
+    let comps = components_from_file("test_data/ejemploJ1_base.csv");
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(bal.self_consumption.is_none());
+    assert!(!balance_to_plain(&bal).contains("Autoconsumo"));
+}
+
+#[test]
+fn cte_balance_by_kexp() {
+    assert_eq!(kexp_range(0.25).unwrap(), vec![0.0, 0.25, 0.5, 0.75, 1.0]);
+    assert_eq!(kexp_range(0.1).unwrap().len(), 11);
+    assert!(kexp_range(0.0).is_err());
+    assert!(kexp_range(1.5).is_err());
+    let comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let balances = energy_performance_by_kexp(&bal, &kexp_range(0.5).unwrap()).unwrap();
+    assert_eq!(
+        balances.iter().map(|(k, _)| *k).collect::<Vec<_>>(),
+        vec![0.0, 0.5, 1.0]
+    );
+    let exp = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    assert!(approx_equal(balances[2].1.balance.B, exp.balance.B));
+    // Con energía exportada los indicadores dependen del factor de exportación
+    assert!(balances[2].1.balance.B.nren < balances[0].1.balance.B.nren);
+    assert!(balance_by_kexp_to_plain(&balances).contains("k_exp = 0.50: C_ep [kWh/m2.an]"));
+    assert!(energy_performance_by_kexp(&bal, &[1.2]).is_err());
+}
+
+#[test]
+fn cte_balance_by_scenario() {
+    let comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    let FP = get_ctefp_peninsula();
+    let scenario = |name: &str, k_exp: Real, perimeter: Option<Perimeter>| Scenario {
+        name: name.to_string(),
+        wfactors: FP.clone(),
+        k_exp,
+        perimeter,
+    };
+    let scenarios = vec![
+        scenario("Base", 0.0, None),
+        scenario("Exportacion", 1.0, None),
+        scenario("Proximo", 0.0, Some(Perimeter::NEARBY)),
+    ];
+    let balances = energy_performance_by_scenario(&comps, &scenarios, 1.0).unwrap();
+    assert_eq!(
+        balances.iter().map(|(l, _)| l.as_str()).collect::<Vec<_>>(),
+        vec!["Base", "Exportacion", "Proximo"]
+    );
+    let exp = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    assert!(approx_equal(balances[1].1.balance.B, exp.balance.B));
+    let nrb = energy_performance(&comps, &wfactors_to_nearby(&FP).unwrap(), 0.0, 1.0).unwrap();
+    assert!(approx_equal(balances[2].1.balance.B, nrb.balance.B));
+
+    let table = scenarios_comparison(&balances).unwrap();
+    assert_eq!(table[0].diff_tot, 0.0);
+    assert_eq!(table[2].perimeter, Perimeter::NEARBY);
+    assert!((table[1].diff_tot - (exp.balance_m2.B.tot() - table[0].ep.tot())).abs() < 0.01);
+    let csv = scenarios_to_csv(&table);
+    assert!(csv.starts_with("escenario,k_exp,perimetro,ep_ren,ep_nren,ep_tot,rer,co2,"));
+    assert_eq!(csv.lines().count(), 4);
+    // Conversión no disponible de los factores de paso
+    let wrong = vec![scenario("Insitu", 0.0, Some(Perimeter::ONSITE))];
+    assert!(energy_performance_by_scenario(&comps, &wrong, 1.0).is_err());
+}
+
+#[test]
+fn cte_balance_systems() {
+    let comps = components_from_file("test_data/ejemplo_sistemas.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 100.0).unwrap();
+    let plain = balance_to_plain(&bal);
+    assert!(plain.contains("2 - Bomba de calor (BDC, ELECTRICIDAD, 8.00 kW): ELECTRICIDAD 3.00"));
+    let xml = balance_to_xml_with_detail(&bal, XmlDetail::Full).unwrap();
+    assert!(xml.contains("<Sistema><Id>2</Id><Nombre>Bomba de calor</Nombre><Tipo>BDC</Tipo><Vector>ELECTRICIDAD</Vector><Potencia>8.00</Potencia><Consumo><Vector>ELECTRICIDAD</Vector><Valor>3.00</Valor></Consumo><HorasEquivalentes>37.5</HorasEquivalentes></Sistema>"));
+    assert!(xml.contains("<Id>1</Id><Vector>GASNATURAL</Vector>"));
+    // Sin sistemas declarados no se muestra la sección
+    let comps = components_from_file("test_data/ejemploJ1_base.csv");
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(!balance_to_plain(&bal).contains("Energía consumida por sistema"));
+}
+
+#[test]
+fn cte_residuos() {
+    let comps = "RESIDUOS, CONSUMO, EPB, ACS, 100.0"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    // Los factores reglamentarios no definen el vector RESIDUOS
+    assert!(!CTE_LOCWF_RITE2014["PENINSULA"]
+        .wdata
+        .iter()
+        .any(|f| f.carrier == Carrier::RESIDUOS));
+    // pero se completan con los factores de usuario por defecto
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(0.0, 130.0, 30.0),
+        bal.balance_m2.B
+    ));
+    // que el usuario puede modificar
+    let user_wf = UserWF {
+        red1: None,
+        red2: None,
+        residuos: Some(RenNrenCo2::new(0.1, 0.5, 0.1)),
+        cogen_to_grid: None,
+        cogen_to_nepb: None,
+    };
+    let FP_user = wfactors_from_loc("PENINSULA", &CTE_LOCWF_RITE2014, user_wf, CTE_USERWF).unwrap();
+    let bal = energy_performance(&comps, &FP_user, TESTKEXP, 1.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(10.0, 50.0, 10.0),
+        bal.balance_m2.B
+    ));
+    // En el perímetro próximo se mantiene como vector de red de distrito
+    let FP_nrb = wfactors_to_nearby(&FP).unwrap();
+    let fp = FP_nrb
+        .wdata
+        .iter()
+        .find(|f| f.carrier == Carrier::RESIDUOS)
+        .unwrap();
+    assert_eq!((fp.ren, fp.nren), (0.0, 1.3));
+    assert_eq!("WASTE".parse::<Carrier>().unwrap(), Carrier::RESIDUOS);
+}
+
+#[test]
+fn cte_location_from_components() {
+    let comps = |metas: &str| {
+        format!("{}\nELECTRICIDAD, CONSUMO, EPB, ILU, 1.0", metas)
+            .parse::<Components>()
+            .unwrap()
+    };
+    assert_eq!(location_from_climate_zone("α3"), Some("CANARIAS"));
+    assert_eq!(location_from_climate_zone("A2c"), Some("CANARIAS"));
+    assert_eq!(location_from_climate_zone("D3"), None);
+    assert!(is_climate_zone("D3") && is_climate_zone("α3") && is_climate_zone("A2c"));
+    assert!(!is_climate_zone("F3") && !is_climate_zone("D5") && !is_climate_zone("D3x"));
+    assert_eq!(location_from_components(&comps("")).unwrap(), None);
+    assert_eq!(
+        location_from_components(&comps("#META CTE_LOCALIZACION: BALEARES")).unwrap(),
+        Some("BALEARES")
+    );
+    assert_eq!(
+        location_from_components(&comps("#META CTE_ZONACLIMATICA: B3c")).unwrap(),
+        Some("CANARIAS")
+    );
+    assert_eq!(
+        location_from_components(&comps("#META CTE_ZONACLIMATICA: D3")).unwrap(),
+        None
+    );
+    assert_eq!(
+        location_from_components(&comps(
+            "#META CTE_LOCALIZACION: PENINSULA\n#META CTE_ZONACLIMATICA: D3"
+        ))
+        .unwrap(),
+        Some("PENINSULA")
+    );
+    // Localización desconocida o incoherente con la zona climática
+    assert!(location_from_components(&comps("#META CTE_LOCALIZACION: MARTE")).is_err());
+    assert!(location_from_components(&comps(
+        "#META CTE_LOCALIZACION: PENINSULA\n#META CTE_ZONACLIMATICA: α3"
+    ))
+    .is_err());
+    assert!(location_from_components(&comps(
+        "#META CTE_LOCALIZACION: CANARIAS\n#META CTE_ZONACLIMATICA: D3"
+    ))
+    .is_err());
+    // Factores de paso de la localización de los componentes
+    let user_wf = UserWF {
+        red1: None,
+        red2: None,
+        residuos: None,
+        cogen_to_grid: None,
+        cogen_to_nepb: None,
+    };
+    let fp = wfactors_from_components(
+        &comps("#META CTE_ZONACLIMATICA: α2"),
+        &CTE_LOCWF_RITE2014,
+        user_wf,
+        CTE_USERWF,
+    )
+    .unwrap();
+    assert!(fp.has_meta_value("CTE_LOCALIZACION", "CANARIAS"));
+    assert!(fp.has_meta_value("CTE_ZONACLIMATICA", "α2"));
+    assert!(
+        wfactors_from_components(&comps(""), &CTE_LOCWF_RITE2014, user_wf, CTE_USERWF).is_err()
+    );
+    // Factores de paso de una localización y zona climática
+    let fp = wfactors_from_loc_zone("PENINSULA", "D3", &CTE_LOCWF_RITE2014, user_wf, CTE_USERWF)
+        .unwrap();
+    assert!(fp.has_meta_value("CTE_ZONACLIMATICA", "D3"));
+    assert_eq!(
+        fp.wdata.len(),
+        wfactors_from_loc("PENINSULA", &CTE_LOCWF_RITE2014, user_wf, CTE_USERWF)
+            .unwrap()
+            .wdata
+            .len()
+    );
+    for (loc, zone) in &[("PENINSULA", "α3"), ("CANARIAS", "D3"), ("PENINSULA", "Z9")] {
+        assert!(
+            wfactors_from_loc_zone(loc, zone, &CTE_LOCWF_RITE2014, user_wf, CTE_USERWF).is_err()
+        );
+    }
+}
+
+#[test]
+fn cte_expand_annual_components() {
+    let comps = "#META CTE_PASO: ANUAL
+#META CTE_ANUALIDADES: 1, 1
+ELECTRICIDAD, CONSUMO, EPB, REF, 1000.0, 500.0
+GASNATURAL, CONSUMO, EPB, CAL, 2000.0, 2000.0
+ELECTRICIDAD, PRODUCCION, INSITU, NDEF, 365.0, 365.0"
+        .parse::<Components>()
+        .unwrap();
+    let monthly = expand_annual_components(&comps).unwrap();
+    assert_eq!(monthly.get_meta("CTE_PASO").unwrap(), "MENSUAL");
+    assert_eq!(monthly.get_meta("CTE_ANUALIDADES").unwrap(), "12, 12");
+    // Se conserva la energía de cada anualidad con la distribución del perfil del servicio
+    for (c, m) in comps.cdata.iter().zip(monthly.cdata.iter()) {
+        assert_eq!(m.values.len(), 24);
+        for year in 0..2 {
+            let total: Real = m.values[year * 12..(year + 1) * 12].iter().sum();
+            assert!((total - c.values[year]).abs() < 0.01);
+        }
+    }
+    let refr = &monthly.cdata[0].values;
+    assert_eq!(refr[0], 0.0);
+    assert!((refr[6] - 280.0).abs() < 0.01);
+    assert!((refr[18] - 140.0).abs() < 0.01);
+    assert!(monthly.cdata[1].values[0] > monthly.cdata[1].values[6]);
+    assert!((monthly.cdata[2].values[1] - 28.0).abs() < 0.01);
+    for service in SERVICES.iter() {
+        let total: Real = cte_monthly_profile(*service).iter().sum();
+        assert!((total - 1.0).abs() < 0.001);
+    }
+    // Solo se expanden componentes anuales
+    assert!(expand_annual_components(&monthly).is_err());
+    // Perfil personalizado
+    let flat = comps.expand_annual(|_| vec![1.0; 12]).unwrap();
+    assert!((flat.cdata[1].values[5] - 2000.0 / 12.0).abs() < 0.01);
+    assert!(comps.expand_annual(|_| vec![1.0; 11]).is_err());
+}
+
+#[test]
+fn cte_balance_to_plain_detail() {
+    let comps = components_from_file("test_data/ejemploJ3_basePVexcess.csv");
+    let FP: Factors = TESTFP.parse().unwrap();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let summary = balance_to_plain_with_detail(&bal, PlainDetail::Summary);
+    let standard = balance_to_plain_with_detail(&bal, PlainDetail::Standard);
+    let full = balance_to_plain_with_detail(&bal, PlainDetail::Full);
+    assert_eq!(standard, balance_to_plain(&bal));
+    assert_eq!(summary.lines().count(), 4);
+    assert!(standard.starts_with(&summary));
+    assert!(!standard.contains("(paso A) [kWh/m2.an]"));
+    assert!(
+        full.contains("** Energía ponderada y emisiones (paso A) [kWh/m2.an] y [kg_CO2e/m2.an]:")
+    );
+    assert!(
+        full.contains("** Energía exportada por vector y paso de cálculo [kWh]:\nELECTRICIDAD: ")
+    );
+    let a = bal.balance_m2.A;
+    assert!(full.contains(&format!(
+        "ren {:.2}, nren {:.2}, tot {:.2}",
+        a.ren,
+        a.nren,
+        a.tot()
+    )));
+    for service in bal.balance_m2.B_byuse.keys() {
+        assert!(full.contains(&format!("\n{}: paso A: ren ", service)));
+    }
+    assert_eq!(
+        "completo".parse::<PlainDetail>().unwrap(),
+        PlainDetail::Full
+    );
+    assert!("total".parse::<PlainDetail>().is_err());
+}
+
+#[test]
+fn cte_balance_to_plain_lang() {
+    let comps = components_from_file("test_data/cte_test_carriers.csv");
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let es = balance_to_plain(&bal);
+    assert!(es.contains("** Energía final (todos los vectores) [kWh/m2.an]:"));
+    i18n::set_lang(i18n::Lang::EN);
+    let en = balance_to_plain(&bal);
+    let warning = error::Warning::GeneratedEnvProduction(Service::CAL).to_string();
+    i18n::set_lang(i18n::Lang::ES);
+    assert!(en.contains("** Final energy (all carriers) [kWh/m2.an]:"));
+    assert!(!en.contains("Energía"));
+    // Los valores no cambian con el idioma
+    assert_eq!(
+        es.lines().find(|l| l.starts_with("C_ep")),
+        en.lines().find(|l| l.starts_with("C_ep"))
+    );
+    assert_eq!(
+        warning,
+        "MEDIOAMBIENTE production generated to balance the energy use of service CAL"
+    );
+}
+
+#[test]
+fn cte_co2_catalog() {
+    let catalog = co2_catalog_from_loc("BALEARES", &CTE_LOCWF_RITE2014).unwrap();
+    assert!(catalog.contains(&(Carrier::ELECTRICIDAD, 0.932)));
+    assert!(catalog.contains(&(Carrier::GASNATURAL, 0.252)));
+    assert!(co2_catalog_from_loc("MARTE", &CTE_LOCWF_RITE2014).is_err());
+
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let FP = wfactors_set_co2_from_loc(FP, "BALEARES", &CTE_LOCWF_RITE2014).unwrap();
+    let el = FP
+        .wdata
+        .iter()
+        .find(|f| f.carrier == Carrier::ELECTRICIDAD && f.source == Source::RED)
+        .unwrap();
+    assert_eq!((el.nren, el.co2), (2.0, 0.932));
+    assert_eq!(
+        FP.get_meta("CTE_FUENTE_CO2"),
+        Some("RITE2014, BALEARES".to_string())
+    );
+}
+
+#[test]
+fn cte_fp_versions() {
+    let locwf = locwf_from_version("rite2014").unwrap();
+    assert!(locwf["PENINSULA"].has_meta_value("CTE_FUENTE", "RITE2014"));
+    assert!(locwf_from_version("RITE1979").is_err());
+
+    let comps = |s: &str| s.parse::<Components>().unwrap();
+    let locwf = locwf_from_components(&comps("#META CTE_FP_VERSION: RITE2014")).unwrap();
+    assert!(locwf["CANARIAS"].has_meta_value("CTE_FUENTE", CTE_FP_VERSION_DEFAULT));
+    assert!(locwf_from_components(&comps("")).is_ok());
+    assert!(locwf_from_components(&comps("#META CTE_FP_VERSION: RITE1979")).is_err());
+}
+
+#[test]
+fn cte_district_networks() {
+    let comps = components_from_file("test_data/ejemplo_redes_distrito.csv");
+    let FP = wfactors_set_districts_from_components(get_ctefp_peninsula(), &comps, &CTE_USERWF)
+        .unwrap()
+        .normalize(&CTE_USERWF)
+        .unwrap();
+    let supply = |c: Carrier| {
+        FP.wdata
+            .iter()
+            .find(|f| f.carrier == c && f.source == Source::RED && f.dest == Dest::SUMINISTRO)
+            .map(|f| f.factors())
+            .unwrap()
+    };
+    // RED3 desde metadatos y RED4 con factores por defecto
+    assert_eq!(
+        supply(Carrier::district(3).unwrap()),
+        RenNrenCo2::new(0.5, 0.5, 0.1)
+    );
+    assert_eq!(supply(Carrier::district(4).unwrap()), CTE_USERWF.red1);
+    // Sin producción de calor in situ no hay factores de exportación de calor a la red de distrito
+    assert!(!FP
+        .wdata
+        .iter()
+        .any(|f| f.carrier == Carrier::district(3).unwrap()
+            && f.source == Source::INSITU
+            && f.dest == Dest::A_RED
+            && f.step == Step::B));
+    // Las redes de distrito están en el perímetro próximo
+    assert!(is_nearby_carrier(Carrier::district(4).unwrap()));
+
+    let bal = energy_performance(&comps, &FP.strip(&comps), TESTKEXP, 100.0).unwrap();
+    assert!(approx_equal(
+        RenNrenCo2::new(13.24, 29.56, 6.09),
+        bal.balance_m2.B
+    ));
+}
+
+#[test]
+fn cte_fuel_cell_factors() {
+    // Los factores reglamentarios no definen el vector HIDROGENO
+    let mut FP = get_ctefp_peninsula();
+    assert!(fuel_cell_cogen_factors(&FP, Carrier::HIDROGENO, 0.5, 0.35).is_err());
+    FP.merge_overriding(
+        &"HIDROGENO, RED, SUMINISTRO, A, 0.000, 1.700, 0.360"
+            .parse::<Factors>()
+            .unwrap(),
+    );
+    let f = fuel_cell_cogen_factors(&FP, Carrier::HIDROGENO, 0.5, 0.35).unwrap();
+    assert!(approx_equal(f, RenNrenCo2::new(0.0, 2.0, 0.4235)));
+    assert!(fuel_cell_cogen_factors(&FP, Carrier::HIDROGENO, 0.7, 0.4).is_err());
+    assert!(fuel_cell_cogen_factors(&FP, Carrier::HIDROGENO, 0.0, 0.8).is_err());
+
+    let comps: Components = "#META CTE_PILA_COMBUSTIBLE: HIDROGENO, 0.50, 0.35
+HIDROGENO, CONSUMO, EPB, NDEF, 200
+ELECTRICIDAD, PRODUCCION, COGENERACION, NDEF, 100"
+        .parse()
+        .unwrap();
+    let FP = wfactors_set_fuel_cell(FP, &comps).unwrap();
+    let to_grid = FP
+        .wdata
+        .iter()
+        .find(|f| f.source == Source::COGENERACION && f.dest == Dest::A_RED)
+        .unwrap();
+    assert!(approx_equal(
+        to_grid.factors(),
+        RenNrenCo2::new(0.0, 2.0, 0.4235)
+    ));
+
+    let comps: Components = "#META CTE_PILA_COMBUSTIBLE: HIDROGENO, 0.50
+HIDROGENO, CONSUMO, EPB, NDEF, 200"
+        .parse()
+        .unwrap();
+    assert!(wfactors_set_fuel_cell(FP, &comps).is_err());
+}
+
+#[test]
+fn cte_balance_to_xml_registro() {
+    let ENERGYDATALIST = get_energydatalist();
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&ENERGYDATALIST, &FP, TESTKEXP, 1.0).unwrap();
+    let xml = balance_to_xml_registro(&bal).unwrap();
+    assert!(xml.contains(&format!("xmlns=\"{}\"", CTE_REGISTRO_XMLNS)));
+    assert!(xml.contains("<Localizacion>PENINSULA</Localizacion>"));
+    assert!(xml.contains("<Servicio codigo=\"NDEF\">"));
+
+    // Sin localización no se puede generar el XML de registro
+    let FP: Factors = TESTFPJ.parse().unwrap();
+    let bal = energy_performance(&ENERGYDATALIST, &FP, TESTKEXP, 1.0).unwrap();
+    assert!(balance_to_xml_registro(&bal).is_err());
+}
+
 #[test]
 fn cte_1_base() {
     let comps = components_from_file("test_data/extra/ejemplo1base.csv");
@@ -692,6 +2232,7 @@ fn cte_EPBD() {
     let user_wf = UserWF {
         red1: Some(CTE_USERWF.red1),
         red2: Some(CTE_USERWF.red2),
+        residuos: None,
         cogen_to_grid: None,
         cogen_to_nepb: None,
     };
@@ -740,6 +2281,32 @@ fn cte_new_services_format_ACS() {
     ));
 }
 
+#[test]
+fn cte_nearby_balances_by_service() {
+    let comps = components_from_file("test_data/newServicesFormat.csv");
+    let FP = get_ctefp_peninsula();
+    let balances = nearby_balances_by_service(&comps, &FP, 0.0, 217.4).unwrap();
+    let services: std::collections::HashSet<_> = comps
+        .cdata
+        .iter()
+        .filter(|c| c.ctype == CType::CONSUMO && c.csubtype == CSubtype::EPB)
+        .map(|c| c.service)
+        .collect();
+    assert_eq!(balances.len(), services.len());
+    // Igual al cálculo de cada servicio por separado
+    let acs = energy_performance(
+        &comps.filter_by_epb_service(Service::ACS),
+        &wfactors_to_nearby(&FP).unwrap(),
+        0.0,
+        217.4,
+    )
+    .unwrap();
+    assert!(approx_equal(
+        acs.balance_m2.B,
+        balances[&Service::ACS].balance_m2.B
+    ));
+}
+
 #[test]
 fn cte_force_electricity_prod_to_NDEF() {
     let comps = "ELECTRICIDAD,CONSUMO,EPB,CAL,20
@@ -766,7 +2333,41 @@ fn cte_balance_byuse() {
         },
     );
 
-    assert_eq!(result, bal.balance_m2.B_byuse);
+    if cfg!(feature = "f64") {
+        assert_eq!(bal.balance_m2.B_byuse.len(), 1);
+        assert!(approx_equal(
+            result[&Service::NDEF],
+            bal.balance_m2.B_byuse[&Service::NDEF]
+        ));
+    } else {
+        assert_eq!(result, bal.balance_m2.B_byuse);
+    }
+}
+
+#[test]
+fn cte_balance_byuse_humidification() {
+    let comps = "ELECTRICIDAD, CONSUMO, EPB, HUMIDIFICATION, 10.0
+ELECTRICIDAD, CONSUMO, EPB, DHU, 30.0
+GASNATURAL, CONSUMO, EPB, CAL, 100.0"
+        .parse::<Components>()
+        .unwrap();
+    assert_eq!(comps.cdata[0].service, Service::HU);
+    let FP = get_ctefp_peninsula();
+    let bal = energy_performance(&comps, &FP, TESTKEXP, 1.0).unwrap();
+    let used = &bal.balance_m2.used_EPB_byuse;
+    assert!((used[&Service::HU] - 10.0).abs() < 0.01);
+    assert!((used[&Service::DHU] - 30.0).abs() < 0.01);
+    // Reparto de la energía ponderada de la electricidad en proporción al consumo
+    let b = &bal.balance_m2.B_byuse;
+    assert!(approx_equal(b[&Service::DHU], b[&Service::HU] * 3.0));
+    assert!(approx_equal(
+        b[&Service::HU],
+        RenNrenCo2::new(4.14, 19.54, 3.31)
+    ));
+    // Filtrado de los componentes de cada servicio
+    let dhu = comps.filter_by_epb_service(Service::DHU);
+    assert_eq!(dhu.cdata.len(), 1);
+    assert_eq!(dhu.cdata[0].service, Service::DHU);
 }
 
 // Tests para demanda renovable de ACS
@@ -784,6 +2385,35 @@ ELECTRICIDAD,PRODUCCION,INSITU,ACS,60"
     assert_eq!(format!("{:.2}", fraccion_ren_acs), "0.60");
 }
 
+#[test]
+fn cte_he4_report() {
+    use cteepbd::cte::he4::*;
+    let mut comps = "ELECTRICIDAD,CONSUMO,EPB,ACS,100
+ELECTRICIDAD,PRODUCCION,INSITU,ACS,65"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+    // Sin demanda anual de ACS
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    assert!(check_he4(&bal, 1000.0).is_err());
+    // Demanda anual en metadatos
+    comps.set_meta("CTE_ACS_DEMANDA_ANUAL", "100");
+    let bal = energy_performance(&comps, &FP, 0.0, 1.0).unwrap();
+    let report = check_he4(&bal, 1000.0).unwrap();
+    assert_eq!(format!("{:.2}", report.renewable_fraction), "0.65");
+    assert_eq!(report.min_renewable_fraction, 0.60);
+    assert!(report.passes);
+    // Demanda alta: mínimo del 70%
+    let report = check_he4(&bal, 6000.0).unwrap();
+    assert_eq!(report.min_renewable_fraction, 0.70);
+    assert!(!report.passes);
+    assert!(report.margin < 0.0);
+    // Demanda anual en los indicadores adicionales del balance
+    let bal = incorpora_demanda_renovable_acs_nrb(bal, Some(130.0));
+    assert_eq!(check_he4(&bal, 1000.0).unwrap().annual_demand, 130.0);
+}
+
 /// Gas natural (fp_nren = 1.1, con rend=0.9) y 60% de cobertura solar (100kWh demanda ACS)
 #[test]
 fn cte_ACS_demanda_ren_gn_60pst() {
@@ -898,6 +2528,61 @@ MEDIOAMBIENTE,CONSUMO,EPB,ACS,60"
     assert_eq!(format!("{:.2}", fraccion_ren_acs), "0.60");
 }
 
+/// Bomba de calor (SCOP=2.5) con componentes del edificio de referencia, que no se consideran
+#[test]
+fn cte_ACS_demanda_ren_bdc_60ma_con_referencia() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,ACS,40.0
+MEDIOAMBIENTE,CONSUMO,EPB,ACS,60
+-1, BIOMASA, CONSUMO, EPB, ACS, 2000.0
+-2, MEDIOAMBIENTE, CONSUMO, EPB, ACS, 500.0"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+    let fraccion_ren_acs = fraccion_renovable_acs_nrb(&comps, &FP, 100.0).unwrap();
+    assert_eq!(format!("{:.2}", fraccion_ren_acs), "0.60");
+    // La producción de MEDIOAMBIENTE generada para la referencia pertenece a la referencia
+    assert!(comps
+        .cdata
+        .iter()
+        .any(|c| c.id == -2 && c.ctype == CType::PRODUCCION && c.is_generated()));
+    let reference = comps.reference().unwrap();
+    assert!(reference.cdata.iter().all(|c| c.id > 0));
+}
+
+/// Bomba de calor (SCOP=2.5) con ajuste del consumo de MEDIOAMBIENTE y de ELECTRICIDAD
+#[test]
+fn cte_ACS_demanda_ren_bdc_con_ajuste() {
+    let comps = "ELECTRICIDAD,CONSUMO,EPB,ACS,40.0
+MEDIOAMBIENTE,CONSUMO,EPB,ACS,60
+MEDIOAMBIENTE,AJUSTE,EPB,ACS,-20 # Corrección de lectura
+ELECTRICIDAD,AJUSTE,EPB,ACS,20 # Corrección de lectura
+ELECTRICIDAD,PRODUCCION,INSITU,NDEF,50"
+        .parse::<Components>()
+        .unwrap()
+        .normalize();
+    let FP: Factors = TESTFP.parse().unwrap();
+    let fraccion_ren_acs = fraccion_renovable_acs_nrb(&comps, &FP, 100.0).unwrap();
+    assert_eq!(format!("{:.2}", fraccion_ren_acs), "0.90");
+    // La producción de MEDIOAMBIENTE generada compensa el consumo ajustado
+    let env_pr: Real = comps
+        .cdata
+        .iter()
+        .filter(|c| c.carrier == Carrier::MEDIOAMBIENTE && c.ctype == CType::PRODUCCION)
+        .map(|c| c.total())
+        .sum();
+    assert!((env_pr - 40.0).abs() < 0.01);
+    // La producción eléctrica asignada al servicio tiene en cuenta el ajuste del consumo
+    let acs = comps.filter_by_epb_service(Service::ACS);
+    let el_pr: Real = acs
+        .cdata
+        .iter()
+        .filter(|c| c.carrier == Carrier::ELECTRICIDAD && c.ctype == CType::PRODUCCION)
+        .map(|c| c.total())
+        .sum();
+    assert!((el_pr - 50.0).abs() < 0.01);
+}
+
 /// Bomba de calor (SCOP=2.5) + 10kWh PV (100kWh demanda ACS)
 #[test]
 fn cte_ACS_demanda_ren_bdc_60ma_10pv() {
@@ -996,4 +2681,10 @@ fn cte_ACS_demanda_ren_excluye_aux() {
     let FP = TESTFP.parse().unwrap();
     let fraccion_ren_acs = fraccion_renovable_acs_nrb(&comps, &FP, 4549.0).unwrap();
     assert_eq!(format!("{:.3}", fraccion_ren_acs), "0.967");
+    // Los consumos auxiliares marcados en el comentario se desglosan en el balance
+    assert_eq!(comps.cdata.iter().filter(|c| c.aux).count(), 3);
+    let bal = energy_performance(&comps, &FP, 1.0, 1.0).unwrap();
+    let el = bal.carrier(Carrier::ELECTRICIDAD).unwrap();
+    assert!((el.used_EPB_aux_an_byuse[&Service::ACS] - 138.63).abs() < 0.1);
+    assert_eq!(bal.balance.used_EPB_aux_byuse.len(), 1);
 }